@@ -0,0 +1,54 @@
+/* Built by capi.rs's tests against include/git_rs.h and libgit_rs.a:
+ * prints HEAD, the type and size of its commit, then its history. */
+#include <stdio.h>
+#include <string.h>
+
+#include "git_rs.h"
+
+static void print_id(const uint8_t *id) {
+    for (int i = 0; i < 20; i++) {
+        printf("%02x", id[i]);
+    }
+    printf("\n");
+}
+
+int main(int argc, char **argv) {
+    git_rs_repository *repository = NULL;
+    uint8_t head[20], id[20];
+    int typ = 0;
+    uint8_t *data = NULL;
+    uintptr_t len = 0;
+
+    if (argc != 2 || git_rs_repository_open(argv[1], &repository) != GIT_RS_OK) {
+        fprintf(stderr, "open: %s\n", git_rs_last_error());
+        return 1;
+    }
+    if (git_rs_ref_resolve(repository, "HEAD", head) != GIT_RS_OK) {
+        fprintf(stderr, "HEAD: %s\n", git_rs_last_error());
+        return 1;
+    }
+    print_id(head);
+    if (git_rs_object_read(repository, head, &typ, &data, &len) != GIT_RS_OK) {
+        return 1;
+    }
+    printf("%d %lu\n", typ, (unsigned long) len);
+    git_rs_buffer_free(data, len);
+
+    memset(id, 0, sizeof(id));
+    if (git_rs_ref_resolve(repository, "refs/heads/nope", id) != GIT_RS_ENOTFOUND) {
+        return 1;
+    }
+    git_rs_revwalk *walk = NULL;
+    if (git_rs_revwalk_new(repository, id, &walk) != GIT_RS_ERROR || strlen(git_rs_last_error()) == 0) {
+        return 1;
+    }
+    if (git_rs_revwalk_new(repository, head, &walk) != GIT_RS_OK) {
+        return 1;
+    }
+    while (git_rs_revwalk_next(walk, id) == GIT_RS_OK) {
+        print_id(id);
+    }
+    git_rs_revwalk_free(walk);
+    git_rs_repository_free(repository);
+    return 0;
+}
@@ -0,0 +1,120 @@
+#ifndef GIT_RS_H
+#define GIT_RS_H
+
+/* Generated by cbindgen from src/capi.rs; do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define GIT_RS_OK 0
+
+#define GIT_RS_ERROR -1
+
+#define GIT_RS_ENOTFOUND -3
+
+#define GIT_RS_ITEROVER -31
+
+#define GIT_RS_OBJECT_COMMIT 1
+
+#define GIT_RS_OBJECT_TREE 2
+
+#define GIT_RS_OBJECT_BLOB 3
+
+#define GIT_RS_OBJECT_TAG 4
+
+typedef struct git_rs_repository git_rs_repository;
+
+typedef struct git_rs_revwalk git_rs_revwalk;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * The message of the last GIT_RS_ERROR on this thread, valid until the
+ * next call that fails.
+ */
+const char *git_rs_last_error(void);
+
+/**
+ * Opens the repository whose worktree (or bare git dir) is at `path`.
+ *
+ * # Safety
+ *
+ * `path` must be a NUL terminated string and `out` valid to write to.
+ */
+int git_rs_repository_open(const char *path, git_rs_repository **out);
+
+/**
+ * # Safety
+ *
+ * `repository` must come from `git_rs_repository_open` (or be NULL), with
+ * no revwalks left on it.
+ */
+void git_rs_repository_free(git_rs_repository *repository);
+
+/**
+ * Reads object `id`, setting `*typ` to a GIT_RS_OBJECT_* and `*data` to
+ * `*len` bytes to release with `git_rs_buffer_free`.
+ *
+ * # Safety
+ *
+ * `id` must point to 20 bytes and the out pointers be valid to write to.
+ */
+int git_rs_object_read(const git_rs_repository *repository,
+                       const uint8_t *id,
+                       int *typ,
+                       uint8_t **data,
+                       uintptr_t *len);
+
+/**
+ * # Safety
+ *
+ * `data` and `len` must be as `git_rs_object_read` returned them.
+ */
+void git_rs_buffer_free(uint8_t *data, uintptr_t len);
+
+/**
+ * Resolves the ref `name` ("HEAD", "refs/heads/master") through symbolic
+ * refs into the 20 bytes at `out`.
+ *
+ * # Safety
+ *
+ * `name` must be a NUL terminated string and `out` valid for 20 bytes.
+ */
+int git_rs_ref_resolve(const git_rs_repository *repository, const char *name, uint8_t *out);
+
+/**
+ * Starts a walk of the history of commit `id`, newest first.
+ *
+ * # Safety
+ *
+ * `id` must point to 20 bytes and `out` be valid to write to. The walk
+ * must be freed before its repository.
+ */
+int git_rs_revwalk_new(const git_rs_repository *repository, const uint8_t *id, git_rs_revwalk **out);
+
+/**
+ * Writes the next commit's id to `out`, or returns GIT_RS_ITEROVER.
+ *
+ * # Safety
+ *
+ * `walk` must come from `git_rs_revwalk_new` and `out` be valid for 20
+ * bytes.
+ */
+int git_rs_revwalk_next(git_rs_revwalk *walk, uint8_t *out);
+
+/**
+ * # Safety
+ *
+ * `walk` must come from `git_rs_revwalk_new` (or be NULL).
+ */
+void git_rs_revwalk_free(git_rs_revwalk *walk);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* GIT_RS_H */
@@ -0,0 +1,107 @@
+use std::path::Path;
+
+use crate::errors::{ ErrorKind, Result };
+use crate::worktree::common_dir;
+use crate::config::Config;
+use crate::stores::fs;
+use crate::id::Id;
+
+// git's fallback, and the shortest length `auto` scales up from.
+pub const DEFAULT: usize = 7;
+pub const MIN: usize = 4;
+
+// core.abbrev: "auto", "no" (full ids) or a length from 4 to 40.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Abbrev {
+    Auto,
+    Len(usize)
+}
+
+impl Abbrev {
+    pub fn from_config(config: &Config) -> Result<Abbrev> {
+        let value = match config.get("core.abbrev") {
+            Some(xs) => xs.trim(),
+            None => return Ok(Abbrev::Auto)
+        };
+        if value.eq_ignore_ascii_case("auto") {
+            return Ok(Abbrev::Auto)
+        }
+        match value.parse::<usize>() {
+            Ok(xs) if xs >= MIN => Ok(Abbrev::Len(xs.min(40))),
+            Err(_) if config.get_bool("core.abbrev") == Some(false) => Ok(Abbrev::Len(40)),
+            _ => Err(ErrorKind::BadAbbrev(String::from(value)).into())
+        }
+    }
+
+    pub fn resolve(self, path: &Path) -> Result<usize> {
+        match self {
+            Abbrev::Len(xs) => Ok(xs),
+            Abbrev::Auto => Ok(for_count(count_objects(path)?))
+        }
+    }
+}
+
+// With about 2^n objects, a collision is expected among ids of n/2 bits;
+// at four bits a hex digit, that is n/4 digits, rounded up. Small
+// repositories keep the default.
+pub fn for_count(count: u64) -> usize {
+    let bits = 64 - count.leading_zeros() as usize;
+    bits.div_ceil(2).max(DEFAULT)
+}
+
+// Packed objects plus loose ones; what `for_count` scales with.
+pub fn count_objects(path: &Path) -> std::result::Result<u64, std::io::Error> {
+    let mut count: u64 = fs::pack_indices(path)?.iter().map(|xs| xs.ids().len() as u64).sum();
+    let objects = common_dir(path)?.join("objects");
+    for dir in std::fs::read_dir(&objects)? {
+        let dir = dir?;
+        let name = dir.file_name();
+        let is_fanout = name.len() == 2 && name.to_str().is_some_and(|xs| xs.bytes().all(|xs| xs.is_ascii_hexdigit()));
+        if is_fanout {
+            count += std::fs::read_dir(dir.path())?.count() as u64;
+        }
+    }
+    Ok(count)
+}
+
+// The abbreviation length the repository at `path` is configured for.
+pub fn length(path: &Path) -> Result<usize> {
+    Abbrev::from_config(&Config::from_path(path)?)?.resolve(path)
+}
+
+pub fn abbreviate(id: &Id, len: usize) -> String {
+    let mut hex = id.to_string();
+    hex.truncate(len);
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::config::Config;
+    use crate::files;
+    use super::{ count_objects, for_count, length, Abbrev };
+
+    #[test]
+    fn auto_scales_with_object_count() {
+        assert_eq!(for_count(0), 7);
+        assert_eq!(for_count(1 << 14), 8);
+        assert_eq!(for_count(2_000_000), 11);
+        assert_eq!(for_count(u64::MAX), 32);
+
+        let parse = |xs: &str| Abbrev::from_config(&Config::parse(&format!("[core]\n\tabbrev = {}\n", xs)).unwrap());
+        assert_eq!(parse("auto").unwrap(), Abbrev::Auto);
+        assert_eq!(parse("no").unwrap(), Abbrev::Len(40));
+        assert_eq!(parse("12").unwrap(), Abbrev::Len(12));
+        assert!(parse("3").is_err());
+
+        let dir = TempDir::new("abbrev").expect("failed to create tempdir");
+        RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"])
+            .write(dir.path())
+            .expect("failed to write");
+        // a commit, a tree and a blob
+        assert_eq!(count_objects(dir.path()).unwrap(), 3);
+        assert_eq!(length(dir.path()).unwrap(), 7);
+    }
+}
@@ -0,0 +1,605 @@
+use std::path::Path;
+use std::io::Write;
+use std::sync::Arc;
+
+use chrono::{ DateTime, Datelike, TimeZone, Timelike, Utc };
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use crate::stores::{ Queryable, StorageSet };
+use crate::errors::{ ErrorKind, Result };
+use crate::objects::commit::Commit;
+use crate::attributes::Attributes;
+use crate::walk::tree::{ TreeWalk, Visit };
+use crate::objects::tree::FileMode;
+use crate::clock::{ self, Clock };
+use crate::abbrev::{ self, abbreviate };
+use crate::objects::Object;
+use crate::id::Id;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    Zip
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    // prepended to every path as is, so directories want a trailing slash.
+    pub prefix: String,
+    // overrides the commit time (or the current time, for a bare tree).
+    pub mtime: Option<DateTime<Utc>>,
+    // what "the current time" is.
+    pub clock: Arc<dyn Clock>,
+    // hex digits of the ids %h, %t and %p expand to
+    pub abbrev: usize
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            prefix: String::new(),
+            mtime: None,
+            clock: clock::system(),
+            abbrev: abbrev::DEFAULT
+        }
+    }
+}
+
+impl Options {
+    // The defaults, abbreviating ids as the repository's core.abbrev asks.
+    pub fn from_path(path: &Path) -> Result<Options> {
+        Ok(Options { abbrev: abbrev::length(path)?, ..Options::default() })
+    }
+}
+
+enum Kind {
+    Directory,
+    File { executable: bool },
+    Symlink
+}
+
+struct Entry {
+    path: Vec<u8>,
+    kind: Kind,
+    contents: Vec<u8>
+}
+
+// Peels tags down to a commit or a tree.
+fn resolve<S: Queryable>(storage_set: &StorageSet<S>, id: &Id) -> Result<(Id, Option<(Id, Commit)>)> {
+    let mut id = id.clone();
+    loop {
+        match storage_set.get_and_load(&id)? {
+            Some(Object::Tag(tag)) => id = match tag.object() {
+                Some(xs) => xs,
+                None => return Err(ErrorKind::MissingObject.into())
+            },
+            Some(Object::Commit(commit)) => return match commit.tree() {
+                Some(tree) => Ok((tree, Some((id, commit)))),
+                None => Err(ErrorKind::MissingObject.into())
+            },
+            Some(Object::Tree(_)) => return Ok((id, None)),
+            _ => return Err(ErrorKind::MissingObject.into())
+        }
+    }
+}
+
+// The `$Format:...$` placeholders git's export-subst expands, for the
+// pretty-format codes a release tarball usually asks for.
+fn pretty(format: &str, id: &Id, commit: &Commit, abbrev: usize) -> String {
+    let mut output = String::new();
+    let mut chars = format.chars();
+    let identity = |who: char| match who {
+        'a' => commit.author(),
+        _ => commit.committer()
+    };
+    while let Some(xs) = chars.next() {
+        if xs != '%' {
+            output.push(xs);
+            continue
+        }
+        let code = match chars.next() {
+            Some(xs) => xs,
+            None => {
+                output.push('%');
+                break
+            }
+        };
+        match code {
+            '%' => output.push('%'),
+            'n' => output.push('\n'),
+            'H' => output.push_str(&id.to_string()),
+            'h' => output.push_str(&abbreviate(id, abbrev)),
+            'T' => output.push_str(&commit.tree().map(|xs| xs.to_string()).unwrap_or_default()),
+            't' => output.push_str(&commit.tree().map(|xs| abbreviate(&xs, abbrev)).unwrap_or_default()),
+            'P' | 'p' => {
+                let parents: Vec<String> = commit.parents().unwrap_or_default().iter().map(|xs| {
+                    if code == 'P' { xs.to_string() } else { abbreviate(xs, abbrev) }
+                }).collect();
+                output.push_str(&parents.join(" "));
+            },
+            's' => {
+                let message = String::from_utf8_lossy(commit.message());
+                output.push_str(message.lines().next().unwrap_or(""));
+            },
+            'a' | 'c' => {
+                let person = identity(code);
+                let field = chars.next();
+                let person = match person {
+                    Some(xs) => xs,
+                    None => continue
+                };
+                let at = person.at().with_timezone(person.offset());
+                match field {
+                    Some('n') => output.push_str(&String::from_utf8_lossy(person.name())),
+                    Some('e') => output.push_str(&String::from_utf8_lossy(person.email())),
+                    Some('t') => output.push_str(&person.at().timestamp().to_string()),
+                    Some('d') => output.push_str(&at.format("%a %b %-d %H:%M:%S %Y %z").to_string()),
+                    Some('D') => output.push_str(&at.to_rfc2822()),
+                    Some('I') => output.push_str(&at.to_rfc3339()),
+                    Some(other) => {
+                        output.push('%');
+                        output.push(code);
+                        output.push(other);
+                    },
+                    None => {
+                        output.push('%');
+                        output.push(code);
+                    }
+                }
+            },
+            other => {
+                output.push('%');
+                output.push(other);
+            }
+        }
+    }
+    output
+}
+
+fn export_subst(contents: &[u8], id: &Id, commit: &Commit, abbrev: usize) -> Vec<u8> {
+    let mut output = Vec::with_capacity(contents.len());
+    let mut rest = contents;
+    while let Some(start) = rest.windows(8).position(|xs| xs == b"$Format:") {
+        let after = &rest[start + 8..];
+        let end = match after.iter().position(|xs| *xs == b'$') {
+            Some(xs) if !after[..xs].contains(&b'\n') => xs,
+            _ => break
+        };
+        output.extend_from_slice(&rest[..start]);
+        output.extend_from_slice(pretty(&String::from_utf8_lossy(&after[..end]), id, commit, abbrev).as_bytes());
+        rest = &after[end + 1..];
+    }
+    output.extend_from_slice(rest);
+    output
+}
+
+// The archive's entries in walk order, each directory before its contents.
+// Submodules become empty directories.
+fn collect<S: Queryable>(
+    storage_set: &StorageSet<S>,
+    tree: &Id,
+    commit: Option<&(Id, Commit)>,
+    attributes: &Attributes,
+    options: &Options
+) -> Result<Vec<Entry>> {
+    let prefix = options.prefix.as_bytes();
+    let mut entries = Vec::new();
+    if prefix.ends_with(b"/") {
+        entries.push(Entry { path: prefix.to_vec(), kind: Kind::Directory, contents: Vec::new() });
+    }
+    TreeWalk::new(storage_set).run(tree, |path, entry| {
+        if attributes.is_set(path, "export-ignore") {
+            return Ok(Visit::Skip)
+        }
+        let archived = [prefix, path].concat();
+        if entry.mode.is_tree() || entry.mode.is_gitlink() {
+            entries.push(Entry { path: [&archived[..], b"/"].concat(), kind: Kind::Directory, contents: Vec::new() });
+            return Ok(Visit::Continue)
+        }
+
+        let mut contents = match storage_set.get_and_load(&entry.id)? {
+            Some(Object::Blob(xs)) => xs.contents,
+            _ => return Err(ErrorKind::MissingObject.into())
+        };
+        let kind = if entry.mode == FileMode::SYMLINK {
+            Kind::Symlink
+        } else {
+            if let Some((id, commit)) = commit {
+                if attributes.is_set(path, "export-subst") {
+                    contents = export_subst(&contents, id, commit, options.abbrev);
+                }
+            }
+            Kind::File { executable: entry.mode.bits() & 0o111 != 0 }
+        };
+        entries.push(Entry { path: archived, kind, contents });
+        Ok(Visit::Continue)
+    })?;
+    Ok(entries)
+}
+
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+// One pax extended header record: "<length> <key>=<value>\n", where the
+// length counts itself.
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let len = key.len() + value.len() + 3;
+    let mut total = len + 1;
+    while total.to_string().len() + len != total {
+        total = total.to_string().len() + len;
+    }
+    let mut record = format!("{} {}=", total, key).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
+
+fn tar_header(name: &[u8], prefix: &[u8], mode: u32, size: u64, mtime: i64, typeflag: u8, linkname: &[u8]) -> [u8; 512] {
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name);
+    octal(&mut header[100..108], mode as u64);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size);
+    octal(&mut header[136..148], mtime.max(0) as u64);
+    header[156] = typeflag;
+    header[157..157 + linkname.len()].copy_from_slice(linkname);
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[265..269].copy_from_slice(b"root");
+    header[297..301].copy_from_slice(b"root");
+    octal(&mut header[329..337], 0);
+    octal(&mut header[337..345], 0);
+    header[345..345 + prefix.len()].copy_from_slice(prefix);
+
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|xs| *xs as u32).sum();
+    octal(&mut header[148..156], checksum as u64);
+    header
+}
+
+fn write_padded<W: Write>(output: &mut W, data: &[u8], written: &mut u64) -> std::io::Result<()> {
+    output.write_all(data)?;
+    let padding = (512 - data.len() % 512) % 512;
+    output.write_all(&vec![0u8; padding])?;
+    *written += (data.len() + padding) as u64;
+    Ok(())
+}
+
+fn write_tar<W: Write>(output: &mut W, entries: &[Entry], mtime: i64, commit: Option<&Id>) -> Result<()> {
+    let mut written = 0;
+    // git records the commit in a global header, where `git get-tar-commit-id` finds it.
+    if let Some(id) = commit {
+        let record = pax_record("comment", id.to_string().as_bytes());
+        let header = tar_header(b"pax_global_header", b"", 0o666, record.len() as u64, mtime, b'g', b"");
+        write_padded(output, &header, &mut written)?;
+        write_padded(output, &record, &mut written)?;
+    }
+
+    for entry in entries {
+        let (mode, typeflag, size, linkname) = match entry.kind {
+            Kind::Directory => (0o775, b'5', 0, &b""[..]),
+            Kind::File { executable: true } => (0o775, b'0', entry.contents.len(), &b""[..]),
+            Kind::File { executable: false } => (0o664, b'0', entry.contents.len(), &b""[..]),
+            Kind::Symlink => (0o777, b'2', 0, &entry.contents[..])
+        };
+
+        // names that don't fit ustar's fields go in an extended header.
+        let path = &entry.path[..];
+        let (mut name, mut prefix) = (path, &b""[..]);
+        let mut extended = Vec::new();
+        if path.len() > 100 {
+            let trimmed = path.strip_suffix(b"/").unwrap_or(path);
+            let split = (0..trimmed.len()).rev()
+                .find(|xs| trimmed[*xs] == b'/' && *xs <= 155 && path.len() - xs - 1 <= 100);
+            match split {
+                Some(xs) => {
+                    prefix = &path[..xs];
+                    name = &path[xs + 1..];
+                },
+                None => {
+                    extended.extend(pax_record("path", path));
+                    name = &path[..100];
+                }
+            }
+        }
+        let linkname = if linkname.len() > 100 {
+            extended.extend(pax_record("linkpath", linkname));
+            &linkname[..100]
+        } else {
+            linkname
+        };
+        if !extended.is_empty() {
+            let header = tar_header(b"pax_extended_header", b"", 0o666, extended.len() as u64, mtime, b'x', b"");
+            write_padded(output, &header, &mut written)?;
+            write_padded(output, &extended, &mut written)?;
+        }
+
+        let header = tar_header(name, prefix, mode, size as u64, mtime, typeflag, linkname);
+        write_padded(output, &header, &mut written)?;
+        if size > 0 {
+            write_padded(output, &entry.contents, &mut written)?;
+        }
+    }
+
+    // two empty blocks end the archive, padded out to tar's 10240-byte records.
+    let mut end = vec![0u8; 1024];
+    let total = written + 1024;
+    end.resize(end.len() + ((10240 - total % 10240) % 10240) as usize, 0);
+    output.write_all(&end)?;
+    Ok(())
+}
+
+fn dos_time(mtime: i64) -> (u16, u16) {
+    let at = Utc.timestamp_opt(mtime, 0).single().unwrap_or_else(|| Utc.timestamp_opt(0, 0).unwrap());
+    let time = (at.hour() << 11) | (at.minute() << 5) | (at.second() / 2);
+    let date = ((at.year().max(1980) - 1980) << 9) as u32 | (at.month() << 5) | at.day();
+    (time as u16, date as u16)
+}
+
+fn write_zip<W: Write>(output: &mut W, entries: &[Entry], mtime: i64, commit: Option<&Id>) -> Result<()> {
+    let (time, date) = dos_time(mtime);
+    let mut offset: u64 = 0;
+    let mut directory = Vec::new();
+    for entry in entries {
+        let (mode, attr): (u32, u32) = match entry.kind {
+            Kind::Directory => (0o040755, 0x10),
+            Kind::File { executable: true } => (0o100755, 0),
+            Kind::File { executable: false } => (0o100644, 0),
+            Kind::Symlink => (0o120777, 0)
+        };
+        let crc = crc::crc32::checksum_ieee(&entry.contents);
+
+        // deflate plain files unless it doesn't pay off.
+        let (method, data) = match entry.kind {
+            Kind::File { .. } if !entry.contents.is_empty() => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&entry.contents)?;
+                let compressed = encoder.finish()?;
+                if compressed.len() < entry.contents.len() { (8u16, compressed) } else { (0, entry.contents.clone()) }
+            },
+            _ => (0, entry.contents.clone())
+        };
+        let version: u16 = if method == 8 { 20 } else { 10 };
+        let flags: u16 = if entry.path.is_ascii() { 0 } else { 1 << 11 };
+
+        // the extended timestamp field, with the full mtime.
+        let mut extra = Vec::new();
+        extra.extend_from_slice(&0x5455u16.to_le_bytes());
+        extra.extend_from_slice(&5u16.to_le_bytes());
+        extra.push(1);
+        extra.extend_from_slice(&(mtime as u32).to_le_bytes());
+
+        let mut local = Vec::new();
+        local.extend_from_slice(&0x04034b50u32.to_le_bytes());
+        local.extend_from_slice(&version.to_le_bytes());
+        local.extend_from_slice(&flags.to_le_bytes());
+        local.extend_from_slice(&method.to_le_bytes());
+        local.extend_from_slice(&time.to_le_bytes());
+        local.extend_from_slice(&date.to_le_bytes());
+        local.extend_from_slice(&crc.to_le_bytes());
+        local.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        local.extend_from_slice(&(entry.contents.len() as u32).to_le_bytes());
+        local.extend_from_slice(&(entry.path.len() as u16).to_le_bytes());
+        local.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+        local.extend_from_slice(&entry.path);
+        local.extend_from_slice(&extra);
+        output.write_all(&local)?;
+        output.write_all(&data)?;
+
+        directory.extend_from_slice(&0x02014b50u32.to_le_bytes());
+        // made by unix, so the external attributes carry the mode.
+        directory.extend_from_slice(&(0x0300 | version).to_le_bytes());
+        directory.extend_from_slice(&local[4..30]);
+        directory.extend_from_slice(&0u16.to_le_bytes());
+        directory.extend_from_slice(&0u16.to_le_bytes());
+        directory.extend_from_slice(&0u16.to_le_bytes());
+        directory.extend_from_slice(&((mode << 16) | attr).to_le_bytes());
+        directory.extend_from_slice(&(offset as u32).to_le_bytes());
+        directory.extend_from_slice(&entry.path);
+        directory.extend_from_slice(&extra);
+
+        offset += (local.len() + data.len()) as u64;
+    }
+
+    let comment = commit.map(|xs| xs.to_string()).unwrap_or_default();
+    output.write_all(&directory)?;
+    let mut end = Vec::new();
+    end.extend_from_slice(&0x06054b50u32.to_le_bytes());
+    end.extend_from_slice(&[0u8; 4]);
+    end.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    end.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    end.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    end.extend_from_slice(&(offset as u32).to_le_bytes());
+    end.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+    end.extend_from_slice(comment.as_bytes());
+    output.write_all(&end)?;
+    Ok(())
+}
+
+// `git archive`: writes the tree of `tree_ish` (a commit, a tag or a tree)
+// to `output`. Paths with the export-ignore attribute are left out and
+// files with export-subst have their `$Format:...$` placeholders expanded,
+// going by the tree's own .gitattributes.
+pub fn archive<S: Queryable, W: Write>(storage_set: &StorageSet<S>, tree_ish: &Id, format: Format, options: &Options, output: &mut W) -> Result<()> {
+    let (tree, commit) = resolve(storage_set, tree_ish)?;
+    let mtime = match (options.mtime, &commit) {
+        (Some(xs), _) => xs.timestamp(),
+        (None, Some((_, commit))) => commit.committer().map(|xs| xs.at().timestamp()).unwrap_or(0),
+        (None, None) => options.clock.now().timestamp()
+    };
+
+    let attributes = Attributes::from_tree(storage_set, &tree)?;
+    let entries = collect(storage_set, &tree, commit.as_ref(), &attributes, options)?;
+    let commit_id = commit.as_ref().map(|(id, _)| id);
+    match format {
+        Format::Tar => write_tar(output, &entries, mtime, commit_id),
+        Format::Zip => write_zip(output, &entries, mtime, commit_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::{ Duration, TimeZone, Utc };
+
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::testkit::{ executable, gitlink, symlink, RepoBuilder, TempDir };
+    use crate::clock::FixedClock;
+    use crate::stores::fs as gitfs;
+    use crate::objects::Object;
+    use crate::files;
+    use super::{ archive, pax_record, Format, Options };
+
+    #[test]
+    fn archives_trees_as_tar_and_zip() {
+        let builder = RepoBuilder::new()
+            .commit("release", files![
+                ".gitattributes" => "secret export-ignore\nVERSION export-subst\n",
+                "VERSION" => "$Format:%H$ by $Format:%an$\n",
+                "secret" => "hidden\n",
+                "src/main.rs" => "fn main() {}\n"
+            ]);
+        let tip = builder.tip().unwrap();
+        let (storage_set, _) = builder.in_memory();
+        let options = Options { prefix: String::from("release/"), ..Options::default() };
+
+        let mut tar = Vec::new();
+        archive(&storage_set, &tip, Format::Tar, &options, &mut tar).expect("failed to archive");
+        assert_eq!(tar.len() % 10240, 0);
+        let mut zip = Vec::new();
+        archive(&storage_set, &tip, Format::Zip, &options, &mut zip).expect("failed to archive");
+
+        // check both against the system tools, where there are any.
+        let dir = TempDir::new("archive").expect("failed to create tempdir");
+        std::fs::write(dir.path().join("out.tar"), &tar).unwrap();
+        std::fs::write(dir.path().join("out.zip"), &zip).unwrap();
+        let list = std::process::Command::new("tar").arg("-tvf").arg(dir.path().join("out.tar")).output();
+        if let Ok(list) = list {
+            let list = String::from_utf8_lossy(&list.stdout);
+            let names: Vec<&str> = list.lines().filter_map(|xs| xs.split_whitespace().last()).collect();
+            assert_eq!(names, vec!["release/", "release/.gitattributes", "release/VERSION", "release/src/", "release/src/main.rs"]);
+        }
+        let extracted = std::process::Command::new("tar")
+            .arg("-xf").arg(dir.path().join("out.tar"))
+            .arg("-C").arg(dir.path())
+            .status();
+        if extracted.is_ok_and(|xs| xs.success()) {
+            let version = std::fs::read_to_string(dir.path().join("release/VERSION")).unwrap();
+            assert_eq!(version, format!("{} by {}\n", tip, "Test User"));
+            assert!(!dir.path().join("release/secret").exists());
+        }
+        let list = std::process::Command::new("unzip").arg("-Z1").arg(dir.path().join("out.zip")).output();
+        if let Ok(list) = list {
+            let list = String::from_utf8_lossy(&list.stdout);
+            assert_eq!(list.lines().collect::<Vec<_>>(), vec!["release/", "release/.gitattributes", "release/VERSION", "release/src/", "release/src/main.rs"]);
+        }
+        assert!(zip.ends_with(tip.to_string().as_bytes()));
+        assert_eq!(&zip[..4], b"PK\x03\x04");
+
+        // a bare tree takes its timestamps from the clock.
+        let tree = match storage_set.get_and_load(&tip).unwrap() {
+            Some(Object::Commit(commit)) => commit.tree().unwrap(),
+            _ => panic!("expected commit")
+        };
+        let clock = Arc::new(FixedClock::new(Utc.timestamp_opt(1_000_000_000, 0).unwrap()));
+        let options = Options { clock: clock.clone(), ..Options::default() };
+        let mut first = Vec::new();
+        archive(&storage_set, &tree, Format::Zip, &options, &mut first).expect("failed to archive");
+        let mut second = Vec::new();
+        archive(&storage_set, &tree, Format::Zip, &options, &mut second).expect("failed to archive");
+        assert_eq!(first, second);
+        clock.advance(Duration::days(1));
+        let mut third = Vec::new();
+        archive(&storage_set, &tree, Format::Zip, &options, &mut third).expect("failed to archive");
+        assert_ne!(first, third);
+    }
+
+    #[test]
+    fn export_subst_abbreviates_as_core_abbrev_asks() {
+        let dir = TempDir::new("archive-abbrev").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"])
+            .commit("release", files![
+                ".gitattributes" => "VERSION export-subst\n",
+                "VERSION" => "$Format:%h %p$\n"
+            ]);
+        let tip = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        std::fs::write(dir.path().join(".git/config"), "[core]\n\tabbrev = 12\n").unwrap();
+        let storage_set = gitfs::from(dir.path()).expect("failed to open");
+        let parent = match storage_set.get_and_load(&tip).unwrap() {
+            Some(Object::Commit(commit)) => commit.parents().unwrap()[0].clone(),
+            _ => panic!("expected commit")
+        };
+
+        let options = Options::from_path(dir.path()).expect("failed to read config");
+        let mut tar = Vec::new();
+        archive(&storage_set, &tip, Format::Tar, &options, &mut tar).expect("failed to archive");
+        let expected = format!("{} {}\n", &tip.to_string()[..12], &parent.to_string()[..12]);
+        assert!(tar.windows(expected.len()).any(|xs| xs == expected.as_bytes()));
+
+        let mut tar = Vec::new();
+        archive(&storage_set, &tip, Format::Tar, &Options::default(), &mut tar).expect("failed to archive");
+        let expected = format!("{} {}\n", &tip.to_string()[..7], &parent.to_string()[..7]);
+        assert!(tar.windows(expected.len()).any(|xs| xs == expected.as_bytes()));
+    }
+
+    #[test]
+    fn pax_records_count_their_own_length() {
+        for len in &[0, 1, 5, 85, 86, 94, 95, 96, 990, 995, 996, 997] {
+            let record = pax_record("path", &vec![b'a'; *len]);
+            let (length, _) = record.split_at(record.iter().position(|xs| *xs == b' ').unwrap());
+            assert_eq!(String::from_utf8_lossy(length), record.len().to_string());
+            assert!(record.ends_with(b"\n"));
+        }
+    }
+
+    #[test]
+    fn modes_links_and_long_paths_survive_extraction() {
+        let deep = format!("{}/{}", "d".repeat(120), "f".repeat(110));
+        let target = "t".repeat(150);
+        let builder = RepoBuilder::new().commit("first", files!["README" => "hello\n"]);
+        let first = builder.tip().unwrap();
+        let mut files = files![
+            ".gitattributes" => "build export-ignore\n",
+            "build/out.o" => "binary\n",
+            "README" => "hello\n",
+            &deep => "deep\n"
+        ];
+        files.push(executable("run.sh", "#!/bin/sh\n"));
+        files.push(symlink("short", "README"));
+        files.push(symlink("long", &target));
+        files.push(gitlink("vendor/lib", &first));
+        let builder = builder.commit("second", files);
+        let tip = builder.tip().unwrap();
+        let (storage_set, _) = builder.in_memory();
+
+        let mut tar = Vec::new();
+        archive(&storage_set, &tip, Format::Tar, &Options::default(), &mut tar).expect("failed to archive");
+        let dir = TempDir::new("archive-modes").expect("failed to create tempdir");
+        std::fs::write(dir.path().join("out.tar"), &tar).unwrap();
+        std::fs::create_dir(dir.path().join("x")).unwrap();
+        let extracted = std::process::Command::new("tar")
+            .arg("-xf").arg(dir.path().join("out.tar"))
+            .arg("-C").arg(dir.path().join("x"))
+            .status();
+        if !extracted.is_ok_and(|xs| xs.success()) {
+            return
+        }
+
+        let root = dir.path().join("x");
+        assert_eq!(std::fs::read_to_string(root.join(&deep)).unwrap(), "deep\n");
+        assert_ne!(std::fs::metadata(root.join("run.sh")).unwrap().permissions().mode() & 0o111, 0);
+        assert_eq!(std::fs::metadata(root.join("README")).unwrap().permissions().mode() & 0o111, 0);
+        assert_eq!(std::fs::read_link(root.join("short")).unwrap().to_str(), Some("README"));
+        assert_eq!(std::fs::read_link(root.join("long")).unwrap().to_str(), Some(target.as_str()));
+        assert_eq!(std::fs::read_dir(root.join("vendor/lib")).unwrap().count(), 0);
+        assert!(!root.join("build").exists());
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::BTreeMap;
+
+use crate::stores::{ Queryable, StorageSet };
+use crate::walk::tree::{ TreeWalk, Visit };
+use crate::errors::Result;
+use crate::objects::Object;
+use crate::id::Id;
+
+// The value an attribute takes for a path: "attr", "-attr" or "attr=value".
+// An attribute no rule mentions (or one reset with "!attr") is unspecified.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    Set,
+    Unset,
+    Value(String)
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    // the directory of the .gitattributes the rule came from, with a
+    // trailing slash unless it is the root.
+    base: Vec<u8>,
+    pattern: Vec<u8>,
+    anchored: bool,
+    attrs: Vec<(String, Option<State>)>
+}
+
+// Rules from .gitattributes files, searched last to first: later lines win
+// over earlier ones, and a deeper file over a shallower one as long as the
+// files are added shallowest first.
+#[derive(Clone, Debug, Default)]
+pub struct Attributes {
+    rules: Vec<Rule>
+}
+
+// Built in macro attributes.
+fn expand(name: &str, state: Option<State>, attrs: &mut Vec<(String, Option<State>)>) {
+    if name == "binary" && state == Some(State::Set) {
+        for xs in &["diff", "merge", "text"] {
+            attrs.push((String::from(*xs), Some(State::Unset)));
+        }
+    }
+    attrs.push((String::from(name), state));
+}
+
+impl Attributes {
+    pub fn new() -> Attributes {
+        Attributes::default()
+    }
+
+    // Adds the rules of the .gitattributes in directory `base` ("" for the
+    // root, otherwise "dir/sub").
+    pub fn add(&mut self, base: &[u8], contents: &[u8]) {
+        let mut base = base.to_vec();
+        if !base.is_empty() && !base.ends_with(b"/") {
+            base.push(b'/');
+        }
+        for line in contents.split(|xs| *xs == b'\n') {
+            let line = String::from_utf8_lossy(line);
+            let mut fields = line.split_ascii_whitespace();
+            let pattern = match fields.next() {
+                Some(xs) if !xs.starts_with('#') => xs,
+                _ => continue
+            };
+            // directories are never matched, and negative patterns are
+            // forbidden.
+            if pattern.ends_with('/') || pattern.starts_with('!') {
+                continue
+            }
+
+            let mut attrs = Vec::new();
+            for field in fields {
+                if let Some(xs) = field.strip_prefix('-') {
+                    expand(xs, Some(State::Unset), &mut attrs);
+                } else if let Some(xs) = field.strip_prefix('!') {
+                    expand(xs, None, &mut attrs);
+                } else if let Some(split) = field.find('=') {
+                    expand(&field[..split], Some(State::Value(String::from(&field[split + 1..]))), &mut attrs);
+                } else {
+                    expand(field, Some(State::Set), &mut attrs);
+                }
+            }
+            let anchored = pattern.contains('/');
+            self.rules.push(Rule {
+                base: base.clone(),
+                pattern: pattern.trim_start_matches('/').as_bytes().to_vec(),
+                anchored,
+                attrs
+            });
+        }
+    }
+
+    // Every .gitattributes in a tree (or a commit's tree), as `git archive`
+    // reads them.
+    pub fn from_tree<S: Queryable>(storage_set: &StorageSet<S>, id: &Id) -> Result<Attributes> {
+        // shallowest first, so deeper rules are searched before them.
+        let mut files = BTreeMap::new();
+        TreeWalk::new(storage_set).run(id, |path, entry| {
+            let (base, name) = match path.iter().rposition(|xs| *xs == b'/') {
+                Some(xs) => (&path[..xs], &path[xs + 1..]),
+                None => (&b""[..], path)
+            };
+            if name == b".gitattributes" && !entry.mode.is_tree() && !entry.mode.is_gitlink() {
+                if let Some(Object::Blob(blob)) = storage_set.get_and_load(&entry.id)? {
+                    let depth = path.iter().filter(|xs| **xs == b'/').count();
+                    files.insert((depth, base.to_vec()), blob.contents);
+                }
+            }
+            Ok(Visit::Continue)
+        })?;
+
+        let mut attributes = Attributes::new();
+        for ((_, base), contents) in files {
+            attributes.add(&base, &contents);
+        }
+        Ok(attributes)
+    }
+
+    // The state of attribute `name` for `path` (slash separated, from the
+    // root).
+    pub fn get(&self, path: &[u8], name: &str) -> Option<State> {
+        for rule in self.rules.iter().rev() {
+            let relative = match path.strip_prefix(&rule.base[..]) {
+                Some(xs) => xs,
+                None => continue
+            };
+            let subject = if rule.anchored {
+                relative
+            } else {
+                relative.rsplit(|xs| *xs == b'/').next().unwrap_or(relative)
+            };
+            if !wildmatch(&rule.pattern, subject) {
+                continue
+            }
+            if let Some((_, state)) = rule.attrs.iter().rev().find(|(attr, _)| attr == name) {
+                return state.clone()
+            }
+        }
+        None
+    }
+
+    pub fn is_set(&self, path: &[u8], name: &str) -> bool {
+        self.get(path, name) == Some(State::Set)
+    }
+}
+
+// A bracket expression at the start of `pattern` (just past the "["):
+// whether it matches `byte`, and its length including the "]". None if it
+// never closes, in which case the "[" is literal.
+fn match_class(pattern: &[u8], byte: u8) -> Option<(bool, usize)> {
+    let mut idx = 0;
+    let negated = matches!(pattern.first(), Some(b'!') | Some(b'^'));
+    if negated {
+        idx += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while idx < pattern.len() {
+        let mut low = pattern[idx];
+        if low == b']' && !first {
+            return Some((matched != negated, idx + 1))
+        }
+        first = false;
+        if low == b'\\' && idx + 1 < pattern.len() {
+            idx += 1;
+            low = pattern[idx];
+        }
+        if idx + 2 < pattern.len() && pattern[idx + 1] == b'-' && pattern[idx + 2] != b']' {
+            let high = pattern[idx + 2];
+            matched |= low <= byte && byte <= high;
+            idx += 3;
+        } else {
+            matched |= low == byte;
+            idx += 1;
+        }
+    }
+    None
+}
+
+fn wildmatch_at(pattern: &[u8], pi: usize, text: &[u8], ti: usize, pathname: bool) -> bool {
+    let (mut pi, mut ti) = (pi, ti);
+    while pi < pattern.len() {
+        match pattern[pi] {
+            b'*' => {
+                let mut end = pi;
+                while end < pattern.len() && pattern[end] == b'*' {
+                    end += 1;
+                }
+                let double = pathname
+                    && end - pi >= 2
+                    && (pi == 0 || pattern[pi - 1] == b'/')
+                    && (end == pattern.len() || pattern[end] == b'/');
+                if double {
+                    // "**/" is zero or more directories; a trailing "**"
+                    // is everything.
+                    if end == pattern.len() {
+                        return true
+                    }
+                    let rest = end + 1;
+                    return (ti..=text.len())
+                        .filter(|xs| *xs == ti || text[*xs - 1] == b'/')
+                        .any(|xs| wildmatch_at(pattern, rest, text, xs, pathname))
+                }
+                for xs in ti..=text.len() {
+                    if wildmatch_at(pattern, end, text, xs, pathname) {
+                        return true
+                    }
+                    if pathname && xs < text.len() && text[xs] == b'/' {
+                        return false
+                    }
+                }
+                return false
+            },
+            b'?' => {
+                if ti >= text.len() || (pathname && text[ti] == b'/') {
+                    return false
+                }
+                pi += 1;
+                ti += 1;
+            },
+            b'[' => {
+                if ti >= text.len() || (pathname && text[ti] == b'/') {
+                    return false
+                }
+                match match_class(&pattern[pi + 1..], text[ti]) {
+                    Some((true, len)) => {
+                        pi += len + 1;
+                        ti += 1;
+                    },
+                    Some((false, _)) => return false,
+                    None => {
+                        if text[ti] != b'[' {
+                            return false
+                        }
+                        pi += 1;
+                        ti += 1;
+                    }
+                }
+            },
+            byte => {
+                let (literal, width) = if byte == b'\\' && pi + 1 < pattern.len() {
+                    (pattern[pi + 1], 2)
+                } else {
+                    (byte, 1)
+                };
+                if ti >= text.len() || text[ti] != literal {
+                    return false
+                }
+                pi += width;
+                ti += 1;
+            }
+        }
+    }
+    ti == text.len()
+}
+
+// git's wildmatch with WM_PATHNAME: "*", "?" and "[...]" stop at slashes,
+// and "**" between slashes spans any number of directories.
+pub fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    wildmatch_at(pattern, 0, text, 0, true)
+}
+
+// Plain fnmatch: wildcards match slashes too.
+pub fn fnmatch(pattern: &[u8], text: &[u8]) -> bool {
+    wildmatch_at(pattern, 0, text, 0, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::stores::fs as gitfs;
+    use crate::files;
+    use super::{ fnmatch, wildmatch, Attributes, State };
+
+    #[test]
+    fn matches_patterns_and_applies_precedence() {
+        assert!(wildmatch(b"*.c", b"main.c"));
+        assert!(!wildmatch(b"*.c", b"src/main.c"));
+        assert!(wildmatch(b"src/**/*.c", b"src/main.c"));
+        assert!(wildmatch(b"src/**/*.c", b"src/a/b/main.c"));
+        assert!(wildmatch(b"**/test", b"a/test"));
+        assert!(wildmatch(b"doc/**", b"doc/a/b"));
+        assert!(wildmatch(b"[a-c]?.txt", b"b1.txt"));
+        assert!(!wildmatch(b"[!a-c]?.txt", b"b1.txt"));
+        assert!(wildmatch(b"\\*", b"*"));
+
+        let mut attributes = Attributes::new();
+        attributes.add(b"", b"# comment\n*.txt text eol=lf\n/build export-ignore\n*.png binary\n");
+        attributes.add(b"docs", b"*.txt -text\nlegacy.txt !eol\n");
+        assert_eq!(attributes.get(b"a/notes.txt", "text"), Some(State::Set));
+        assert_eq!(attributes.get(b"docs/notes.txt", "text"), Some(State::Unset));
+        assert_eq!(attributes.get(b"docs/notes.txt", "eol"), Some(State::Value(String::from("lf"))));
+        assert_eq!(attributes.get(b"docs/legacy.txt", "eol"), None);
+        assert!(attributes.is_set(b"build", "export-ignore"));
+        assert!(!attributes.is_set(b"src/build", "export-ignore"));
+        assert_eq!(attributes.get(b"logo.png", "diff"), Some(State::Unset));
+    }
+
+    #[test]
+    fn odd_lines_and_patterns() {
+        assert!(fnmatch(b"*.c", b"src/main.c"));
+        assert!(!wildmatch(b"src/*", b"src/a/b"));
+        assert!(wildmatch(b"a[b", b"a[b"));
+        assert!(wildmatch(b"[]]", b"]"));
+        assert!(!wildmatch(b"?", b"/"));
+
+        let mut attributes = Attributes::new();
+        attributes.add(b"", b"build/ export-ignore\n!*.c text\n*.c text -text filter=a=b\n  \n*.h\ttext !text\n");
+        assert_eq!(attributes.get(b"build", "export-ignore"), None);
+        assert_eq!(attributes.get(b"main.c", "text"), Some(State::Unset));
+        assert_eq!(attributes.get(b"main.c", "filter"), Some(State::Value(String::from("a=b"))));
+        assert_eq!(attributes.get(b"main.h", "text"), None);
+        assert!(!attributes.is_set(b"main.h", "text"));
+    }
+
+    #[test]
+    fn deeper_files_win_when_read_from_a_tree() {
+        let dir = TempDir::new("attributes-tree").expect("failed to create tempdir");
+        let builder = RepoBuilder::new().commit("first", files![
+            ".gitattributes" => "*.txt text eol=lf\n",
+            "docs/.gitattributes" => "*.txt -text\n",
+            "docs/api/.gitattributes" => "*.txt text\n",
+            "docs/api/a.txt" => "a\n",
+            "a.txt" => "a\n"
+        ]);
+        let tip = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+
+        let attributes = Attributes::from_tree(&storage_set, &tip).expect("failed to read attributes");
+        assert_eq!(attributes.get(b"a.txt", "text"), Some(State::Set));
+        assert_eq!(attributes.get(b"docs/a.txt", "text"), Some(State::Unset));
+        assert_eq!(attributes.get(b"docs/api/a.txt", "text"), Some(State::Set));
+        // what a deeper file doesn't mention comes from a shallower one.
+        assert_eq!(attributes.get(b"docs/api/a.txt", "eol"), Some(State::Value(String::from("lf"))));
+        assert_eq!(attributes.get(b"docsx/a.txt", "text"), Some(State::Set));
+    }
+}
@@ -0,0 +1,406 @@
+extern crate git_rs;
+
+use std::io::{ self, Cursor, Write };
+use std::os::unix::ffi::OsStrExt;
+use std::path::{ Path, PathBuf };
+use std::fs::File;
+use memmap::MmapOptions;
+
+use git_rs::stores::fs::{ self as gitfs, Storage };
+use git_rs::errors::{ ErrorKind, Failures, Result };
+use git_rs::pack::index::{ self, Indexer };
+use git_rs::objects::{ Object, Type };
+use git_rs::objects::tree::Tree;
+use git_rs::shallow::Shallow;
+use git_rs::refs::RefStore;
+use git_rs::id::Id;
+
+const USAGE: &str = "usage: git-rs <command> [<args>]
+
+    cat-file (-t | -s | -p | -e) <object>
+    ls-tree [-r] <tree-ish>
+    rev-parse <rev>...
+    log [<rev>]
+    verify-pack [-v] <pack>...
+    index-pack [--stdout] <pack>";
+
+struct Repo {
+    path: PathBuf,
+    storage_set: Storage
+}
+
+impl Repo {
+    fn open(dir: &Path) -> Result<Repo> {
+        let path = dir.to_path_buf();
+        let storage_set = gitfs::from(&path)?;
+        Ok(Repo { path, storage_set })
+    }
+
+    fn load(&self, id: &Id) -> Result<Object> {
+        match self.storage_set.get_and_load(id)? {
+            Some(xs) => Ok(xs),
+            None => Err(ErrorKind::MissingObject.into())
+        }
+    }
+
+    fn commit_parents(&self, id: &Id) -> Result<Vec<Id>> {
+        match self.load(id)? {
+            Object::Commit(commit) => Ok(commit.parents().unwrap_or_default()),
+            _ => Err(ErrorKind::MissingObject.into())
+        }
+    }
+
+    // A full id or a ref name, looked up as git does ("master" is tried
+    // as refs/master, refs/tags/master, refs/heads/master and so on), then
+    // any "^", "^N" and "~N" suffixes.
+    fn rev_parse(&self, rev: &str) -> Result<Id> {
+        let split = rev.find(['^', '~']).unwrap_or(rev.len());
+        let (name, mut suffix) = rev.split_at(split);
+        let mut id = self.resolve_name(name)?;
+        while !suffix.is_empty() {
+            let op = suffix.as_bytes()[0];
+            let digits = suffix[1..].find(|xs: char| !xs.is_ascii_digit()).map_or(suffix.len(), |xs| xs + 1);
+            let count = match &suffix[1..digits] {
+                "" => 1,
+                xs => xs.parse().map_err(|_| bad_revision(rev))?
+            };
+            suffix = &suffix[digits..];
+            if op == b'^' {
+                if count == 0 {
+                    continue
+                }
+                id = match self.commit_parents(&id)?.get(count - 1) {
+                    Some(xs) => xs.clone(),
+                    None => return Err(bad_revision(rev))
+                };
+            } else {
+                for _ in 0..count {
+                    id = match self.commit_parents(&id)?.first() {
+                        Some(xs) => xs.clone(),
+                        None => return Err(bad_revision(rev))
+                    };
+                }
+            }
+        }
+        Ok(id)
+    }
+
+    fn resolve_name(&self, name: &str) -> Result<Id> {
+        if name.len() == 40 {
+            if let Ok(id) = name.parse() {
+                return Ok(id)
+            }
+        }
+        let refs = RefStore::new(&self.path);
+        let candidates = [
+            String::from(name),
+            format!("refs/{}", name),
+            format!("refs/tags/{}", name),
+            format!("refs/heads/{}", name),
+            format!("refs/remotes/{}", name),
+            format!("refs/remotes/{}/HEAD", name)
+        ];
+        for candidate in &candidates {
+            // "master" on its own isn't a ref name the store will read.
+            match refs.read(candidate) {
+                Ok(Some(id)) => return Ok(id),
+                Ok(None) => (),
+                Err(ref e) if matches!(e.kind(), ErrorKind::BadRefName(_)) => (),
+                Err(e) => return Err(e)
+            }
+        }
+        Err(bad_revision(name))
+    }
+
+    // A commit's tree, or the tree itself.
+    fn tree(&self, id: &Id) -> Result<Tree> {
+        match self.load(id)? {
+            Object::Tree(tree) => Ok(tree),
+            Object::Commit(commit) => match commit.tree() {
+                Some(xs) => self.tree(&xs),
+                None => Err(ErrorKind::MissingObject.into())
+            },
+            _ => Err(ErrorKind::MissingObject.into())
+        }
+    }
+}
+
+fn bad_revision(rev: &str) -> git_rs::errors::Error {
+    format!("bad revision '{}'", rev).into()
+}
+
+fn usage() -> git_rs::errors::Error {
+    USAGE.into()
+}
+
+fn entry_type(mode: u32) -> &'static str {
+    match mode & 0o170000 {
+        0o040000 => "tree",
+        0o160000 => "commit",
+        _ => "blob"
+    }
+}
+
+fn write_tree(repo: &Repo, out: &mut dyn Write, tree: &Tree, prefix: &[u8], recurse: bool) -> Result<()> {
+    for (name, entry) in tree.entries() {
+        let mut path = prefix.to_vec();
+        path.extend_from_slice(name);
+        if recurse && entry.mode.is_tree() {
+            let mut dir = path.clone();
+            dir.push(b'/');
+            write_tree(repo, out, &repo.tree(&entry.id)?, &dir, recurse)?;
+            continue
+        }
+        write!(out, "{:06o} {} {}\t", entry.mode.bits(), entry_type(entry.mode.bits()), entry.id)?;
+        out.write_all(&path)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+// -e answers with the exit code alone: 0 if the object exists, 1 if not.
+fn cat_file(dir: &Path, args: &[String], out: &mut dyn Write) -> Result<i32> {
+    let (flag, rev) = match args {
+        [flag, rev] => (flag.as_str(), rev),
+        _ => return Err(usage())
+    };
+    let repo = Repo::open(dir)?;
+    let id = repo.rev_parse(rev)?;
+    let mut data = Vec::new();
+    let typ = repo.storage_set.get(&id, &mut data)?;
+    let typ = match (flag, typ) {
+        ("-e", typ) => return Ok(if typ.is_some() { 0 } else { 1 }),
+        (_, Some(xs)) => xs,
+        (_, None) => return Err(ErrorKind::MissingObject.into())
+    };
+    match flag {
+        "-t" => writeln!(out, "{}", typ.as_str())?,
+        "-s" => writeln!(out, "{}", data.len())?,
+        "-p" => match typ {
+            Type::Tree => write_tree(&repo, out, &repo.tree(&id)?, b"", false)?,
+            _ => out.write_all(&data)?
+        },
+        _ => return Err(usage())
+    }
+    Ok(0)
+}
+
+fn ls_tree(dir: &Path, args: &[String], out: &mut dyn Write) -> Result<()> {
+    let (recurse, rev) = match args {
+        [flag, rev] if flag == "-r" => (true, rev),
+        [rev] => (false, rev),
+        _ => return Err(usage())
+    };
+    let repo = Repo::open(dir)?;
+    let tree = repo.tree(&repo.rev_parse(rev)?)?;
+    write_tree(&repo, out, &tree, b"", recurse)
+}
+
+fn rev_parse(dir: &Path, args: &[String], out: &mut dyn Write) -> Result<()> {
+    if args.is_empty() {
+        return Err(usage())
+    }
+    let repo = Repo::open(dir)?;
+    for rev in args {
+        writeln!(out, "{}", repo.rev_parse(rev)?)?;
+    }
+    Ok(())
+}
+
+fn log(dir: &Path, args: &[String], out: &mut dyn Write) -> Result<()> {
+    let rev = match args {
+        [] => "HEAD",
+        [rev] => rev.as_str(),
+        _ => return Err(usage())
+    };
+    let repo = Repo::open(dir)?;
+    let shallow = Shallow::from_path(&repo.path)?;
+    for (id, commit) in repo.storage_set.commits(&repo.rev_parse(rev)?, None).with_shallow(&shallow) {
+        let message = String::from_utf8_lossy(commit.message());
+        writeln!(out, "{} {}", id, message.lines().next().unwrap_or(""))?;
+    }
+    Ok(())
+}
+
+fn map(path: &Path) -> Result<memmap::Mmap> {
+    let file = File::open(path)?;
+    Ok(unsafe { MmapOptions::new().map(&file)? })
+}
+
+// Indexes each pack again and checks every object, checksum included,
+// against its .idx. As with git, a bad pack doesn't stop the others from
+// being checked; every failure is reported at the end.
+fn verify_pack(dir: &Path, args: &[String], out: &mut dyn Write) -> Result<()> {
+    let verbose = args.iter().any(|xs| xs == "-v");
+    let packs: Vec<_> = args.iter().filter(|xs| *xs != "-v").collect();
+    if packs.is_empty() {
+        return Err(usage())
+    }
+    let repo = Repo::open(dir)?;
+    let mut failures = Failures::new(true);
+    for pack in packs {
+        let pack = Path::new(pack).with_extension("pack");
+        if failures.check(pack.as_os_str().as_bytes(), verify_one(&repo, &pack, verbose, out))?.is_some() {
+            writeln!(out, "{}: ok", pack.display())?;
+        }
+    }
+    failures.finish()
+}
+
+fn verify_one(repo: &Repo, pack: &Path, verbose: bool, out: &mut dyn Write) -> Result<()> {
+    let expected = index::read(&map(&pack.with_extension("idx"))?[..])?;
+    let mmap = map(&pack)?;
+    let mut output = Vec::new();
+    Indexer::new().parallel_hashing(true).write(Cursor::new(&mmap[..]), &mut output, Some(&repo.storage_set))?;
+    let actual = index::read(&output[..])?;
+    // the last object runs up to the trailer.
+    let trailer = mmap.len() as u64 - 20;
+
+    let mut objects: Vec<_> = expected.ids().iter().map(|xs| (expected.get_bounds(xs), xs)).collect();
+    objects.sort();
+    let matches = actual.ids() == expected.ids() && objects.iter().all(|(bounds, id)| actual.get_bounds(id) == *bounds);
+    if verbose {
+        for (bounds, id) in &objects {
+            if let Some((start, end)) = bounds {
+                writeln!(out, "{} {} {}", id, (*end).min(trailer) - start, start)?;
+            }
+        }
+    }
+    if !matches {
+        return Err("index does not match the pack".into())
+    }
+    Ok(())
+}
+
+fn index_pack(dir: &Path, args: &[String], out: &mut dyn Write) -> Result<()> {
+    let (stdout, pack) = match args {
+        [flag, pack] if flag == "--stdout" => (true, pack),
+        [pack] => (false, pack),
+        _ => return Err(usage())
+    };
+    let repo = Repo::open(dir)?;
+    let pack = Path::new(pack);
+    let mut output = Vec::new();
+    Indexer::new().parallel_hashing(true).write(Cursor::new(&map(pack)?[..]), &mut output, Some(&repo.storage_set))?;
+    if stdout {
+        out.write_all(&output)?;
+    } else {
+        std::fs::write(pack.with_extension("idx"), &output)?;
+        // the pack's own checksum, as git prints it.
+        writeln!(out, "{}", Id::from(&output[output.len() - 40..output.len() - 20]))?;
+    }
+    Ok(())
+}
+
+fn run(dir: &Path, args: &[String], out: &mut dyn Write) -> Result<i32> {
+    let (command, rest) = match args.split_first() {
+        Some(xs) => xs,
+        None => return Err(usage())
+    };
+    match command.as_str() {
+        "cat-file" => cat_file(dir, rest, out),
+        "ls-tree" => ls_tree(dir, rest, out).map(|_| 0),
+        "rev-parse" => rev_parse(dir, rest, out).map(|_| 0),
+        "log" => log(dir, rest, out).map(|_| 0),
+        "verify-pack" => verify_pack(dir, rest, out).map(|_| 0),
+        "index-pack" => index_pack(dir, rest, out).map(|_| 0),
+        _ => Err(usage())
+    }
+}
+
+// Runs `args` in the repository at `dir`, returning the exit code git
+// would: 129 for usage errors and 128 for fatal ones.
+fn status(dir: &Path, args: &[String], out: &mut dyn Write, err: &mut dyn Write) -> i32 {
+    match run(dir, args, out) {
+        Ok(code) => code,
+        Err(ref e) if e.to_string() == USAGE => {
+            let _ = writeln!(err, "{}", USAGE);
+            129
+        },
+        // a closed pipe (`git-rs log | head`) just ends the output.
+        Err(ref e) if matches!(e.kind(), ErrorKind::Io(xs) if xs.kind() == io::ErrorKind::BrokenPipe) => 0,
+        Err(e) => {
+            let _ = writeln!(err, "fatal: {}", e);
+            128
+        }
+    }
+}
+
+pub fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let code = match std::env::current_dir() {
+        Ok(dir) => status(&dir, &args, &mut io::stdout().lock(), &mut io::stderr().lock()),
+        Err(e) => {
+            eprintln!("fatal: {}", e);
+            128
+        }
+    };
+    std::process::exit(code);
+}
+
+#[cfg(all(test, feature = "testkit"))]
+mod tests {
+    use std::path::Path;
+
+    use git_rs::testkit::{ RepoBuilder, TempDir };
+    use git_rs::stores::fs as gitfs;
+    use git_rs::objects::Type;
+    use git_rs::files;
+    use super::status;
+
+    // The exit code, stdout and stderr of running `args` in `dir`.
+    fn git_rs(dir: &Path, args: &[&str]) -> (i32, String, String) {
+        let args: Vec<String> = args.iter().map(|xs| String::from(*xs)).collect();
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let code = status(dir, &args, &mut out, &mut err);
+        (code, String::from_utf8(out).unwrap(), String::from_utf8(err).unwrap())
+    }
+
+    #[test]
+    fn exit_codes_follow_git() {
+        let dir = TempDir::new("cli-exit").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"])
+            .commit("second", files!["src/lib.rs" => "// lib\n"]);
+        let tip = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        let missing = "0000000000000000000000000000000000000000";
+
+        assert_eq!(git_rs(dir.path(), &["rev-parse", "master"]), (0, format!("{}\n", tip), String::new()));
+        let (code, out, _) = git_rs(dir.path(), &["log"]);
+        assert_eq!((code, out.lines().count()), (0, 2));
+        assert_eq!(git_rs(dir.path(), &["cat-file", "-e", "master"]).0, 0);
+        assert_eq!(git_rs(dir.path(), &["cat-file", "-e", missing]), (1, String::new(), String::new()));
+
+        let (code, out, err) = git_rs(dir.path(), &["cat-file", "-p", missing]);
+        assert_eq!((code, out.as_str()), (128, ""));
+        assert!(err.starts_with("fatal: "));
+        let (code, _, err) = git_rs(dir.path(), &["rev-parse", "master~5"]);
+        assert_eq!((code, err.as_str()), (128, "fatal: bad revision 'master~5'\n"));
+        let (code, _, err) = git_rs(dir.path(), &["ls-tree", "nope"]);
+        assert_eq!((code, err.as_str()), (128, "fatal: bad revision 'nope'\n"));
+
+        for args in &[&[][..], &["frobnicate"][..], &["cat-file", "-t"][..]] {
+            let (code, _, err) = git_rs(dir.path(), args);
+            assert_eq!(code, 129);
+            assert!(err.starts_with("usage: git-rs"));
+        }
+    }
+
+    #[test]
+    fn verify_pack_checks_every_pack() {
+        let dir = TempDir::new("cli-verify-pack").expect("failed to create tempdir");
+        RepoBuilder::new().write(dir.path()).expect("failed to write");
+        let good = gitfs::write_pack(dir.path(), &[(Type::Blob, b"one\n".to_vec())]).expect("failed to write pack");
+        let bad = gitfs::write_pack(dir.path(), &[(Type::Blob, b"two\n".to_vec())]).expect("failed to write pack");
+        let packs = dir.path().join(".git/objects/pack");
+        let (good, bad) = (packs.join(format!("pack-{}.pack", good)), packs.join(format!("pack-{}.pack", bad)));
+        // the bad pack's index describes the good pack.
+        std::fs::copy(good.with_extension("idx"), bad.with_extension("idx")).unwrap();
+
+        let (code, out, err) = git_rs(dir.path(), &["verify-pack", bad.to_str().unwrap(), good.to_str().unwrap()]);
+        assert_eq!(code, 128);
+        assert_eq!(out, format!("{}: ok\n", good.display()));
+        assert_eq!(err, format!("fatal: 1 item(s) failed: {}: index does not match the pack\n", bad.display()));
+    }
+}
@@ -21,7 +21,11 @@ pub fn main() -> std::io::Result<()> {
     let cursor = Cursor::new(&mmap[..]);
 
     let storage_set = gitfs::from(current_dir.as_path()).expect("failed to open storage");
-    write(cursor, &mut io::stdout(), Some(&storage_set));
+    // nothing reaches stdout unless the pack and its checksum check out.
+    if let Err(e) = write(cursor, &mut io::stdout(), Some(&storage_set)) {
+        eprintln!("fatal: {}", e);
+        std::process::exit(128);
+    }
 
     Ok(())
 }
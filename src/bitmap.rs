@@ -0,0 +1,347 @@
+use byteorder::{ BigEndian, ReadBytesExt, WriteBytesExt };
+use std::collections::{ HashMap, HashSet };
+use std::sync::{ Arc, Mutex };
+use std::path::Path;
+use std::io::Read;
+
+use crate::stores::{ Queryable, StorageSet };
+use crate::errors::{ ErrorKind, Result };
+use crate::objects::Object;
+use crate::stores::fs;
+use crate::id::Id;
+
+// A fixed-size set of object positions.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Bitmap {
+    words: Vec<u64>
+}
+
+impl Bitmap {
+    pub fn new() -> Bitmap {
+        Bitmap::default()
+    }
+
+    pub fn insert(&mut self, position: usize) -> bool {
+        let (word, bit) = (position / 64, position % 64);
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        let fresh = self.words[word] & (1 << bit) == 0;
+        self.words[word] |= 1 << bit;
+        fresh
+    }
+
+    pub fn contains(&self, position: usize) -> bool {
+        self.words.get(position / 64).is_some_and(|xs| xs & (1 << (position % 64)) != 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.iter().map(|xs| xs.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|xs| *xs == 0)
+    }
+
+    pub fn union(&self, other: &Bitmap) -> Bitmap {
+        let mut words = self.words.clone();
+        if other.words.len() > words.len() {
+            words.resize(other.words.len(), 0);
+        }
+        for (lhs, rhs) in words.iter_mut().zip(&other.words) {
+            *lhs |= rhs;
+        }
+        Bitmap { words }
+    }
+
+    pub fn intersection(&self, other: &Bitmap) -> Bitmap {
+        Bitmap {
+            words: self.words.iter().zip(&other.words).map(|(lhs, rhs)| lhs & rhs).collect()
+        }
+    }
+
+    // Positions in `self` but not in `other`.
+    pub fn difference(&self, other: &Bitmap) -> Bitmap {
+        Bitmap {
+            words: self.words.iter().enumerate().map(|(idx, xs)| xs & !other.words.get(idx).cloned().unwrap_or(0)).collect()
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(idx, word)| {
+            (0..64).filter(move |bit| word & (1 << bit) != 0).map(move |bit| idx * 64 + bit)
+        })
+    }
+
+    // The EWAH encoding of git's index extensions, as ewah_io.c lays it out:
+    // the bit count, then 64-bit words where each marker word holds a run of
+    // identical words (the low bit, then a 32-bit length) and the number of
+    // literal words after it. Positions from `limit` on are refused.
+    pub fn read_ewah<R: Read>(input: &mut R, limit: usize) -> Result<Bitmap> {
+        let size = input.read_u32::<BigEndian>()? as u64;
+        if size > limit as u64 {
+            return Err(ErrorKind::CorruptedIndex.into())
+        }
+        let count = input.read_u32::<BigEndian>()?;
+        let mut words = Vec::new();
+        for _ in 0..count {
+            words.push(input.read_u64::<BigEndian>()?);
+        }
+        // the position of the last marker, which only matters for appending.
+        input.read_u32::<BigEndian>()?;
+
+        let mut bitmap = Bitmap::new();
+        let mut at = 0u64;
+        let mut words = words.into_iter();
+        while let Some(marker) = words.next() {
+            let run = ((marker >> 1) & 0xffff_ffff) * 64;
+            if marker & 1 != 0 {
+                for position in at..(at + run).min(size) {
+                    bitmap.insert(position as usize);
+                }
+            }
+            at += run;
+            for _ in 0..marker >> 33 {
+                let word = words.next().ok_or(ErrorKind::CorruptedIndex)?;
+                for position in (0..64).filter(|xs| word & (1 << xs) != 0).map(|xs| at + xs).filter(|xs| *xs < size) {
+                    bitmap.insert(position as usize);
+                }
+                at += 64;
+            }
+        }
+        Ok(bitmap)
+    }
+
+    // Every word goes out as a literal after a single marker, which git
+    // reads as well as a compressed run.
+    pub fn write_ewah(&self, output: &mut Vec<u8>) {
+        let size = self.iter().last().map_or(0, |xs| xs + 1);
+        let words = &self.words[..size.div_ceil(64)];
+        output.write_u32::<BigEndian>(size as u32).unwrap();
+        output.write_u32::<BigEndian>(words.len() as u32 + 1).unwrap();
+        output.write_u64::<BigEndian>((words.len() as u64) << 33).unwrap();
+        for word in words {
+            output.write_u64::<BigEndian>(*word).unwrap();
+        }
+        output.write_u32::<BigEndian>(0).unwrap();
+    }
+}
+
+// Stable positions for a set of objects: sorted by id, as a multi-pack
+// index orders them. Bitmaps are only meaningful against the index that
+// produced them.
+#[derive(Debug, Default)]
+pub struct ObjectIndex {
+    ids: Vec<Id>
+}
+
+impl ObjectIndex {
+    pub fn from_ids<I: IntoIterator<Item = Id>>(ids: I) -> ObjectIndex {
+        let mut ids: Vec<Id> = ids.into_iter().collect();
+        ids.sort();
+        ids.dedup();
+        ObjectIndex { ids }
+    }
+
+    // Every packed object in the repository. Loose objects have no position.
+    pub fn from_path(path: &Path) -> std::result::Result<ObjectIndex, std::io::Error> {
+        let indices = fs::pack_indices(path)?;
+        Ok(ObjectIndex::from_ids(indices.iter().flat_map(|xs| xs.ids().iter().cloned())))
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    pub fn position(&self, id: &Id) -> Option<usize> {
+        self.ids.binary_search(id).ok()
+    }
+
+    pub fn id(&self, position: usize) -> Option<&Id> {
+        self.ids.get(position)
+    }
+}
+
+// Marks every indexed object reachable from `tip`. Walking stops at commits
+// in `known`, whose bitmaps are folded in instead.
+fn walk<S: Queryable>(
+    storage_set: &StorageSet<S>,
+    index: &ObjectIndex,
+    tip: &Id,
+    known: &HashMap<Id, Arc<Bitmap>>
+) -> Result<Bitmap> {
+    let mut bitmap = Bitmap::new();
+    // objects without a position still have to be walked exactly once.
+    let mut unindexed = HashSet::new();
+    let mut stack = vec![tip.clone()];
+    while let Some(id) = stack.pop() {
+        let fresh = match index.position(&id) {
+            Some(position) => bitmap.insert(position),
+            None => unindexed.insert(id.clone())
+        };
+        if !fresh {
+            continue
+        }
+        if let Some(xs) = known.get(&id) {
+            bitmap = bitmap.union(xs);
+            continue
+        }
+
+        match storage_set.get_and_load(&id)? {
+            Some(Object::Commit(commit)) => {
+                stack.extend(commit.parents().unwrap_or_default());
+                stack.extend(commit.tree());
+            },
+            Some(Object::Tree(tree)) => {
+                // gitlinks point into other repositories.
+                stack.extend(tree.into_iter().filter(|(_, xs)| !xs.mode.is_gitlink()).map(|(_, xs)| xs.id));
+            },
+            Some(Object::Tag(tag)) => stack.extend(tag.object()),
+            Some(Object::Blob(_)) => (),
+            None => return Err(ErrorKind::MissingObject.into())
+        }
+    }
+    Ok(bitmap)
+}
+
+pub fn reachable<S: Queryable>(storage_set: &StorageSet<S>, index: &ObjectIndex, tip: &Id) -> Result<Bitmap> {
+    walk(storage_set, index, tip, &HashMap::new())
+}
+
+// Reachability bitmaps per ref, recomputed only when the ref moves. A
+// recomputation reuses the bitmaps of every other cached tip it reaches,
+// so a fork's bitmap costs a walk of what it adds on top of its parent.
+pub struct BitmapCache {
+    index: ObjectIndex,
+    entries: Mutex<HashMap<String, (Id, Arc<Bitmap>)>>
+}
+
+impl BitmapCache {
+    pub fn new(index: ObjectIndex) -> BitmapCache {
+        BitmapCache {
+            index,
+            entries: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn index(&self) -> &ObjectIndex {
+        &self.index
+    }
+
+    pub fn get<S: Queryable>(&self, storage_set: &StorageSet<S>, name: &str, tip: &Id) -> Result<Arc<Bitmap>> {
+        let mut known: HashMap<Id, Arc<Bitmap>> = {
+            let entries = self.entries.lock().unwrap();
+            if let Some((cached, bitmap)) = entries.get(name) {
+                if cached == tip {
+                    return Ok(bitmap.clone())
+                }
+            }
+            entries.values().map(|(id, bitmap)| (id.clone(), bitmap.clone())).collect()
+        };
+
+        // another ref at the same tip already has the answer.
+        let shared = known.remove(tip);
+        let bitmap = Arc::new(match shared {
+            Some(xs) => (*xs).clone(),
+            None => walk(storage_set, &self.index, tip, &known)?
+        });
+        self.entries.lock().unwrap().insert(String::from(name), (tip.clone(), bitmap.clone()));
+        Ok(bitmap)
+    }
+
+    pub fn forget(&self, name: &str) {
+        self.entries.lock().unwrap().remove(name);
+    }
+
+    // Objects reachable from `name` that no ref in `others` reaches.
+    pub fn unique_to<S: Queryable>(&self, storage_set: &StorageSet<S>, name: (&str, &Id), others: &[(&str, &Id)]) -> Result<Bitmap> {
+        let mut rest = Bitmap::new();
+        for (other, tip) in others {
+            rest = rest.union(&*self.get(storage_set, other, tip)?);
+        }
+        Ok(self.get(storage_set, name.0, name.1)?.difference(&rest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::stores::fs as gitfs;
+    use crate::files;
+    use super::{ reachable, Bitmap, BitmapCache, ObjectIndex };
+
+    fn loose_ids(path: &std::path::Path) -> Vec<crate::id::Id> {
+        let mut ids = Vec::new();
+        for dir in std::fs::read_dir(path.join(".git/objects")).unwrap() {
+            let dir = dir.unwrap();
+            let prefix = dir.file_name().into_string().unwrap();
+            if prefix.len() != 2 {
+                continue
+            }
+            for entry in std::fs::read_dir(dir.path()).unwrap() {
+                let rest = entry.unwrap().file_name().into_string().unwrap();
+                ids.push(format!("{}{}", prefix, rest).parse().unwrap());
+            }
+        }
+        ids
+    }
+
+    #[test]
+    fn cached_bitmaps_compose() {
+        let dir = TempDir::new("bitmap").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n", "src/lib.rs" => "fn main() {}\n"]);
+        let first = builder.tip().unwrap();
+        let builder = builder.branch("fork").checkout("fork").commit("fork", files!["FORK" => "mine\n"]);
+        let fork = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let index = ObjectIndex::from_ids(loose_ids(dir.path()));
+        let upstream = reachable(&storage_set, &index, &first).expect("failed to walk");
+        // commit, root tree, src tree and two blobs
+        assert_eq!(upstream.len(), 5);
+
+        let cache = BitmapCache::new(index);
+        cache.get(&storage_set, "refs/heads/master", &first).expect("failed to compute");
+        let forked = cache.get(&storage_set, "refs/heads/fork", &fork).expect("failed to compute");
+        assert_eq!(*forked, reachable(&storage_set, cache.index(), &fork).unwrap());
+
+        let unique = cache.unique_to(&storage_set, ("refs/heads/fork", &fork), &[("refs/heads/master", &first)]).unwrap();
+        // the fork commit, its root tree and FORK
+        assert_eq!(unique.len(), 3);
+        assert!(unique.iter().all(|xs| !upstream.contains(xs)));
+        assert_eq!(upstream.union(&unique), *forked);
+        assert_eq!(forked.intersection(&upstream), upstream);
+        assert!(upstream.difference(&forked).is_empty());
+        assert!(cache.index().position(&fork).is_some_and(|xs| unique.contains(xs)));
+    }
+
+    #[test]
+    fn ewah_roundtrips() {
+        let mut bitmap = Bitmap::new();
+        for position in &[0, 5, 64, 200] {
+            bitmap.insert(*position);
+        }
+        let mut data = Vec::new();
+        bitmap.write_ewah(&mut data);
+        assert_eq!(Bitmap::read_ewah(&mut &data[..], 201).unwrap(), bitmap);
+        assert!(Bitmap::read_ewah(&mut &data[..], 200).is_err());
+
+        // a run of ones, then one literal word.
+        let mut data = Vec::new();
+        for word in &[66u32, 2] {
+            data.extend_from_slice(&word.to_be_bytes());
+        }
+        data.extend_from_slice(&(1u64 << 33 | 1 << 1 | 1).to_be_bytes());
+        data.extend_from_slice(&2u64.to_be_bytes());
+        data.extend_from_slice(&0u32.to_be_bytes());
+        let read = Bitmap::read_ewah(&mut &data[..], 66).unwrap();
+        assert_eq!(read.len(), 65);
+        assert!(read.contains(63) && read.contains(65) && !read.contains(64));
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::{ BinaryHeap, HashMap };
+use std::cmp::Reverse;
+use std::sync::{ Arc, Mutex };
+
+use crate::stores::{ Queryable, StorageSet };
+use crate::errors::{ ErrorKind, Result };
+use crate::diff::{ edits, Edit };
+use crate::patch::split_lines;
+use crate::objects::Object;
+use crate::id::Id;
+
+type Blames = HashMap<Id, Arc<Vec<Origin>>>;
+
+// Where a line of the blamed file came from: the commit that introduced
+// it, and its (zero-based) line number in that commit's version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Origin {
+    pub commit: Id,
+    pub line: usize
+}
+
+// The blob at `path` in a commit's tree, if there is one.
+fn blob_at<S: Queryable>(storage_set: &StorageSet<S>, commit: &Id, path: &[u8]) -> Result<Option<Id>> {
+    let mut id = match storage_set.get_and_load(commit)? {
+        Some(Object::Commit(xs)) => match xs.tree() {
+            Some(xs) => xs,
+            None => return Err(ErrorKind::MissingObject.into())
+        },
+        _ => return Err(ErrorKind::MissingObject.into())
+    };
+    for component in path.split(|xs| *xs == b'/') {
+        let tree = match storage_set.get_and_load(&id)? {
+            Some(Object::Tree(xs)) => xs,
+            Some(_) => return Ok(None),
+            None => return Err(ErrorKind::MissingObject.into())
+        };
+        id = match tree.entries().get(component) {
+            Some(xs) if !xs.mode.is_gitlink() => xs.id.clone(),
+            _ => return Ok(None)
+        };
+    }
+    Ok(Some(id))
+}
+
+struct Blamer<'a, S: Queryable> {
+    storage_set: &'a StorageSet<S>,
+    path: &'a [u8],
+    blobs: HashMap<Id, Arc<Vec<u8>>>
+}
+
+impl<'a, S: Queryable> Blamer<'a, S> {
+    fn contents(&mut self, id: &Id) -> Result<Arc<Vec<u8>>> {
+        if let Some(xs) = self.blobs.get(id) {
+            return Ok(xs.clone())
+        }
+        let contents = match self.storage_set.get_and_load(id)? {
+            Some(Object::Blob(xs)) => Arc::new(xs.contents),
+            _ => return Err(ErrorKind::MissingObject.into())
+        };
+        self.blobs.insert(id.clone(), contents.clone());
+        Ok(contents)
+    }
+
+    // Blames every line of `path` at `tip`. Lines that reach a commit in
+    // `known` take their origin from its blame instead of walking on, so
+    // only the history since a known blame is visited.
+    fn run(&mut self, tip: &Id, known: &Blames) -> Result<Vec<Origin>> {
+        let blob = match blob_at(self.storage_set, tip, self.path)? {
+            Some(xs) => xs,
+            None => return Err(ErrorKind::NoSuchPath(self.path.to_vec()).into())
+        };
+        let count = split_lines(&self.contents(&blob)?).len();
+        let mut result: Vec<Option<Origin>> = vec![None; count];
+
+        // lines still to be blamed, as (line of the result, line in the
+        // commit's version); commits are visited newest first, as git does,
+        // and in ascending id order within the same second.
+        let mut pending: HashMap<Id, (Id, Vec<(usize, usize)>)> = HashMap::new();
+        let mut queue = BinaryHeap::new();
+        pending.insert(tip.clone(), (blob, (0..count).map(|xs| (xs, xs)).collect()));
+        queue.push((0, Reverse(tip.clone())));
+
+        while let Some((_, Reverse(id))) = queue.pop() {
+            let (blob, mut lines) = match pending.remove(&id) {
+                Some(xs) => xs,
+                None => continue
+            };
+            if let Some(origins) = known.get(&id) {
+                for (target, line) in lines {
+                    result[target] = Some(origins[line].clone());
+                }
+                continue
+            }
+
+            let parents = match self.storage_set.get_and_load(&id)? {
+                Some(Object::Commit(xs)) => xs.parents().unwrap_or_default(),
+                _ => return Err(ErrorKind::MissingObject.into())
+            };
+            let contents = self.contents(&blob)?;
+            for parent in parents {
+                if lines.is_empty() {
+                    break
+                }
+                let parent_blob = match blob_at(self.storage_set, &parent, self.path)? {
+                    Some(xs) => xs,
+                    None => continue
+                };
+
+                // lines the parent already had are its to explain.
+                let passed = if parent_blob == blob {
+                    std::mem::take(&mut lines)
+                } else {
+                    let parent_contents = self.contents(&parent_blob)?;
+                    let mut mapping = HashMap::new();
+                    for edit in edits(&split_lines(&parent_contents), &split_lines(&contents)) {
+                        if let Edit::Equal(x, y) = edit {
+                            mapping.insert(y, x);
+                        }
+                    }
+                    let (passed, kept): (Vec<_>, Vec<_>) = lines.into_iter().partition(|(_, line)| mapping.contains_key(line));
+                    lines = kept;
+                    passed.into_iter().map(|(target, line)| (target, mapping[&line])).collect()
+                };
+                if passed.is_empty() {
+                    continue
+                }
+
+                let time = match self.storage_set.get_and_load(&parent)? {
+                    Some(Object::Commit(xs)) => xs.committer().map(|xs| xs.at().timestamp()).unwrap_or(0),
+                    _ => return Err(ErrorKind::MissingObject.into())
+                };
+                let entry = pending.entry(parent.clone()).or_insert_with(|| {
+                    queue.push((time, Reverse(parent.clone())));
+                    (parent_blob, Vec::new())
+                });
+                entry.1.extend(passed);
+            }
+
+            for (target, line) in lines {
+                result[target] = Some(Origin { commit: id.clone(), line });
+            }
+        }
+        Ok(result.into_iter().map(|xs| xs.expect("every line is blamed")).collect())
+    }
+}
+
+// The origin of every line of `path` at `commit`, following history without
+// rename detection.
+pub fn blame<S: Queryable>(storage_set: &StorageSet<S>, commit: &Id, path: &[u8]) -> Result<Vec<Origin>> {
+    let mut blamer = Blamer { storage_set, path, blobs: HashMap::new() };
+    blamer.run(commit, &HashMap::new())
+}
+
+// Blames kept per (path, commit) for editors that show blame inline. Once a
+// path is blamed at some commit, blaming it at a descendant only walks the
+// commits in between, and blaming unsaved contents only diffs them against
+// the commit they were edited from.
+#[derive(Default)]
+pub struct BlameCache {
+    entries: Mutex<HashMap<Vec<u8>, Blames>>
+}
+
+impl BlameCache {
+    pub fn new() -> BlameCache {
+        BlameCache::default()
+    }
+
+    pub fn get<S: Queryable>(&self, storage_set: &StorageSet<S>, commit: &Id, path: &[u8]) -> Result<Arc<Vec<Origin>>> {
+        let known = match self.entries.lock().unwrap().get(path) {
+            Some(xs) => xs.clone(),
+            None => HashMap::new()
+        };
+        if let Some(xs) = known.get(commit) {
+            return Ok(xs.clone())
+        }
+
+        let mut blamer = Blamer { storage_set, path, blobs: HashMap::new() };
+        let origins = Arc::new(blamer.run(commit, &known)?);
+        self.entries.lock().unwrap()
+            .entry(path.to_vec())
+            .or_default()
+            .insert(commit.clone(), origins.clone());
+        Ok(origins)
+    }
+
+    // Blames `contents`, an edited version of `path` at `commit`. Lines not
+    // in the commit have no origin yet.
+    pub fn contents<S: Queryable>(&self, storage_set: &StorageSet<S>, commit: &Id, path: &[u8], contents: &[u8]) -> Result<Vec<Option<Origin>>> {
+        let lines = split_lines(contents);
+        let mut result = vec![None; lines.len()];
+        let blob = match blob_at(storage_set, commit, path)? {
+            Some(xs) => xs,
+            None => return Ok(result)
+        };
+        let committed = match storage_set.get_and_load(&blob)? {
+            Some(Object::Blob(xs)) => xs.contents,
+            _ => return Err(ErrorKind::MissingObject.into())
+        };
+
+        let origins = self.get(storage_set, commit, path)?;
+        for edit in edits(&split_lines(&committed), &lines) {
+            if let Edit::Equal(x, y) = edit {
+                result[y] = Some(origins[x].clone());
+            }
+        }
+        Ok(result)
+    }
+
+    // Drops every blame of `path`, or of every path if None.
+    pub fn forget(&self, path: Option<&[u8]>) {
+        let mut entries = self.entries.lock().unwrap();
+        match path {
+            Some(xs) => {
+                entries.remove(xs);
+            },
+            None => entries.clear()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::testkit::RepoBuilder;
+    use crate::errors::ErrorKind;
+    use crate::files;
+    use super::{ blame, BlameCache, Origin };
+
+    #[test]
+    fn blames_lines_through_history_and_merges() {
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "a\nb\nc\n"]);
+        let first = builder.tip().unwrap();
+        let builder = builder.branch("side")
+            .commit("second", files!["README" => "a\nB\nc\n"]);
+        let second = builder.tip().unwrap();
+        let builder = builder.checkout("side")
+            .commit("side", files!["README" => "a\nb\nc\nd\n"]);
+        let builder = builder.checkout("master")
+            .commit("also d", files!["README" => "a\nB\nc\nd\n"]);
+        let also = builder.tip().unwrap();
+        let builder = builder.merge("merge", "side");
+        let merge = builder.tip().unwrap();
+        let (storage_set, _) = builder.in_memory();
+
+        let cache = BlameCache::new();
+        let at_second = cache.get(&storage_set, &second, b"README").expect("failed to blame");
+        assert_eq!(*at_second, vec![
+            Origin { commit: first.clone(), line: 0 },
+            Origin { commit: second.clone(), line: 1 },
+            Origin { commit: first.clone(), line: 2 }
+        ]);
+
+        let origins = cache.get(&storage_set, &merge, b"README").expect("failed to blame");
+        assert_eq!(*origins, blame(&storage_set, &merge, b"README").unwrap());
+        let commits: Vec<_> = origins.iter().map(|xs| xs.commit.clone()).collect();
+        // the merge took the side's version; both sides added "d" and the
+        // first parent is credited, as git does.
+        assert_eq!(commits, vec![first.clone(), first.clone(), first.clone(), also.clone()]);
+
+        let edited = cache.contents(&storage_set, &merge, b"README", b"new\na\nB\nd\n").expect("failed to blame");
+        assert_eq!(edited[0], None);
+        assert_eq!(edited[1], Some(Origin { commit: first.clone(), line: 0 }));
+        assert_eq!(edited[3].as_ref(), origins.get(3));
+        assert!(blame(&storage_set, &merge, b"missing").is_err());
+    }
+
+    #[test]
+    fn cached_blames_cut_the_walk_short() {
+        let builder = RepoBuilder::new()
+            .commit("first", files!["src/lib.rs" => "a\nb\n"]);
+        let first = builder.tip().unwrap();
+        let builder = builder.commit("second", files!["src/lib.rs" => "a\nb\nc\n"]);
+        let second = builder.tip().unwrap();
+        let (storage_set, _) = builder.in_memory();
+
+        // a planted blame of the first commit is taken as it is.
+        let cache = BlameCache::new();
+        let planted = vec![Origin { commit: second.clone(), line: 7 }, Origin { commit: second.clone(), line: 8 }];
+        cache.entries.lock().unwrap().entry(b"src/lib.rs".to_vec()).or_default().insert(first.clone(), Arc::new(planted.clone()));
+        let origins = cache.get(&storage_set, &second, b"src/lib.rs").expect("failed to blame");
+        assert_eq!(origins[..2], planted[..]);
+        assert_eq!(origins[2], Origin { commit: second.clone(), line: 2 });
+
+        cache.forget(Some(b"src/lib.rs"));
+        let origins = cache.get(&storage_set, &second, b"src/lib.rs").expect("failed to blame");
+        assert_eq!(*origins, blame(&storage_set, &second, b"src/lib.rs").unwrap());
+        assert_eq!(origins[0], Origin { commit: first.clone(), line: 0 });
+    }
+
+    #[test]
+    fn paths_that_come_and_go() {
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "a\n", "NOTES" => "x\n"]);
+        let builder = builder.remove("second", &["NOTES"]);
+        let second = builder.tip().unwrap();
+        let builder = builder.commit("third", files!["NOTES" => "x\ny\n"]);
+        let third = builder.tip().unwrap();
+        let (storage_set, _) = builder.in_memory();
+
+        // re-added lines start over at the commit that added them back.
+        let origins = blame(&storage_set, &third, b"NOTES").expect("failed to blame");
+        assert_eq!(origins, vec![Origin { commit: third.clone(), line: 0 }, Origin { commit: third.clone(), line: 1 }]);
+        match blame(&storage_set, &second, b"NOTES") {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::NoSuchPath(xs) if xs == b"NOTES")),
+            Ok(_) => panic!("expected no such path")
+        }
+        assert!(blame(&storage_set, &third, b"README/nested").is_err());
+
+        let cache = BlameCache::new();
+        assert_eq!(cache.contents(&storage_set, &second, b"NOTES", b"x\n").unwrap(), vec![None]);
+    }
+}
@@ -0,0 +1,68 @@
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::Arc;
+
+use crate::errors::{ ErrorKind, Result };
+
+// Lets another thread ask a long operation to stop. Operations check the
+// token between units of work (an object, a file) and fail with
+// `ErrorKind::Interrupted`, leaving things as an error at that point would:
+// an interrupted checkout stays pending, for instance. Clones share the
+// same flag.
+#[derive(Clone, Debug, Default)]
+pub struct Token(Arc<AtomicBool>);
+
+impl Token {
+    pub fn new() -> Token {
+        Token::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(ErrorKind::Interrupted.into())
+        }
+        Ok(())
+    }
+}
+
+// An existing flag, such as one a signal handler sets.
+impl From<Arc<AtomicBool>> for Token {
+    fn from(flag: Arc<AtomicBool>) -> Token {
+        Token(flag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{ AtomicBool, Ordering };
+    use std::sync::Arc;
+
+    use crate::errors::ErrorKind;
+    use super::Token;
+
+    #[test]
+    fn clones_and_flags_share_one_cancellation() {
+        let token = Token::new();
+        let clone = token.clone();
+        assert!(clone.check().is_ok());
+        token.cancel();
+        assert!(clone.is_cancelled());
+        match clone.check() {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::Interrupted)),
+            Ok(_) => panic!("expected the token to be cancelled")
+        }
+
+        let flag = Arc::new(AtomicBool::new(false));
+        let token = Token::from(flag.clone());
+        assert!(!token.is_cancelled());
+        flag.store(true, Ordering::Relaxed);
+        assert!(token.is_cancelled());
+    }
+}
@@ -0,0 +1,426 @@
+// A C ABI over the object store, refs and history walks, for embedding from
+// other languages. `cargo build --release --features capi` builds
+// libgit_rs as a cdylib and a staticlib; the header is include/git_rs.h
+// (`cbindgen --config cbindgen.toml --output include/git_rs.h`).
+//
+// Functions return GIT_RS_OK or a negative code; after GIT_RS_ERROR,
+// `git_rs_last_error` describes what went wrong on that thread. Ids are 20
+// raw bytes.
+use std::cell::RefCell;
+use std::ffi::{ CStr, CString };
+use std::os::raw::{ c_char, c_int };
+use std::panic::{ self, AssertUnwindSafe };
+use std::path::PathBuf;
+
+use crate::walk::commits::CommitIterator;
+use crate::stores::fs::{ self as gitfs, Backend, Storage };
+use crate::errors::{ ErrorKind, Result };
+use crate::objects::Type;
+use crate::refs::RefStore;
+use crate::id::Id;
+
+pub const GIT_RS_OK: c_int = 0;
+pub const GIT_RS_ERROR: c_int = -1;
+pub const GIT_RS_ENOTFOUND: c_int = -3;
+pub const GIT_RS_ITEROVER: c_int = -31;
+
+// git's numbers for the object types, as packs store them.
+pub const GIT_RS_OBJECT_COMMIT: c_int = 1;
+pub const GIT_RS_OBJECT_TREE: c_int = 2;
+pub const GIT_RS_OBJECT_BLOB: c_int = 3;
+pub const GIT_RS_OBJECT_TAG: c_int = 4;
+
+pub struct Repository {
+    storage_set: Storage,
+    refs: RefStore
+}
+
+pub struct Revwalk {
+    // borrows the repository it was made from, which must outlive it.
+    commits: CommitIterator<'static, Backend>
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|xs| *xs.borrow_mut() = message);
+}
+
+// Runs `f`, turning errors and panics into GIT_RS_ERROR: unwinding into C
+// is undefined behaviour.
+fn guard<F: FnOnce() -> Result<c_int>>(f: F) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(xs)) => xs,
+        Ok(Err(e)) => {
+            set_error(e.to_string());
+            GIT_RS_ERROR
+        },
+        Err(_) => {
+            set_error(String::from("panicked"));
+            GIT_RS_ERROR
+        }
+    }
+}
+
+unsafe fn read_id(id: *const u8) -> Id {
+    Id::from(std::slice::from_raw_parts(id, 20))
+}
+
+unsafe fn write_id(id: &Id, out: *mut u8) {
+    std::ptr::copy_nonoverlapping(id.as_ref().as_ptr(), out, 20);
+}
+
+unsafe fn read_str<'a>(xs: *const c_char) -> Result<&'a str> {
+    Ok(CStr::from_ptr(xs).to_str()?)
+}
+
+/// The message of the last GIT_RS_ERROR on this thread, valid until the
+/// next call that fails.
+#[no_mangle]
+pub extern "C" fn git_rs_last_error() -> *const c_char {
+    LAST_ERROR.with(|xs| xs.borrow().as_ptr())
+}
+
+/// Opens the repository whose worktree (or bare git dir) is at `path`.
+///
+/// # Safety
+///
+/// `path` must be a NUL terminated string and `out` valid to write to.
+#[no_mangle]
+pub unsafe extern "C" fn git_rs_repository_open(path: *const c_char, out: *mut *mut Repository) -> c_int {
+    guard(|| {
+        let path = PathBuf::from(read_str(path)?);
+        let repository = Repository {
+            storage_set: gitfs::from(&path)?,
+            refs: RefStore::new(&path)
+        };
+        *out = Box::into_raw(Box::new(repository));
+        Ok(GIT_RS_OK)
+    })
+}
+
+/// # Safety
+///
+/// `repository` must come from `git_rs_repository_open` (or be NULL), with
+/// no revwalks left on it.
+#[no_mangle]
+pub unsafe extern "C" fn git_rs_repository_free(repository: *mut Repository) {
+    if !repository.is_null() {
+        drop(Box::from_raw(repository));
+    }
+}
+
+/// Reads object `id`, setting `*typ` to a GIT_RS_OBJECT_* and `*data` to
+/// `*len` bytes to release with `git_rs_buffer_free`.
+///
+/// # Safety
+///
+/// `id` must point to 20 bytes and the out pointers be valid to write to.
+#[no_mangle]
+pub unsafe extern "C" fn git_rs_object_read(
+    repository: *const Repository,
+    id: *const u8,
+    typ: *mut c_int,
+    data: *mut *mut u8,
+    len: *mut usize
+) -> c_int {
+    guard(|| {
+        let mut output = Vec::new();
+        let object_type = match (*repository).storage_set.get(&read_id(id), &mut output)? {
+            Some(xs) => xs,
+            None => return Ok(GIT_RS_ENOTFOUND)
+        };
+        *typ = match object_type {
+            Type::Commit => GIT_RS_OBJECT_COMMIT,
+            Type::Tree => GIT_RS_OBJECT_TREE,
+            Type::Blob => GIT_RS_OBJECT_BLOB,
+            Type::Tag => GIT_RS_OBJECT_TAG
+        };
+        let output = output.into_boxed_slice();
+        *len = output.len();
+        *data = Box::into_raw(output) as *mut u8;
+        Ok(GIT_RS_OK)
+    })
+}
+
+/// # Safety
+///
+/// `data` and `len` must be as `git_rs_object_read` returned them.
+#[no_mangle]
+pub unsafe extern "C" fn git_rs_buffer_free(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(data, len)));
+    }
+}
+
+/// Resolves the ref `name` ("HEAD", "refs/heads/master") through symbolic
+/// refs into the 20 bytes at `out`.
+///
+/// # Safety
+///
+/// `name` must be a NUL terminated string and `out` valid for 20 bytes.
+#[no_mangle]
+pub unsafe extern "C" fn git_rs_ref_resolve(repository: *const Repository, name: *const c_char, out: *mut u8) -> c_int {
+    guard(|| {
+        match (*repository).refs.read(read_str(name)?)? {
+            Some(id) => {
+                write_id(&id, out);
+                Ok(GIT_RS_OK)
+            },
+            None => Ok(GIT_RS_ENOTFOUND)
+        }
+    })
+}
+
+/// Starts a walk of the history of commit `id`, newest first.
+///
+/// # Safety
+///
+/// `id` must point to 20 bytes and `out` be valid to write to. The walk
+/// must be freed before its repository.
+#[no_mangle]
+pub unsafe extern "C" fn git_rs_revwalk_new(repository: *const Repository, id: *const u8, out: *mut *mut Revwalk) -> c_int {
+    guard(|| {
+        let repository: &'static Repository = &*repository;
+        let id = read_id(id);
+        if repository.storage_set.get_and_load(&id)?.is_none() {
+            return Err(ErrorKind::MissingObject.into())
+        }
+        *out = Box::into_raw(Box::new(Revwalk { commits: repository.storage_set.commits(&id, None) }));
+        Ok(GIT_RS_OK)
+    })
+}
+
+/// Writes the next commit's id to `out`, or returns GIT_RS_ITEROVER.
+///
+/// # Safety
+///
+/// `walk` must come from `git_rs_revwalk_new` and `out` be valid for 20
+/// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn git_rs_revwalk_next(walk: *mut Revwalk, out: *mut u8) -> c_int {
+    guard(|| {
+        match (*walk).commits.next() {
+            Some((id, _)) => {
+                write_id(&id, out);
+                Ok(GIT_RS_OK)
+            },
+            None => Ok(GIT_RS_ITEROVER)
+        }
+    })
+}
+
+/// # Safety
+///
+/// `walk` must come from `git_rs_revwalk_new` (or be NULL).
+#[no_mangle]
+pub unsafe extern "C" fn git_rs_revwalk_free(walk: *mut Revwalk) {
+    if !walk.is_null() {
+        drop(Box::from_raw(walk));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{ CStr, CString };
+    use std::os::raw::{ c_char, c_int };
+    use std::path::Path;
+    use std::process::Command;
+
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::id::Id;
+    use crate::files;
+    use super::{
+        git_rs_buffer_free, git_rs_last_error, git_rs_object_read, git_rs_ref_resolve, git_rs_repository_free,
+        git_rs_repository_open, git_rs_revwalk_free, git_rs_revwalk_new, git_rs_revwalk_next, Repository, Revwalk,
+        GIT_RS_ENOTFOUND, GIT_RS_ERROR, GIT_RS_ITEROVER, GIT_RS_OBJECT_BLOB, GIT_RS_OBJECT_COMMIT,
+        GIT_RS_OBJECT_TAG, GIT_RS_OBJECT_TREE, GIT_RS_OK
+    };
+
+    const HEADER: &str = include_str!("../include/git_rs.h");
+
+    #[test]
+    fn reads_objects_refs_and_history() {
+        let dir = TempDir::new("capi").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"]);
+        let first = builder.tip().unwrap();
+        let builder = builder.commit("second", files!["README" => "goodbye\n"]);
+        let second = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        unsafe {
+            let path = CString::new(dir.path().to_str().unwrap()).unwrap();
+            let mut repository = std::ptr::null_mut();
+            assert_eq!(git_rs_repository_open(path.as_ptr(), &mut repository), GIT_RS_OK);
+
+            let mut head = [0u8; 20];
+            let name = CString::new("HEAD").unwrap();
+            assert_eq!(git_rs_ref_resolve(repository, name.as_ptr(), head.as_mut_ptr()), GIT_RS_OK);
+            assert_eq!(Id::from(&head[..]), second);
+            let missing = CString::new("refs/heads/nope").unwrap();
+            assert_eq!(git_rs_ref_resolve(repository, missing.as_ptr(), head.as_mut_ptr()), GIT_RS_ENOTFOUND);
+
+            let (mut typ, mut data, mut len) = (0, std::ptr::null_mut(), 0);
+            assert_eq!(git_rs_object_read(repository, second.as_ref().as_ptr(), &mut typ, &mut data, &mut len), GIT_RS_OK);
+            assert_eq!(typ, GIT_RS_OBJECT_COMMIT);
+            assert!(std::slice::from_raw_parts(data, len).ends_with(b"second\n"));
+            git_rs_buffer_free(data, len);
+            assert_eq!(git_rs_object_read(repository, [0u8; 20].as_ptr(), &mut typ, &mut data, &mut len), GIT_RS_ENOTFOUND);
+
+            let mut walk = std::ptr::null_mut();
+            assert_eq!(git_rs_revwalk_new(repository, second.as_ref().as_ptr(), &mut walk), GIT_RS_OK);
+            let mut ids = Vec::new();
+            let mut id = [0u8; 20];
+            while git_rs_revwalk_next(walk, id.as_mut_ptr()) == GIT_RS_OK {
+                ids.push(Id::from(&id[..]));
+            }
+            assert_eq!(ids, vec![second, first]);
+            git_rs_revwalk_free(walk);
+
+            assert_eq!(git_rs_revwalk_new(repository, [0u8; 20].as_ptr(), &mut walk), GIT_RS_ERROR);
+            assert!(!CStr::from_ptr(git_rs_last_error()).to_bytes().is_empty());
+            git_rs_repository_free(repository);
+        }
+    }
+
+    #[test]
+    fn header_matches_the_exports() {
+        // these fail to compile if a signature drifts from the header's.
+        let _: extern "C" fn() -> *const c_char = git_rs_last_error;
+        let _: unsafe extern "C" fn(*const c_char, *mut *mut Repository) -> c_int = git_rs_repository_open;
+        let _: unsafe extern "C" fn(*mut Repository) = git_rs_repository_free;
+        let _: unsafe extern "C" fn(*const Repository, *const u8, *mut c_int, *mut *mut u8, *mut usize) -> c_int = git_rs_object_read;
+        let _: unsafe extern "C" fn(*mut u8, usize) = git_rs_buffer_free;
+        let _: unsafe extern "C" fn(*const Repository, *const c_char, *mut u8) -> c_int = git_rs_ref_resolve;
+        let _: unsafe extern "C" fn(*const Repository, *const u8, *mut *mut Revwalk) -> c_int = git_rs_revwalk_new;
+        let _: unsafe extern "C" fn(*mut Revwalk, *mut u8) -> c_int = git_rs_revwalk_next;
+        let _: unsafe extern "C" fn(*mut Revwalk) = git_rs_revwalk_free;
+
+        let functions: Vec<&str> = HEADER.split(|xs: char| !xs.is_ascii_alphanumeric() && xs != '_')
+            .filter(|xs| xs.starts_with("git_rs_") && !xs.ends_with("repository") && !xs.ends_with("revwalk"))
+            .collect();
+        for name in &[
+            "git_rs_last_error", "git_rs_repository_open", "git_rs_repository_free", "git_rs_object_read",
+            "git_rs_buffer_free", "git_rs_ref_resolve", "git_rs_revwalk_new", "git_rs_revwalk_next", "git_rs_revwalk_free"
+        ] {
+            assert!(functions.contains(name), "{} is missing from the header", name);
+        }
+        let source = include_str!("capi.rs");
+        for name in &functions {
+            assert!(source.contains(&format!("extern \"C\" fn {}(", name)), "the header declares {} but nothing exports it", name);
+        }
+
+        let defines: Vec<(&str, c_int)> = HEADER.lines()
+            .filter_map(|xs| xs.strip_prefix("#define GIT_RS_"))
+            .filter_map(|xs| xs.split_once(' '))
+            .map(|(name, value)| (name, value.parse().unwrap()))
+            .collect();
+        assert_eq!(defines, vec![
+            ("OK", GIT_RS_OK),
+            ("ERROR", GIT_RS_ERROR),
+            ("ENOTFOUND", GIT_RS_ENOTFOUND),
+            ("ITEROVER", GIT_RS_ITEROVER),
+            ("OBJECT_COMMIT", GIT_RS_OBJECT_COMMIT),
+            ("OBJECT_TREE", GIT_RS_OBJECT_TREE),
+            ("OBJECT_BLOB", GIT_RS_OBJECT_BLOB),
+            ("OBJECT_TAG", GIT_RS_OBJECT_TAG)
+        ]);
+    }
+
+    #[test]
+    fn failures_are_codes_with_per_thread_messages() {
+        let dir = TempDir::new("capi-errors").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"]);
+        let tip = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        unsafe {
+            git_rs_repository_free(std::ptr::null_mut());
+            git_rs_revwalk_free(std::ptr::null_mut());
+            git_rs_buffer_free(std::ptr::null_mut(), 0);
+
+            let mut repository = std::ptr::null_mut();
+            let missing = CString::new(dir.path().join("nope").to_str().unwrap()).unwrap();
+            assert_eq!(git_rs_repository_open(missing.as_ptr(), &mut repository), GIT_RS_ERROR);
+            assert!(repository.is_null());
+            let message = CStr::from_ptr(git_rs_last_error()).to_owned();
+            assert!(!message.to_bytes().is_empty());
+            let invalid = CString::new(vec![0xff, 0xfe]).unwrap();
+            assert_eq!(git_rs_repository_open(invalid.as_ptr(), &mut repository), GIT_RS_ERROR);
+
+            // another thread's failure leaves this thread's message alone.
+            let message = CStr::from_ptr(git_rs_last_error()).to_owned();
+            std::thread::spawn(move || {
+                let mut repository = std::ptr::null_mut();
+                let other = CString::new("/nonexistent/elsewhere").unwrap();
+                assert_eq!(git_rs_repository_open(other.as_ptr(), &mut repository), GIT_RS_ERROR);
+            }).join().unwrap();
+            assert_eq!(CStr::from_ptr(git_rs_last_error()), message.as_c_str());
+
+            let path = CString::new(dir.path().to_str().unwrap()).unwrap();
+            assert_eq!(git_rs_repository_open(path.as_ptr(), &mut repository), GIT_RS_OK);
+            let invalid = CString::new(vec![b'r', 0xff]).unwrap();
+            let mut id = [0u8; 20];
+            assert_eq!(git_rs_ref_resolve(repository, invalid.as_ptr(), id.as_mut_ptr()), GIT_RS_ERROR);
+
+            // a finished walk stays finished.
+            let mut walk = std::ptr::null_mut();
+            assert_eq!(git_rs_revwalk_new(repository, tip.as_ref().as_ptr(), &mut walk), GIT_RS_OK);
+            assert_eq!(git_rs_revwalk_next(walk, id.as_mut_ptr()), GIT_RS_OK);
+            assert_eq!(Id::from(&id[..]), tip);
+            assert_eq!(git_rs_revwalk_next(walk, id.as_mut_ptr()), GIT_RS_ITEROVER);
+            assert_eq!(git_rs_revwalk_next(walk, id.as_mut_ptr()), GIT_RS_ITEROVER);
+            git_rs_revwalk_free(walk);
+            git_rs_repository_free(repository);
+        }
+    }
+
+    #[test]
+    fn c_programs_build_against_the_header_and_library() {
+        // the staticlib cargo built alongside this test, when there is one.
+        let exe = std::env::current_exe().unwrap();
+        let library = match exe.parent().and_then(Path::parent) {
+            Some(xs) => xs.join("libgit_rs.a"),
+            None => return
+        };
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let dir = TempDir::new("capi-c").expect("failed to create tempdir");
+        let program = dir.path().join("walk");
+        if !library.exists() {
+            return
+        }
+        let built = Command::new("cc")
+            .arg("-Wall").arg("-Werror")
+            .arg("-I").arg(root.join("include"))
+            .arg(root.join("fixtures/capi/walk.c"))
+            .arg(&library)
+            .args(&["-lpthread", "-ldl", "-lm"])
+            .arg("-o").arg(&program)
+            .status();
+        match built {
+            Ok(xs) => assert!(xs.success(), "walk.c failed to build"),
+            Err(_) => return
+        }
+
+        let repo = dir.path().join("repo");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"]);
+        let first = builder.tip().unwrap();
+        let builder = builder.commit("second", files!["README" => "goodbye\n"]);
+        let second = builder.tip().unwrap();
+        builder.write(&repo).expect("failed to write");
+
+        let output = Command::new(&program).arg(&repo).output().expect("failed to run walk");
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let output = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], second.to_string());
+        assert!(lines[1].starts_with(&format!("{} ", GIT_RS_OBJECT_COMMIT)));
+        assert_eq!(&lines[2..], &[second.to_string(), first.to_string()]);
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::{ BTreeMap, HashMap };
+use std::path::Path;
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::objects::tree::TreeEntry;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    // refuse the checkout before anything is written
+    Error,
+    // write later paths of a colliding group as `<path>~<short id>`
+    Rename,
+    // write only the first path of a colliding group
+    Skip
+}
+
+// Paths that name the same file on a case-insensitive or unicode-normalizing
+// filesystem. `paths[0]` is the one that gets written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Collision {
+    pub paths: Vec<Vec<u8>>
+}
+
+// The key a case-insensitive, normalization-insensitive filesystem would use.
+fn fold(entry_path: &[u8]) -> Vec<u8> {
+    match std::str::from_utf8(entry_path) {
+        Ok(xs) => xs.nfc().collect::<String>().to_lowercase().into_bytes(),
+        Err(_) => entry_path.to_ascii_lowercase()
+    }
+}
+
+pub fn detect<'a, I>(paths: I) -> Vec<Collision> where I: Iterator<Item = &'a Vec<u8>> {
+    let mut groups: BTreeMap<Vec<u8>, Vec<Vec<u8>>> = BTreeMap::new();
+    let mut dirs: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    let mut files = Vec::new();
+
+    for entry_path in paths {
+        let folded = fold(entry_path);
+        for (idx, byte) in entry_path.iter().enumerate() {
+            if *byte == b'/' {
+                dirs.entry(fold(&entry_path[..idx])).or_insert_with(|| entry_path[..idx].to_vec());
+            }
+        }
+        groups.entry(folded.clone()).or_default().push(entry_path.clone());
+        files.push(folded);
+    }
+
+    // a file can also collide with a directory: "foo" vs "FOO/bar".
+    for folded in files {
+        if let Some(dir) = dirs.get(&folded) {
+            let group = groups.get_mut(&folded).unwrap();
+            if !group.contains(dir) {
+                group.insert(0, dir.clone());
+            }
+        }
+    }
+
+    groups.into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|(_, paths)| Collision { paths })
+        .collect()
+}
+
+// Applies `policy` to the paths of `entries`; Error is handled by the caller.
+pub fn resolve(entries: &mut BTreeMap<Vec<u8>, TreeEntry>, collisions: &[Collision], policy: CollisionPolicy) {
+    for collision in collisions {
+        for entry_path in &collision.paths[1..] {
+            let entry = match entries.remove(entry_path) {
+                Some(xs) => xs,
+                None => continue
+            };
+
+            if policy == CollisionPolicy::Rename {
+                let mut renamed = entry_path.clone();
+                renamed.push(b'~');
+                renamed.extend_from_slice(&entry.id.to_string().as_bytes()[0..7]);
+                entries.insert(renamed, entry);
+            }
+        }
+    }
+}
+
+// Probes whether the filesystem at `path` folds case, the way git decides
+// core.ignorecase at init.
+pub fn folds_case(path: &Path) -> Result<bool, std::io::Error> {
+    let probe = path.join(format!(".git-rs-case-probe-{}", std::process::id()));
+    std::fs::write(probe.as_path(), b"")?;
+
+    let upper = path.join(format!(".GIT-RS-CASE-PROBE-{}", std::process::id()));
+    let folds = upper.exists();
+    std::fs::remove_file(probe)?;
+    Ok(folds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ detect, Collision };
+
+    fn paths(xs: &[&str]) -> Vec<Vec<u8>> {
+        xs.iter().map(|xs| xs.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn detects_case_collisions() {
+        let collisions = detect(paths(&["README", "readme", "src/lib.rs"]).iter());
+        assert_eq!(collisions, vec![Collision { paths: paths(&["README", "readme"]) }]);
+    }
+
+    #[test]
+    fn detects_normalization_collisions() {
+        // "é" precomposed (NFC) and decomposed (NFD)
+        let collisions = detect(paths(&["caf\u{e9}", "cafe\u{301}"]).iter());
+        assert_eq!(collisions.len(), 1);
+    }
+
+    #[test]
+    fn detects_file_directory_collisions() {
+        let collisions = detect(paths(&["docs", "DOCS/index.md"]).iter());
+        assert_eq!(collisions, vec![Collision { paths: paths(&["DOCS", "docs"]) }]);
+    }
+
+    #[test]
+    fn ignores_distinct_paths() {
+        assert!(detect(paths(&["Docs/a", "docs/b", "c"]).iter()).is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+use std::io::{ BufWriter, Write };
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::fs::{ File, OpenOptions };
+
+use crate::errors::{ ErrorKind, Result };
+use crate::worktree::git_dir;
+use crate::id::Id;
+
+// Checkout progress lives in `.git/CHECKOUT_JOURNAL` as NUL-terminated
+// records, since tree entry names may contain anything but NUL:
+//
+//     from <id or ->\0to <id>\0wr <path>\0wr <path>\0...
+//
+// A journal that still exists means the checkout never finished.
+#[derive(Debug, PartialEq)]
+pub struct Journal {
+    pub from: Option<Id>,
+    pub to: Id,
+    pub written: Vec<Vec<u8>>
+}
+
+pub fn path_for(path: &Path) -> Result<PathBuf> {
+    let mut root = git_dir(path)?;
+    root.push("CHECKOUT_JOURNAL");
+    Ok(root)
+}
+
+impl Journal {
+    pub fn read(path: &Path) -> Result<Option<Journal>> {
+        let buffer = match std::fs::read(path_for(path)?) {
+            Ok(xs) => xs,
+            Err(e) => {
+                return match e.kind() {
+                    std::io::ErrorKind::NotFound => Ok(None),
+                    _ => Err(e.into())
+                }
+            }
+        };
+
+        Journal::parse(&buffer).map(Some)
+    }
+
+    fn parse(buffer: &[u8]) -> Result<Journal> {
+        let mut records: Vec<&[u8]> = buffer.split(|xs| *xs == 0).collect();
+
+        // the last piece is either empty or a record cut short by the interruption.
+        records.pop();
+        let mut records = records.into_iter();
+
+        let from = match records.next() {
+            Some(b"from -") => None,
+            Some(xs) if xs.starts_with(b"from ") => Some(parse_id(&xs[5..])?),
+            _ => return Err(ErrorKind::CorruptedCheckoutJournal.into())
+        };
+
+        let to = match records.next() {
+            Some(xs) if xs.starts_with(b"to ") => parse_id(&xs[3..])?,
+            _ => return Err(ErrorKind::CorruptedCheckoutJournal.into())
+        };
+
+        let written = records
+            .filter(|xs| xs.starts_with(b"wr "))
+            .map(|xs| xs[3..].to_vec())
+            .collect();
+
+        Ok(Journal {
+            from,
+            to,
+            written
+        })
+    }
+}
+
+fn parse_id(bytes: &[u8]) -> Result<Id> {
+    match std::str::from_utf8(bytes) {
+        Ok(xs) => Id::from_str(xs),
+        Err(_) => Err(ErrorKind::CorruptedCheckoutJournal.into())
+    }
+}
+
+pub struct JournalWriter {
+    file: BufWriter<File>
+}
+
+impl JournalWriter {
+    pub fn create(path: &Path, from: Option<&Id>, to: &Id) -> Result<JournalWriter> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path_for(path)?)?;
+
+        let mut writer = JournalWriter {
+            file: BufWriter::new(file)
+        };
+        writer.header(from, to)?;
+        Ok(writer)
+    }
+
+    // Atomically swaps the current journal for a fresh one.
+    pub fn replace(path: &Path, from: Option<&Id>, to: &Id) -> Result<JournalWriter> {
+        let journal_path = path_for(path)?;
+        let tmp = journal_path.with_file_name("CHECKOUT_JOURNAL.new");
+
+        let mut writer = JournalWriter {
+            file: BufWriter::new(File::create(tmp.as_path())?)
+        };
+        writer.header(from, to)?;
+        std::fs::rename(tmp, journal_path)?;
+        Ok(writer)
+    }
+
+    fn header(&mut self, from: Option<&Id>, to: &Id) -> Result<()> {
+        match from {
+            Some(id) => write!(self.file, "from {}\0", id)?,
+            None => self.file.write_all(b"from -\0")?
+        }
+        write!(self.file, "to {}\0", to)?;
+        self.sync()
+    }
+
+    pub fn append(path: &Path) -> Result<JournalWriter> {
+        let file = OpenOptions::new()
+            .append(true)
+            .open(path_for(path)?)?;
+
+        Ok(JournalWriter {
+            file: BufWriter::new(file)
+        })
+    }
+
+    pub fn written(&mut self, entry_path: &[u8]) -> Result<()> {
+        self.file.write_all(b"wr ")?;
+        self.file.write_all(entry_path)?;
+        self.file.write_all(b"\0")?;
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    pub fn finish(mut self, path: &Path) -> Result<()> {
+        self.sync()?;
+        drop(self.file);
+        std::fs::remove_file(path_for(path)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::id::Id;
+    use super::Journal;
+
+    #[test]
+    fn parse_works() {
+        let to = Id::from(&[1u8; 20]);
+        let buffer = format!("from -\0to {}\0wr README\0wr src/lib.rs\0wr src/par", to);
+        let journal = Journal::parse(buffer.as_bytes()).expect("failed to parse");
+        assert_eq!(journal, Journal {
+            from: None,
+            to,
+            written: vec![b"README".to_vec(), b"src/lib.rs".to_vec()]
+        });
+    }
+
+    #[test]
+    fn parse_rejects_garbage() {
+        assert!(Journal::parse(b"to nowhere\0").is_err());
+    }
+}
@@ -0,0 +1,818 @@
+use std::collections::BTreeMap;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{ Path, PathBuf };
+use std::ffi::OsStr;
+use std::sync::Arc;
+use std::io::Write;
+use std::fs::File;
+
+use crate::stores::{ Queryable, StorageSet };
+use crate::errors::{ ErrorKind, Failures, Result };
+use crate::objects::tree::{ FileMode, TreeEntry };
+use crate::progress::{ self, Progress };
+use crate::filter::Filters;
+use crate::pathspec::Pathspec;
+use crate::sparse::Sparse;
+use crate::cancel::Token;
+use crate::walk::tree::TreeWalk;
+use crate::id::Id;
+
+pub mod journal;
+pub mod collisions;
+pub mod paths;
+pub mod modes;
+
+use self::collisions::{ Collision, CollisionPolicy };
+use self::paths::Limits;
+use self::modes::Modes;
+use self::journal::{ Journal, JournalWriter };
+
+// Materializes trees into the worktree rooted at `path`. Every run is
+// journaled (see `journal`), so an interrupted checkout can be finished with
+// `resume()` or undone with `rollback()`.
+pub struct Checkout<'a, S: Queryable> {
+    storage_set: &'a StorageSet<S>,
+    path: PathBuf,
+    collision_policy: CollisionPolicy,
+    ignore_case: Option<bool>,
+    limits: Limits,
+    keep_going: bool,
+    progress: Arc<dyn Progress>,
+    cancel: Token,
+    filters: Option<Arc<Filters>>,
+    modes: Option<Modes>,
+    sparse: Option<Sparse>,
+    pathspec: Pathspec
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub collisions: Vec<Collision>,
+    // removed gitlinks whose directories still held a submodule's checkout,
+    // left in place as git does ("unable to rmdir")
+    pub kept: Vec<Vec<u8>>
+}
+
+struct Plan {
+    previous: BTreeMap<Vec<u8>, TreeEntry>,
+    target: BTreeMap<Vec<u8>, TreeEntry>,
+    report: Report
+}
+
+impl<'a, S: Queryable> Checkout<'a, S> {
+    pub fn new(storage_set: &'a StorageSet<S>, path: &Path) -> Checkout<'a, S> {
+        Checkout {
+            storage_set,
+            path: path.to_path_buf(),
+            collision_policy: CollisionPolicy::Error,
+            ignore_case: None,
+            limits: Limits::platform(false),
+            keep_going: false,
+            progress: progress::noop(),
+            cancel: Token::new(),
+            filters: None,
+            modes: None,
+            sparse: None,
+            pathspec: Pathspec::new()
+        }
+    }
+
+    pub fn collision_policy(mut self, policy: CollisionPolicy) -> Checkout<'a, S> {
+        self.collision_policy = policy;
+        self
+    }
+
+    // Whether the worktree's filesystem folds case and unicode normalization.
+    // Probed from the worktree when not set.
+    pub fn ignore_case(mut self, ignore_case: bool) -> Checkout<'a, S> {
+        self.ignore_case = Some(ignore_case);
+        self
+    }
+
+    // core.longpaths; see `paths::Limits::platform`.
+    pub fn long_paths(mut self, long_paths: bool) -> Checkout<'a, S> {
+        self.limits = Limits::platform(long_paths);
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Checkout<'a, S> {
+        self.limits = limits;
+        self
+    }
+
+    // Writes every entry it can instead of stopping at the first failure,
+    // then fails with all of them (`ErrorKind::Failures`). The checkout
+    // stays pending, so `resume()` retries what failed.
+    pub fn keep_going(mut self, keep_going: bool) -> Checkout<'a, S> {
+        self.keep_going = keep_going;
+        self
+    }
+
+    // Reports "Updating files" as entries are written and removed.
+    pub fn progress(mut self, progress: Arc<dyn Progress>) -> Checkout<'a, S> {
+        self.progress = progress;
+        self
+    }
+
+    // Stops between entries once `cancel` is cancelled, failing with
+    // `ErrorKind::Interrupted`. The checkout stays pending, so `resume()`
+    // or `rollback()` can deal with what was written.
+    pub fn cancel(mut self, cancel: Token) -> Checkout<'a, S> {
+        self.cancel = cancel;
+        self
+    }
+
+    // Runs files through the smudge filters their `filter` attributes name
+    // as they are written.
+    pub fn filters(mut self, filters: Arc<Filters>) -> Checkout<'a, S> {
+        self.filters = Some(filters);
+        self
+    }
+
+    // What the filesystem can record; read from core.fileMode and
+    // core.symlinks when not set. Without symlinks, a symlink is written as
+    // a file holding its target.
+    pub fn modes(mut self, modes: Modes) -> Checkout<'a, S> {
+        self.modes = Some(modes);
+        self
+    }
+
+    // Only writes the paths `sparse` includes; read from core.sparseCheckout
+    // and `info/sparse-checkout` when not set. Paths it leaves out are
+    // removed if the previous tree had them.
+    pub fn sparse(mut self, sparse: Sparse) -> Checkout<'a, S> {
+        self.sparse = Some(sparse);
+        self
+    }
+
+    // Only touches the paths `pathspec` matches, as `git checkout
+    // --no-overlay <tree> -- <pathspec>` does: those are written, or removed
+    // if `to` lacks them, and everything else is left as it is.
+    pub fn pathspec(mut self, pathspec: Pathspec) -> Checkout<'a, S> {
+        self.pathspec = pathspec;
+        self
+    }
+
+    // `from` is the tree (or commit) currently in the worktree, if any; paths
+    // it has that `to` lacks are removed.
+    pub fn run(&self, from: Option<&Id>, to: &Id) -> Result<Report> {
+        if Journal::read(&self.path)?.is_some() {
+            return Err(ErrorKind::CheckoutInProgress.into())
+        }
+
+        let plan = self.plan(from, to)?;
+        let journal = JournalWriter::create(&self.path, from, to)?;
+        self.apply(plan, journal)
+    }
+
+    pub fn pending(&self) -> Result<Option<Journal>> {
+        Journal::read(&self.path)
+    }
+
+    // Finishes an interrupted checkout. Returns None if there was none.
+    pub fn resume(&self) -> Result<Option<Report>> {
+        let journal = match Journal::read(&self.path)? {
+            Some(xs) => xs,
+            None => return Ok(None)
+        };
+
+        let plan = self.plan(journal.from.as_ref(), &journal.to)?;
+        let writer = JournalWriter::append(&self.path)?;
+        self.apply(plan, writer).map(Some)
+    }
+
+    // Restores the worktree an interrupted checkout started from. Returns
+    // None if there was nothing to roll back.
+    pub fn rollback(&self) -> Result<Option<Report>> {
+        let journal = match Journal::read(&self.path)? {
+            Some(xs) => xs,
+            None => return Ok(None)
+        };
+
+        match journal.from {
+            Some(ref from) => {
+                // the reverse checkout is journaled too, so it can be resumed.
+                let plan = self.plan(Some(&journal.to), from)?;
+                let writer = JournalWriter::replace(&self.path, Some(&journal.to), from)?;
+                self.apply(plan, writer).map(Some)
+            },
+            None => {
+                for entry_path in &journal.written {
+                    self.remove_entry(entry_path)?;
+                }
+                std::fs::remove_file(journal::path_for(&self.path)?)?;
+                Ok(Some(Report::default()))
+            }
+        }
+    }
+
+    fn plan(&self, from: Option<&Id>, to: &Id) -> Result<Plan> {
+        let mut target = self.entries(to)?;
+        if let Some(entry_path) = target.keys().find(|xs| !paths::verify(xs)) {
+            return Err(ErrorKind::UnsafePath(entry_path.clone()).into())
+        }
+        let sparse = match self.sparse {
+            Some(ref xs) => Some(xs.clone()),
+            None => Sparse::from_path(&self.path)?
+        };
+        if let Some(sparse) = sparse {
+            target.retain(|entry_path, _| sparse.includes(entry_path));
+        }
+        let mut previous = match from {
+            Some(id) => self.entries(id)?,
+            None => BTreeMap::new()
+        };
+
+        let ignore_case = match self.ignore_case {
+            Some(xs) => xs,
+            None => collisions::folds_case(&self.path)?
+        };
+
+        let mut report = Report::default();
+        if ignore_case {
+            report.collisions = collisions::detect(target.keys());
+            if !report.collisions.is_empty() && self.collision_policy == CollisionPolicy::Error {
+                return Err(ErrorKind::PathCollision(report.collisions).into())
+            }
+            collisions::resolve(&mut target, &report.collisions, self.collision_policy);
+
+            // the previous checkout went through the same renames.
+            let previous_collisions = collisions::detect(previous.keys());
+            collisions::resolve(&mut previous, &previous_collisions, self.collision_policy);
+        }
+
+        // checked after renames, which lengthen paths.
+        let long_paths = paths::preflight(&self.path, target.keys(), self.limits);
+        if !long_paths.is_empty() {
+            return Err(ErrorKind::PathsTooLong(long_paths).into())
+        }
+
+        Ok(Plan {
+            previous,
+            target,
+            report
+        })
+    }
+
+    fn entries(&self, id: &Id) -> Result<BTreeMap<Vec<u8>, TreeEntry>> {
+        let walk = TreeWalk::new(self.storage_set).pathspec(self.pathspec.clone());
+        Ok(walk.entries(id)?.into_iter().collect())
+    }
+
+    fn apply(&self, plan: Plan, mut journal: JournalWriter) -> Result<Report> {
+        let Plan { previous, target, mut report } = plan;
+        let mut failures = Failures::new(self.keep_going);
+
+        let removed: Vec<&Vec<u8>> = previous.keys().filter(|xs| !target.contains_key(*xs)).collect();
+        let written: Vec<(&Vec<u8>, &TreeEntry)> = target.iter().filter(|(entry_path, entry)| {
+            match previous.get(*entry_path) {
+                Some(old) => !(old.mode == entry.mode && old.id == entry.id && self.exists(entry_path)),
+                None => true
+            }
+        }).collect();
+        let (mut count, mut bytes) = (0, 0);
+        self.progress.start("Updating files", Some((removed.len() + written.len()) as u64));
+
+        for entry_path in removed {
+            self.check_cancel(&mut journal)?;
+            if failures.check(entry_path, self.remove_entry(entry_path))? == Some(false) {
+                report.kept.push(entry_path.clone());
+            }
+            count += 1;
+            self.progress.update(count, bytes);
+        }
+
+        for (entry_path, entry) in written {
+            self.check_cancel(&mut journal)?;
+            if failures.check(entry_path, self.write_entry(entry_path, entry))?.is_some() {
+                journal.written(entry_path)?;
+                bytes += std::fs::symlink_metadata(self.full_path(entry_path)).map(|xs| xs.len()).unwrap_or(0);
+            }
+            count += 1;
+            self.progress.update(count, bytes);
+        }
+        self.progress.finish();
+
+        if !failures.is_empty() {
+            journal.sync()?;
+            return failures.finish().map(|_| report)
+        }
+        journal.finish(&self.path)?;
+        Ok(report)
+    }
+
+    fn check_cancel(&self, journal: &mut JournalWriter) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            journal.sync()?;
+            return Err(ErrorKind::Interrupted.into())
+        }
+        Ok(())
+    }
+
+    fn full_path(&self, entry_path: &[u8]) -> PathBuf {
+        self.path.join(OsStr::from_bytes(entry_path))
+    }
+
+    fn exists(&self, entry_path: &[u8]) -> bool {
+        std::fs::symlink_metadata(self.full_path(entry_path)).is_ok()
+    }
+
+    // Entries are checked before anything is joined to the worktree: none
+    // may leave it, name .git or go through a symlink.
+    pub(crate) fn write_entry(&self, entry_path: &[u8], entry: &TreeEntry) -> Result<()> {
+        if !paths::verify(entry_path) {
+            return Err(ErrorKind::UnsafePath(entry_path.to_vec()).into())
+        }
+        if paths::beyond_symlink(&self.path, entry_path) {
+            return Err(ErrorKind::BeyondSymlink(entry_path.to_vec()).into())
+        }
+        let full_path = self.full_path(entry_path);
+        if let Some(parent) = full_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        match std::fs::symlink_metadata(full_path.as_path()) {
+            Ok(ref metadata) if metadata.is_dir() => {
+                if entry.mode.is_gitlink() {
+                    return Ok(())
+                }
+                std::fs::remove_dir(full_path.as_path())?;
+            },
+            Ok(_) => std::fs::remove_file(full_path.as_path())?,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into())
+        }
+
+        if entry.mode.is_gitlink() {
+            std::fs::create_dir(full_path.as_path())?;
+            return Ok(())
+        }
+
+        if entry.mode == FileMode::SYMLINK && self.symlinks()? {
+            let mut target = Vec::new();
+            self.read_blob(&entry.id, &mut target)?;
+            std::os::unix::fs::symlink(OsStr::from_bytes(&target), full_path.as_path())?;
+            return Ok(())
+        }
+
+        let mut file = File::create(full_path.as_path())?;
+        match self.filters {
+            Some(ref filters) if entry.mode != FileMode::SYMLINK => {
+                let mut data = Vec::new();
+                self.read_blob(&entry.id, &mut data)?;
+                file.write_all(&filters.smudge(entry_path, data)?)?;
+            },
+            _ => self.read_blob(&entry.id, &mut file)?
+        }
+
+        if entry.mode == FileMode::EXECUTABLE {
+            use std::os::unix::fs::PermissionsExt;
+            let mut permissions = file.metadata()?.permissions();
+            let mode = permissions.mode();
+            permissions.set_mode(mode | ((mode & 0o444) >> 2));
+            file.set_permissions(permissions)?;
+        }
+
+        Ok(())
+    }
+
+    fn symlinks(&self) -> Result<bool> {
+        match self.modes {
+            Some(xs) => Ok(xs.symlinks),
+            None => Ok(Modes::from_path(&self.path)?.symlinks)
+        }
+    }
+
+    fn read_blob<W: std::io::Write>(&self, id: &Id, output: &mut W) -> Result<()> {
+        match self.storage_set.get(id, output)? {
+            Some(_) => Ok(()),
+            None => Err(ErrorKind::MissingObject.into())
+        }
+    }
+
+    // Behind a symlink there is nothing of ours to remove, as git's
+    // `unlink_entry` has it.
+    // False when the entry is a directory that isn't empty, a submodule's
+    // checkout say, which is left alone.
+    pub(crate) fn remove_entry(&self, entry_path: &[u8]) -> Result<bool> {
+        if !paths::verify(entry_path) {
+            return Err(ErrorKind::UnsafePath(entry_path.to_vec()).into())
+        }
+        if paths::beyond_symlink(&self.path, entry_path) {
+            return Ok(true)
+        }
+        let full_path = self.full_path(entry_path);
+        let removed = match std::fs::symlink_metadata(full_path.as_path()) {
+            Ok(ref metadata) if metadata.is_dir() => std::fs::remove_dir(full_path.as_path()),
+            Ok(_) => std::fs::remove_file(full_path.as_path()),
+            Err(e) => Err(e)
+        };
+
+        match removed {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(ref e) if e.kind() == std::io::ErrorKind::DirectoryNotEmpty => return Ok(false),
+            Err(e) => return Err(e.into()),
+            Ok(_) => ()
+        }
+
+        // prune directories the removal left empty.
+        let mut parent = full_path.parent();
+        while let Some(dir) = parent {
+            if dir == self.path.as_path() || std::fs::remove_dir(dir).is_err() {
+                break
+            }
+            parent = dir.parent();
+        }
+
+        Ok(true)
+    }
+}
+
+// Maps every path under a commit or tree to its entry.
+pub(crate) fn flatten<S: Queryable>(storage_set: &StorageSet<S>, id: &Id) -> Result<BTreeMap<Vec<u8>, TreeEntry>> {
+    Ok(TreeWalk::new(storage_set).entries(id)?.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use crate::testkit::{ executable, gitlink, symlink, RepoBuilder, TempDir };
+    use super::collisions::CollisionPolicy;
+    use super::paths::Limits;
+    use crate::stores::fs as gitfs;
+    use crate::objects::{ self, Type };
+    use crate::attributes::Attributes;
+    use crate::errors::ErrorKind;
+    use crate::filter::Filters;
+    use crate::pathspec::Pathspec;
+    use crate::config::Config;
+    use crate::files;
+    use crate::objects::tree::FileMode;
+    use super::journal::JournalWriter;
+    use super::modes::Modes;
+    use super::Checkout;
+
+    fn read(path: &Path, name: &str) -> String {
+        std::fs::read_to_string(path.join(name)).expect("failed to read file")
+    }
+
+    #[test]
+    fn checkout_works() {
+        let dir = TempDir::new("checkout").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n", "src/lib.rs" => "// lib\n"]);
+        let first = builder.tip().unwrap();
+        let builder = builder
+            .remove("second", &["src/lib.rs"])
+            .commit("third", vec![executable("run.sh", "#!/bin/sh\n"), symlink("link", "README")]);
+        let third = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let checkout = Checkout::new(&storage_set, dir.path());
+        checkout.run(None, &first).expect("failed to check out");
+        assert_eq!(read(dir.path(), "src/lib.rs"), "// lib\n");
+
+        checkout.run(Some(&first), &third).expect("failed to check out");
+        assert!(!dir.path().join("src").exists());
+        assert_eq!(read(dir.path(), "link"), "hello\n");
+        let mode = std::fs::metadata(dir.path().join("run.sh")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o100, 0o100);
+        assert!(checkout.pending().expect("failed to read journal").is_none());
+    }
+
+    #[test]
+    fn checkout_smudges_filtered_files() {
+        let dir = TempDir::new("checkout-filters").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files![".gitattributes" => "*.txt filter=upper\n", "a.txt" => "hello\n", "b.md" => "hello\n"]);
+        let first = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let config = Config::parse("[filter \"upper\"]\n\tsmudge = tr a-z A-Z\n").unwrap();
+        let attributes = Attributes::from_tree(&storage_set, &first).unwrap();
+        let filters = Arc::new(Filters::new(&config, attributes, dir.path()));
+        Checkout::new(&storage_set, dir.path()).filters(filters).run(None, &first).expect("failed to check out");
+        assert_eq!(read(dir.path(), "a.txt"), "HELLO\n");
+        assert_eq!(read(dir.path(), "b.md"), "hello\n");
+    }
+
+    #[test]
+    fn checkout_writes_symlinks_as_files_without_symlink_support() {
+        let dir = TempDir::new("checkout-symlinks").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", vec![symlink("link", "README"), executable("run.sh", "#!/bin/sh\n")]);
+        let first = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        std::fs::write(dir.path().join(".git/config"), "[core]\n\tsymlinks = false\n").unwrap();
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        Checkout::new(&storage_set, dir.path()).run(None, &first).expect("failed to check out");
+        let metadata = std::fs::symlink_metadata(dir.path().join("link")).unwrap();
+        assert!(metadata.file_type().is_file());
+        assert_eq!(read(dir.path(), "link"), "README");
+
+        let modes = Modes::from_path(dir.path()).unwrap();
+        assert_eq!(modes.stage(Some(FileMode::SYMLINK), FileMode::FILE), FileMode::SYMLINK);
+    }
+
+    #[test]
+    fn checkout_reports_progress() {
+        use std::sync::Arc;
+        use crate::progress::{ Log, Phase };
+
+        let dir = TempDir::new("checkout-progress").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n", "src/lib.rs" => "// lib\n"]);
+        let first = builder.tip().unwrap();
+        let builder = builder.commit("second", files!["README" => "hello\n", "NEW" => "new\n"]);
+        let second = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let log = Arc::new(Log::new());
+        let checkout = Checkout::new(&storage_set, dir.path()).progress(log.clone());
+        checkout.run(None, &first).expect("failed to check out");
+        checkout.run(Some(&first), &second).expect("failed to check out");
+        let phase = |count, bytes| Phase { name: String::from("Updating files"), total: Some(count), count, bytes, finished: true };
+        // the second run only has NEW to write.
+        assert_eq!(log.phases(), vec![phase(2, 13), phase(1, 4)]);
+    }
+
+    #[test]
+    fn cancelled_checkout_stays_pending() {
+        use std::sync::Arc;
+        use crate::progress::Progress;
+        use crate::cancel::Token;
+
+        // cancels as soon as the first entry is done.
+        struct CancelAfterOne(Token);
+        impl Progress for CancelAfterOne {
+            fn update(&self, count: u64, _bytes: u64) {
+                if count == 1 {
+                    self.0.cancel();
+                }
+            }
+        }
+
+        let dir = TempDir::new("checkout-cancel").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["A" => "a\n", "B" => "b\n", "C" => "c\n"]);
+        let first = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let token = Token::new();
+        let checkout = Checkout::new(&storage_set, dir.path())
+            .progress(Arc::new(CancelAfterOne(token.clone())))
+            .cancel(token);
+        match checkout.run(None, &first) {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::Interrupted)),
+            Ok(_) => panic!("expected the checkout to be interrupted")
+        }
+        assert!(dir.path().join("A").exists());
+        assert!(!dir.path().join("B").exists());
+        assert!(checkout.pending().expect("failed to read journal").is_some());
+
+        Checkout::new(&storage_set, dir.path()).resume().expect("failed to resume");
+        assert_eq!(read(dir.path(), "C"), "c\n");
+    }
+
+    #[test]
+    fn interrupted_checkout_resumes_and_rolls_back() {
+        let dir = TempDir::new("checkout-journal").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"]);
+        let first = builder.tip().unwrap();
+        let builder = builder.commit("second", files!["README" => "goodbye\n", "NEW" => "new\n"]);
+        let second = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let checkout = Checkout::new(&storage_set, dir.path());
+        checkout.run(None, &first).expect("failed to check out");
+
+        // simulate dying right after the journal was started.
+        JournalWriter::create(dir.path(), Some(&first), &second).expect("failed to start journal");
+        std::fs::write(dir.path().join("NEW"), "half").unwrap();
+        match checkout.run(Some(&first), &second) {
+            Err(e) => match e.kind() {
+                ErrorKind::CheckoutInProgress => (),
+                _ => panic!("unexpected error")
+            },
+            Ok(_) => panic!("expected checkout to refuse")
+        }
+
+        assert!(checkout.rollback().expect("failed to roll back").is_some());
+        assert_eq!(read(dir.path(), "README"), "hello\n");
+        assert!(!dir.path().join("NEW").exists());
+
+        JournalWriter::create(dir.path(), Some(&first), &second).expect("failed to start journal");
+        assert!(checkout.resume().expect("failed to resume").is_some());
+        assert_eq!(read(dir.path(), "README"), "goodbye\n");
+        assert_eq!(read(dir.path(), "NEW"), "new\n");
+        assert!(checkout.resume().expect("failed to resume").is_none());
+    }
+
+    #[test]
+    fn keep_going_reports_every_failure() {
+        let dir = TempDir::new("checkout-keep-going").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["a" => "a\n", "b" => "b\n", "c/d" => "d\n", "e" => "e\n"]);
+        let first = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        for contents in &["b\n", "d\n"] {
+            let hex = objects::hash(Type::Blob, contents.as_bytes()).to_string();
+            std::fs::remove_file(dir.path().join(".git/objects").join(&hex[..2]).join(&hex[2..])).unwrap();
+        }
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+
+        assert!(Checkout::new(&storage_set, dir.path()).run(None, &first).is_err());
+        assert!(!dir.path().join("e").exists());
+        Checkout::new(&storage_set, dir.path()).rollback().expect("failed to roll back");
+
+        let checkout = Checkout::new(&storage_set, dir.path()).keep_going(true);
+        match checkout.run(None, &first) {
+            Err(e) => match e.kind() {
+                ErrorKind::Failures(failures) => {
+                    let items: Vec<_> = failures.iter().map(|xs| xs.item.clone()).collect();
+                    assert_eq!(items, vec![b"b".to_vec(), b"c/d".to_vec()]);
+                    assert!(matches!(failures[0].error.kind(), ErrorKind::MissingObject));
+                },
+                _ => panic!("unexpected error")
+            },
+            Ok(_) => panic!("expected failures")
+        }
+        assert_eq!(read(dir.path(), "e"), "e\n");
+        assert!(checkout.pending().expect("failed to read journal").is_some());
+    }
+
+    #[test]
+    fn pathspecs_limit_what_is_touched() {
+        let dir = TempDir::new("checkout-pathspec").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["src/a.rs" => "a\n", "src/old.rs" => "old\n", "README" => "hello\n"]);
+        let first = builder.tip().unwrap();
+        let builder = builder.commit("second", files!["src/a.rs" => "a2\n", "src/b.c" => "b\n", "README" => "there\n"])
+            .remove("third", &["src/old.rs"]);
+        let second = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        Checkout::new(&storage_set, dir.path()).run(None, &first).expect("failed to check out");
+        let pathspec = Pathspec::parse(&["src", ":!*.c"]).unwrap();
+        Checkout::new(&storage_set, dir.path()).pathspec(pathspec).run(Some(&first), &second).expect("failed to check out");
+
+        assert_eq!(read(dir.path(), "src/a.rs"), "a2\n");
+        assert!(!dir.path().join("src/old.rs").exists());
+        assert!(!dir.path().join("src/b.c").exists());
+        assert_eq!(read(dir.path(), "README"), "hello\n");
+    }
+
+    #[test]
+    fn collisions_follow_policy() {
+        let dir = TempDir::new("checkout-collisions").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "upper\n", "readme" => "lower\n"]);
+        let first = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+
+        let checkout = Checkout::new(&storage_set, dir.path()).ignore_case(true);
+        match checkout.run(None, &first) {
+            Err(e) => match e.kind() {
+                ErrorKind::PathCollision(collisions) => assert_eq!(collisions[0].paths.len(), 2),
+                _ => panic!("unexpected error")
+            },
+            Ok(_) => panic!("expected collision error")
+        }
+        assert!(!dir.path().join("README").exists());
+        assert!(checkout.pending().expect("failed to read journal").is_none());
+
+        let checkout = checkout.collision_policy(CollisionPolicy::Skip);
+        let report = checkout.run(None, &first).expect("failed to check out");
+        assert_eq!(report.collisions.len(), 1);
+        assert_eq!(read(dir.path(), "README"), "upper\n");
+        assert!(!dir.path().join("readme").exists());
+
+        let checkout = Checkout::new(&storage_set, dir.path())
+            .ignore_case(true)
+            .collision_policy(CollisionPolicy::Rename);
+        checkout.run(Some(&first), &first).expect("failed to check out");
+        let renamed: Vec<_> = std::fs::read_dir(dir.path()).unwrap()
+            .filter_map(|xs| xs.ok())
+            .map(|xs| xs.file_name().into_string().unwrap())
+            .filter(|xs| xs.starts_with("readme~"))
+            .collect();
+        assert_eq!(renamed.len(), 1);
+    }
+
+    #[test]
+    fn long_paths_are_refused_up_front() {
+        let dir = TempDir::new("checkout-long").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["short" => "ok\n", "deeply/nested/file" => "a\n", "averyverylongname" => "b\n"]);
+        let first = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+
+        let root_len = dir.path().to_string_lossy().len() + 1;
+        let checkout = Checkout::new(&storage_set, dir.path())
+            .limits(Limits { max_path: root_len + 12, max_component: 12 });
+        match checkout.run(None, &first) {
+            Err(e) => match e.kind() {
+                ErrorKind::PathsTooLong(paths) => {
+                    let names: Vec<_> = paths.iter().map(|xs| xs.path.as_slice()).collect();
+                    assert_eq!(names, vec![&b"averyverylongname"[..], &b"deeply/nested/file"[..]]);
+                },
+                _ => panic!("unexpected error")
+            },
+            Ok(_) => panic!("expected long path error")
+        }
+        assert!(!dir.path().join("short").exists());
+        assert!(checkout.pending().expect("failed to read journal").is_none());
+    }
+
+    #[test]
+    fn crafted_trees_stay_in_the_worktree() {
+        use crate::objects::tree::{ FileMode, Tree, TreeEntry };
+
+        let dir = TempDir::new("checkout-crafted").expect("failed to create tempdir");
+        let outside = TempDir::new("checkout-crafted-outside").expect("failed to create tempdir");
+        let worktree = dir.path().join("repo");
+        RepoBuilder::new().write(&worktree).expect("failed to write");
+        let blob = gitfs::write_loose(&worktree, Type::Blob, b"payload\n").unwrap();
+        let write_tree = |entries: Vec<(&str, FileMode, &super::Id)>| {
+            let mut tree = Tree::new();
+            for (name, mode, id) in entries {
+                tree.insert(name.as_bytes().to_vec(), TreeEntry { mode, id: id.clone() });
+            }
+            let mut data = Vec::new();
+            tree.write(&mut data).unwrap();
+            gitfs::write_loose(&worktree, Type::Tree, &data).unwrap()
+        };
+        let hooks = write_tree(vec![("post-checkout", FileMode::EXECUTABLE, &blob)]);
+        let git = write_tree(vec![("hooks", FileMode::from_bits(0o40000), &hooks)]);
+        let escaped = write_tree(vec![("escaped", FileMode::FILE, &blob)]);
+        let target = gitfs::write_loose(&worktree, Type::Blob, outside.path().to_string_lossy().as_bytes()).unwrap();
+
+        for tree in vec![
+            write_tree(vec![("..", FileMode::from_bits(0o40000), &escaped)]),
+            write_tree(vec![(".GIT", FileMode::from_bits(0o40000), &git)]),
+            write_tree(vec![("", FileMode::FILE, &blob)])
+        ] {
+            let storage_set = gitfs::from(&worktree).expect("failed to open storage");
+            match Checkout::new(&storage_set, &worktree).run(None, &tree) {
+                Err(e) => match e.kind() {
+                    ErrorKind::UnsafePath(_) => (),
+                    xs => panic!("unexpected error {:?}", xs)
+                },
+                Ok(_) => panic!("expected unsafe path error")
+            }
+        }
+        assert!(!dir.path().join("escaped").exists());
+        assert!(!worktree.join(".git/hooks/post-checkout").exists());
+
+        // a symlink, then a file through it.
+        let tree = write_tree(vec![("a", FileMode::SYMLINK, &target), ("a/x", FileMode::FILE, &blob)]);
+        let storage_set = gitfs::from(&worktree).expect("failed to open storage");
+        match Checkout::new(&storage_set, &worktree).run(None, &tree) {
+            Err(e) => match e.kind() {
+                ErrorKind::BeyondSymlink(path) => assert_eq!(path, b"a/x"),
+                xs => panic!("unexpected error {:?}", xs)
+            },
+            Ok(_) => panic!("expected symlink error")
+        }
+        assert!(!outside.path().join("x").exists());
+    }
+
+    #[test]
+    fn removed_gitlinks_keep_populated_checkouts() {
+        let dir = TempDir::new("checkout-gitlinks").expect("failed to create tempdir");
+        let lib = RepoBuilder::new().commit("lib", files!["lib.rs" => "// lib\n"]);
+        let lib_tip = lib.tip().unwrap();
+        let builder = RepoBuilder::new()
+            .commit("first", vec![gitlink("vendor/lib", &lib_tip), gitlink("empty", &lib_tip)]);
+        let first = builder.tip().unwrap();
+        let builder = builder.remove("second", &["vendor/lib", "empty"]).commit("third", files!["README" => "hello\n"]);
+        let third = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let checkout = Checkout::new(&storage_set, dir.path());
+        checkout.run(None, &first).expect("failed to check out");
+        assert!(dir.path().join("vendor/lib").is_dir());
+        lib.write(&dir.path().join("vendor/lib")).expect("failed to write lib");
+
+        let report = checkout.run(Some(&first), &third).expect("failed to check out");
+        assert_eq!(report.kept, vec![b"vendor/lib".to_vec()]);
+        assert!(dir.path().join("vendor/lib/.git/HEAD").exists());
+        assert!(!dir.path().join("empty").exists());
+        assert_eq!(read(dir.path(), "README"), "hello\n");
+        assert!(checkout.pending().expect("failed to read journal").is_none());
+    }
+}
@@ -0,0 +1,102 @@
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::objects::tree::FileMode;
+use crate::errors::Result;
+use crate::config::Config;
+
+// What the worktree's filesystem can record: core.fileMode (executable
+// bits) and core.symlinks. Where it can't, the modes the index or HEAD
+// recorded are kept instead of what the filesystem reports, and symlinks
+// are checked out as plain files holding their target, as git does on
+// Windows and FAT.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Modes {
+    pub file_mode: bool,
+    pub symlinks: bool
+}
+
+impl Default for Modes {
+    fn default() -> Modes {
+        Modes {
+            file_mode: true,
+            symlinks: true
+        }
+    }
+}
+
+impl Modes {
+    pub fn from_config(config: &Config) -> Modes {
+        Modes {
+            file_mode: config.get_bool("core.filemode").unwrap_or(true),
+            symlinks: config.get_bool("core.symlinks").unwrap_or(true)
+        }
+    }
+
+    // The repository's settings; a missing config has both.
+    pub fn from_path(path: &Path) -> Result<Modes> {
+        Ok(Modes::from_config(&Config::from_path(path)?))
+    }
+
+    // Tries out directory `dir`, as `git init` does to pick the settings.
+    pub fn probe(dir: &Path) -> Result<Modes> {
+        let probe = dir.join("probe-modes");
+        std::fs::write(&probe, b"")?;
+        let file_mode = is_executable_kept(&probe);
+        std::fs::remove_file(&probe)?;
+
+        let link = dir.join("probe-symlink");
+        let symlinks = std::os::unix::fs::symlink("probe-modes", &link).is_ok();
+        if symlinks {
+            std::fs::remove_file(&link)?;
+        }
+        Ok(Modes { file_mode, symlinks })
+    }
+
+    // The mode to stage a worktree file as, given what the filesystem
+    // reports and what was recorded for it before, if anything.
+    pub fn stage(&self, recorded: Option<FileMode>, actual: FileMode) -> FileMode {
+        let recorded = match recorded {
+            Some(xs) => xs,
+            None => return actual
+        };
+        let regular = |xs: FileMode| xs == FileMode::FILE || xs == FileMode::EXECUTABLE;
+        if !self.file_mode && regular(recorded) && regular(actual) {
+            return recorded
+        }
+        // the file holding a symlink's target is still the symlink.
+        if !self.symlinks && recorded == FileMode::SYMLINK && regular(actual) {
+            return recorded
+        }
+        actual
+    }
+}
+
+fn is_executable_kept(path: &Path) -> bool {
+    let set = std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755));
+    set.is_ok() && std::fs::metadata(path).is_ok_and(|xs| xs.permissions().mode() & 0o100 != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::objects::tree::FileMode;
+    use crate::testkit::TempDir;
+    use crate::config::Config;
+    use super::Modes;
+
+    #[test]
+    fn recorded_modes_win_where_the_filesystem_cannot_tell() {
+        let modes = Modes::from_config(&Config::parse("[core]\n\tfilemode = false\n\tsymlinks = false\n").unwrap());
+        assert_eq!(modes, Modes { file_mode: false, symlinks: false });
+        assert_eq!(modes.stage(Some(FileMode::EXECUTABLE), FileMode::FILE), FileMode::EXECUTABLE);
+        assert_eq!(modes.stage(Some(FileMode::FILE), FileMode::EXECUTABLE), FileMode::FILE);
+        assert_eq!(modes.stage(Some(FileMode::SYMLINK), FileMode::FILE), FileMode::SYMLINK);
+        assert_eq!(modes.stage(None, FileMode::EXECUTABLE), FileMode::EXECUTABLE);
+        assert_eq!(Modes::default().stage(Some(FileMode::FILE), FileMode::EXECUTABLE), FileMode::EXECUTABLE);
+
+        let dir = TempDir::new("modes").expect("failed to create tempdir");
+        let probed = Modes::probe(dir.path()).expect("failed to probe");
+        assert_eq!(probed, Modes::default());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
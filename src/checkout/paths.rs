@@ -0,0 +1,164 @@
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ffi::OsStr;
+
+// Length limits of the filesystem a worktree lives on, in bytes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub max_path: usize,
+    pub max_component: usize
+}
+
+impl Limits {
+    // `long_paths` mirrors core.longpaths, which only lifts Windows' MAX_PATH
+    // and so changes nothing here: the crate is unix-only.
+    pub fn platform(_long_paths: bool) -> Limits {
+        Limits {
+            max_path: 4095,
+            max_component: 255
+        }
+    }
+}
+
+// A tree path that cannot be written under `Limits`. `component` is set when
+// a single path component is over the limit rather than the whole path.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LongPath {
+    pub path: Vec<u8>,
+    pub component: Option<Vec<u8>>,
+    pub length: usize,
+    pub limit: usize
+}
+
+// Checks every path as it would be written below `root`.
+pub fn preflight<'a, I>(root: &Path, paths: I, limits: Limits) -> Vec<LongPath> where I: Iterator<Item = &'a Vec<u8>> {
+    let root_len = root.as_os_str().len() + 1;
+    let mut long_paths = Vec::new();
+
+    for entry_path in paths {
+        if let Some(component) = entry_path.split(|xs| *xs == b'/').find(|xs| xs.len() > limits.max_component) {
+            long_paths.push(LongPath {
+                path: entry_path.clone(),
+                component: Some(component.to_vec()),
+                length: component.len(),
+                limit: limits.max_component
+            });
+            continue
+        }
+
+        let length = root_len + entry_path.len();
+        if length > limits.max_path {
+            long_paths.push(LongPath {
+                path: entry_path.clone(),
+                component: None,
+                length,
+                limit: limits.max_path
+            });
+        }
+    }
+
+    long_paths
+}
+
+// Code points HFS+ ignores when comparing names, so ".g\u{200c}it" is
+// ".git" there.
+fn hfs_ignorable(c: char) -> bool {
+    matches!(c, '\u{200c}'..='\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{206a}'..='\u{206f}' | '\u{feff}')
+}
+
+// Whether a path component names the git dir on some filesystem: exactly,
+// in another case, with HFS+ ignorable code points, or under NTFS with
+// trailing dots and spaces, an alternate data stream or its 8.3 short name.
+fn is_dot_git(component: &[u8]) -> bool {
+    let name: String = String::from_utf8_lossy(component).chars().filter(|xs| !hfs_ignorable(*xs)).collect();
+    let name = name.to_lowercase();
+    let name = name.split(':').next().unwrap_or("");
+    let name = name.trim_end_matches(['.', ' ']);
+    name == ".git" || name == "git~1"
+}
+
+// Whether a tree or patch path is safe to write below a worktree, as git's
+// `verify_path` decides: relative, with no empty, "." or ".." components
+// and none naming the git dir. Backslashes separate components too, as
+// they do on Windows.
+pub fn verify(path: &[u8]) -> bool {
+    !path.is_empty() && path.split(|xs| *xs == b'/' || *xs == b'\\').all(|component| {
+        !component.is_empty() && component != b"." && component != b".." && !is_dot_git(component)
+    })
+}
+
+// Whether a directory leading to `path` below `root` is a symlink, which
+// writing or removing `path` would go through: git's
+// `has_symlinks_leading_path`. Missing directories end the search.
+pub fn beyond_symlink(root: &Path, path: &[u8]) -> bool {
+    let mut leading = root.to_path_buf();
+    let mut components: Vec<&[u8]> = path.split(|xs| *xs == b'/').collect();
+    components.pop();
+    for component in components {
+        leading.push(OsStr::from_bytes(component));
+        match std::fs::symlink_metadata(&leading) {
+            Ok(ref metadata) if metadata.file_type().is_symlink() => return true,
+            Ok(_) => (),
+            Err(_) => return false
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::testkit::TempDir;
+    use super::{ beyond_symlink, preflight, verify, Limits, LongPath };
+
+    fn paths(xs: &[&str]) -> Vec<Vec<u8>> {
+        xs.iter().map(|xs| xs.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn preflight_works() {
+        let limits = Limits { max_path: 20, max_component: 8 };
+        let long_paths = preflight(Path::new("/repo"), paths(&["a/b", "a/toolongname", "abc/def/ghi/jkl"]).iter(), limits);
+        assert_eq!(long_paths, vec![
+            LongPath {
+                path: b"a/toolongname".to_vec(),
+                component: Some(b"toolongname".to_vec()),
+                length: 11,
+                limit: 8
+            },
+            LongPath {
+                path: b"abc/def/ghi/jkl".to_vec(),
+                component: None,
+                length: 21,
+                limit: 20
+            }
+        ]);
+    }
+
+    #[test]
+    fn unsafe_paths_are_refused() {
+        for path in &["README", "a/b/c", ".gitignore", "a/.github/x", "git~10", "...."] {
+            assert!(verify(path.as_bytes()), "{}", path);
+        }
+        for path in &["", "/etc/passwd", "a//b", "a/", "./a", "a/../../b", "..", ".git/hooks/x", "a/.GIT/config",
+                      ".git./x", ".git /x", "GIT~1/x", ".git::$INDEX_ALLOCATION/x", ".g\u{200c}it/x", "a\\..\\b"] {
+            assert!(!verify(path.as_bytes()), "{}", path);
+        }
+
+        let dir = TempDir::new("paths-symlinks").expect("failed to create tempdir");
+        std::fs::create_dir(dir.path().join("real")).unwrap();
+        std::os::unix::fs::symlink("/tmp", dir.path().join("real/link")).unwrap();
+        assert!(!beyond_symlink(dir.path(), b"real/file"));
+        assert!(!beyond_symlink(dir.path(), b"real/link"));
+        assert!(beyond_symlink(dir.path(), b"real/link/file"));
+        assert!(!beyond_symlink(dir.path(), b"missing/link/file"));
+    }
+
+    #[test]
+    fn platform_limits_work() {
+        let limits = Limits::platform(false);
+        assert!(limits.max_component <= limits.max_path);
+        assert!(Limits::platform(true).max_path >= limits.max_path);
+    }
+}
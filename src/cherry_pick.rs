@@ -0,0 +1,529 @@
+use std::collections::{ BTreeMap, BTreeSet };
+use std::path::Path;
+
+use crate::objects::tree::{ write_nested, TreeEntry };
+use crate::merge::tree::{ merge_trees, Conflict };
+use crate::stores::{ Queryable, StorageSet };
+use crate::errors::{ ErrorKind, Result };
+use crate::refs::RefStore;
+use crate::objects::commit::Commit;
+use crate::objects::{ self, Object, Type };
+use crate::stash::read_worktree_as;
+use crate::checkout::modes::Modes;
+use crate::stores::fs as gitfs;
+use crate::identity::Identity;
+use crate::checkout::{ flatten, Checkout };
+use crate::merge::file;
+use crate::abbrev::{ self, abbreviate };
+use crate::index::{ Entry, Index };
+use crate::{ reflog, worktree };
+use crate::id::Id;
+
+const CHERRY_PICK_HEAD: &str = "CHERRY_PICK_HEAD";
+const REVERT_HEAD: &str = "REVERT_HEAD";
+const MERGE_MSG: &str = "MERGE_MSG";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    CherryPick,
+    Revert
+}
+
+impl Operation {
+    fn state_file(self) -> &'static str {
+        match self {
+            Operation::CherryPick => CHERRY_PICK_HEAD,
+            Operation::Revert => REVERT_HEAD
+        }
+    }
+
+    fn reflog_prefix(self) -> &'static str {
+        match self {
+            Operation::CherryPick => "cherry-pick",
+            Operation::Revert => "revert"
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    // the parent (from 1) a merge commit's change is taken against, as -m
+    pub mainline: Option<usize>,
+    // leave the result in the worktree and index instead of committing, as -n
+    pub no_commit: bool,
+    // end a cherry-picked message with "(cherry picked from commit ...)", as -x
+    pub record_origin: bool,
+    pub merge: file::Options
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    Committed(Id),
+    // applied cleanly, but `no_commit` was asked for
+    Applied,
+    // the worktree and index hold the conflicts; settle them in the index,
+    // then `finish` (or `abort`)
+    Conflicts(Vec<Conflict>)
+}
+
+fn load_commit<S: Queryable>(storage_set: &StorageSet<S>, id: &Id) -> Result<Commit> {
+    match storage_set.get_and_load(id)? {
+        Some(Object::Commit(xs)) => Ok(xs),
+        _ => Err(ErrorKind::MissingObject.into())
+    }
+}
+
+fn subject(commit: &Commit) -> String {
+    let line = commit.message().split(|xs| *xs == b'\n').next().unwrap_or(b"");
+    String::from_utf8_lossy(line).into_owned()
+}
+
+fn put<'a>(path: &'a Path) -> impl FnMut(Type, Vec<u8>) -> Result<Id> + 'a {
+    move |typ, data| Ok(gitfs::write_loose(path, typ, &data)?)
+}
+
+// The operation waiting on conflicts to be settled, and the commit it is
+// replaying.
+pub fn in_progress(path: &Path) -> Result<Option<(Operation, Id)>> {
+    let git_dir = worktree::git_dir(path)?;
+    for operation in &[Operation::CherryPick, Operation::Revert] {
+        match std::fs::read_to_string(git_dir.join(operation.state_file())) {
+            Ok(xs) => return Ok(Some((*operation, xs.trim().parse()?))),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into())
+        }
+    }
+    Ok(None)
+}
+
+// HEAD's commit, and the ref that moves when committing on top of it: the
+// branch, or HEAD itself when detached.
+fn head(path: &Path) -> Result<(Id, String)> {
+    match RefStore::new(path).resolve_ref("HEAD")? {
+        (name, Some(id)) => Ok((id, name)),
+        (_, None) => Err(ErrorKind::MissingObject.into())
+    }
+}
+
+fn commit_onto(path: &Path, tree: &Id, author: &Identity, committer: &Identity, message: &str, reflog_message: &str) -> Result<Id> {
+    let (head, name) = head(path)?;
+    let mut data = Vec::new();
+    Commit::write(&mut data, tree, std::slice::from_ref(&head), author, committer, message.as_bytes())?;
+    let id = gitfs::write_loose(path, Type::Commit, &data)?;
+    // through HEAD, so a detached HEAD moves in this worktree's git dir.
+    RefStore::new(path).transaction().compare_and_swap("HEAD", Some(&head), Some(&id)).commit()?;
+    reflog::append(path, &name, &reflog::Entry::new(Some(&head), &id, committer, reflog_message))?;
+    Ok(id)
+}
+
+// Brings the index's entries for `touched` in line with `entries`, leaving
+// every other entry (staged changes, skip-worktree bits) and the extensions
+// as they are.
+fn update_index(index: &mut Index, touched: &BTreeSet<Vec<u8>>, entries: &BTreeMap<Vec<u8>, TreeEntry>) {
+    for entry_path in touched {
+        match entries.get(entry_path) {
+            Some(xs) => {
+                let skip_worktree = index.get(entry_path, 0).is_some_and(|xs| xs.skip_worktree);
+                index.add(Entry { skip_worktree, ..Entry::new(entry_path.clone(), xs.mode, xs.id.clone()) });
+            },
+            None => { index.remove(entry_path); }
+        }
+    }
+}
+
+// The paths `id` changed against any of its parents: all a replay of it
+// can touch.
+fn changed_paths<S: Queryable>(storage_set: &StorageSet<S>, id: &Id) -> Result<BTreeSet<Vec<u8>>> {
+    let after = flatten(storage_set, id)?;
+    let parents = load_commit(storage_set, id)?.parents().unwrap_or_default();
+    if parents.is_empty() {
+        return Ok(after.into_keys().collect())
+    }
+    let mut touched = BTreeSet::new();
+    for parent in parents {
+        let before = flatten(storage_set, &parent)?;
+        for entry_path in before.keys().chain(after.keys()) {
+            if before.get(entry_path) != after.get(entry_path) {
+                touched.insert(entry_path.clone());
+            }
+        }
+    }
+    Ok(touched)
+}
+
+fn replay<S: Queryable>(
+    path: &Path,
+    storage_set: &StorageSet<S>,
+    identity: &Identity,
+    operation: Operation,
+    id: &Id,
+    options: &Options
+) -> Result<Outcome> {
+    if in_progress(path)?.is_some() {
+        return Err(ErrorKind::OperationInProgress.into())
+    }
+    let (head, _) = head(path)?;
+    let commit = load_commit(storage_set, id)?;
+    let parents = commit.parents().unwrap_or_default();
+    let parent = match (parents.len(), options.mainline) {
+        (0, _) => None,
+        (1, None) => Some(parents[0].clone()),
+        (_, Some(n)) => match parents.get(n.wrapping_sub(1)) {
+            Some(xs) => Some(xs.clone()),
+            None => return Err(ErrorKind::NoSuchParent(n).into())
+        },
+        (_, None) => return Err(ErrorKind::MainlineRequired.into())
+    };
+
+    let mut put = put(path);
+    // a root commit's change is against the empty tree.
+    let parent_tree = match parent {
+        Some(ref xs) => xs.clone(),
+        None => write_nested(&BTreeMap::new(), &mut put)?
+    };
+
+    // cherry-picking merges in the change from the parent to the commit;
+    // reverting, the change back from the commit to its parent.
+    let short = abbreviate(id, abbrev::length(path)?);
+    let subject = subject(&commit);
+    let (ours_label, commit_label, parent_label) = (
+        String::from("HEAD"),
+        format!("{} ({})", short, subject),
+        format!("parent of {} ({})", short, subject)
+    );
+    let (base, theirs, base_label, theirs_label) = match operation {
+        Operation::CherryPick => (parent_tree, id.clone(), parent_label, commit_label),
+        Operation::Revert => (id.clone(), parent_tree, commit_label, parent_label)
+    };
+    let merge_options = file::Options { ours_label, base_label, theirs_label, ..options.merge.clone() };
+    let merge = merge_trees(storage_set, Some(&base), &head, &theirs, &merge_options, &mut put)?;
+
+    let message = match operation {
+        Operation::CherryPick => {
+            let mut message = String::from_utf8_lossy(commit.message()).into_owned();
+            if options.record_origin {
+                if !message.ends_with('\n') {
+                    message.push('\n');
+                }
+                message.push_str(&format!("\n(cherry picked from commit {})\n", id));
+            }
+            message
+        },
+        Operation::Revert => match parent {
+            Some(ref xs) if parents.len() > 1 => format!(
+                "Revert \"{}\"\n\nThis reverts commit {}, reversing\nchanges made to {}.\n", subject, id, xs
+            ),
+            _ => format!("Revert \"{}\"\n\nThis reverts commit {}.\n", subject, id)
+        }
+    };
+
+    // what was just written may be in fan-out directories the caller's
+    // store doesn't look in.
+    let written = gitfs::from(path)?;
+    let (before, after) = (flatten(&written, &head)?, flatten(&written, &merge.tree)?);
+    let touched: BTreeSet<Vec<u8>> = before.keys().chain(after.keys())
+        .filter(|xs| before.get(*xs) != after.get(*xs))
+        .cloned()
+        .collect();
+    let mut index = Index::open_or_from_tree(path, &written, &head)?;
+    let modes = Modes::from_path(path)?;
+    let mut dirty = Vec::new();
+    for entry_path in &touched {
+        let old = before.get(entry_path);
+        if old.is_some_and(|xs| xs.mode.is_gitlink()) {
+            continue
+        }
+        let current = read_worktree_as(path, entry_path, old.map(|xs| xs.mode), &modes)?.map(|(mode, contents)| {
+            TreeEntry { mode, id: objects::hash(Type::Blob, &contents) }
+        });
+        let staged = index.get(entry_path, 0).map(|xs| TreeEntry { mode: xs.mode, id: xs.id.clone() });
+        if current.as_ref() != old || staged.as_ref() != old {
+            dirty.push(entry_path.clone());
+        }
+    }
+    if !dirty.is_empty() {
+        return Err(ErrorKind::LocalChanges(dirty).into())
+    }
+    Checkout::new(&written, path).run(Some(&head), &merge.tree)?;
+
+    if merge.is_clean() && !options.no_commit {
+        let author = match operation {
+            Operation::CherryPick => commit.author().unwrap_or(identity),
+            Operation::Revert => identity
+        };
+        let first_line = message.lines().next().unwrap_or("");
+        let reflog_message = format!("{}: {}", operation.reflog_prefix(), first_line);
+        let new = commit_onto(path, &merge.tree, author, identity, &message, &reflog_message)?;
+        update_index(&mut index, &touched, &after);
+        index.save(path)?;
+        return Ok(Outcome::Committed(new))
+    }
+
+    update_index(&mut index, &touched, &after);
+    for conflict in &merge.conflicts {
+        index.add_conflict(&conflict.path, conflict.base.as_ref(), conflict.ours.as_ref(), conflict.theirs.as_ref());
+    }
+    index.save(path)?;
+    if merge.is_clean() {
+        return Ok(Outcome::Applied)
+    }
+    let git_dir = worktree::git_dir(path)?;
+    std::fs::write(git_dir.join(MERGE_MSG), &message)?;
+    std::fs::write(git_dir.join(operation.state_file()), format!("{}\n", id))?;
+    Ok(Outcome::Conflicts(merge.conflicts))
+}
+
+// Applies the change `id` made onto HEAD, as `git cherry-pick`.
+pub fn cherry_pick<S: Queryable>(path: &Path, storage_set: &StorageSet<S>, identity: &Identity, id: &Id, options: &Options) -> Result<Outcome> {
+    replay(path, storage_set, identity, Operation::CherryPick, id, options)
+}
+
+// Applies the inverse of the change `id` made onto HEAD, as `git revert`.
+pub fn revert<S: Queryable>(path: &Path, storage_set: &StorageSet<S>, identity: &Identity, id: &Id, options: &Options) -> Result<Outcome> {
+    replay(path, storage_set, identity, Operation::Revert, id, options)
+}
+
+fn clear_state(path: &Path) -> Result<()> {
+    let git_dir = worktree::git_dir(path)?;
+    for name in &[CHERRY_PICK_HEAD, REVERT_HEAD, MERGE_MSG] {
+        match std::fs::remove_file(git_dir.join(name)) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            xs => xs?
+        }
+    }
+    Ok(())
+}
+
+// Commits the index once its conflicts are settled, as `git cherry-pick
+// --continue`.
+pub fn finish<S: Queryable>(path: &Path, storage_set: &StorageSet<S>, identity: &Identity) -> Result<Id> {
+    let (operation, id) = match in_progress(path)? {
+        Some(xs) => xs,
+        None => return Err(ErrorKind::NoOperationInProgress.into())
+    };
+    let mut index = Index::open(path)?;
+    let tree = index.write_tree(put(path))?;
+    let message = std::fs::read_to_string(worktree::git_dir(path)?.join(MERGE_MSG))?;
+
+    let commit = load_commit(storage_set, &id)?;
+    let author = match operation {
+        Operation::CherryPick => commit.author().unwrap_or(identity),
+        Operation::Revert => identity
+    };
+    let reflog_message = format!("{}: {}", operation.reflog_prefix(), message.lines().next().unwrap_or(""));
+    let new = commit_onto(path, &tree, author, identity, &message, &reflog_message)?;
+    index.clear_resolve_undo();
+    index.save(path)?;
+    clear_state(path)?;
+    Ok(new)
+}
+
+// Puts the paths the operation touched back the way HEAD has them in the
+// worktree and index, as `git cherry-pick --abort`.
+pub fn abort<S: Queryable>(path: &Path, storage_set: &StorageSet<S>) -> Result<()> {
+    let id = match in_progress(path)? {
+        Some((_, xs)) => xs,
+        None => return Err(ErrorKind::NoOperationInProgress.into())
+    };
+    let (head, _) = head(path)?;
+    let entries = flatten(storage_set, &head)?;
+    let touched = changed_paths(storage_set, &id)?;
+    let mut index = Index::open_or_from_tree(path, storage_set, &head)?;
+
+    let checkout = Checkout::new(storage_set, path);
+    let modes = Modes::from_path(path)?;
+    for entry_path in &touched {
+        let entry = match entries.get(entry_path) {
+            Some(xs) => xs,
+            None => {
+                checkout.remove_entry(entry_path)?;
+                continue
+            }
+        };
+        let current = read_worktree_as(path, entry_path, Some(entry.mode), &modes)?.map(|(mode, contents)| {
+            TreeEntry { mode, id: objects::hash(Type::Blob, &contents) }
+        });
+        if !entry.mode.is_gitlink() && current.as_ref() != Some(entry) {
+            checkout.write_entry(entry_path, entry)?;
+        }
+    }
+    update_index(&mut index, &touched, &entries);
+    index.save(path)?;
+    clear_state(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{ FixedOffset, TimeZone, Utc };
+
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::objects::tree::FileMode;
+    use crate::refs::{ RefPtr, RefSet };
+    use crate::checkout::Checkout;
+    use crate::worktree;
+    use crate::identity::Identity;
+    use crate::index::{ Entry, Index };
+    use crate::stores::fs as gitfs;
+    use crate::objects::{ Object, Type };
+    use crate::files;
+    use super::{ abort, cherry_pick, finish, in_progress, revert, Options, Outcome };
+
+    fn identity() -> Identity {
+        Identity::new(
+            b"Test User",
+            b"test@example.com",
+            Utc.timestamp_opt(1_545_300_000, 0).unwrap(),
+            FixedOffset::east_opt(0).unwrap()
+        )
+    }
+
+    fn head(path: &std::path::Path) -> crate::id::Id {
+        RefSet::from_path(path).expect("failed to read refs").deref("HEAD").unwrap().clone()
+    }
+
+    fn read(path: &std::path::Path, name: &str) -> String {
+        std::fs::read_to_string(path.join(name)).expect("failed to read")
+    }
+
+    #[test]
+    fn picks_and_reverts_cleanly() {
+        let dir = TempDir::new("cherry-pick").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "1\n2\n3\n", "other" => "x\n"])
+            .branch("topic")
+            .commit("master", files!["other" => "y\n"])
+            .checkout("topic")
+            .commit("fix the end", files!["README" => "1\n2\nthree\n"]);
+        let fix = builder.tip().unwrap();
+        let builder = builder.checkout("master");
+        let master = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        {
+            let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+            Checkout::new(&storage_set, dir.path()).run(None, &master).expect("failed to check out");
+        }
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let options = Options { record_origin: true, ..Options::default() };
+        let picked = match cherry_pick(dir.path(), &storage_set, &identity(), &fix, &options).expect("failed to pick") {
+            Outcome::Committed(xs) => xs,
+            xs => panic!("unexpected outcome {:?}", xs)
+        };
+        assert_eq!(head(dir.path()), picked);
+        assert_eq!(read(dir.path(), "README"), "1\n2\nthree\n");
+        assert_eq!(read(dir.path(), "other"), "y\n");
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        match storage_set.get_and_load(&picked).unwrap() {
+            Some(Object::Commit(commit)) => {
+                assert_eq!(commit.parents().unwrap(), vec![master.clone()]);
+                let message = String::from_utf8_lossy(commit.message()).into_owned();
+                assert_eq!(message, format!("fix the end\n\n(cherry picked from commit {})\n", fix));
+            },
+            _ => panic!("expected a commit")
+        }
+
+        let reverted = match revert(dir.path(), &storage_set, &identity(), &picked, &Options::default()).expect("failed to revert") {
+            Outcome::Committed(xs) => xs,
+            xs => panic!("unexpected outcome {:?}", xs)
+        };
+        assert_eq!(read(dir.path(), "README"), "1\n2\n3\n");
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        match storage_set.get_and_load(&reverted).unwrap() {
+            Some(Object::Commit(commit)) => assert!(commit.message().starts_with(b"Revert \"fix the end\"\n\nThis reverts commit ")),
+            _ => panic!("expected a commit")
+        }
+        assert!(!Index::open(dir.path()).unwrap().has_conflicts());
+    }
+
+    #[test]
+    fn conflicts_wait_for_finish_or_abort() {
+        let dir = TempDir::new("cherry-pick-conflict").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "a\n"])
+            .branch("topic")
+            .commit("ours", files!["README" => "ours\n"])
+            .checkout("topic")
+            .commit("theirs", files!["README" => "theirs\n"]);
+        let theirs = builder.tip().unwrap();
+        let builder = builder.checkout("master");
+        let ours = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        {
+            let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+            Checkout::new(&storage_set, dir.path()).run(None, &ours).expect("failed to check out");
+        }
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        for attempt in 0..2 {
+            match cherry_pick(dir.path(), &storage_set, &identity(), &theirs, &Options::default()).expect("failed to pick") {
+                Outcome::Conflicts(xs) => assert_eq!(xs.len(), 1),
+                xs => panic!("unexpected outcome {:?}", xs)
+            }
+            assert_eq!(in_progress(dir.path()).unwrap().map(|xs| xs.1), Some(theirs.clone()));
+            assert!(read(dir.path(), "README").starts_with("<<<<<<< HEAD\nours\n=======\ntheirs\n>>>>>>> "));
+            assert!(cherry_pick(dir.path(), &storage_set, &identity(), &theirs, &Options::default()).is_err());
+
+            if attempt == 0 {
+                let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+                abort(dir.path(), &storage_set).expect("failed to abort");
+                assert_eq!(read(dir.path(), "README"), "ours\n");
+                assert!(in_progress(dir.path()).unwrap().is_none());
+                assert_eq!(head(dir.path()), ours);
+                continue
+            }
+
+            let mut index = Index::open(dir.path()).expect("failed to open index");
+            assert!(finish(dir.path(), &storage_set, &identity()).is_err());
+            let resolved = gitfs::write_loose(dir.path(), Type::Blob, b"both\n").expect("failed to write");
+            index.resolve(b"README", Some(Entry::new(Vec::new(), FileMode::FILE, resolved)));
+            index.save(dir.path()).expect("failed to save");
+        }
+
+        let picked = finish(dir.path(), &storage_set, &identity()).expect("failed to finish");
+        assert_eq!(head(dir.path()), picked);
+        assert!(in_progress(dir.path()).unwrap().is_none());
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        match storage_set.get_and_load(&picked).unwrap() {
+            Some(Object::Commit(commit)) => {
+                assert_eq!(commit.message(), b"theirs\n");
+                assert_eq!(commit.parents().unwrap(), vec![ours.clone()]);
+            },
+            _ => panic!("expected a commit")
+        }
+    }
+
+    #[test]
+    fn detached_picks_in_linked_worktrees_keep_the_index() {
+        let dir = TempDir::new("cherry-pick-worktree").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "1\n", "other" => "x\n"])
+            .branch("topic")
+            .checkout("topic")
+            .commit("fix", files!["README" => "2\n"]);
+        let fix = builder.tip().unwrap();
+        let builder = builder.checkout("master");
+        let master = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        let linked = dir.path().join("linked");
+        worktree::add(dir.path(), "linked", &linked, &RefPtr::Direct(master.clone())).expect("failed to add worktree");
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        Checkout::new(&storage_set, &linked).run(None, &master).expect("failed to check out");
+        let mut index = Index::from_tree(&storage_set, &master).expect("failed to read tree");
+        let staged = gitfs::write_loose(dir.path(), Type::Blob, b"staged\n").expect("failed to write");
+        index.add(Entry::new(b"other".to_vec(), FileMode::FILE, staged.clone()));
+        index.save(&linked).expect("failed to save");
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let picked = match cherry_pick(&linked, &storage_set, &identity(), &fix, &Options::default()).expect("failed to pick") {
+            Outcome::Committed(xs) => xs,
+            xs => panic!("unexpected outcome {:?}", xs)
+        };
+        assert_eq!(head(&linked), picked);
+        assert_eq!(head(dir.path()), master);
+        assert_eq!(read(&linked, "README"), "2\n");
+        let index = Index::open(&linked).expect("failed to open index");
+        assert_eq!(index.get(b"other", 0).map(|xs| xs.id.clone()), Some(staged));
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::{ Arc, Mutex };
+
+use chrono::{ DateTime, Duration, TimeZone, Utc };
+
+// Where the current time comes from, wherever the library needs one: new
+// signatures and their reflog entries, and archives of bare trees. Swap in a
+// `FixedClock` to make those reproducible.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+impl std::fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Clock({})", self.now())
+    }
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+pub fn system() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+// The SOURCE_DATE_EPOCH reproducible builds set, if it is set and valid,
+// otherwise the system clock.
+pub fn from_env() -> Arc<dyn Clock> {
+    from_vars(|name| std::env::var(name).ok())
+}
+
+// Like `from_env`, looking variables up with `var`.
+pub fn from_vars<F: Fn(&str) -> Option<String>>(var: F) -> Arc<dyn Clock> {
+    let epoch = var("SOURCE_DATE_EPOCH")
+        .and_then(|xs| xs.trim().parse::<i64>().ok())
+        .and_then(|xs| Utc.timestamp_opt(xs, 0).single());
+    match epoch {
+        Some(at) => Arc::new(FixedClock::new(at)),
+        None => system()
+    }
+}
+
+// A clock that stands still until it is moved.
+#[derive(Debug)]
+pub struct FixedClock(Mutex<DateTime<Utc>>);
+
+impl FixedClock {
+    pub fn new(at: DateTime<Utc>) -> FixedClock {
+        FixedClock(Mutex::new(at))
+    }
+
+    pub fn set(&self, at: DateTime<Utc>) {
+        *self.0.lock().unwrap() = at;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{ Duration, TimeZone, Utc };
+
+    use super::{ from_vars, Clock, FixedClock };
+
+    #[test]
+    fn fixed_clocks_only_move_when_told() {
+        let at = Utc.timestamp_opt(1545286964, 0).unwrap();
+        let clock = FixedClock::new(at);
+        assert_eq!(clock.now(), at);
+        assert_eq!(clock.now(), at);
+        clock.advance(Duration::seconds(90));
+        assert_eq!(clock.now(), at + Duration::seconds(90));
+        clock.set(at);
+        assert_eq!(clock.now(), at);
+    }
+
+    #[test]
+    fn source_date_epoch_fixes_the_clock() {
+        let clock = from_vars(|name| if name == "SOURCE_DATE_EPOCH" { Some(String::from(" 1545286964\n")) } else { None });
+        assert_eq!(clock.now(), Utc.timestamp_opt(1545286964, 0).unwrap());
+
+        // anything else falls back to the system clock.
+        let before = Utc::now();
+        for value in &[None, Some("soon"), Some("")] {
+            let clock = from_vars(|_| value.map(String::from));
+            assert!(clock.now() >= before);
+        }
+    }
+}
@@ -0,0 +1,346 @@
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::path::{ Path, PathBuf };
+
+use crate::stores::{ Queryable, StorageSet };
+use crate::errors::{ ErrorKind, Result };
+use crate::refs::{ update_ref, RefStore };
+use crate::objects::{ Object, Type };
+use crate::pack::index::Index as PackIndex;
+use crate::stores::fs as gitfs;
+use crate::checkout::Checkout;
+use crate::checkout::modes::Modes;
+use crate::worktree::common_dir;
+use crate::index::Index;
+use crate::id::Id;
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    // A local repository to borrow objects from through objects/info/alternates
+    // instead of copying them (--reference).
+    pub reference: Option<PathBuf>,
+    // Copy the borrowed objects into the clone once it is made and stop
+    // borrowing (--dissociate).
+    pub dissociate: bool,
+    pub origin: String
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            reference: None,
+            dissociate: false,
+            origin: String::from("origin")
+        }
+    }
+}
+
+// What one objects directory holds itself, not counting its alternates.
+struct ObjectDir {
+    root: PathBuf,
+    packs: Vec<PackIndex>
+}
+
+impl ObjectDir {
+    fn open(root: &Path) -> Result<ObjectDir> {
+        Ok(ObjectDir {
+            root: root.to_path_buf(),
+            packs: gitfs::pack_indices_from_dir(root)?
+        })
+    }
+
+    fn contains(&self, id: &Id) -> bool {
+        if self.packs.iter().any(|xs| xs.contains(id)) {
+            return true
+        }
+        let hex = id.to_string();
+        self.root.join(&hex[..2]).join(&hex[2..]).is_file()
+    }
+}
+
+// The objects reachable from `tips` that `have` lacks, to stream into a
+// pack. A repository's objects directory is `complete`: it has everything
+// its objects reach, so the walk stops at them. A clone borrowing through
+// alternates is not.
+fn missing<S: Queryable>(storage_set: &StorageSet<S>, tips: &[Id], have: Option<&ObjectDir>, complete: bool) -> Result<Vec<Id>> {
+    let mut seen = HashSet::new();
+    let mut stack = tips.to_vec();
+    let mut objects = Vec::new();
+    while let Some(id) = stack.pop() {
+        if !seen.insert(id.clone()) {
+            continue
+        }
+        let present = have.is_some_and(|xs| xs.contains(&id));
+        if present && complete {
+            continue
+        }
+
+        let mut data = Vec::new();
+        let typ = match storage_set.get(&id, &mut data)? {
+            Some(xs) => xs,
+            None => return Err(ErrorKind::MissingObject.into())
+        };
+        // there's nothing to walk in a blob.
+        if let Type::Blob = typ {
+            if !present {
+                objects.push(id);
+            }
+            continue
+        }
+        match typ.load(&mut Cursor::new(&data))? {
+            Object::Commit(commit) => {
+                stack.extend(commit.parents().unwrap_or_default());
+                stack.extend(commit.tree());
+            },
+            Object::Tree(tree) => {
+                // gitlinks point into other repositories.
+                stack.extend(tree.into_iter().filter(|(_, xs)| !xs.mode.is_gitlink()).map(|(_, xs)| xs.id));
+            },
+            Object::Tag(tag) => stack.extend(tag.object()),
+            Object::Blob(_) => ()
+        }
+        if !present {
+            objects.push(id);
+        }
+    }
+    Ok(objects)
+}
+
+fn ref_tips(path: &Path) -> Result<Vec<Id>> {
+    let mut tips: Vec<Id> = RefStore::new(path).list()?.into_iter().map(|(_, id)| id).collect();
+    tips.sort();
+    tips.dedup();
+    Ok(tips)
+}
+
+// Clones the repository at `source` into `dest`, which must not be a
+// repository yet. Source branches become remote-tracking branches of
+// `options.origin`, and the branch the source HEAD names is checked out.
+// With a reference, only the objects the reference lacks are copied. A
+// failed clone leaves nothing behind.
+pub fn clone_local(source: &Path, dest: &Path, options: &Options) -> Result<()> {
+    let git_dir = dest.join(".git");
+    if git_dir.exists() {
+        return Err(ErrorKind::DestinationExists(dest.to_path_buf()).into())
+    }
+    let created = !dest.exists();
+    let result = clone_into(source, dest, options);
+    if result.is_err() {
+        // whatever was removed, the clone's own error is the one to report.
+        let _ = std::fs::remove_dir_all(if created { dest } else { &git_dir });
+    }
+    result
+}
+
+fn clone_into(source: &Path, dest: &Path, options: &Options) -> Result<()> {
+    let git_dir = dest.join(".git");
+    for dir in &["objects/pack", "objects/info", "refs/heads", "refs/tags", "refs/remotes"] {
+        std::fs::create_dir_all(git_dir.join(dir))?;
+    }
+
+    let reference = match options.reference {
+        Some(ref xs) => {
+            let objects = common_dir(xs)?.join("objects").canonicalize()?;
+            std::fs::write(git_dir.join("objects").join("info").join("alternates"), format!("{}\n", objects.display()))?;
+            Some(ObjectDir::open(&objects)?)
+        },
+        None => None
+    };
+
+    // objects are copied as they are; refs/replace goes along with them.
+    let source_storage = gitfs::from(source)?.without_replacements();
+    let objects = missing(&source_storage, &ref_tips(source)?, reference.as_ref(), true)?;
+    if !objects.is_empty() {
+        gitfs::write_pack_from(dest, &source_storage, &objects)?;
+    }
+
+    let source_refs = RefStore::new(source);
+    let (head_name, head) = source_refs.resolve_ref("HEAD")?;
+    let branch = head_name.strip_prefix("refs/heads/").map(String::from);
+    let refs = source_refs.list()?;
+    if refs.is_empty() {
+        return Err(ErrorKind::NothingToClone(source.to_path_buf()).into())
+    }
+    for (name, id) in refs {
+        if let Some(xs) = name.strip_prefix("refs/heads/") {
+            update_ref(dest, &format!("refs/remotes/{}/{}", options.origin, xs), &id)?;
+        } else if name.starts_with("refs/tags/") || name.starts_with("refs/replace/") {
+            update_ref(dest, &name, &id)?;
+        }
+    }
+
+    let modes = Modes::probe(&git_dir)?;
+    let mut config = format!(
+        "[core]\n\trepositoryformatversion = 0\n\tfilemode = {}\n\tbare = false\n{}",
+        modes.file_mode,
+        if modes.symlinks { "" } else { "\tsymlinks = false\n" }
+    );
+    config.push_str(&format!(
+        "[remote \"{}\"]\n\turl = {}\n\tfetch = +refs/heads/*:refs/remotes/{}/*\n",
+        options.origin,
+        source.canonicalize()?.display(),
+        options.origin
+    ));
+    match (branch, head.as_ref()) {
+        (Some(branch), Some(id)) => {
+            update_ref(dest, &format!("refs/heads/{}", branch), id)?;
+            std::fs::write(git_dir.join("HEAD"), format!("ref: refs/heads/{}\n", branch))?;
+            std::fs::write(
+                git_dir.join("refs").join("remotes").join(&options.origin).join("HEAD"),
+                format!("ref: refs/remotes/{}/{}\n", options.origin, branch)
+            )?;
+            config.push_str(&format!("[branch \"{}\"]\n\tremote = {}\n\tmerge = refs/heads/{}\n", branch, options.origin, branch));
+        },
+        // an unborn branch stays unborn; a detached HEAD stays detached.
+        (Some(branch), None) => std::fs::write(git_dir.join("HEAD"), format!("ref: refs/heads/{}\n", branch))?,
+        (None, Some(id)) => std::fs::write(git_dir.join("HEAD"), format!("{}\n", id))?,
+        (None, None) => std::fs::write(git_dir.join("HEAD"), "ref: refs/heads/master\n")?
+    }
+    std::fs::write(git_dir.join("config"), config)?;
+
+    if options.dissociate {
+        dissociate(dest)?;
+    }
+
+    if let Some(id) = head {
+        // reopened, since the packs written above came after `source_storage`.
+        let storage_set = gitfs::from(dest)?;
+        Checkout::new(&storage_set, dest).run(None, &id)?;
+        Index::from_tree(&storage_set, &id)?.save(dest)?;
+    }
+    Ok(())
+}
+
+// Copies every reachable object the repository at `path` borrows through
+// alternates into a pack of its own, then stops borrowing: git's
+// `repack -a -d` and removal of objects/info/alternates after --dissociate.
+pub fn dissociate(path: &Path) -> Result<()> {
+    let objects_dir = common_dir(path)?.join("objects");
+    let alternates = objects_dir.join("info").join("alternates");
+    if !alternates.exists() {
+        return Ok(())
+    }
+
+    let storage_set = gitfs::from(path)?.without_replacements();
+    let local = ObjectDir::open(&objects_dir)?;
+    let objects = missing(&storage_set, &ref_tips(path)?, Some(&local), false)?;
+    if !objects.is_empty() {
+        gitfs::write_pack_from(path, &storage_set, &objects)?;
+    }
+    std::fs::remove_file(alternates)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::stores::fs as gitfs;
+    use crate::objects::Object;
+    use crate::refs::RefStore;
+    use crate::files;
+    use super::{ clone_local, Options };
+
+    fn history(commits: usize) -> RepoBuilder {
+        let mut builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"]);
+        for n in 1..commits {
+            builder = builder.commit(&format!("commit {}", n), files!["README" => format!("hello {}\n", n).as_str()]);
+        }
+        builder.tag("v1")
+    }
+
+    fn local_objects(path: &std::path::Path) -> usize {
+        gitfs::pack_indices(path).unwrap().iter().map(|xs| xs.ids().len()).sum()
+    }
+
+    #[test]
+    fn clones_borrow_from_a_reference_until_dissociated() {
+        let dir = TempDir::new("clone").expect("failed to create tempdir");
+        let reference = dir.path().join("reference");
+        let source = dir.path().join("source");
+        history(1).write(&reference).expect("failed to write");
+        let builder = history(2);
+        let tip = builder.tip().unwrap();
+        builder.write(&source).expect("failed to write");
+
+        let plain = dir.path().join("plain");
+        clone_local(&source, &plain, &Options::default()).expect("failed to clone");
+        // two commits, two trees, two blobs
+        assert_eq!(local_objects(&plain), 6);
+        assert!(clone_local(&source, &plain, &Options::default()).is_err());
+
+        let borrowed = dir.path().join("borrowed");
+        let options = Options { reference: Some(reference.clone()), ..Options::default() };
+        clone_local(&source, &borrowed, &options).expect("failed to clone");
+        // only the second commit's objects are copied.
+        assert_eq!(local_objects(&borrowed), 3);
+        assert!(borrowed.join(".git/objects/info/alternates").exists());
+        assert_eq!(std::fs::read(borrowed.join("README")).unwrap(), b"hello 1\n");
+
+        let refs = RefStore::new(&borrowed);
+        assert_eq!(refs.read("HEAD").unwrap(), Some(tip.clone()));
+        assert_eq!(refs.read("refs/remotes/origin/master").unwrap(), Some(tip.clone()));
+        assert_eq!(refs.read("refs/remotes/origin/HEAD").unwrap(), Some(tip.clone()));
+        assert_eq!(refs.read("refs/tags/v1").unwrap(), Some(tip.clone()));
+
+        let dissociated = dir.path().join("dissociated");
+        let options = Options { reference: Some(reference.clone()), dissociate: true, ..Options::default() };
+        clone_local(&source, &dissociated, &options).expect("failed to clone");
+        assert!(!dissociated.join(".git/objects/info/alternates").exists());
+        assert_eq!(local_objects(&dissociated), 6);
+        std::fs::remove_dir_all(&reference).expect("failed to remove reference");
+        let storage_set = gitfs::from(&dissociated).expect("failed to open");
+        assert!(matches!(storage_set.get_and_load(&tip).unwrap(), Some(Object::Commit(_))));
+    }
+
+    #[test]
+    fn clones_copy_replaced_objects_as_they_are() {
+        use crate::objects::{ self, Type };
+
+        let dir = TempDir::new("clone-replace").expect("failed to create tempdir");
+        let source = dir.path().join("source");
+        history(1).write(&source).expect("failed to write");
+        let original = objects::hash(Type::Blob, b"hello\n");
+        let replacement = gitfs::write_loose(&source, Type::Blob, b"replaced\n").unwrap();
+        crate::refs::update_ref(&source, &format!("refs/replace/{}", original), &replacement).unwrap();
+
+        let dest = dir.path().join("dest");
+        clone_local(&source, &dest, &Options { dissociate: true, ..Options::default() }).expect("failed to clone");
+        let refs = RefStore::new(&dest);
+        assert_eq!(refs.read(&format!("refs/replace/{}", original)).unwrap(), Some(replacement));
+        let storage_set = gitfs::from(&dest).expect("failed to open");
+        let mut output = Vec::new();
+        storage_set.get_unreplaced(&original, &mut output).unwrap();
+        assert_eq!(output, b"hello\n");
+        // the checkout honors the replacement, as git's does.
+        assert_eq!(std::fs::read(dest.join("README")).unwrap(), b"replaced\n");
+    }
+
+    #[test]
+    fn clones_read_packed_refs_and_clean_up_failures() {
+        let dir = TempDir::new("clone-packed").expect("failed to create tempdir");
+        let source = dir.path().join("source");
+        let builder = history(1);
+        let tip = builder.tip().unwrap();
+        builder.write(&source).expect("failed to write");
+        // as `git pack-refs --all` leaves it, with no refs/remotes at all.
+        std::fs::remove_dir_all(source.join(".git/refs")).expect("failed to remove refs");
+        std::fs::write(
+            source.join(".git/packed-refs"),
+            format!("# pack-refs with: peeled fully-peeled sorted \n{} refs/heads/master\n{} refs/tags/v1\n", tip, tip)
+        ).expect("failed to write");
+
+        let dest = dir.path().join("dest");
+        clone_local(&source, &dest, &Options::default()).expect("failed to clone");
+        let refs = RefStore::new(&dest);
+        assert_eq!(refs.read("HEAD").unwrap(), Some(tip.clone()));
+        assert_eq!(refs.read("refs/remotes/origin/master").unwrap(), Some(tip.clone()));
+        assert_eq!(refs.read("refs/tags/v1").unwrap(), Some(tip));
+        assert_eq!(std::fs::read(dest.join("README")).unwrap(), b"hello\n");
+
+        std::fs::remove_file(source.join(".git/packed-refs")).expect("failed to remove");
+        let failed = dir.path().join("failed");
+        assert!(clone_local(&source, &failed, &Options::default()).is_err());
+        assert!(!failed.exists());
+    }
+}
@@ -0,0 +1,633 @@
+use std::ffi::OsString;
+use std::path::{ Path, PathBuf };
+
+use crate::errors::{ ErrorKind, Result };
+use crate::attributes::wildmatch;
+use crate::worktree::Layout;
+
+// git takes include chains deeper than this for cycles.
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub section: String,
+    pub subsection: Option<String>,
+    pub key: String,
+    // None for a bare `key` line, which git reads as boolean true.
+    pub value: Option<String>
+}
+
+// A parsed git-config file. Section and key names are case-insensitive and
+// stored lowercased; subsection names are case-sensitive. Lookups return the
+// last matching entry, as git does.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    entries: Vec<Entry>
+}
+
+// The configuration git reads besides the repository's own: the system file,
+// the global files in the order they are read, then the parameters of
+// GIT_CONFIG_COUNT, GIT_CONFIG_KEY_<n> and GIT_CONFIG_VALUE_<n>.
+#[derive(Debug, Clone, Default)]
+pub struct Sources {
+    pub system: Option<PathBuf>,
+    pub global: Vec<PathBuf>,
+    pub parameters: Vec<(String, String)>
+}
+
+impl Sources {
+    pub fn from_env() -> Result<Sources> {
+        Sources::from_vars(|name| std::env::var_os(name))
+    }
+
+    // Like `from_env`, looking variables up with `var`.
+    pub fn from_vars<F: Fn(&str) -> Option<OsString>>(var: F) -> Result<Sources> {
+        let nonempty = |name: &str| var(name).filter(|xs| !xs.is_empty());
+
+        let nosystem = match nonempty("GIT_CONFIG_NOSYSTEM") {
+            Some(xs) => parse_bool(&xs.to_string_lossy()).ok_or_else(|| ErrorKind::BadConfigParameter(String::from("GIT_CONFIG_NOSYSTEM")))?,
+            None => false
+        };
+        let system = match var("GIT_CONFIG_SYSTEM") {
+            _ if nosystem => None,
+            // an empty override reads nothing, like /dev/null.
+            Some(xs) => Some(PathBuf::from(xs)).filter(|xs| !xs.as_os_str().is_empty()),
+            None => Some(PathBuf::from("/etc/gitconfig"))
+        };
+
+        let home = nonempty("HOME").map(PathBuf::from);
+        let global = match var("GIT_CONFIG_GLOBAL") {
+            Some(xs) => Some(PathBuf::from(xs)).filter(|xs| !xs.as_os_str().is_empty()).into_iter().collect(),
+            None => {
+                let xdg = nonempty("XDG_CONFIG_HOME").map(PathBuf::from).or_else(|| home.as_ref().map(|xs| xs.join(".config")));
+                xdg.map(|xs| xs.join("git/config")).into_iter()
+                    .chain(home.as_ref().map(|xs| xs.join(".gitconfig")))
+                    .collect()
+            }
+        };
+
+        let mut parameters = Vec::new();
+        if let Some(count) = nonempty("GIT_CONFIG_COUNT") {
+            let count: usize = count.to_str().and_then(|xs| xs.parse().ok())
+                .ok_or_else(|| ErrorKind::BadConfigParameter(String::from("GIT_CONFIG_COUNT")))?;
+            for n in 0..count {
+                let key = format!("GIT_CONFIG_KEY_{}", n);
+                let value = format!("GIT_CONFIG_VALUE_{}", n);
+                let name = match nonempty(&key).map(|xs| xs.into_string()) {
+                    Some(Ok(xs)) if xs.contains('.') => xs,
+                    _ => return Err(ErrorKind::BadConfigParameter(key).into())
+                };
+                match var(&value).map(|xs| xs.into_string()) {
+                    Some(Ok(xs)) => parameters.push((name, xs)),
+                    _ => return Err(ErrorKind::BadConfigParameter(value).into())
+                }
+            }
+        }
+
+        Ok(Sources {
+            system,
+            global,
+            parameters
+        })
+    }
+}
+
+impl Config {
+    // Reads the configuration of the repository `path` belongs to as git
+    // does: the system, global and repository files, then the environment's
+    // parameters. Missing files are empty.
+    pub fn from_path(path: &Path) -> Result<Config> {
+        Config::from_layout(&Layout::resolve(path)?, &Sources::from_env()?)
+    }
+
+    pub fn from_layout(layout: &Layout, sources: &Sources) -> Result<Config> {
+        let mut config = Config::default();
+        let git_dir = Some(layout.git_dir.as_path());
+        for file in sources.system.iter().chain(&sources.global) {
+            config.include(file, git_dir, 0)?;
+        }
+        config.include(&layout.common_dir.join("config"), git_dir, 0)?;
+        for (name, value) in &sources.parameters {
+            let (section, subsection, key) = split_name(name);
+            config.entries.push(Entry { section, subsection, key, value: Some(value.clone()) });
+        }
+        Ok(config)
+    }
+
+    // Reads one file and whatever it includes; "includeIf" sections only
+    // apply when there is a repository to test them against.
+    pub fn from_file(file: &Path) -> Result<Config> {
+        let mut config = Config::default();
+        config.include(file, None, 0)?;
+        Ok(config)
+    }
+
+    fn include(&mut self, file: &Path, git_dir: Option<&Path>, depth: usize) -> Result<()> {
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(ErrorKind::ConfigIncludeTooDeep(file.to_path_buf()).into())
+        }
+        let parsed = match std::fs::read(file) {
+            Ok(buffer) => Config::parse(std::str::from_utf8(&buffer)?)?,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into())
+        };
+        let base = file.parent().unwrap_or_else(|| Path::new(""));
+        for entry in parsed.entries {
+            let included = match (entry.section.as_str(), entry.subsection.as_deref(), entry.key.as_str(), entry.value.as_deref()) {
+                ("include", None, "path", Some(target)) => Some(expand(target, base)),
+                ("includeif", Some(condition), "path", Some(target)) if applies(condition, base, git_dir) => Some(expand(target, base)),
+                _ => None
+            };
+            self.entries.push(entry);
+            if let Some(target) = included {
+                self.include(&target, git_dir, depth + 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parse(input: &str) -> Result<Config> {
+        let mut entries = Vec::new();
+        let mut section: Option<(String, Option<String>)> = None;
+        let mut chars = input.chars().peekable();
+        let mut line = 1;
+
+        loop {
+            // skip leading whitespace and blank lines
+            while let Some(&c) = chars.peek() {
+                if c == '\n' {
+                    line += 1;
+                } else if !c.is_whitespace() {
+                    break
+                }
+                chars.next();
+            }
+
+            let c = match chars.next() {
+                Some(xs) => xs,
+                None => break
+            };
+
+            match c {
+                '#' | ';' => {
+                    for c in chars.by_ref() {
+                        if c == '\n' {
+                            line += 1;
+                            break
+                        }
+                    }
+                },
+
+                '[' => {
+                    let mut header = String::new();
+                    let mut subsection = None;
+                    loop {
+                        match chars.next() {
+                            Some(']') => break,
+                            Some('"') => {
+                                let mut sub = String::new();
+                                loop {
+                                    match chars.next() {
+                                        Some('\\') => match chars.next() {
+                                            Some(xs) if xs != '\n' => sub.push(xs),
+                                            _ => return Err(ErrorKind::BadConfig(line).into())
+                                        },
+                                        Some('"') => break,
+                                        Some('\n') | None => return Err(ErrorKind::BadConfig(line).into()),
+                                        Some(xs) => sub.push(xs)
+                                    }
+                                }
+                                subsection = Some(sub);
+                            },
+                            Some('\n') | None => return Err(ErrorKind::BadConfig(line).into()),
+                            Some(xs) => header.push(xs)
+                        }
+                    }
+
+                    let header = header.trim();
+                    let name = if subsection.is_some() {
+                        header.to_lowercase()
+                    } else {
+                        // legacy `[section.subsection]` syntax
+                        match header.find('.') {
+                            Some(idx) => {
+                                subsection = Some(header[idx + 1..].to_lowercase());
+                                header[..idx].to_lowercase()
+                            },
+                            None => header.to_lowercase()
+                        }
+                    };
+
+                    if name.is_empty() || !name.chars().all(|xs| xs.is_alphanumeric() || xs == '-' || xs == '.') {
+                        return Err(ErrorKind::BadConfig(line).into())
+                    }
+                    section = Some((name, subsection));
+                },
+
+                c if c.is_alphanumeric() => {
+                    let (section_name, subsection) = match section {
+                        Some(ref xs) => xs.clone(),
+                        None => return Err(ErrorKind::BadConfig(line).into())
+                    };
+
+                    let mut key = c.to_lowercase().to_string();
+                    while let Some(&c) = chars.peek() {
+                        if c.is_alphanumeric() || c == '-' {
+                            key.extend(c.to_lowercase());
+                            chars.next();
+                        } else {
+                            break
+                        }
+                    }
+
+                    while let Some(&c) = chars.peek() {
+                        if c == ' ' || c == '\t' {
+                            chars.next();
+                        } else {
+                            break
+                        }
+                    }
+
+                    let value = match chars.peek() {
+                        Some('=') => {
+                            chars.next();
+                            Some(parse_value(&mut chars, &mut line)?)
+                        },
+                        Some('\n') | Some('#') | Some(';') | None => None,
+                        _ => return Err(ErrorKind::BadConfig(line).into())
+                    };
+
+                    entries.push(Entry {
+                        section: section_name,
+                        subsection,
+                        key,
+                        value
+                    });
+                },
+
+                _ => return Err(ErrorKind::BadConfig(line).into())
+            }
+        }
+
+        Ok(Config {
+            entries
+        })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    fn matching<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a Entry> {
+        let (section, subsection, key) = split_name(name);
+        self.entries.iter().filter(move |entry| {
+            entry.section == section &&
+            entry.key == key &&
+            entry.subsection.as_deref() == subsection.as_deref()
+        })
+    }
+
+    // Looks up "section.key" or "section.subsection.key".
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.matching(name).last().map(|entry| entry.value.as_deref().unwrap_or("true"))
+    }
+
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.matching(name).map(|entry| entry.value.as_deref().unwrap_or("true")).collect()
+    }
+
+    pub fn get_bool(&self, name: &str) -> Option<bool> {
+        let entry = self.matching(name).last()?;
+        match entry.value.as_deref() {
+            None => Some(true),
+            Some(xs) => parse_bool(xs)
+        }
+    }
+
+    // Integers may carry a k, m or g suffix.
+    pub fn get_int(&self, name: &str) -> Option<i64> {
+        let value = self.get(name)?.trim();
+        let (digits, scale) = match value.chars().last()?.to_ascii_lowercase() {
+            'k' => (&value[..value.len() - 1], 1 << 10),
+            'm' => (&value[..value.len() - 1], 1 << 20),
+            'g' => (&value[..value.len() - 1], 1 << 30),
+            _ => (value, 1)
+        };
+        digits.parse::<i64>().ok().map(|xs| xs * scale)
+    }
+
+    // Subsection names seen for `section`, in file order without duplicates.
+    pub fn subsections(&self, section: &str) -> Vec<&str> {
+        let section = section.to_lowercase();
+        let mut result: Vec<&str> = Vec::new();
+        for entry in &self.entries {
+            if entry.section != section {
+                continue
+            }
+            if let Some(ref sub) = entry.subsection {
+                if !result.contains(&sub.as_str()) {
+                    result.push(sub);
+                }
+            }
+        }
+        result
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" => Some(true),
+        "false" | "no" | "off" | "" => Some(false),
+        other => other.parse::<i64>().ok().map(|xs| xs != 0)
+    }
+}
+
+// Include paths may start with "~/"; relative ones are relative to the
+// including file.
+fn expand(target: &str, base: &Path) -> PathBuf {
+    match (target.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => base.join(target)
+    }
+}
+
+// The "gitdir:", "gitdir/i:" and "onbranch:" conditions of includeIf.
+fn applies(condition: &str, base: &Path, git_dir: Option<&Path>) -> bool {
+    let git_dir = match git_dir {
+        Some(xs) => xs,
+        None => return false
+    };
+    let (pattern, text, fold) = if let Some(pattern) = condition.strip_prefix("gitdir:") {
+        (gitdir_pattern(pattern, base), git_dir.to_string_lossy().into_owned(), false)
+    } else if let Some(pattern) = condition.strip_prefix("gitdir/i:") {
+        (gitdir_pattern(pattern, base), git_dir.to_string_lossy().into_owned(), true)
+    } else if let Some(pattern) = condition.strip_prefix("onbranch:") {
+        let head = std::fs::read_to_string(git_dir.join("HEAD")).unwrap_or_default();
+        let branch = match head.trim_end().strip_prefix("ref: refs/heads/") {
+            Some(xs) => xs.to_string(),
+            None => return false
+        };
+        let pattern = if pattern.ends_with('/') { format!("{}**", pattern) } else { pattern.to_string() };
+        (pattern, branch, false)
+    } else {
+        return false
+    };
+    if fold {
+        wildmatch(pattern.to_lowercase().as_bytes(), text.to_lowercase().as_bytes())
+    } else {
+        wildmatch(pattern.as_bytes(), text.as_bytes())
+    }
+}
+
+// git's rewriting of a gitdir pattern: "~/" and "./" expand, other relative
+// patterns match at any depth, and a trailing slash matches everything below.
+fn gitdir_pattern(pattern: &str, base: &Path) -> String {
+    let mut pattern = match (pattern.strip_prefix("~/"), pattern.strip_prefix("./"), std::env::var_os("HOME")) {
+        (Some(rest), _, Some(home)) => Path::new(&home).join(rest).to_string_lossy().into_owned(),
+        (_, Some(rest), _) => base.join(rest).to_string_lossy().into_owned(),
+        _ if pattern.starts_with('/') => pattern.to_string(),
+        _ => format!("**/{}", pattern)
+    };
+    if pattern.ends_with('/') {
+        pattern.push_str("**");
+    }
+    pattern
+}
+
+fn split_name(name: &str) -> (String, Option<String>, String) {
+    let first = name.find('.').unwrap_or(name.len());
+    let last = name.rfind('.').unwrap_or(0);
+    let section = name[..first].to_lowercase();
+    if last <= first {
+        return (section, None, name[first.min(name.len())..].trim_start_matches('.').to_lowercase())
+    }
+    (section, Some(name[first + 1..last].to_string()), name[last + 1..].to_lowercase())
+}
+
+fn parse_value<I: Iterator<Item = char>>(chars: &mut std::iter::Peekable<I>, line: &mut usize) -> Result<String> {
+    let mut value = String::new();
+    let mut quoted = false;
+    // whitespace is only kept when something non-blank follows it
+    let mut pending = String::new();
+
+    while let Some(&c) = chars.peek() {
+        if c == ' ' || c == '\t' {
+            chars.next();
+        } else {
+            break
+        }
+    }
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => {
+                *line += 1;
+                if quoted {
+                    return Err(ErrorKind::BadConfig(*line).into())
+                }
+                break
+            },
+            '#' | ';' if !quoted => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        *line += 1;
+                        break
+                    }
+                }
+                break
+            },
+            '"' => {
+                value.push_str(&pending);
+                pending.clear();
+                quoted = !quoted;
+            },
+            '\\' => {
+                value.push_str(&pending);
+                pending.clear();
+                match chars.next() {
+                    Some('\n') => *line += 1,
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some('b') => value.push('\u{8}'),
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    _ => return Err(ErrorKind::BadConfig(*line).into())
+                }
+            },
+            ' ' | '\t' if !quoted => pending.push(c),
+            c => {
+                value.push_str(&pending);
+                pending.clear();
+                value.push(c);
+            }
+        }
+    }
+
+    if quoted {
+        return Err(ErrorKind::BadConfig(*line).into())
+    }
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::ffi::OsString;
+    use std::path::PathBuf;
+
+    use crate::testkit::TempDir;
+    use crate::worktree::Layout;
+    use super::{ Config, Sources };
+
+    const SAMPLE: &str = r#"
+# comment
+[core]
+    bare = false
+    filemode
+    abbrev = 12 ; trailing comment
+[remote "origin"]
+    url = "https://example.com/a b.git"
+    fetch = +refs/heads/*:refs/remotes/origin/*
+[Remote "Upstream"]
+    URL = git@example.com:x.git
+[pack]
+    windowMemory = 10m
+[alias]
+    lg = log --graph \
+        --oneline
+"#;
+
+    #[test]
+    fn parse_works() {
+        let config = Config::parse(SAMPLE).expect("failed to parse");
+        assert_eq!(config.get_bool("core.bare"), Some(false));
+        assert_eq!(config.get_bool("core.filemode"), Some(true));
+        assert_eq!(config.get_int("core.abbrev"), Some(12));
+        assert_eq!(config.get("remote.origin.url"), Some("https://example.com/a b.git"));
+        assert_eq!(config.get("remote.Upstream.url"), Some("git@example.com:x.git"));
+        assert_eq!(config.get("remote.upstream.url"), None);
+        assert_eq!(config.get_int("pack.windowmemory"), Some(10 << 20));
+        assert_eq!(config.get("alias.lg"), Some("log --graph         --oneline"));
+        assert_eq!(config.subsections("remote"), vec!["origin", "Upstream"]);
+    }
+
+    #[test]
+    fn last_value_wins() {
+        let config = Config::parse("[a]\nb = 1\nb = 2\n").expect("failed to parse");
+        assert_eq!(config.get("a.b"), Some("2"));
+        assert_eq!(config.get_all("a.b"), vec!["1", "2"]);
+    }
+
+    #[test]
+    fn parse_rejects_garbage() {
+        assert!(Config::parse("key = value\n").is_err());
+        assert!(Config::parse("[unterminated\n").is_err());
+        assert!(Config::parse("[a]\nb = \"open\n").is_err());
+    }
+
+    #[test]
+    fn escapes_in_values() {
+        let config = Config::parse("[a]\n\tb = \"x\\by\\tz\\n\\\"q\\\" \\\\\"\n\tc = one\\\n two\n").expect("failed to parse");
+        assert_eq!(config.get("a.b"), Some("x\u{8}y\tz\n\"q\" \\"));
+        assert_eq!(config.get("a.c"), Some("one two"));
+        assert!(Config::parse("[a]\n\tb = \\q\n").is_err());
+    }
+
+    #[test]
+    fn includes_follow_paths_and_conditions() {
+        let dir = TempDir::new("config-include").expect("failed to create tempdir");
+        let root = dir.path();
+        let git_dir = root.join("Work/repo/.git");
+        std::fs::create_dir_all(&git_dir).unwrap();
+        std::fs::write(git_dir.join("HEAD"), "ref: refs/heads/topic/x\n").unwrap();
+        std::fs::create_dir_all(root.join("conf")).unwrap();
+        std::fs::write(root.join("conf/main"), format!(concat!(
+            "[a]\n\tb = 1\n",
+            "[include]\n\tpath = other\n\tpath = missing\n",
+            "[a]\n\tb = 3\n",
+            "[includeIf \"gitdir:{}/Work/\"]\n\tpath = work\n",
+            "[includeIf \"gitdir:{}/work/\"]\n\tpath = lower\n",
+            "[includeIf \"gitdir/i:{}/work/\"]\n\tpath = folded\n",
+            "[includeIf \"gitdir:repo/.git\"]\n\tpath = anywhere\n",
+            "[includeIf \"onbranch:topic/\"]\n\tpath = branch\n",
+            "[includeIf \"onbranch:main\"]\n\tpath = onmain\n"
+        ), root.display(), root.display(), root.display())).unwrap();
+        for (name, key) in &[("other", "b"), ("work", "work"), ("lower", "lower"), ("folded", "folded"), ("anywhere", "anywhere"), ("branch", "branch"), ("onmain", "main")] {
+            std::fs::write(root.join("conf").join(name), format!("[a]\n\t{} = 2\n", key)).unwrap();
+        }
+
+        let layout = Layout { worktree: root.join("Work/repo"), git_dir: git_dir.clone(), common_dir: PathBuf::from("/nonexistent") };
+        let sources = Sources { system: Some(root.join("conf/main")), ..Sources::default() };
+        let config = Config::from_layout(&layout, &sources).expect("failed to read");
+        // included entries sit where the include was.
+        assert_eq!(config.get_all("a.b"), vec!["1", "2", "3"]);
+        assert_eq!(config.get("include.path"), Some("missing"));
+        assert_eq!(config.get("a.work"), Some("2"));
+        assert_eq!(config.get("a.lower"), None);
+        assert_eq!(config.get("a.folded"), Some("2"));
+        assert_eq!(config.get("a.anywhere"), Some("2"));
+        assert_eq!(config.get("a.branch"), Some("2"));
+        assert_eq!(config.get("a.main"), None);
+
+        // without a repository the conditions never apply.
+        let config = Config::from_file(&root.join("conf/main")).expect("failed to read");
+        assert_eq!(config.get_all("a.b"), vec!["1", "2", "3"]);
+        assert_eq!(config.get("a.work"), None);
+
+        std::fs::write(root.join("conf/loop"), "[include]\n\tpath = loop\n").unwrap();
+        assert!(Config::from_file(&root.join("conf/loop")).is_err());
+    }
+
+    #[test]
+    fn sources_come_from_the_environment() {
+        let vars = |pairs: &[(&str, &str)]| {
+            let vars: HashMap<String, OsString> = pairs.iter().map(|(k, v)| (k.to_string(), OsString::from(v))).collect();
+            Sources::from_vars(move |name| vars.get(name).cloned())
+        };
+
+        let sources = vars(&[("HOME", "/home/u")]).unwrap();
+        assert_eq!(sources.system, Some(PathBuf::from("/etc/gitconfig")));
+        assert_eq!(sources.global, vec![PathBuf::from("/home/u/.config/git/config"), PathBuf::from("/home/u/.gitconfig")]);
+        assert!(sources.parameters.is_empty());
+
+        let sources = vars(&[("HOME", "/home/u"), ("XDG_CONFIG_HOME", "/xdg"), ("GIT_CONFIG_SYSTEM", "/opt/gitconfig")]).unwrap();
+        assert_eq!(sources.system, Some(PathBuf::from("/opt/gitconfig")));
+        assert_eq!(sources.global, vec![PathBuf::from("/xdg/git/config"), PathBuf::from("/home/u/.gitconfig")]);
+
+        let sources = vars(&[("HOME", "/home/u"), ("GIT_CONFIG_NOSYSTEM", "true"), ("GIT_CONFIG_GLOBAL", "/tmp/global")]).unwrap();
+        assert_eq!(sources.system, None);
+        assert_eq!(sources.global, vec![PathBuf::from("/tmp/global")]);
+        assert!(vars(&[("GIT_CONFIG_GLOBAL", "")]).unwrap().global.is_empty());
+
+        let sources = vars(&[
+            ("GIT_CONFIG_COUNT", "2"),
+            ("GIT_CONFIG_KEY_0", "core.abbrev"), ("GIT_CONFIG_VALUE_0", "12"),
+            ("GIT_CONFIG_KEY_1", "remote.Origin.URL"), ("GIT_CONFIG_VALUE_1", "")
+        ]).unwrap();
+        assert_eq!(sources.parameters, vec![
+            (String::from("core.abbrev"), String::from("12")),
+            (String::from("remote.Origin.URL"), String::new())
+        ]);
+        assert!(vars(&[("GIT_CONFIG_COUNT", "x")]).is_err());
+        assert!(vars(&[("GIT_CONFIG_COUNT", "1"), ("GIT_CONFIG_VALUE_0", "1")]).is_err());
+        assert!(vars(&[("GIT_CONFIG_COUNT", "1"), ("GIT_CONFIG_KEY_0", "nodot"), ("GIT_CONFIG_VALUE_0", "1")]).is_err());
+        assert!(vars(&[("GIT_CONFIG_COUNT", "1"), ("GIT_CONFIG_KEY_0", "a.b")]).is_err());
+        assert!(vars(&[("GIT_CONFIG_NOSYSTEM", "maybe")]).is_err());
+
+        // system, then global, then the repository, then parameters.
+        let dir = TempDir::new("config-sources").expect("failed to create tempdir");
+        let root = dir.path();
+        std::fs::write(root.join("system"), "[a]\n\tb = system\n").unwrap();
+        std::fs::write(root.join("global"), "[a]\n\tb = global\n").unwrap();
+        std::fs::write(root.join("config"), "[a]\n\tb = local\n").unwrap();
+        let layout = Layout { worktree: root.to_path_buf(), git_dir: root.to_path_buf(), common_dir: root.to_path_buf() };
+        let sources = Sources {
+            system: Some(root.join("system")),
+            global: vec![root.join("missing"), root.join("global")],
+            parameters: vec![(String::from("A.B"), String::from("parameter"))]
+        };
+        let config = Config::from_layout(&layout, &sources).expect("failed to read");
+        assert_eq!(config.get_all("a.b"), vec!["system", "global", "local", "parameter"]);
+    }
+}
@@ -0,0 +1,396 @@
+use std::process::{ Command, Stdio };
+use std::io::{ self, Write };
+use std::path::Path;
+
+use crate::errors::{ ErrorKind, Result };
+use crate::config::Config;
+
+// What a credential helper is asked about and answers with, as the
+// "key=value" lines of `git credential`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Credential {
+    pub protocol: Option<String>,
+    pub host: Option<String>,
+    pub path: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>
+}
+
+// The parts of a URL credential matching looks at, lowercased where git
+// compares them without case.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Url {
+    protocol: String,
+    username: Option<String>,
+    host: String,
+    port: Option<String>,
+    path: String
+}
+
+impl Url {
+    fn parse(url: &str) -> Option<Url> {
+        let at = url.find("://")?;
+        let rest = &url[at + 3..];
+        let (authority, path) = match rest.find('/') {
+            Some(xs) => (&rest[..xs], &rest[xs + 1..]),
+            None => (rest, "")
+        };
+        let (username, host) = match authority.rfind('@') {
+            Some(xs) => (Some(authority[..xs].split(':').next().unwrap_or("").to_string()), &authority[xs + 1..]),
+            None => (None, authority)
+        };
+        let (host, port) = match host.rfind(':') {
+            Some(xs) if !host.ends_with(']') => (&host[..xs], Some(host[xs + 1..].to_string())),
+            _ => (host, None)
+        };
+        Some(Url {
+            protocol: url[..at].to_lowercase(),
+            username,
+            host: host.to_lowercase(),
+            port: port.filter(|xs| !xs.is_empty()),
+            path: path.to_string()
+        })
+    }
+
+    fn port(&self) -> Option<&str> {
+        self.port.as_deref().or(match self.protocol.as_str() {
+            "http" => Some("80"),
+            "https" => Some("443"),
+            "ssh" => Some("22"),
+            "git" => Some("9418"),
+            _ => None
+        })
+    }
+
+    // Whether `self`, a `credential.<url>` section's URL, covers `url`, as
+    // git's urlmatch has it: the same protocol, host and port, the same
+    // user if one is given, and a path its path leads to by whole
+    // components. A "*" host label matches any one label.
+    fn covers(&self, url: &Url) -> bool {
+        let labels: Vec<&str> = self.host.split('.').collect();
+        let url_labels: Vec<&str> = url.host.split('.').collect();
+        let host = labels.len() == url_labels.len() && labels.iter().zip(&url_labels).all(|(xs, ys)| *xs == "*" || xs == ys);
+        let path = self.path.trim_end_matches('/');
+        self.protocol == url.protocol &&
+            host &&
+            self.port() == url.port() &&
+            self.username.as_ref().is_none_or(|xs| url.username.as_ref() == Some(xs)) &&
+            (path.is_empty() || url.path == path || url.path.starts_with(&format!("{}/", path)))
+    }
+}
+
+// Undoes %XX escapes, as git does to the parts of a URL it hands helpers.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes.get(i + 1..i + 3)
+            .and_then(|xs| std::str::from_utf8(xs).ok())
+            .and_then(|xs| u8::from_str_radix(xs, 16).ok());
+        match escaped {
+            Some(xs) if bytes[i] == b'%' => {
+                decoded.push(xs);
+                i += 3;
+            },
+            _ => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Values with a newline, carriage return or NUL could smuggle lines into
+// what a helper reads (CVE-2020-5260), so they are refused.
+fn check_value(key: &'static str, value: &str) -> Result<()> {
+    if value.contains(['\n', '\r', '\0']) {
+        return Err(ErrorKind::BadCredential(key).into())
+    }
+    Ok(())
+}
+
+impl Credential {
+    // The credential to ask about for `url`: its protocol, host (with the
+    // port) and any username. HTTP paths are left out, as git does without
+    // credential.useHttpPath.
+    pub fn from_url(url: &str, use_http_path: bool) -> Result<Credential> {
+        check_value("url", url)?;
+        let (protocol, rest) = match url.find("://") {
+            Some(xs) => (Some(url[..xs].to_string()), &url[xs + 3..]),
+            None => (None, url)
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(xs) => (&rest[..xs], &rest[xs + 1..]),
+            None => (rest, "")
+        };
+        let (username, host) = match authority.rfind('@') {
+            Some(xs) => (Some(authority[..xs].to_string()), &authority[xs + 1..]),
+            None => (None, authority)
+        };
+        let is_http = protocol.as_deref().is_some_and(|xs| xs == "http" || xs == "https");
+        let credential = Credential {
+            protocol,
+            host: Some(percent_decode(host)).filter(|xs| !xs.is_empty()),
+            path: Some(percent_decode(path)).filter(|xs| !xs.is_empty() && (use_http_path || !is_http)),
+            username: username.map(|xs| percent_decode(&xs)),
+            password: None
+        };
+        credential.check()?;
+        Ok(credential)
+    }
+
+    fn fields(&self) -> [(&'static str, &Option<String>); 5] {
+        [
+            ("protocol", &self.protocol),
+            ("host", &self.host),
+            ("path", &self.path),
+            ("username", &self.username),
+            ("password", &self.password)
+        ]
+    }
+
+    // Errors out on any value a helper can't be safely handed.
+    pub fn check(&self) -> Result<()> {
+        for (key, value) in self.fields().iter() {
+            if let Some(xs) = value {
+                check_value(key, xs)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.username.is_some() && self.password.is_some()
+    }
+
+    fn write(&self, output: &mut dyn Write) -> io::Result<()> {
+        for (key, value) in self.fields().iter() {
+            if let Some(xs) = value {
+                writeln!(output, "{}={}", key, xs)?;
+            }
+        }
+        writeln!(output)
+    }
+
+    // Takes in what a helper answered; returns whether it said to quit.
+    fn read(&mut self, input: &[u8]) -> bool {
+        let mut quit = false;
+        for line in String::from_utf8_lossy(input).lines() {
+            let (key, value) = match line.find('=') {
+                Some(xs) => (&line[..xs], line[xs + 1..].to_string()),
+                None => continue
+            };
+            match key {
+                "protocol" => self.protocol = Some(value),
+                "host" => self.host = Some(value),
+                "path" => self.path = Some(value),
+                "username" => self.username = Some(value),
+                "password" => self.password = Some(value),
+                "quit" => quit = value == "1" || value == "true",
+                _ => ()
+            }
+        }
+        quit
+    }
+}
+
+// The credential helpers configured for a URL: credential.helper from
+// `credential` and from every `credential.<url>` section covering the URL,
+// in file order, where an empty value clears those before it.
+#[derive(Clone, Debug, Default)]
+pub struct Helpers {
+    helpers: Vec<String>,
+    use_http_path: bool,
+    username: Option<String>
+}
+
+// "!" starts a shell snippet, a path is run as is, and any other name is a
+// `git credential-<name>` helper.
+fn helper_command(helper: &str, action: &str) -> String {
+    if let Some(xs) = helper.strip_prefix('!') {
+        format!("{} {}", xs, action)
+    } else if helper.starts_with('/') {
+        format!("{} {}", helper, action)
+    } else {
+        format!("git credential-{} {}", helper, action)
+    }
+}
+
+impl Helpers {
+    pub fn from_config(config: &Config, url: &str) -> Helpers {
+        let mut helpers = Helpers::default();
+        let url = Url::parse(url);
+        for entry in config.entries() {
+            let covered = match entry.subsection {
+                None => true,
+                Some(ref xs) => match (Url::parse(xs), url.as_ref()) {
+                    (Some(xs), Some(url)) => xs.covers(url),
+                    _ => false
+                }
+            };
+            if entry.section != "credential" || !covered {
+                continue
+            }
+            let value = entry.value.as_deref().unwrap_or("true");
+            match entry.key.as_str() {
+                "helper" if value.is_empty() => helpers.helpers.clear(),
+                "helper" => helpers.helpers.push(value.to_string()),
+                "usehttppath" => helpers.use_http_path = ["true", "yes", "on", "1"].contains(&value.to_lowercase().as_str()),
+                "username" => helpers.username = Some(value.to_string()),
+                _ => ()
+            }
+        }
+        helpers
+    }
+
+    pub fn from_path(path: &Path, url: &str) -> Result<Helpers> {
+        Ok(Helpers::from_config(&Config::from_path(path)?, url))
+    }
+
+    // The credential to fill in for `url`, with credential.username if the
+    // URL names no user.
+    pub fn credential(&self, url: &str) -> Result<Credential> {
+        let mut credential = Credential::from_url(url, self.use_http_path)?;
+        if credential.username.is_none() {
+            credential.username = self.username.clone();
+        }
+        Ok(credential)
+    }
+
+    // Asks each helper in turn to "get" what `credential` lacks, until it
+    // has a username and password or a helper says to quit; returns
+    // whether it is complete. Helpers that fail are passed over, as git
+    // does. Run from `cwd`.
+    pub fn fill(&self, credential: &mut Credential, cwd: &Path) -> Result<bool> {
+        for helper in &self.helpers {
+            credential.check()?;
+            let output = match run_helper(&helper_command(helper, "get"), credential, cwd)? {
+                Some(xs) => xs,
+                None => continue
+            };
+            if credential.read(&output) || credential.is_complete() {
+                break
+            }
+        }
+        Ok(credential.is_complete())
+    }
+
+    // Tells every helper the credential worked ("store"), as after a
+    // successful request.
+    pub fn approve(&self, credential: &Credential, cwd: &Path) -> Result<()> {
+        self.tell(credential, "store", cwd)
+    }
+
+    // Tells every helper the credential was turned down ("erase").
+    pub fn reject(&self, credential: &Credential, cwd: &Path) -> Result<()> {
+        self.tell(credential, "erase", cwd)
+    }
+
+    fn tell(&self, credential: &Credential, action: &str, cwd: &Path) -> Result<()> {
+        credential.check()?;
+        for helper in &self.helpers {
+            run_helper(&helper_command(helper, action), credential, cwd)?;
+        }
+        Ok(())
+    }
+}
+
+// The helper's output, or None if it exited unsuccessfully.
+fn run_helper(command: &str, credential: &Credential, cwd: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(cwd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut input = child.stdin.take().expect("stdin is piped");
+    // a helper that doesn't read its input closes the pipe early.
+    let _ = credential.write(&mut input);
+    drop(input);
+    let output = child.wait_with_output()?;
+    Ok(if output.status.success() { Some(output.stdout) } else { None })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testkit::TempDir;
+    use crate::config::Config;
+    use crate::errors::ErrorKind;
+    use super::{ Credential, Helpers, Url };
+
+    #[test]
+    fn helpers_fill_store_and_erase() {
+        let dir = TempDir::new("credential").expect("failed to create tempdir");
+        assert_eq!(Credential::from_url("https://b%40b@example.com:8443/org/repo.git", false).unwrap(), Credential {
+            protocol: Some("https".to_string()),
+            host: Some("example.com:8443".to_string()),
+            path: None,
+            username: Some("b@b".to_string()),
+            password: None
+        });
+
+        let config = Config::parse(concat!(
+            "[credential]\n\thelper = !exit 1\n\thelper =\n",
+            "\thelper = \"!f() { cat > asked; echo username=alice; }; f\"\n",
+            "\thelper = \"!f() { [ $1 = get ] && echo password=secret || cat >> told; }; f\"\n",
+            "[credential \"https://other.com\"]\n\thelper =\n\thelper = \"!f() { echo quit=1; }; f\"\n\thelper = !echo password=never\n"
+        )).unwrap();
+        let helpers = Helpers::from_config(&config, "https://example.com/repo.git");
+        let mut credential = helpers.credential("https://example.com/repo.git").unwrap();
+        assert!(helpers.fill(&mut credential, dir.path()).unwrap());
+        assert_eq!(std::fs::read_to_string(dir.path().join("asked")).unwrap(), "protocol=https\nhost=example.com\n\n");
+        assert_eq!(credential.username.as_deref(), Some("alice"));
+        assert_eq!(credential.password.as_deref(), Some("secret"));
+
+        helpers.approve(&credential, dir.path()).unwrap();
+        helpers.reject(&credential, dir.path()).unwrap();
+        let told = "protocol=https\nhost=example.com\nusername=alice\npassword=secret\n\n";
+        assert_eq!(std::fs::read_to_string(dir.path().join("told")).unwrap(), told.repeat(2));
+
+        // a helper saying to quit stops the rest.
+        let helpers = Helpers::from_config(&config, "https://other.com/repo.git");
+        let mut credential = helpers.credential("https://other.com/repo.git").unwrap();
+        assert!(!helpers.fill(&mut credential, dir.path()).unwrap());
+        assert_eq!(credential.password, None);
+    }
+
+    #[test]
+    fn sections_match_whole_hosts_and_paths() {
+        let section = Url::parse("https://example.com/org").unwrap();
+        for url in &["https://example.com/org", "https://EXAMPLE.com:443/org/repo.git", "https://bob@example.com/org/"] {
+            assert!(section.covers(&Url::parse(url).unwrap()), "{}", url);
+        }
+        for url in &["https://example.com.evil.net/org", "https://example.company.com/org", "http://example.com/org",
+                     "https://example.com:8443/org", "https://example.com/organization", "https://example.com/"] {
+            assert!(!section.covers(&Url::parse(url).unwrap()), "{}", url);
+        }
+        let wildcard = Url::parse("https://bob@*.example.com").unwrap();
+        assert!(wildcard.covers(&Url::parse("https://bob@git.example.com/x").unwrap()));
+        assert!(!wildcard.covers(&Url::parse("https://git.example.com/x").unwrap()));
+        assert!(!wildcard.covers(&Url::parse("https://bob@a.git.example.com/x").unwrap()));
+
+        let config = Config::parse("[credential \"https://example.com\"]\n\tusername = alice\n").unwrap();
+        assert_eq!(Helpers::from_config(&config, "https://example.com.evil.net/").credential("https://example.com.evil.net/").unwrap().username, None);
+    }
+
+    #[test]
+    fn newlines_and_nuls_are_refused() {
+        for url in &["https://example.com%0a/x", "https://evil.com/\nhost=example.com", "https://example.com/\0"] {
+            match Credential::from_url(url, true) {
+                Ok(xs) => panic!("accepted {:?}", xs),
+                Err(e) => match e.kind() {
+                    ErrorKind::BadCredential(_) => (),
+                    xs => panic!("unexpected error {:?}", xs)
+                }
+            }
+        }
+        let credential = Credential { username: Some("alice\nhost=evil.com".to_string()), ..Credential::default() };
+        let dir = TempDir::new("credential-injection").expect("failed to create tempdir");
+        let helpers = Helpers::from_config(&Config::parse("[credential]\n\thelper = \"!f() { cat > told; }; f\"\n").unwrap(), "https://example.com");
+        assert!(helpers.approve(&credential, dir.path()).is_err());
+        assert!(!dir.path().join("told").exists());
+    }
+}
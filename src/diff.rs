@@ -0,0 +1,392 @@
+use std::collections::{ BTreeMap, BTreeSet };
+use std::path::Path;
+use std::io::Write;
+
+use crate::patch::{ split_lines, Binary, Change, FilePatch, Hunk, Line };
+use crate::stores::{ Queryable, StorageSet };
+use crate::objects::tree::TreeEntry;
+use crate::errors::{ ErrorKind, Result };
+use crate::walk::tree::TreeWalk;
+use crate::pathspec::Pathspec;
+use crate::abbrev;
+use crate::objects::Object;
+use crate::id::Id;
+
+// One step of an edit script, by position in the old and new sequences.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edit {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize)
+}
+
+// A shortest edit script turning `old` into `new` (Myers' O(ND) algorithm).
+pub fn edits<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Edit> {
+    // common prefixes and suffixes never take part in the search.
+    let prefix = old.iter().zip(new).take_while(|(lhs, rhs)| lhs == rhs).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|(lhs, rhs)| lhs == rhs).count();
+    let (lhs, rhs) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    let mut script: Vec<Edit> = (0..prefix).map(|idx| Edit::Equal(idx, idx)).collect();
+    for edit in middle(lhs, rhs) {
+        script.push(match edit {
+            Edit::Equal(x, y) => Edit::Equal(x + prefix, y + prefix),
+            Edit::Delete(x) => Edit::Delete(x + prefix),
+            Edit::Insert(y) => Edit::Insert(y + prefix)
+        });
+    }
+    let (old_tail, new_tail) = (old.len() - suffix, new.len() - suffix);
+    script.extend((0..suffix).map(|idx| Edit::Equal(old_tail + idx, new_tail + idx)));
+    script
+}
+
+fn middle<T: PartialEq>(old: &[T], new: &[T]) -> Vec<Edit> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let max = n + m;
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    // trace[d] holds v[-d - 1..=d + 1] as it was before round d.
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+
+    let mut found = None;
+    'search: for d in 0..=max {
+        trace.push(v[(offset - d - 1) as usize..=(offset + d + 1) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let idx = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) { v[idx + 1] } else { v[idx - 1] + 1 };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                found = Some(d);
+                break 'search
+            }
+        }
+    }
+
+    let mut script = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for d in (0..=found.unwrap_or(0)).rev() {
+        let row = &trace[d as usize];
+        let at = |k: isize| row[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) { k + 1 } else { k - 1 };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            script.push(Edit::Equal(x as usize, y as usize));
+        }
+        if d > 0 {
+            if x == prev_x {
+                script.push(Edit::Insert(prev_y as usize));
+            } else {
+                script.push(Edit::Delete(prev_x as usize));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+    script.reverse();
+    script
+}
+
+// Unified-diff hunks with `context` lines around each change. Lines keep
+// their newline, so a missing final newline shows up as a change.
+pub fn hunks(old: &[u8], new: &[u8], context: usize) -> Vec<Hunk> {
+    let (old_lines, new_lines) = (split_lines(old), split_lines(new));
+    let script = edits(&old_lines, &new_lines);
+
+    let changed: Vec<usize> = script.iter().enumerate()
+        .filter(|(_, edit)| !matches!(edit, Edit::Equal(..)))
+        .map(|(idx, _)| idx)
+        .collect();
+
+    // group changes whose contexts touch or overlap.
+    let mut groups: Vec<(usize, usize)> = Vec::new();
+    for idx in changed {
+        match groups.last_mut() {
+            Some((_, end)) if idx <= *end + 2 * context + 1 => *end = idx,
+            _ => groups.push((idx, idx))
+        }
+    }
+
+    groups.into_iter().map(|(first, last)| {
+        let start = first.saturating_sub(context);
+        let end = (last + context + 1).min(script.len());
+
+        let (mut old_start, mut new_start) = (0, 0);
+        for edit in &script[..start] {
+            match edit {
+                Edit::Equal(..) => { old_start += 1; new_start += 1 },
+                Edit::Delete(_) => old_start += 1,
+                Edit::Insert(_) => new_start += 1
+            }
+        }
+
+        let lines: Vec<Line> = script[start..end].iter().map(|edit| match *edit {
+            Edit::Equal(x, _) => Line::Context(old_lines[x].to_vec()),
+            Edit::Delete(x) => Line::Remove(old_lines[x].to_vec()),
+            Edit::Insert(y) => Line::Add(new_lines[y].to_vec())
+        }).collect();
+        let old_count = lines.iter().filter(|xs| !matches!(xs, Line::Add(_))).count();
+        let new_count = lines.iter().filter(|xs| !matches!(xs, Line::Remove(_))).count();
+
+        // an empty side is numbered by the line it follows, as git does.
+        Hunk {
+            old_start: if old_count == 0 { old_start } else { old_start + 1 },
+            old_count,
+            new_start: if new_count == 0 { new_start } else { new_start + 1 },
+            new_count,
+            lines
+        }
+    }).collect()
+}
+
+// git's heuristic: a NUL in the first 8000 bytes means binary.
+pub fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(8000)].contains(&0)
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub context: usize,
+    // emit full "GIT binary patch" literals rather than "Binary files differ"
+    pub binary: bool,
+    // hex digits of the blob ids on "index" lines of text patches
+    pub abbrev: usize,
+    // only paths it matches are compared
+    pub pathspec: Pathspec
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            context: 3,
+            binary: false,
+            abbrev: abbrev::DEFAULT,
+            pathspec: Pathspec::new()
+        }
+    }
+}
+
+impl Options {
+    // The defaults, abbreviating ids as the repository's core.abbrev asks.
+    pub fn from_path(path: &Path) -> Result<Options> {
+        Ok(Options { abbrev: abbrev::length(path)?, ..Options::default() })
+    }
+}
+
+fn contents<S: Queryable>(storage_set: &StorageSet<S>, entry: Option<&TreeEntry>) -> Result<Vec<u8>> {
+    let entry = match entry {
+        Some(xs) => xs,
+        None => return Ok(Vec::new())
+    };
+    if entry.mode.is_gitlink() {
+        return Ok(format!("Subproject commit {}\n", entry.id).into_bytes())
+    }
+    match storage_set.get_and_load(&entry.id)? {
+        Some(Object::Blob(blob)) => Ok(blob.contents),
+        _ => Err(ErrorKind::MissingObject.into())
+    }
+}
+
+// Compares two trees (or the trees of two commits) within `options.pathspec`;
+// `None` is the empty tree.
+// Renames are not detected: a moved file is a delete plus an add.
+pub fn diff_trees<S: Queryable>(
+    storage_set: &StorageSet<S>,
+    old: Option<&Id>,
+    new: Option<&Id>,
+    options: &Options
+) -> Result<Vec<FilePatch>> {
+    let walk = TreeWalk::new(storage_set).pathspec(options.pathspec.clone());
+    let old_entries: BTreeMap<_, _> = match old {
+        Some(xs) => walk.entries(xs)?.into_iter().collect(),
+        None => Default::default()
+    };
+    let new_entries: BTreeMap<_, _> = match new {
+        Some(xs) => walk.entries(xs)?.into_iter().collect(),
+        None => Default::default()
+    };
+
+    let paths: BTreeSet<&Vec<u8>> = old_entries.keys().chain(new_entries.keys()).collect();
+    let mut files = Vec::new();
+    for path in paths {
+        let (before, after) = (old_entries.get(path), new_entries.get(path));
+        if before == after {
+            continue
+        }
+
+        let change = if before.map(|xs| &xs.id) == after.map(|xs| &xs.id) {
+            Change::Text(Vec::new())
+        } else {
+            let (old_data, new_data) = (contents(storage_set, before)?, contents(storage_set, after)?);
+            if is_binary(&old_data) || is_binary(&new_data) {
+                Change::Binary(if options.binary { Binary::Literal(new_data) } else { Binary::Unavailable })
+            } else {
+                Change::Text(hunks(&old_data, &new_data, options.context))
+            }
+        };
+
+        files.push(FilePatch {
+            old_path: before.map(|_| path.clone()),
+            new_path: after.map(|_| path.clone()),
+            old_mode: before.map(|xs| xs.mode),
+            new_mode: after.map(|xs| xs.mode),
+            is_rename: false,
+            is_copy: false,
+            old_id: before.map(|xs| xs.id.clone()),
+            new_id: after.map(|xs| xs.id.clone()),
+            change
+        });
+    }
+    Ok(files)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileStat {
+    pub path: Vec<u8>,
+    pub insertions: usize,
+    pub deletions: usize,
+    pub binary: bool
+}
+
+pub fn stat(files: &[FilePatch]) -> Vec<FileStat> {
+    files.iter().map(|file| {
+        let path = match (&file.old_path, &file.new_path) {
+            (Some(old), Some(new)) if old != new => [&old[..], b" => ", &new[..]].concat(),
+            _ => file.path().to_vec()
+        };
+        let (mut insertions, mut deletions) = (0, 0);
+        if let Change::Text(ref hunks) = file.change {
+            for line in hunks.iter().flat_map(|xs| &xs.lines) {
+                match line {
+                    Line::Add(_) => insertions += 1,
+                    Line::Remove(_) => deletions += 1,
+                    Line::Context(_) => ()
+                }
+            }
+        }
+        FileStat {
+            path,
+            insertions,
+            deletions,
+            binary: matches!(file.change, Change::Binary(_))
+        }
+    }).collect()
+}
+
+// `git diff --stat` output, with the +/- graph scaled to fit `width` columns.
+pub fn write_stat<W: Write>(output: &mut W, stats: &[FileStat], width: usize) -> std::io::Result<()> {
+    let name_width = stats.iter().map(|xs| String::from_utf8_lossy(&xs.path).chars().count()).max().unwrap_or(0);
+    let most = stats.iter().map(|xs| xs.insertions + xs.deletions).max().unwrap_or(0);
+    let mut count_width = most.to_string().len();
+    if stats.iter().any(|xs| xs.binary) {
+        count_width = count_width.max(3);
+    }
+    let graph_width = width.saturating_sub(name_width + count_width + 6).max(10);
+
+    for entry in stats {
+        let name = String::from_utf8_lossy(&entry.path);
+        write!(output, " {:<width$} | ", name, width = name_width)?;
+        if entry.binary {
+            writeln!(output, "{:>width$}", "Bin", width = count_width)?;
+            continue
+        }
+
+        let total = entry.insertions + entry.deletions;
+        let (mut plus, mut minus) = (entry.insertions, entry.deletions);
+        if most > graph_width {
+            let scale = |xs: usize| if xs == 0 { 0 } else { (xs * graph_width / most).max(1) };
+            plus = scale(plus);
+            minus = scale(minus);
+        }
+        let graph = format!("{}{}", "+".repeat(plus), "-".repeat(minus));
+        writeln!(output, "{:>width$} {}", total, graph, width = count_width)?;
+    }
+
+    let insertions: usize = stats.iter().map(|xs| xs.insertions).sum();
+    let deletions: usize = stats.iter().map(|xs| xs.deletions).sum();
+    let plural = |count: usize, word: &str| format!("{} {}{}", count, word, if count == 1 { "" } else { "s" });
+    write!(output, " {} changed", plural(stats.len(), "file"))?;
+    if insertions > 0 || deletions == 0 {
+        write!(output, ", {}(+)", plural(insertions, "insertion"))?;
+    }
+    if deletions > 0 || insertions == 0 {
+        write!(output, ", {}(-)", plural(deletions, "deletion"))?;
+    }
+    writeln!(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::patch::{ apply::{ apply_blob, Options as ApplyOptions }, Binary, Change, Line };
+    use crate::testkit::RepoBuilder;
+    use crate::files;
+    use crate::pathspec::Pathspec;
+    use super::{ diff_trees, edits, hunks, Edit, Options };
+
+    #[test]
+    fn edits_are_minimal() {
+        let script = edits(b"abcabba", b"cbabac");
+        let changes = script.iter().filter(|xs| !matches!(xs, Edit::Equal(..))).count();
+        assert_eq!(changes, 5);
+
+        let rebuilt: Vec<u8> = script.iter().filter_map(|edit| match *edit {
+            Edit::Equal(x, _) => Some(b"abcabba"[x]),
+            Edit::Insert(y) => Some(b"cbabac"[y]),
+            Edit::Delete(_) => None
+        }).collect();
+        assert_eq!(rebuilt, b"cbabac");
+    }
+
+    #[test]
+    fn hunks_carry_context_and_apply() {
+        let old = b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let new = b"1\n2\nthree\n4\n5\n6\n7\n8\n9\n10\n11\n12\nthirteen";
+        let result = hunks(old, new, 3);
+        assert_eq!(result.len(), 2);
+        assert_eq!((result[0].old_start, result[0].old_count, result[0].new_start, result[0].new_count), (1, 6, 1, 6));
+        assert_eq!(result[1].lines.last(), Some(&Line::Add(b"thirteen".to_vec())));
+
+        assert_eq!(hunks(b"", b"a\n", 3)[0].old_start, 0);
+        assert!(hunks(old, old, 3).is_empty());
+    }
+
+    #[test]
+    fn diff_trees_roundtrips_through_apply() {
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\nworld\n", "gone" => "bye\n", "img" => "\0\x01"]);
+        let first = builder.tip().unwrap();
+        let builder = builder.commit("second", files!["README" => "hello\nthere\n", "new" => "fresh\n", "img" => "\0\x02"]);
+        let second = builder.tip().unwrap();
+        let builder = builder.remove("third", &["gone"]);
+        let third = builder.tip().unwrap();
+        let (storage_set, _) = builder.in_memory();
+
+        let options = Options { binary: true, ..Options::default() };
+        let files = diff_trees(&storage_set, Some(&first), Some(&third), &options).expect("failed to diff");
+        let paths: Vec<_> = files.iter().map(|xs| xs.path().to_vec()).collect();
+        assert_eq!(paths, vec![b"README".to_vec(), b"gone".to_vec(), b"img".to_vec(), b"new".to_vec()]);
+        assert!(files[1].is_delete());
+        assert!(files[3].is_add());
+        assert_eq!(files[2].change, Change::Binary(Binary::Literal(b"\0\x02".to_vec())));
+
+        let patched = apply_blob(Some(&b"hello\nworld\n"[..]), &files[0], &ApplyOptions::default()).expect("failed to apply");
+        assert_eq!(patched.as_deref(), Some(&b"hello\nthere\n"[..]));
+
+        assert!(diff_trees(&storage_set, Some(&second), Some(&second), &options).expect("failed to diff").is_empty());
+        assert_eq!(diff_trees(&storage_set, None, Some(&first), &options).expect("failed to diff").len(), 3);
+
+        let limited = Options { pathspec: Pathspec::parse(&["READ*", "new"]).unwrap(), ..Options::default() };
+        let files = diff_trees(&storage_set, Some(&first), Some(&third), &limited).expect("failed to diff");
+        let paths: Vec<_> = files.iter().map(|xs| xs.path().to_vec()).collect();
+        assert_eq!(paths, vec![b"README".to_vec(), b"new".to_vec()]);
+    }
+}
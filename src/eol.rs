@@ -0,0 +1,305 @@
+use std::fmt;
+
+use crate::attributes::{ Attributes, State };
+use crate::errors::{ ErrorKind, Result };
+use crate::config::Config;
+
+// core.autocrlf.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AutoCrlf {
+    False,
+    True,
+    Input
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Eol {
+    Lf,
+    Crlf
+}
+
+impl Eol {
+    pub fn native() -> Eol {
+        if cfg!(windows) { Eol::Crlf } else { Eol::Lf }
+    }
+}
+
+// core.safecrlf: what to do when adding a file and checking it out again
+// would not give back the same bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SafeCrlf {
+    False,
+    Warn,
+    True
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Irreversible {
+    CrlfToLf,
+    LfToCrlf
+}
+
+impl fmt::Display for Irreversible {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Irreversible::CrlfToLf => write!(f, "CRLF would be replaced by LF"),
+            Irreversible::LfToCrlf => write!(f, "LF would be replaced by CRLF")
+        }
+    }
+}
+
+// How a path's line endings are converted, as git's crlf_action: `Auto`
+// only converts what looks like text. The eol is the worktree's.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Action {
+    Binary,
+    Text(Eol),
+    Auto(Eol)
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    nul: usize,
+    lonecr: usize,
+    lonelf: usize,
+    crlf: usize,
+    printable: usize,
+    nonprintable: usize
+}
+
+impl Stats {
+    fn gather(data: &[u8]) -> Stats {
+        let mut stats = Stats::default();
+        let mut bytes = data.iter().peekable();
+        while let Some(&byte) = bytes.next() {
+            match byte {
+                b'\r' if bytes.peek() == Some(&&b'\n') => {
+                    stats.crlf += 1;
+                    bytes.next();
+                },
+                b'\r' => stats.lonecr += 1,
+                b'\n' => stats.lonelf += 1,
+                0 => {
+                    stats.nul += 1;
+                    stats.nonprintable += 1;
+                },
+                // backspace, tab, escape and form feed pass for text.
+                8 | 9 | 27 | 12 => stats.printable += 1,
+                127 | 1..=31 => stats.nonprintable += 1,
+                _ => stats.printable += 1
+            }
+        }
+        stats
+    }
+
+    fn is_binary(&self) -> bool {
+        self.lonecr > 0 || self.nul > 0 || (self.printable >> 7) < self.nonprintable
+    }
+}
+
+// Line ending conversion between the object database (LF) and the
+// worktree, from core.autocrlf, core.eol and core.safecrlf and the `text`
+// and `eol` attributes, as git's convert.c does it. git also leaves "auto"
+// files alone when the index copy already has CRs; that takes the index,
+// which isn't consulted here.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Settings {
+    pub autocrlf: AutoCrlf,
+    // core.eol; ignored unless autocrlf is false.
+    pub eol: Eol,
+    pub safecrlf: SafeCrlf
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            autocrlf: AutoCrlf::False,
+            eol: Eol::native(),
+            safecrlf: SafeCrlf::Warn
+        }
+    }
+}
+
+impl Settings {
+    pub fn from_config(config: &Config) -> Settings {
+        let autocrlf = match config.get("core.autocrlf") {
+            Some(xs) if xs.eq_ignore_ascii_case("input") => AutoCrlf::Input,
+            _ if config.get_bool("core.autocrlf") == Some(true) => AutoCrlf::True,
+            _ => AutoCrlf::False
+        };
+        let eol = match config.get("core.eol").map(str::to_ascii_lowercase).as_deref() {
+            Some("lf") => Eol::Lf,
+            Some("crlf") => Eol::Crlf,
+            _ => Eol::native()
+        };
+        let safecrlf = match config.get("core.safecrlf") {
+            Some(xs) if xs.eq_ignore_ascii_case("warn") => SafeCrlf::Warn,
+            Some(_) if config.get_bool("core.safecrlf") == Some(true) => SafeCrlf::True,
+            Some(_) => SafeCrlf::False,
+            None => SafeCrlf::Warn
+        };
+        Settings { autocrlf, eol, safecrlf }
+    }
+
+    // Whether `path` is converted at all, rather than left as binary.
+    pub fn converts(&self, attributes: &Attributes, path: &[u8]) -> bool {
+        self.action(attributes, path) != Action::Binary
+    }
+
+    fn action(&self, attributes: &Attributes, path: &[u8]) -> Action {
+        let eol = match attributes.get(path, "eol") {
+            Some(State::Value(ref xs)) if xs == "lf" => Some(Eol::Lf),
+            Some(State::Value(ref xs)) if xs == "crlf" => Some(Eol::Crlf),
+            _ => None
+        };
+        let worktree_eol = eol.unwrap_or(match self.autocrlf {
+            AutoCrlf::True => Eol::Crlf,
+            AutoCrlf::Input => Eol::Lf,
+            AutoCrlf::False => self.eol
+        });
+        match attributes.get(path, "text") {
+            Some(State::Set) => Action::Text(worktree_eol),
+            Some(State::Unset) => Action::Binary,
+            Some(State::Value(ref xs)) if xs == "auto" => Action::Auto(worktree_eol),
+            // an eol attribute makes the path text.
+            _ if eol.is_some() => Action::Text(worktree_eol),
+            _ if self.autocrlf == AutoCrlf::False => Action::Binary,
+            _ => Action::Auto(worktree_eol)
+        }
+    }
+
+    // `data` from a blob as checkout writes it.
+    pub fn to_worktree(&self, attributes: &Attributes, path: &[u8], data: Vec<u8>) -> Vec<u8> {
+        let auto = match self.action(attributes, path) {
+            Action::Text(Eol::Crlf) => false,
+            Action::Auto(Eol::Crlf) => true,
+            _ => return data
+        };
+        let stats = Stats::gather(&data);
+        // "auto" leaves blobs that already have CRs as they are.
+        if stats.lonelf == 0 || (auto && (stats.crlf > 0 || stats.is_binary())) {
+            return data
+        }
+
+        let mut output = Vec::with_capacity(data.len() + stats.lonelf);
+        let mut previous = 0;
+        for byte in data {
+            if byte == b'\n' && previous != b'\r' {
+                output.push(b'\r');
+            }
+            output.push(byte);
+            previous = byte;
+        }
+        output
+    }
+
+    // `data` from the worktree as it would be stored, and whether checking
+    // it out again would give different bytes under core.safecrlf=warn. A
+    // conversion that isn't reversible fails under core.safecrlf=true.
+    pub fn to_git(&self, attributes: &Attributes, path: &[u8], data: Vec<u8>) -> Result<(Vec<u8>, Option<Irreversible>)> {
+        let (eol, auto) = match self.action(attributes, path) {
+            Action::Binary => return Ok((data, None)),
+            Action::Text(xs) => (xs, false),
+            Action::Auto(xs) => (xs, true)
+        };
+        let stats = Stats::gather(&data);
+        if auto && stats.is_binary() {
+            return Ok((data, None))
+        }
+
+        let irreversible = match eol {
+            Eol::Lf if stats.crlf > 0 => Some(Irreversible::CrlfToLf),
+            Eol::Crlf if stats.lonelf > 0 => Some(Irreversible::LfToCrlf),
+            _ => None
+        };
+        let warning = match (self.safecrlf, irreversible) {
+            (SafeCrlf::True, Some(xs)) => return Err(ErrorKind::IrreversibleEol(path.to_vec(), xs).into()),
+            (SafeCrlf::Warn, xs) => xs,
+            _ => None
+        };
+        if stats.crlf == 0 {
+            return Ok((data, warning))
+        }
+
+        let mut output = Vec::with_capacity(data.len() - stats.crlf);
+        for (i, byte) in data.iter().enumerate() {
+            if *byte != b'\r' || data.get(i + 1) != Some(&b'\n') {
+                output.push(*byte);
+            }
+        }
+        Ok((output, warning))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::attributes::Attributes;
+    use crate::config::Config;
+    use super::{ AutoCrlf, Eol, Irreversible, SafeCrlf, Settings };
+
+    #[test]
+    fn converts_as_autocrlf_and_attributes_say() {
+        let config = Config::parse("[core]\n\tautocrlf = true\n\tsafecrlf = warn\n").unwrap();
+        let settings = Settings::from_config(&config);
+        assert_eq!(settings, Settings { autocrlf: AutoCrlf::True, eol: Eol::native(), safecrlf: SafeCrlf::Warn });
+
+        let mut attributes = Attributes::new();
+        attributes.add(b"", b"*.png -text\n*.sh eol=lf\n*.bat text eol=crlf\n");
+        assert_eq!(settings.to_worktree(&attributes, b"a.txt", b"a\nb\n".to_vec()), b"a\r\nb\r\n");
+        assert_eq!(settings.to_worktree(&attributes, b"a.png", b"a\nb\n".to_vec()), b"a\nb\n");
+        assert_eq!(settings.to_worktree(&attributes, b"a.sh", b"a\nb\n".to_vec()), b"a\nb\n");
+        // auto leaves blobs with CRs, and binary ones, alone.
+        assert_eq!(settings.to_worktree(&attributes, b"a.txt", b"a\r\nb\n".to_vec()), b"a\r\nb\n");
+        assert_eq!(settings.to_worktree(&attributes, b"a.txt", b"a\0\nb\n".to_vec()), b"a\0\nb\n");
+
+        assert_eq!(settings.to_git(&attributes, b"a.txt", b"a\r\nb\r\n".to_vec()).unwrap(), (b"a\nb\n".to_vec(), None));
+        assert_eq!(settings.to_git(&attributes, b"a.sh", b"a\r\nb\n".to_vec()).unwrap(), (b"a\nb\n".to_vec(), Some(Irreversible::CrlfToLf)));
+        assert_eq!(settings.to_git(&attributes, b"a.bat", b"a\r\nb\n".to_vec()).unwrap(), (b"a\nb\n".to_vec(), Some(Irreversible::LfToCrlf)));
+        assert_eq!(settings.to_git(&attributes, b"a.png", b"a\r\n".to_vec()).unwrap(), (b"a\r\n".to_vec(), None));
+
+        // without autocrlf only text paths convert, to core.eol.
+        let settings = Settings::from_config(&Config::parse("[core]\n\teol = crlf\n\tsafecrlf = true\n").unwrap());
+        assert_eq!(settings.to_worktree(&attributes, b"a.txt", b"a\n".to_vec()), b"a\n");
+        assert_eq!(settings.to_worktree(&attributes, b"a.bat", b"a\n".to_vec()), b"a\r\n");
+        assert!(settings.to_git(&attributes, b"a.bat", b"a\r\nb\n".to_vec()).is_err());
+        let input = Settings { autocrlf: AutoCrlf::Input, ..settings };
+        assert_eq!(input.to_worktree(&attributes, b"a.txt", b"a\n".to_vec()), b"a\n");
+    }
+
+    #[test]
+    fn auto_tells_text_from_binary() {
+        let mut attributes = Attributes::new();
+        attributes.add(b"", b"* text=auto\n");
+        let settings = Settings { autocrlf: AutoCrlf::False, eol: Eol::Crlf, safecrlf: SafeCrlf::False };
+        assert!(settings.converts(&attributes, b"any"));
+
+        // tabs and escapes are text; a lone CR, a NUL or mostly control
+        // bytes make a file binary.
+        assert_eq!(settings.to_worktree(&attributes, b"a", b"\ta\x1b[0m\n".to_vec()), b"\ta\x1b[0m\r\n");
+        for binary in &[&b"a\rb\n"[..], b"a\0\n", b"\x01\x02\x03\n"] {
+            assert_eq!(settings.to_worktree(&attributes, b"a", binary.to_vec()), binary.to_vec());
+            assert_eq!(settings.to_git(&attributes, b"a", binary.to_vec()).unwrap(), (binary.to_vec(), None));
+        }
+        let mut text = vec![b'x'; 128];
+        text.extend_from_slice(b"\x01\n");
+        assert_eq!(settings.to_git(&attributes, b"a", text.clone()).unwrap().0, text);
+        assert_eq!(settings.to_worktree(&attributes, b"a", text).len(), 131);
+    }
+
+    #[test]
+    fn text_survives_a_round_trip() {
+        let config = Config::parse("[core]\n\tautocrlf = input\n\tsafecrlf = false\n\teol = crlf\n").unwrap();
+        let settings = Settings::from_config(&config);
+        assert_eq!(settings, Settings { autocrlf: AutoCrlf::Input, eol: Eol::Crlf, safecrlf: SafeCrlf::False });
+        let attributes = Attributes::new();
+        // input only converts on the way in, and false keeps quiet.
+        assert_eq!(settings.to_git(&attributes, b"a", b"a\r\nb\n".to_vec()).unwrap(), (b"a\nb\n".to_vec(), None));
+        assert_eq!(settings.to_worktree(&attributes, b"a", b"a\nb\n".to_vec()), b"a\nb\n");
+
+        let settings = Settings { autocrlf: AutoCrlf::True, ..settings };
+        for blob in &[&b""[..], b"no newline", b"a\nb\n\n", b"\n"] {
+            let worktree = settings.to_worktree(&attributes, b"a", blob.to_vec());
+            assert_eq!(settings.to_git(&attributes, b"a", worktree).unwrap().0, blob.to_vec());
+        }
+    }
+}
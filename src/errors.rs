@@ -1,3 +1,8 @@
+use std::path::PathBuf;
+use std::fmt;
+
+use crate::id::Id;
+
 error_chain! {
     foreign_links {
         Io(::std::io::Error);
@@ -10,11 +15,348 @@ error_chain! {
         TruncatedDeltaOutput
         BadDeltaBase
         BadLooseObject
+        CorruptedLooseObject(id: Id, reason: &'static str) {
+            description("corrupted loose object")
+            display("corrupted loose object {}: {}", id, reason)
+        }
         NotImplemented
-        CorruptedPackfile
+        CorruptedPackData(context: Box<Context>, reason: &'static str) {
+            description("corrupted packfile")
+            display("corrupted packfile: {} ({})", reason, context)
+        }
+        PackChecksumMismatch(context: Box<Context>, expected: Id, actual: Id) {
+            description("packfile checksum mismatch")
+            display("packfile checksum mismatch: expected {}, got {} ({})", expected, actual, context)
+        }
+        MissingDeltaBase(context: Box<Context>, base: Id) {
+            description("delta base is missing")
+            display("delta base {} is missing ({})", base, context)
+        }
         InvalidPackfileIndex
         UnsupportedPackfileIndexVersion
         CorruptedPackfileIndex
         NeedStorageSet
+        MissingObject
+        CheckoutInProgress
+        Interrupted
+        CorruptedIndex
+        UnsupportedIndexVersion(version: u32) {
+            description("unsupported index version")
+            display("index version {} is not supported", version)
+        }
+        UnsupportedIndexExtension(signature: String) {
+            description("index has a required extension that is not supported")
+            display("index extension {:?} is not supported", signature)
+        }
+        NoSuchPath(path: Vec<u8>) {
+            description("path does not exist in the tree")
+            display("{} does not exist in the tree", String::from_utf8_lossy(path))
+        }
+        BadPathspec(spec: String) {
+            description("invalid pathspec")
+            display("invalid pathspec {:?}", spec)
+        }
+        BadAbbrev(value: String) {
+            description("invalid core.abbrev")
+            display("invalid core.abbrev {:?}", value)
+        }
+        OperationInProgress
+        NoOperationInProgress
+        MainlineRequired
+        NoSuchParent(n: usize) {
+            description("commit does not have the given parent")
+            display("commit does not have parent {}", n)
+        }
+        LocalChanges(paths: Vec<Vec<u8>>) {
+            description("local changes would be overwritten")
+            display("local changes to {} path(s) would be overwritten", paths.len())
+        }
+        Failures(failures: Vec<Failure>) {
+            description("some items failed")
+            display("{} item(s) failed: {}", failures.len(), failures.iter()
+                .map(|xs| format!("{}: {}", String::from_utf8_lossy(&xs.item), xs.error))
+                .collect::<Vec<_>>()
+                .join("; "))
+        }
+        DestinationExists(path: std::path::PathBuf) {
+            description("destination is already a repository")
+            display("{} is already a repository", path.display())
+        }
+        NothingToClone(path: std::path::PathBuf) {
+            description("source repository has no refs")
+            display("{} has no refs to clone", path.display())
+        }
+        UnmergedIndex(paths: Vec<Vec<u8>>) {
+            description("index has unresolved conflicts")
+            display("{} path(s) have unresolved conflicts", paths.len())
+        }
+        CorruptedCheckoutJournal
+        BadConfig(line: usize) {
+            description("malformed config file")
+            display("malformed config file at line {}", line)
+        }
+        BadConfigParameter(name: String) {
+            description("malformed config parameter in the environment")
+            display("malformed config parameter {}", name)
+        }
+        ConfigIncludeTooDeep(file: std::path::PathBuf) {
+            description("config includes nest too deeply")
+            display("config includes nest too deeply at {}", file.display())
+        }
+        BadPatch(line: usize) {
+            description("malformed patch")
+            display("malformed patch at line {}", line)
+        }
+        BadEmail(reason: &'static str) {
+            description("malformed patch email")
+            display("malformed patch email: {}", reason)
+        }
+        PatchDoesNotApply(path: Vec<u8>) {
+            description("patch does not apply")
+            display("patch does not apply to {}", String::from_utf8_lossy(path))
+        }
+        PatchTargetExists(path: Vec<u8>) {
+            description("patch creates a path that already exists")
+            display("{} already exists", String::from_utf8_lossy(path))
+        }
+        RefLocked(name: String) {
+            description("ref is locked by another writer")
+            display("{} is locked by another writer", name)
+        }
+        RefChanged(name: String) {
+            description("ref changed since it was read")
+            display("{} does not hold the expected value", name)
+        }
+        BadRefName(name: String) {
+            description("invalid ref name")
+            display("{:?} is not a valid ref name", name)
+        }
+        DuplicateRefUpdate(name: String) {
+            description("a ref is updated more than once in a transaction")
+            display("multiple updates for {} are not allowed", name)
+        }
+        BadNamespace(name: String) {
+            description("invalid namespace name")
+            display("{:?} is not a valid namespace", name)
+        }
+        RefOutsideNamespace(name: String) {
+            description("ref is outside of the namespace")
+            display("{} cannot be named from inside a namespace", name)
+        }
+        NoSuchStash(n: usize) {
+            description("no such stash entry")
+            display("stash@{{{}}} does not exist", n)
+        }
+        StashConflict(paths: Vec<Vec<u8>>) {
+            description("stashed changes conflict with the worktree")
+            display("stashed changes conflict with {} path(s) in the worktree", paths.len())
+        }
+        NotARepository(path: PathBuf) {
+            description("not a git repository")
+            display("not a git repository (or any of the parent directories): {}", path.display())
+        }
+        NoSuchBranch(name: String) {
+            description("no such branch")
+            display("no branch named {}", name)
+        }
+        BadCredential(key: &'static str) {
+            description("credential value holds a newline or NUL")
+            display("credential {} holds a newline, carriage return or NUL", key)
+        }
+        FilterFailed(name: String, path: Vec<u8>) {
+            description("a required filter failed")
+            display("filter {} failed on {}", name, String::from_utf8_lossy(path))
+        }
+        IrreversibleEol(path: Vec<u8>, conversion: crate::eol::Irreversible) {
+            description("line ending conversion is irreversible")
+            display("{} in {}", conversion, String::from_utf8_lossy(path))
+        }
+        PathCollision(collisions: Vec<crate::checkout::collisions::Collision>) {
+            description("paths collide on this filesystem")
+            display("{} group(s) of paths collide on this filesystem", collisions.len())
+        }
+        UnsafePath(path: Vec<u8>) {
+            description("path is unsafe to write in a worktree")
+            display("refusing to write {}: it is absolute, leaves the worktree or names .git", String::from_utf8_lossy(path))
+        }
+        BeyondSymlink(path: Vec<u8>) {
+            description("path is beyond a symbolic link")
+            display("refusing to write {}: it is beyond a symbolic link", String::from_utf8_lossy(path))
+        }
+        PathsTooLong(paths: Vec<crate::checkout::paths::LongPath>) {
+            description("paths exceed the filesystem's length limits")
+            display("{} path(s) exceed the filesystem's length limits: {}", paths.len(), paths.iter()
+                .map(|xs| String::from_utf8_lossy(&xs.path).into_owned())
+                .collect::<Vec<_>>()
+                .join(", "))
+        }
+    }
+}
+
+// Where reading the object database went wrong: what was being done, in
+// which pack, at which offset and for which object, as far as each is
+// known.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Context {
+    pub operation: Option<&'static str>,
+    pub pack: Option<PathBuf>,
+    pub offset: Option<u64>,
+    pub id: Option<Id>
+}
+
+impl Context {
+    pub fn new(operation: &'static str) -> Context {
+        Context { operation: Some(operation), ..Context::default() }
+    }
+
+    pub fn pack<P: Into<PathBuf>>(mut self, pack: P) -> Context {
+        self.pack = Some(pack.into());
+        self
+    }
+
+    pub fn offset(mut self, offset: u64) -> Context {
+        self.offset = Some(offset);
+        self
+    }
+
+    pub fn id(mut self, id: Id) -> Context {
+        self.id = Some(id);
+        self
+    }
+
+    // Takes whatever `outer` knows that this doesn't.
+    fn fill(&mut self, outer: Context) {
+        self.operation = self.operation.or(outer.operation);
+        self.pack = self.pack.take().or(outer.pack);
+        self.offset = self.offset.or(outer.offset);
+        self.id = self.id.take().or(outer.id);
+    }
+}
+
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(xs) = self.operation {
+            parts.push(format!("while {}", xs));
+        }
+        if let Some(ref xs) = self.pack {
+            parts.push(xs.display().to_string());
+        }
+        if let Some(xs) = self.offset {
+            parts.push(format!("at offset {}", xs));
+        }
+        if let Some(ref xs) = self.id {
+            parts.push(format!("reading {}", xs));
+        }
+        if parts.is_empty() {
+            return write!(f, "no context")
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+impl ErrorKind {
+    pub fn context(&self) -> Option<&Context> {
+        match self {
+            ErrorKind::CorruptedPackData(xs, _) |
+            ErrorKind::PackChecksumMismatch(xs, _, _) |
+            ErrorKind::MissingDeltaBase(xs, _) => Some(xs),
+            _ => None
+        }
+    }
+
+    fn context_mut(&mut self) -> Option<&mut Context> {
+        match self {
+            ErrorKind::CorruptedPackData(xs, _) |
+            ErrorKind::PackChecksumMismatch(xs, _, _) |
+            ErrorKind::MissingDeltaBase(xs, _) => Some(xs),
+            _ => None
+        }
+    }
+}
+
+impl Error {
+    // Fills in the parts of `context` the error doesn't know itself, for
+    // callers further up that know which pack or object was being read.
+    pub fn within(mut self, context: Context) -> Error {
+        if let Some(xs) = self.0.context_mut() {
+            xs.fill(context);
+        }
+        self
+    }
+}
+
+// One item a batch operation failed on (a path, an object id, a ref name)
+// and why.
+#[derive(Debug)]
+pub struct Failure {
+    pub item: Vec<u8>,
+    pub error: Error
+}
+
+// Collects per-item errors for operations with a `keep_going` option, so
+// far `Checkout::keep_going` and the CLI's verify-pack: without it the
+// first error is returned as is, with it every failure is kept and reported
+// together by `finish`.
+#[derive(Debug, Default)]
+pub struct Failures {
+    keep_going: bool,
+    failures: Vec<Failure>
+}
+
+impl Failures {
+    pub fn new(keep_going: bool) -> Failures {
+        Failures {
+            keep_going,
+            failures: Vec::new()
+        }
+    }
+
+    // None if `result` failed and the failure was recorded.
+    pub fn check<T>(&mut self, item: &[u8], result: Result<T>) -> Result<Option<T>> {
+        match result {
+            Ok(xs) => Ok(Some(xs)),
+            Err(error) if self.keep_going => {
+                self.failures.push(Failure { item: item.to_vec(), error });
+                Ok(None)
+            },
+            Err(error) => Err(error)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    pub fn finish(self) -> Result<()> {
+        if self.failures.is_empty() {
+            return Ok(())
+        }
+        Err(ErrorKind::Failures(self.failures).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::id::Id;
+    use super::{ Context, Error, ErrorKind };
+
+    #[test]
+    fn contexts_fill_in_from_outside() {
+        let error: Error = ErrorKind::CorruptedPackData(Context::new("reading object").offset(12).into(), "unknown object type").into();
+        let error = error.within(Context::default().pack("objects/pack/pack-1.pack").offset(0).id(Id::default()));
+        assert_eq!(error.kind().context(), Some(&Context {
+            operation: Some("reading object"),
+            pack: Some("objects/pack/pack-1.pack".into()),
+            offset: Some(12),
+            id: Some(Id::default())
+        }));
+        assert_eq!(
+            error.to_string(),
+            format!("corrupted packfile: unknown object type (while reading object, objects/pack/pack-1.pack, at offset 12, reading {})", Id::default())
+        );
+
+        let error: Error = ErrorKind::MissingObject.into();
+        assert!(error.within(Context::new("reading object")).kind().context().is_none());
     }
 }
@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+use std::io::{ self, BufReader, Read, Write };
+use std::path::{ Path, PathBuf };
+use std::process::{ Child, Command, Stdio };
+use std::sync::Mutex;
+
+use crate::attributes::{ Attributes, State };
+use crate::errors::{ ErrorKind, Result };
+use crate::objects::tree::{ FileMode, TreeEntry };
+use crate::objects::{ self, Type };
+use crate::eol::{ self, Irreversible };
+use crate::stash::read_worktree;
+use crate::config::Config;
+
+// pkt-lines carry at most this much data.
+const MAX_PACKET_DATA: usize = 65516;
+
+// One `[filter "<name>"]` section.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Driver {
+    pub clean: Option<String>,
+    pub smudge: Option<String>,
+    // a long-running filter speaking git's filter protocol, used instead of
+    // `clean` and `smudge` when set.
+    pub process: Option<String>,
+    // a failing filter fails the checkout or add, instead of the content
+    // passing through as it is.
+    pub required: bool
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Direction {
+    Clean,
+    Smudge
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Direction::Clean => "clean",
+            Direction::Smudge => "smudge"
+        }
+    }
+}
+
+// What content goes through between the object database and the worktree:
+// line ending conversion (see `eol`) and the filter drivers named by
+// `filter=<name>` attributes. Smudge runs as blobs are written to the
+// worktree, clean as worktree files are hashed into blobs. Commands run
+// through the shell from the worktree, with "%f" replaced by the quoted
+// path. Paths without a configured driver pass through untouched.
+pub struct Filters {
+    drivers: HashMap<String, Driver>,
+    attributes: Attributes,
+    worktree: PathBuf,
+    eol: eol::Settings,
+    // core.safecrlf=warn warnings from `clean`, for the caller to show.
+    warnings: Mutex<Vec<(Vec<u8>, Irreversible)>>,
+    // started on first use and kept for every path after; None once one
+    // failed to start or broke the protocol, as git gives up on it too.
+    processes: Mutex<HashMap<String, Option<Process>>>
+}
+
+impl Filters {
+    pub fn new(config: &Config, attributes: Attributes, worktree: &Path) -> Filters {
+        let drivers = config.subsections("filter").into_iter().map(|name| {
+            let key = |xs: &str| format!("filter.{}.{}", name, xs);
+            let driver = Driver {
+                clean: config.get(&key("clean")).map(String::from),
+                smudge: config.get(&key("smudge")).map(String::from),
+                process: config.get(&key("process")).map(String::from),
+                required: config.get_bool(&key("required")).unwrap_or(false)
+            };
+            (String::from(name), driver)
+        }).collect();
+        Filters::with_drivers(drivers, attributes, worktree).with_eol(eol::Settings::from_config(config))
+    }
+
+    pub fn with_drivers(drivers: HashMap<String, Driver>, attributes: Attributes, worktree: &Path) -> Filters {
+        Filters {
+            drivers,
+            attributes,
+            worktree: worktree.to_path_buf(),
+            eol: eol::Settings::default(),
+            warnings: Mutex::new(Vec::new()),
+            processes: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn with_eol(mut self, eol: eol::Settings) -> Filters {
+        self.eol = eol;
+        self
+    }
+
+    // `data` as the worktree should have it: line endings are converted
+    // before the smudge filter runs.
+    pub fn smudge(&self, path: &[u8], data: Vec<u8>) -> Result<Vec<u8>> {
+        let data = self.eol.to_worktree(&self.attributes, path, data);
+        self.apply(Direction::Smudge, path, data)
+    }
+
+    // `data` as the object database should have it: the clean filter runs
+    // before line endings are converted.
+    pub fn clean(&self, path: &[u8], data: Vec<u8>) -> Result<Vec<u8>> {
+        let data = self.apply(Direction::Clean, path, data)?;
+        let (data, warning) = self.eol.to_git(&self.attributes, path, data)?;
+        if let Some(xs) = warning {
+            self.warnings.lock().unwrap().push((path.to_vec(), xs));
+        }
+        Ok(data)
+    }
+
+    // Whether `clean` could change `path` at all: a driver or line ending
+    // conversion applies to it. Paths it can't may be streamed in as they
+    // are.
+    pub fn cleans(&self, path: &[u8]) -> bool {
+        let driver = match self.attributes.get(path, "filter") {
+            Some(State::Value(xs)) => self.drivers.get(&xs),
+            _ => None
+        };
+        driver.is_some_and(|xs| xs.process.is_some() || xs.clean.is_some()) || self.eol.converts(&self.attributes, path)
+    }
+
+    // The paths `clean` converted irreversibly since the last call.
+    pub fn take_warnings(&self) -> Vec<(Vec<u8>, Irreversible)> {
+        std::mem::take(&mut *self.warnings.lock().unwrap())
+    }
+
+    // The entry worktree file `path` would be staged as, cleaned; None if
+    // it's missing. Symlinks are never filtered.
+    pub fn hash_worktree(&self, path: &[u8]) -> Result<Option<TreeEntry>> {
+        let (mode, contents) = match read_worktree(&self.worktree, path)? {
+            Some(xs) => xs,
+            None => return Ok(None)
+        };
+        let contents = if mode == FileMode::SYMLINK { contents } else { self.clean(path, contents)? };
+        Ok(Some(TreeEntry { mode, id: objects::hash(Type::Blob, &contents) }))
+    }
+
+    fn apply(&self, direction: Direction, path: &[u8], data: Vec<u8>) -> Result<Vec<u8>> {
+        let name = match self.attributes.get(path, "filter") {
+            Some(State::Value(xs)) => xs,
+            _ => return Ok(data)
+        };
+        let driver = match self.drivers.get(&name) {
+            Some(xs) => xs,
+            None => return Ok(data)
+        };
+
+        let command = match direction {
+            Direction::Clean => driver.clean.as_ref(),
+            Direction::Smudge => driver.smudge.as_ref()
+        };
+        let filtered = match (&driver.process, command) {
+            (Some(process), _) => self.run_process(&name, process, direction, path, &data),
+            (None, Some(command)) => run_command(command, &self.worktree, path, &data).ok().flatten(),
+            (None, None) => None
+        };
+        match filtered {
+            Some(xs) => Ok(xs),
+            None if driver.required => Err(ErrorKind::FilterFailed(name, path.to_vec()).into()),
+            None => Ok(data)
+        }
+    }
+
+    fn run_process(&self, name: &str, command: &str, direction: Direction, path: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+        let mut processes = self.processes.lock().unwrap();
+        let process = processes.entry(String::from(name)).or_insert_with(|| Process::start(command, &self.worktree).ok());
+        match process.as_mut()?.filter(direction, path, data) {
+            Ok(xs) => xs,
+            Err(_) => {
+                *process = None;
+                None
+            }
+        }
+    }
+}
+
+fn quote(path: &[u8]) -> String {
+    format!("'{}'", String::from_utf8_lossy(path).replace('\'', "'\\''"))
+}
+
+// The command's output, or None if it exited unsuccessfully.
+fn run_command(command: &str, worktree: &Path, path: &[u8], data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command.replace("%f", &quote(path)))
+        .current_dir(worktree)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let mut input = child.stdin.take().expect("stdin is piped");
+    let output = std::thread::scope(|scope| {
+        // fed from another thread so a filter writing before it has read
+        // everything can't deadlock us. A filter that ignores its input
+        // closes the pipe early; its exit status decides.
+        scope.spawn(move || input.write_all(data));
+        child.wait_with_output()
+    })?;
+    Ok(if output.status.success() { Some(output.stdout) } else { None })
+}
+
+pub(crate) fn write_packet(output: &mut dyn Write, data: &[u8]) -> io::Result<()> {
+    write!(output, "{:04x}", data.len() + 4)?;
+    output.write_all(data)
+}
+
+pub(crate) fn write_flush(output: &mut dyn Write) -> io::Result<()> {
+    output.write_all(b"0000")
+}
+
+// None for a flush packet.
+pub(crate) fn read_packet(input: &mut dyn Read) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; 4];
+    input.read_exact(&mut header)?;
+    let len = std::str::from_utf8(&header).ok()
+        .and_then(|xs| usize::from_str_radix(xs, 16).ok())
+        .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+    match len {
+        0 => Ok(None),
+        1..=3 => Err(io::ErrorKind::InvalidData.into()),
+        _ => {
+            let mut data = vec![0; len - 4];
+            input.read_exact(&mut data)?;
+            Ok(Some(data))
+        }
+    }
+}
+
+// Text packets up to a flush, without their newlines.
+fn read_list(input: &mut dyn Read) -> io::Result<Vec<String>> {
+    let mut list = Vec::new();
+    while let Some(xs) = read_packet(input)? {
+        list.push(String::from_utf8_lossy(&xs).trim_end_matches('\n').to_string());
+    }
+    Ok(list)
+}
+
+fn status(list: &[String]) -> Option<&str> {
+    list.iter().rev().find_map(|xs| xs.strip_prefix("status="))
+}
+
+// A long-running filter (`filter.<name>.process`), spoken to in version 2
+// of git's filter protocol over pkt-lines.
+struct Process {
+    input: Option<Box<dyn Write + Send>>,
+    output: BufReader<Box<dyn Read + Send>>,
+    capabilities: Vec<String>,
+    child: Option<Child>
+}
+
+impl Process {
+    fn start(command: &str, worktree: &Path) -> io::Result<Process> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .current_dir(worktree)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let input = child.stdin.take().expect("stdin is piped");
+        let output = child.stdout.take().expect("stdout is piped");
+        let mut process = Process::connect(Box::new(input), Box::new(output))?;
+        process.child = Some(child);
+        Ok(process)
+    }
+
+    fn connect(input: Box<dyn Write + Send>, output: Box<dyn Read + Send>) -> io::Result<Process> {
+        let mut process = Process {
+            input: Some(input),
+            output: BufReader::new(output),
+            capabilities: Vec::new(),
+            child: None
+        };
+        let (input, output) = process.streams();
+        write_packet(input, b"git-filter-client\n")?;
+        write_packet(input, b"version=2\n")?;
+        write_flush(input)?;
+        input.flush()?;
+        let welcome = read_list(output)?;
+        if welcome.first().map(String::as_str) != Some("git-filter-server") || !welcome.iter().any(|xs| xs == "version=2") {
+            return Err(io::ErrorKind::InvalidData.into())
+        }
+
+        write_packet(input, b"capability=clean\n")?;
+        write_packet(input, b"capability=smudge\n")?;
+        write_flush(input)?;
+        input.flush()?;
+        process.capabilities = read_list(process.streams().1)?.into_iter()
+            .filter_map(|xs| xs.strip_prefix("capability=").map(String::from))
+            .collect();
+        Ok(process)
+    }
+
+    fn streams(&mut self) -> (&mut dyn Write, &mut dyn Read) {
+        (self.input.as_mut().expect("input is open until drop"), &mut self.output)
+    }
+
+    // Ok(None) when the filter reports an error for this path; content
+    // for a direction it doesn't do passes through.
+    fn filter(&mut self, direction: Direction, path: &[u8], data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        if !self.capabilities.iter().any(|xs| xs == direction.as_str()) {
+            return Ok(Some(data.to_vec()))
+        }
+
+        let (input, output) = self.streams();
+        write_packet(input, format!("command={}\n", direction.as_str()).as_bytes())?;
+        let mut pathname = b"pathname=".to_vec();
+        pathname.extend_from_slice(path);
+        pathname.push(b'\n');
+        write_packet(input, &pathname)?;
+        write_flush(input)?;
+        for chunk in data.chunks(MAX_PACKET_DATA) {
+            write_packet(input, chunk)?;
+        }
+        write_flush(input)?;
+        input.flush()?;
+
+        if status(&read_list(output)?) != Some("success") {
+            return Ok(None)
+        }
+        let mut content = Vec::new();
+        while let Some(xs) = read_packet(output)? {
+            content.extend_from_slice(&xs);
+        }
+        // an empty list keeps the status sent before the content.
+        match status(&read_list(output)?) {
+            None | Some("success") => Ok(Some(content)),
+            Some(_) => Ok(None)
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // a closed stdin is the filter's cue to exit.
+        self.input = None;
+        if let Some(ref mut child) = self.child {
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Write;
+
+    use crate::attributes::Attributes;
+    use crate::testkit::TempDir;
+    use crate::eol::Irreversible;
+    use crate::config::Config;
+    use super::{ read_list, read_packet, write_flush, write_packet, Direction, Driver, Filters, Process };
+
+    #[test]
+    fn runs_clean_and_smudge_commands() {
+        let dir = TempDir::new("filter").expect("failed to create tempdir");
+        let config = Config::parse("[filter \"upper\"]\n\tsmudge = tr a-z A-Z\n\tclean = tr A-Z a-z\n[filter \"broken\"]\n\tsmudge = false\n\trequired\n").unwrap();
+        let mut attributes = Attributes::new();
+        attributes.add(b"", b"*.txt filter=upper\n*.bin filter=broken\n*.md filter=unconfigured\n");
+        let filters = Filters::new(&config, attributes, dir.path());
+
+        assert_eq!(filters.smudge(b"a.txt", b"hello\n".to_vec()).unwrap(), b"HELLO\n");
+        assert_eq!(filters.clean(b"a.txt", b"HELLO\n".to_vec()).unwrap(), b"hello\n");
+        assert_eq!(filters.smudge(b"a.md", b"hello\n".to_vec()).unwrap(), b"hello\n");
+        assert!(filters.smudge(b"a.bin", b"hello\n".to_vec()).is_err());
+
+        std::fs::write(dir.path().join("a.txt"), "HELLO\n").unwrap();
+        let entry = filters.hash_worktree(b"a.txt").unwrap().unwrap();
+        assert_eq!(entry.id, crate::objects::hash(crate::objects::Type::Blob, b"hello\n"));
+        assert!(filters.hash_worktree(b"missing.txt").unwrap().is_none());
+
+        // line endings convert on the LF side of the filter.
+        let config = Config::parse("[core]\n\tautocrlf = true\n[filter \"upper\"]\n\tsmudge = tr a-z A-Z\n\tclean = tr A-Z a-z\n").unwrap();
+        let mut attributes = Attributes::new();
+        attributes.add(b"", b"*.txt filter=upper\n");
+        let filters = Filters::new(&config, attributes, dir.path());
+        assert_eq!(filters.smudge(b"a.txt", b"a\nb\n".to_vec()).unwrap(), b"A\r\nB\r\n");
+        assert_eq!(filters.clean(b"a.txt", b"A\r\nB\n".to_vec()).unwrap(), b"a\nb\n");
+        assert_eq!(filters.take_warnings(), vec![(b"a.txt".to_vec(), Irreversible::LfToCrlf)]);
+        assert!(filters.take_warnings().is_empty());
+    }
+
+    #[test]
+    fn speaks_the_filter_protocol() {
+        let (client_output, mut server_input) = std::io::pipe().unwrap();
+        let (mut server_output, client_input) = std::io::pipe().unwrap();
+        let server = std::thread::spawn(move || {
+            assert_eq!(read_list(&mut server_output).unwrap(), vec!["git-filter-client", "version=2"]);
+            write_packet(&mut server_input, b"git-filter-server\n").unwrap();
+            write_packet(&mut server_input, b"version=2\n").unwrap();
+            write_flush(&mut server_input).unwrap();
+            assert_eq!(read_list(&mut server_output).unwrap(), vec!["capability=clean", "capability=smudge"]);
+            write_packet(&mut server_input, b"capability=smudge\n").unwrap();
+            write_flush(&mut server_input).unwrap();
+
+            assert_eq!(read_list(&mut server_output).unwrap(), vec!["command=smudge", "pathname=big.txt"]);
+            let mut content = Vec::new();
+            while let Some(xs) = read_packet(&mut server_output).unwrap() {
+                content.extend(xs);
+            }
+            write_packet(&mut server_input, b"status=success\n").unwrap();
+            write_flush(&mut server_input).unwrap();
+            for chunk in content.to_ascii_uppercase().chunks(super::MAX_PACKET_DATA) {
+                write_packet(&mut server_input, chunk).unwrap();
+            }
+            write_flush(&mut server_input).unwrap();
+            write_flush(&mut server_input).unwrap();
+            server_input.flush().unwrap();
+            content.len()
+        });
+
+        let mut process = Process::connect(Box::new(client_input), Box::new(client_output)).expect("failed to connect");
+        assert_eq!(process.capabilities, vec!["smudge"]);
+        // spans several packets.
+        let data = b"abc".repeat(30000);
+        assert_eq!(process.filter(Direction::Smudge, b"big.txt", &data).unwrap(), Some(b"ABC".repeat(30000)));
+        assert_eq!(process.filter(Direction::Clean, b"big.txt", b"abc").unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(server.join().unwrap(), data.len());
+
+        // a process that can't be started leaves content as it is, unless
+        // the filter is required.
+        let mut drivers = HashMap::new();
+        drivers.insert(String::from("lfs"), Driver { process: Some(String::from("exit 1")), ..Driver::default() });
+        let mut attributes = Attributes::new();
+        attributes.add(b"", b"* filter=lfs\n");
+        let dir = TempDir::new("filter-process").expect("failed to create tempdir");
+        let filters = Filters::with_drivers(drivers, attributes, dir.path());
+        assert_eq!(filters.smudge(b"a", b"abc".to_vec()).unwrap(), b"abc");
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::HashSet;
+use std::path::{ Path, PathBuf };
+use std::io::Write;
+
+use crate::diff::{ self, diff_trees, stat, write_stat };
+use crate::stores::{ Queryable, StorageSet };
+use crate::walk::commits::CommitIterator;
+use crate::errors::{ ErrorKind, Result };
+use crate::objects::commit::Commit;
+use crate::identity::Identity;
+use crate::objects::Object;
+use crate::patch::write::write_abbrev as write_patch;
+use crate::id::Id;
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    // number of the first patch in the series
+    pub start_number: usize,
+    // "[PATCH n/m]"; by default only for series of more than one patch
+    pub numbered: Option<bool>,
+    pub subject_prefix: String,
+    // the text after the "-- " line; None leaves the signature out
+    pub signature: Option<String>,
+    pub diff: diff::Options
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            start_number: 1,
+            numbered: None,
+            subject_prefix: String::from("PATCH"),
+            signature: Some(String::from(env!("CARGO_PKG_VERSION"))),
+            diff: diff::Options { binary: true, ..diff::Options::default() }
+        }
+    }
+}
+
+impl Options {
+    // The defaults, with the repository's core.abbrev on "index" lines.
+    pub fn from_path(path: &Path) -> Result<Options> {
+        let options = Options::default();
+        Ok(Options { diff: diff::Options { abbrev: crate::abbrev::length(path)?, ..options.diff }, ..options })
+    }
+}
+
+pub struct Email {
+    pub id: Id,
+    pub number: usize,
+    // the subject without its "[PATCH]" prefix
+    pub subject: String,
+    pub contents: Vec<u8>
+}
+
+impl Email {
+    // "0001-fix-the-thing.patch", as git names them.
+    pub fn filename(&self) -> String {
+        let mut name = String::new();
+        for xs in self.subject.chars() {
+            if xs.is_ascii_alphanumeric() || xs == '.' || xs == '_' {
+                name.push(xs);
+            } else if !name.is_empty() && !name.ends_with('-') {
+                name.push('-');
+            }
+        }
+        let mut name: String = name.chars().take(52).collect();
+        while name.ends_with('-') || name.ends_with('.') {
+            name.pop();
+        }
+        format!("{:04}-{}.patch", self.number, name)
+    }
+}
+
+// Commits reachable from `until` but not from `since`, oldest first. Merges
+// are left out: they have no single diff to mail.
+pub fn range<S: Queryable>(storage_set: &StorageSet<S>, since: Option<&Id>, until: &Id) -> Result<Vec<Id>> {
+    let excluded: HashSet<Id> = match since {
+        Some(xs) => CommitIterator::new(storage_set, xs, None).map(|(id, _)| id).collect(),
+        None => HashSet::new()
+    };
+    if excluded.contains(until) {
+        return Ok(Vec::new())
+    }
+
+    let mut ids: Vec<Id> = CommitIterator::new(storage_set, until, Some(excluded))
+        .filter(|(_, commit)| commit.parents().map_or(0, |xs| xs.len()) < 2)
+        .map(|(id, _)| id)
+        .collect();
+    ids.reverse();
+    Ok(ids)
+}
+
+fn needs_encoding(text: &[u8]) -> bool {
+    text.iter().any(|xs| *xs >= 0x80)
+}
+
+// RFC 2047 "Q" encoding for header text that is not plain ASCII.
+fn encode_header(text: &[u8]) -> String {
+    if !needs_encoding(text) {
+        return String::from_utf8_lossy(text).into_owned()
+    }
+    let mut output = String::from("=?UTF-8?q?");
+    for byte in text {
+        match *byte {
+            b' ' => output.push('_'),
+            xs if xs.is_ascii_alphanumeric() => output.push(xs as char),
+            xs => output.push_str(&format!("={:02X}", xs))
+        }
+    }
+    output.push_str("?=");
+    output
+}
+
+fn encode_name(name: &[u8]) -> String {
+    if !needs_encoding(name) && name.iter().any(|xs| b"()<>@,;:\\\".[]".contains(xs)) {
+        let escaped = String::from_utf8_lossy(name).replace('\\', "\\\\").replace('"', "\\\"");
+        return format!("\"{}\"", escaped)
+    }
+    encode_header(name)
+}
+
+// The subject is the first paragraph folded onto one line; the body is
+// everything after it.
+fn split_message(message: &[u8]) -> (Vec<u8>, &[u8]) {
+    let end = message.windows(2).position(|xs| xs == b"\n\n").unwrap_or(message.len());
+    let subject: Vec<&[u8]> = message[..end].split(|xs| *xs == b'\n').map(|xs| xs.trim_ascii()).filter(|xs| !xs.is_empty()).collect();
+    let mut body = &message[end..];
+    while let Some(rest) = body.strip_prefix(b"\n") {
+        body = rest;
+    }
+    (subject.join(&b' '), body)
+}
+
+fn write_email<S: Queryable>(
+    storage_set: &StorageSet<S>,
+    id: &Id,
+    commit: &Commit,
+    tag: &str,
+    options: &Options,
+    output: &mut Vec<u8>
+) -> Result<Vec<u8>> {
+    let author: &Identity = match commit.author() {
+        Some(xs) => xs,
+        None => return Err(ErrorKind::MissingObject.into())
+    };
+    let parent = commit.parents().and_then(|xs| xs.into_iter().next());
+    let parent_tree = match parent {
+        Some(ref xs) => match storage_set.get_and_load(xs)? {
+            Some(Object::Commit(parent)) => parent.tree(),
+            _ => return Err(ErrorKind::MissingObject.into())
+        },
+        None => None
+    };
+    let files = diff_trees(storage_set, parent_tree.as_ref(), commit.tree().as_ref(), &options.diff)?;
+
+    let mut diff = Vec::new();
+    for file in &files {
+        write_patch(&mut diff, file, options.diff.abbrev)?;
+    }
+    let (subject, body) = split_message(commit.message());
+
+    // the date every mbox "From " line carries, for tools that sniff for it.
+    writeln!(output, "From {} Mon Sep 17 00:00:00 2001", id)?;
+    write!(output, "From: {} <", encode_name(author.name()))?;
+    output.write_all(author.email())?;
+    writeln!(output, ">")?;
+    writeln!(output, "Date: {}", author.at().with_timezone(author.offset()).to_rfc2822())?;
+    writeln!(output, "Subject: {}{}", tag, encode_header(&subject))?;
+    if needs_encoding(commit.message()) || needs_encoding(&diff) {
+        output.write_all(b"MIME-Version: 1.0\nContent-Type: text/plain; charset=UTF-8\nContent-Transfer-Encoding: 8bit\n")?;
+    }
+    output.write_all(b"\n")?;
+    output.write_all(body)?;
+    if !body.is_empty() && !body.ends_with(b"\n") {
+        output.write_all(b"\n")?;
+    }
+
+    output.write_all(b"---\n")?;
+    write_stat(output, &stat(&files), 80)?;
+    for file in files.iter().filter(|xs| xs.is_add() || xs.is_delete()) {
+        let (verb, mode) = if file.is_add() { ("create", file.new_mode) } else { ("delete", file.old_mode) };
+        write!(output, " {} mode {:o} ", verb, mode.map_or(0, |xs| xs.bits()))?;
+        output.write_all(file.path())?;
+        output.write_all(b"\n")?;
+    }
+    output.write_all(b"\n")?;
+    output.write_all(&diff)?;
+
+    if let Some(ref signature) = options.signature {
+        writeln!(output, "-- \n{}\n", signature)?;
+    }
+    Ok(subject)
+}
+
+// Formats each commit as an RFC 2822 message, in the order given; use
+// `range` to pick a series. Concatenating the contents gives an mbox.
+pub fn format<S: Queryable>(storage_set: &StorageSet<S>, ids: &[Id], options: &Options) -> Result<Vec<Email>> {
+    let numbered = options.numbered.unwrap_or(ids.len() > 1);
+    let total = (options.start_number + ids.len()).saturating_sub(1);
+
+    let mut emails = Vec::with_capacity(ids.len());
+    for (offset, id) in ids.iter().enumerate() {
+        let commit = match storage_set.get_and_load(id)? {
+            Some(Object::Commit(xs)) => xs,
+            _ => return Err(ErrorKind::MissingObject.into())
+        };
+
+        let number = options.start_number + offset;
+        let tag = match (options.subject_prefix.is_empty(), numbered) {
+            (true, false) => String::new(),
+            (true, true) => format!("[{}/{}] ", number, total),
+            (false, false) => format!("[{}] ", options.subject_prefix),
+            (false, true) => format!("[{} {}/{}] ", options.subject_prefix, number, total)
+        };
+
+        let mut contents = Vec::new();
+        let subject = write_email(storage_set, id, &commit, &tag, options, &mut contents)?;
+        emails.push(Email {
+            id: id.clone(),
+            number,
+            subject: String::from_utf8_lossy(&subject).into_owned(),
+            contents
+        });
+    }
+    Ok(emails)
+}
+
+// Writes each email to its own file under `dir`, returning the paths.
+pub fn write_files(dir: &Path, emails: &[Email]) -> std::io::Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)?;
+    emails.iter().map(|email| {
+        let path = dir.join(email.filename());
+        std::fs::write(path.as_path(), &email.contents)?;
+        Ok(path)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::patch::{ apply::{ apply_blob, Options as ApplyOptions }, parse };
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::stores::fs as gitfs;
+    use crate::files;
+    use super::{ encode_header, encode_name, format, range, split_message, write_files, Email, Options };
+
+    #[test]
+    fn series_is_numbered_and_applies() {
+        let builder = RepoBuilder::new()
+            .author("Zo\u{eb} Example", "zoe@example.com")
+            .commit("first", files!["README" => "hello\n"]);
+        let base = builder.tip().unwrap();
+        let builder = builder
+            .commit("Change the greeting\n\nIt was too terse.\n", files!["README" => "hello there\n"])
+            .commit("Add notes", files!["NOTES" => "one\ntwo\n"]);
+        let tip = builder.tip().unwrap();
+        let (storage_set, _) = builder.in_memory();
+
+        let ids = range(&storage_set, Some(&base), &tip).expect("failed to walk");
+        assert_eq!(ids.len(), 2);
+        assert!(range(&storage_set, Some(&tip), &base).expect("failed to walk").is_empty());
+
+        let emails = format(&storage_set, &ids, &Options::default()).expect("failed to format");
+        assert_eq!(emails[0].filename(), "0001-Change-the-greeting.patch");
+        let text = String::from_utf8(emails[0].contents.clone()).unwrap();
+        assert!(text.starts_with(&format!("From {} Mon Sep 17 00:00:00 2001\n", ids[0])));
+        assert!(text.contains("From: =?UTF-8?q?Zo=C3=AB_Example?= <zoe@example.com>\n"));
+        assert!(text.contains("Subject: [PATCH 1/2] Change the greeting\n\nIt was too terse.\n---\n README | 2 +-\n"));
+        assert!(text.contains(" 1 file changed, 1 insertion(+), 1 deletion(-)\n"));
+        assert!(text.ends_with(&format!("-- \n{}\n\n", env!("CARGO_PKG_VERSION"))));
+
+        let second = String::from_utf8(emails[1].contents.clone()).unwrap();
+        assert!(second.contains("Subject: [PATCH 2/2] Add notes\n"));
+        assert!(second.contains(" create mode 100644 NOTES\n"));
+
+        let files = parse(&emails[0].contents).expect("failed to parse");
+        let patched = apply_blob(Some(&b"hello\n"[..]), &files[0], &ApplyOptions::default()).expect("failed to apply");
+        assert_eq!(patched.as_deref(), Some(&b"hello there\n"[..]));
+
+        let single = format(&storage_set, &ids[1..], &Options::default()).expect("failed to format");
+        assert!(String::from_utf8_lossy(&single[0].contents).contains("Subject: [PATCH] Add notes\n"));
+
+        let dir = TempDir::new("format-patch").expect("failed to create tempdir");
+        let paths = write_files(dir.path(), &emails).expect("failed to write");
+        assert_eq!(paths[1].file_name().unwrap(), "0002-Add-notes.patch");
+        assert_eq!(std::fs::read(&paths[1]).unwrap(), emails[1].contents);
+    }
+
+    #[test]
+    fn index_lines_follow_core_abbrev() {
+        let dir = TempDir::new("format-patch-abbrev").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"])
+            .commit("second", files!["README" => "hello there\n"]);
+        let tip = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        std::fs::write(dir.path().join(".git/config"), "[core]\n\tabbrev = 12\n").unwrap();
+        let storage_set = gitfs::from(dir.path()).expect("failed to open");
+
+        let options = Options::from_path(dir.path()).expect("failed to read config");
+        assert_eq!(options.diff.abbrev, 12);
+        let emails = format(&storage_set, &[tip], &options).expect("failed to format");
+        let text = String::from_utf8(emails[0].contents.clone()).unwrap();
+        let index = text.lines().find(|xs| xs.starts_with("index ")).expect("no index line");
+        let (old, new) = index[6..].split_once("..").unwrap();
+        assert_eq!(old.len(), 12);
+        assert_eq!(new.split(' ').next().unwrap().len(), 12);
+    }
+
+    #[test]
+    fn headers_subjects_and_filenames() {
+        assert_eq!(encode_name(b"Plain Name"), "Plain Name");
+        assert_eq!(encode_name(b"Last, First \"Nick\""), "\"Last, First \\\"Nick\\\"\"");
+        assert_eq!(encode_header("caf\u{e9} au lait".as_bytes()), "=?UTF-8?q?caf=C3=A9_au_lait?=");
+
+        let (subject, body) = split_message(b"Fix the\n  wrapped   \nsubject\n\n\nBody text.\n");
+        assert_eq!(subject, b"Fix the wrapped subject");
+        assert_eq!(body, b"Body text.\n");
+        let (subject, body) = split_message(b"Only a subject");
+        assert_eq!((&subject[..], body), (&b"Only a subject"[..], &b""[..]));
+
+        let email = |number, subject: &str| Email { id: Default::default(), number, subject: String::from(subject), contents: Vec::new() };
+        assert_eq!(email(3, "[foo] Don't crash on v1.2_rc!").filename(), "0003-foo-Don-t-crash-on-v1.2_rc.patch");
+        let long = email(12, &"word ".repeat(20));
+        assert_eq!(long.filename(), format!("0012-{}.patch", ["word"; 10].join("-") + "-wo"));
+    }
+
+    #[test]
+    fn ranges_skip_merges_and_prefixes_follow_options() {
+        let builder = RepoBuilder::new().commit("first", files!["README" => "a\n"]);
+        let base = builder.tip().unwrap();
+        let builder = builder.branch("side")
+            .commit("second", files!["README" => "b\n"])
+            .checkout("side")
+            .commit("side", files!["NOTES" => "n\n"])
+            .checkout("master")
+            .merge("merge", "side");
+        let tip = builder.tip().unwrap();
+        let (storage_set, _) = builder.in_memory();
+
+        let ids = range(&storage_set, Some(&base), &tip).expect("failed to walk");
+        assert_eq!(ids.len(), 2);
+        assert!(!ids.contains(&tip));
+        assert_eq!(range(&storage_set, None, &base).unwrap(), vec![base.clone()]);
+
+        let subjects = |options: &Options| -> Vec<String> {
+            format(&storage_set, &ids, options).unwrap().iter()
+                .map(|xs| String::from_utf8_lossy(&xs.contents).lines().find(|xs| xs.starts_with("Subject: ")).unwrap().to_string())
+                .collect()
+        };
+        let options = Options { start_number: 4, subject_prefix: String::from("RFC PATCH v2"), signature: None, ..Options::default() };
+        let numbered = subjects(&options);
+        assert!(numbered[0].starts_with("Subject: [RFC PATCH v2 4/5] "));
+        assert!(numbered[1].starts_with("Subject: [RFC PATCH v2 5/5] "));
+        let bare = Options { subject_prefix: String::new(), numbered: Some(false), ..Options::default() };
+        assert!(subjects(&bare).iter().all(|xs| !xs.contains('[')));
+        let emails = format(&storage_set, &ids, &options).unwrap();
+        assert_eq!(emails[0].number, 4);
+        assert!(!String::from_utf8_lossy(&emails[0].contents).contains("\n-- \n"));
+    }
+}
@@ -0,0 +1,270 @@
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixStream;
+use std::path::{ Path, PathBuf };
+use std::process::{ Command, Stdio };
+use std::ffi::OsStr;
+use std::io;
+
+use crate::filter::{ read_packet, write_flush, write_packet };
+use crate::errors::Result;
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::index::Index;
+use crate::worktree;
+
+// Who core.fsmonitor says to ask what changed in the worktree: a hook, such
+// as the fsmonitor-watchman sample for Watchman, or with "true" git's
+// builtin daemon, listening on `fsmonitor--daemon.ipc` in the git dir.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Monitor {
+    Hook(PathBuf),
+    Daemon(PathBuf)
+}
+
+// A monitor's answer: the token to ask with next time, and the paths changed
+// since the token asked with, directories with a trailing slash. No paths
+// means it can't say, as on a first query or when it lost track.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Changes {
+    pub token: Option<Vec<u8>>,
+    pub paths: Option<Vec<Vec<u8>>>
+}
+
+// A response's paths, split at NULs; "/" means anything may have changed.
+fn parse_paths(data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let paths: Vec<Vec<u8>> = data.split(|xs| *xs == 0).filter(|xs| !xs.is_empty()).map(<[u8]>::to_vec).collect();
+    if paths.iter().any(|xs| xs == b"/") { None } else { Some(paths) }
+}
+
+// The hook's output, or None if it failed.
+fn run_hook(hook: &Path, worktree: &Path, version: u32, token: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg("\"$0\" \"$@\"")
+        .arg(hook)
+        .arg(version.to_string())
+        .arg(OsStr::from_bytes(token))
+        .current_dir(worktree)
+        .stdin(Stdio::null())
+        .output()?;
+    Ok(if output.status.success() { Some(output.stdout) } else { None })
+}
+
+// The daemon takes the token as a packetized message and answers the same
+// way, as git's simple-ipc.
+fn ask_daemon(socket: &Path, token: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = UnixStream::connect(socket)?;
+    write_packet(&mut stream, token)?;
+    write_flush(&mut stream)?;
+    let mut response = Vec::new();
+    while let Some(xs) = read_packet(&mut stream)? {
+        response.extend(xs);
+    }
+    Ok(response)
+}
+
+impl Monitor {
+    // The monitor of the worktree at `path`, if core.fsmonitor names one.
+    pub fn from_path(path: &Path) -> Result<Option<Monitor>> {
+        let config = Config::from_path(path)?;
+        let value = match config.get("core.fsmonitor") {
+            Some(xs) => xs,
+            None => return Ok(None)
+        };
+        Ok(match config.get_bool("core.fsmonitor") {
+            Some(true) => Some(Monitor::Daemon(worktree::git_dir(path)?.join("fsmonitor--daemon.ipc"))),
+            Some(false) => None,
+            None => Some(Monitor::Hook(path.join(value)))
+        })
+    }
+
+    // What changed in the worktree at `worktree` since `token`. A version 2
+    // hook answers "<token>\0<path>\0..."; one that fails is asked again as
+    // version 1, which takes nanoseconds since the epoch and answers with
+    // paths alone; its next token is `clock`'s time. A daemon that isn't
+    // running can't say.
+    pub fn query(&self, worktree: &Path, token: Option<&[u8]>, clock: &dyn Clock) -> Result<Changes> {
+        let response = match self {
+            Monitor::Hook(hook) => match run_hook(hook, worktree, 2, token.unwrap_or_default())? {
+                Some(xs) => xs,
+                None => {
+                    let now = clock.now().timestamp_nanos_opt().unwrap_or(0).max(0);
+                    let since = token.and_then(|xs| std::str::from_utf8(xs).ok()).and_then(|xs| xs.parse::<u64>().ok());
+                    return Ok(match run_hook(hook, worktree, 1, since.unwrap_or(0).to_string().as_bytes())? {
+                        Some(xs) => Changes {
+                            token: Some(now.to_string().into_bytes()),
+                            paths: since.and_then(|_| parse_paths(&xs))
+                        },
+                        None => Changes { token: None, paths: None }
+                    })
+                }
+            },
+            Monitor::Daemon(socket) => match ask_daemon(socket, token.unwrap_or_default()) {
+                Ok(xs) => xs,
+                Err(_) => return Ok(Changes { token: None, paths: None })
+            }
+        };
+        let (new_token, paths) = match response.iter().position(|xs| *xs == 0) {
+            Some(xs) => (&response[..xs], &response[xs + 1..]),
+            None => (&response[..], &b""[..])
+        };
+        Ok(Changes {
+            token: Some(new_token.to_vec()),
+            paths: token.and_then(|_| parse_paths(paths))
+        })
+    }
+}
+
+// Asks core.fsmonitor what changed in the worktree at `path` since the
+// index's token: those entries (all of them, when it can't say) lose
+// `fsmonitor_valid`, and their directories' untracked listings go. Entries
+// still valid needn't be lstat'd; whoever checks the rest marks those found
+// clean with `Index::set_fsmonitor_valid`. Without core.fsmonitor the
+// extension is dropped. Save the index to keep the new token.
+pub fn refresh(path: &Path, index: &mut Index, clock: &dyn Clock) -> Result<()> {
+    let monitor = match Monitor::from_path(path)? {
+        Some(xs) => xs,
+        None => {
+            index.set_fsmonitor_token(None);
+            return Ok(())
+        }
+    };
+    let changes = monitor.query(path, index.fsmonitor_token(), clock)?;
+    match changes.paths {
+        Some(paths) => for changed in paths {
+            index.invalidate_fsmonitor(&changed);
+            index.invalidate_untracked(&changed);
+        },
+        None => index.invalidate_fsmonitor(b"")
+    }
+    index.set_fsmonitor_token(changes.token);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixListener;
+
+    use crate::filter::{ read_packet, write_flush, write_packet };
+    use chrono::{ TimeZone, Utc };
+
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::clock::{ FixedClock, SystemClock };
+    use crate::stores::fs as gitfs;
+    use crate::index::Index;
+    use crate::files;
+    use super::{ refresh, Changes, Monitor };
+
+    fn valid(index: &Index) -> Vec<&[u8]> {
+        index.entries().iter().filter(|xs| xs.fsmonitor_valid).map(|xs| xs.path.as_slice()).collect()
+    }
+
+    #[test]
+    fn marks_what_the_monitor_reports_changed() {
+        let dir = TempDir::new("fsmonitor").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "a\n", "a/b" => "b\n", "a-c" => "c\n", "d" => "d\n"]);
+        let tip = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let mut index = Index::from_tree(&storage_set, &tip).unwrap();
+
+        let hook = dir.path().join("watchman-hook");
+        std::fs::write(&hook, "#!/bin/sh\n[ \"$1\" = 2 ] || exit 1\nif [ -z \"$2\" ]; then printf 'one\\000/\\000'; else printf 'two\\000README\\000a/\\000'; fi\n").unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::write(dir.path().join(".git/config"), "[core]\n\tfsmonitor = watchman-hook\n").unwrap();
+
+        // a first query can't say what changed.
+        refresh(dir.path(), &mut index, &SystemClock).expect("failed to refresh");
+        assert_eq!(index.fsmonitor_token(), Some(&b"one"[..]));
+        assert!(valid(&index).is_empty());
+        for path in &[&b"README"[..], b"a-c", b"a/b", b"d"] {
+            index.set_fsmonitor_valid(path, true);
+        }
+
+        refresh(dir.path(), &mut index, &SystemClock).expect("failed to refresh");
+        assert_eq!(index.fsmonitor_token(), Some(&b"two"[..]));
+        assert_eq!(valid(&index), vec![&b"a-c"[..], b"d"]);
+        let mut data = Vec::new();
+        index.write(&mut data).unwrap();
+        let read = Index::parse(&data).unwrap();
+        assert_eq!(read.fsmonitor_token(), Some(&b"two"[..]));
+        assert_eq!(valid(&read), vec![&b"a-c"[..], b"d"]);
+
+        // the builtin daemon answers over its socket.
+        let listener = UnixListener::bind(dir.path().join(".git/fsmonitor--daemon.ipc")).unwrap();
+        let daemon = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert_eq!(read_packet(&mut stream).unwrap(), Some(b"two".to_vec()));
+            assert_eq!(read_packet(&mut stream).unwrap(), None);
+            write_packet(&mut stream, b"three\0d\0").unwrap();
+            write_flush(&mut stream).unwrap();
+        });
+        std::fs::write(dir.path().join(".git/config"), "[core]\n\tfsmonitor = true\n").unwrap();
+        refresh(dir.path(), &mut index, &SystemClock).expect("failed to refresh");
+        daemon.join().unwrap();
+        assert_eq!(index.fsmonitor_token(), Some(&b"three"[..]));
+        assert_eq!(valid(&index), vec![&b"a-c"[..]]);
+
+        std::fs::write(dir.path().join(".git/config"), "").unwrap();
+        refresh(dir.path(), &mut index, &SystemClock).expect("failed to refresh");
+        assert_eq!(index.fsmonitor_token(), None);
+    }
+
+    #[test]
+    fn version_one_hooks_get_tokens_from_the_clock() {
+        let dir = TempDir::new("fsmonitor-v1").expect("failed to create tempdir");
+        RepoBuilder::new()
+            .commit("first", files!["README" => "a\n"])
+            .write(dir.path())
+            .expect("failed to write");
+        let hook = dir.path().join("old-hook");
+        std::fs::write(&hook, "#!/bin/sh\n[ \"$1\" = 1 ] || exit 1\necho \"$2\" > asked\nprintf 'README\\000'\n").unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let monitor = Monitor::Hook(hook);
+
+        let clock = FixedClock::new(Utc.timestamp_opt(1_500_000_000, 25).unwrap());
+        let first = monitor.query(dir.path(), None, &clock).expect("failed to query");
+        assert_eq!(first.token, Some(b"1500000000000000025".to_vec()));
+        assert_eq!(first.paths, None);
+        assert_eq!(std::fs::read_to_string(dir.path().join("asked")).unwrap(), "0\n");
+
+        clock.advance(chrono::Duration::seconds(1));
+        let second = monitor.query(dir.path(), first.token.as_deref(), &clock).expect("failed to query");
+        assert_eq!(second.token, Some(b"1500000001000000025".to_vec()));
+        assert_eq!(second.paths, Some(vec![b"README".to_vec()]));
+        assert_eq!(std::fs::read_to_string(dir.path().join("asked")).unwrap(), "1500000000000000025\n");
+    }
+
+    #[test]
+    fn monitors_that_cannot_answer_invalidate_everything() {
+        let dir = TempDir::new("fsmonitor-fail").expect("failed to create tempdir");
+        let builder = RepoBuilder::new().commit("first", files!["README" => "a\n", "src/lib.rs" => "\n"]);
+        let tip = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let mut index = Index::from_tree(&storage_set, &tip).unwrap();
+        let hook = dir.path().join("broken-hook");
+        std::fs::write(&hook, "#!/bin/sh\nexit 1\n").unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        for (value, monitor) in &[("false", None), ("broken-hook", Some(Monitor::Hook(hook.clone()))), ("true", Some(Monitor::Daemon(dir.path().join(".git/fsmonitor--daemon.ipc"))))] {
+            std::fs::write(dir.path().join(".git/config"), format!("[core]\n\tfsmonitor = {}\n", value)).unwrap();
+            assert_eq!(Monitor::from_path(dir.path()).unwrap().as_ref(), monitor.as_ref());
+        }
+
+        // no daemon is listening, and the hook fails as either version.
+        let clock = FixedClock::new(Utc.timestamp_opt(1_500_000_000, 0).unwrap());
+        for monitor in &[Monitor::Hook(hook), Monitor::Daemon(dir.path().join(".git/fsmonitor--daemon.ipc"))] {
+            assert_eq!(monitor.query(dir.path(), Some(b"old"), &clock).unwrap(), Changes { token: None, paths: None });
+        }
+        index.set_fsmonitor_token(Some(b"old".to_vec()));
+        for path in &[&b"README"[..], b"src/lib.rs"] {
+            index.set_fsmonitor_valid(path, true);
+        }
+        refresh(dir.path(), &mut index, &clock).expect("failed to refresh");
+        assert_eq!(index.fsmonitor_token(), None);
+        assert!(valid(&index).is_empty());
+    }
+}
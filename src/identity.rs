@@ -1,6 +1,9 @@
 use chrono::{ DateTime, Utc, FixedOffset, NaiveDateTime };
+use std::io::Write;
 
-#[derive(Debug)]
+use crate::clock::Clock;
+
+#[derive(Debug, Clone)]
 pub struct Identity {
     name: Vec<u8>,
     email: Vec<u8>,
@@ -9,10 +12,48 @@ pub struct Identity {
 }
 
 impl Identity {
+    pub fn new(name: &[u8], email: &[u8], at: DateTime<Utc>, offset: FixedOffset) -> Identity {
+        Identity {
+            name: name.to_vec(),
+            email: email.to_vec(),
+            at,
+            offset
+        }
+    }
+
+    // A signature made now by `clock`, in UTC: git's default when no date
+    // is given.
+    pub fn now(name: &[u8], email: &[u8], clock: &dyn Clock) -> Identity {
+        Identity::new(name, email, clock.now(), FixedOffset::east_opt(0).unwrap())
+    }
+
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    pub fn email(&self) -> &[u8] {
+        &self.email
+    }
+
     pub fn at(&self) -> &DateTime<Utc> {
         &self.at
     }
 
+    pub fn offset(&self) -> &FixedOffset {
+        &self.offset
+    }
+
+    pub fn write<W: Write>(&self, output: &mut W) -> std::io::Result<()> {
+        let seconds = self.offset.local_minus_utc();
+        let sign = if seconds < 0 { '-' } else { '+' };
+        let minutes = seconds.abs() / 60;
+
+        output.write_all(&self.name)?;
+        output.write_all(b" <")?;
+        output.write_all(&self.email)?;
+        write!(output, "> {} {}{:02}{:02}", self.at.timestamp(), sign, minutes / 60, minutes % 60)
+    }
+
     pub fn parse(input: &[u8]) -> Option<Identity> {
 
         #[derive(Debug)]
@@ -49,7 +90,7 @@ impl Identity {
 
                 Mode::FindEmailEnd((a, b)) => {
                     if input[idx] == 62 {
-                        Mode::FindEmailStart((idx - 1, a, b))
+                        Mode::FindEmailStart((idx, a, b))
                     } else {
                         Mode::FindEmailEnd((a, b))
                     }
@@ -76,7 +117,7 @@ impl Identity {
         }
 
         if let Mode::Done((name_end, email_start, email_end, time_start, time_end)) = mode {
-            let name = input[0 .. name_end].to_vec();
+            let name = input[0 ..= name_end].to_vec();
             let email = input[email_start .. email_end].to_vec();
 
             let timestamp_str = std::str::from_utf8(&input[time_start + 1 .. time_end]).ok()?;
@@ -123,7 +164,18 @@ mod tests {
     fn read_identity() {
         let bytes = "Chris Dickinson <christopher.s.dickinson@gmail.com> 1545286964 -0800".as_bytes();
 
-        let ident = Identity::parse(&bytes);
-        assert_eq!(ident.is_some(), true);
+        let ident = Identity::parse(&bytes).expect("failed to parse");
+        assert_eq!(ident.name(), b"Chris Dickinson");
+        assert_eq!(ident.email(), b"christopher.s.dickinson@gmail.com");
+    }
+
+    #[test]
+    fn write_identity_roundtrips() {
+        let bytes = "Chris Dickinson <christopher.s.dickinson@gmail.com> 1545286964 -0830".as_bytes();
+
+        let ident = Identity::parse(&bytes).expect("failed to parse");
+        let mut output = Vec::new();
+        ident.write(&mut output).expect("failed to write");
+        assert_eq!(&output[..], bytes);
     }
 }
@@ -0,0 +1,118 @@
+use crate::attributes::wildmatch;
+
+#[derive(Clone, Debug)]
+struct Rule {
+    // the directory of the .gitignore the rule came from, with a trailing
+    // slash unless it is the root.
+    base: Vec<u8>,
+    pattern: Vec<u8>,
+    anchored: bool,
+    negated: bool,
+    dir_only: bool
+}
+
+// Rules from .gitignore files and `info/exclude`, searched last to first as
+// attributes are: the last matching line decides, and "!pattern" takes a path
+// back. Paths inside an ignored directory aren't looked at, as git doesn't
+// descend into one.
+#[derive(Clone, Debug, Default)]
+pub struct Ignores {
+    rules: Vec<Rule>
+}
+
+impl Ignores {
+    pub fn new() -> Ignores {
+        Ignores::default()
+    }
+
+    // Adds the rules of the .gitignore in directory `base` ("" for the root
+    // and for `info/exclude`, otherwise "dir/sub").
+    pub fn add(&mut self, base: &[u8], contents: &[u8]) {
+        let mut base = base.to_vec();
+        if !base.is_empty() && !base.ends_with(b"/") {
+            base.push(b'/');
+        }
+        for line in contents.split(|xs| *xs == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            // trailing spaces go unless escaped.
+            let mut end = line.len();
+            while end > 0 && line[end - 1] == b' ' && (end < 2 || line[end - 2] != b'\\') {
+                end -= 1;
+            }
+            let mut pattern = &line[..end];
+            if pattern.is_empty() || pattern[0] == b'#' {
+                continue
+            }
+            let negated = pattern[0] == b'!';
+            if negated {
+                pattern = &pattern[1..];
+            }
+            let dir_only = pattern.ends_with(b"/");
+            if dir_only {
+                pattern = &pattern[..pattern.len() - 1];
+            }
+            if pattern.is_empty() {
+                continue
+            }
+            let anchored = pattern.contains(&b'/');
+            self.rules.push(Rule {
+                base: base.clone(),
+                pattern: pattern.strip_prefix(b"/").unwrap_or(pattern).to_vec(),
+                anchored,
+                negated,
+                dir_only
+            });
+        }
+    }
+
+    // Whether `path` (slash separated, from the root) is ignored.
+    pub fn is_ignored(&self, path: &[u8], is_dir: bool) -> bool {
+        self.matches(path, is_dir).unwrap_or(false)
+    }
+
+    // What the last rule matching `path` says: ignored, taken back with
+    // "!", or None if no rule matches.
+    pub fn matches(&self, path: &[u8], is_dir: bool) -> Option<bool> {
+        for rule in self.rules.iter().rev() {
+            if rule.dir_only && !is_dir {
+                continue
+            }
+            let relative = match path.strip_prefix(&rule.base[..]) {
+                Some(xs) => xs,
+                None => continue
+            };
+            let subject = if rule.anchored {
+                relative
+            } else {
+                relative.rsplit(|xs| *xs == b'/').next().unwrap_or(relative)
+            };
+            if wildmatch(&rule.pattern, subject) {
+                return Some(!rule.negated)
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ignores;
+
+    #[test]
+    fn last_matching_rule_decides() {
+        let mut ignores = Ignores::new();
+        ignores.add(b"", b"# comment\n*.o\n!keep.o\nbuild/\n/top\n");
+        ignores.add(b"sub", b"*.tmp\ndeep/*.txt\n");
+        assert!(ignores.is_ignored(b"a.o", false));
+        assert!(ignores.is_ignored(b"dir/a.o", false));
+        assert!(!ignores.is_ignored(b"keep.o", false));
+        assert!(ignores.is_ignored(b"build", true));
+        assert!(!ignores.is_ignored(b"build", false));
+        assert!(ignores.is_ignored(b"top", false));
+        assert!(!ignores.is_ignored(b"dir/top", false));
+        assert!(ignores.is_ignored(b"sub/x/a.tmp", false));
+        assert!(!ignores.is_ignored(b"a.tmp", false));
+        assert!(ignores.is_ignored(b"sub/deep/a.txt", false));
+        assert!(!ignores.is_ignored(b"sub/x/deep/a.txt", false));
+    }
+}
@@ -0,0 +1,1115 @@
+use byteorder::{ BigEndian, ReadBytesExt, WriteBytesExt };
+use crypto::{ sha1::Sha1, digest::Digest };
+use std::collections::BTreeMap;
+use std::os::unix::fs::MetadataExt;
+use std::io::{ Cursor, Read, Write };
+use std::path::Path;
+
+use chrono::{ DateTime, Utc };
+
+use crate::objects::tree::{ write_nested, FileMode, TreeEntry };
+use crate::stores::{ Queryable, StorageSet };
+use crate::errors::{ ErrorKind, Result };
+use crate::merge::tree::TreeMerge;
+use crate::untracked::UntrackedCache;
+use crate::bitmap::Bitmap;
+use crate::config::Config;
+use crate::checkout::flatten;
+use crate::lock::{ LockFile, Retry };
+use crate::clock::{ Clock, SystemClock };
+use crate::objects;
+use crate::objects::Type;
+use crate::worktree;
+use crate::id::Id;
+
+const SIGNATURE: &[u8] = b"DIRC";
+const RESOLVE_UNDO: [u8; 4] = *b"REUC";
+const CACHE_TREE: [u8; 4] = *b"TREE";
+const UNTRACKED: [u8; 4] = *b"UNTR";
+const LINK: [u8; 4] = *b"link";
+const FSMONITOR: [u8; 4] = *b"FSMN";
+
+const ASSUME_VALID: u16 = 0x8000;
+const EXTENDED: u16 = 0x4000;
+const STAGE_MASK: u16 = 0x3000;
+const NAME_MASK: u16 = 0x0fff;
+const SKIP_WORKTREE: u16 = 0x4000;
+const INTENT_TO_ADD: u16 = 0x2000;
+
+// What git caches from lstat(2) to tell whether a file has changed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stat {
+    pub ctime: (u32, u32),
+    pub mtime: (u32, u32),
+    pub dev: u32,
+    pub ino: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32
+}
+
+impl Stat {
+    // git truncates every field to 32 bits.
+    pub fn from_metadata(metadata: &std::fs::Metadata) -> Stat {
+        Stat {
+            ctime: (metadata.ctime() as u32, metadata.ctime_nsec() as u32),
+            mtime: (metadata.mtime() as u32, metadata.mtime_nsec() as u32),
+            dev: metadata.dev() as u32,
+            ino: metadata.ino() as u32,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size() as u32
+        }
+    }
+
+    // Whether a file with this stat could have changed again in the same
+    // second as `at`, when an index recording it is written: its stat would
+    // still match, so only its contents can tell. git compares seconds.
+    pub fn is_racy(&self, at: DateTime<Utc>) -> bool {
+        self.mtime != (0, 0) && i64::from(self.mtime.0) >= at.timestamp()
+    }
+}
+
+// Stage 0 is a merged path. A conflicted path has no stage 0, but up to
+// three of: 1 (the common ancestor), 2 (ours) and 3 (theirs).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub path: Vec<u8>,
+    pub mode: FileMode,
+    pub id: Id,
+    pub stage: u8,
+    pub stat: Stat,
+    pub assume_valid: bool,
+    pub skip_worktree: bool,
+    pub intent_to_add: bool,
+    // the fsmonitor hasn't seen the path change since it was last found
+    // clean, so it needn't be lstat'd
+    pub fsmonitor_valid: bool
+}
+
+impl Entry {
+    // Whether `stat`, from the worktree, shows the file unchanged since the
+    // entry was recorded; otherwise only its contents can say. A smudged
+    // size matches nothing but an empty blob.
+    pub fn stat_matches(&self, stat: &Stat) -> bool {
+        self.stat == *stat && (self.stat.size != 0 || self.id == objects::hash(Type::Blob, b""))
+    }
+
+    pub fn new(path: Vec<u8>, mode: FileMode, id: Id) -> Entry {
+        Entry {
+            path,
+            mode,
+            id,
+            stage: 0,
+            stat: Stat::default(),
+            assume_valid: false,
+            skip_worktree: false,
+            intent_to_add: false,
+            fsmonitor_valid: false
+        }
+    }
+
+    fn staged(path: &[u8], stage: u8, entry: &TreeEntry) -> Entry {
+        Entry {
+            stage,
+            ..Entry::new(path.to_vec(), entry.mode, entry.id.clone())
+        }
+    }
+
+    fn is_extended(&self) -> bool {
+        self.skip_worktree || self.intent_to_add
+    }
+}
+
+// The stages of a conflicted path, indexed by stage - 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Unmerged {
+    pub path: Vec<u8>,
+    pub stages: [Option<TreeEntry>; 3]
+}
+
+impl Unmerged {
+    pub fn base(&self) -> Option<&TreeEntry> {
+        self.stages[0].as_ref()
+    }
+
+    pub fn ours(&self) -> Option<&TreeEntry> {
+        self.stages[1].as_ref()
+    }
+
+    pub fn theirs(&self) -> Option<&TreeEntry> {
+        self.stages[2].as_ref()
+    }
+}
+
+// The base a split index was read against: the entries of
+// `sharedindex.<id>` in the git directory, which the index file only
+// records changes to.
+#[derive(Clone, Debug)]
+struct Shared {
+    id: Id,
+    entries: Vec<Entry>
+}
+
+// The contents of `.git/index`, versions 2 to 4.
+#[derive(Clone, Debug)]
+pub struct Index {
+    pub version: u32,
+    // sorted by path, then stage
+    entries: Vec<Entry>,
+    // the REUC extension: stages of conflicts since resolved, so that
+    // `git checkout -m` can recreate them
+    resolve_undo: Vec<Unmerged>,
+    // the UNTR extension; one we can't read is kept with the others
+    untracked: Option<UntrackedCache>,
+    // set when read from a split index, to write the next one against
+    shared: Option<Shared>,
+    // the FSMN extension: what to ask the fsmonitor for changes since
+    fsmonitor_token: Option<Vec<u8>>,
+    // optional extensions we don't interpret, written back verbatim
+    extensions: Vec<([u8; 4], Vec<u8>)>
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Index {
+            version: 2,
+            entries: Vec::new(),
+            resolve_undo: Vec::new(),
+            untracked: None,
+            shared: None,
+            fsmonitor_token: None,
+            extensions: Vec::new()
+        }
+    }
+}
+
+pub(crate) fn read_path<R: Read>(input: &mut R) -> Result<Vec<u8>> {
+    let mut path = Vec::new();
+    loop {
+        match input.read_u8()? {
+            0 => return Ok(path),
+            xs => path.push(xs)
+        }
+    }
+}
+
+pub(crate) fn read_id<R: Read>(input: &mut R) -> Result<Id> {
+    let mut bytes = [0u8; 20];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes.into())
+}
+
+// git's varint.c, which differs from the pack format's: each continuation
+// adds one so that no value has two encodings. Version 4 entries and the
+// UNTR extension use it.
+pub(crate) fn read_varint<R: Read>(input: &mut R) -> Result<u64> {
+    let mut byte = input.read_u8()?;
+    let mut value = u64::from(byte & 127);
+    while byte & 128 != 0 {
+        byte = input.read_u8()?;
+        value = value.checked_add(1)
+            .and_then(|xs| xs.checked_mul(128))
+            .and_then(|xs| xs.checked_add(u64::from(byte & 127)))
+            .ok_or(ErrorKind::CorruptedIndex)?;
+    }
+    Ok(value)
+}
+
+pub(crate) fn write_varint(value: u64, output: &mut Vec<u8>) {
+    let mut bytes = vec![(value & 127) as u8];
+    let mut value = value >> 7;
+    while value != 0 {
+        value -= 1;
+        bytes.push(128 | (value & 127) as u8);
+        value >>= 7;
+    }
+    output.extend(bytes.iter().rev());
+}
+
+fn read_resolve_undo(data: &[u8]) -> Result<Vec<Unmerged>> {
+    let mut input = Cursor::new(data);
+    let mut result = Vec::new();
+    while (input.position() as usize) < data.len() {
+        let path = read_path(&mut input)?;
+        let mut modes = [None, None, None];
+        for mode in modes.iter_mut() {
+            let octal = String::from_utf8(read_path(&mut input)?).map_err(|_| ErrorKind::CorruptedIndex)?;
+            *mode = match FileMode::from_octal(&octal) {
+                Some(xs) if xs.bits() == 0 => None,
+                Some(xs) => Some(xs),
+                None => return Err(ErrorKind::CorruptedIndex.into())
+            };
+        }
+        let mut stages = [None, None, None];
+        for (stage, mode) in stages.iter_mut().zip(modes.iter()) {
+            if let Some(mode) = mode {
+                *stage = Some(TreeEntry { mode: *mode, id: read_id(&mut input)? });
+            }
+        }
+        result.push(Unmerged { path, stages });
+    }
+    Ok(result)
+}
+
+// How `entries` differ from a shared index's: the positions it deletes and
+// replaces, then the replacements (in the order of the positions, with
+// their paths left out) followed by the entries it adds.
+fn split_against(base: &[Entry], entries: &[Entry]) -> (Bitmap, Bitmap, Vec<Entry>) {
+    let (mut delete, mut replace) = (Bitmap::new(), Bitmap::new());
+    let (mut replacements, mut additions) = (Vec::new(), Vec::new());
+    let (mut lhs, mut rhs) = (base.iter().enumerate().peekable(), entries.iter().peekable());
+    loop {
+        let order = match (lhs.peek(), rhs.peek()) {
+            (Some((_, old)), Some(new)) => (old.path.as_slice(), old.stage).cmp(&(new.path.as_slice(), new.stage)),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => break
+        };
+        match order {
+            std::cmp::Ordering::Less => {
+                delete.insert(lhs.next().unwrap().0);
+            },
+            std::cmp::Ordering::Greater => additions.push(rhs.next().unwrap().clone()),
+            std::cmp::Ordering::Equal => {
+                let ((position, old), new) = (lhs.next().unwrap(), rhs.next().unwrap());
+                if old != new {
+                    replace.insert(position);
+                    replacements.push(Entry { path: Vec::new(), ..new.clone() });
+                }
+            }
+        }
+    }
+    replacements.extend(additions);
+    (delete, replace, replacements)
+}
+
+fn write_resolve_undo(records: &[Unmerged]) -> Vec<u8> {
+    let mut output = Vec::new();
+    for record in records {
+        output.extend_from_slice(&record.path);
+        output.push(0);
+        for stage in record.stages.iter() {
+            let mode = stage.as_ref().map(|xs| xs.mode.bits()).unwrap_or(0);
+            output.extend_from_slice(format!("{:o}\0", mode).as_bytes());
+        }
+        for stage in record.stages.iter().flatten() {
+            output.extend_from_slice(stage.id.as_ref());
+        }
+    }
+    output
+}
+
+impl Index {
+    pub fn new() -> Index {
+        Index::default()
+    }
+
+    // An index file on its own; a split index needs its shared index, which
+    // `open` finds.
+    pub fn parse(data: &[u8]) -> Result<Index> {
+        Index::parse_with(data, |_| Err(ErrorKind::UnsupportedIndexExtension(String::from("link")).into()))
+    }
+
+    // Like `parse`, with `shared` reading the shared index a split index
+    // names.
+    pub fn parse_with<F>(data: &[u8], mut shared: F) -> Result<Index> where F: FnMut(&Id) -> Result<Index> {
+        if data.len() < 32 || &data[..4] != SIGNATURE {
+            return Err(ErrorKind::CorruptedIndex.into())
+        }
+        let (body, checksum) = data.split_at(data.len() - 20);
+        let mut hash = Sha1::new();
+        hash.input(body);
+        let mut expected = [0u8; 20];
+        hash.result(&mut expected);
+        if expected != checksum {
+            return Err(ErrorKind::CorruptedIndex.into())
+        }
+
+        let mut input = Cursor::new(body);
+        input.set_position(4);
+        let version = input.read_u32::<BigEndian>()?;
+        if !(2..=4).contains(&version) {
+            return Err(ErrorKind::UnsupportedIndexVersion(version).into())
+        }
+        let count = input.read_u32::<BigEndian>()?;
+
+        let mut index = Index { version, ..Index::default() };
+        let mut previous: Vec<u8> = Vec::new();
+        for _ in 0..count {
+            let start = input.position();
+            let mut stat = Stat {
+                ctime: (input.read_u32::<BigEndian>()?, input.read_u32::<BigEndian>()?),
+                mtime: (input.read_u32::<BigEndian>()?, input.read_u32::<BigEndian>()?),
+                dev: input.read_u32::<BigEndian>()?,
+                ino: input.read_u32::<BigEndian>()?,
+                ..Stat::default()
+            };
+            let mode = FileMode::from_bits(input.read_u32::<BigEndian>()?);
+            stat.uid = input.read_u32::<BigEndian>()?;
+            stat.gid = input.read_u32::<BigEndian>()?;
+            stat.size = input.read_u32::<BigEndian>()?;
+            let id = read_id(&mut input)?;
+            let flags = input.read_u16::<BigEndian>()?;
+            let extended = if flags & EXTENDED != 0 {
+                if version < 3 {
+                    return Err(ErrorKind::CorruptedIndex.into())
+                }
+                input.read_u16::<BigEndian>()?
+            } else {
+                0
+            };
+            let path = if version == 4 {
+                // how much of the previous path to drop, then the rest.
+                let strip = read_varint(&mut input)? as usize;
+                if strip > previous.len() {
+                    return Err(ErrorKind::CorruptedIndex.into())
+                }
+                let mut path = previous[..previous.len() - strip].to_vec();
+                path.extend(read_path(&mut input)?);
+                previous = path.clone();
+                path
+            } else {
+                let path = read_path(&mut input)?;
+                // entries are NUL-padded to a multiple of eight bytes.
+                let padded = (input.position() - start + 7) & !7;
+                input.set_position(start + padded);
+                path
+            };
+
+            index.entries.push(Entry {
+                path,
+                mode,
+                id,
+                stage: ((flags & STAGE_MASK) >> 12) as u8,
+                stat,
+                assume_valid: flags & ASSUME_VALID != 0,
+                skip_worktree: extended & SKIP_WORKTREE != 0,
+                intent_to_add: extended & INTENT_TO_ADD != 0,
+                fsmonitor_valid: false
+            });
+        }
+
+        let mut link = None;
+        let mut fsmonitor = None;
+        while (input.position() as usize) < body.len() {
+            let mut signature = [0u8; 4];
+            input.read_exact(&mut signature)?;
+            let size = input.read_u32::<BigEndian>()? as usize;
+            let start = input.position() as usize;
+            let data = match body.get(start..start + size) {
+                Some(xs) => xs,
+                None => return Err(ErrorKind::CorruptedIndex.into())
+            };
+            input.set_position((start + size) as u64);
+
+            if signature == LINK {
+                let mut data = Cursor::new(data);
+                let id = read_id(&mut data)?;
+                let base = shared(&id)?;
+                let bitmaps = if data.get_ref().len() > 20 {
+                    (Bitmap::read_ewah(&mut data, base.entries.len())?, Bitmap::read_ewah(&mut data, base.entries.len())?)
+                } else {
+                    (Bitmap::new(), Bitmap::new())
+                };
+                link = Some((id, base.entries, bitmaps));
+            } else if signature == FSMONITOR {
+                let mut data = Cursor::new(data);
+                let token = match data.read_u32::<BigEndian>()? {
+                    // version 1 had a timestamp in nanoseconds, which is
+                    // what the version 1 hook takes.
+                    1 => data.read_u64::<BigEndian>()?.to_string().into_bytes(),
+                    2 => read_path(&mut data)?,
+                    _ => return Err(ErrorKind::CorruptedIndex.into())
+                };
+                data.read_u32::<BigEndian>()?;
+                let position = data.position() as usize;
+                fsmonitor = Some((token, data.into_inner()[position..].to_vec()));
+            } else if signature == RESOLVE_UNDO {
+                index.resolve_undo = read_resolve_undo(data)?;
+            } else if let (UNTRACKED, Ok(cache)) = (signature, UntrackedCache::parse(data)) {
+                index.untracked = Some(cache);
+            } else if signature[0].is_ascii_uppercase() {
+                index.extensions.push((signature, data.to_vec()));
+            } else {
+                // lowercase extensions must be understood to read the index.
+                return Err(ErrorKind::UnsupportedIndexExtension(String::from_utf8_lossy(&signature).into_owned()).into())
+            }
+        }
+        if let Some((id, base, (delete, replace))) = link {
+            index.merge_shared(id, base, &delete, &replace)?;
+        }
+        // the bitmap marks the entries to check, counting those from a
+        // shared index too.
+        if let Some((token, bitmap)) = fsmonitor {
+            let dirty = Bitmap::read_ewah(&mut &bitmap[..], index.entries.len())?;
+            for (position, entry) in index.entries.iter_mut().enumerate() {
+                entry.fsmonitor_valid = !dirty.contains(position);
+            }
+            index.fsmonitor_token = Some(token);
+        }
+        Ok(index)
+    }
+
+    // Applies the entries read from a split index to those of its shared
+    // index, as git's merge_base_index.
+    fn merge_shared(&mut self, id: Id, base: Vec<Entry>, delete: &Bitmap, replace: &Bitmap) -> Result<()> {
+        let mut merged = base.clone();
+        let mut split = std::mem::take(&mut self.entries).into_iter();
+        for position in replace.iter() {
+            match split.next() {
+                Some(xs) if xs.path.is_empty() && !delete.contains(position) => {
+                    merged[position] = Entry { path: merged[position].path.clone(), ..xs };
+                },
+                _ => return Err(ErrorKind::CorruptedIndex.into())
+            }
+        }
+        let mut entries: BTreeMap<(Vec<u8>, u8), Entry> = merged.into_iter().enumerate()
+            .filter(|(position, _)| !delete.contains(*position))
+            .map(|(_, xs)| ((xs.path.clone(), xs.stage), xs))
+            .collect();
+        for entry in split {
+            if entry.path.is_empty() {
+                return Err(ErrorKind::CorruptedIndex.into())
+            }
+            entries.insert((entry.path.clone(), entry.stage), entry);
+        }
+        self.entries = entries.into_values().collect();
+        self.shared = Some(Shared { id, entries: base });
+        Ok(())
+    }
+
+    pub fn write<W: Write>(&self, output: &mut W) -> Result<()> {
+        self.write_parts(&self.entries, None, true, output)?;
+        Ok(())
+    }
+
+    // Writes `entries` after the header, then the `link` extension and, with
+    // `extensions`, the others; the result is the file's checksum.
+    fn write_parts<W: Write>(&self, entries: &[Entry], link: Option<&[u8]>, extensions: bool, output: &mut W) -> Result<Id> {
+        // extended flags need version 3.
+        let version = if entries.iter().any(Entry::is_extended) { self.version.max(3) } else { self.version };
+        let mut data = Vec::new();
+        data.extend_from_slice(SIGNATURE);
+        data.write_u32::<BigEndian>(version)?;
+        data.write_u32::<BigEndian>(entries.len() as u32)?;
+
+        let mut previous: &[u8] = &[];
+        for entry in entries {
+            let start = data.len();
+            for field in &[entry.stat.ctime.0, entry.stat.ctime.1, entry.stat.mtime.0, entry.stat.mtime.1, entry.stat.dev, entry.stat.ino] {
+                data.write_u32::<BigEndian>(*field)?;
+            }
+            data.write_u32::<BigEndian>(entry.mode.bits())?;
+            for field in &[entry.stat.uid, entry.stat.gid, entry.stat.size] {
+                data.write_u32::<BigEndian>(*field)?;
+            }
+            data.extend_from_slice(entry.id.as_ref());
+
+            let mut flags = (entry.path.len().min(NAME_MASK as usize) as u16) | ((entry.stage as u16) << 12);
+            if entry.assume_valid {
+                flags |= ASSUME_VALID;
+            }
+            if entry.is_extended() {
+                flags |= EXTENDED;
+            }
+            data.write_u16::<BigEndian>(flags)?;
+            if entry.is_extended() {
+                let mut extended = 0;
+                if entry.skip_worktree {
+                    extended |= SKIP_WORKTREE;
+                }
+                if entry.intent_to_add {
+                    extended |= INTENT_TO_ADD;
+                }
+                data.write_u16::<BigEndian>(extended)?;
+            }
+            if version == 4 {
+                let common = previous.iter().zip(&entry.path).take_while(|(lhs, rhs)| lhs == rhs).count();
+                write_varint((previous.len() - common) as u64, &mut data);
+                data.extend_from_slice(&entry.path[common..]);
+                data.push(0);
+                previous = &entry.path;
+            } else {
+                data.extend_from_slice(&entry.path);
+                // at least one NUL, up to the next multiple of eight.
+                let padded = (data.len() - start + 8) & !7;
+                data.resize(start + padded, 0);
+            }
+        }
+
+        let mut sections: Vec<([u8; 4], Vec<u8>)> = Vec::new();
+        if let Some(xs) = link {
+            sections.push((LINK, xs.to_vec()));
+        }
+        if extensions {
+            sections.extend(self.extensions.iter().cloned());
+            if !self.resolve_undo.is_empty() {
+                sections.push((RESOLVE_UNDO, write_resolve_undo(&self.resolve_undo)));
+            }
+            if let Some(ref xs) = self.untracked {
+                sections.push((UNTRACKED, xs.write()));
+            }
+            if let Some(ref token) = self.fsmonitor_token {
+                let mut dirty = Bitmap::new();
+                for (position, _) in self.entries.iter().enumerate().filter(|(_, xs)| !xs.fsmonitor_valid) {
+                    dirty.insert(position);
+                }
+                let mut bitmap = Vec::new();
+                dirty.write_ewah(&mut bitmap);
+                let mut contents = Vec::new();
+                contents.write_u32::<BigEndian>(2)?;
+                contents.extend_from_slice(token);
+                contents.push(0);
+                contents.write_u32::<BigEndian>(bitmap.len() as u32)?;
+                contents.extend(bitmap);
+                sections.push((FSMONITOR, contents));
+            }
+        }
+        for (signature, contents) in sections {
+            data.extend_from_slice(&signature);
+            data.write_u32::<BigEndian>(contents.len() as u32)?;
+            data.extend_from_slice(&contents);
+        }
+
+        let mut hash = Sha1::new();
+        hash.input(&data);
+        let mut checksum = [0u8; 20];
+        hash.result(&mut checksum);
+        output.write_all(&data)?;
+        output.write_all(&checksum)?;
+        Ok(checksum.into())
+    }
+
+    // Writes the index split, as core.splitIndex has git do: only what
+    // changed since the shared index it was read with, unless that is more
+    // than `max_percent` of the shared entries (100: never), in which case every entry
+    // goes to a new shared index and the index file records no changes.
+    fn write_split<W: Write>(&self, git_dir: &Path, max_percent: usize, output: &mut W) -> Result<()> {
+        let shared = self.shared.as_ref().filter(|xs| git_dir.join(format!("sharedindex.{}", xs.id)).exists());
+        let (id, (delete, replace, entries)) = match shared.map(|xs| (xs, split_against(&xs.entries, &self.entries))) {
+            Some((shared, delta)) if max_percent == 100 || (delta.0.len() + delta.2.len()) * 100 <= shared.entries.len() * max_percent => (shared.id.clone(), delta),
+            _ => {
+                let mut data = Vec::new();
+                let id = self.write_parts(&self.entries, None, false, &mut data)?;
+                let file = git_dir.join(format!("sharedindex.{}", id));
+                if !file.exists() {
+                    let mut lock = LockFile::acquire(&file, &Retry::none())?;
+                    lock.write_all(&data)?;
+                    lock.commit()?;
+                }
+                (id, (Bitmap::new(), Bitmap::new(), Vec::new()))
+            }
+        };
+        let mut link = id.as_ref().to_vec();
+        delete.write_ewah(&mut link);
+        replace.write_ewah(&mut link);
+        self.write_parts(&entries, Some(&link), true, output)?;
+        Ok(())
+    }
+
+    // The index of the worktree at `path`; empty if there is none yet.
+    pub fn open(path: &Path) -> Result<Index> {
+        let file = worktree::git_dir(path)?.join("index");
+        let shared = |id: &Id| Index::parse(&std::fs::read(file.with_file_name(format!("sharedindex.{}", id)))?);
+        match std::fs::read(&file) {
+            Ok(xs) => Index::parse_with(&xs, shared),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Index::new()),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    // Replaces the worktree's index through `index.lock`, as git does; a
+    // lock held by another process fails with AlreadyExists.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.save_with_retry(path, &Retry::none())
+    }
+
+    // Like `save`, waiting up to `retry` for someone else's `index.lock`.
+    // core.splitIndex splits the index or joins it back up; without it a
+    // split index stays split.
+    pub fn save_with_retry(&self, path: &Path, retry: &Retry) -> Result<()> {
+        self.save_with_clock(path, retry, &SystemClock)
+    }
+
+    // Like `save_with_retry`, smudging the entries that are racy as of
+    // `clock`'s time.
+    pub fn save_with_clock(&self, path: &Path, retry: &Retry, clock: &dyn Clock) -> Result<()> {
+        let now = clock.now();
+        let smudged;
+        let index = if self.entries.iter().any(|xs| xs.stat.is_racy(now)) {
+            let mut copy = self.clone();
+            copy.smudge_racy(now);
+            smudged = copy;
+            &smudged
+        } else {
+            self
+        };
+        index.write_locked(path, retry)
+    }
+
+    // Sets the size of every entry racy as of `at` to 0, as git does when it
+    // writes the index: a size that can't match makes the next reader
+    // compare the file's contents rather than trust its stat.
+    pub fn smudge_racy(&mut self, at: DateTime<Utc>) {
+        for entry in self.entries.iter_mut().filter(|xs| xs.stat.is_racy(at)) {
+            entry.stat.size = 0;
+        }
+    }
+
+    fn write_locked(&self, path: &Path, retry: &Retry) -> Result<()> {
+        let git_dir = worktree::git_dir(path)?;
+        let config = Config::from_path(path)?;
+        let mut lock = LockFile::acquire(&git_dir.join("index"), retry)?;
+        if config.get_bool("core.splitindex").unwrap_or(self.shared.is_some()) {
+            let max_percent = config.get_int("splitindex.maxpercentchange").filter(|xs| (0..=100).contains(xs)).unwrap_or(20);
+            self.write_split(&git_dir, max_percent as usize, &mut lock)?;
+        } else {
+            self.write(&mut lock)?;
+        }
+        Ok(lock.commit()?)
+    }
+
+    // The worktree's index, or `id`'s tree for one that never wrote an index
+    // (checkouts here don't).
+    pub fn open_or_from_tree<S: Queryable>(path: &Path, storage_set: &StorageSet<S>, id: &Id) -> Result<Index> {
+        if worktree::git_dir(path)?.join("index").exists() {
+            Index::open(path)
+        } else {
+            Index::from_tree(storage_set, id)
+        }
+    }
+
+    // Every blob of a tree (or a commit's tree) at stage 0, as `git read-tree`.
+    pub fn from_tree<S: Queryable>(storage_set: &StorageSet<S>, id: &Id) -> Result<Index> {
+        let entries = flatten(storage_set, id)?;
+        Ok(Index {
+            entries: entries.into_iter().map(|(path, entry)| Entry::new(path, entry.mode, entry.id)).collect(),
+            ..Index::default()
+        })
+    }
+
+    // The merged tree at stage 0 with every conflict's sides at stages 1-3,
+    // as `git merge` leaves the index.
+    pub fn from_merge<S: Queryable>(storage_set: &StorageSet<S>, merge: &TreeMerge) -> Result<Index> {
+        let mut index = Index::from_tree(storage_set, &merge.tree)?;
+        for conflict in &merge.conflicts {
+            index.add_conflict(&conflict.path, conflict.base.as_ref(), conflict.ours.as_ref(), conflict.theirs.as_ref());
+        }
+        Ok(index)
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    fn search(&self, path: &[u8], stage: u8) -> std::result::Result<usize, usize> {
+        self.entries.binary_search_by(|xs| (xs.path.as_slice(), xs.stage).cmp(&(path, stage)))
+    }
+
+    // The range of entries for `path`, at any stage.
+    fn stages_of(&self, path: &[u8]) -> std::ops::Range<usize> {
+        let start = match self.search(path, 0) { Ok(xs) | Err(xs) => xs };
+        let end = start + self.entries[start..].iter().take_while(|xs| xs.path == path).count();
+        start..end
+    }
+
+    pub fn get(&self, path: &[u8], stage: u8) -> Option<&Entry> {
+        self.search(path, stage).ok().map(|xs| &self.entries[xs])
+    }
+
+    pub fn untracked_cache(&self) -> Option<&UntrackedCache> {
+        self.untracked.as_ref()
+    }
+
+    // Replaces the untracked cache, returning the old one; None drops it, as
+    // `git update-index --no-untracked-cache`.
+    pub fn set_untracked_cache(&mut self, cache: Option<UntrackedCache>) -> Option<UntrackedCache> {
+        self.extensions.retain(|(signature, _)| *signature != UNTRACKED);
+        std::mem::replace(&mut self.untracked, cache)
+    }
+
+    pub fn fsmonitor_token(&self) -> Option<&[u8]> {
+        self.fsmonitor_token.as_deref()
+    }
+
+    // Replaces the token the fsmonitor is next asked for changes since; None
+    // drops the extension.
+    pub fn set_fsmonitor_token(&mut self, token: Option<Vec<u8>>) {
+        self.fsmonitor_token = token;
+    }
+
+    pub fn set_skip_worktree(&mut self, path: &[u8], skip: bool) {
+        let range = self.stages_of(path);
+        for entry in &mut self.entries[range] {
+            entry.skip_worktree = skip;
+        }
+    }
+
+    // Marks the entries of `path` (every stage) as checked and clean, or as
+    // needing a check.
+    pub fn set_fsmonitor_valid(&mut self, path: &[u8], valid: bool) {
+        let range = self.stages_of(path);
+        for entry in &mut self.entries[range] {
+            entry.fsmonitor_valid = valid;
+        }
+    }
+
+    // Marks the entries of `path`, and of everything under it if it is a
+    // directory, as needing a check; "" marks them all, as when the
+    // fsmonitor can't say what changed.
+    pub fn invalidate_fsmonitor(&mut self, path: &[u8]) {
+        let path = path.strip_suffix(b"/").unwrap_or(path);
+        if path.is_empty() {
+            for entry in &mut self.entries {
+                entry.fsmonitor_valid = false;
+            }
+            return
+        }
+        self.set_fsmonitor_valid(path, false);
+        let dir = [path, b"/"].concat();
+        let start = self.entries.partition_point(|xs| xs.path < dir);
+        for entry in self.entries[start..].iter_mut().take_while(|xs| xs.path.starts_with(&dir)) {
+            entry.fsmonitor_valid = false;
+        }
+    }
+
+    // Forgets the untracked listing of the directory holding `path`.
+    pub(crate) fn invalidate_untracked(&mut self, path: &[u8]) {
+        if let Some(ref mut cache) = self.untracked {
+            cache.invalidate(path);
+        }
+    }
+
+    // Any change to the entries stales the cached trees, and the untracked
+    // listing of the directory the change was in.
+    fn invalidate(&mut self, path: &[u8]) {
+        self.extensions.retain(|(signature, _)| *signature != CACHE_TREE);
+        self.invalidate_untracked(path);
+    }
+
+    // Takes the conflict stages of `path` out of the index, remembering them
+    // in the REUC extension.
+    fn take_stages(&mut self, path: &[u8]) {
+        let range = self.stages_of(path);
+        let mut stages = [None, None, None];
+        for entry in self.entries.drain(range) {
+            if entry.stage > 0 {
+                stages[entry.stage as usize - 1] = Some(TreeEntry { mode: entry.mode, id: entry.id });
+            }
+        }
+        if stages.iter().any(Option::is_some) {
+            self.resolve_undo.retain(|xs| xs.path != path);
+            self.resolve_undo.push(Unmerged { path: path.to_vec(), stages });
+            self.resolve_undo.sort_by(|lhs, rhs| lhs.path.cmp(&rhs.path));
+        }
+    }
+
+    // Adds or replaces an entry. A stage 0 entry resolves any conflict at its
+    // path; a conflict stage replaces the path's stage 0 entry.
+    pub fn add(&mut self, entry: Entry) {
+        self.invalidate(&entry.path);
+        if entry.stage == 0 {
+            self.take_stages(&entry.path);
+        } else if let Ok(xs) = self.search(&entry.path, 0) {
+            self.entries.remove(xs);
+        }
+        match self.search(&entry.path, entry.stage) {
+            Ok(xs) => self.entries[xs] = entry,
+            Err(xs) => self.entries.insert(xs, entry)
+        }
+    }
+
+    // Removes `path` at every stage, as `git rm --cached`.
+    pub fn remove(&mut self, path: &[u8]) -> bool {
+        self.invalidate(path);
+        let found = !self.stages_of(path).is_empty();
+        self.take_stages(path);
+        found
+    }
+
+    // Records `path` as conflicted; None leaves a stage out, as for a path
+    // one side deleted.
+    pub fn add_conflict(&mut self, path: &[u8], base: Option<&TreeEntry>, ours: Option<&TreeEntry>, theirs: Option<&TreeEntry>) {
+        self.invalidate(path);
+        let range = self.stages_of(path);
+        let entries: Vec<Entry> = [base, ours, theirs].iter().zip(1..).filter_map(|(side, stage)| {
+            side.map(|xs| Entry::staged(path, stage, xs))
+        }).collect();
+        self.entries.splice(range, entries);
+    }
+
+    pub fn has_conflicts(&self) -> bool {
+        self.entries.iter().any(|xs| xs.stage > 0)
+    }
+
+    pub fn conflicts(&self) -> Vec<Unmerged> {
+        let mut result: Vec<Unmerged> = Vec::new();
+        for entry in self.entries.iter().filter(|xs| xs.stage > 0) {
+            if result.last().is_none_or(|xs| xs.path != entry.path) {
+                result.push(Unmerged { path: entry.path.clone(), stages: [None, None, None] });
+            }
+            let last = result.last_mut().unwrap();
+            last.stages[entry.stage as usize - 1] = Some(TreeEntry { mode: entry.mode, id: entry.id.clone() });
+        }
+        result
+    }
+
+    // Settles a conflict with `entry` (or by deleting the path, if None).
+    pub fn resolve(&mut self, path: &[u8], entry: Option<Entry>) {
+        match entry {
+            Some(xs) => self.add(Entry { path: path.to_vec(), stage: 0, ..xs }),
+            None => {
+                self.remove(path);
+            }
+        }
+    }
+
+    pub fn resolve_undo(&self) -> &[Unmerged] {
+        &self.resolve_undo
+    }
+
+    // Recreates the conflict `path` was resolved from, as `git update-index
+    // --unresolve`. False if nothing was recorded for it.
+    pub fn unresolve(&mut self, path: &[u8]) -> bool {
+        let record = match self.resolve_undo.iter().position(|xs| xs.path == path) {
+            Some(xs) => self.resolve_undo.remove(xs),
+            None => return false
+        };
+        let [base, ours, theirs] = &record.stages;
+        self.add_conflict(path, base.as_ref(), ours.as_ref(), theirs.as_ref());
+        true
+    }
+
+    // What `git commit` and `git checkout` do once the recorded resolutions
+    // are no longer wanted.
+    pub fn clear_resolve_undo(&mut self) {
+        self.resolve_undo.clear();
+    }
+
+    // Writes the stage 0 entries out as a tree, as `git write-tree`. An index
+    // with conflicts can't be committed.
+    pub fn write_tree<F>(&self, mut put: F) -> Result<Id> where F: FnMut(Type, Vec<u8>) -> Result<Id> {
+        let conflicts = self.conflicts();
+        if !conflicts.is_empty() {
+            return Err(ErrorKind::UnmergedIndex(conflicts.into_iter().map(|xs| xs.path).collect()).into())
+        }
+        let entries: BTreeMap<Vec<u8>, TreeEntry> = self.entries.iter()
+            .filter(|xs| !xs.intent_to_add)
+            .map(|xs| (xs.path.clone(), TreeEntry { mode: xs.mode, id: xs.id.clone() }))
+            .collect();
+        write_nested(&entries, &mut put)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::objects::tree::{ FileMode, TreeEntry };
+    use crate::merge::tree::merge_commits;
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::stores::fs::{ self as gitfs, write_loose };
+    use crate::merge::file::Options;
+    use crate::errors::ErrorKind;
+    use chrono::{ Duration, TimeZone, Utc };
+
+    use crate::clock::{ Clock, FixedClock };
+    use crate::lock::Retry;
+    use crate::objects::{ self, Type };
+    use crate::id::Id;
+    use crate::files;
+    use super::{ read_varint, write_varint, Entry, Index, Stat };
+
+    fn blob(byte: u8) -> TreeEntry {
+        TreeEntry { mode: FileMode::FILE, id: Id::from(&[byte; 20]) }
+    }
+
+    #[test]
+    fn stages_and_resolve_undo_roundtrip() {
+        let mut index = Index::new();
+        index.add(Entry::new(b"a".to_vec(), FileMode::FILE, blob(1).id));
+        index.add(Entry { intent_to_add: true, ..Entry::new(b"b/long-name.txt".to_vec(), FileMode::EXECUTABLE, blob(2).id) });
+        index.add(Entry::new(b"c".to_vec(), FileMode::FILE, blob(3).id));
+        index.add_conflict(b"c", Some(&blob(4)), Some(&blob(5)), None);
+        index.extensions.push((*b"UNTR", b"kept".to_vec()));
+        assert!(index.get(b"c", 0).is_none());
+        assert_eq!(index.conflicts()[0].ours(), Some(&blob(5)));
+
+        let mut data = Vec::new();
+        index.write(&mut data).expect("failed to write");
+        let mut read = Index::parse(&data).expect("failed to parse");
+        assert_eq!(read.version, 3);
+        assert_eq!(read.entries(), index.entries());
+        assert_eq!(read.extensions, index.extensions);
+
+        read.resolve(b"c", Some(Entry::new(Vec::new(), FileMode::FILE, blob(6).id)));
+        assert!(!read.has_conflicts());
+        let mut data = Vec::new();
+        read.write(&mut data).expect("failed to write");
+        let mut read = Index::parse(&data).expect("failed to parse");
+        assert_eq!(read.resolve_undo()[0].stages, [Some(blob(4)), Some(blob(5)), None]);
+
+        assert!(read.unresolve(b"c"));
+        assert!(read.resolve_undo().is_empty());
+        assert_eq!(read.conflicts(), index.conflicts());
+        data[40] ^= 1;
+        assert!(Index::parse(&data).is_err());
+    }
+
+    #[test]
+    fn merges_record_conflicts_until_resolved() {
+        let dir = TempDir::new("index").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("base", files!["README" => "a\n", "same" => "x\n"])
+            .branch("theirs")
+            .commit("ours", files!["README" => "ours\n"])
+            .checkout("theirs")
+            .commit("theirs", files!["README" => "theirs\n"]);
+        let theirs = builder.tip().unwrap();
+        let builder = builder.checkout("master");
+        let ours = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let put = |typ, data: Vec<u8>| Ok(write_loose(dir.path(), typ, &data)?);
+        let merge = merge_commits(&storage_set, &ours, &theirs, &Options::default(), put).expect("failed to merge");
+        // the merged tree was written after the storage was opened.
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let index = Index::from_merge(&storage_set, &merge).expect("failed to read merge");
+        let stages: Vec<_> = index.entries().iter().map(|xs| (String::from_utf8_lossy(&xs.path).into_owned(), xs.stage)).collect();
+        assert_eq!(stages, vec![
+            (String::from("README"), 1),
+            (String::from("README"), 2),
+            (String::from("README"), 3),
+            (String::from("same"), 0)
+        ]);
+        index.save(dir.path()).expect("failed to save");
+        let mut index = Index::open(dir.path()).expect("failed to open");
+        match index.write_tree(put) {
+            Err(e) => match e.kind() {
+                ErrorKind::UnmergedIndex(paths) => assert_eq!(paths, &vec![b"README".to_vec()]),
+                xs => panic!("unexpected error {:?}", xs)
+            },
+            Ok(_) => panic!("wrote a tree with conflicts")
+        }
+
+        let resolved = write_loose(dir.path(), crate::objects::Type::Blob, b"both\n").expect("failed to write");
+        index.resolve(b"README", Some(Entry::new(Vec::new(), FileMode::FILE, resolved)));
+        let tree = index.write_tree(put).expect("failed to write tree");
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        assert_eq!(Index::from_tree(&storage_set, &tree).unwrap().entries(), index.entries());
+    }
+
+    #[test]
+    fn split_index_records_changes_against_the_shared_index() {
+        let dir = TempDir::new("index").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "a\n", "one" => "1\n", "two" => "2\n"]);
+        let tip = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        let config = dir.path().join(".git/config");
+        let set = |contents: &str| std::fs::write(&config, contents).unwrap();
+        set("[core]\n\tsplitIndex = true\n[splitIndex]\n\tmaxPercentChange = 100\n");
+        let shared = || std::fs::read_dir(dir.path().join(".git")).unwrap()
+            .filter(|xs| xs.as_ref().unwrap().file_name().to_string_lossy().starts_with("sharedindex."))
+            .count();
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        Index::from_tree(&storage_set, &tip).unwrap().save(dir.path()).expect("failed to save");
+        assert_eq!(shared(), 1);
+        let data = std::fs::read(dir.path().join(".git/index")).unwrap();
+        assert_eq!(&data[8..12], &[0, 0, 0, 0]);
+        assert!(Index::parse(&data).is_err());
+
+        let mut index = Index::open(dir.path()).expect("failed to open");
+        assert_eq!(index.entries().len(), 3);
+        index.add(Entry::new(b"one".to_vec(), FileMode::EXECUTABLE, blob(1).id));
+        index.add(Entry::new(b"three".to_vec(), FileMode::FILE, blob(3).id));
+        index.remove(b"two");
+        index.save(dir.path()).expect("failed to save");
+        assert_eq!(shared(), 1);
+        let data = std::fs::read(dir.path().join(".git/index")).unwrap();
+        assert_eq!(&data[8..12], &[0, 0, 0, 2]);
+        let read = Index::open(dir.path()).expect("failed to open");
+        assert_eq!(read.entries(), index.entries());
+
+        // a change to most of the entries starts a new shared index...
+        set("[core]\n\tsplitIndex = true\n[splitIndex]\n\tmaxPercentChange = 10\n");
+        read.save(dir.path()).expect("failed to save");
+        assert_eq!(shared(), 2);
+        assert_eq!(Index::open(dir.path()).unwrap().entries(), index.entries());
+
+        // ...and core.splitIndex=false joins it back up.
+        set("[core]\n\tsplitIndex = false\n");
+        Index::open(dir.path()).unwrap().save(dir.path()).expect("failed to save");
+        let data = std::fs::read(dir.path().join(".git/index")).unwrap();
+        assert_eq!(Index::parse(&data).unwrap().entries(), index.entries());
+    }
+
+    #[test]
+    fn varints_roundtrip() {
+        for value in &[0u64, 127, 128, 16511, 16512, 1 << 40] {
+            let mut data = Vec::new();
+            write_varint(*value, &mut data);
+            assert_eq!(read_varint(&mut &data[..]).unwrap(), *value);
+        }
+        let mut data = Vec::new();
+        write_varint(128, &mut data);
+        assert_eq!(data, vec![0x80, 0x00]);
+    }
+
+    #[test]
+    fn version_4_compresses_paths() {
+        let mut index = Index { version: 4, ..Index::new() };
+        for (path, byte) in &[(&b"dir/a"[..], 1), (b"dir/b", 2), (b"dir/sub/c", 3), (b"e", 4)] {
+            index.add(Entry::new(path.to_vec(), FileMode::FILE, blob(*byte).id));
+        }
+        let mut data = Vec::new();
+        index.write(&mut data).expect("failed to write");
+        assert!(!data.windows(5).any(|xs| xs == b"dir/b"));
+        // "dir/b" drops one byte of "dir/a" and adds "b".
+        assert!(data.windows(3).any(|xs| xs == b"\x01b\0"));
+        let read = Index::parse(&data).expect("failed to parse");
+        assert_eq!(read.version, 4);
+        assert_eq!(read.entries(), index.entries());
+    }
+
+    #[test]
+    fn racy_entries_are_smudged_as_of_the_clock() {
+        let dir = TempDir::new("index-racy").expect("failed to create tempdir");
+        RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"])
+            .write(dir.path())
+            .expect("failed to write");
+        let clock = FixedClock::new(Utc.timestamp_opt(1_600_000_000, 0).unwrap());
+        let at = |offset: i64| Stat { mtime: ((1_600_000_000 + offset) as u32, 500), size: 6, ..Stat::default() };
+        let empty = objects::hash(Type::Blob, b"");
+
+        let mut index = Index::new();
+        index.add(Entry { stat: at(-1), ..Entry::new(b"old".to_vec(), FileMode::FILE, blob(1).id) });
+        index.add(Entry { stat: at(0), ..Entry::new(b"same-second".to_vec(), FileMode::FILE, blob(2).id) });
+        index.add(Entry { stat: at(5), ..Entry::new(b"future".to_vec(), FileMode::FILE, blob(3).id) });
+        index.add(Entry { stat: Stat { size: 0, ..at(0) }, ..Entry::new(b"empty".to_vec(), FileMode::FILE, empty) });
+        index.add(Entry::new(b"unstatted".to_vec(), FileMode::FILE, blob(4).id));
+        assert!(at(0).is_racy(clock.now()));
+        assert!(!at(-1).is_racy(clock.now()));
+        assert!(!Stat::default().is_racy(clock.now()));
+
+        index.save_with_clock(dir.path(), &Retry::none(), &clock).expect("failed to save");
+        // the index saved is smudged; the one in memory is not.
+        assert!(index.entries().iter().all(|xs| xs.path == b"empty" || xs.path == b"unstatted" || xs.stat.size == 6));
+        let read = Index::open(dir.path()).expect("failed to open");
+        let sizes: Vec<(&[u8], u32)> = read.entries().iter().map(|xs| (xs.path.as_slice(), xs.stat.size)).collect();
+        assert_eq!(sizes, vec![(&b"empty"[..], 0), (b"future", 0), (b"old", 6), (b"same-second", 0), (b"unstatted", 0)]);
+        let entry = |path: &[u8]| read.entries().iter().find(|xs| xs.path == path).unwrap().clone();
+        assert!(entry(b"old").stat_matches(&at(-1)));
+        assert!(!entry(b"same-second").stat_matches(&Stat { size: 0, ..at(0) }));
+        assert!(entry(b"empty").stat_matches(&Stat { size: 0, ..at(0) }));
+
+        // once the clock has moved on, nothing recorded before it is racy.
+        clock.advance(Duration::seconds(10));
+        let mut index = read.clone();
+        index.add(Entry { stat: at(0), ..entry(b"same-second") });
+        index.save_with_clock(dir.path(), &Retry::none(), &clock).expect("failed to save");
+        assert!(Index::open(dir.path()).unwrap().entries().iter().any(|xs| xs.path == b"same-second" && xs.stat.size == 6));
+    }
+}
@@ -1,3 +1,5 @@
+#![recursion_limit = "256"]
+
 #[macro_use]
 extern crate error_chain;
 
@@ -10,6 +12,50 @@ pub mod pack;
 pub mod refs;
 pub mod walk;
 pub mod identity;
+pub mod shallow;
+pub mod checkout;
+pub mod worktree;
+pub mod config;
+pub mod credential;
+pub mod submodule;
+pub mod reflog;
+pub mod stash;
+pub mod metrics;
+pub mod namespace;
+pub mod bitmap;
+pub mod patch;
+pub mod diff;
+pub mod merge;
+pub mod format_patch;
+pub mod mailinfo;
+pub mod index;
+pub mod ignore;
+pub mod untracked;
+pub mod fsmonitor;
+pub mod sparse;
+pub mod blame;
+pub mod cherry_pick;
+pub mod abbrev;
+pub mod clone;
+pub mod patch_id;
+pub mod attributes;
+pub mod filter;
+pub mod eol;
+pub mod archive;
+pub mod pathspec;
+pub mod progress;
+pub mod cancel;
+pub mod clock;
+pub mod lock;
+pub mod vfs;
+pub mod repository;
+pub mod switch;
+
+#[cfg(feature = "capi")]
+pub mod capi;
+
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,166 @@
+use std::fs::{ File, OpenOptions };
+use std::path::{ Path, PathBuf };
+use std::time::{ Duration, Instant };
+use std::io::Write;
+
+// How long to keep trying for a lock someone else holds. Each wait is
+// roughly twice the last, with some jitter so that waiters don't retry in
+// step, as git's hold_lock_file_for_update_timeout does.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Retry {
+    pub timeout: Duration
+}
+
+impl Retry {
+    // Fail at once, git's default for the index and shallow.
+    pub fn none() -> Retry {
+        Retry::default()
+    }
+
+    pub fn timeout(timeout: Duration) -> Retry {
+        Retry { timeout }
+    }
+
+    // core.filesRefLockTimeout's default.
+    pub fn refs() -> Retry {
+        Retry::timeout(Duration::from_millis(100))
+    }
+}
+
+fn jitter(wait: Duration) -> Duration {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|xs| xs.subsec_nanos())
+        .unwrap_or(0);
+    // somewhere between 3/4 and 5/4 of `wait`.
+    wait * 3 / 4 + wait * (nanos % 512) / 1024
+}
+
+// `<path>.lock`, git's lock on `path`: whoever creates it owns `path` until
+// it is renamed over `path` (`commit`) or removed (dropping the lock). Git
+// takes the same locks, so the two can work in one repository at once.
+pub struct LockFile {
+    path: PathBuf,
+    lock: PathBuf,
+    file: Option<File>
+}
+
+pub fn lock_path(path: &Path) -> PathBuf {
+    path.with_file_name(format!("{}.lock", path.file_name().unwrap_or_default().to_string_lossy()))
+}
+
+impl LockFile {
+    // Fails with AlreadyExists if the lock is still held once `retry` runs out.
+    pub fn acquire(path: &Path, retry: &Retry) -> Result<LockFile, std::io::Error> {
+        let lock = lock_path(path);
+        let start = Instant::now();
+        let mut wait = Duration::from_millis(1);
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(lock.as_path()) {
+                Ok(file) => return Ok(LockFile {
+                    path: path.to_path_buf(),
+                    lock,
+                    file: Some(file)
+                }),
+                Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists && start.elapsed() < retry.timeout => (),
+                Err(e) => return Err(e)
+            }
+            let left = retry.timeout - start.elapsed().min(retry.timeout);
+            std::thread::sleep(jitter(wait).min(left));
+            wait = (wait * 2).min(Duration::from_secs(1));
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn lock_path(&self) -> &Path {
+        &self.lock
+    }
+
+    // Replaces `path` with what was written. If that fails the lock is
+    // removed, as when dropped.
+    pub fn commit(mut self) -> Result<(), std::io::Error> {
+        if let Some(ref file) = self.file {
+            file.sync_all()?;
+        }
+        std::fs::rename(self.lock.as_path(), self.path.as_path())?;
+        self.file = None;
+        Ok(())
+    }
+}
+
+impl Write for LockFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.as_mut().expect("lock is committed").write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.as_mut().expect("lock is committed").flush()
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        // committed locks have been renamed away already.
+        if self.file.is_some() {
+            let _ = std::fs::remove_file(self.lock.as_path());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{ Duration, Instant };
+    use std::io::Write;
+
+    use crate::testkit::TempDir;
+    use super::{ LockFile, Retry };
+
+    #[test]
+    fn locks_exclude_and_retry() {
+        let dir = TempDir::new("lock").expect("failed to create tempdir");
+        let target = dir.path().join("HEAD");
+
+        let mut lock = LockFile::acquire(&target, &Retry::none()).expect("failed to lock");
+        let started = Instant::now();
+        let second = LockFile::acquire(&target, &Retry::timeout(Duration::from_millis(50)));
+        assert_eq!(second.err().map(|xs| xs.kind()), Some(std::io::ErrorKind::AlreadyExists));
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        lock.write_all(b"ref: refs/heads/master\n").unwrap();
+        lock.commit().expect("failed to commit");
+        assert_eq!(std::fs::read(&target).unwrap(), b"ref: refs/heads/master\n");
+        assert!(!dir.path().join("HEAD.lock").exists());
+
+        // a dropped lock leaves the target alone.
+        let mut lock = LockFile::acquire(&target, &Retry::none()).expect("failed to lock");
+        lock.write_all(b"garbage").unwrap();
+        drop(lock);
+        assert_eq!(std::fs::read(&target).unwrap(), b"ref: refs/heads/master\n");
+
+        // a lock released while we wait is taken.
+        let held = LockFile::acquire(&target, &Retry::none()).expect("failed to lock");
+        let releaser = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            drop(held);
+        });
+        LockFile::acquire(&target, &Retry::timeout(Duration::from_secs(5))).expect("failed to lock after release");
+        releaser.join().unwrap();
+    }
+
+    #[test]
+    fn failed_commits_release_the_lock() {
+        let dir = TempDir::new("lock-commit").expect("failed to create tempdir");
+        // a file can't be renamed over a directory that has something in it.
+        let target = dir.path().join("refs");
+        std::fs::create_dir_all(target.join("heads")).unwrap();
+
+        let mut lock = LockFile::acquire(&target, &Retry::none()).expect("failed to lock");
+        lock.write_all(b"garbage").unwrap();
+        assert!(lock.commit().is_err());
+        assert!(!dir.path().join("refs.lock").exists());
+        assert!(target.join("heads").is_dir());
+    }
+}
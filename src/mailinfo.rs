@@ -0,0 +1,375 @@
+use chrono::{ DateTime, Utc };
+
+use crate::errors::{ ErrorKind, Result };
+use crate::patch::{ self, split_lines, FilePatch };
+use crate::identity::Identity;
+
+// What `git mailinfo` pulls out of a patch email: who wrote it, the commit
+// message (subject, blank line, body) and the patch text.
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub author: Identity,
+    pub subject: String,
+    pub message: Vec<u8>,
+    pub patch: Vec<u8>
+}
+
+impl Mail {
+    pub fn files(&self) -> Result<Vec<FilePatch>> {
+        patch::parse(&self.patch)
+    }
+}
+
+// Splits an mbox on its "From " separator lines. Input without one is
+// treated as a single message.
+pub fn split_mbox(input: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut offset = 0;
+    let mut previous_blank = true;
+    for line in split_lines(input) {
+        if previous_blank && is_separator(line) {
+            starts.push(offset);
+        }
+        previous_blank = line == b"\n" || line == b"\r\n";
+        offset += line.len();
+    }
+    if starts.first() != Some(&0) {
+        starts.insert(0, 0);
+    }
+
+    let mut messages = Vec::new();
+    for (idx, start) in starts.iter().enumerate() {
+        let end = starts.get(idx + 1).cloned().unwrap_or(input.len());
+        if input[*start..end].iter().any(|xs| !xs.is_ascii_whitespace()) {
+            messages.push(&input[*start..end]);
+        }
+    }
+    messages
+}
+
+fn is_separator(line: &[u8]) -> bool {
+    line.starts_with(b"From ") && !line.starts_with(b"From: ")
+}
+
+pub fn parse_mbox(input: &[u8]) -> Result<Vec<Mail>> {
+    split_mbox(input).into_iter().map(parse).collect()
+}
+
+fn base64(input: &[u8]) -> Option<Vec<u8>> {
+    let value = |xs: u8| match xs {
+        b'A'..=b'Z' => Some(xs - b'A'),
+        b'a'..=b'z' => Some(xs - b'a' + 26),
+        b'0'..=b'9' => Some(xs - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None
+    };
+
+    let mut output = Vec::new();
+    let (mut acc, mut bits) = (0u32, 0);
+    for byte in input.iter().filter(|xs| !xs.is_ascii_whitespace()) {
+        if *byte == b'=' {
+            break
+        }
+        acc = (acc << 6) | u32::from(value(*byte)?);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((acc >> bits) as u8);
+        }
+    }
+    Some(output)
+}
+
+fn hex_digit(xs: u8) -> Option<u8> {
+    (xs as char).to_digit(16).map(|xs| xs as u8)
+}
+
+// Quoted-printable; `underscores` is the header ("Q") variant.
+fn quoted_printable(input: &[u8], underscores: bool) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut idx = 0;
+    while idx < input.len() {
+        match input[idx] {
+            b'_' if underscores => output.push(b' '),
+            b'=' if input[idx + 1..].starts_with(b"\r\n") => idx += 2,
+            b'=' if input[idx + 1..].starts_with(b"\n") => idx += 1,
+            b'=' if idx + 2 < input.len() => match (hex_digit(input[idx + 1]), hex_digit(input[idx + 2])) {
+                (Some(hi), Some(lo)) => {
+                    output.push(hi << 4 | lo);
+                    idx += 2;
+                },
+                _ => output.push(b'=')
+            },
+            xs => output.push(xs)
+        }
+        idx += 1;
+    }
+    output
+}
+
+// Decodes RFC 2047 encoded words ("=?UTF-8?q?...?="); other text is kept.
+fn decode_header(value: &str) -> String {
+    let mut output = String::new();
+    let mut rest = value;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let decoded = rest[start + 2..].splitn(3, '?').collect::<Vec<_>>();
+        let word = match decoded.as_slice() {
+            [charset, encoding, tail] => tail.find("?=").map(|end| (charset.to_ascii_lowercase(), encoding.to_ascii_lowercase(), &tail[..end], end)),
+            _ => None
+        };
+        let (charset, encoding, text, end) = match word {
+            Some(xs) => xs,
+            None => break
+        };
+
+        // whitespace between two encoded words is dropped.
+        let between = &rest[..start];
+        if !(after_word && between.trim().is_empty()) {
+            output.push_str(between);
+        }
+        let bytes = match encoding.as_str() {
+            "b" => base64(text.as_bytes()).unwrap_or_default(),
+            _ => quoted_printable(text.as_bytes(), true)
+        };
+        if charset == "iso-8859-1" || charset == "latin1" {
+            output.extend(bytes.iter().map(|xs| *xs as char));
+        } else {
+            output.push_str(&String::from_utf8_lossy(&bytes));
+        }
+
+        let consumed = start + 2 + decoded[0].len() + decoded[1].len() + 2 + end + 2;
+        rest = &rest[consumed..];
+        after_word = true;
+    }
+    output.push_str(rest);
+    output
+}
+
+// "A U Thor <a@example.com>", "a@example.com (A U Thor)" or a bare address.
+fn parse_address(value: &str) -> Option<(String, String)> {
+    let value = value.trim();
+    if let (Some(open), Some(close)) = (value.rfind('<'), value.rfind('>')) {
+        if open < close {
+            let name = value[..open].trim().trim_matches('"').replace("\\\"", "\"").replace("\\\\", "\\");
+            let email = value[open + 1..close].trim();
+            return Some((if name.is_empty() { String::from(email) } else { name }, String::from(email)))
+        }
+    }
+    if let (Some(open), Some(close)) = (value.find('('), value.rfind(')')) {
+        if open < close {
+            return Some((String::from(value[open + 1..close].trim()), String::from(value[..open].trim())))
+        }
+    }
+    if value.contains('@') {
+        return Some((String::from(value), String::from(value)))
+    }
+    None
+}
+
+// Removes "Re:" and "[PATCH n/m]"-style prefixes, as `git mailinfo` does.
+fn clean_subject(subject: &str) -> String {
+    let mut subject = subject.trim();
+    loop {
+        // by bytes: the first three may end inside a character.
+        if subject.as_bytes().get(..3).is_some_and(|xs| xs.eq_ignore_ascii_case(b"re:")) {
+            subject = subject[3..].trim_start();
+        } else if subject.starts_with('[') {
+            match subject.find(']') {
+                Some(end) => subject = subject[end + 1..].trim_start(),
+                None => break
+            }
+        } else {
+            break
+        }
+    }
+    subject.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn trim_line(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+// Where the patch starts: the "---" line before a diffstat, or the first
+// diff header when there is none.
+fn is_patch_start(line: &[u8]) -> bool {
+    line == b"---" || line.starts_with(b"diff -") || line.starts_with(b"Index: ")
+}
+
+// Parses a single message: headers, then a body split into the commit
+// message and the patch.
+pub fn parse(email: &[u8]) -> Result<Mail> {
+    let lines = split_lines(email);
+    let mut idx = 0;
+    if lines.first().is_some_and(|xs| is_separator(xs)) {
+        idx += 1;
+    }
+
+    // headers, with continuation lines unfolded.
+    let mut headers: Vec<(String, String)> = Vec::new();
+    while idx < lines.len() {
+        let line = String::from_utf8_lossy(trim_line(lines[idx])).into_owned();
+        idx += 1;
+        if line.is_empty() {
+            break
+        }
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue
+        }
+        if let Some(colon) = line.find(':') {
+            headers.push((line[..colon].trim().to_ascii_lowercase(), String::from(line[colon + 1..].trim())));
+        }
+    }
+    let header = |name: &str| headers.iter().rev().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+
+    let raw_body = lines[idx..].concat();
+    let body = match header("content-transfer-encoding").map(|xs| xs.to_ascii_lowercase()) {
+        Some(ref xs) if xs == "quoted-printable" => quoted_printable(&raw_body, false),
+        Some(ref xs) if xs == "base64" => match base64(&raw_body) {
+            Some(xs) => xs,
+            None => return Err(ErrorKind::BadEmail("undecodable base64 body").into())
+        },
+        _ => raw_body
+    };
+
+    let mut from = header("from").map(decode_header);
+    let mut subject = header("subject").map(decode_header).map(|xs| clean_subject(&xs));
+    let mut date = header("date").map(String::from);
+
+    let body_lines = split_lines(&body);
+    let mut start = 0;
+    // "From:"/"Subject:"/"Date:" at the top of the body override the headers.
+    while let Some(line) = body_lines.get(start) {
+        let line = String::from_utf8_lossy(trim_line(line)).into_owned();
+        if let Some(xs) = line.strip_prefix("From:") {
+            from = Some(decode_header(xs.trim()));
+        } else if let Some(xs) = line.strip_prefix("Subject:") {
+            subject = Some(clean_subject(&decode_header(xs.trim())));
+        } else if let Some(xs) = line.strip_prefix("Date:") {
+            date = Some(String::from(xs.trim()));
+        } else {
+            break
+        }
+        start += 1;
+    }
+
+    let patch_start = body_lines[start..].iter().position(|xs| is_patch_start(trim_line(xs))).map(|xs| xs + start);
+    let end = patch_start.unwrap_or(body_lines.len());
+    let description = body_lines[start..end].concat();
+    let mut patch: Vec<u8> = match patch_start {
+        Some(xs) => body_lines[xs..].concat(),
+        None => Vec::new()
+    };
+    // the "-- " signature is not part of the patch.
+    if let Some(xs) = split_lines(&patch).iter().rposition(|xs| trim_line(xs) == b"-- ") {
+        let offset: usize = split_lines(&patch)[..xs].iter().map(|xs| xs.len()).sum();
+        patch.truncate(offset);
+    }
+
+    let (name, address) = match from.as_deref().and_then(parse_address) {
+        Some(xs) => xs,
+        None => return Err(ErrorKind::BadEmail("no author").into())
+    };
+    let when = match date.as_deref().map(DateTime::parse_from_rfc2822) {
+        Some(Ok(xs)) => xs,
+        _ => return Err(ErrorKind::BadEmail("missing or malformed date").into())
+    };
+    let subject = subject.unwrap_or_default();
+
+    let mut message = subject.clone().into_bytes();
+    message.push(b'\n');
+    let description = String::from_utf8_lossy(&description);
+    let description = description.trim();
+    if !description.is_empty() {
+        message.push(b'\n');
+        message.extend_from_slice(description.as_bytes());
+        message.push(b'\n');
+    }
+
+    Ok(Mail {
+        author: Identity::new(name.as_bytes(), address.as_bytes(), when.with_timezone(&Utc), *when.offset()),
+        subject,
+        message,
+        patch
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::format_patch::{ format, range, Options };
+    use crate::testkit::RepoBuilder;
+    use crate::diff::diff_trees;
+    use crate::files;
+    use super::{ parse, parse_mbox };
+
+    #[test]
+    fn reads_back_format_patch_output() {
+        let builder = RepoBuilder::new()
+            .author("Zo\u{eb} Example", "zoe@example.com")
+            .commit("first", files!["README" => "hello\n"]);
+        let base = builder.tip().unwrap();
+        let builder = builder
+            .commit("Change the greeting\n\nIt was too terse.\n", files!["README" => "hello there\n"])
+            .commit("Add notes", files!["NOTES" => "one\ntwo\n"]);
+        let tip = builder.tip().unwrap();
+        let (storage_set, _) = builder.in_memory();
+
+        let ids = range(&storage_set, Some(&base), &tip).expect("failed to walk");
+        let emails = format(&storage_set, &ids, &Options::default()).expect("failed to format");
+        let mbox: Vec<u8> = emails.iter().flat_map(|xs| xs.contents.clone()).collect();
+
+        let mails = parse_mbox(&mbox).expect("failed to parse");
+        assert_eq!(mails.len(), 2);
+        assert_eq!(mails[0].author.name(), "Zo\u{eb} Example".as_bytes());
+        assert_eq!(mails[0].author.email(), b"zoe@example.com");
+        assert_eq!(mails[0].subject, "Change the greeting");
+        assert_eq!(mails[0].message, b"Change the greeting\n\nIt was too terse.\n");
+        assert_eq!(mails[1].message, b"Add notes\n");
+
+        let commit = match storage_set.get_and_load(&ids[0]).expect("failed to load") {
+            Some(crate::objects::Object::Commit(xs)) => xs,
+            _ => panic!("expected a commit")
+        };
+        assert_eq!(mails[0].author.at(), commit.author().unwrap().at());
+
+        let expected = diff_trees(&storage_set, Some(&base), Some(&ids[0]), &Options::default().diff).expect("failed to diff");
+        let files = mails[0].files().expect("failed to parse patch");
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].change, expected[0].change);
+    }
+
+    #[test]
+    fn decodes_mime_and_in_body_headers() {
+        let email = b"From: =?ISO-8859-1?Q?Andr=E9?= <andre@example.com>\n\
+Date: Thu, 20 Dec 2018 10:00:00 +0100\n\
+Subject: Re: [RFC PATCH v2 3/7] frob the\n =?UTF-8?b?d2lkZ2V0?=\n\
+Content-Transfer-Encoding: quoted-printable\n\
+\n\
+From: Real Author <real@example.com>\n\
+\n\
+Long line that was =\nwrapped, caf=C3=A9.\n\
+---\n\
+diff --git a/x b/x\n";
+        let mail = parse(email).expect("failed to parse");
+        assert_eq!(mail.author.name(), b"Real Author");
+        assert_eq!(mail.subject, "frob the widget");
+        assert_eq!(mail.message, "frob the widget\n\nLong line that was wrapped, caf\u{e9}.\n".as_bytes());
+        assert_eq!(mail.patch, b"---\ndiff --git a/x b/x\n");
+        assert_eq!(mail.author.offset().local_minus_utc(), 3600);
+        assert_eq!(super::decode_header("=?ISO-8859-1?Q?Andr=E9?= <andre@example.com>"), "Andr\u{e9} <andre@example.com>");
+    }
+
+    #[test]
+    fn subjects_starting_with_multibyte_characters() {
+        let mail = parse("From: A <a@example.com>\nDate: Thu, 20 Dec 2018 10:00:00 +0100\nSubject: \u{f1}\u{f1} fix\n\nbody\n".as_bytes()).expect("failed to parse");
+        assert_eq!(mail.subject, "\u{f1}\u{f1} fix");
+        assert_eq!(super::clean_subject("RE: \u{f1} fix"), "\u{f1} fix");
+        assert_eq!(super::clean_subject("r\u{e9}sum\u{e9}"), "r\u{e9}sum\u{e9}");
+    }
+}
@@ -0,0 +1,303 @@
+use crate::diff::{ edits, is_binary, Edit };
+use crate::patch::split_lines;
+
+// How to settle regions both sides changed differently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Favor {
+    Ours,
+    Theirs,
+    // both sides' lines, ours first, without markers
+    Union
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Style {
+    Merge,
+    // also show the base between "|||||||" and "=======" (merge.conflictStyle=diff3)
+    Diff3
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub marker_size: usize,
+    pub favor: Option<Favor>,
+    pub style: Style,
+    pub ours_label: String,
+    pub base_label: String,
+    pub theirs_label: String
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            marker_size: 7,
+            favor: None,
+            style: Style::Merge,
+            ours_label: String::from("ours"),
+            base_label: String::from("base"),
+            theirs_label: String::from("theirs")
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Merged {
+    pub contents: Vec<u8>,
+    // regions written with conflict markers
+    pub conflicts: usize
+}
+
+impl Merged {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts == 0
+    }
+}
+
+// A changed region: base[base_start..base_end] became side[start..end].
+#[derive(Clone, Copy, Debug)]
+struct Change {
+    base_start: usize,
+    base_end: usize,
+    start: usize,
+    end: usize
+}
+
+fn changes<T: PartialEq>(base: &[T], side: &[T]) -> Vec<Change> {
+    let mut result: Vec<Change> = Vec::new();
+    let (mut x, mut y) = (0, 0);
+    let mut open: Option<Change> = None;
+    for edit in edits(base, side) {
+        match edit {
+            Edit::Equal(..) => {
+                result.extend(open.take());
+                x += 1;
+                y += 1;
+            },
+            Edit::Delete(_) => {
+                open.get_or_insert(Change { base_start: x, base_end: x, start: y, end: y }).base_end += 1;
+                x += 1;
+            },
+            Edit::Insert(_) => {
+                open.get_or_insert(Change { base_start: x, base_end: x, start: y, end: y }).end += 1;
+                y += 1;
+            }
+        }
+    }
+    result.extend(open);
+    result
+}
+
+// The side's range covering base[start..end], given the side's changes that
+// fall within it (sorted, possibly none).
+fn side_range(changes: &[Change], start: usize, end: usize, offset: isize) -> (usize, usize) {
+    match (changes.first(), changes.last()) {
+        (Some(first), Some(last)) => (first.start - (first.base_start - start), last.end + (end - last.base_end)),
+        _ => ((start as isize + offset) as usize, (end as isize + offset) as usize)
+    }
+}
+
+struct Writer<'a> {
+    output: Vec<u8>,
+    options: &'a Options,
+    conflicts: usize
+}
+
+impl<'a> Writer<'a> {
+    fn lines(&mut self, lines: &[&[u8]]) {
+        for line in lines {
+            self.output.extend_from_slice(line);
+        }
+    }
+
+    // Like `lines`, but ends on a newline so a marker can follow.
+    fn terminated(&mut self, lines: &[&[u8]]) {
+        self.lines(lines);
+        if !lines.is_empty() && !self.output.ends_with(b"\n") {
+            self.output.push(b'\n');
+        }
+    }
+
+    fn marker(&mut self, byte: u8, label: &str) {
+        self.output.extend(std::iter::repeat_n(byte, self.options.marker_size));
+        if !label.is_empty() {
+            self.output.push(b' ');
+            self.output.extend_from_slice(label.as_bytes());
+        }
+        self.output.push(b'\n');
+    }
+
+    fn conflict(&mut self, base: &[&[u8]], ours: &[&[u8]], theirs: &[&[u8]]) {
+        match self.options.favor {
+            Some(Favor::Ours) => return self.lines(ours),
+            Some(Favor::Theirs) => return self.lines(theirs),
+            Some(Favor::Union) => {
+                self.terminated(ours);
+                return self.lines(theirs)
+            },
+            None => ()
+        }
+
+        // lines both sides agree on at either end are not in conflict. The
+        // diff3 style keeps them, lest the base shown not match the sides.
+        let (prefix, suffix) = if self.options.style == Style::Diff3 {
+            (0, 0)
+        } else {
+            let prefix = ours.iter().zip(theirs).take_while(|(lhs, rhs)| lhs == rhs).count();
+            let suffix = ours[prefix..].iter().rev().zip(theirs[prefix..].iter().rev()).take_while(|(lhs, rhs)| lhs == rhs).count();
+            (prefix, suffix)
+        };
+        let (ours_end, theirs_end) = (ours.len() - suffix, theirs.len() - suffix);
+
+        self.lines(&ours[..prefix]);
+        self.conflicts += 1;
+        let options = self.options;
+        self.marker(b'<', &options.ours_label);
+        self.terminated(&ours[prefix..ours_end]);
+        if options.style == Style::Diff3 {
+            self.marker(b'|', &options.base_label);
+            self.terminated(base);
+        }
+        self.marker(b'=', "");
+        self.terminated(&theirs[prefix..theirs_end]);
+        self.marker(b'>', &options.theirs_label);
+        self.lines(&ours[ours_end..]);
+    }
+}
+
+// A line-based three-way merge of `ours` and `theirs` against their common
+// `base`, as `git merge-file` does. Binary inputs are merged whole.
+pub fn merge(base: &[u8], ours: &[u8], theirs: &[u8], options: &Options) -> Merged {
+    if ours == theirs || base == theirs {
+        return Merged { contents: ours.to_vec(), conflicts: 0 }
+    }
+    if base == ours {
+        return Merged { contents: theirs.to_vec(), conflicts: 0 }
+    }
+    if is_binary(base) || is_binary(ours) || is_binary(theirs) {
+        return match options.favor {
+            Some(Favor::Theirs) => Merged { contents: theirs.to_vec(), conflicts: 0 },
+            Some(_) => Merged { contents: ours.to_vec(), conflicts: 0 },
+            None => Merged { contents: ours.to_vec(), conflicts: 1 }
+        }
+    }
+
+    let (base_lines, ours_lines, theirs_lines) = (split_lines(base), split_lines(ours), split_lines(theirs));
+    let (ours_changes, theirs_changes) = (changes(&base_lines, &ours_lines), changes(&base_lines, &theirs_lines));
+
+    let mut writer = Writer {
+        output: Vec::with_capacity(ours.len().max(theirs.len())),
+        options,
+        conflicts: 0
+    };
+    // lines gained (or lost) by each side before the current base position.
+    let (mut ours_offset, mut theirs_offset) = (0isize, 0isize);
+    let (mut ours_idx, mut theirs_idx) = (0, 0);
+    let mut position = 0;
+
+    while ours_idx < ours_changes.len() || theirs_idx < theirs_changes.len() {
+        // start a region with whichever change comes first, then absorb every
+        // change on either side that overlaps or touches it.
+        let take_ours = match (ours_changes.get(ours_idx), theirs_changes.get(theirs_idx)) {
+            (Some(lhs), Some(rhs)) => lhs.base_start <= rhs.base_start,
+            (Some(_), None) => true,
+            _ => false
+        };
+        let first = if take_ours { ours_changes[ours_idx] } else { theirs_changes[theirs_idx] };
+        let (start, mut end) = (first.base_start, first.base_end);
+        let (ours_from, theirs_from) = (ours_idx, theirs_idx);
+        loop {
+            let mut grew = false;
+            while let Some(change) = ours_changes.get(ours_idx) {
+                if change.base_start > end {
+                    break
+                }
+                end = end.max(change.base_end);
+                ours_idx += 1;
+                grew = true;
+            }
+            while let Some(change) = theirs_changes.get(theirs_idx) {
+                if change.base_start > end {
+                    break
+                }
+                end = end.max(change.base_end);
+                theirs_idx += 1;
+                grew = true;
+            }
+            if !grew {
+                break
+            }
+        }
+
+        writer.lines(&base_lines[position..start]);
+        let ours_region = &ours_changes[ours_from..ours_idx];
+        let theirs_region = &theirs_changes[theirs_from..theirs_idx];
+        let (ours_start, ours_end) = side_range(ours_region, start, end, ours_offset);
+        let (theirs_start, theirs_end) = side_range(theirs_region, start, end, theirs_offset);
+        let (ours_text, theirs_text) = (&ours_lines[ours_start..ours_end], &theirs_lines[theirs_start..theirs_end]);
+
+        if theirs_region.is_empty() || ours_text == theirs_text {
+            writer.lines(ours_text);
+        } else if ours_region.is_empty() {
+            writer.lines(theirs_text);
+        } else {
+            writer.conflict(&base_lines[start..end], ours_text, theirs_text);
+        }
+
+        ours_offset = ours_end as isize - end as isize;
+        theirs_offset = theirs_end as isize - end as isize;
+        position = end;
+    }
+    writer.lines(&base_lines[position..]);
+
+    Merged {
+        contents: writer.output,
+        conflicts: writer.conflicts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ merge, Favor, Options, Style };
+
+    #[test]
+    fn disjoint_changes_merge_cleanly() {
+        let base = b"one\ntwo\nthree\nfour\nfive\nsix\n";
+        let ours = b"ONE\ntwo\nthree\nfour\nfive\nsix\n";
+        let theirs = b"one\ntwo\nthree\nfour\nfive\nSIX\nseven\n";
+        let merged = merge(base, ours, theirs, &Options::default());
+        assert!(merged.is_clean());
+        assert_eq!(merged.contents, b"ONE\ntwo\nthree\nfour\nfive\nSIX\nseven\n");
+
+        // the same change on both sides is not a conflict.
+        let merged = merge(base, ours, ours, &Options::default());
+        assert_eq!(merged.contents, ours);
+    }
+
+    #[test]
+    fn overlapping_changes_conflict() {
+        let base = b"a\nb\nc\n";
+        let ours = b"a\nours\nc\n";
+        let theirs = b"a\ntheirs\nc";
+        let merged = merge(base, ours, theirs, &Options::default());
+        assert_eq!(merged.conflicts, 1);
+        assert_eq!(merged.contents, b"a\n<<<<<<< ours\nours\nc\n=======\ntheirs\nc\n>>>>>>> theirs\n");
+
+        let options = Options { style: Style::Diff3, marker_size: 3, ..Options::default() };
+        let merged = merge(b"a\nb\n", b"a\nx\n", b"a\ny\n", &options);
+        assert_eq!(merged.contents, b"a\n<<< ours\nx\n||| base\nb\n===\ny\n>>> theirs\n");
+
+        let favor = |favor| Options { favor: Some(favor), ..Options::default() };
+        assert_eq!(merge(b"a\nb\n", b"a\nx\n", b"a\ny\n", &favor(Favor::Ours)).contents, b"a\nx\n");
+        assert_eq!(merge(b"a\nb\n", b"a\nx\n", b"a\ny\n", &favor(Favor::Theirs)).contents, b"a\ny\n");
+        let union = merge(b"a\nb\n", b"a\nx\n", b"a\ny\n", &favor(Favor::Union));
+        assert!(union.is_clean());
+        assert_eq!(union.contents, b"a\nx\ny\n");
+    }
+
+    #[test]
+    fn conflicts_keep_agreeing_lines_outside_markers() {
+        let merged = merge(b"a\nz\n", b"a\nsame\nx\n", b"a\nsame\ny\n", &Options::default());
+        assert_eq!(merged.contents, b"a\nsame\n<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs\n");
+        assert_eq!(merge(b"\0", b"\0a", b"\0b", &Options::default()).conflicts, 1);
+    }
+}
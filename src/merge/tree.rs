@@ -0,0 +1,355 @@
+use std::collections::{ BTreeMap, BTreeSet, HashMap, HashSet };
+
+use crate::objects::tree::{ write_nested, FileMode, TreeEntry };
+use crate::stores::{ Queryable, StorageSet };
+use crate::errors::{ ErrorKind, Result };
+use crate::objects::{ Object, Type };
+use crate::checkout::flatten;
+use crate::id::Id;
+use super::file::{ self, merge as merge_file };
+
+type Entries = BTreeMap<Vec<u8>, TreeEntry>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictKind {
+    // both sides changed the contents; the result has conflict markers
+    Content,
+    // both sides added the path with different contents
+    AddAdd,
+    // one side changed what the other deleted; the result keeps the change
+    ModifyDelete,
+    // the sides disagree on the kind of entry (file, symlink, submodule) or
+    // changed a symlink or submodule differently; the result keeps ours
+    Type,
+    // a file on one side is a directory on the other; the file is moved to
+    // "<path>~<label>"
+    DirectoryFile
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conflict {
+    pub path: Vec<u8>,
+    pub kind: ConflictKind,
+    pub base: Option<TreeEntry>,
+    pub ours: Option<TreeEntry>,
+    pub theirs: Option<TreeEntry>
+}
+
+#[derive(Clone, Debug)]
+pub struct TreeMerge {
+    pub tree: Id,
+    pub conflicts: Vec<Conflict>
+}
+
+impl TreeMerge {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+}
+
+// Every best common ancestor of the commits in `lhs` and `rhs`: common
+// ancestors that are not themselves ancestors of another common ancestor.
+fn bases_of<S: Queryable>(storage_set: &StorageSet<S>, lhs: &[Id], rhs: &[Id]) -> Result<Vec<Id>> {
+    let mut parents: HashMap<Id, Vec<Id>> = HashMap::new();
+    let mut ancestors = |tips: &[Id], order: &mut Vec<Id>| -> HashSet<Id> {
+        let mut seen = HashSet::new();
+        for tip in tips {
+            for (id, commit) in storage_set.commits(tip, None) {
+                if seen.insert(id.clone()) {
+                    order.push(id.clone());
+                    parents.entry(id).or_insert_with(|| commit.parents().unwrap_or_default());
+                }
+            }
+        }
+        seen
+    };
+
+    let mut order = Vec::new();
+    ancestors(lhs, &mut order);
+    let right = ancestors(rhs, &mut Vec::new());
+
+    // an ancestor of a common commit is a parent of some common commit.
+    let common: Vec<Id> = order.into_iter().filter(|xs| right.contains(xs)).collect();
+    let redundant: HashSet<&Id> = common.iter().flat_map(|xs| &parents[xs]).collect();
+    Ok(common.iter().filter(|xs| !redundant.contains(xs)).cloned().collect())
+}
+
+pub fn merge_bases<S: Queryable>(storage_set: &StorageSet<S>, ours: &Id, theirs: &Id) -> Result<Vec<Id>> {
+    bases_of(storage_set, std::slice::from_ref(ours), std::slice::from_ref(theirs))
+}
+
+fn is_file(mode: FileMode) -> bool {
+    mode == FileMode::FILE || mode == FileMode::EXECUTABLE
+}
+
+struct Merger<'a, S: Queryable, F> {
+    storage_set: &'a StorageSet<S>,
+    options: &'a file::Options,
+    put: F,
+    // blobs this merge wrote, which the storage set may not see yet
+    written: HashMap<Id, Vec<u8>>
+}
+
+impl<'a, S: Queryable, F: FnMut(Type, Vec<u8>) -> Result<Id>> Merger<'a, S, F> {
+    fn contents(&self, id: &Id) -> Result<Vec<u8>> {
+        if let Some(xs) = self.written.get(id) {
+            return Ok(xs.clone())
+        }
+        match self.storage_set.get_and_load(id)? {
+            Some(Object::Blob(blob)) => Ok(blob.contents),
+            _ => Err(ErrorKind::MissingObject.into())
+        }
+    }
+
+    fn write_blob(&mut self, data: Vec<u8>) -> Result<Id> {
+        let id = (self.put)(Type::Blob, data.clone())?;
+        self.written.insert(id.clone(), data);
+        Ok(id)
+    }
+
+    fn entries(&self, commit: &Id) -> Result<Entries> {
+        flatten(self.storage_set, commit)
+    }
+
+    // Merges two (possibly virtual) commits, each given as the real commits
+    // it stands for and its flattened tree.
+    fn recursive(&mut self, ours: (&[Id], &Entries), theirs: (&[Id], &Entries)) -> Result<(Entries, Vec<Conflict>)> {
+        let bases = bases_of(self.storage_set, ours.0, theirs.0)?;
+        let base = match bases.split_first() {
+            None => Entries::new(),
+            Some((first, rest)) => {
+                let mut tips = vec![first.clone()];
+                let mut merged = self.entries(first)?;
+                // fold multiple bases into one virtual base, conflicts and all.
+                for next in rest {
+                    let next_entries = self.entries(next)?;
+                    merged = self.recursive((&tips, &merged), (std::slice::from_ref(next), &next_entries))?.0;
+                    tips.push(next.clone());
+                }
+                merged
+            }
+        };
+        self.merge_entries(&base, ours.1, theirs.1)
+    }
+
+    fn merge_entries(&mut self, base: &Entries, ours: &Entries, theirs: &Entries) -> Result<(Entries, Vec<Conflict>)> {
+        let paths: BTreeSet<&Vec<u8>> = base.keys().chain(ours.keys()).chain(theirs.keys()).collect();
+        let mut merged = Entries::new();
+        let mut conflicts = Vec::new();
+
+        for path in paths {
+            let (b, o, t) = (base.get(path), ours.get(path), theirs.get(path));
+            let conflict = |kind| Conflict {
+                path: path.clone(),
+                kind,
+                base: b.cloned(),
+                ours: o.cloned(),
+                theirs: t.cloned()
+            };
+
+            let resolved = if o == t || b == t {
+                o.cloned()
+            } else if b == o {
+                t.cloned()
+            } else {
+                match (o, t) {
+                    (Some(lhs), Some(rhs)) => {
+                        let base_blob = b.filter(|xs| is_file(xs.mode));
+                        if !is_file(lhs.mode) || !is_file(rhs.mode) || (b.is_some() && base_blob.is_none()) {
+                            conflicts.push(conflict(ConflictKind::Type));
+                            Some(lhs.clone())
+                        } else {
+                            let mode = match b {
+                                Some(xs) if xs.mode == lhs.mode => rhs.mode,
+                                _ => lhs.mode
+                            };
+                            let base_data = match base_blob {
+                                Some(xs) => self.contents(&xs.id)?,
+                                None => Vec::new()
+                            };
+                            let result = merge_file(&base_data, &self.contents(&lhs.id)?, &self.contents(&rhs.id)?, self.options);
+                            if !result.is_clean() {
+                                conflicts.push(conflict(if b.is_some() { ConflictKind::Content } else { ConflictKind::AddAdd }));
+                            }
+                            Some(TreeEntry { mode, id: self.write_blob(result.contents)? })
+                        }
+                    },
+                    (Some(kept), None) | (None, Some(kept)) => {
+                        conflicts.push(conflict(ConflictKind::ModifyDelete));
+                        Some(kept.clone())
+                    },
+                    (None, None) => None
+                }
+            };
+            if let Some(entry) = resolved {
+                merged.insert(path.clone(), entry);
+            }
+        }
+
+        // a file whose path is now also a directory moves aside.
+        let files: Vec<Vec<u8>> = merged.keys().filter(|path| {
+            let mut prefix = path.to_vec();
+            prefix.push(b'/');
+            merged.range(prefix.clone()..).next().is_some_and(|(xs, _)| xs.starts_with(&prefix))
+        }).cloned().collect();
+        for path in files {
+            let entry = merged.remove(&path).unwrap();
+            let label = if ours.get(&path) == Some(&entry) { &self.options.ours_label } else { &self.options.theirs_label };
+            let mut moved = path.clone();
+            moved.push(b'~');
+            moved.extend_from_slice(label.as_bytes());
+            conflicts.push(Conflict {
+                path: path.clone(),
+                kind: ConflictKind::DirectoryFile,
+                base: base.get(&path).cloned(),
+                ours: ours.get(&path).cloned(),
+                theirs: theirs.get(&path).cloned()
+            });
+            merged.insert(moved, entry);
+        }
+
+        Ok((merged, conflicts))
+    }
+}
+
+// Merges the trees `ours` and `theirs` (or the trees of commits) against
+// `base`; None is the empty tree. Every object the result needs is handed
+// to `put`, which returns its id.
+pub fn merge_trees<S, F>(
+    storage_set: &StorageSet<S>,
+    base: Option<&Id>,
+    ours: &Id,
+    theirs: &Id,
+    options: &file::Options,
+    put: F
+) -> Result<TreeMerge>
+    where S: Queryable, F: FnMut(Type, Vec<u8>) -> Result<Id> {
+    let mut merger = Merger {
+        storage_set,
+        options,
+        put,
+        written: HashMap::new()
+    };
+    let base = match base {
+        Some(xs) => merger.entries(xs)?,
+        None => Entries::new()
+    };
+    let (ours, theirs) = (merger.entries(ours)?, merger.entries(theirs)?);
+    let (merged, conflicts) = merger.merge_entries(&base, &ours, &theirs)?;
+    Ok(TreeMerge {
+        tree: write_nested(&merged, &mut merger.put)?,
+        conflicts
+    })
+}
+
+// Merges two commits as git's "recursive" strategy does: with several best
+// common ancestors, they are first merged into a virtual ancestor.
+pub fn merge_commits<S, F>(
+    storage_set: &StorageSet<S>,
+    ours: &Id,
+    theirs: &Id,
+    options: &file::Options,
+    put: F
+) -> Result<TreeMerge>
+    where S: Queryable, F: FnMut(Type, Vec<u8>) -> Result<Id> {
+    let mut merger = Merger {
+        storage_set,
+        options,
+        put,
+        written: HashMap::new()
+    };
+    let (ours_entries, theirs_entries) = (merger.entries(ours)?, merger.entries(theirs)?);
+    let (merged, conflicts) = merger.recursive(
+        (std::slice::from_ref(ours), &ours_entries),
+        (std::slice::from_ref(theirs), &theirs_entries)
+    )?;
+    Ok(TreeMerge {
+        tree: write_nested(&merged, &mut merger.put)?,
+        conflicts
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::merge::file::Options;
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::stores::fs::{ self as gitfs, write_loose };
+    use crate::checkout::flatten;
+    use crate::objects::Object;
+    use crate::files;
+    use super::{ merge_bases, merge_commits, ConflictKind };
+
+    fn contents(dir: &std::path::Path, tree: &crate::id::Id, path: &str) -> Option<Vec<u8>> {
+        let storage_set = gitfs::from(dir).expect("failed to open storage");
+        let entries = flatten(&storage_set, tree).expect("failed to read tree");
+        let entry = entries.get(path.as_bytes())?;
+        match storage_set.get_and_load(&entry.id).expect("failed to read blob") {
+            Some(Object::Blob(blob)) => Some(blob.contents),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn merges_and_reports_conflicts() {
+        let dir = TempDir::new("merge-tree").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("base", files!["README" => "1\n2\n3\n4\n5\n", "same" => "a\n", "gone" => "x\n", "dir" => "file\n"])
+            .branch("theirs")
+            .commit("ours", files!["README" => "one\n2\n3\n4\n5\n", "same" => "ours\n", "added" => "ours\n", "gone" => "changed\n", "dir" => "changed\n"])
+            .checkout("theirs")
+            .remove("drop", &["gone", "dir"])
+            .commit("theirs", files!["README" => "1\n2\n3\n4\nfive\n", "same" => "theirs\n", "added" => "theirs\n", "new" => "n\n", "dir/inner" => "d\n"]);
+        let theirs = builder.tip().unwrap();
+        let builder = builder.checkout("master");
+        let ours = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let result = merge_commits(&storage_set, &ours, &theirs, &Options::default(), |typ, data| {
+            Ok(write_loose(dir.path(), typ, &data)?)
+        }).expect("failed to merge");
+
+        let kinds: Vec<_> = result.conflicts.iter().map(|xs| (String::from_utf8_lossy(&xs.path).into_owned(), xs.kind)).collect();
+        assert_eq!(kinds, vec![
+            (String::from("added"), ConflictKind::AddAdd),
+            (String::from("dir"), ConflictKind::ModifyDelete),
+            (String::from("gone"), ConflictKind::ModifyDelete),
+            (String::from("same"), ConflictKind::Content),
+            (String::from("dir"), ConflictKind::DirectoryFile)
+        ]);
+        assert_eq!(contents(dir.path(), &result.tree, "README").unwrap(), b"one\n2\n3\n4\nfive\n");
+        assert_eq!(contents(dir.path(), &result.tree, "gone").unwrap(), b"changed\n");
+        assert_eq!(contents(dir.path(), &result.tree, "new").unwrap(), b"n\n");
+        assert_eq!(contents(dir.path(), &result.tree, "dir/inner").unwrap(), b"d\n");
+        assert!(contents(dir.path(), &result.tree, "dir").is_none());
+        assert_eq!(contents(dir.path(), &result.tree, "dir~ours").unwrap(), b"changed\n");
+        assert_eq!(contents(dir.path(), &result.tree, "same").unwrap(), b"<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\n");
+    }
+
+    #[test]
+    fn criss_cross_merges_use_a_virtual_base() {
+        let dir = TempDir::new("merge-recursive").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("base", files!["README" => "1\n2\n3\n4\n5\n6\n7\n8\n9\n"])
+            .branch("other")
+            .commit("x1", files!["README" => "one\n2\n3\n4\n5\n6\n7\n8\n9\n"])
+            .branch("x1")
+            .checkout("other")
+            .commit("y1", files!["README" => "1\n2\n3\n4\n5\n6\n7\n8\nnine\n"])
+            .branch("y1")
+            .merge("y2", "x1")
+            .commit("y3", files!["later" => "y\n"]);
+        let theirs = builder.tip().unwrap();
+        let builder = builder.checkout("master").merge("x2", "y1");
+        let ours = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        assert_eq!(merge_bases(&storage_set, &ours, &theirs).expect("failed to find bases").len(), 2);
+        let result = merge_commits(&storage_set, &ours, &theirs, &Options::default(), |typ, data| {
+            Ok(write_loose(dir.path(), typ, &data)?)
+        }).expect("failed to merge");
+        assert!(result.is_clean(), "{:?}", result.conflicts);
+        assert_eq!(contents(dir.path(), &result.tree, "later").unwrap(), b"y\n");
+    }
+}
@@ -0,0 +1,176 @@
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Arc;
+
+use crate::objects::Type;
+
+// Hooks the object store calls as it works. Every method defaults to doing
+// nothing, so implementors only override what they export. Calls happen on
+// whatever thread is reading, hence Send + Sync.
+pub trait Metrics: Send + Sync {
+    // an object was returned from `StorageSet::get`
+    fn object_read(&self, _typ: Type, _bytes: u64) {}
+    fn cache_hit(&self) {}
+    fn cache_miss(&self) {}
+    // zlib output, loose and packed alike
+    fn bytes_inflated(&self, _bytes: u64) {}
+    fn pack_opened(&self) {}
+    // number of deltas applied to produce a packed object; 0 for a full object
+    fn delta_chain(&self, _length: usize) {}
+}
+
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+pub fn noop() -> Arc<dyn Metrics> {
+    Arc::new(NoMetrics)
+}
+
+// An in-process `Metrics` that keeps running totals.
+#[derive(Debug, Default)]
+pub struct Counters {
+    objects: [AtomicU64; 4],
+    object_bytes: AtomicU64,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    bytes_inflated: AtomicU64,
+    packs_opened: AtomicU64,
+    deltas_resolved: AtomicU64,
+    longest_delta_chain: AtomicU64
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub commits: u64,
+    pub trees: u64,
+    pub blobs: u64,
+    pub tags: u64,
+    pub object_bytes: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub bytes_inflated: u64,
+    pub packs_opened: u64,
+    pub deltas_resolved: u64,
+    pub longest_delta_chain: u64
+}
+
+fn slot(typ: Type) -> usize {
+    match typ {
+        Type::Commit => 0,
+        Type::Tree => 1,
+        Type::Blob => 2,
+        Type::Tag => 3
+    }
+}
+
+impl Counters {
+    pub fn new() -> Counters {
+        Counters::default()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let load = |xs: &AtomicU64| xs.load(Ordering::Relaxed);
+        Snapshot {
+            commits: load(&self.objects[0]),
+            trees: load(&self.objects[1]),
+            blobs: load(&self.objects[2]),
+            tags: load(&self.objects[3]),
+            object_bytes: load(&self.object_bytes),
+            cache_hits: load(&self.cache_hits),
+            cache_misses: load(&self.cache_misses),
+            bytes_inflated: load(&self.bytes_inflated),
+            packs_opened: load(&self.packs_opened),
+            deltas_resolved: load(&self.deltas_resolved),
+            longest_delta_chain: load(&self.longest_delta_chain)
+        }
+    }
+}
+
+impl Metrics for Counters {
+    fn object_read(&self, typ: Type, bytes: u64) {
+        self.objects[slot(typ)].fetch_add(1, Ordering::Relaxed);
+        self.object_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn bytes_inflated(&self, bytes: u64) {
+        self.bytes_inflated.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn pack_opened(&self) {
+        self.packs_opened.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn delta_chain(&self, length: usize) {
+        self.deltas_resolved.fetch_add(length as u64, Ordering::Relaxed);
+        self.longest_delta_chain.fetch_max(length as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::objects::Type;
+    use super::{ Counters, Metrics, Snapshot };
+
+    #[test]
+    fn counters_keep_running_totals() {
+        let counters = Counters::new();
+        counters.object_read(Type::Commit, 200);
+        counters.object_read(Type::Blob, 10);
+        counters.object_read(Type::Blob, 5);
+        counters.cache_miss();
+        counters.cache_hit();
+        counters.cache_hit();
+        counters.bytes_inflated(215);
+        counters.pack_opened();
+        counters.delta_chain(3);
+        counters.delta_chain(0);
+        counters.delta_chain(1);
+
+        assert_eq!(counters.snapshot(), Snapshot {
+            commits: 1,
+            trees: 0,
+            blobs: 2,
+            tags: 0,
+            object_bytes: 215,
+            cache_hits: 2,
+            cache_misses: 1,
+            bytes_inflated: 215,
+            packs_opened: 1,
+            deltas_resolved: 4,
+            longest_delta_chain: 3
+        });
+    }
+
+    #[test]
+    fn counters_add_up_across_threads() {
+        let counters = Arc::new(Counters::new());
+        let threads: Vec<_> = (0..4).map(|n| {
+            let counters = counters.clone();
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    counters.object_read(Type::Tree, 1);
+                }
+                counters.delta_chain(n);
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let snapshot = counters.snapshot();
+        assert_eq!(snapshot.trees, 4000);
+        assert_eq!(snapshot.object_bytes, 4000);
+        assert_eq!(snapshot.deltas_resolved, 6);
+        assert_eq!(snapshot.longest_delta_chain, 3);
+    }
+}
@@ -0,0 +1,67 @@
+use crate::errors::{ ErrorKind, Result };
+
+// A git namespace (GIT_NAMESPACE): one logical repository whose refs live
+// under `refs/namespaces/<name>/` while sharing the object store with every
+// other namespace. "a/b" nests, as `refs/namespaces/a/refs/namespaces/b/`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Namespace {
+    name: String,
+    prefix: String
+}
+
+impl Namespace {
+    pub fn new(name: &str) -> Result<Namespace> {
+        let components: Vec<&str> = name.split('/').collect();
+        let valid = components.iter().all(|xs| {
+            !xs.is_empty() && *xs != "." && *xs != ".." && !xs.ends_with(".lock") &&
+                !xs.contains(|c: char| c.is_control() || " ~^:?*[\\".contains(c))
+        });
+        if !valid {
+            return Err(ErrorKind::BadNamespace(String::from(name)).into())
+        }
+
+        let prefix = components.iter().map(|xs| format!("refs/namespaces/{}/", xs)).collect();
+        Ok(Namespace {
+            name: String::from(name),
+            prefix
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // "refs/namespaces/<name>/", with a trailing slash.
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    // The storage name of a ref as clients of the namespace see it:
+    // "refs/heads/x" becomes "refs/namespaces/<name>/refs/heads/x".
+    pub fn qualify(&self, name: &str) -> String {
+        format!("{}{}", self.prefix, name)
+    }
+
+    // The inverse of `qualify`; None for refs outside the namespace.
+    pub fn strip<'a>(&self, name: &'a str) -> Option<&'a str> {
+        name.strip_prefix(self.prefix.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Namespace;
+
+    #[test]
+    fn namespaces_nest_and_validate() {
+        let ns = Namespace::new("forks/alice").expect("valid namespace");
+        assert_eq!(ns.prefix(), "refs/namespaces/forks/refs/namespaces/alice/");
+        let qualified = ns.qualify("refs/heads/master");
+        assert_eq!(ns.strip(&qualified), Some("refs/heads/master"));
+        assert_eq!(ns.strip("refs/heads/master"), None);
+
+        for bad in &["", "a//b", "../x", "a b", "x.lock"] {
+            assert!(Namespace::new(bad).is_err(), "{:?} should be rejected", bad);
+        }
+    }
+}
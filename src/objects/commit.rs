@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::io::Write;
 
 use crate::identity::Identity;
 use crate::errors::Result;
@@ -25,6 +26,10 @@ impl Commit {
         }
     }
 
+    pub fn author(&self) -> Option<&Identity> {
+        self.author.as_ref()
+    }
+
     pub fn tree(&self) -> Option<Id> {
         let v = self.attributes.get(b"tree" as &[u8])?;
 
@@ -34,6 +39,16 @@ impl Commit {
         result.pop()
     }
 
+    // tags share this parser; "object" points at the tagged object.
+    pub fn object(&self) -> Option<Id> {
+        let v = self.attributes.get(b"object" as &[u8])?;
+
+        let mut result: Vec<Id> = v.iter().filter_map(|id_bytes| {
+            std::str::from_utf8(id_bytes).ok().and_then(|xs| xs.parse().ok())
+        }).collect();
+        result.pop()
+    }
+
     pub fn parents(&self) -> Option<Vec<Id>> {
         let v = self.attributes.get(b"parent" as &[u8])?;
         let result: Vec<Id> = v.iter().filter_map(|id_bytes| {
@@ -44,6 +59,27 @@ impl Commit {
 }
 
 impl Commit {
+    pub fn write<W: Write>(
+        output: &mut W,
+        tree: &Id,
+        parents: &[Id],
+        author: &Identity,
+        committer: &Identity,
+        message: &[u8]
+    ) -> Result<()> {
+        writeln!(output, "tree {}", tree)?;
+        for parent in parents {
+            writeln!(output, "parent {}", parent)?;
+        }
+        output.write_all(b"author ")?;
+        author.write(output)?;
+        output.write_all(b"\ncommitter ")?;
+        committer.write(output)?;
+        output.write_all(b"\n\n")?;
+        output.write_all(message)?;
+        Ok(())
+    }
+
     pub fn load<T: std::io::Read>(handle: &mut T) -> Result<Commit> {
         // attr SP value NL
         // NL
@@ -139,4 +175,20 @@ mod tests {
         let message = std::str::from_utf8(&commit.message).expect("not utf8");
         assert_eq!(message, "initial commit\n\n");
     }
+
+    #[test]
+    fn commit_write_roundtrips() {
+        let bytes = include_bytes!("../../fixtures/commit");
+        let commit = super::Commit::load(&mut bytes.as_ref()).expect("oh no");
+        let mut output = Vec::new();
+        super::Commit::write(
+            &mut output,
+            &commit.tree().expect("no tree"),
+            &commit.parents().unwrap_or_default(),
+            commit.author().expect("no author"),
+            commit.committer().expect("no committer"),
+            commit.message()
+        ).expect("failed to write");
+        assert_eq!(&output[..], &bytes[..]);
+    }
 }
@@ -1,5 +1,8 @@
+use crypto::{ sha1::Sha1, digest::Digest };
+
 use crate::pack::internal_type::PackfileType;
 use crate::errors::Result;
+use crate::id::Id;
 
 pub mod commit;
 pub mod blob;
@@ -53,6 +56,17 @@ impl std::convert::From<PackfileType> for Type {
     }
 }
 
+pub fn hash(typ: Type, data: &[u8]) -> Id {
+    let mut hash = Sha1::new();
+    let header = format!("{} {}\0", typ.as_str(), data.len());
+    hash.input(header.as_bytes());
+    hash.input(data);
+
+    let mut id_output = [0u8; 20];
+    hash.result(&mut id_output);
+    id_output.into()
+}
+
 impl Type {
     pub fn as_str(&self) -> &'static str {
         match self {
@@ -84,3 +98,17 @@ impl Type {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::id::Id;
+    use super::{ hash, Type };
+
+    #[test]
+    fn hash_works() {
+        assert_eq!(hash(Type::Blob, b""), Id::from_str("e69de29bb2d1d6434b8b29ae775ad8c2e48c5391").unwrap());
+        assert_eq!(hash(Type::Tree, b""), Id::from_str("4b825dc642cb6eb9a060e54bf8d69288fbee4904").unwrap());
+    }
+}
@@ -1,25 +1,123 @@
 use std::collections::BTreeMap;
+use std::io::Write;
 use crate::errors::Result;
+use crate::objects::Type;
 use crate::id::Id;
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub struct FileMode(u32);
 
-#[derive(Debug)]
+impl FileMode {
+    pub const FILE: FileMode = FileMode(0o100644);
+    pub const EXECUTABLE: FileMode = FileMode(0o100755);
+    pub const SYMLINK: FileMode = FileMode(0o120000);
+    pub const TREE: FileMode = FileMode(0o040000);
+    pub const GITLINK: FileMode = FileMode(0o160000);
+
+    pub fn is_tree(&self) -> bool {
+        self.0 & 0o170000 == 0o040000
+    }
+
+    pub fn is_gitlink(&self) -> bool {
+        self.0 & 0o170000 == 0o160000
+    }
+
+    // Parses the octal form used in trees and diff headers ("100644").
+    pub fn from_octal(octal: &str) -> Option<FileMode> {
+        u32::from_str_radix(octal, 8).ok().map(FileMode)
+    }
+
+    pub fn from_bits(bits: u32) -> FileMode {
+        FileMode(bits)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct TreeEntry {
     pub mode: FileMode,
     pub id: Id
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Tree {
     entries: BTreeMap<Vec<u8>, TreeEntry>
 }
 
 impl Tree {
+    pub fn new() -> Tree {
+        Tree::default()
+    }
+
     pub fn entries (&self) -> &BTreeMap<Vec<u8>, TreeEntry> {
         &self.entries
     }
+
+    pub fn insert(&mut self, name: Vec<u8>, entry: TreeEntry) {
+        self.entries.insert(name, entry);
+    }
+
+    pub fn write<W: Write>(&self, output: &mut W) -> Result<()> {
+        // git orders entries as though subtrees had a trailing slash.
+        let mut sorted: Vec<(&Vec<u8>, &TreeEntry)> = self.entries.iter().collect();
+        sorted.sort_by(|(lhs_name, lhs), (rhs_name, rhs)| {
+            let lhs_key = lhs_name.iter().chain(if lhs.mode.is_tree() { &b"/"[..] } else { &b""[..] });
+            let rhs_key = rhs_name.iter().chain(if rhs.mode.is_tree() { &b"/"[..] } else { &b""[..] });
+            lhs_key.cmp(rhs_key)
+        });
+
+        for (name, entry) in sorted {
+            write!(output, "{:o} ", entry.mode.0)?;
+            output.write_all(name)?;
+            output.write_all(b"\0")?;
+            output.write_all(entry.id.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+// Writes the nested trees for a flat `path -> entry` map, such as the one
+// checkout builds, and returns the root tree's id. `put` stores a single
+// object.
+pub fn write_nested<F>(entries: &BTreeMap<Vec<u8>, TreeEntry>, put: &mut F) -> Result<Id>
+    where F: FnMut(Type, Vec<u8>) -> Result<Id> {
+    let flat: Vec<(&[u8], &TreeEntry)> = entries.iter().map(|(name, entry)| (name.as_slice(), entry)).collect();
+    write_level(&flat, put)
+}
+
+fn write_level<F>(entries: &[(&[u8], &TreeEntry)], put: &mut F) -> Result<Id>
+    where F: FnMut(Type, Vec<u8>) -> Result<Id> {
+    let mut tree = Tree::new();
+    let mut idx = 0;
+    while idx < entries.len() {
+        let (entry_path, entry) = entries[idx];
+        let slash = match entry_path.iter().position(|xs| *xs == b'/') {
+            Some(xs) => xs,
+            None => {
+                tree.insert(entry_path.to_vec(), entry.clone());
+                idx += 1;
+                continue
+            }
+        };
+
+        // paths sharing a "dir/" prefix are contiguous in byte order.
+        let prefix = &entry_path[..=slash];
+        let mut children = Vec::new();
+        while idx < entries.len() && entries[idx].0.starts_with(prefix) {
+            children.push((&entries[idx].0[slash + 1..], entries[idx].1));
+            idx += 1;
+        }
+
+        let id = write_level(&children, put)?;
+        tree.insert(entry_path[..slash].to_vec(), TreeEntry { mode: FileMode::TREE, id });
+    }
+
+    let mut data = Vec::new();
+    tree.write(&mut data)?;
+    put(Type::Tree, data)
 }
 
 impl IntoIterator for Tree {
@@ -109,6 +207,25 @@ mod tests {
         assert_eq!(tree_entry.mode, FileMode(0o40000));
     }
 
+    #[test]
+    fn tree_write_roundtrips() {
+        let bytes = include_bytes!("../../fixtures/tree_1");
+        let tree = super::Tree::load(&mut bytes.as_ref()).expect("oh no");
+        let mut output = Vec::new();
+        tree.write(&mut output).expect("failed to write");
+        assert_eq!(&output[..], &bytes[..]);
+    }
+
+    #[test]
+    fn tree_write_sorts_subtrees_with_slash() {
+        let mut tree = super::Tree::new();
+        tree.insert(b"foo".to_vec(), super::TreeEntry { mode: FileMode::TREE, id: Id::default() });
+        tree.insert(b"foo.rs".to_vec(), super::TreeEntry { mode: FileMode::FILE, id: Id::default() });
+        let mut output = Vec::new();
+        tree.write(&mut output).expect("failed to write");
+        assert!(output.starts_with(b"100644 foo.rs\0"));
+    }
+
     #[test]
     fn tree_complex_read_works() {
         let bytes = include_bytes!("../../fixtures/tree_1");
@@ -136,4 +253,36 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn write_nested_works() {
+        use std::collections::BTreeMap;
+        use crate::stores::memory::Store as MemoryStore;
+        use crate::stores::StorageSet;
+        use crate::objects::Object;
+
+        let blob = Id::from(&[1u8; 20]);
+        let mut entries = BTreeMap::new();
+        for name in &["README", "src/lib.rs", "src/objects/mod.rs", "src.rs"] {
+            entries.insert(name.as_bytes().to_vec(), super::TreeEntry { mode: FileMode::FILE, id: blob.clone() });
+        }
+
+        let mut store = MemoryStore::new();
+        let root = super::write_nested(&entries, &mut |typ, data| Ok(store.put(typ, data))).expect("failed to write");
+        let storage_set = StorageSet::new(store);
+
+        let tree = match storage_set.get_and_load(&root).expect("failed to read") {
+            Some(Object::Tree(xs)) => xs,
+            _ => panic!("expected a tree")
+        };
+        let names: Vec<&[u8]> = tree.entries.keys().map(|xs| xs.as_slice()).collect();
+        assert_eq!(names, vec![&b"README"[..], &b"src"[..], &b"src.rs"[..]]);
+
+        let src = tree.entries.get(&b"src"[..]).unwrap();
+        assert!(src.mode.is_tree());
+        match storage_set.get_and_load(&src.id).expect("failed to read") {
+            Some(Object::Tree(xs)) => assert_eq!(xs.entries.len(), 2),
+            _ => panic!("expected a tree")
+        }
+    }
 }
@@ -4,6 +4,7 @@ use std;
 
 use crate::stores::{ Queryable, StorageSet };
 use crate::pack::read::packfile_read;
+use crate::pack::cache::DeltaCache;
 use crate::errors::Result;
 use crate::pack::Packfile;
 use crate::objects::Type;
@@ -31,18 +32,22 @@ impl<R: Read + Seek + std::fmt::Debug> Packfile for Reader<R> {
         start: u64,
         end: u64,
         output: &mut W,
-        backends: &StorageSet<S>
+        backends: &StorageSet<S>,
+        bases: &DeltaCache
     ) -> Result<Type> {
         let handle = (self.read)()?;
         let mut buffered_file = BufReader::new(handle);
         buffered_file.seek(SeekFrom::Start(start))?;
 
-        let packfile_type = packfile_read(&mut buffered_file, output, &mut 0)?;
-        let obj_type = packfile_type.decompress(
+        let mut inflated = 0;
+        let packfile_type = packfile_read(&mut buffered_file, output, &mut 0, &mut inflated)?;
+        backends.metrics().bytes_inflated(inflated);
+        let obj_type = packfile_type.decompress_cached(
             start,
             &mut buffered_file,
             output,
-            Some(backends)
+            Some(backends),
+            bases
         )?;
         Ok(obj_type)
     }
@@ -0,0 +1,122 @@
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::{ Arc, Mutex };
+
+use lru::LruCache;
+
+use crate::objects::Type;
+
+// git's default core.deltaBaseCacheLimit.
+pub const DEFAULT_LIMIT: usize = 96 * 1024 * 1024;
+
+struct Entries {
+    objects: LruCache<(usize, u64), (Type, Vec<u8>)>,
+    bytes: usize
+}
+
+struct Shared {
+    entries: Mutex<Entries>,
+    limit: usize,
+    packs: AtomicUsize
+}
+
+// Inflated delta bases of one pack by offset, so that the objects sharing a
+// base (most versions of a file, in a typical pack) don't each inflate the
+// whole chain again. The least recently used are dropped past `limit` bytes.
+// Caches made with `share` hold other packs' bases under the same limit, as
+// core.deltaBaseCacheLimit bounds all of a repository's packs together.
+pub struct DeltaCache {
+    shared: Arc<Shared>,
+    pack: usize
+}
+
+impl DeltaCache {
+    pub fn new(limit: usize) -> DeltaCache {
+        DeltaCache {
+            shared: Arc::new(Shared {
+                entries: Mutex::new(Entries {
+                    objects: LruCache::unbounded(),
+                    bytes: 0
+                }),
+                limit,
+                packs: AtomicUsize::new(1)
+            }),
+            pack: 0
+        }
+    }
+
+    // A cache for another pack, sharing this one's entries and limit.
+    pub fn share(&self) -> DeltaCache {
+        DeltaCache {
+            shared: self.shared.clone(),
+            pack: self.shared.packs.fetch_add(1, Ordering::Relaxed)
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.shared.limit
+    }
+
+    pub fn get(&self, offset: u64) -> Option<(Type, Vec<u8>)> {
+        self.shared.entries.lock().unwrap().objects.get(&(self.pack, offset)).cloned()
+    }
+
+    pub fn put(&self, offset: u64, typ: Type, data: &[u8]) {
+        let limit = self.shared.limit;
+        if data.len() > limit {
+            return
+        }
+        let mut entries = self.shared.entries.lock().unwrap();
+        entries.bytes += data.len();
+        if let Some((_, old)) = entries.objects.put((self.pack, offset), (typ, data.to_vec())) {
+            entries.bytes -= old.len();
+        }
+        while entries.bytes > limit {
+            match entries.objects.pop_lru() {
+                Some((_, (_, xs))) => entries.bytes -= xs.len(),
+                None => break
+            }
+        }
+    }
+}
+
+impl Default for DeltaCache {
+    fn default() -> DeltaCache {
+        DeltaCache::new(DEFAULT_LIMIT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::objects::Type;
+    use super::DeltaCache;
+
+    #[test]
+    fn evicts_past_the_byte_limit() {
+        let cache = DeltaCache::new(8);
+        cache.put(12, Type::Blob, b"abcd");
+        cache.put(40, Type::Blob, b"efgh");
+        assert!(cache.get(12).is_some());
+        cache.put(80, Type::Tree, b"ijkl");
+        // 40 was used least recently.
+        assert!(cache.get(40).is_none());
+        assert_eq!(cache.get(12).map(|(_, xs)| xs), Some(b"abcd".to_vec()));
+        assert!(matches!(cache.get(80), Some((Type::Tree, _))));
+        cache.put(90, Type::Blob, b"too large to keep");
+        assert!(cache.get(90).is_none());
+    }
+
+    #[test]
+    fn shared_caches_keep_packs_apart_under_one_limit() {
+        let first = DeltaCache::new(8);
+        let second = first.share();
+        first.put(12, Type::Blob, b"abcd");
+        assert!(second.get(12).is_none());
+        second.put(12, Type::Blob, b"efgh");
+        assert_eq!(first.get(12).map(|(_, xs)| xs), Some(b"abcd".to_vec()));
+        assert_eq!(second.get(12).map(|(_, xs)| xs), Some(b"efgh".to_vec()));
+        // a third base pushes out the least recently used, whichever pack's.
+        second.put(40, Type::Blob, b"ijkl");
+        assert!(first.get(12).is_none());
+        assert!(second.get(40).is_some());
+    }
+}
@@ -4,159 +4,391 @@ use byteorder::{ BigEndian, ReadBytesExt };
 use std::io::{ Cursor, SeekFrom };
 use std::io::prelude::*;
 use rayon::prelude::*;
+use rayon::{ Scope, ThreadPool, ThreadPoolBuilder };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::sync::Mutex;
 use std::fmt::Debug;
 
 use crate::stores::{ StorageSet, Queryable };
-use crate::errors::{ ErrorKind, Result };
+use crate::errors::{ Context, ErrorKind, Result };
+use crate::pack::internal_type::PackfileType;
 use crate::pack::iter::PackfileIterator;
+use crate::objects::{ self, Type };
+use crate::progress::{ NoProgress, Progress };
+use crate::pack::cache::DeltaCache;
+use crate::cancel::Token;
 use crate::id::Id;
 
+// How many bytes of inflated objects are hashed as one job under
+// `Indexer::parallel_hashing`.
+const HASH_BATCH_BYTES: usize = 32 << 20;
+
 pub fn write<R, W, S>(
-    mut input: R,
+    input: R,
     output: &mut W,
     storage_set: Option<&StorageSet<S>>
 ) -> Result<()> where
     R: BufRead + Seek + Clone + Debug + Sync,
     W: Write,
     S: Queryable + Sync {
+    Indexer::new().write(input, output, storage_set)
+}
 
-    let len = input.seek(SeekFrom::End(0))?;
-    input.seek(SeekFrom::Start(0))?;
+// Writes the index of a pack, as `git index-pack` does. Deltas are resolved
+// in parallel, the threads sharing one cache of inflated bases so a base
+// many deltas build on is inflated once. The pack's trailing checksum is
+// checked before anything is written.
+pub struct Indexer<'a> {
+    progress: &'a dyn Progress,
+    cancel: Option<&'a Token>,
+    threads: Option<usize>,
+    parallel_hashing: bool
+}
+
+impl<'a> Default for Indexer<'a> {
+    fn default() -> Indexer<'a> {
+        Indexer {
+            progress: &NoProgress,
+            cancel: None,
+            threads: None,
+            parallel_hashing: false
+        }
+    }
+}
 
-    let iter = PackfileIterator::new(input.clone(), storage_set).expect("failed to parse as packfile");
-    let mut offsets = Vec::with_capacity(4096);
+impl<'a> Indexer<'a> {
+    pub fn new() -> Indexer<'a> {
+        Indexer::default()
+    }
 
-    // first pass: find all offsets and non-delta'd ids
-    let objects: Vec<_> = iter.map(|(offset, pf_type, id)| {
-        offsets.push(offset.clone());
-        (offset, pf_type, id)
-    }).collect();
-    offsets.push(len - 20);
-
-    // second pass: calculate crcs between offsets
-    let windows: Vec<_> = offsets.windows(2).collect();
-    let crcs: Vec<_> = windows.par_iter().filter_map(|offset| {
-        let mut digest = CRCDigest::new(crc32::IEEE);
-
-        let mut cursor = input.clone();
-        cursor.seek(SeekFrom::Start(offset[0])).ok()?;
-        let mut input_bytes = Vec::with_capacity((offset[1] - offset[0]) as usize);
-        cursor.take(offset[1] - offset[0]).read_to_end(&mut input_bytes).ok()?;
-        digest.write(&input_bytes);
-        Some(digest.sum32())
-    }).collect();
+    // Reports "Indexing objects" and "Resolving deltas".
+    pub fn progress(mut self, progress: &'a dyn Progress) -> Indexer<'a> {
+        self.progress = progress;
+        self
+    }
 
-    if crcs.len() != objects.len() {
-        return Err(ErrorKind::CorruptedPackfile.into());
+    // Stops early, failing with `ErrorKind::Interrupted`, once `cancel` is
+    // cancelled.
+    pub fn cancel(mut self, cancel: &'a Token) -> Indexer<'a> {
+        self.cancel = Some(cancel);
+        self
     }
 
-    // third pass: calculate delta reprs
-    let mut decompressed: Vec<_> = objects
-                        .into_par_iter()
-                        .enumerate()
-                        .filter_map(|(idx, (offset, pf_type, id))| {
-        if id.is_some() {
-            return Some((idx, offset, id.unwrap()))
+    // How many threads resolve deltas (--threads); rayon's global pool
+    // otherwise.
+    pub fn threads(mut self, threads: usize) -> Indexer<'a> {
+        self.threads = Some(threads);
+        self
+    }
+
+    // Hashes non-delta objects on the pool in batches while the pack is
+    // still being inflated, instead of one by one as they are read, and
+    // checks the pack's checksum on a thread of its own alongside.
+    pub fn parallel_hashing(mut self, parallel_hashing: bool) -> Indexer<'a> {
+        self.parallel_hashing = parallel_hashing;
+        self
+    }
+
+    pub fn write<R, W, S>(
+        &self,
+        mut input: R,
+        output: &mut W,
+        storage_set: Option<&StorageSet<S>>
+    ) -> Result<()> where
+        R: BufRead + Seek + Clone + Debug + Sync,
+        W: Write,
+        S: Queryable + Sync {
+
+        let len = input.seek(SeekFrom::End(0))?;
+        input.seek(SeekFrom::Start(0))?;
+        if len < 32 {
+            return Err(ErrorKind::CorruptedPackData(Context::new("indexing pack").offset(len).into(), "pack is too short").into())
         }
+        let pool = match self.threads {
+            Some(threads) => Some(ThreadPoolBuilder::new().num_threads(threads).build().map_err(std::io::Error::other)?),
+            None => None
+        };
 
-        let mut input = input.clone();
+        std::thread::scope(|scope| {
+            let checked = if self.parallel_hashing {
+                Some(scope.spawn(|| checksums(&input, len)))
+            } else {
+                None
+            };
+            let index = self.build(&input, len, pool.as_ref(), storage_set);
+            let (expected, actual) = match checked {
+                Some(xs) => xs.join().expect("checksum thread panicked")?,
+                None => checksums(&input, len)?
+            };
+            let index = index?;
+            if expected != actual {
+                let context = Context::new("indexing pack").offset(len - 20);
+                return Err(ErrorKind::PackChecksumMismatch(context.into(), expected, actual).into())
+            }
+            output.write_all(&index)?;
+            Ok(())
+        })
+    }
+
+    fn build<R, S>(
+        &self,
+        input: &R,
+        len: u64,
+        pool: Option<&ThreadPool>,
+        storage_set: Option<&StorageSet<S>>
+    ) -> Result<Vec<u8>> where
+        R: BufRead + Seek + Clone + Debug + Sync,
+        S: Queryable + Sync {
+
+        let progress = self.progress;
+        let never = Token::new();
+        let cancel = self.cancel.unwrap_or(&never);
         let mut output = Vec::new();
-        let object_type = pf_type.decompress(
-            offset,
-            &mut input,
-            &mut output,
-            storage_set
-        ).ok()?;
-        let mut hash = Sha1::new();
-        let header = format!("{} {}\0", object_type.as_str(), output.len());
-        hash.input(header.as_bytes());
-        hash.input(&output[..]);
-        let mut id_output = [0u8; 20];
-        hash.result(&mut id_output);
-        Some((idx, offset, Id::from(&id_output[..])))
-    }).collect();
 
-    // sort the results by id hash (instead of offset order)
-    decompressed.par_sort_unstable_by(|lhs, rhs| {
-        lhs.2.cmp(&rhs.2)
-    });
+        let mut header = [0u8; 12];
+        input.clone().read_exact(&mut header)?;
+        let total = (&header[8..]).read_u32::<BigEndian>()?;
 
-    let mut fanout = [0u32; 256]; // each value in fanout holds the upper bound index of the object starting with the incoming byte
-    let mut byte = 0u8;
-    fanout[0xff] = (decompressed.len() as u32).to_be();
-
-    let mut offsets = Vec::with_capacity(decompressed.len());
-    let mut large_offsets = Vec::new();
-    let mut crcs_out = Vec::with_capacity(decompressed.len());
-    let mut ids = Vec::with_capacity(decompressed.len());
-    for (idx, (crc_idx, offset, id)) in decompressed.into_iter().enumerate() {
-        if byte != id.as_ref()[0] {
-            fanout[byte as usize] = (idx as u32).to_be();
-            byte += 1;
+        let mut iter = PackfileIterator::new(input.clone(), storage_set)?;
+        if self.parallel_hashing {
+            iter = iter.without_ids();
         }
+        let mut offsets = Vec::with_capacity(4096);
+
+        // first pass: find all offsets and non-delta'd ids
+        progress.start("Indexing objects", Some(u64::from(total)));
+        let mut objects = Vec::new();
+        let hashed: Hashed = Mutex::new(Vec::new());
+        in_place_scope(pool, |scope| -> Result<()> {
+            let mut batch = Vec::new();
+            let mut batch_bytes = 0;
+            while let Some(item) = iter.next() {
+                let (offset, pf_type, id) = item?;
+                cancel.check()?;
+                offsets.push(offset);
+                progress.update(offsets.len() as u64, offset);
+                if let (PackfileType::Plain(ident), true) = (&pf_type, self.parallel_hashing) {
+                    let typ: Type = PackfileType::Plain(*ident).into();
+                    batch_bytes += iter.data().len();
+                    batch.push((objects.len(), typ, iter.data().to_vec()));
+                }
+                objects.push((offset, pf_type, id));
+                if batch_bytes >= HASH_BATCH_BYTES {
+                    spawn_hashing(scope, &hashed, std::mem::take(&mut batch));
+                    batch_bytes = 0;
+                }
+            }
+            spawn_hashing(scope, &hashed, batch);
+            Ok(())
+        })?;
+        for (idx, id) in hashed.into_inner().expect("hashing thread panicked") {
+            objects[idx].2 = Some(id);
+        }
+        // the header's count, and objects up to the trailer: nothing more or
+        // less.
+        if objects.len() as u64 != u64::from(total) {
+            let context = Context::new("indexing pack").offset(iter.offset());
+            return Err(ErrorKind::CorruptedPackData(context.into(), "pack has fewer objects than its header says").into())
+        }
+        if iter.offset() != len - 20 {
+            let context = Context::new("indexing pack").offset(iter.offset());
+            return Err(ErrorKind::CorruptedPackData(context.into(), "pack has data after its last object").into())
+        }
+        offsets.push(len - 20);
+        progress.update(objects.len() as u64, len);
+        progress.finish();
+
+        // second pass: calculate crcs between offsets
+        let windows: Vec<_> = offsets.windows(2).collect();
+        let crcs: Vec<_> = windows.par_iter().map(|offset| {
+            let mut digest = CRCDigest::new(crc32::IEEE);
+
+            let mut cursor = input.clone();
+            cursor.seek(SeekFrom::Start(offset[0])).ok()?;
+            let mut input_bytes = Vec::with_capacity((offset[1] - offset[0]) as usize);
+            cursor.take(offset[1] - offset[0]).read_to_end(&mut input_bytes).ok()?;
+            digest.write(&input_bytes);
+            Some(digest.sum32())
+        }).collect();
+
+        if let Some(idx) = crcs.iter().position(Option::is_none) {
+            let context = Context::new("indexing pack").offset(offsets[idx]);
+            return Err(ErrorKind::CorruptedPackData(context.into(), "object is truncated").into())
+        }
+        let crcs: Vec<u32> = crcs.into_iter().flatten().collect();
+
+        // third pass: calculate delta reprs
+        let deltas = objects.iter().filter(|(_, _, id)| id.is_none()).count();
+        let resolved = AtomicU64::new(0);
+        progress.start("Resolving deltas", Some(deltas as u64));
+        let bases = DeltaCache::default();
+        let resolve = || -> Result<Vec<_>> {
+            objects.into_par_iter().enumerate().map(|(idx, (offset, pf_type, id))| {
+                if let Some(id) = id {
+                    return Ok((idx, offset, id))
+                }
+                cancel.check()?;
+
+                let mut input = input.clone();
+                let mut output = Vec::new();
+                let object_type = pf_type.decompress_cached(
+                    offset,
+                    &mut input,
+                    &mut output,
+                    storage_set,
+                    &bases
+                ).map_err(|xs| xs.within(Context::new("resolving deltas").offset(offset)))?;
+                progress.update(resolved.fetch_add(1, Ordering::Relaxed) + 1, 0);
+                let mut hash = Sha1::new();
+                let header = format!("{} {}\0", object_type.as_str(), output.len());
+                hash.input(header.as_bytes());
+                hash.input(&output[..]);
+                let mut id_output = [0u8; 20];
+                hash.result(&mut id_output);
+                Ok((idx, offset, Id::from(&id_output[..])))
+            }).collect()
+        };
+        let mut decompressed = match pool {
+            Some(pool) => pool.install(resolve),
+            None => resolve()
+        }?;
+
+        progress.finish();
+
+        // sort the results by id hash (instead of offset order)
+        decompressed.par_sort_unstable_by(|lhs, rhs| {
+            lhs.2.cmp(&rhs.2)
+        });
+
+        let mut fanout = [0u32; 256]; // each value in fanout holds the upper bound index of the object starting with the incoming byte
+        let mut byte = 0u8;
+        fanout[0xff] = (decompressed.len() as u32).to_be();
+
+        let mut offsets = Vec::with_capacity(decompressed.len());
+        let mut large_offsets = Vec::new();
+        let mut crcs_out = Vec::with_capacity(decompressed.len());
+        let mut ids = Vec::with_capacity(decompressed.len());
+        for (idx, (crc_idx, offset, id)) in decompressed.into_iter().enumerate() {
+            // fanout[b] counts the ids whose first byte is at most b.
+            while byte != id.as_ref()[0] {
+                fanout[byte as usize] = (idx as u32).to_be();
+                byte += 1;
+            }
 
-        ids.push(id);
+            ids.push(id);
 
-        if offset > 0x7fff_ffff {
-            offsets.push((large_offsets.len() as u32 & 0x8000_0000).to_be());
-            large_offsets.push(offset.to_be());
-        } else {
-            offsets.push((offset as u32).to_be());
+            if offset > 0x7fff_ffff {
+                offsets.push((large_offsets.len() as u32 | 0x8000_0000).to_be());
+                large_offsets.push(offset.to_be());
+            } else {
+                offsets.push((offset as u32).to_be());
+            }
+
+            crcs_out.push(crcs[crc_idx].to_be());
+        }
+        while byte != 0xff {
+            fanout[byte as usize] = (ids.len() as u32).to_be();
+            byte += 1;
         }
 
-        crcs_out.push(crcs[crc_idx].to_be());
-    }
+        let mut shasum = Sha1::new();
 
-    let mut shasum = Sha1::new();
+        let magic_byte = b"\xfftOc";
+        shasum.input(magic_byte);
+        output.write(magic_byte)?;
 
-    let magic_byte = b"\xfftOc";
-    shasum.input(magic_byte);
-    output.write(magic_byte)?;
+        let version_bytes = unsafe { std::mem::transmute::<u32, [u8; 4]>(2u32.to_be()) };
+        shasum.input(&version_bytes);
+        output.write(&version_bytes)?;
 
-    let version_bytes = unsafe { std::mem::transmute::<u32, [u8; 4]>(2u32.to_be()) };
-    shasum.input(&version_bytes);
-    output.write(&version_bytes)?;
+        let fanout_bytes = unsafe { std::mem::transmute::<[u32; 256], [u8; 256 * 4]>(fanout) };
+        shasum.input(&fanout_bytes);
+        output.write(&fanout_bytes)?;
 
-    let fanout_bytes = unsafe { std::mem::transmute::<[u32; 256], [u8; 256 * 4]>(fanout) };
-    shasum.input(&fanout_bytes);
-    output.write(&fanout_bytes)?;
+        for id in ids {
+            let id_bytes = id.as_ref();
+            shasum.input(id_bytes);
+            output.write(id_bytes)?;
+        }
 
-    for id in ids {
-        let id_bytes = id.as_ref();
-        shasum.input(id_bytes);
-        output.write(id_bytes)?;
-    }
+        for crc in crcs_out {
+            let crc_bytes = unsafe { std::mem::transmute::<u32, [u8; 4]>(crc) };
+            shasum.input(&crc_bytes);
+            output.write(&crc_bytes)?;
+        }
 
-    for crc in crcs_out {
-        let crc_bytes = unsafe { std::mem::transmute::<u32, [u8; 4]>(crc) };
-        shasum.input(&crc_bytes);
-        output.write(&crc_bytes)?;
-    }
+        for offset in offsets {
+            let offset_bytes = unsafe { std::mem::transmute::<u32, [u8; 4]>(offset) };
+            shasum.input(&offset_bytes);
+            output.write(&offset_bytes)?;
+        }
 
-    for offset in offsets {
-        let offset_bytes = unsafe { std::mem::transmute::<u32, [u8; 4]>(offset) };
-        shasum.input(&offset_bytes);
-        output.write(&offset_bytes)?;
-    }
+        for large_offset in large_offsets {
+            let large_offset_bytes = unsafe { std::mem::transmute::<u64, [u8; 8]>(large_offset) };
+            shasum.input(&large_offset_bytes);
+            output.write(&large_offset_bytes)?;
+        }
+
+        let mut input = input.clone();
+        input.seek(SeekFrom::End(-20))?;
+        let mut packfile_checksum_bytes = Vec::with_capacity(20);
 
-    for large_offset in large_offsets {
-        let large_offset_bytes = unsafe { std::mem::transmute::<u64, [u8; 8]>(large_offset) };
-        shasum.input(&large_offset_bytes);
-        output.write(&large_offset_bytes)?;
+        input.read_to_end(&mut packfile_checksum_bytes)?;
+        shasum.input(&packfile_checksum_bytes);
+        output.write(&packfile_checksum_bytes)?;
+
+        let mut checksum = [0u8; 20];
+        shasum.result(&mut checksum);
+        output.write(&checksum)?;
+
+        Ok(output)
     }
+}
 
-    input.seek(SeekFrom::End(-20))?;
-    let mut packfile_checksum_bytes = Vec::with_capacity(20);
+// A scope spawning onto `pool`, or rayon's global pool without one.
+fn in_place_scope<'a, F, T>(pool: Option<&ThreadPool>, op: F) -> T where F: FnOnce(&Scope<'a>) -> T {
+    match pool {
+        Some(pool) => pool.in_place_scope(op),
+        None => rayon::in_place_scope(op)
+    }
+}
 
-    input.read_to_end(&mut packfile_checksum_bytes)?;
-    shasum.input(&packfile_checksum_bytes);
-    output.write(&packfile_checksum_bytes)?;
+type Hashed = Mutex<Vec<(usize, Id)>>;
 
-    let mut checksum = [0u8; 20];
-    shasum.result(&mut checksum);
-    output.write(&checksum)?;
+// Hashes a batch of (object number, type, contents) on the scope's pool
+// while the caller reads on.
+fn spawn_hashing<'a>(scope: &Scope<'a>, hashed: &'a Hashed, batch: Vec<(usize, Type, Vec<u8>)>) {
+    if batch.is_empty() {
+        return
+    }
+    scope.spawn(move |_| {
+        let ids: Vec<_> = batch.into_par_iter().map(|(idx, typ, data)| (idx, objects::hash(typ, &data))).collect();
+        hashed.lock().expect("hashing thread panicked").extend(ids);
+    });
+}
 
-    Ok(())
+// The SHA-1 trailing a pack and that of everything before it, which should
+// be the same. One stream of SHA-1 can't be split across threads, so this is
+// overlapped with indexing rather than chunked.
+fn checksums<R: Read + Seek + Clone>(input: &R, len: u64) -> Result<(Id, Id)> {
+    let mut input = input.clone();
+    input.seek(SeekFrom::Start(0))?;
+    let mut shasum = Sha1::new();
+    let mut buffer = vec![0u8; 1 << 16];
+    let mut left = len - 20;
+    while left > 0 {
+        let read = input.read(&mut buffer[..left.min(1 << 16) as usize])?;
+        if read == 0 {
+            return Err(ErrorKind::CorruptedPackData(Context::new("checking pack checksum").offset(len - left).into(), "pack is truncated").into())
+        }
+        shasum.input(&buffer[..read]);
+        left -= read as u64;
+    }
+    let mut expected = [0u8; 20];
+    input.read_exact(&mut expected)?;
+    let mut actual = [0u8; 20];
+    shasum.result(&mut actual);
+    Ok((Id::from(&expected[..]), Id::from(&actual[..])))
 }
 
 pub fn read<R: Read>(mut input: R) -> Result<Index> {
@@ -213,7 +445,9 @@ pub fn read<R: Read>(mut input: R) -> Result<Index> {
     let mut offset_idx_sorted: Vec<(usize, &u64)> = offsets.iter().enumerate().collect();
     offset_idx_sorted.sort_by_key(|(_, offset)| *offset);
 
-    let mut next_offsets_indices = vec![0; offset_idx_sorted.len()];
+    // the object at the highest offset runs up to the trailer; it is its own
+    // "next".
+    let mut next_offsets_indices: Vec<usize> = (0..offset_idx_sorted.len()).collect();
     let mut idx = 0;
     while idx < offset_idx_sorted.len() - 1 {
         next_offsets_indices[offset_idx_sorted[idx].0] = offset_idx_sorted[idx + 1].0;
@@ -238,6 +472,14 @@ pub struct Index {
 }
 
 impl Index {
+    pub fn ids(&self) -> &[Id] {
+        &self.ids
+    }
+
+    pub fn contains(&self, id: &Id) -> bool {
+        self.get_bounds(id).is_some()
+    }
+
     pub fn get_bounds (&self, id: &Id) -> Option<(u64, u64)> {
         let as_bytes: &[u8] = id.as_ref();
         let mut lo = if as_bytes[0] > 0 {
@@ -263,10 +505,9 @@ impl Index {
                         lo = (middle + 1) as u32;
                     },
                     std::cmp::Ordering::Equal => {
-                        return Some((
-                            self.offsets[middle],
-                            self.offsets[self.next_offsets_indices[middle]]
-                        ));
+                        let next = self.next_offsets_indices[middle];
+                        let end = if next == middle { u64::MAX } else { self.offsets[next] };
+                        return Some((self.offsets[middle], end));
                     }
                 },
                 None => return None
@@ -280,3 +521,162 @@ impl Index {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::stores::StorageSet;
+    use crate::errors::ErrorKind;
+    use super::{ read, Indexer };
+
+    #[test]
+    fn indexes_match_git_on_any_thread_count() {
+        let packfile = include_bytes!("../../fixtures/packfile");
+        let expected = read(&include_bytes!("../../fixtures/pack_index")[..]).expect("failed to read index");
+
+        for threads in &[1, 4] {
+            let mut output = Vec::new();
+            Indexer::new().threads(*threads)
+                .write(Cursor::new(&packfile[..]), &mut output, None::<&StorageSet<()>>)
+                .expect("failed to index");
+            let index = read(&output[..]).expect("failed to read index");
+            assert_eq!(index.ids(), expected.ids());
+            for id in expected.ids() {
+                assert_eq!(index.get_bounds(id), expected.get_bounds(id));
+            }
+        }
+    }
+
+    #[test]
+    fn progress_counts_up_to_the_totals() {
+        use crate::progress::Log;
+
+        let packfile = include_bytes!("../../fixtures/packfile");
+        let expected = read(&include_bytes!("../../fixtures/pack_index")[..]).expect("failed to read index");
+        let log = Log::new();
+        let mut output = Vec::new();
+        Indexer::new().threads(4).progress(&log)
+            .write(Cursor::new(&packfile[..]), &mut output, None::<&StorageSet<()>>)
+            .expect("failed to index");
+
+        let phases = log.phases();
+        assert_eq!(phases.len(), 2);
+        assert_eq!(phases[0].name, "Indexing objects");
+        assert_eq!(phases[0].total, Some(expected.ids().len() as u64));
+        assert_eq!(phases[0].bytes, packfile.len() as u64);
+        assert_eq!(phases[1].name, "Resolving deltas");
+        assert!(phases.iter().all(|xs| xs.finished && Some(xs.count) == xs.total));
+    }
+
+    #[test]
+    fn indexing_stops_once_cancelled() {
+        use crate::progress::Progress;
+        use crate::cancel::Token;
+
+        // cancels once the second object has been found.
+        struct CancelAfterTwo(Token);
+        impl Progress for CancelAfterTwo {
+            fn update(&self, count: u64, _bytes: u64) {
+                if count == 2 {
+                    self.0.cancel();
+                }
+            }
+        }
+
+        let packfile = include_bytes!("../../fixtures/packfile");
+        let token = Token::new();
+        let progress = CancelAfterTwo(token.clone());
+        let mut output = Vec::new();
+        let result = Indexer::new().progress(&progress).cancel(&token)
+            .write(Cursor::new(&packfile[..]), &mut output, None::<&StorageSet<()>>);
+        match result {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::Interrupted)),
+            Ok(_) => panic!("expected indexing to be interrupted")
+        }
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn hashes_in_parallel_and_checks_the_pack_checksum() {
+        let packfile = include_bytes!("../../fixtures/packfile");
+        let mut serial = Vec::new();
+        Indexer::new().write(Cursor::new(&packfile[..]), &mut serial, None::<&StorageSet<()>>).expect("failed to index");
+        let mut parallel = Vec::new();
+        Indexer::new().parallel_hashing(true)
+            .write(Cursor::new(&packfile[..]), &mut parallel, None::<&StorageSet<()>>)
+            .expect("failed to index");
+        assert_eq!(serial, parallel);
+
+        let mut corrupted = packfile.to_vec();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        for parallel_hashing in &[false, true] {
+            let mut output = Vec::new();
+            let result = Indexer::new().parallel_hashing(*parallel_hashing)
+                .write(Cursor::new(&corrupted[..]), &mut output, None::<&StorageSet<()>>);
+            match result {
+                Err(e) => match e.kind() {
+                    ErrorKind::PackChecksumMismatch(context, expected, actual) => {
+                        assert_eq!(context.offset, Some(corrupted.len() as u64 - 20));
+                        assert_eq!(expected.as_ref()[19], actual.as_ref()[19] ^ 1);
+                    },
+                    _ => panic!("expected a checksum mismatch, got {}", e)
+                },
+                Ok(_) => panic!("indexed a corrupted pack")
+            }
+            assert!(output.is_empty());
+        }
+    }
+
+    #[test]
+    fn corrupt_objects_fail_even_with_a_fixed_up_trailer() {
+        use crypto::{ sha1::Sha1, digest::Digest };
+        use crate::pack::iter::PackfileIterator;
+        use crate::pack::write::Writer;
+        use crate::objects::Type;
+
+        let mut pack = Writer::new(Vec::new(), 24).expect("failed to start pack");
+        for idx in 0..24 {
+            let contents: Vec<u8> = (0..400).map(|xs: u32| (xs * 7 + idx * 13) as u8 ^ (xs / 5) as u8).collect();
+            pack.add(Type::Blob, &contents).expect("failed to add object");
+        }
+        let (packfile, _) = pack.finish().expect("failed to finish pack");
+        Indexer::new().write(Cursor::new(&packfile[..]), &mut Vec::new(), None::<&StorageSet<()>>).expect("failed to index");
+
+        let mut offsets: Vec<u64> = PackfileIterator::<_, ()>::new(Cursor::new(&packfile[..]), None).expect("failed to parse")
+            .map(|xs| xs.expect("failed to read object").0)
+            .collect();
+        offsets.push(packfile.len() as u64 - 20);
+        let fix_trailer = |pack: &mut Vec<u8>| {
+            let body = pack.len() - 20;
+            let mut shasum = Sha1::new();
+            shasum.input(&pack[..body]);
+            shasum.result(&mut pack[body..]);
+        };
+
+        // a flipped bit in the middle of each object's zlib stream.
+        for window in offsets.windows(2) {
+            let mut corrupted = packfile.clone();
+            corrupted[((window[0] + window[1]) / 2) as usize] ^= 0x10;
+            fix_trailer(&mut corrupted);
+
+            let mut output = Vec::new();
+            let result = Indexer::new().write(Cursor::new(&corrupted[..]), &mut output, None::<&StorageSet<()>>);
+            match result {
+                Err(e) => assert!(matches!(e.kind(), ErrorKind::CorruptedPackData(..)), "unexpected error {}", e),
+                Ok(_) => panic!("indexed a pack with a corrupt object at {}", window[0])
+            }
+            assert!(output.is_empty());
+        }
+
+        // a header promising more objects than there are.
+        let mut short = packfile.clone();
+        short[11] += 1;
+        fix_trailer(&mut short);
+        match Indexer::new().write(Cursor::new(&short[..]), &mut Vec::new(), None::<&StorageSet<()>>) {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::CorruptedPackData(..)), "unexpected error {}", e),
+            Ok(_) => panic!("indexed a pack missing an object")
+        }
+    }
+}
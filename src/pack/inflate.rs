@@ -0,0 +1,127 @@
+use flate2::{ Decompress, FlushDecompress, Status };
+use std::io::{ self, BufRead, Read };
+
+// Inflates one zlib stream, as packed and loose objects hold, reading no
+// further into `input` than its end. Unlike flate2's `ZlibDecoder`, which
+// returns end of file once its input runs out, a stream cut off before its
+// Adler-32 trailer is an error; so are a bad header, a preset dictionary and
+// a trailer that doesn't match. Read errors then say why in `corruption`.
+pub struct Inflate<R: BufRead> {
+    input: R,
+    state: Decompress,
+    done: bool,
+    corruption: Option<&'static str>
+}
+
+impl<R: BufRead> Inflate<R> {
+    pub fn new(input: R) -> Self {
+        Inflate {
+            input,
+            state: Decompress::new(true),
+            done: false,
+            corruption: None
+        }
+    }
+
+    // Why the stream was rejected, if it was; other read errors come from
+    // `input`.
+    pub fn corruption(&self) -> Option<&'static str> {
+        self.corruption
+    }
+
+    pub fn total_in(&self) -> u64 {
+        self.state.total_in()
+    }
+
+    pub fn total_out(&self) -> u64 {
+        self.state.total_out()
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.input
+    }
+
+    fn fail(&mut self, reason: &'static str) -> io::Error {
+        self.corruption = Some(reason);
+        io::Error::new(io::ErrorKind::InvalidData, reason)
+    }
+}
+
+impl<R: BufRead> Read for Inflate<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(reason) = self.corruption {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, reason))
+        }
+        if self.done || buf.is_empty() {
+            return Ok(0)
+        }
+        loop {
+            let input = self.input.fill_buf()?;
+            let eof = input.is_empty();
+            // git never writes a preset dictionary, flagged in a valid
+            // header's second byte, and has none to offer.
+            let header = self.state.total_in() == 0 && input.len() >= 2 && (u16::from(input[0]) << 8 | u16::from(input[1])) % 31 == 0;
+            if header && input[1] & 0x20 != 0 {
+                return Err(self.fail("zlib stream needs a preset dictionary"))
+            }
+            let (before_in, before_out) = (self.state.total_in(), self.state.total_out());
+            let flush = if eof { FlushDecompress::Finish } else { FlushDecompress::None };
+            let status = self.state.decompress(input, buf, flush);
+            let consumed = (self.state.total_in() - before_in) as usize;
+            let produced = (self.state.total_out() - before_out) as usize;
+            self.input.consume(consumed);
+            match status {
+                Ok(Status::StreamEnd) => {
+                    self.done = true;
+                    return Ok(produced)
+                },
+                Ok(_) if produced > 0 => return Ok(produced),
+                Ok(_) if eof => return Err(self.fail("zlib stream is truncated")),
+                Ok(_) if consumed == 0 => return Err(self.fail("zlib stream is corrupt")),
+                Ok(_) => continue,
+                Err(_) => return Err(self.fail("zlib stream is corrupt"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::{ Read, Write };
+
+    use super::Inflate;
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn inflate(data: &[u8]) -> Result<Vec<u8>, Option<&'static str>> {
+        let mut stream = Inflate::new(data);
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).map_err(|_| stream.corruption())?;
+        Ok(output)
+    }
+
+    #[test]
+    fn checks_header_and_trailer() {
+        let compressed = deflate(b"hello, hello, hello\n");
+        let mut followed = compressed.clone();
+        followed.extend(b"next object");
+        let mut stream = Inflate::new(&followed[..]);
+        let mut output = Vec::new();
+        stream.read_to_end(&mut output).unwrap();
+        assert_eq!(output, b"hello, hello, hello\n");
+        assert_eq!(stream.total_in(), compressed.len() as u64);
+
+        let mut checksum = compressed.clone();
+        *checksum.last_mut().unwrap() ^= 1;
+        assert_eq!(inflate(&checksum), Err(Some("zlib stream is corrupt")));
+        assert_eq!(inflate(&compressed[..compressed.len() - 2]), Err(Some("zlib stream is truncated")));
+        assert_eq!(inflate(&[0x78, 0xbb, 0, 0, 0, 1]), Err(Some("zlib stream needs a preset dictionary")));
+        assert_eq!(inflate(&[0x78, 0x9d, 0, 0]), Err(Some("zlib stream is corrupt")));
+    }
+}
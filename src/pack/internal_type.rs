@@ -5,8 +5,9 @@ use std;
 
 use crate::delta::{ DeltaDecoder, DeltaDecoderStream };
 use crate::stores::{ Queryable, StorageSet };
-use crate::errors::{ Result, ErrorKind };
+use crate::errors::{ Context, Result, ErrorKind };
 use crate::pack::read::packfile_read;
+use crate::pack::cache::DeltaCache;
 use crate::objects::Type;
 use crate::id::Id;
 
@@ -19,32 +20,75 @@ pub enum PackfileType {
 
 impl PackfileType {
     pub fn decompress<R, W, S>(self, initial: u64, input: &mut R, output: &mut W, backends: Option<&StorageSet<S>>) -> Result<Type>
+        where R: Debug + Read + BufRead + Seek,
+              W: Write,
+              S: Queryable {
+        self.decompress_chain(initial, input, output, backends, None, 0)
+    }
+
+    // Like `decompress`, but offset delta bases are looked up in and added
+    // to `bases`.
+    pub fn decompress_cached<R, W, S>(self, initial: u64, input: &mut R, output: &mut W, backends: Option<&StorageSet<S>>, bases: &DeltaCache) -> Result<Type>
+        where R: Debug + Read + BufRead + Seek,
+              W: Write,
+              S: Queryable {
+        self.decompress_chain(initial, input, output, backends, Some(bases), 0)
+    }
+
+    // `depth` counts the deltas already stacked on top of this object.
+    fn decompress_chain<R, W, S>(self, initial: u64, input: &mut R, output: &mut W, backends: Option<&StorageSet<S>>, bases: Option<&DeltaCache>, depth: usize) -> Result<Type>
         where R: Debug + Read + BufRead + Seek,
               W: Write,
               S: Queryable {
         Ok(match self {
             PackfileType::Plain(t) => {
+                if let Some(xs) = backends {
+                    xs.metrics().delta_chain(depth);
+                }
                 PackfileType::Plain(t).into()
             },
 
             PackfileType::OffsetDelta((offset, instructions)) => {
-                let mut intermediary = Vec::new();
-                let current_position = input.seek(SeekFrom::Current(0))?;
                 let object_start = initial - offset;
-                input.seek(SeekFrom::Start(object_start))?;
+                let (object_type, intermediary) = match bases.and_then(|xs| xs.get(object_start)) {
+                    Some(xs) => {
+                        // the base's own chain was counted when it was cached.
+                        if let Some(xs) = backends {
+                            xs.metrics().delta_chain(depth + 1);
+                        }
+                        xs
+                    },
+                    None => {
+                        let mut intermediary = Vec::new();
+                        let current_position = input.seek(SeekFrom::Current(0))?;
+                        input.seek(SeekFrom::Start(object_start))?;
 
-                let object_type = packfile_read(
-                    input,
-                    &mut intermediary,
-                    &mut 0,
-                )?.decompress(
-                    object_start,
-                    input,
-                    &mut intermediary,
-                    backends
-                )?;
+                        let mut inflated = 0;
+                        let object_type = packfile_read(
+                            input,
+                            &mut intermediary,
+                            &mut 0,
+                            &mut inflated
+                        )?.decompress_chain(
+                            object_start,
+                            input,
+                            &mut intermediary,
+                            backends,
+                            bases,
+                            depth + 1
+                        )?;
+                        if let Some(xs) = backends {
+                            xs.metrics().bytes_inflated(inflated);
+                        }
+                        if let Some(xs) = bases {
+                            xs.put(object_start, object_type, &intermediary);
+                        }
+
+                        input.seek(SeekFrom::Start(current_position))?;
+                        (object_type, intermediary)
+                    }
+                };
 
-                input.seek(SeekFrom::Start(current_position))?;
                 let delta_decoder = DeltaDecoder::new(&instructions, intermediary)?;
                 let mut stream: DeltaDecoderStream = delta_decoder.into();
                 std::io::copy(&mut stream, output)?;
@@ -57,11 +101,18 @@ impl PackfileType {
                 }
 
                 let mut base_data = Vec::new();
-                let t = match backends.unwrap().get(&id, &mut base_data)? {
+                let t = match backends.unwrap().get_unreplaced(&id, &mut base_data)? {
                     Some(xs) => xs,
-                    None => return Err(ErrorKind::CorruptedPackfile.into())
+                    None => {
+                        let context = Context::new("resolving delta").offset(initial);
+                        return Err(ErrorKind::MissingDeltaBase(context.into(), id).into())
+                    }
                 };
 
+                // the base was read (and its own chain counted) through the
+                // storage set, so this chain ends here.
+                backends.unwrap().metrics().delta_chain(depth + 1);
+
                 let delta_decoder = DeltaDecoder::new(&instructions, base_data)?;
                 let mut stream: DeltaDecoderStream = delta_decoder.into();
                 std::io::copy(&mut stream, output)?;
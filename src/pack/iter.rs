@@ -2,7 +2,7 @@ use crypto::{ sha1::Sha1, digest::Digest };
 use std::io::{ BufRead, Seek, Write };
 
 use crate::stores::{ Queryable, StorageSet };
-use crate::errors::{ Result, ErrorKind };
+use crate::errors::{ Context, Result, ErrorKind };
 use crate::pack::read::packfile_read;
 use crate::id::Id;
 
@@ -13,6 +13,7 @@ pub struct PackfileIterator<'a, R: BufRead + Seek + std::fmt::Debug, S: Queryabl
     buffer: Vec<u8>,
     header_buffer: Vec<u8>,
     current_offset: u64,
+    hash_ids: bool,
     storage_set: Option<&'a StorageSet<S>>
 }
 
@@ -22,7 +23,7 @@ impl<'a, R: BufRead + Seek + std::fmt::Debug, S: Queryable> PackfileIterator<'a,
         stream.read_exact(&mut magic)?;
 
         if &magic != b"PACK" {
-            return Err(ErrorKind::CorruptedPackfile.into())
+            return Err(ErrorKind::CorruptedPackData(Context::new("reading pack header").offset(0).into(), "not a packfile").into())
         }
 
         let mut version_bytes = [0u8; 4];
@@ -43,25 +44,38 @@ impl<'a, R: BufRead + Seek + std::fmt::Debug, S: Queryable> PackfileIterator<'a,
             object_count,
             storage_set,
             current_offset: 12,
+            hash_ids: true,
             buffer: Vec::with_capacity(65535),
             header_buffer: Vec::with_capacity(128),
             stream
         })
     }
+
+    // Leaves non-delta objects unhashed (their ids None), for callers that
+    // hash `data()` themselves.
+    pub fn without_ids(mut self) -> Self {
+        self.hash_ids = false;
+        self
+    }
+
+    // The inflated contents of the object `next()` last returned; a delta's
+    // instructions rather than the object it builds.
+    pub fn data(&self) -> &[u8] {
+        &self.buffer
+    }
 }
 
 use crate::pack::internal_type::PackfileType;
 use crate::objects::Type;
 
-impl<'a, R: BufRead + Seek + std::fmt::Debug, S: Queryable> Iterator for PackfileIterator<'a, R, S> {
-    type Item = (u64, PackfileType, Option<Id>);
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.index >= self.object_count {
-            return None
-        }
+impl<'a, R: BufRead + Seek + std::fmt::Debug, S: Queryable> PackfileIterator<'a, R, S> {
+    // The offset just past the last object read: where the next one, or the
+    // trailer, starts.
+    pub fn offset(&self) -> u64 {
+        self.current_offset
+    }
 
-        self.index += 1;
+    fn read_next(&mut self) -> Result<(u64, PackfileType, Option<Id>)> {
         self.buffer.clear();
 
         let offset = self.current_offset;
@@ -69,16 +83,25 @@ impl<'a, R: BufRead + Seek + std::fmt::Debug, S: Queryable> Iterator for Packfil
         let packfile_type = packfile_read(
             &mut self.stream,
             &mut self.buffer,
-            &mut bytes_read
-        ).ok()?;
+            &mut bytes_read,
+            &mut 0
+        ).map_err(|xs| {
+            let context = Context::new("reading pack").offset(offset);
+            match xs.kind() {
+                // the stream ran out partway through the object.
+                ErrorKind::Io(_) => ErrorKind::CorruptedPackData(context.into(), "object is truncated").into(),
+                _ => xs.within(context)
+            }
+        })?;
 
         self.current_offset += bytes_read;
 
-        let id = if let PackfileType::Plain(ident) = packfile_type {
+        let id = if let (PackfileType::Plain(ident), true) = (&packfile_type, self.hash_ids) {
+            let ident = *ident;
             let object_type: Type = PackfileType::Plain(ident).into();
             let mut hash = Sha1::new();
             self.header_buffer.clear();
-            write!(&mut self.header_buffer, "{} {}\0", object_type.as_str(), self.buffer.len()).ok()?;
+            write!(&mut self.header_buffer, "{} {}\0", object_type.as_str(), self.buffer.len())?;
             hash.input(&(self.header_buffer)[..]);
             hash.input(&(self.buffer)[..]);
 
@@ -90,13 +113,34 @@ impl<'a, R: BufRead + Seek + std::fmt::Debug, S: Queryable> Iterator for Packfil
             None
         };
 
-        Some((offset, packfile_type, id))
+        Ok((offset, packfile_type, id))
+    }
+}
+
+// Yields each object's offset, type and (unless `without_ids`) id. A
+// corrupt or truncated object is an error, after which iteration stops.
+impl<'a, R: BufRead + Seek + std::fmt::Debug, S: Queryable> Iterator for PackfileIterator<'a, R, S> {
+    type Item = Result<(u64, PackfileType, Option<Id>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.object_count {
+            return None
+        }
+
+        self.index += 1;
+        let item = self.read_next();
+        if item.is_err() {
+            self.index = self.object_count;
+        }
+        Some(item)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::io::{ Read, Cursor };
+
+    use crate::errors::ErrorKind;
     use super::PackfileIterator;
 
     #[test]
@@ -105,7 +149,26 @@ mod tests {
 
         let packfile_iter: PackfileIterator<_, ()> = PackfileIterator::new(Cursor::new(&packfile[..]), None).expect("failed to parse");
         for entry in packfile_iter {
-            println!("entry: {:?}", entry);
+            println!("entry: {:?}", entry.expect("failed to read object"));
+        }
+    }
+
+    #[test]
+    fn corrupt_objects_are_errors() {
+        let packfile = include_bytes!("../../fixtures/packfile");
+        let mut corrupted = packfile.to_vec();
+        // inside the first object's zlib stream, past its header.
+        corrupted[20] ^= 0xff;
+
+        let results: Vec<_> = PackfileIterator::<_, ()>::new(Cursor::new(&corrupted[..]), None).expect("failed to parse").collect();
+        assert!(results.len() < 5);
+        match results.last() {
+            Some(Err(e)) => assert!(matches!(e.kind(), ErrorKind::CorruptedPackData(..))),
+            _ => panic!("expected a corrupt object")
         }
+
+        let truncated = &packfile[..packfile.len() / 2];
+        let last = PackfileIterator::<_, ()>::new(Cursor::new(truncated), None).expect("failed to parse").last();
+        assert!(matches!(last, Some(Err(_))));
     }
 }
@@ -3,33 +3,40 @@ use memmap::Mmap;
 
 use crate::stores::{ Queryable, StorageSet };
 use crate::pack::read::packfile_read;
+use crate::pack::cache::DeltaCache;
 use crate::errors::Result;
 use crate::pack::Packfile;
 use crate::objects::Type;
 
-pub struct Reader {
-    mmap: Mmap
+// A pack mapped into memory, or read into it whole when there is nothing to
+// map (`stores::fs::from_vfs`).
+pub struct Reader<M: AsRef<[u8]> = Mmap> {
+    mmap: M
 }
 
-impl Reader {
-    pub fn new(mmap: Mmap) -> Self {
+impl<M: AsRef<[u8]>> Reader<M> {
+    pub fn new(mmap: M) -> Self {
         Reader {
             mmap
         }
     }
 }
 
-impl Packfile for Reader {
-    fn read_bounds<W: Write, S: Queryable>(&self, start: u64, end: u64, output: &mut W, backends: &StorageSet<S>) -> Result<Type> {
-        let mut cursor = Cursor::new(&self.mmap[ .. end as usize]);
+impl<M: AsRef<[u8]>> Packfile for Reader<M> {
+    fn read_bounds<W: Write, S: Queryable>(&self, start: u64, end: u64, output: &mut W, backends: &StorageSet<S>, bases: &DeltaCache) -> Result<Type> {
+        let mmap = self.mmap.as_ref();
+        let mut cursor = Cursor::new(&mmap[ .. (end as usize).min(mmap.len())]);
         cursor.seek(SeekFrom::Start(start))?;
 
-        let packfile_type = packfile_read(&mut cursor, output, &mut 0)?;
-        let obj_type = packfile_type.decompress(
+        let mut inflated = 0;
+        let packfile_type = packfile_read(&mut cursor, output, &mut 0, &mut inflated)?;
+        backends.metrics().bytes_inflated(inflated);
+        let obj_type = packfile_type.decompress_cached(
             start,
             &mut cursor,
             output,
-            Some(backends)
+            Some(backends),
+            bases
         )?;
 
         Ok(obj_type)
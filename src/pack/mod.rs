@@ -2,6 +2,7 @@ use std::io::Write;
 use std;
 
 use crate::stores::{ Queryable, StorageSet };
+use crate::pack::cache::DeltaCache;
 use crate::errors::Result;
 use crate::objects::Type;
 use crate::id::Id;
@@ -11,6 +12,9 @@ pub mod mmap;
 pub mod any;
 pub mod iter;
 pub mod internal_type;
+pub mod write;
+pub mod cache;
+pub mod inflate;
 mod read;
 
 #[derive(Debug)]
@@ -38,5 +42,5 @@ impl IndexEntry {
 pub struct Fanout ([u32; 256]);
 
 pub trait Packfile {
-    fn read_bounds<W: Write, S: Queryable>(&self, start: u64, end: u64, output: &mut W, backends: &StorageSet<S>) -> Result<Type>;
+    fn read_bounds<W: Write, S: Queryable>(&self, start: u64, end: u64, output: &mut W, backends: &StorageSet<S>, bases: &DeltaCache) -> Result<Type>;
 }
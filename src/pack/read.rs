@@ -1,16 +1,25 @@
-use flate2::bufread::ZlibDecoder;
 use std::io::prelude::*;
 use std;
 
 use crate::pack::internal_type::PackfileType;
 use crate::delta::{ OFS_DELTA, REF_DELTA };
-use crate::errors::{ Result, ErrorKind };
+use crate::errors::{ Context, Error, Result, ErrorKind };
+use crate::pack::inflate::Inflate;
 use crate::id::Id;
 
+// A failed inflate is corrupt pack data unless writing the output failed.
+fn inflate_error<R: BufRead>(stream: &Inflate<R>, error: std::io::Error) -> Error {
+    match stream.corruption() {
+        Some(reason) => ErrorKind::CorruptedPackData(Context::new("inflating object").into(), reason).into(),
+        None => error.into()
+    }
+}
+
 pub fn packfile_read<R: BufRead, W: Write>(
     input: &mut R,
     output: &mut W,
-    read_bytes: &mut u64
+    read_bytes: &mut u64,
+    inflated: &mut u64
 ) -> Result<PackfileType> {
     let mut byte = [0u8; 1];
     input.read_exact(&mut byte)?;
@@ -31,12 +40,13 @@ pub fn packfile_read<R: BufRead, W: Write>(
         count += 1;
     }
 
-    match obj_type {
+    let packfile_type = match obj_type {
         0...4 => {
-            let mut deflate_stream = ZlibDecoder::new(input);
-            std::io::copy(&mut deflate_stream, output)?;
+            let mut deflate_stream = Inflate::new(input);
+            std::io::copy(&mut deflate_stream, output).map_err(|xs| inflate_error(&deflate_stream, xs))?;
             *read_bytes = 1 + count + deflate_stream.total_in();
-            return Ok(PackfileType::Plain(obj_type));
+            *inflated = deflate_stream.total_out();
+            PackfileType::Plain(obj_type)
         },
 
         OFS_DELTA => {
@@ -51,12 +61,13 @@ pub fn packfile_read<R: BufRead, W: Write>(
                 count += 1;
             }
 
-            let mut deflate_stream = ZlibDecoder::new(input);
+            let mut deflate_stream = Inflate::new(input);
             let mut instructions = Vec::new();
-            deflate_stream.read_to_end(&mut instructions)?;
+            deflate_stream.read_to_end(&mut instructions).map_err(|xs| inflate_error(&deflate_stream, xs))?;
 
             *read_bytes = 2 + count + deflate_stream.total_in();
-            return Ok(PackfileType::OffsetDelta((offset, instructions)))
+            *inflated = deflate_stream.total_out();
+            PackfileType::OffsetDelta((offset, instructions))
         },
 
         REF_DELTA => {
@@ -64,15 +75,23 @@ pub fn packfile_read<R: BufRead, W: Write>(
             input.read_exact(&mut ref_bytes)?;
             let id = Id::from(&ref_bytes);
 
-            let mut deflate_stream = ZlibDecoder::new(input);
+            let mut deflate_stream = Inflate::new(input);
             let mut instructions = Vec::new();
-            deflate_stream.read_to_end(&mut instructions)?;
+            deflate_stream.read_to_end(&mut instructions).map_err(|xs| inflate_error(&deflate_stream, xs))?;
             *read_bytes = 21 + count + deflate_stream.total_in();
-            return Ok(PackfileType::RefDelta((id, instructions)))
+            *inflated = deflate_stream.total_out();
+            PackfileType::RefDelta((id, instructions))
         },
 
         _ => {
-            Err(ErrorKind::BadLooseObject.into())
+            return Err(ErrorKind::CorruptedPackData(Context::new("reading object header").into(), "unknown object type").into())
         }
+    };
+
+    // as git's index-pack, a stream inflating to another size than the
+    // header gives is corrupt.
+    if *inflated != size {
+        return Err(ErrorKind::CorruptedPackData(Context::new("inflating object").into(), "object size doesn't match its header").into())
     }
+    Ok(packfile_type)
 }
@@ -0,0 +1,137 @@
+use crypto::{ sha1::Sha1, digest::Digest };
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::Write;
+
+use crate::pack::internal_type::PackfileType;
+use crate::progress::{ NoProgress, Progress };
+use crate::errors::Result;
+use crate::objects::Type;
+use crate::id::Id;
+
+// The type and size header: the type in bits 4-6 of the first byte, the
+// size four bits there and seven in each byte after.
+fn header(typ: Type, size: usize) -> Vec<u8> {
+    let code = match typ.into() {
+        PackfileType::Plain(xs) => xs,
+        _ => unreachable!()
+    };
+    let mut bytes = vec![(code << 4) | (size & 0x0f) as u8];
+    let mut rest = size >> 4;
+    while rest > 0 {
+        *bytes.last_mut().unwrap() |= 0x80;
+        bytes.push((rest & 0x7f) as u8);
+        rest >>= 7;
+    }
+    bytes
+}
+
+// Writes a version 2 pack of whole objects (no deltas) one object at a
+// time, so only the object being added is ever in memory. The header
+// carries the count, so it is given up front.
+pub struct Writer<W: Write> {
+    output: W,
+    hash: Sha1,
+    count: u32,
+    added: u32,
+    bytes: u64
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(output: W, count: u32) -> Result<Writer<W>> {
+        let mut writer = Writer {
+            output,
+            hash: Sha1::new(),
+            count,
+            added: 0,
+            bytes: 0
+        };
+        let mut header = Vec::with_capacity(12);
+        header.extend_from_slice(b"PACK");
+        header.extend_from_slice(&2u32.to_be_bytes());
+        header.extend_from_slice(&count.to_be_bytes());
+        writer.write(&header)?;
+        Ok(writer)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.hash.input(data);
+        self.output.write_all(data)?;
+        self.bytes += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn add(&mut self, typ: Type, contents: &[u8]) -> Result<()> {
+        let mut data = header(typ, contents.len());
+        let mut encoder = ZlibEncoder::new(&mut data, Compression::default());
+        encoder.write_all(contents)?;
+        encoder.finish()?;
+        self.added += 1;
+        self.write(&data)
+    }
+
+    // How much of the pack is written so far.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    // Writes the trailing checksum, which git names the pack after.
+    pub fn finish(mut self) -> Result<(W, Id)> {
+        if self.added != self.count {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "pack holds a different number of objects than its header").into())
+        }
+        let mut checksum = [0u8; 20];
+        self.hash.result(&mut checksum);
+        self.output.write_all(&checksum)?;
+        Ok((self.output, checksum.into()))
+    }
+}
+
+// Writes `objects` as a version 2 pack of whole objects (no deltas) and
+// returns its checksum, which git names the pack after.
+pub fn write<W: Write>(output: &mut W, objects: &[(Type, Vec<u8>)]) -> Result<Id> {
+    write_with_progress(output, objects, &NoProgress)
+}
+
+// Like `write`, reporting "Writing objects" to `progress`.
+pub fn write_with_progress<W: Write>(output: &mut W, objects: &[(Type, Vec<u8>)], progress: &dyn Progress) -> Result<Id> {
+    let mut writer = Writer::new(output, objects.len() as u32)?;
+    progress.start("Writing objects", Some(objects.len() as u64));
+    for (idx, (typ, contents)) in objects.iter().enumerate() {
+        writer.add(*typ, contents)?;
+        progress.update(idx as u64 + 1, writer.bytes());
+    }
+    progress.finish();
+    Ok(writer.finish()?.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::stores::{ pack::Store, StorageSet };
+    use crate::pack::index::{ read, write as write_index };
+    use crate::objects::{ self, Type };
+    use crate::pack::any::Reader;
+    use super::write;
+
+    #[test]
+    fn written_packs_can_be_indexed_and_read() {
+        let big = vec![b'x'; 5000];
+        let objects = vec![(Type::Blob, b"hello\n".to_vec()), (Type::Blob, big.clone()), (Type::Blob, Vec::new())];
+        let mut pack = Vec::new();
+        write(&mut pack, &objects).expect("failed to write pack");
+
+        let mut idx = Vec::new();
+        write_index(Cursor::new(&pack[..]), &mut idx, None::<&StorageSet<()>>).expect("failed to index");
+        let index = read(Cursor::new(&idx)).expect("failed to read index");
+        let pack = pack.clone();
+        let storage_set = StorageSet::new(Store::new(Reader::new(move || Ok(Cursor::new(pack.clone()))), index));
+        for (typ, contents) in &objects {
+            let mut output = Vec::new();
+            let found = storage_set.get(&objects::hash(*typ, contents), &mut output).expect("failed to read");
+            assert!(matches!(found, Some(Type::Blob)));
+            assert_eq!(&output, contents);
+        }
+    }
+}
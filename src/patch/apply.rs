@@ -0,0 +1,629 @@
+use std::collections::{ BTreeMap, HashSet };
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::io::Read;
+use std::path::Path;
+use std::ffi::OsStr;
+
+use crate::objects::tree::{ write_nested, FileMode, TreeEntry };
+use crate::stores::{ Queryable, StorageSet };
+use crate::errors::{ ErrorKind, Result };
+use crate::delta::{ DeltaDecoder, DeltaDecoderStream };
+use crate::objects::{ self, Object, Type };
+use crate::checkout::{ flatten, paths };
+use crate::index::{ Entry, Index };
+use crate::id::Id;
+use super::{ Binary, Change, FilePatch, Hunk, Line };
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    // match lines regardless of whitespace changes (--ignore-whitespace)
+    pub ignore_whitespace: bool,
+    // how many context lines may be dropped from each end of a hunk to make
+    // it apply; 0 requires all context to match
+    pub fuzz: usize,
+    // verify that everything applies but change nothing (--check)
+    pub check: bool
+}
+
+fn same_line(lhs: &[u8], rhs: &[u8], options: &Options) -> bool {
+    if !options.ignore_whitespace {
+        return lhs == rhs
+    }
+    let words = |xs: &[u8]| -> Vec<Vec<u8>> {
+        xs.split(|xs| xs.is_ascii_whitespace()).filter(|xs| !xs.is_empty()).map(|xs| xs.to_vec()).collect()
+    };
+    words(lhs) == words(rhs)
+}
+
+fn matches_at(original: &[&[u8]], at: usize, preimage: &[&[u8]], options: &Options) -> bool {
+    at + preimage.len() <= original.len() &&
+        preimage.iter().zip(&original[at..]).all(|(lhs, rhs)| same_line(lhs, rhs, options))
+}
+
+// Applies `hunks` in order. Each hunk is looked for at its stated position
+// (shifted by how far earlier hunks moved), then progressively further away,
+// but never before the end of the previous hunk.
+pub fn apply_text(original: &[u8], hunks: &[Hunk], options: &Options) -> Option<Vec<u8>> {
+    let lines = super::split_lines(original);
+    let mut output = Vec::with_capacity(original.len());
+    let mut consumed = 0;
+    let mut offset: isize = 0;
+
+    for hunk in hunks {
+        let mut applied = false;
+        for fuzz in 0..=options.fuzz {
+            let leading = hunk.lines.iter().take(fuzz).take_while(|xs| matches!(xs, Line::Context(_))).count();
+            let trailing = hunk.lines.iter().rev().take(fuzz).take_while(|xs| matches!(xs, Line::Context(_))).count();
+            if leading + trailing > hunk.lines.len() {
+                continue
+            }
+            let body = &hunk.lines[leading..hunk.lines.len() - trailing];
+
+            let preimage: Vec<&[u8]> = body.iter().filter_map(|line| match line {
+                Line::Context(xs) | Line::Remove(xs) => Some(xs.as_slice()),
+                Line::Add(_) => None
+            }).collect();
+
+            // a zero-length old range names the line the hunk goes after.
+            let stated = if hunk.old_count == 0 { hunk.old_start } else { hunk.old_start.saturating_sub(1) } + leading;
+            let last = match lines.len().checked_sub(preimage.len()) {
+                Some(xs) if xs >= consumed => xs,
+                _ => continue
+            };
+            let expected = ((stated as isize + offset).max(consumed as isize) as usize).min(last);
+
+            let mut found = None;
+            for distance in 0..=last - consumed {
+                if expected + distance <= last && matches_at(&lines, expected + distance, &preimage, options) {
+                    found = Some(expected + distance);
+                    break
+                }
+                if distance > 0 && expected >= consumed + distance && matches_at(&lines, expected - distance, &preimage, options) {
+                    found = Some(expected - distance);
+                    break
+                }
+            }
+
+            let at = match found {
+                Some(xs) => xs,
+                None => continue
+            };
+
+            for line in &lines[consumed..at] {
+                output.extend_from_slice(line);
+            }
+
+            // context is copied from the original, which matters when
+            // whitespace was ignored while matching.
+            let mut cursor = at;
+            for line in body {
+                match line {
+                    Line::Context(_) => {
+                        output.extend_from_slice(lines[cursor]);
+                        cursor += 1;
+                    },
+                    Line::Remove(_) => cursor += 1,
+                    Line::Add(xs) => output.extend_from_slice(xs)
+                }
+            }
+
+            consumed = cursor;
+            offset = at as isize - stated as isize;
+            applied = true;
+            break
+        }
+
+        if !applied {
+            return None
+        }
+    }
+
+    for line in &lines[consumed..] {
+        output.extend_from_slice(line);
+    }
+    Some(output)
+}
+
+// Returns the new contents of the file `file` patches, or None if the patch
+// deletes it. `original` is None when the file does not exist.
+pub fn apply_blob(original: Option<&[u8]>, file: &FilePatch, options: &Options) -> Result<Option<Vec<u8>>> {
+    let fail = || -> Result<Option<Vec<u8>>> { Err(ErrorKind::PatchDoesNotApply(file.path().to_vec()).into()) };
+    let original = match (original, file.is_add()) {
+        (Some(_), true) | (None, false) => return fail(),
+        (Some(xs), false) => xs,
+        (None, true) => &b""[..]
+    };
+
+    let contents = match file.change {
+        Change::Text(ref hunks) => match apply_text(original, hunks, options) {
+            Some(xs) => xs,
+            None => return fail()
+        },
+        Change::Binary(Binary::Literal(ref xs)) => xs.clone(),
+        Change::Binary(Binary::Delta(ref delta)) => {
+            let decoder = match DeltaDecoder::new(delta, original.to_vec()) {
+                Ok(xs) => xs,
+                Err(_) => return fail()
+            };
+            let mut stream: DeltaDecoderStream = decoder.into();
+            let mut output = Vec::new();
+            stream.read_to_end(&mut output)?;
+            output
+        },
+        Change::Binary(Binary::Unavailable) => return fail()
+    };
+
+    if file.is_delete() {
+        // everything must have been removed for a deletion to apply.
+        return if contents.is_empty() { Ok(None) } else { fail() }
+    }
+    Ok(Some(contents))
+}
+
+fn new_mode(file: &FilePatch, old_mode: Option<FileMode>) -> FileMode {
+    file.new_mode.or(old_mode).unwrap_or(FileMode::FILE)
+}
+
+// Paths a patch names must stay inside the worktree once stripped, as
+// `git apply` checks: no absolute paths, ".." or .git.
+fn verify_paths(files: &[FilePatch]) -> Result<()> {
+    for file in files {
+        for entry_path in file.old_path.iter().chain(file.new_path.iter()) {
+            if !paths::verify(entry_path) {
+                return Err(ErrorKind::UnsafePath(entry_path.clone()).into())
+            }
+        }
+    }
+    Ok(())
+}
+
+// A file the patch creates, or renames or copies into place, must not be
+// there already, unless an earlier file of the patch took it away.
+fn creates(file: &FilePatch) -> Option<&Vec<u8>> {
+    file.new_path.as_ref().filter(|xs| file.old_path.as_ref() != Some(xs))
+}
+
+fn removes(file: &FilePatch) -> Option<&Vec<u8>> {
+    file.old_path.as_ref().filter(|_| (file.is_rename || file.is_delete()) && !file.is_copy)
+}
+
+// Nor may they lead through a symlink, including one made earlier in the
+// same patch.
+fn check_leading(path: &Path, entry_path: &[u8]) -> Result<()> {
+    if paths::beyond_symlink(path, entry_path) {
+        return Err(ErrorKind::BeyondSymlink(entry_path.to_vec()).into())
+    }
+    Ok(())
+}
+
+// Applies every file of a patch to the worktree at `path`. All files are
+// patched in memory first, so nothing is written unless the whole patch
+// applies.
+pub fn apply_worktree(path: &Path, files: &[FilePatch], options: &Options) -> Result<()> {
+    verify_paths(files)?;
+    let mut results = Vec::with_capacity(files.len());
+    let (mut gone, mut made) = (HashSet::new(), HashSet::new());
+    for file in files {
+        if let Some(new_path) = creates(file) {
+            let present = std::fs::symlink_metadata(path.join(OsStr::from_bytes(new_path))).is_ok();
+            if made.contains(new_path) || (present && !gone.contains(new_path)) {
+                return Err(ErrorKind::PatchTargetExists(new_path.clone()).into())
+            }
+        }
+        let (old_mode, original) = match file.old_path {
+            Some(ref old_path) => match read_worktree(path, old_path)? {
+                Some((mode, contents)) => (Some(mode), Some(contents)),
+                None => (None, None)
+            },
+            None => (None, None)
+        };
+
+        let contents = apply_blob(original.as_deref(), file, options)?;
+        if let Some(old_path) = removes(file) {
+            made.remove(old_path);
+            gone.insert(old_path.clone());
+        }
+        if let (Some(new_path), Some(_)) = (file.new_path.as_ref(), contents.as_ref()) {
+            gone.remove(new_path);
+            made.insert(new_path.clone());
+        }
+        results.push((file, new_mode(file, old_mode), contents));
+    }
+
+    if options.check {
+        return Ok(())
+    }
+
+    for (file, mode, contents) in results {
+        if let Some(old_path) = removes(file) {
+            check_leading(path, old_path)?;
+            std::fs::remove_file(path.join(OsStr::from_bytes(old_path)))?;
+        }
+
+        let (new_path, contents) = match (file.new_path.as_ref(), contents) {
+            (Some(xs), Some(contents)) => (xs, contents),
+            _ => continue
+        };
+
+        check_leading(path, new_path)?;
+        let full_path = path.join(OsStr::from_bytes(new_path));
+        if let Some(parent) = full_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if std::fs::symlink_metadata(full_path.as_path()).is_ok() {
+            std::fs::remove_file(full_path.as_path())?;
+        }
+
+        if mode == FileMode::SYMLINK {
+            std::os::unix::fs::symlink(OsStr::from_bytes(&contents), full_path.as_path())?;
+            continue
+        }
+
+        std::fs::write(full_path.as_path(), &contents)?;
+        let mut permissions = std::fs::metadata(full_path.as_path())?.permissions();
+        let bits = permissions.mode();
+        permissions.set_mode(if mode == FileMode::EXECUTABLE { bits | ((bits & 0o444) >> 2) } else { bits & !0o111 });
+        std::fs::set_permissions(full_path.as_path(), permissions)?;
+    }
+
+    Ok(())
+}
+
+// Applies a patch to a commit or tree and returns the resulting tree. New
+// objects go through `put`; with `check` set they are only hashed, so the
+// returned id is what the tree would be.
+pub fn apply_tree<S, F>(storage_set: &StorageSet<S>, id: &Id, files: &[FilePatch], options: &Options, put: &mut F) -> Result<Id>
+    where S: Queryable,
+          F: FnMut(Type, Vec<u8>) -> Result<Id> {
+    verify_paths(files)?;
+    let mut entries: BTreeMap<Vec<u8>, TreeEntry> = flatten(storage_set, id)?;
+    let mut store = |typ: Type, data: Vec<u8>| -> Result<Id> {
+        if options.check {
+            Ok(objects::hash(typ, &data))
+        } else {
+            put(typ, data)
+        }
+    };
+
+    for (entry_path, entry) in apply_entries(storage_set, |xs| entries.get(xs).cloned(), files, options, &mut store)? {
+        match entry {
+            Some(xs) => entries.insert(entry_path, xs),
+            None => entries.remove(&entry_path)
+        };
+    }
+    write_nested(&entries, &mut store)
+}
+
+// Applies a patch to the index alone, as `git apply --cached`: blobs are
+// read from the index's entries and new ones go through `put`, and the
+// worktree is left as it is. With `check` set the index is not changed.
+pub fn apply_index<S, F>(storage_set: &StorageSet<S>, index: &mut Index, files: &[FilePatch], options: &Options, put: &mut F) -> Result<()>
+    where S: Queryable,
+          F: FnMut(Type, Vec<u8>) -> Result<Id> {
+    verify_paths(files)?;
+    if index.has_conflicts() {
+        let paths = index.conflicts().into_iter().map(|xs| xs.path).collect();
+        return Err(ErrorKind::UnmergedIndex(paths).into())
+    }
+    let mut store = |typ: Type, data: Vec<u8>| -> Result<Id> {
+        if options.check {
+            Ok(objects::hash(typ, &data))
+        } else {
+            put(typ, data)
+        }
+    };
+
+    let lookup = |entry_path: &[u8]| index.get(entry_path, 0).map(|xs| TreeEntry { mode: xs.mode, id: xs.id.clone() });
+    let changes = apply_entries(storage_set, lookup, files, options, &mut store)?;
+    if options.check {
+        return Ok(())
+    }
+    for (entry_path, entry) in changes {
+        match entry {
+            Some(xs) => index.add(Entry::new(entry_path, xs.mode, xs.id)),
+            None => { index.remove(&entry_path); }
+        }
+    }
+    Ok(())
+}
+
+// Patches the blobs `lookup` finds for each file, returning what becomes of
+// every path the patch names: its new entry, or None once it is removed.
+fn apply_entries<S, L, F>(storage_set: &StorageSet<S>, lookup: L, files: &[FilePatch], options: &Options, store: &mut F) -> Result<BTreeMap<Vec<u8>, Option<TreeEntry>>>
+    where S: Queryable,
+          L: Fn(&[u8]) -> Option<TreeEntry>,
+          F: FnMut(Type, Vec<u8>) -> Result<Id> {
+    let mut changes: BTreeMap<Vec<u8>, Option<TreeEntry>> = BTreeMap::new();
+    let current = |changes: &BTreeMap<Vec<u8>, Option<TreeEntry>>, entry_path: &[u8]| match changes.get(entry_path) {
+        Some(xs) => xs.clone(),
+        None => lookup(entry_path)
+    };
+
+    for file in files {
+        if let Some(new_path) = creates(file) {
+            if current(&changes, new_path).is_some() {
+                return Err(ErrorKind::PatchTargetExists(new_path.clone()).into())
+            }
+        }
+        let old_entry = file.old_path.as_ref().and_then(|xs| current(&changes, xs));
+        let original = match old_entry {
+            Some(ref entry) => match storage_set.get_and_load(&entry.id)? {
+                Some(Object::Blob(blob)) => Some(blob.contents),
+                _ => return Err(ErrorKind::MissingObject.into())
+            },
+            None => None
+        };
+
+        let contents = apply_blob(original.as_deref(), file, options)?;
+        if let Some(old_path) = removes(file) {
+            changes.insert(old_path.clone(), None);
+        }
+        if let (Some(new_path), Some(contents)) = (file.new_path.as_ref(), contents) {
+            let mode = new_mode(file, old_entry.map(|xs| xs.mode));
+            let id = store(Type::Blob, contents)?;
+            changes.insert(new_path.clone(), Some(TreeEntry { mode, id }));
+        }
+    }
+    Ok(changes)
+}
+
+fn read_worktree(path: &Path, entry_path: &[u8]) -> Result<Option<(FileMode, Vec<u8>)>> {
+    check_leading(path, entry_path)?;
+    let full_path = path.join(OsStr::from_bytes(entry_path));
+    let metadata = match std::fs::symlink_metadata(full_path.as_path()) {
+        Ok(xs) => xs,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into())
+    };
+
+    if metadata.file_type().is_symlink() {
+        let target = std::fs::read_link(full_path.as_path())?;
+        return Ok(Some((FileMode::SYMLINK, target.as_os_str().as_bytes().to_vec())))
+    }
+
+    let mode = if metadata.permissions().mode() & 0o100 != 0 { FileMode::EXECUTABLE } else { FileMode::FILE };
+    Ok(Some((mode, std::fs::read(full_path)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stores::memory::Store as MemoryStore;
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::objects::{ self, Object, Type };
+    use crate::objects::tree::FileMode;
+    use crate::errors::ErrorKind;
+    use crate::files;
+    use super::super::parse;
+    use crate::index::{ Entry, Index };
+    use super::{ apply_index, apply_text, apply_tree, apply_worktree, Options };
+
+    const ORIGINAL: &[u8] = b"one\ntwo\nthree\nfour\nfive\nsix\nseven\n";
+
+    fn hunks(patch: &[u8]) -> Vec<super::Hunk> {
+        match parse(patch).expect("failed to parse").remove(0).change {
+            super::Change::Text(xs) => xs,
+            _ => panic!("expected text")
+        }
+    }
+
+    #[test]
+    fn apply_text_works() {
+        let patch = b"--- a/x\n+++ b/x\n@@ -2,3 +2,3 @@\n two\n-three\n+THREE\n four\n@@ -6,2 +6,3 @@\n six\n seven\n+eight\n";
+        let output = apply_text(ORIGINAL, &hunks(patch), &Options::default()).expect("failed to apply");
+        assert_eq!(output, b"one\ntwo\nTHREE\nfour\nfive\nsix\nseven\neight\n".to_vec());
+    }
+
+    #[test]
+    fn apply_text_tolerates_offsets() {
+        let patch = b"--- a/x\n+++ b/x\n@@ -1,3 +1,3 @@\n four\n-five\n+FIVE\n six\n";
+        let output = apply_text(ORIGINAL, &hunks(patch), &Options::default()).expect("failed to apply");
+        assert_eq!(output, b"one\ntwo\nthree\nfour\nFIVE\nsix\nseven\n".to_vec());
+    }
+
+    #[test]
+    fn apply_text_honors_whitespace_and_fuzz() {
+        let patch = b"--- a/x\n+++ b/x\n@@ -2,3 +2,3 @@\n  two\n-three\n+THREE\n changed\n";
+        assert!(apply_text(ORIGINAL, &hunks(patch), &Options::default()).is_none());
+
+        let options = Options { fuzz: 1, ..Options::default() };
+        assert!(apply_text(ORIGINAL, &hunks(patch), &options).is_some());
+
+        let patch = b"--- a/x\n+++ b/x\n@@ -2,3 +2,3 @@\n two  \n-three\n+THREE\n four\n";
+        assert!(apply_text(ORIGINAL, &hunks(patch), &Options::default()).is_none());
+        let options = Options { ignore_whitespace: true, ..Options::default() };
+        assert_eq!(
+            apply_text(ORIGINAL, &hunks(patch), &options).expect("failed to apply"),
+            b"one\ntwo\nTHREE\nfour\nfive\nsix\nseven\n".to_vec()
+        );
+    }
+
+    const WORKTREE_PATCH: &[u8] = b"diff --git a/README b/README
+--- a/README
++++ b/README
+@@ -1 +1 @@
+-hello
++goodbye
+diff --git a/run.sh b/run.sh
+old mode 100644
+new mode 100755
+diff --git a/old b/new
+similarity index 100%
+rename from old
+rename to new
+diff --git a/added b/added
+new file mode 100644
+--- /dev/null
++++ b/added
+@@ -0,0 +1 @@
++fresh
+";
+
+    #[test]
+    fn apply_worktree_works() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("patch-apply").expect("failed to create tempdir");
+        std::fs::write(dir.path().join("README"), "hello\n").unwrap();
+        std::fs::write(dir.path().join("run.sh"), "#!/bin/sh\n").unwrap();
+        std::fs::write(dir.path().join("old"), "moved\n").unwrap();
+        let files = parse(WORKTREE_PATCH).expect("failed to parse");
+
+        apply_worktree(dir.path(), &files, &Options { check: true, ..Options::default() }).expect("failed to check");
+        assert_eq!(std::fs::read_to_string(dir.path().join("README")).unwrap(), "hello\n");
+
+        apply_worktree(dir.path(), &files, &Options::default()).expect("failed to apply");
+        assert_eq!(std::fs::read_to_string(dir.path().join("README")).unwrap(), "goodbye\n");
+        assert_eq!(std::fs::read_to_string(dir.path().join("new")).unwrap(), "moved\n");
+        assert_eq!(std::fs::read_to_string(dir.path().join("added")).unwrap(), "fresh\n");
+        assert!(!dir.path().join("old").exists());
+        let mode = std::fs::metadata(dir.path().join("run.sh")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o100, 0o100);
+
+        // applying again fails on the first file and leaves the rest alone.
+        match apply_worktree(dir.path(), &files, &Options::default()) {
+            Err(e) => match e.kind() {
+                ErrorKind::PatchDoesNotApply(path) => assert_eq!(path, b"README"),
+                _ => panic!("unexpected error")
+            },
+            Ok(_) => panic!("expected failure")
+        }
+        assert_eq!(std::fs::read_to_string(dir.path().join("added")).unwrap(), "fresh\n");
+    }
+
+    #[test]
+    fn apply_worktree_stays_in_the_worktree() {
+        let dir = TempDir::new("patch-apply-unsafe").expect("failed to create tempdir");
+        let outside = TempDir::new("patch-apply-outside").expect("failed to create tempdir");
+        let worktree = dir.path().join("repo");
+        std::fs::create_dir(&worktree).unwrap();
+        for target in &["../../escaped", "/tmp/escaped", "sub/.git/hooks/pre-commit"] {
+            let patch = format!("diff --git a/x b/{0}\nnew file mode 100644\n--- /dev/null\n+++ b/{0}\n@@ -0,0 +1 @@\n+payload\n", target);
+            let files = parse(patch.as_bytes()).expect("failed to parse");
+            match apply_worktree(&worktree, &files, &Options::default()) {
+                Err(e) => match e.kind() {
+                    ErrorKind::UnsafePath(_) => (),
+                    xs => panic!("unexpected error {:?}", xs)
+                },
+                Ok(_) => panic!("expected unsafe path error")
+            }
+        }
+        assert!(!dir.path().join("escaped").exists());
+
+        // a symlink the patch makes, then a file through it.
+        let patch = format!(concat!(
+            "diff --git a/link b/link\nnew file mode 120000\n--- /dev/null\n+++ b/link\n@@ -0,0 +1 @@\n+{}\n\\ No newline at end of file\n",
+            "diff --git a/link/x b/link/x\nnew file mode 100644\n--- /dev/null\n+++ b/link/x\n@@ -0,0 +1 @@\n+payload\n"
+        ), outside.path().display());
+        let files = parse(patch.as_bytes()).expect("failed to parse");
+        match apply_worktree(&worktree, &files, &Options::default()) {
+            Err(e) => match e.kind() {
+                ErrorKind::BeyondSymlink(path) => assert_eq!(path, b"link/x"),
+                xs => panic!("unexpected error {:?}", xs)
+            },
+            Ok(_) => panic!("expected symlink error")
+        }
+        assert!(!outside.path().join("x").exists());
+    }
+
+    #[test]
+    fn apply_tree_works() {
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n", "run.sh" => "#!/bin/sh\n", "old" => "moved\n"]);
+        let tip = builder.tip().unwrap();
+        let (storage_set, _) = builder.in_memory();
+        let files = parse(WORKTREE_PATCH).expect("failed to parse");
+
+        let mut objects = MemoryStore::new();
+        let tree = apply_tree(&storage_set, &tip, &files, &Options::default(), &mut |typ, data| Ok(objects.put(typ, data)))
+            .expect("failed to apply");
+        let checked = apply_tree(&storage_set, &tip, &files, &Options { check: true, ..Options::default() }, &mut |_, _| {
+            panic!("check mode must not write")
+        }).expect("failed to check");
+        assert_eq!(tree, checked);
+
+        let written = crate::stores::StorageSet::new(objects);
+        let names: Vec<Vec<u8>> = match written.get_and_load(&tree).expect("failed to read") {
+            Some(Object::Tree(xs)) => xs.entries().keys().cloned().collect(),
+            _ => panic!("expected a tree")
+        };
+        assert_eq!(names, vec![b"README".to_vec(), b"added".to_vec(), b"new".to_vec(), b"run.sh".to_vec()]);
+    }
+
+    #[test]
+    fn creations_and_renames_refuse_existing_paths() {
+        const ADD: &[u8] = b"diff --git a/README b/README\nnew file mode 100644\n--- /dev/null\n+++ b/README\n@@ -0,0 +1 @@\n+clobbered\n";
+        const RENAME: &[u8] = b"diff --git a/old b/README\nsimilarity index 100%\nrename from old\nrename to README\n";
+        // the path is free again once an earlier file moves it away.
+        const SWAP: &[u8] = b"diff --git a/README b/kept\nsimilarity index 100%\nrename from README\nrename to kept\n\
+diff --git a/old b/README\nsimilarity index 100%\nrename from old\nrename to README\n";
+
+        let dir = TempDir::new("patch-apply-exists").expect("failed to create tempdir");
+        std::fs::write(dir.path().join("README"), "hello\n").unwrap();
+        std::fs::write(dir.path().join("old"), "moved\n").unwrap();
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n", "old" => "moved\n"]);
+        let tip = builder.tip().unwrap();
+        let (storage_set, _) = builder.in_memory();
+        let mut objects = MemoryStore::new();
+
+        for patch in &[ADD, RENAME] {
+            let files = parse(patch).expect("failed to parse");
+            match apply_worktree(dir.path(), &files, &Options::default()).map_err(|e| e.0) {
+                Err(ErrorKind::PatchTargetExists(path)) => assert_eq!(path, b"README"),
+                xs => panic!("unexpected result {:?}", xs)
+            }
+            match apply_tree(&storage_set, &tip, &files, &Options::default(), &mut |typ, data| Ok(objects.put(typ, data))).map_err(|e| e.0) {
+                Err(ErrorKind::PatchTargetExists(path)) => assert_eq!(path, b"README"),
+                xs => panic!("unexpected result {:?}", xs)
+            }
+        }
+        assert_eq!(std::fs::read_to_string(dir.path().join("README")).unwrap(), "hello\n");
+        assert!(dir.path().join("old").exists());
+
+        let files = parse(SWAP).expect("failed to parse");
+        apply_worktree(dir.path(), &files, &Options::default()).expect("failed to apply");
+        assert_eq!(std::fs::read_to_string(dir.path().join("README")).unwrap(), "moved\n");
+        assert_eq!(std::fs::read_to_string(dir.path().join("kept")).unwrap(), "hello\n");
+        apply_tree(&storage_set, &tip, &files, &Options::default(), &mut |typ, data| Ok(objects.put(typ, data))).expect("failed to apply");
+    }
+
+    #[test]
+    fn apply_index_changes_only_the_index() {
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n", "run.sh" => "#!/bin/sh\n", "old" => "moved\n"]);
+        let tip = builder.tip().unwrap();
+        let (storage_set, _) = builder.in_memory();
+        let files = parse(WORKTREE_PATCH).expect("failed to parse");
+
+        let mut index = Index::from_tree(&storage_set, &tip).expect("failed to read tree");
+        let mut objects = MemoryStore::new();
+        apply_index(&storage_set, &mut index, &files, &Options { check: true, ..Options::default() }, &mut |_, _| {
+            panic!("check mode must not write")
+        }).expect("failed to check");
+        assert!(index.get(b"added", 0).is_none());
+
+        apply_index(&storage_set, &mut index, &files, &Options::default(), &mut |typ, data| Ok(objects.put(typ, data)))
+            .expect("failed to apply");
+        let paths: Vec<&[u8]> = index.entries().iter().map(|xs| xs.path.as_slice()).collect();
+        assert_eq!(paths, vec![&b"README"[..], b"added", b"new", b"run.sh"]);
+        assert_eq!(index.get(b"run.sh", 0).unwrap().mode, FileMode::EXECUTABLE);
+        let written = crate::stores::StorageSet::new(objects);
+        match written.get_and_load(&index.get(b"README", 0).unwrap().id).expect("failed to read") {
+            Some(Object::Blob(xs)) => assert_eq!(xs.contents, b"goodbye\n"),
+            _ => panic!("expected a blob")
+        }
+
+        // "added" is staged now, so the patch no longer applies, and a
+        // failure leaves the index as it was.
+        let mut index = Index::from_tree(&storage_set, &tip).expect("failed to read tree");
+        let added = objects::hash(Type::Blob, b"x\n");
+        index.add(Entry::new(b"added".to_vec(), FileMode::FILE, added.clone()));
+        match apply_index(&storage_set, &mut index, &files, &Options::default(), &mut |typ, data| Ok(objects::hash(typ, &data))) {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::PatchTargetExists(_))),
+            Ok(_) => panic!("expected failure")
+        }
+        assert_eq!(index.get(b"README", 0).unwrap().id, Index::from_tree(&storage_set, &tip).unwrap().get(b"README", 0).unwrap().id);
+        assert_eq!(index.get(b"added", 0).unwrap().id, added);
+    }
+}
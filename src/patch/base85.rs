@@ -0,0 +1,73 @@
+// git's base85 alphabet, used for "GIT binary patch" bodies. It differs
+// from Ascii85 and RFC 1924 in its choice of punctuation.
+const ALPHABET: &[u8; 85] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz!#$%&()*+-;<=>?@^_`{|}~";
+
+fn value(byte: u8) -> Option<u32> {
+    ALPHABET.iter().position(|xs| *xs == byte).map(|xs| xs as u32)
+}
+
+// Decodes one line of a binary patch: a length byte ('A'..'Z' for 1..26,
+// 'a'..'z' for 27..52) followed by groups of five base85 digits.
+pub fn decode_line(line: &[u8]) -> Option<Vec<u8>> {
+    let (&length, digits) = line.split_first()?;
+    let length = match length {
+        b'A'..=b'Z' => (length - b'A') as usize + 1,
+        b'a'..=b'z' => (length - b'a') as usize + 27,
+        _ => return None
+    };
+
+    if digits.len() % 5 != 0 || digits.len() / 5 * 4 < length {
+        return None
+    }
+
+    let mut output = Vec::with_capacity(digits.len() / 5 * 4);
+    for group in digits.chunks(5) {
+        let mut acc: u64 = 0;
+        for digit in group {
+            acc = acc * 85 + u64::from(value(*digit)?);
+        }
+        if acc > u64::from(u32::MAX) {
+            return None
+        }
+        output.extend_from_slice(&(acc as u32).to_be_bytes());
+    }
+
+    output.truncate(length);
+    Some(output)
+}
+
+// The inverse of `decode_line`, for at most 52 bytes.
+pub fn encode_line(data: &[u8]) -> Vec<u8> {
+    let mut line = vec![if data.len() <= 26 { b'A' + data.len() as u8 - 1 } else { b'a' + data.len() as u8 - 27 }];
+    for group in data.chunks(4) {
+        let mut bytes = [0u8; 4];
+        bytes[..group.len()].copy_from_slice(group);
+        let mut acc = u32::from_be_bytes(bytes);
+        let mut digits = [0u8; 5];
+        for digit in digits.iter_mut().rev() {
+            *digit = ALPHABET[(acc % 85) as usize];
+            acc /= 85;
+        }
+        line.extend_from_slice(&digits);
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ decode_line, encode_line };
+
+    #[test]
+    fn roundtrip_works() {
+        for data in &[&b"a"[..], b"hello world", &[0xffu8; 52][..]] {
+            assert_eq!(decode_line(&encode_line(data)).as_deref(), Some(*data));
+        }
+    }
+
+    #[test]
+    fn decode_rejects_garbage() {
+        assert!(decode_line(b"B\"\"\"\"\"").is_none());
+        assert!(decode_line(b"Z0000").is_none());
+        assert!(decode_line(b"").is_none());
+    }
+}
@@ -0,0 +1,528 @@
+use std::io::Read;
+
+use flate2::read::ZlibDecoder;
+
+use crate::errors::{ ErrorKind, Result };
+use crate::objects::tree::FileMode;
+use crate::id::Id;
+
+pub mod base85;
+pub mod apply;
+pub mod write;
+
+// One line of a hunk, newline included unless the patch marked it with
+// "\ No newline at end of file".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Line {
+    Context(Vec<u8>),
+    Add(Vec<u8>),
+    Remove(Vec<u8>)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hunk {
+    pub old_start: usize,
+    pub old_count: usize,
+    pub new_start: usize,
+    pub new_count: usize,
+    pub lines: Vec<Line>
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Binary {
+    // the complete new contents
+    Literal(Vec<u8>),
+    // a delta against the old contents, in packfile delta format
+    Delta(Vec<u8>),
+    // "Binary files a/x and b/x differ": no data to apply
+    Unavailable
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Text(Vec<Hunk>),
+    Binary(Binary)
+}
+
+// The changes to a single file. A missing path is /dev/null: no old path for
+// an added file, no new path for a deleted one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilePatch {
+    pub old_path: Option<Vec<u8>>,
+    pub new_path: Option<Vec<u8>>,
+    pub old_mode: Option<FileMode>,
+    pub new_mode: Option<FileMode>,
+    pub is_rename: bool,
+    pub is_copy: bool,
+    // blob ids from a full "index" line; abbreviated ids are not kept.
+    pub old_id: Option<Id>,
+    pub new_id: Option<Id>,
+    pub change: Change
+}
+
+impl FilePatch {
+    fn new() -> FilePatch {
+        FilePatch {
+            old_path: None,
+            new_path: None,
+            old_mode: None,
+            new_mode: None,
+            is_rename: false,
+            is_copy: false,
+            old_id: None,
+            new_id: None,
+            change: Change::Text(Vec::new())
+        }
+    }
+
+    pub fn is_add(&self) -> bool {
+        self.old_path.is_none() && self.new_path.is_some()
+    }
+
+    pub fn is_delete(&self) -> bool {
+        self.new_path.is_none() && self.old_path.is_some()
+    }
+
+    // The path the patch is read against: the new path for additions.
+    pub fn path(&self) -> &[u8] {
+        self.old_path.as_ref().or(self.new_path.as_ref()).map(|xs| xs.as_slice()).unwrap_or(b"")
+    }
+}
+
+pub(crate) fn split_lines(input: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (idx, byte) in input.iter().enumerate() {
+        if *byte == b'\n' {
+            lines.push(&input[start..=idx]);
+            start = idx + 1;
+        }
+    }
+    if start < input.len() {
+        lines.push(&input[start..]);
+    }
+    lines
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+// Undoes git's C-style quoting of unusual paths.
+fn unquote(path: &[u8]) -> Vec<u8> {
+    if path.len() < 2 || path[0] != b'"' || path[path.len() - 1] != b'"' {
+        return path.to_vec()
+    }
+
+    let inner = &path[1..path.len() - 1];
+    let mut output = Vec::with_capacity(inner.len());
+    let mut idx = 0;
+    while idx < inner.len() {
+        if inner[idx] != b'\\' || idx + 1 == inner.len() {
+            output.push(inner[idx]);
+            idx += 1;
+            continue
+        }
+
+        idx += 1;
+        match inner[idx] {
+            b'n' => output.push(b'\n'),
+            b't' => output.push(b'\t'),
+            b'"' => output.push(b'"'),
+            b'\\' => output.push(b'\\'),
+            b'0'..=b'7' if idx + 2 < inner.len() => {
+                let octal = std::str::from_utf8(&inner[idx..idx + 3]).unwrap_or("");
+                match u8::from_str_radix(octal, 8) {
+                    Ok(xs) => {
+                        output.push(xs);
+                        idx += 2;
+                    },
+                    Err(_) => output.push(inner[idx])
+                }
+            },
+            other => output.push(other)
+        }
+        idx += 1;
+    }
+    output
+}
+
+// A `---`/`+++` path: /dev/null is None, and one leading component ("a/",
+// "b/") is stripped, as `git apply -p1` does.
+fn header_path(raw: &[u8]) -> Option<Vec<u8>> {
+    // a tab separates the path from an optional timestamp in plain diffs.
+    let raw = match raw.iter().position(|xs| *xs == b'\t') {
+        Some(idx) => &raw[..idx],
+        None => raw
+    };
+    let path = unquote(raw);
+    if path == b"/dev/null" {
+        return None
+    }
+    Some(strip_prefix(&path))
+}
+
+fn strip_prefix(path: &[u8]) -> Vec<u8> {
+    match path.iter().position(|xs| *xs == b'/') {
+        Some(idx) => path[idx + 1..].to_vec(),
+        None => path.to_vec()
+    }
+}
+
+// "diff --git a/x b/x": used for names when there are no ---/+++ lines, as
+// for pure mode changes and renames.
+fn git_header_paths(rest: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    if rest.starts_with(b"\"") {
+        let close = rest[1..].iter().position(|xs| *xs == b'"')? + 2;
+        let old = unquote(&rest[..close]);
+        let new = unquote(rest[close..].strip_prefix(b" ")?);
+        return Some((strip_prefix(&old), strip_prefix(&new)))
+    }
+
+    // without quoting the names are ambiguous; git assumes both are equal.
+    if rest.is_empty() {
+        return None
+    }
+    let half = (rest.len() - 1) / 2;
+    let (old, new) = (&rest[..half], &rest[half + 1..]);
+    if rest.get(half) == Some(&b' ') && strip_prefix(old) == strip_prefix(new) {
+        return Some((strip_prefix(old), strip_prefix(new)))
+    }
+    None
+}
+
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    match range.find(',') {
+        Some(idx) => Some((range[..idx].parse().ok()?, range[idx + 1..].parse().ok()?)),
+        None => Some((range.parse().ok()?, 1))
+    }
+}
+
+fn parse_hunk_header(line: &[u8]) -> Option<Hunk> {
+    let line = std::str::from_utf8(trim_newline(line)).ok()?;
+    let mut parts = line.strip_prefix("@@ -")?.splitn(2, " +");
+    let (old_start, old_count) = parse_range(parts.next()?)?;
+    let rest = parts.next()?;
+    let (new_start, new_count) = parse_range(&rest[..rest.find(" @@")?])?;
+    Some(Hunk {
+        old_start,
+        old_count,
+        new_start,
+        new_count,
+        lines: Vec::new()
+    })
+}
+
+fn parse_binary(lines: &[&[u8]], idx: &mut usize) -> Result<Binary> {
+    let header = match lines.get(*idx) {
+        Some(xs) => std::str::from_utf8(trim_newline(xs)).unwrap_or(""),
+        None => return Err(ErrorKind::BadPatch(*idx).into())
+    };
+    let (literal, size) = if let Some(size) = header.strip_prefix("literal ") {
+        (true, size)
+    } else if let Some(size) = header.strip_prefix("delta ") {
+        (false, size)
+    } else {
+        return Err(ErrorKind::BadPatch(*idx + 1).into())
+    };
+    let size: usize = match size.parse() {
+        Ok(xs) => xs,
+        Err(_) => return Err(ErrorKind::BadPatch(*idx + 1).into())
+    };
+    *idx += 1;
+
+    let mut compressed = Vec::new();
+    while let Some(line) = lines.get(*idx) {
+        let line = trim_newline(line);
+        *idx += 1;
+        if line.is_empty() {
+            break
+        }
+        match base85::decode_line(line) {
+            Some(xs) => compressed.extend_from_slice(&xs),
+            None => return Err(ErrorKind::BadPatch(*idx).into())
+        }
+    }
+
+    let mut data = Vec::with_capacity(size);
+    ZlibDecoder::new(&compressed[..]).read_to_end(&mut data)?;
+    if data.len() != size {
+        return Err(ErrorKind::BadPatch(*idx).into())
+    }
+
+    Ok(if literal { Binary::Literal(data) } else { Binary::Delta(data) })
+}
+
+// Parses the output of `git diff` (or any unified diff) into per-file
+// patches. Text outside of file sections, such as a commit message, is
+// skipped.
+pub fn parse(input: &[u8]) -> Result<Vec<FilePatch>> {
+    let lines = split_lines(input);
+    let mut files: Vec<FilePatch> = Vec::new();
+    let mut current: Option<FilePatch> = None;
+    let mut idx = 0;
+
+    while idx < lines.len() {
+        let line = lines[idx];
+        let text = trim_newline(line);
+        idx += 1;
+
+        if let Some(rest) = text.strip_prefix(b"diff --git ") {
+            files.extend(current.take());
+            let mut file = FilePatch::new();
+            if let Some((old, new)) = git_header_paths(rest) {
+                file.old_path = Some(old);
+                file.new_path = Some(new);
+            }
+            current = Some(file);
+            continue
+        }
+
+        if text.starts_with(b"--- ") && lines.get(idx).is_some_and(|xs| xs.starts_with(b"+++ ")) {
+            // a plain unified diff has no "diff --git" line to start a file.
+            let has_hunks = match current {
+                Some(FilePatch { change: Change::Text(ref hunks), .. }) => !hunks.is_empty(),
+                Some(_) => true,
+                None => true
+            };
+            if has_hunks {
+                files.extend(current.take());
+                current = Some(FilePatch::new());
+            }
+
+            let file = current.as_mut().unwrap();
+            file.old_path = header_path(&text[4..]);
+            file.new_path = header_path(&trim_newline(lines[idx])[4..]);
+            idx += 1;
+            continue
+        }
+
+        let file = match current.as_mut() {
+            Some(xs) => xs,
+            None => continue
+        };
+
+        if text.starts_with(b"@@ -") {
+            let mut hunk = match parse_hunk_header(text) {
+                Some(xs) => xs,
+                None => return Err(ErrorKind::BadPatch(idx).into())
+            };
+
+            let (mut old_left, mut new_left) = (hunk.old_count, hunk.new_count);
+            while old_left > 0 || new_left > 0 {
+                let body = match lines.get(idx) {
+                    Some(xs) => *xs,
+                    None => return Err(ErrorKind::BadPatch(idx).into())
+                };
+                idx += 1;
+
+                match body.first() {
+                    Some(b' ') if old_left > 0 && new_left > 0 => {
+                        hunk.lines.push(Line::Context(body[1..].to_vec()));
+                        old_left -= 1;
+                        new_left -= 1;
+                    },
+                    // editors sometimes strip the lone space of an empty context line.
+                    Some(b'\n') if old_left > 0 && new_left > 0 => {
+                        hunk.lines.push(Line::Context(b"\n".to_vec()));
+                        old_left -= 1;
+                        new_left -= 1;
+                    },
+                    Some(b'-') if old_left > 0 => {
+                        hunk.lines.push(Line::Remove(body[1..].to_vec()));
+                        old_left -= 1;
+                    },
+                    Some(b'+') if new_left > 0 => {
+                        hunk.lines.push(Line::Add(body[1..].to_vec()));
+                        new_left -= 1;
+                    },
+                    Some(b'\\') => strip_last_newline(&mut hunk),
+                    _ => return Err(ErrorKind::BadPatch(idx).into())
+                }
+            }
+
+            if lines.get(idx).is_some_and(|xs| xs.starts_with(b"\\")) {
+                strip_last_newline(&mut hunk);
+                idx += 1;
+            }
+
+            match file.change {
+                Change::Text(ref mut hunks) => hunks.push(hunk),
+                Change::Binary(_) => return Err(ErrorKind::BadPatch(idx).into())
+            }
+            continue
+        }
+
+        if text == b"GIT binary patch" {
+            let forward = parse_binary(&lines, &mut idx)?;
+            // the reverse section, if present, is only needed for `-R`.
+            if lines.get(idx).is_some_and(|xs| xs.starts_with(b"literal ") || xs.starts_with(b"delta ")) {
+                parse_binary(&lines, &mut idx)?;
+            }
+            file.change = Change::Binary(forward);
+            continue
+        }
+
+        if text.starts_with(b"Binary files ") && text.ends_with(b" differ") {
+            file.change = Change::Binary(Binary::Unavailable);
+            continue
+        }
+
+        let text = match std::str::from_utf8(text) {
+            Ok(xs) => xs,
+            Err(_) => continue
+        };
+        let mode = |xs: &str| FileMode::from_octal(xs.trim());
+        if let Some(xs) = text.strip_prefix("old mode ") {
+            file.old_mode = mode(xs);
+        } else if let Some(xs) = text.strip_prefix("new mode ") {
+            file.new_mode = mode(xs);
+        } else if let Some(xs) = text.strip_prefix("deleted file mode ") {
+            file.old_mode = mode(xs);
+            file.new_path = None;
+        } else if let Some(xs) = text.strip_prefix("new file mode ") {
+            file.new_mode = mode(xs);
+            file.old_path = None;
+        } else if let Some(xs) = text.strip_prefix("rename from ") {
+            file.is_rename = true;
+            file.old_path = Some(unquote(xs.as_bytes()));
+        } else if let Some(xs) = text.strip_prefix("rename to ") {
+            file.is_rename = true;
+            file.new_path = Some(unquote(xs.as_bytes()));
+        } else if let Some(xs) = text.strip_prefix("copy from ") {
+            file.is_copy = true;
+            file.old_path = Some(unquote(xs.as_bytes()));
+        } else if let Some(xs) = text.strip_prefix("copy to ") {
+            file.is_copy = true;
+            file.new_path = Some(unquote(xs.as_bytes()));
+        } else if let Some(xs) = text.strip_prefix("index ") {
+            // "index <old>..<new> <mode>" carries the mode of unchanged-mode files.
+            if let Some((old, new)) = xs.split(' ').next().and_then(|xs| xs.split_once("..")) {
+                file.old_id = full_id(old);
+                file.new_id = full_id(new);
+            }
+            if let Some(xs) = xs.split(' ').nth(1) {
+                if file.old_mode.is_none() && file.new_mode.is_none() {
+                    file.old_mode = mode(xs);
+                    file.new_mode = mode(xs);
+                }
+            }
+        }
+    }
+
+    files.extend(current.take());
+    Ok(files)
+}
+
+// The all-zero id stands for a missing side.
+fn full_id(hex: &str) -> Option<Id> {
+    if hex.len() != 40 || hex.bytes().all(|xs| xs == b'0') {
+        return None
+    }
+    hex.parse().ok()
+}
+
+fn strip_last_newline(hunk: &mut Hunk) {
+    let line = match hunk.lines.last_mut() {
+        Some(Line::Context(xs)) | Some(Line::Add(xs)) | Some(Line::Remove(xs)) => xs,
+        None => return
+    };
+    if line.ends_with(b"\n") {
+        line.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::objects::tree::FileMode;
+    use super::{ parse, Binary, Change, Line };
+
+    const PATCH: &[u8] = b"From 1234 Mon Sep 17 00:00:00 2001
+Subject: [PATCH] example
+
+---
+diff --git a/README b/README
+index 3b18e51..a042389 100644
+--- a/README
++++ b/README
+@@ -1,3 +1,3 @@ heading
+ hello
+-world
++there
+ end
+\\ No newline at end of file
+diff --git a/run.sh b/run.sh
+old mode 100644
+new mode 100755
+diff --git a/old.txt b/new.txt
+similarity index 100%
+rename from old.txt
+rename to new.txt
+diff --git a/gone b/gone
+deleted file mode 100644
+index 3b18e51..0000000
+--- a/gone
++++ /dev/null
+@@ -1 +0,0 @@
+-bye
+";
+
+    #[test]
+    fn parse_works() {
+        let files = parse(PATCH).expect("failed to parse");
+        assert_eq!(files.len(), 4);
+
+        assert_eq!(files[0].old_path.as_deref(), Some(&b"README"[..]));
+        assert_eq!(files[0].new_mode, Some(FileMode::FILE));
+        match files[0].change {
+            Change::Text(ref hunks) => {
+                assert_eq!(hunks.len(), 1);
+                assert_eq!(hunks[0].lines, vec![
+                    Line::Context(b"hello\n".to_vec()),
+                    Line::Remove(b"world\n".to_vec()),
+                    Line::Add(b"there\n".to_vec()),
+                    Line::Context(b"end".to_vec())
+                ]);
+            },
+            _ => panic!("expected a text change")
+        }
+
+        assert_eq!(files[1].path(), b"run.sh");
+        assert_eq!(files[1].new_mode, Some(FileMode::EXECUTABLE));
+
+        assert!(files[2].is_rename);
+        assert_eq!(files[2].old_path.as_deref(), Some(&b"old.txt"[..]));
+        assert_eq!(files[2].new_path.as_deref(), Some(&b"new.txt"[..]));
+
+        assert!(files[3].is_delete());
+    }
+
+    #[test]
+    fn parse_binary_works() {
+        use std::io::Write;
+        use flate2::write::ZlibEncoder;
+        use flate2::Compression;
+        use super::base85::encode_line;
+
+        let contents = b"\x00\x01binary\xff";
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(contents).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut patch = b"diff --git a/img b/img\nnew file mode 100644\nindex 0000000..1111111\nGIT binary patch\n".to_vec();
+        patch.extend_from_slice(format!("literal {}\n", contents.len()).as_bytes());
+        patch.extend_from_slice(&encode_line(&compressed));
+        patch.extend_from_slice(b"\n\nliteral 0\nHcmV?d00001\n\n");
+
+        let files = parse(&patch).expect("failed to parse");
+        assert_eq!(files.len(), 1);
+        assert!(files[0].is_add());
+        assert_eq!(files[0].change, Change::Binary(Binary::Literal(contents.to_vec())));
+    }
+
+    #[test]
+    fn parse_rejects_truncated_hunks() {
+        assert!(parse(b"--- a/x\n+++ b/x\n@@ -1,2 +1,2 @@\n a\n").is_err());
+    }
+}
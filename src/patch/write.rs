@@ -0,0 +1,209 @@
+use std::io::Write;
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use super::{ base85, Binary, Change, FilePatch, Hunk, Line };
+use crate::abbrev;
+
+// git's C-style quoting, applied when a path has control characters, quotes
+// or non-ASCII bytes. The prefix ("a/", "b/") goes inside the quotes.
+fn quote(prefix: &[u8], path: &[u8]) -> Vec<u8> {
+    let needs_quoting = path.iter().any(|xs| *xs < 0x20 || *xs >= 0x7f || *xs == b'"' || *xs == b'\\');
+    if !needs_quoting {
+        return [prefix, path].concat()
+    }
+
+    let mut output = vec![b'"'];
+    output.extend_from_slice(prefix);
+    for byte in path {
+        match *byte {
+            b'\n' => output.extend_from_slice(b"\\n"),
+            b'\t' => output.extend_from_slice(b"\\t"),
+            b'"' => output.extend_from_slice(b"\\\""),
+            b'\\' => output.extend_from_slice(b"\\\\"),
+            xs if !(0x20..0x7f).contains(&xs) => output.extend_from_slice(format!("\\{:03o}", xs).as_bytes()),
+            xs => output.push(xs)
+        }
+    }
+    output.push(b'"');
+    output
+}
+
+fn side(prefix: &[u8], path: Option<&Vec<u8>>) -> Vec<u8> {
+    match path {
+        Some(xs) => quote(prefix, xs),
+        None => b"/dev/null".to_vec()
+    }
+}
+
+fn range(start: usize, count: usize) -> String {
+    if count == 1 {
+        start.to_string()
+    } else {
+        format!("{},{}", start, count)
+    }
+}
+
+fn write_hunk<W: Write>(output: &mut W, hunk: &Hunk) -> std::io::Result<()> {
+    writeln!(output, "@@ -{} +{} @@", range(hunk.old_start, hunk.old_count), range(hunk.new_start, hunk.new_count))?;
+    for line in &hunk.lines {
+        let (marker, text) = match line {
+            Line::Context(xs) => (b' ', xs),
+            Line::Add(xs) => (b'+', xs),
+            Line::Remove(xs) => (b'-', xs)
+        };
+        output.write_all(&[marker])?;
+        output.write_all(text)?;
+        if !text.ends_with(b"\n") {
+            output.write_all(b"\n\\ No newline at end of file\n")?;
+        }
+    }
+    Ok(())
+}
+
+fn write_binary<W: Write>(output: &mut W, kind: &str, data: &[u8]) -> std::io::Result<()> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+
+    writeln!(output, "{} {}", kind, data.len())?;
+    for chunk in compressed.chunks(52) {
+        output.write_all(&base85::encode_line(chunk))?;
+        output.write_all(b"\n")?;
+    }
+    output.write_all(b"\n")
+}
+
+// Writes one file's patch in `git diff` format; `parse` reads it back.
+// Ids are abbreviated to git's default; `write_abbrev` with
+// `abbrev::length` follows a repository's core.abbrev.
+pub fn write<W: Write>(output: &mut W, file: &FilePatch) -> std::io::Result<()> {
+    write_abbrev(output, file, abbrev::DEFAULT)
+}
+
+// Like `write`, with `abbrev` hex digits of the ids on the "index" line.
+pub fn write_abbrev<W: Write>(output: &mut W, file: &FilePatch, abbrev: usize) -> std::io::Result<()> {
+    let old_name = file.old_path.as_ref().or(file.new_path.as_ref());
+    let new_name = file.new_path.as_ref().or(file.old_path.as_ref());
+    output.write_all(b"diff --git ")?;
+    output.write_all(&side(b"a/", old_name))?;
+    output.write_all(b" ")?;
+    output.write_all(&side(b"b/", new_name))?;
+    output.write_all(b"\n")?;
+
+    let same_mode = match (file.old_mode, file.new_mode) {
+        (Some(old), Some(new)) if old != new => {
+            writeln!(output, "old mode {:o}\nnew mode {:o}", old.bits(), new.bits())?;
+            None
+        },
+        (Some(xs), Some(_)) => Some(xs),
+        _ => None
+    };
+    if file.is_add() {
+        if let Some(mode) = file.new_mode {
+            writeln!(output, "new file mode {:o}", mode.bits())?;
+        }
+    } else if file.is_delete() {
+        if let Some(mode) = file.old_mode {
+            writeln!(output, "deleted file mode {:o}", mode.bits())?;
+        }
+    }
+
+    if file.is_rename || file.is_copy {
+        let verb = if file.is_rename { "rename" } else { "copy" };
+        for (direction, path) in &[("from", &file.old_path), ("to", &file.new_path)] {
+            if let Some(path) = path {
+                write!(output, "{} {} ", verb, direction)?;
+                output.write_all(&quote(b"", path))?;
+                output.write_all(b"\n")?;
+            }
+        }
+    }
+
+    let is_binary = matches!(file.change, Change::Binary(_));
+    if file.old_id.is_some() || file.new_id.is_some() {
+        // binary patches need the full ids to be applied by git.
+        let hex = |id: &Option<crate::id::Id>| {
+            let full = id.as_ref().map(|xs| xs.to_string()).unwrap_or_else(|| "0".repeat(40));
+            if is_binary { full } else { full[..abbrev.min(40)].to_string() }
+        };
+        write!(output, "index {}..{}", hex(&file.old_id), hex(&file.new_id))?;
+        match same_mode {
+            Some(mode) => writeln!(output, " {:o}", mode.bits())?,
+            None => writeln!(output)?
+        }
+    }
+
+    match file.change {
+        Change::Text(ref hunks) => {
+            if hunks.is_empty() {
+                return Ok(())
+            }
+            output.write_all(b"--- ")?;
+            output.write_all(&side(b"a/", file.old_path.as_ref()))?;
+            output.write_all(b"\n+++ ")?;
+            output.write_all(&side(b"b/", file.new_path.as_ref()))?;
+            output.write_all(b"\n")?;
+            for hunk in hunks {
+                write_hunk(output, hunk)?;
+            }
+        },
+        Change::Binary(Binary::Unavailable) => {
+            output.write_all(b"Binary files ")?;
+            output.write_all(&side(b"a/", file.old_path.as_ref()))?;
+            output.write_all(b" and ")?;
+            output.write_all(&side(b"b/", file.new_path.as_ref()))?;
+            output.write_all(b" differ\n")?;
+        },
+        Change::Binary(Binary::Literal(ref data)) => {
+            output.write_all(b"GIT binary patch\n")?;
+            write_binary(output, "literal", data)?;
+        },
+        Change::Binary(Binary::Delta(ref data)) => {
+            output.write_all(b"GIT binary patch\n")?;
+            write_binary(output, "delta", data)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diff::{ diff_trees, Options };
+    use crate::testkit::RepoBuilder;
+    use crate::files;
+    use super::super::{ parse, Change };
+    use super::write;
+
+    #[test]
+    fn written_patches_parse_back() {
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\nworld", "gone" => "bye\n", "caf\u{e9}" => "\0\x01"]);
+        let first = builder.tip().unwrap();
+        let builder = builder.commit("second", files!["README" => "hello\nthere\n", "new" => "fresh\n", "caf\u{e9}" => "\0\x02"]);
+        let builder = builder.remove("third", &["gone"]);
+        let third = builder.tip().unwrap();
+        let (storage_set, _) = builder.in_memory();
+
+        let options = Options { binary: true, ..Options::default() };
+        let files = diff_trees(&storage_set, Some(&first), Some(&third), &options).expect("failed to diff");
+        let mut output = Vec::new();
+        for file in &files {
+            write(&mut output, file).expect("failed to write");
+        }
+
+        let text = String::from_utf8_lossy(&output);
+        assert!(text.contains("\\ No newline at end of file\n"));
+        assert!(text.contains("diff --git \"a/caf\\303\\251\" \"b/caf\\303\\251\"\n"));
+        // text patches carry abbreviated ids, which are not parsed back.
+        let expected: Vec<_> = files.into_iter().map(|mut file| {
+            if let Change::Text(_) = file.change {
+                file.old_id = None;
+                file.new_id = None;
+            }
+            file
+        }).collect();
+        assert_eq!(parse(&output).expect("failed to parse"), expected);
+    }
+}
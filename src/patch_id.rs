@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+
+use crypto::{ sha1::Sha1, digest::Digest };
+
+use crate::stores::{ Queryable, StorageSet };
+use crate::errors::{ ErrorKind, Result };
+use crate::patch::write::write_abbrev;
+use crate::diff::{ self, diff_trees };
+use crate::format_patch::range;
+use crate::patch::FilePatch;
+use crate::objects::Object;
+use crate::id::Id;
+
+// The count a side of a hunk header gives; "-5" alone means one line.
+fn hunk_count(range: &[u8]) -> Option<usize> {
+    let count = match range.iter().position(|xs| *xs == b',') {
+        Some(xs) => &range[xs + 1..],
+        None => return Some(1)
+    };
+    std::str::from_utf8(count).ok()?.parse().ok()
+}
+
+// The (old, new) line counts of "@@ -a,b +c,d @@".
+fn scan_hunk_header(line: &[u8]) -> Option<(usize, usize)> {
+    let mut fields = line.split(|xs| *xs == b' ').skip(1);
+    let old = fields.next()?.strip_prefix(b"-")?;
+    let new = fields.next()?.strip_prefix(b"+")?;
+    Some((hunk_count(old)?, hunk_count(new)?))
+}
+
+// Each file is hashed on its own and the hashes summed, so the id doesn't
+// depend on the order the files come in.
+fn flush(result: &mut [u8; 20], hash: &mut Sha1) {
+    let mut digest = [0u8; 20];
+    hash.result(&mut digest);
+    hash.reset();
+    let mut carry: u16 = 0;
+    for (sum, byte) in result.iter_mut().zip(digest.iter()) {
+        carry += *sum as u16 + *byte as u16;
+        *sum = carry as u8;
+        carry >>= 8;
+    }
+}
+
+// `git patch-id --stable` of one patch in `git diff` format; anything before
+// the first "diff " line (a commit message, say) is skipped. Whitespace and
+// line numbers don't count, so the same change made on another base or
+// reindented keeps its id. None if there is no diff.
+pub fn from_text(input: &[u8]) -> Option<Id> {
+    let mut result = [0u8; 20];
+    let mut hash = Sha1::new();
+    let mut hashed = 0;
+    // -1 while reading a file's header, then the lines left in the hunk.
+    let (mut before, mut after): (isize, isize) = (-1, -1);
+    let (mut pre, mut post): (&[u8], &[u8]) = (b"", b"");
+    let mut is_binary = false;
+
+    for line in input.split_inclusive(|xs| *xs == b'\n') {
+        if hashed == 0 && !line.starts_with(b"diff ") {
+            continue
+        }
+
+        if before == -1 {
+            if line.starts_with(b"GIT binary patch") || line.starts_with(b"Binary files") {
+                is_binary = true;
+                before = 0;
+                hash.input(pre);
+                hash.input(post);
+                flush(&mut result, &mut hash);
+                continue
+            } else if let Some(ids) = line.strip_prefix(b"index ") {
+                let ids = ids.split(|xs| *xs == b' ' || *xs == b'\n').next().unwrap_or(b"");
+                if let Some(split) = ids.windows(2).position(|xs| xs == b"..") {
+                    pre = &ids[..split];
+                    post = &ids[split + 2..];
+                }
+                continue
+            } else if line.starts_with(b"--- ") {
+                before = 1;
+                after = 1;
+            } else if !line.first().is_some_and(|xs| xs.is_ascii_alphabetic()) {
+                break
+            }
+        }
+
+        if is_binary {
+            if line.starts_with(b"diff ") {
+                is_binary = false;
+                before = -1;
+            }
+            continue
+        }
+
+        if before == 0 && after == 0 {
+            if line.starts_with(b"@@ -") {
+                match scan_hunk_header(line) {
+                    Some((old, new)) => {
+                        before = old as isize;
+                        after = new as isize;
+                    },
+                    None => break
+                }
+                continue
+            }
+            // the end of the diff, unless another file starts.
+            if !line.starts_with(b"diff ") {
+                break
+            }
+            flush(&mut result, &mut hash);
+            before = -1;
+            after = -1;
+        }
+
+        match line.first() {
+            Some(b'-') => before -= 1,
+            Some(b'+') => after -= 1,
+            Some(b' ') => {
+                before -= 1;
+                after -= 1;
+            },
+            Some(b'\\') => continue,
+            _ => ()
+        }
+        let stripped: Vec<u8> = line.iter().cloned().filter(|xs| !xs.is_ascii_whitespace() && *xs != 0x0b).collect();
+        hashed += stripped.len();
+        hash.input(&stripped);
+    }
+
+    if hashed == 0 {
+        return None
+    }
+    flush(&mut result, &mut hash);
+    Some(result.into())
+}
+
+// The patch-id of a diff, as `from_text` reads it written out with full ids.
+pub fn for_files(files: &[FilePatch]) -> Result<Option<Id>> {
+    let mut text = Vec::new();
+    for file in files {
+        write_abbrev(&mut text, file, 40)?;
+    }
+    Ok(from_text(&text))
+}
+
+// The patch-id of a commit's change against its first parent. Merges have
+// none, as with `git cherry`.
+pub fn for_commit<S: Queryable>(storage_set: &StorageSet<S>, id: &Id) -> Result<Option<Id>> {
+    let commit = match storage_set.get_and_load(id)? {
+        Some(Object::Commit(xs)) => xs,
+        _ => return Err(ErrorKind::MissingObject.into())
+    };
+    let parents = commit.parents().unwrap_or_default();
+    if parents.len() > 1 {
+        return Ok(None)
+    }
+    let parent_tree = match parents.first() {
+        Some(xs) => match storage_set.get_and_load(xs)? {
+            Some(Object::Commit(parent)) => parent.tree(),
+            _ => return Err(ErrorKind::MissingObject.into())
+        },
+        None => None
+    };
+    let files = diff_trees(storage_set, parent_tree.as_ref(), commit.tree().as_ref(), &diff::Options::default())?;
+    for_files(&files)
+}
+
+// A commit of `head` and, if it has one, the commit of `upstream` making
+// the same change.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cherry {
+    pub id: Id,
+    pub upstream: Option<Id>
+}
+
+// `git cherry upstream head`: each commit reachable from `head` but not
+// `upstream`, oldest first, matched by patch-id against the commits
+// `upstream` has that `head` doesn't. Those with no match still need
+// picking; merges are left out.
+pub fn cherry<S: Queryable>(storage_set: &StorageSet<S>, upstream: &Id, head: &Id) -> Result<Vec<Cherry>> {
+    let mut applied = HashMap::new();
+    for id in range(storage_set, Some(head), upstream)? {
+        if let Some(patch_id) = for_commit(storage_set, &id)? {
+            applied.entry(patch_id).or_insert(id);
+        }
+    }
+
+    let mut result = Vec::new();
+    for id in range(storage_set, Some(upstream), head)? {
+        let upstream = match for_commit(storage_set, &id)? {
+            Some(ref xs) => applied.get(xs).cloned(),
+            None => None
+        };
+        result.push(Cherry { id, upstream });
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::testkit::RepoBuilder;
+    use crate::id::Id;
+    use crate::files;
+    use super::{ cherry, for_commit, from_text, Cherry };
+
+    #[test]
+    fn matches_git_patch_id_stable() {
+        let patch = b"From 1 Mon Sep 17 00:00:00 2001\nSubject: x\n\n---\n a | 2 +-\n\n\
+            diff --git a/a b/a\nindex 7898192..6178079 100644\n--- a/a\n+++ b/a\n@@ -1 +1 @@\n-a\n+b\n\
+            diff --git a/b b/b\nnew file mode 100644\nindex 0000000..e69de29\n--- /dev/null\n+++ b/b\n@@ -0,0 +1 @@\n+x\n";
+        // from `git patch-id --stable` on the same input.
+        let expected = Id::from_str("748423e66d8b1333455335b0418d98c6b86879e8").unwrap();
+        assert_eq!(from_text(patch), Some(expected.clone()));
+
+        // whitespace, line numbers and file order don't matter.
+        let moved = b"diff --git a/b b/b\nnew file mode 100644\nindex 0000000..e69de29\n--- /dev/null\n+++ b/b\n@@ -0,0 +1,1 @@\n+ x\n\
+            diff --git a/a b/a\nindex 1111111..2222222 100644\n--- a/a\n+++ b/a\n@@ -10 +10 @@\n- a\n+\tb\n-- \n2.40.0\n";
+        assert_eq!(from_text(moved), Some(expected));
+        assert_eq!(from_text(b"no diff here\n"), None);
+    }
+
+    #[test]
+    fn cherry_finds_changes_already_upstream() {
+        let builder = RepoBuilder::new()
+            .commit("base", files!["README" => "a\nb\nc\n", "NOTES" => "x\n"]);
+        let builder = builder.branch("topic")
+            .commit("fix b", files!["README" => "a\nB\nc\n", "NOTES" => "x\n"]);
+        let upstream = builder.tip().unwrap();
+        let builder = builder.checkout("topic")
+            .commit("notes", files!["README" => "a\nb\nc\n", "NOTES" => "y\n"]);
+        let notes = builder.tip().unwrap();
+        let builder = builder.commit("fix b too", files!["README" => "a\nB\nc\n", "NOTES" => "y\n"]);
+        let picked = builder.tip().unwrap();
+        let (storage_set, _) = builder.in_memory();
+
+        assert_eq!(for_commit(&storage_set, &upstream).unwrap(), for_commit(&storage_set, &picked).unwrap());
+        assert_eq!(cherry(&storage_set, &upstream, &picked).expect("failed to cherry"), vec![
+            Cherry { id: notes, upstream: None },
+            Cherry { id: picked, upstream: Some(upstream) }
+        ]);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::errors::{ ErrorKind, Result };
+use crate::attributes::{ fnmatch, wildmatch };
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Item {
+    pattern: Vec<u8>,
+    // how much of the pattern comes before its first wildcard; all of it
+    // for literal patterns.
+    literal_len: usize,
+    glob: bool,
+    icase: bool,
+    exclude: bool
+}
+
+fn is_wildcard(byte: u8) -> bool {
+    matches!(byte, b'*' | b'?' | b'[' | b'\\')
+}
+
+fn fold(bytes: &[u8], icase: bool) -> Vec<u8> {
+    if icase { bytes.to_ascii_lowercase() } else { bytes.to_vec() }
+}
+
+impl Item {
+    fn parse(spec: &str) -> Result<Item> {
+        let mut item = Item::default();
+        let mut literal = false;
+        let mut rest = spec;
+        if let Some(magic) = spec.strip_prefix(":(") {
+            let end = match magic.find(')') {
+                Some(xs) => xs,
+                None => return Err(ErrorKind::BadPathspec(String::from(spec)).into())
+            };
+            for word in magic[..end].split(',').map(str::trim) {
+                match word {
+                    "top" | "" => (),
+                    "literal" => literal = true,
+                    "glob" => item.glob = true,
+                    "icase" => item.icase = true,
+                    "exclude" => item.exclude = true,
+                    _ => return Err(ErrorKind::BadPathspec(String::from(spec)).into())
+                }
+            }
+            rest = &magic[end + 1..];
+        } else if let Some(short) = spec.strip_prefix(':') {
+            // short magic: ":/" (top), ":!" or ":^" (exclude), up to an
+            // optional ":" ending the magic.
+            let end = short.find(|xs| !matches!(xs, '/' | '!' | '^')).unwrap_or(short.len());
+            item.exclude = short[..end].contains(['!', '^']);
+            rest = &short[end..];
+            rest = rest.strip_prefix(':').unwrap_or(rest);
+        }
+        if literal && item.glob {
+            return Err(ErrorKind::BadPathspec(String::from(spec)).into())
+        }
+
+        let pattern = rest.trim_end_matches('/');
+        item.pattern = fold(pattern.as_bytes(), item.icase);
+        item.literal_len = if literal {
+            item.pattern.len()
+        } else {
+            item.pattern.iter().position(|xs| is_wildcard(*xs)).unwrap_or(item.pattern.len())
+        };
+        Ok(item)
+    }
+
+    fn is_literal(&self) -> bool {
+        self.literal_len == self.pattern.len()
+    }
+
+    fn matches(&self, path: &[u8]) -> bool {
+        let path = fold(path, self.icase);
+        let prefix = &self.pattern[..self.literal_len];
+        if !path.starts_with(prefix) {
+            return false
+        }
+        // a literal pattern names the path, or a directory it is under.
+        if self.is_literal() {
+            return prefix.is_empty() || path.len() == prefix.len() || path[prefix.len()] == b'/'
+        }
+        if self.glob { wildmatch(&self.pattern, &path) } else { fnmatch(&self.pattern, &path) }
+    }
+
+    // Whether anything under directory `dir` could match.
+    fn may_match_under(&self, dir: &[u8]) -> bool {
+        let mut dir = fold(dir, self.icase);
+        dir.push(b'/');
+        let prefix = &self.pattern[..self.literal_len];
+        if self.is_literal() {
+            let mut named = prefix.to_vec();
+            named.push(b'/');
+            return dir.starts_with(&named) || prefix.starts_with(&dir[..]) || prefix.is_empty()
+        }
+        dir.starts_with(prefix) || prefix.starts_with(&dir[..])
+    }
+}
+
+// A list of git pathspecs: "dir" is that path and everything under it,
+// "*.c" matches across directories unless `:(glob)` makes wildcards stop at
+// slashes, `:(literal)` turns wildcards off, `:(icase)` ignores ASCII case
+// and `:(exclude)` (or ":!") takes paths back out. An empty list matches
+// everything.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Pathspec {
+    items: Vec<Item>
+}
+
+impl Pathspec {
+    pub fn new() -> Pathspec {
+        Pathspec::default()
+    }
+
+    pub fn parse<S: AsRef<str>>(specs: &[S]) -> Result<Pathspec> {
+        let items = specs.iter().map(|xs| Item::parse(xs.as_ref())).collect::<Result<Vec<_>>>()?;
+        Ok(Pathspec { items })
+    }
+
+    // Every path matched as is, wildcards and all.
+    pub fn literal<P: AsRef<[u8]>>(paths: &[P]) -> Pathspec {
+        let items = paths.iter().map(|xs| {
+            let xs = xs.as_ref();
+            let pattern = xs.strip_suffix(b"/").unwrap_or(xs).to_vec();
+            Item { literal_len: pattern.len(), pattern, ..Item::default() }
+        }).collect();
+        Pathspec { items }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    fn includes(&self) -> impl Iterator<Item = &Item> {
+        self.items.iter().filter(|xs| !xs.exclude)
+    }
+
+    pub fn matches(&self, path: &[u8]) -> bool {
+        let mut includes = self.includes().peekable();
+        let included = includes.peek().is_none() || includes.any(|xs| xs.matches(path));
+        included && !self.items.iter().any(|xs| xs.exclude && xs.matches(path))
+    }
+
+    // Whether a walk has to descend into directory `dir` to find matches.
+    pub fn may_match_under(&self, dir: &[u8]) -> bool {
+        if self.items.iter().any(|xs| xs.exclude && xs.is_literal() && xs.matches(dir)) {
+            return false
+        }
+        let mut includes = self.includes().peekable();
+        includes.peek().is_none() || includes.any(|xs| xs.may_match_under(dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::ErrorKind;
+    use super::Pathspec;
+
+    #[test]
+    fn matches_git_pathspec_semantics() {
+        let spec = Pathspec::parse(&["src", "*.md", ":(glob)tests/*.rs", ":!src/vendor", ":(icase)LICENSE"]).unwrap();
+        assert!(spec.matches(b"src/lib.rs"));
+        assert!(spec.matches(b"src"));
+        assert!(!spec.matches(b"srcs/lib.rs"));
+        // without glob magic, "*" crosses directories.
+        assert!(spec.matches(b"docs/guide/intro.md"));
+        assert!(spec.matches(b"tests/merge.rs"));
+        assert!(!spec.matches(b"tests/fixtures/data.rs"));
+        assert!(!spec.matches(b"src/vendor/dep.rs"));
+        assert!(spec.matches(b"license"));
+
+        assert!(spec.may_match_under(b"src"));
+        assert!(spec.may_match_under(b"docs"));
+        assert!(!spec.may_match_under(b"src/vendor"));
+
+        let narrow = Pathspec::parse(&["src/bin", ":(literal)a*b"]).unwrap();
+        assert!(narrow.may_match_under(b"src"));
+        assert!(!narrow.may_match_under(b"tests"));
+        assert!(narrow.matches(b"a*b"));
+        assert!(!narrow.matches(b"axb"));
+
+        let excludes = Pathspec::parse(&[":^*.lock"]).unwrap();
+        assert!(excludes.matches(b"src/lib.rs"));
+        assert!(!excludes.matches(b"Cargo.lock"));
+        assert!(Pathspec::new().matches(b"anything"));
+        assert!(Pathspec::parse(&[":(attr:x)a"]).is_err());
+    }
+
+    #[test]
+    fn magic_is_parsed_or_refused() {
+        for spec in &[":(icase", ":(literal,glob)a", ":(top,frob)a"] {
+            match Pathspec::parse(&[spec]) {
+                Err(e) => assert!(matches!(e.kind(), ErrorKind::BadPathspec(xs) if xs == spec)),
+                Ok(_) => panic!("{:?} should be refused", spec)
+            }
+        }
+
+        // ":/" is the top, which pathspecs are relative to anyway.
+        let top = Pathspec::parse(&[":/src/", ":/!:src/vendor", ":(exclude,icase)SRC/GEN"]).unwrap();
+        assert!(top.matches(b"src/lib.rs"));
+        assert!(!top.matches(b"src/vendor/dep.rs"));
+        assert!(!top.matches(b"src/gen/out.rs"));
+        assert!(!top.matches(b"README"));
+
+        // only excludes: everything else.
+        let excludes = Pathspec::parse(&[":(exclude)docs"]).unwrap();
+        assert!(excludes.matches(b"README"));
+        assert!(!excludes.matches(b"docs/intro.md"));
+        assert!(!excludes.may_match_under(b"docs"));
+    }
+
+    #[test]
+    fn literal_paths_never_wildcard() {
+        let literal = Pathspec::literal(&["docs/", "[ab].c"]);
+        assert!(literal.matches(b"docs/intro.md"));
+        assert!(!literal.matches(b"docsx"));
+        assert!(literal.matches(b"[ab].c"));
+        assert!(!literal.matches(b"a.c"));
+        assert!(literal.may_match_under(b"docs"));
+        assert!(!literal.may_match_under(b"src"));
+        assert!(!literal.is_empty());
+        assert!(Pathspec::literal::<&str>(&[]).is_empty());
+    }
+}
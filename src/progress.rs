@@ -0,0 +1,94 @@
+use std::sync::{ Arc, Mutex };
+
+// Hooks long operations call as they go, for progress bars. An operation
+// runs in phases named as git names them ("Indexing objects", "Resolving
+// deltas", ...); `start` opens one, `update` reports the running totals and
+// `finish` closes it. Updates may come from any thread, hence Send + Sync.
+pub trait Progress: Send + Sync {
+    // `total` is the count the phase works towards, if known.
+    fn start(&self, _phase: &str, _total: Option<u64>) {}
+    // items (and bytes) done so far in the current phase
+    fn update(&self, _count: u64, _bytes: u64) {}
+    fn finish(&self) {}
+}
+
+pub struct NoProgress;
+
+impl Progress for NoProgress {}
+
+pub fn noop() -> Arc<dyn Progress> {
+    Arc::new(NoProgress)
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Phase {
+    pub name: String,
+    pub total: Option<u64>,
+    pub count: u64,
+    pub bytes: u64,
+    pub finished: bool
+}
+
+// A `Progress` that remembers the last update of every phase.
+#[derive(Debug, Default)]
+pub struct Log {
+    phases: Mutex<Vec<Phase>>
+}
+
+impl Log {
+    pub fn new() -> Log {
+        Log::default()
+    }
+
+    pub fn phases(&self) -> Vec<Phase> {
+        self.phases.lock().unwrap().clone()
+    }
+}
+
+impl Progress for Log {
+    fn start(&self, phase: &str, total: Option<u64>) {
+        self.phases.lock().unwrap().push(Phase {
+            name: String::from(phase),
+            total,
+            ..Phase::default()
+        });
+    }
+
+    fn update(&self, count: u64, bytes: u64) {
+        if let Some(phase) = self.phases.lock().unwrap().last_mut() {
+            // updates from parallel workers can arrive out of order.
+            phase.count = phase.count.max(count);
+            phase.bytes = phase.bytes.max(bytes);
+        }
+    }
+
+    fn finish(&self) {
+        if let Some(phase) = self.phases.lock().unwrap().last_mut() {
+            phase.finished = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ Log, Phase, Progress };
+
+    #[test]
+    fn log_keeps_the_furthest_update_of_each_phase() {
+        let log = Log::new();
+        // nothing to update before a phase starts.
+        log.update(1, 1);
+        log.start("Indexing objects", Some(3));
+        log.update(2, 20);
+        log.update(1, 10);
+        log.update(3, 30);
+        log.finish();
+        log.start("Resolving deltas", None);
+        log.update(1, 0);
+
+        assert_eq!(log.phases(), vec![
+            Phase { name: String::from("Indexing objects"), total: Some(3), count: 3, bytes: 30, finished: true },
+            Phase { name: String::from("Resolving deltas"), total: None, count: 1, bytes: 0, finished: false }
+        ]);
+    }
+}
@@ -0,0 +1,196 @@
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::io::Write;
+
+use chrono::Duration;
+
+use crate::worktree::Layout;
+use crate::lock::{ LockFile, Retry };
+use crate::clock::Clock;
+use crate::identity::Identity;
+use crate::id::Id;
+
+// One line of `logs/<ref>`:
+//
+//     <old> SP <new> SP <identity> TAB <message> LF
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub old: Id,
+    pub new: Id,
+    pub identity: Identity,
+    pub message: String
+}
+
+// HEAD's log is per-worktree; every other log lives in the common dir.
+fn log_path(path: &Path, name: &str) -> Result<PathBuf, std::io::Error> {
+    let layout = Layout::resolve(path)?;
+    let root = if name == "HEAD" { layout.git_dir } else { layout.common_dir };
+    Ok(root.join("logs").join(name))
+}
+
+impl Entry {
+    pub fn new(old: Option<&Id>, new: &Id, identity: &Identity, message: &str) -> Entry {
+        Entry {
+            old: old.cloned().unwrap_or_default(),
+            new: new.clone(),
+            identity: identity.clone(),
+            message: String::from(message)
+        }
+    }
+
+    fn parse(line: &str) -> Option<Entry> {
+        if line.len() < 82 {
+            return None
+        }
+
+        let old = Id::from_str(&line[0..40]).ok()?;
+        let new = Id::from_str(&line[41..81]).ok()?;
+        let (identity, message) = match line[82..].find('\t') {
+            Some(idx) => (&line[82..82 + idx], &line[83 + idx..]),
+            None => (&line[82..], "")
+        };
+
+        Some(Entry {
+            old,
+            new,
+            identity: Identity::parse(identity.as_bytes())?,
+            message: String::from(message)
+        })
+    }
+
+    fn write<W: Write>(&self, output: &mut W) -> std::io::Result<()> {
+        write!(output, "{} {} ", self.old, self.new)?;
+        self.identity.write(output)?;
+        // messages are single-line; git squashes newlines the same way.
+        writeln!(output, "\t{}", self.message.replace('\n', " "))
+    }
+}
+
+// Returns the log of `name`, oldest entry first. A missing log is empty.
+pub fn read(path: &Path, name: &str) -> Result<Vec<Entry>, std::io::Error> {
+    let contents = match std::fs::read(log_path(path, name)?) {
+        Ok(xs) => xs,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e)
+    };
+
+    let contents = match std::str::from_utf8(&contents) {
+        Ok(xs) => xs,
+        Err(_) => return Err(std::io::ErrorKind::InvalidData.into())
+    };
+
+    // like git, skip lines that fail to parse rather than failing the read.
+    Ok(contents.lines().filter_map(Entry::parse).collect())
+}
+
+pub fn append(path: &Path, name: &str, entry: &Entry) -> Result<(), std::io::Error> {
+    let log = log_path(path, name)?;
+    if let Some(parent) = log.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut buffer = Vec::new();
+    entry.write(&mut buffer)?;
+    let mut f = std::fs::OpenOptions::new().append(true).create(true).open(log)?;
+    f.write_all(&buffer)
+}
+
+// Replaces the whole log; an empty `entries` removes it.
+pub fn write(path: &Path, name: &str, entries: &[Entry]) -> Result<(), std::io::Error> {
+    let log = log_path(path, name)?;
+    if entries.is_empty() {
+        return match std::fs::remove_file(log) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            xs => xs
+        }
+    }
+
+    if let Some(parent) = log.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut lock = LockFile::acquire(&log, &Retry::refs())?;
+    for entry in entries {
+        entry.write(&mut lock)?;
+    }
+    lock.commit()
+}
+
+// git's default gc.reflogExpire.
+pub fn default_expiry() -> Duration {
+    Duration::days(90)
+}
+
+// Drops the entries of `name` older than `expiry` by `clock`, as
+// `git reflog expire` does, and returns how many went.
+pub fn expire(path: &Path, name: &str, expiry: Duration, clock: &dyn Clock) -> Result<usize, std::io::Error> {
+    let entries = read(path, name)?;
+    let cutoff = clock.now() - expiry;
+    let kept: Vec<Entry> = entries.iter().filter(|xs| *xs.identity.at() >= cutoff).cloned().collect();
+    let expired = entries.len() - kept.len();
+    if expired > 0 {
+        write(path, name, &kept)?;
+    }
+    Ok(expired)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{ FixedOffset, TimeZone, Utc };
+
+    use crate::testkit::TempDir;
+    use crate::identity::Identity;
+    use crate::id::Id;
+    use super::{ append, default_expiry, expire, read, write, Entry };
+
+    #[test]
+    fn append_and_read_work() {
+        let dir = TempDir::new("reflog").expect("failed to create tempdir");
+        std::fs::create_dir_all(dir.path().join(".git")).unwrap();
+
+        let identity = Identity::new(
+            b"Test User",
+            b"test@example.com",
+            Utc.timestamp_opt(1_545_286_964, 0).unwrap(),
+            FixedOffset::east_opt(-7 * 3600).unwrap()
+        );
+        let first = Id::from(&[1u8; 20]);
+        let second = Id::from(&[2u8; 20]);
+        append(dir.path(), "refs/stash", &Entry::new(None, &first, &identity, "one")).expect("failed to append");
+        append(dir.path(), "refs/stash", &Entry::new(Some(&first), &second, &identity, "two\nlines")).expect("failed to append");
+
+        let entries = read(dir.path(), "refs/stash").expect("failed to read");
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].old, Id::default());
+        assert_eq!(entries[1].old, first);
+        assert_eq!(entries[1].new, second);
+        assert_eq!(entries[1].message, "two lines");
+        assert_eq!(entries[1].identity.email(), b"test@example.com");
+        assert_eq!(entries[1].identity.offset().local_minus_utc(), -7 * 3600);
+
+        write(dir.path(), "refs/stash", &entries[1..]).expect("failed to rewrite");
+        assert_eq!(read(dir.path(), "refs/stash").expect("failed to read").len(), 1);
+        write(dir.path(), "refs/stash", &[]).expect("failed to rewrite");
+        assert!(read(dir.path(), "refs/stash").expect("failed to read").is_empty());
+    }
+
+    #[test]
+    fn expire_goes_by_the_clock() {
+        use chrono::Duration;
+        use crate::clock::FixedClock;
+
+        let dir = TempDir::new("reflog-expire").expect("failed to create tempdir");
+        std::fs::create_dir_all(dir.path().join(".git")).unwrap();
+        let clock = FixedClock::new(Utc.timestamp_opt(1_545_286_964, 0).unwrap());
+        for n in 0..3u8 {
+            let identity = Identity::now(b"Test User", b"test@example.com", &clock);
+            append(dir.path(), "HEAD", &Entry::new(None, &Id::from(&[n; 20]), &identity, "moved")).expect("failed to append");
+            clock.advance(Duration::days(60));
+        }
+
+        // now 180 days after the first entry and 60 after the last.
+        assert_eq!(expire(dir.path(), "HEAD", default_expiry(), &clock).expect("failed to expire"), 2);
+        let entries = read(dir.path(), "HEAD").expect("failed to read");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].new, Id::from(&[2u8; 20]));
+    }
+}
@@ -1,16 +1,24 @@
 use std::collections::HashMap;
+use std::cell::OnceCell;
 use std::path::{Path, PathBuf};
+use std::sync::{ Arc, RwLock };
 use std::str::FromStr;
 use std::fs::File;
-use std::io::Read;
+use std::io::{ Read, Write };
 
+use crate::worktree::{ common_dir, Layout };
+use crate::errors::{ ErrorKind, Result as GitResult };
+use crate::vfs::{ self, VfsProvider };
+use crate::lock::{ LockFile, Retry };
+use crate::namespace::Namespace;
 use crate::id::Id;
 
 #[derive(Copy, Clone, Debug)]
 pub enum Kind {
     Local,
     Remote,
-    Tag
+    Tag,
+    Replace
 }
 
 #[derive(Debug)]
@@ -25,9 +33,25 @@ pub struct Ref {
     ptr: RefPtr
 }
 
+#[derive(Default)]
 pub struct RefSet(HashMap<String, Ref>);
 
 impl Ref {
+    pub fn new(kind: Kind, ptr: RefPtr) -> Ref {
+        Ref {
+            kind,
+            ptr
+        }
+    }
+
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    pub fn ptr(&self) -> &RefPtr {
+        &self.ptr
+    }
+
     pub fn load(path: &Path, kind: Kind) -> Result<Ref, std::io::Error> {
         let mut f = File::open(path)?;
         let mut buffer = Vec::new();
@@ -38,15 +62,15 @@ impl Ref {
                 return Err(std::io::ErrorKind::InvalidData.into());
             }
 
-            if &contents[0..16] == "ref: refs/heads/" {
+            if let Some(xs) = contents.strip_prefix("ref: refs/heads/") {
                 return Ok(Ref {
                     kind,
-                    ptr: RefPtr::Indirect(String::from(contents[16..].trim()))
+                    ptr: RefPtr::Indirect(String::from(xs.trim()))
                 });
             }
 
-            if buffer.len() >= 40 {
-                if let Ok(id) = Id::from_str(&contents[0..40]) {
+            if let Some(xs) = contents.get(0..40) {
+                if let Ok(id) = Id::from_str(xs) {
                     return Ok(Ref {
                         ptr: RefPtr::Direct(id),
                         kind
@@ -91,13 +115,480 @@ fn recurse_dir(
     Ok(())
 }
 
+// Reads `refs/replace/<original>` entries into an original -> replacement map.
+pub fn replacements_from_path(path: &Path) -> Result<HashMap<Id, Id>, std::io::Error> {
+    replacements_from_common_dir(&common_dir(path)?)
+}
+
+pub fn replacements_from_common_dir(common_dir: &Path) -> Result<HashMap<Id, Id>, std::io::Error> {
+    let mut root = common_dir.to_path_buf();
+    root.push("refs");
+    root.push("replace");
+
+    let mut replacements = HashMap::new();
+    if !root.is_dir() {
+        return Ok(replacements)
+    }
+
+    let mut map = HashMap::new();
+    recurse_dir(&mut root, &mut Vec::new(), &mut map, Kind::Replace)?;
+    for (name, reference) in map {
+        let original = match Id::from_str(&name) {
+            Ok(xs) => xs,
+            Err(_) => continue
+        };
+
+        if let RefPtr::Direct(replacement) = reference.ptr {
+            replacements.insert(original, replacement);
+        }
+    }
+
+    Ok(replacements)
+}
+
+// Whether `name` is a ref name git would accept, as `git check-ref-format`
+// decides: refs/* or a one-level all-caps name like HEAD, with no empty,
+// dot-led or ".lock" components, no "..", "@{", control characters or any
+// of ` ~^:?*[\`, and not ending in a slash or dot. Names are joined to the
+// git dir, so nothing else may be built into a path.
+pub fn check_ref_format(name: &str) -> bool {
+    let one_level = !name.is_empty() && name.chars().all(|xs| xs.is_ascii_uppercase() || xs == '_');
+    if !(one_level || name.starts_with("refs/")) {
+        return false
+    }
+    let bad_char = name.chars().any(|xs| xs.is_ascii_control() || " ~^:?*[\\".contains(xs));
+    let bad_component = name.split('/').any(|xs| xs.is_empty() || xs.starts_with('.') || xs.ends_with(".lock"));
+    !bad_char && !bad_component && !name.contains("..") && !name.contains("@{") && !name.ends_with('.')
+}
+
+fn check_name(name: &str) -> Result<(), std::io::Error> {
+    if !check_ref_format(name) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{:?} is not a valid ref name", name)))
+    }
+    Ok(())
+}
+
+// Points the loose ref `name` (e.g. "refs/stash") at `id`, going through
+// `<name>.lock` so readers never see a partial write. Per-worktree refs are
+// written in the worktree's own git dir (see `loose_ref_path`).
+pub fn update_ref(path: &Path, name: &str, id: &Id) -> Result<(), std::io::Error> {
+    check_name(name)?;
+    let ref_path = loose_ref_path(&Layout::resolve(path)?, name);
+    if let Some(parent) = ref_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut lock = LockFile::acquire(&ref_path, &Retry::refs())?;
+    writeln!(lock, "{}", id)?;
+    lock.commit()
+}
+
+// Removes the loose ref `name` under `<name>.lock`, so it can't race a
+// writer; dropping the lock releases it.
+pub fn delete_ref(path: &Path, name: &str) -> Result<(), std::io::Error> {
+    check_name(name)?;
+    let ref_path = loose_ref_path(&Layout::resolve(path)?, name);
+    let _lock = match LockFile::acquire(&ref_path, &Retry::refs()) {
+        // no directory to lock in, so no ref either.
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        xs => xs?
+    };
+    match std::fs::remove_file(&ref_path) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        xs => xs
+    }
+}
+
+// One ref moved by a transaction; `None` is "did not exist" / "deleted".
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RefUpdate {
+    pub name: String,
+    pub old: Option<Id>,
+    pub new: Option<Id>
+}
+
+type Listener = Arc<dyn Fn(&[RefUpdate]) + Send + Sync>;
+
+// Loose refs of one repository, updated through transactions. Listeners run
+// on the committing thread after every successful commit, with the whole
+// change set.
+//
+// With a namespace every name is read and written inside it and reported
+// without its prefix, so each namespace behaves as its own repository.
+pub struct RefStore {
+    path: PathBuf,
+    namespace: Option<Namespace>,
+    listeners: RwLock<Vec<Listener>>,
+    retry: Retry,
+    vfs: Arc<dyn VfsProvider>,
+    layout: Option<Layout>
+}
+
+enum Expect {
+    Any,
+    Value(Option<Id>)
+}
+
+pub struct Transaction<'a> {
+    store: &'a RefStore,
+    updates: Vec<(String, Expect, Option<Id>)>
+}
+
+// HEAD and other one-level names are per-worktree, as are refs/bisect/,
+// refs/worktree/ and refs/rewritten/; the rest of refs/* is shared.
+fn loose_ref_path(layout: &Layout, name: &str) -> PathBuf {
+    let per_worktree = !name.contains('/') ||
+        ["refs/bisect/", "refs/worktree/", "refs/rewritten/"].iter().any(|xs| name.starts_with(xs));
+    let root = if per_worktree { &layout.git_dir } else { &layout.common_dir };
+    root.join(name)
+}
+
+impl RefStore {
+    pub fn new(path: &Path) -> RefStore {
+        RefStore {
+            path: path.to_path_buf(),
+            namespace: None,
+            listeners: RwLock::new(Vec::new()),
+            retry: Retry::refs(),
+            vfs: vfs::os(),
+            layout: None
+        }
+    }
+
+    // Uses `layout` as is instead of resolving it from the path, for git
+    // dirs that aren't (or aren't under) the path, as with GIT_DIR.
+    pub fn with_layout(mut self, layout: Layout) -> Self {
+        self.layout = Some(layout);
+        self
+    }
+
+    fn layout(&self) -> std::io::Result<Layout> {
+        match self.layout {
+            Some(ref xs) => Ok(xs.clone()),
+            None => Layout::resolve_with(&*self.vfs, &self.path)
+        }
+    }
+
+    // Reads refs through `vfs`. Transactions still lock and write on the OS
+    // filesystem.
+    pub fn with_vfs(mut self, vfs: Arc<dyn VfsProvider>) -> Self {
+        self.vfs = vfs;
+        self
+    }
+
+    // How long transactions wait for a ref someone else has locked before
+    // failing with `ErrorKind::RefLocked`; core.filesRefLockTimeout.
+    pub fn with_lock_retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn with_namespace(mut self, namespace: Namespace) -> Self {
+        self.namespace = Some(namespace);
+        self
+    }
+
+    pub fn namespace(&self) -> Option<&Namespace> {
+        self.namespace.as_ref()
+    }
+
+    // A namespace may only name HEAD and refs/*, so no update can reach a
+    // ref outside of it.
+    fn storage_name(&self, name: &str) -> GitResult<String> {
+        let namespace = match self.namespace {
+            Some(ref xs) => xs,
+            None if check_ref_format(name) => return Ok(String::from(name)),
+            None => return Err(ErrorKind::BadRefName(String::from(name)).into())
+        };
+        let escapes = name.split('/').any(|xs| xs.is_empty() || xs == "." || xs == "..");
+        if escapes || !(name == "HEAD" || name.starts_with("refs/")) {
+            return Err(ErrorKind::RefOutsideNamespace(String::from(name)).into())
+        }
+        let qualified = namespace.qualify(name);
+        if !check_ref_format(&qualified) {
+            return Err(ErrorKind::BadRefName(String::from(name)).into())
+        }
+        Ok(qualified)
+    }
+
+    fn client_name(&self, name: &str) -> String {
+        match self.namespace {
+            Some(ref xs) => String::from(xs.strip(name).unwrap_or(name)),
+            None => String::from(name)
+        }
+    }
+
+    pub fn on_update<F: Fn(&[RefUpdate]) + Send + Sync + 'static>(&self, listener: F) {
+        self.listeners.write().unwrap().push(Arc::new(listener));
+    }
+
+    // The refs `git pack-refs` moved into packed-refs, by storage name. Peeled
+    // lines ("^<id>", the commit an annotated tag points at) are skipped.
+    fn read_packed(&self, layout: &Layout) -> GitResult<HashMap<String, Id>> {
+        let contents = match self.vfs.read(&layout.common_dir.join("packed-refs")) {
+            Ok(xs) => String::from_utf8_lossy(&xs).into_owned(),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e.into())
+        };
+        let mut packed = HashMap::new();
+        for line in contents.lines().filter(|xs| !xs.is_empty() && !xs.starts_with('#') && !xs.starts_with('^')) {
+            let (id, name) = match line.split_once(' ') {
+                Some((id, name)) => (Id::from_str(id).map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidData))?, name),
+                None => return Err(std::io::Error::from(std::io::ErrorKind::InvalidData).into())
+            };
+            packed.insert(String::from(name), id);
+        }
+        Ok(packed)
+    }
+
+    fn packed<'p>(&self, layout: &Layout, packed: &'p OnceCell<HashMap<String, Id>>) -> GitResult<&'p HashMap<String, Id>> {
+        if packed.get().is_none() {
+            let _ = packed.set(self.read_packed(layout)?);
+        }
+        Ok(packed.get().unwrap())
+    }
+
+    fn resolve(&self, layout: &Layout, name: &str) -> GitResult<(String, Option<Id>)> {
+        self.resolve_in(layout, &OnceCell::new(), name)
+    }
+
+    // Follows symbolic refs ("ref: refs/heads/x") to the ref that holds an id.
+    // A loose ref shadows a packed one of the same name, as in git.
+    fn resolve_in(&self, layout: &Layout, packed: &OnceCell<HashMap<String, Id>>, name: &str) -> GitResult<(String, Option<Id>)> {
+        let mut name = String::from(name);
+        for _ in 0..5 {
+            let contents = match self.vfs.read(&loose_ref_path(layout, &name)) {
+                Ok(xs) => String::from_utf8_lossy(&xs).into_owned(),
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    let id = self.packed(layout, packed)?.get(&name).cloned();
+                    return Ok((name, id))
+                },
+                Err(e) => return Err(e.into())
+            };
+            match contents.trim().strip_prefix("ref: ") {
+                // symbolic refs inside a namespace are kept inside it.
+                Some(target) => name = match self.namespace {
+                    Some(ref xs) if xs.strip(target).is_none() => xs.qualify(target),
+                    _ => String::from(target)
+                },
+                None => match Id::from_str(contents.trim()) {
+                    Ok(id) => return Ok((name, Some(id))),
+                    Err(_) => return Err(std::io::Error::from(std::io::ErrorKind::InvalidData).into())
+                }
+            }
+        }
+        Err(std::io::Error::from(std::io::ErrorKind::InvalidData).into())
+    }
+
+    // The ref `name` ends up at through symbolic refs, and its id if it has
+    // one: HEAD gives "refs/heads/<branch>" on a branch, "HEAD" detached.
+    pub fn resolve_ref(&self, name: &str) -> GitResult<(String, Option<Id>)> {
+        let layout = self.layout()?;
+        let (target, id) = self.resolve(&layout, &self.storage_name(name)?)?;
+        Ok((self.client_name(&target), id))
+    }
+
+    pub fn read(&self, name: &str) -> GitResult<Option<Id>> {
+        let layout = self.layout()?;
+        Ok(self.resolve(&layout, &self.storage_name(name)?)?.1)
+    }
+
+    // Every ref that resolves to an id, HEAD first and the rest sorted by
+    // name as bytes, loose and packed alike, whatever order the filesystem
+    // lists them in: what a server advertises for this repository (or
+    // namespace).
+    pub fn list(&self) -> GitResult<Vec<(String, Id)>> {
+        let layout = self.layout()?;
+        let prefix = match self.namespace {
+            Some(ref xs) => xs.qualify("refs"),
+            None => String::from("refs")
+        };
+
+        let packed_prefix = format!("{}/", prefix);
+        let mut names = Vec::new();
+        let mut stack = vec![(layout.common_dir.join(&prefix), prefix)];
+        while let Some((dir, name)) = stack.pop() {
+            let entries = match self.vfs.read_dir(&dir) {
+                Ok(xs) => xs,
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into())
+            };
+            for filename in entries {
+                let child = format!("{}/{}", name, filename);
+                let path = dir.join(&filename);
+                if self.vfs.metadata(&path)?.is_dir {
+                    stack.push((path, child));
+                } else if !filename.ends_with(".lock") {
+                    names.push(child);
+                }
+            }
+        }
+        let packed = OnceCell::new();
+        names.extend(self.packed(&layout, &packed)?.keys().filter(|xs| xs.starts_with(&packed_prefix)).cloned());
+        names.sort();
+        names.dedup();
+
+        let mut refs = Vec::new();
+        for name in std::iter::once(self.storage_name("HEAD")?).chain(names) {
+            if let (_, Some(id)) = self.resolve_in(&layout, &packed, &name)? {
+                refs.push((self.client_name(&name), id));
+            }
+        }
+        Ok(refs)
+    }
+
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction {
+            store: self,
+            updates: Vec::new()
+        }
+    }
+}
+
+impl<'a> Transaction<'a> {
+    pub fn update(mut self, name: &str, new: &Id) -> Self {
+        self.updates.push((String::from(name), Expect::Any, Some(new.clone())));
+        self
+    }
+
+    pub fn delete(mut self, name: &str) -> Self {
+        self.updates.push((String::from(name), Expect::Any, None));
+        self
+    }
+
+    // Like `update`/`delete`, but only if `name` still holds `old` (None: the
+    // ref must not exist) when the transaction commits.
+    pub fn compare_and_swap(mut self, name: &str, old: Option<&Id>, new: Option<&Id>) -> Self {
+        self.updates.push((String::from(name), Expect::Value(old.cloned()), new.cloned()));
+        self
+    }
+
+    // Locks every ref, checks expectations, then applies all updates. Nothing
+    // is written unless every ref could be locked and checked, and an update
+    // that fails puts back the ones already applied.
+    pub fn commit(self) -> GitResult<Vec<RefUpdate>> {
+        let layout = self.store.layout()?;
+        // dropping the locks on failure releases them.
+        let (locks, changes) = self.prepare(&layout)?;
+        self.apply(locks, &changes, apply_update)?;
+
+        let listeners = self.store.listeners.read().unwrap().clone();
+        for listener in listeners {
+            listener(&changes);
+        }
+        Ok(changes)
+    }
+
+    // Applies each change with its lock. Should one fail, the refs already
+    // changed are restored to their old values while the rest are still
+    // locked, and the failure is returned.
+    fn apply<F>(&self, locks: Vec<LockFile>, changes: &[RefUpdate], mut apply: F) -> std::io::Result<()>
+        where F: FnMut(LockFile, &RefUpdate) -> std::io::Result<()> {
+        let mut applied: Vec<(PathBuf, &RefUpdate)> = Vec::new();
+        let mut locks = locks.into_iter();
+        for change in changes {
+            let lock = locks.next().expect("a lock for every change");
+            let ref_path = lock.path().to_path_buf();
+            if let Err(e) = apply(lock, change) {
+                for (ref_path, change) in applied.iter().rev() {
+                    // best effort: the failure already says the refs may be off.
+                    let _ = restore(ref_path, change.old.as_ref(), &self.store.retry);
+                }
+                return Err(e)
+            }
+            applied.push((ref_path, change));
+        }
+        Ok(())
+    }
+
+    fn prepare(&self, layout: &Layout) -> GitResult<(Vec<LockFile>, Vec<RefUpdate>)> {
+        // a ref named twice, directly or through a symref, would be locked
+        // twice; git refuses that up front.
+        let mut targets = Vec::with_capacity(self.updates.len());
+        for (name, _, _) in &self.updates {
+            let (target, _) = self.store.resolve(layout, &self.store.storage_name(name)?)?;
+            if targets.contains(&target) {
+                return Err(ErrorKind::DuplicateRefUpdate(self.store.client_name(&target)).into())
+            }
+            targets.push(target);
+        }
+
+        let mut locks = Vec::new();
+        let mut changes = Vec::new();
+        let packed = OnceCell::new();
+        for ((_, expect, new), target) in self.updates.iter().zip(targets) {
+            // that would mean rewriting packed-refs, which isn't done yet; a
+            // deleted loose ref would only uncover the packed one.
+            if new.is_none() && self.store.packed(layout, &packed)?.contains_key(&target) {
+                return Err(ErrorKind::NotImplemented.into())
+            }
+            let ref_path = loose_ref_path(layout, &target);
+            if let Some(parent) = ref_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            let mut lock = match LockFile::acquire(&ref_path, &self.store.retry) {
+                Ok(xs) => xs,
+                Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    return Err(ErrorKind::RefLocked(self.store.client_name(&target)).into())
+                },
+                Err(e) => return Err(e.into())
+            };
+
+            // re-read under the lock: only now is the value stable.
+            let (_, old) = self.store.resolve_in(layout, &packed, &target)?;
+            if let Expect::Value(ref expected) = *expect {
+                if *expected != old {
+                    return Err(ErrorKind::RefChanged(self.store.client_name(&target)).into())
+                }
+            }
+            if let Some(ref id) = *new {
+                writeln!(lock, "{}", id)?;
+            }
+
+            locks.push(lock);
+            changes.push(RefUpdate {
+                name: self.store.client_name(&target),
+                old,
+                new: new.clone()
+            });
+        }
+        Ok((locks, changes))
+    }
+}
+
+fn apply_update(lock: LockFile, change: &RefUpdate) -> std::io::Result<()> {
+    match change.new {
+        Some(_) => lock.commit(),
+        // the lock goes once the ref has.
+        None => match std::fs::remove_file(lock.path()) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            xs => xs
+        }
+    }
+}
+
+// Puts a ref a failed transaction changed back to `old`.
+fn restore(ref_path: &Path, old: Option<&Id>, retry: &Retry) -> std::io::Result<()> {
+    let mut lock = LockFile::acquire(ref_path, retry)?;
+    match old {
+        Some(id) => {
+            writeln!(lock, "{}", id)?;
+            lock.commit()
+        },
+        None => match std::fs::remove_file(ref_path) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            xs => xs
+        }
+    }
+}
+
 impl RefSet {
     pub fn from_path(path: &Path) -> Result<RefSet, std::io::Error> {
-        let mut root = std::path::PathBuf::new();
+        let layout = Layout::resolve(path)?;
+        let mut root = layout.common_dir.clone();
         let mut map = HashMap::new();
         let mut dirs = Vec::new();
-        root.push(path);
-        root.push(".git");
         root.push("refs");
         root.push("heads");
         recurse_dir(&mut root, &mut dirs, &mut map, Kind::Local)?;
@@ -107,8 +598,8 @@ impl RefSet {
         root.pop();
         root.push("tags");
         recurse_dir(&mut root, &mut dirs, &mut map, Kind::Tag)?;
-        root.pop();
-        root.pop();
+        // HEAD is per-worktree; everything else is shared.
+        root = layout.git_dir;
         root.push("HEAD");
         if let Ok(reference) = Ref::load(root.as_path(), Kind::Local) {
             map.insert(String::from("HEAD"), reference);
@@ -119,6 +610,14 @@ impl RefSet {
         })
     }
 
+    pub fn get(&self, name: &str) -> Option<&Ref> {
+        self.0.get(name)
+    }
+
+    pub fn insert(&mut self, name: &str, reference: Ref) {
+        self.0.insert(String::from(name), reference);
+    }
+
     pub fn deref(&self, name: &str) -> Option<&Id> {
         let mut reference = self.0.get(name);
         loop {
@@ -136,3 +635,232 @@ impl RefSet {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{ Arc, Mutex };
+
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::errors::ErrorKind;
+    use crate::lock::{ LockFile, Retry };
+    use crate::files;
+    use super::{ apply_update, delete_ref, RefStore, RefUpdate };
+
+    #[test]
+    fn short_ref_files_are_errors() {
+        let dir = TempDir::new("refs-short").expect("failed to create tempdir");
+        for contents in &["", "ref: ", "0123456789abcdef", "é0123456789abcdef0123456789abcdef0123456"] {
+            let file = dir.path().join("ref");
+            std::fs::write(&file, contents).unwrap();
+            assert!(super::Ref::load(&file, super::Kind::Local).is_err(), "{:?}", contents);
+        }
+        let replace = dir.path().join("refs/replace");
+        std::fs::create_dir_all(&replace).unwrap();
+        std::fs::write(replace.join("0123456789abcdef0123456789abcdef01234567"), "0123456").unwrap();
+        // unreadable refs are passed over, as elsewhere.
+        assert!(super::replacements_from_common_dir(dir.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_and_duplicate_names_are_refused() {
+        let dir = TempDir::new("refs-names").expect("failed to create tempdir");
+        let builder = RepoBuilder::new().commit("first", files!["README" => "hello\n"]);
+        let first = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        for name in &["refs/heads/main", "HEAD", "ORIG_HEAD", "refs/tags/v1.0", "refs/heads/a@b"] {
+            assert!(super::check_ref_format(name), "{}", name);
+        }
+        for name in &["refs/../../config", "../config", "config", "refs/heads/", "refs//x", "refs/heads/.hidden",
+                      "refs/heads/x.lock", "refs/heads/a@{1}", "refs/heads/a b", "refs/heads/x.", "/etc/passwd"] {
+            assert!(!super::check_ref_format(name), "{}", name);
+        }
+        assert!(super::update_ref(dir.path(), "refs/../../escaped", &first).is_err());
+        assert!(!dir.path().join("escaped").exists());
+
+        let store = RefStore::new(dir.path());
+        match store.transaction().update("refs/../config", &first).commit() {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::BadRefName(_))),
+            Ok(_) => panic!("expected a bad ref name")
+        }
+        // HEAD and the branch it names are the same ref.
+        for second in &["refs/heads/topic", "HEAD", "refs/heads/master"] {
+            let result = store.transaction()
+                .update("refs/heads/master", &first)
+                .update(second, &first)
+                .commit();
+            match result {
+                Err(e) => assert!(matches!(e.kind(), ErrorKind::DuplicateRefUpdate(name) if name == "refs/heads/master")),
+                Ok(_) => assert_eq!(*second, "refs/heads/topic")
+            }
+        }
+    }
+
+    #[test]
+    fn transactions_notify_listeners() {
+        let dir = TempDir::new("refstore").expect("failed to create tempdir");
+        let builder = RepoBuilder::new().commit("first", files!["README" => "hello\n"]);
+        let first = builder.tip().unwrap();
+        let builder = builder.commit("second", files!["README" => "there\n"]);
+        let second = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        let store = RefStore::new(dir.path());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        store.on_update(move |changes| sink.lock().unwrap().push(changes.to_vec()));
+
+        let changes = store.transaction()
+            .update("HEAD", &first)
+            .compare_and_swap("refs/heads/topic", None, Some(&second))
+            .commit()
+            .expect("failed to commit");
+        assert_eq!(changes, vec![
+            RefUpdate { name: String::from("refs/heads/master"), old: Some(second.clone()), new: Some(first.clone()) },
+            RefUpdate { name: String::from("refs/heads/topic"), old: None, new: Some(second.clone()) }
+        ]);
+        assert_eq!(*seen.lock().unwrap(), vec![changes]);
+        assert_eq!(store.read("HEAD").expect("failed to read"), Some(first.clone()));
+
+        // a failed expectation leaves every ref alone and notifies no one.
+        let result = store.transaction()
+            .delete("refs/heads/topic")
+            .compare_and_swap("refs/heads/master", Some(&second), Some(&second))
+            .commit();
+        match result {
+            Err(e) => match e.kind() {
+                ErrorKind::RefChanged(name) => assert_eq!(name, "refs/heads/master"),
+                _ => panic!("unexpected error {:?}", e)
+            },
+            Ok(_) => panic!("expected a conflict")
+        }
+        assert_eq!(store.read("refs/heads/topic").expect("failed to read"), Some(second.clone()));
+        assert_eq!(seen.lock().unwrap().len(), 1);
+        assert!(!dir.path().join(".git/refs/heads/topic.lock").exists());
+
+        std::fs::write(dir.path().join(".git/refs/heads/topic.lock"), "").unwrap();
+        match store.transaction().delete("refs/heads/topic").commit() {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::RefLocked(_))),
+            Ok(_) => panic!("expected the ref to be locked")
+        }
+    }
+
+    #[test]
+    fn namespaces_are_isolated() {
+        use crate::namespace::Namespace;
+
+        let dir = TempDir::new("refstore-ns").expect("failed to create tempdir");
+        let builder = RepoBuilder::new().commit("first", files!["README" => "hello\n"]);
+        let first = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        let alice = RefStore::new(dir.path()).with_namespace(Namespace::new("alice").unwrap());
+        let bob = RefStore::new(dir.path()).with_namespace(Namespace::new("bob").unwrap());
+        let heard = Arc::new(Mutex::new(0));
+        let sink = heard.clone();
+        bob.on_update(move |_| *sink.lock().unwrap() += 1);
+
+        let changes = alice.transaction()
+            .update("refs/heads/master", &first)
+            .update("refs/tags/v1", &first)
+            .commit()
+            .expect("failed to commit");
+        assert_eq!(changes[0].name, "refs/heads/master");
+        assert!(dir.path().join(".git/refs/namespaces/alice/refs/heads/master").exists());
+        std::fs::write(dir.path().join(".git/refs/namespaces/alice/HEAD"), "ref: refs/heads/master\n").unwrap();
+
+        let names: Vec<String> = alice.list().expect("failed to list").into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["HEAD", "refs/heads/master", "refs/tags/v1"]);
+        assert!(bob.list().expect("failed to list").is_empty());
+        assert_eq!(bob.read("refs/heads/master").expect("failed to read"), None);
+        assert_eq!(*heard.lock().unwrap(), 0);
+
+        // the root repository is untouched and no name escapes a namespace.
+        assert_eq!(RefStore::new(dir.path()).read("refs/heads/master").unwrap().as_ref(), Some(&first));
+        for name in &["refs/../../heads/master", "packed-refs", "refs/heads//x"] {
+            match bob.transaction().update(name, &first).commit() {
+                Err(e) => assert!(matches!(e.kind(), ErrorKind::RefOutsideNamespace(_))),
+                Ok(_) => panic!("{} should be rejected", name)
+            }
+        }
+    }
+
+    #[test]
+    fn failed_updates_put_back_the_ones_applied() {
+        let dir = TempDir::new("refstore-rollback").expect("failed to create tempdir");
+        let builder = RepoBuilder::new().commit("first", files!["README" => "hello\n"]);
+        let first = builder.tip().unwrap();
+        let builder = builder.commit("second", files!["README" => "there\n"]).branch("gone");
+        let second = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        let store = RefStore::new(dir.path());
+        let transaction = store.transaction()
+            .update("refs/heads/master", &first)
+            .delete("refs/heads/gone")
+            .compare_and_swap("refs/heads/new", None, Some(&first))
+            .update("refs/heads/last", &first);
+        let layout = store.layout().unwrap();
+        let (locks, changes) = transaction.prepare(&layout).expect("failed to prepare");
+
+        // the third update fails after two have gone through.
+        let mut count = 0;
+        let result = transaction.apply(locks, &changes, |lock, change| {
+            count += 1;
+            if count == 3 {
+                assert!(dir.path().join(".git/refs/heads/last.lock").exists());
+                return Err(std::io::ErrorKind::Other.into())
+            }
+            apply_update(lock, change)
+        });
+        assert!(result.is_err());
+        assert_eq!(store.read("refs/heads/master").unwrap(), Some(second.clone()));
+        assert_eq!(store.read("refs/heads/gone").unwrap(), Some(second.clone()));
+        assert_eq!(store.read("refs/heads/new").unwrap(), None);
+        assert_eq!(store.read("refs/heads/last").unwrap(), None);
+        for name in &["master", "gone", "new", "last"] {
+            assert!(!dir.path().join(format!(".git/refs/heads/{}.lock", name)).exists());
+        }
+    }
+
+    #[test]
+    fn deletes_take_the_ref_lock() {
+        let dir = TempDir::new("refs-delete").expect("failed to create tempdir");
+        let builder = RepoBuilder::new().commit("first", files!["README" => "hello\n"]).branch("topic");
+        builder.write(dir.path()).expect("failed to write");
+        let ref_path = dir.path().join(".git/refs/heads/topic");
+
+        let lock = LockFile::acquire(&ref_path, &Retry::none()).unwrap();
+        assert!(delete_ref(dir.path(), "refs/heads/topic").is_err());
+        assert!(ref_path.exists());
+        drop(lock);
+
+        delete_ref(dir.path(), "refs/heads/topic").expect("failed to delete");
+        assert!(!ref_path.exists());
+        assert!(!dir.path().join(".git/refs/heads/topic.lock").exists());
+        delete_ref(dir.path(), "refs/heads/topic").expect("failed to delete");
+        delete_ref(dir.path(), "refs/missing/dir").expect("failed to delete");
+    }
+
+    #[test]
+    fn refs_are_listed_in_name_order() {
+        let dir = TempDir::new("refs-order").expect("failed to create tempdir");
+        let builder = RepoBuilder::new().commit("first", files!["README" => "hello\n"]);
+        let first = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        std::fs::write(dir.path().join(".git/packed-refs"), format!("{} refs/heads/b\n{} refs/tags/v1\n", first, first)).unwrap();
+
+        let store = RefStore::new(dir.path());
+        let mut transaction = store.transaction();
+        for name in &["refs/heads/z", "refs/heads/a/b", "refs/heads/a-b", "refs/heads/B"] {
+            transaction = transaction.update(name, &first);
+        }
+        transaction.commit().expect("failed to commit");
+
+        let names: Vec<String> = store.list().expect("failed to list").into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec![
+            "HEAD", "refs/heads/B", "refs/heads/a-b", "refs/heads/a/b", "refs/heads/b",
+            "refs/heads/master", "refs/heads/z", "refs/tags/v1"
+        ]);
+    }
+}
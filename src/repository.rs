@@ -0,0 +1,466 @@
+use std::io::Read;
+use std::path::{ Path, PathBuf };
+use std::sync::OnceLock;
+
+use crate::stores::fs::{ self as gitfs, BlobWriter, LooseWriter, Storage };
+use crate::worktree::{ is_git_dir, Layout };
+use crate::errors::{ ErrorKind, Result };
+use crate::checkout::modes::Modes;
+use crate::config::{ Config, Sources };
+use crate::refs::RefStore;
+use crate::namespace::Namespace;
+use crate::filter::Filters;
+use crate::objects::Type;
+use crate::id::Id;
+use crate::metrics;
+use crate::vfs::OsFs;
+
+#[derive(Clone, Debug)]
+pub struct InitOptions {
+    // Make `path` the git dir itself rather than a worktree with `.git`
+    // under it (--bare).
+    pub bare: bool,
+    // The unborn branch HEAD points at (--initial-branch).
+    pub initial_branch: String
+}
+
+impl Default for InitOptions {
+    fn default() -> InitOptions {
+        InitOptions {
+            bare: false,
+            initial_branch: String::from("master")
+        }
+    }
+}
+
+// One handle on a repository: where it lives, its objects (loose, packed
+// and borrowed through alternates), its refs and its config.
+pub struct Repository {
+    layout: Layout,
+    bare: bool,
+    storage_set: Storage,
+    refs: RefStore,
+    config: Config,
+    // made on the first write, as it reads every pack index.
+    writer: OnceLock<LooseWriter>
+}
+
+impl Repository {
+    // `path` is a worktree, or a bare repository's git dir.
+    pub fn open(path: &Path) -> Result<Repository> {
+        Repository::from_layout(Layout::resolve(path)?)
+    }
+
+    // The repository `path` is in, searched for as git does: see `Discover`.
+    pub fn discover(path: &Path) -> Result<Repository> {
+        Discover::from_env().run(path)
+    }
+
+    // Creates an empty repository at `path`, which is made if need be. As
+    // with `git init`, an existing repository is left as it is: HEAD and
+    // config are only written when missing.
+    pub fn init(path: &Path, options: &InitOptions) -> Result<Repository> {
+        let git_dir = if options.bare { path.to_path_buf() } else { path.join(".git") };
+        for dir in &["objects/pack", "objects/info", "refs/heads", "refs/tags"] {
+            std::fs::create_dir_all(git_dir.join(dir))?;
+        }
+
+        let head = git_dir.join("HEAD");
+        if !head.exists() {
+            std::fs::write(head, format!("ref: refs/heads/{}\n", options.initial_branch))?;
+        }
+        let config = git_dir.join("config");
+        if !config.exists() {
+            let modes = Modes::probe(&git_dir)?;
+            std::fs::write(config, format!(
+                "[core]\n\trepositoryformatversion = 0\n\tfilemode = {}\n\tbare = {}\n{}{}",
+                modes.file_mode,
+                options.bare,
+                if options.bare { "" } else { "\tlogallrefupdates = true\n" },
+                if modes.symlinks { "" } else { "\tsymlinks = false\n" }
+            ))?;
+        }
+
+        Repository::from_layout(Layout::from_git_dir(&OsFs, path, &git_dir)?)
+    }
+
+    pub fn from_layout(layout: Layout) -> Result<Repository> {
+        let config = Config::from_layout(&layout, &Sources::from_env()?)?;
+        let bare = layout.is_bare() || config.get_bool("core.bare") == Some(true);
+        let storage_set = gitfs::from_common_dir(&layout.common_dir, metrics::noop())?;
+        let refs = RefStore::new(&layout.worktree).with_layout(layout.clone());
+        Ok(Repository {
+            layout,
+            bare,
+            storage_set,
+            refs,
+            config,
+            writer: OnceLock::new()
+        })
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    pub fn git_dir(&self) -> &Path {
+        &self.layout.git_dir
+    }
+
+    pub fn common_dir(&self) -> &Path {
+        &self.layout.common_dir
+    }
+
+    // None for a bare repository.
+    pub fn workdir(&self) -> Option<&Path> {
+        if self.bare { None } else { Some(&self.layout.worktree) }
+    }
+
+    pub fn is_bare(&self) -> bool {
+        self.bare
+    }
+
+    pub fn storage_set(&self) -> &Storage {
+        &self.storage_set
+    }
+
+    pub fn refs(&self) -> &RefStore {
+        &self.refs
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    // The same repository seen through one namespace: its refs, HEAD
+    // included, are read and written under `refs/namespaces/<name>/`. The
+    // ref store keeps its listeners and lock retry.
+    pub fn with_namespace(mut self, namespace: Namespace) -> Repository {
+        self.refs = self.refs.with_namespace(namespace);
+        self
+    }
+
+    pub fn namespace(&self) -> Option<&Namespace> {
+        self.refs.namespace()
+    }
+
+    // Streams a blob of `size` bytes into the object store as it is,
+    // without holding it in memory; see `LooseWriter::blob_writer`.
+    pub fn blob_writer(&self, size: u64) -> Result<BlobWriter<'_>> {
+        Ok(self.writer()?.blob_writer(size)?)
+    }
+
+    // Writes the blob read from `input` as it is. Without a `size` it is
+    // spooled to a temp file first to learn its length.
+    pub fn write_blob<R: Read>(&self, input: R, size: Option<u64>) -> Result<Id> {
+        Ok(self.writer()?.write_blob(input, size)?)
+    }
+
+    // Writes worktree content for `path` as `git add` would store it,
+    // through the clean filters and line ending conversion. Those need the
+    // whole file, so only paths they leave alone are streamed.
+    pub fn write_worktree_blob<R: Read>(&self, filters: &Filters, path: &[u8], mut input: R, size: Option<u64>) -> Result<Id> {
+        if !filters.cleans(path) {
+            return self.write_blob(input, size)
+        }
+        let mut data = Vec::new();
+        match size {
+            Some(xs) => {
+                input.take(xs).read_to_end(&mut data)?;
+                if (data.len() as u64) < xs {
+                    return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "blob is smaller than its declared size").into())
+                }
+            },
+            None => { input.read_to_end(&mut data)?; }
+        }
+        let data = filters.clean(path, data)?;
+        Ok(self.writer()?.write(Type::Blob, &data)?)
+    }
+
+    fn writer(&self) -> Result<&LooseWriter> {
+        if let Some(xs) = self.writer.get() {
+            return Ok(xs)
+        }
+        let writer = LooseWriter::new(&self.layout.worktree)?;
+        Ok(self.writer.get_or_init(|| writer))
+    }
+}
+
+// Looks for a repository from a directory upwards: a `.git` dir or gitdir
+// file in each directory, or the directory being a git dir itself, until
+// the search would move up into one of the ceiling dirs. An explicit git
+// dir (GIT_DIR) skips the search; its worktree is then the work tree given
+// (GIT_WORK_TREE) or the starting directory. A namespace (GIT_NAMESPACE)
+// scopes the refs of the repository found.
+#[derive(Clone, Debug, Default)]
+pub struct Discover {
+    git_dir: Option<PathBuf>,
+    work_tree: Option<PathBuf>,
+    ceiling_dirs: Vec<PathBuf>,
+    namespace: Option<String>
+}
+
+impl Discover {
+    pub fn new() -> Discover {
+        Discover::default()
+    }
+
+    // Reads GIT_DIR, GIT_WORK_TREE, GIT_CEILING_DIRECTORIES and
+    // GIT_NAMESPACE.
+    pub fn from_env() -> Discover {
+        let ceiling_dirs = match std::env::var_os("GIT_CEILING_DIRECTORIES") {
+            Some(xs) => std::env::split_paths(&xs).filter(|xs| !xs.as_os_str().is_empty()).collect(),
+            None => Vec::new()
+        };
+        Discover {
+            git_dir: std::env::var_os("GIT_DIR").map(PathBuf::from),
+            work_tree: std::env::var_os("GIT_WORK_TREE").map(PathBuf::from),
+            ceiling_dirs,
+            // git treats an empty GIT_NAMESPACE as none.
+            namespace: std::env::var("GIT_NAMESPACE").ok().filter(|xs| !xs.is_empty())
+        }
+    }
+
+    pub fn git_dir<P: Into<PathBuf>>(mut self, git_dir: P) -> Self {
+        self.git_dir = Some(git_dir.into());
+        self
+    }
+
+    pub fn work_tree<P: Into<PathBuf>>(mut self, work_tree: P) -> Self {
+        self.work_tree = Some(work_tree.into());
+        self
+    }
+
+    pub fn ceiling_dirs<P: Into<PathBuf>>(mut self, ceiling_dirs: Vec<P>) -> Self {
+        self.ceiling_dirs = ceiling_dirs.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Some(String::from(namespace));
+        self
+    }
+
+    pub fn run(&self, path: &Path) -> Result<Repository> {
+        let namespace = match self.namespace {
+            Some(ref name) => Some(Namespace::new(name)?),
+            None => None
+        };
+        let start = path.canonicalize()?;
+        let mut layout = match self.git_dir {
+            Some(ref git_dir) => {
+                let git_dir = start.join(git_dir);
+                if !is_git_dir(&OsFs, &git_dir) {
+                    return Err(ErrorKind::NotARepository(git_dir).into())
+                }
+                Layout::from_git_dir(&OsFs, &start, &git_dir)?
+            },
+            None => self.search(&start)?
+        };
+        if let Some(ref work_tree) = self.work_tree {
+            layout.worktree = start.join(work_tree);
+        }
+        let repo = Repository::from_layout(layout)?;
+        Ok(match namespace {
+            Some(namespace) => repo.with_namespace(namespace),
+            None => repo
+        })
+    }
+
+    fn search(&self, start: &Path) -> Result<Layout> {
+        // compared as canonical paths, like the start; ceilings that don't
+        // exist can't be passed through anyway.
+        let ceilings: Vec<_> = self.ceiling_dirs.iter().filter_map(|xs| xs.canonicalize().ok()).collect();
+        let mut dir = start;
+        loop {
+            let dot_git = dir.join(".git");
+            if dot_git.is_file() || is_git_dir(&OsFs, &dot_git) {
+                return Ok(Layout::resolve(dir)?)
+            }
+            if is_git_dir(&OsFs, dir) {
+                // inside a worktree's own `.git`, the worktree is still
+                // its parent.
+                let worktree = match dir.parent() {
+                    Some(parent) if dir.file_name() == Some(".git".as_ref()) => parent,
+                    _ => dir
+                };
+                return Ok(Layout::from_git_dir(&OsFs, worktree, dir)?)
+            }
+            match dir.parent() {
+                Some(parent) if !ceilings.iter().any(|xs| xs == parent) => dir = parent,
+                _ => return Err(ErrorKind::NotARepository(start.to_path_buf()).into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::errors::ErrorKind;
+    use crate::files;
+    use std::collections::HashMap;
+    use std::io::Write;
+    use std::sync::{ Arc, Mutex };
+
+    use crate::attributes::Attributes;
+    use crate::namespace::Namespace;
+    use crate::stores::fs as gitfs;
+    use crate::filter::Filters;
+    use crate::objects::{ self, Type };
+    use super::{ Discover, InitOptions, Repository };
+
+    #[test]
+    fn discovers_repositories_from_inside() {
+        let dir = TempDir::new("discover").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["src/lib.rs" => "fn main() {}\n"]);
+        let tip = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("src/deep")).unwrap();
+
+        let repo = Discover::new().run(&root.join("src/deep")).expect("failed to discover");
+        assert_eq!(repo.workdir(), Some(root.as_path()));
+        assert_eq!(repo.git_dir(), root.join(".git"));
+        assert!(!repo.is_bare());
+        assert_eq!(repo.refs().read("HEAD").unwrap(), Some(tip.clone()));
+        assert!(repo.storage_set().get_and_load(&tip).unwrap().is_some());
+
+        // from inside the git dir, the worktree is still found.
+        let repo = Discover::new().run(&root.join(".git/refs")).expect("failed to discover");
+        assert_eq!(repo.workdir(), Some(root.as_path()));
+
+        // a gitdir file pointing somewhere else.
+        let linked = root.join("linked");
+        std::fs::create_dir(&linked).unwrap();
+        std::fs::write(linked.join(".git"), format!("gitdir: {}\n", root.join(".git").display())).unwrap();
+        let repo = Discover::new().run(&linked).expect("failed to discover");
+        assert_eq!(repo.workdir(), Some(linked.as_path()));
+        assert_eq!(repo.refs().read("HEAD").unwrap(), Some(tip.clone()));
+
+        // an explicit git dir and work tree skip the search.
+        let outside = TempDir::new("discover-outside").expect("failed to create tempdir");
+        let repo = Discover::new()
+            .git_dir(root.join(".git"))
+            .work_tree(&root)
+            .run(outside.path())
+            .expect("failed to open");
+        assert_eq!(repo.workdir(), Some(root.as_path()));
+        assert_eq!(repo.refs().read("HEAD").unwrap(), Some(tip));
+        assert!(Discover::new().git_dir(root.join("src")).run(outside.path()).is_err());
+    }
+
+    #[test]
+    fn discovers_bare_repositories_and_stops_at_ceilings() {
+        let dir = TempDir::new("discover-bare").expect("failed to create tempdir");
+        RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"])
+            .write(dir.path())
+            .expect("failed to write");
+        let root = dir.path().canonicalize().unwrap();
+        std::fs::rename(root.join(".git"), root.join("repo.git")).unwrap();
+
+        let repo = Discover::new().run(&root.join("repo.git/refs/heads")).expect("failed to discover");
+        assert!(repo.is_bare());
+        assert_eq!(repo.workdir(), None);
+        assert_eq!(repo.git_dir(), root.join("repo.git"));
+        assert!(Repository::open(&root.join("repo.git")).expect("failed to open").is_bare());
+
+        std::fs::create_dir_all(root.join("repo.git/refs/heads/nested")).unwrap();
+        let stopped = Discover::new()
+            .ceiling_dirs(vec![root.join("repo.git/refs")])
+            .run(&root.join("repo.git/refs/heads/nested"));
+        match stopped {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::NotARepository(_))),
+            Ok(_) => panic!("searched past the ceiling")
+        }
+    }
+
+    #[test]
+    fn namespaces_scope_refs() {
+        let dir = TempDir::new("discover-ns").expect("failed to create tempdir");
+        let builder = RepoBuilder::new().commit("first", files!["README" => "hello\n"]);
+        let first = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+
+        let repo = Discover::new().namespace("alice").run(dir.path()).expect("failed to discover");
+        assert_eq!(repo.namespace().map(|ns| ns.name()), Some("alice"));
+        assert_eq!(repo.refs().read("refs/heads/master").unwrap(), None);
+        repo.refs().transaction().update("refs/heads/master", &first).commit().expect("failed to commit");
+        assert!(dir.path().join(".git/refs/namespaces/alice/refs/heads/master").exists());
+        assert_eq!(repo.refs().read("refs/heads/master").unwrap(), Some(first.clone()));
+
+        let root = Repository::open(dir.path()).expect("failed to open");
+        assert!(root.namespace().is_none());
+        let names: Vec<String> = root.refs().list().unwrap().into_iter().map(|(name, _)| name).collect();
+        assert!(names.contains(&String::from("refs/namespaces/alice/refs/heads/master")));
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let listener = seen.clone();
+        root.refs().on_update(move |updates| {
+            listener.lock().unwrap().extend(updates.iter().map(|xs| xs.name.clone()));
+        });
+        let bob = root.with_namespace(Namespace::new("bob").unwrap());
+        assert!(bob.refs().list().unwrap().is_empty());
+        bob.refs().transaction().update("refs/heads/topic", &first).commit().expect("failed to commit");
+        assert_eq!(*seen.lock().unwrap(), vec![String::from("refs/heads/topic")]);
+
+        match Discover::new().namespace("bad..name/").run(dir.path()) {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::BadNamespace(_))),
+            Ok(_) => panic!("bad namespaces are refused")
+        }
+    }
+
+    #[test]
+    fn blobs_are_written_filtered_or_streamed() {
+        let dir = TempDir::new("repo-blobs").expect("failed to create tempdir");
+        RepoBuilder::new().commit("first", files!["README" => "hello\n"]).write(dir.path()).expect("failed to write");
+        let repo = Repository::open(dir.path()).expect("failed to open");
+
+        let mut blob = repo.blob_writer(6).expect("failed to start blob");
+        blob.write_all(b"hello\n").unwrap();
+        assert_eq!(blob.finish().unwrap(), objects::hash(Type::Blob, b"hello\n"));
+
+        let mut attributes = Attributes::new();
+        attributes.add(b"", b"*.txt text\n");
+        let filters = Filters::with_drivers(HashMap::new(), attributes, dir.path());
+        let crlf = b"one\r\ntwo\r\n";
+        let text = repo.write_worktree_blob(&filters, b"notes.txt", &crlf[..], None).expect("failed to write");
+        assert_eq!(text, objects::hash(Type::Blob, b"one\ntwo\n"));
+        let raw = repo.write_worktree_blob(&filters, b"data.bin", &crlf[..], Some(crlf.len() as u64)).expect("failed to write");
+        assert_eq!(raw, objects::hash(Type::Blob, crlf));
+        assert!(repo.write_worktree_blob(&filters, b"short.txt", &b"one"[..], Some(10)).is_err());
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        assert!(storage_set.get_and_load(&text).unwrap().is_some());
+        assert!(storage_set.get_and_load(&raw).unwrap().is_some());
+    }
+
+    #[test]
+    fn initializes_repositories() {
+        let dir = TempDir::new("init").expect("failed to create tempdir");
+        let path = dir.path().join("work");
+        let options = InitOptions { initial_branch: String::from("main"), ..InitOptions::default() };
+        let repo = Repository::init(&path, &options).expect("failed to init");
+        assert!(!repo.is_bare());
+        assert_eq!(repo.workdir(), Some(path.as_path()));
+        assert_eq!(std::fs::read_to_string(path.join(".git/HEAD")).unwrap(), "ref: refs/heads/main\n");
+        assert_eq!(repo.config().get_bool("core.bare"), Some(false));
+        assert_eq!(repo.refs().read("HEAD").unwrap(), None);
+
+        // what git init makes, this crate (and git) finds again.
+        let reopened = Discover::new().run(&path).expect("failed to discover");
+        assert_eq!(reopened.git_dir(), path.canonicalize().unwrap().join(".git"));
+
+        // a second init keeps what is there.
+        let again = Repository::init(&path, &InitOptions::default()).expect("failed to reinit");
+        assert_eq!(std::fs::read_to_string(again.git_dir().join("HEAD")).unwrap(), "ref: refs/heads/main\n");
+
+        let bare = Repository::init(&dir.path().join("bare.git"), &InitOptions { bare: true, ..InitOptions::default() })
+            .expect("failed to init");
+        assert!(bare.is_bare());
+        assert_eq!(bare.workdir(), None);
+        assert_eq!(bare.config().get_bool("core.bare"), Some(true));
+        assert!(Repository::open(&dir.path().join("bare.git")).expect("failed to open").is_bare());
+    }
+}
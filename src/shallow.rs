@@ -0,0 +1,171 @@
+use std::collections::HashSet;
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::io::Write;
+
+use crate::lock::{ LockFile, Retry };
+use crate::worktree::common_dir;
+use crate::id::Id;
+
+// The set of commits recorded in `.git/shallow`. Their parents were never
+// fetched, so history walks treat them as root commits.
+#[derive(Debug, Default)]
+pub struct Shallow {
+    path: Option<PathBuf>,
+    ids: HashSet<Id>
+}
+
+impl Shallow {
+    pub fn from_path(path: &Path) -> Result<Shallow, std::io::Error> {
+        let mut root = common_dir(path)?;
+        root.push("shallow");
+
+        let ids = match std::fs::read(root.as_path()) {
+            Ok(buffer) => Shallow::parse(&buffer)?,
+            Err(e) => {
+                match e.kind() {
+                    std::io::ErrorKind::NotFound => HashSet::new(),
+                    _ => return Err(e)
+                }
+            }
+        };
+
+        Ok(Shallow {
+            path: Some(root),
+            ids
+        })
+    }
+
+    fn parse(buffer: &[u8]) -> Result<HashSet<Id>, std::io::Error> {
+        let contents = match std::str::from_utf8(buffer) {
+            Ok(xs) => xs,
+            Err(_) => return Err(std::io::ErrorKind::InvalidData.into())
+        };
+
+        let mut ids = HashSet::new();
+        for line in contents.lines() {
+            if line.trim().is_empty() {
+                continue
+            }
+
+            match Id::from_str(line) {
+                Ok(id) => ids.insert(id),
+                Err(_) => return Err(std::io::ErrorKind::InvalidData.into())
+            };
+        }
+
+        Ok(ids)
+    }
+
+    pub fn is_shallow(&self) -> bool {
+        !self.ids.is_empty()
+    }
+
+    pub fn contains(&self, id: &Id) -> bool {
+        self.ids.contains(id)
+    }
+
+    pub fn ids(&self) -> &HashSet<Id> {
+        &self.ids
+    }
+
+    // Record the outcome of a deepen: `added` are the new boundary commits,
+    // `removed` are former boundaries whose parents are now present.
+    pub fn update(&mut self, added: &[Id], removed: &[Id]) {
+        for id in removed {
+            self.ids.remove(id);
+        }
+
+        for id in added {
+            self.ids.insert(id.clone());
+        }
+    }
+
+    pub fn write(&self) -> Result<(), std::io::Error> {
+        let path = match self.path {
+            Some(ref xs) => xs,
+            None => return Err(std::io::ErrorKind::NotFound.into())
+        };
+
+        let mut lock = LockFile::acquire(path, &Retry::none())?;
+        // git removes the file entirely once the repository is complete,
+        // under the lock all the same; dropping the lock releases it.
+        if self.ids.is_empty() {
+            return match std::fs::remove_file(path) {
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                xs => xs
+            }
+        }
+
+        let mut ids: Vec<&Id> = self.ids.iter().collect();
+        ids.sort();
+        for id in ids {
+            writeln!(lock, "{}", id)?;
+        }
+        lock.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::id::Id;
+    use crate::files;
+    use super::Shallow;
+
+    #[test]
+    fn parse_works() {
+        let ids = Shallow::parse(b"0123456789abcdef000000000000000000000000\n\n").expect("failed to parse");
+        assert_eq!(ids.len(), 1);
+        assert!(ids.contains(&Id::from_str("0123456789abcdef000000000000000000000000").unwrap()));
+    }
+
+    #[test]
+    fn parse_rejects_garbage() {
+        assert!(Shallow::parse(b"not an id\n").is_err());
+    }
+
+    #[test]
+    fn update_works() {
+        let first = Id::from(&[1u8; 20]);
+        let second = Id::from(&[2u8; 20]);
+
+        let mut shallow = Shallow::default();
+        shallow.update(&[first.clone()], &[]);
+        assert!(shallow.is_shallow());
+        shallow.update(&[second.clone()], &[first.clone()]);
+        assert!(!shallow.contains(&first));
+        assert!(shallow.contains(&second));
+    }
+
+    #[test]
+    fn emptying_the_set_removes_the_file_under_the_lock() {
+        let dir = TempDir::new("shallow").expect("failed to create tempdir");
+        RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"])
+            .write(dir.path())
+            .expect("failed to write");
+        let file = dir.path().join(".git/shallow");
+        let first = Id::from(&[1u8; 20]);
+
+        let mut shallow = Shallow::from_path(dir.path()).expect("failed to read");
+        shallow.update(&[first.clone()], &[]);
+        shallow.write().expect("failed to write");
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), format!("{}\n", first));
+
+        // someone else's lock stops the removal too.
+        shallow.update(&[], &[first]);
+        std::fs::write(dir.path().join(".git/shallow.lock"), "").unwrap();
+        assert_eq!(shallow.write().unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+        assert!(file.exists());
+
+        std::fs::remove_file(dir.path().join(".git/shallow.lock")).unwrap();
+        shallow.write().expect("failed to write");
+        assert!(!file.exists());
+        assert!(!dir.path().join(".git/shallow.lock").exists());
+        // writing an empty set again is fine.
+        shallow.write().expect("failed to write");
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use crate::errors::Result;
+use crate::ignore::Ignores;
+use crate::config::Config;
+use crate::index::Index;
+use crate::worktree;
+
+#[derive(Clone, Debug)]
+enum Rules {
+    // directories checked out whole, and those only their files are
+    Cone { recursive: BTreeSet<Vec<u8>>, parents: BTreeSet<Vec<u8>> },
+    Patterns(Ignores)
+}
+
+// The paths `info/sparse-checkout` keeps in the worktree. In cone mode the
+// file lists directories: every file at the top level is in, as are the
+// files directly in a parent of a listed directory, and everything under
+// one. Otherwise its lines are gitignore patterns saying what is in, and a
+// path no line matches takes what its closest matching directory gets.
+#[derive(Clone, Debug)]
+pub struct Sparse {
+    rules: Rules
+}
+
+// A cone mode file's directories, or None if its lines aren't the ones
+// `git sparse-checkout set --cone` writes, in which case git reads it as
+// patterns.
+fn parse_cone(contents: &[u8]) -> Option<Rules> {
+    let (mut dirs, mut parents) = (BTreeSet::new(), BTreeSet::new());
+    for line in contents.split(|xs| *xs == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() || line.starts_with(b"#") || line == b"/*" || line == b"!/*/" {
+            continue
+        }
+        if let Some(xs) = line.strip_prefix(b"!/").and_then(|xs| xs.strip_suffix(b"/*/")) {
+            parents.insert(xs.to_vec());
+        } else if let Some(xs) = line.strip_prefix(b"/").and_then(|xs| xs.strip_suffix(b"/")) {
+            if xs.is_empty() || xs.contains(&b'*') {
+                return None
+            }
+            dirs.insert(xs.to_vec());
+        } else {
+            return None
+        }
+    }
+    let recursive = dirs.difference(&parents).cloned().collect();
+    Some(Rules::Cone { recursive, parents })
+}
+
+impl Sparse {
+    pub fn parse(contents: &[u8], cone: bool) -> Sparse {
+        let rules = match parse_cone(contents).filter(|_| cone) {
+            Some(xs) => xs,
+            None => {
+                let mut patterns = Ignores::new();
+                patterns.add(b"", contents);
+                Rules::Patterns(patterns)
+            }
+        };
+        Sparse { rules }
+    }
+
+    // The sparse checkout of the worktree at `path`, if core.sparseCheckout
+    // is set; core.sparseCheckoutCone picks the mode. A missing file keeps
+    // nothing but the top level in cone mode, and nothing at all otherwise.
+    pub fn from_path(path: &Path) -> Result<Option<Sparse>> {
+        let config = Config::from_path(path)?;
+        if config.get_bool("core.sparsecheckout") != Some(true) {
+            return Ok(None)
+        }
+        let file = worktree::git_dir(path)?.join("info").join("sparse-checkout");
+        let contents = match std::fs::read(file) {
+            Ok(xs) => xs,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into())
+        };
+        Ok(Some(Sparse::parse(&contents, config.get_bool("core.sparsecheckoutcone").unwrap_or(false))))
+    }
+
+    // Whether the file at `path` (slash separated, from the root) is
+    // checked out.
+    pub fn includes(&self, path: &[u8]) -> bool {
+        let dirs: Vec<&[u8]> = path.iter().enumerate().rev()
+            .filter(|(_, xs)| **xs == b'/')
+            .map(|(i, _)| &path[..i])
+            .collect();
+        match self.rules {
+            Rules::Cone { ref recursive, ref parents } => match dirs.first() {
+                None => true,
+                Some(dir) => parents.contains(*dir) || dirs.iter().any(|xs| recursive.contains(*xs))
+            },
+            Rules::Patterns(ref patterns) => {
+                std::iter::once((path, false)).chain(dirs.into_iter().map(|xs| (xs, true)))
+                    .find_map(|(xs, is_dir)| patterns.matches(xs, is_dir))
+                    .unwrap_or(false)
+            }
+        }
+    }
+}
+
+// Sets the skip-worktree bit of every entry `sparse` leaves out and clears
+// it from the rest, as `git sparse-checkout reapply` does; None clears it
+// from them all. Conflicted paths are always checked out.
+pub fn apply(index: &mut Index, sparse: Option<&Sparse>) {
+    let entries: Vec<(Vec<u8>, bool)> = index.entries().iter()
+        .map(|xs| (xs.path.clone(), xs.stage == 0 && sparse.is_some_and(|sparse| !sparse.includes(&xs.path))))
+        .collect();
+    for (entry_path, skip) in entries {
+        index.set_skip_worktree(&entry_path, skip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::checkout::Checkout;
+    use crate::stores::fs as gitfs;
+    use crate::index::Index;
+    use crate::files;
+    use super::{ apply, Sparse };
+
+    #[test]
+    fn cone_and_pattern_modes() {
+        let cone = Sparse::parse(b"/*\n!/*/\n/a/\n!/a/*/\n/a/b/\n/c/\n", true);
+        for (path, included) in &[(&b"README"[..], true), (b"a/file", true), (b"a/x/file", false), (b"a/b/file", true), (b"a/b/x/y", true), (b"c/d/e", true), (b"d/file", false)] {
+            assert_eq!(cone.includes(path), *included, "{}", String::from_utf8_lossy(path));
+        }
+
+        let patterns = Sparse::parse(b"/*\n!/*/\n/docs/\n*.md\n!secret.md\n", false);
+        for (path, included) in &[(&b"README"[..], true), (b"src/lib.rs", false), (b"docs/guide/a.txt", true), (b"src/notes.md", true), (b"src/secret.md", false)] {
+            assert_eq!(patterns.includes(path), *included, "{}", String::from_utf8_lossy(path));
+        }
+        // what isn't in cone form is read as patterns.
+        assert!(!Sparse::parse(b"*.md\n", true).includes(b"README"));
+    }
+
+    #[test]
+    fn checkout_and_index_follow_the_sparse_set() {
+        let dir = TempDir::new("sparse").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "a\n", "src/lib.rs" => "b\n", "docs/guide.md" => "c\n"]);
+        let tip = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        std::fs::write(dir.path().join(".git/config"), "[core]\n\tsparseCheckout = true\n\tsparseCheckoutCone = true\n").unwrap();
+        std::fs::create_dir_all(dir.path().join(".git/info")).unwrap();
+        std::fs::write(dir.path().join(".git/info/sparse-checkout"), "/*\n!/*/\n/src/\n").unwrap();
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        Checkout::new(&storage_set, dir.path()).run(None, &tip).expect("failed to check out");
+        assert!(dir.path().join("README").exists());
+        assert!(dir.path().join("src/lib.rs").exists());
+        assert!(!dir.path().join("docs").exists());
+
+        let mut index = Index::from_tree(&storage_set, &tip).unwrap();
+        let sparse = Sparse::from_path(dir.path()).unwrap().expect("sparse checkout is on");
+        apply(&mut index, Some(&sparse));
+        let skipped: Vec<_> = index.entries().iter().filter(|xs| xs.skip_worktree).map(|xs| xs.path.as_slice()).collect();
+        assert_eq!(skipped, vec![&b"docs/guide.md"[..]]);
+        apply(&mut index, None);
+        assert!(index.entries().iter().all(|xs| !xs.skip_worktree));
+    }
+}
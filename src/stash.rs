@@ -0,0 +1,556 @@
+use std::collections::{ BTreeMap, BTreeSet };
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::ffi::OsStr;
+use std::sync::Arc;
+
+use crate::refs::{ delete_ref, update_ref, RefStore };
+use crate::objects::tree::{ write_nested, FileMode, TreeEntry };
+use crate::checkout::{ flatten, Checkout };
+use crate::checkout::modes::Modes;
+use crate::index::{ Entry, Index };
+use crate::stores::{ Queryable, StorageSet };
+use crate::errors::{ ErrorKind, Result };
+use crate::objects::commit::Commit;
+use crate::objects::{ self, Object, Type };
+use crate::stores::fs as gitfs;
+use crate::identity::Identity;
+use crate::abbrev::{ self, abbreviate };
+use crate::attributes::Attributes;
+use crate::merge::file::{ self, Merged };
+use crate::filter::Filters;
+use crate::config::Config;
+use crate::diff::is_binary;
+use crate::reflog;
+use crate::id::Id;
+
+const STASH_REF: &str = "refs/stash";
+
+// `stash@{n}`; entries are listed newest first, so `stash@{0}` is the top.
+#[derive(Debug)]
+pub struct StashEntry {
+    pub id: Id,
+    pub message: String
+}
+
+// Stashes are commits with two parents, as git lays them out:
+//
+//       .----W    W: the worktree; parents are H and I
+//      /    /
+//     H----I      I: the index; its only parent is H
+//
+// Only tracked files are stashed: those in the index, including ones added
+// since HEAD. They are stashed as they would be staged, through the clean
+// filters and line ending conversion. Afterwards the index and worktree are
+// reset to HEAD.
+pub fn push<S: Queryable>(path: &Path, storage_set: &StorageSet<S>, identity: &Identity, message: Option<&str>) -> Result<Option<Id>> {
+    let (head_name, head) = RefStore::new(path).resolve_ref("HEAD")?;
+    let head = match head {
+        Some(xs) => xs,
+        None => return Err(ErrorKind::MissingObject.into())
+    };
+    let branch = match head_name.strip_prefix("refs/heads/") {
+        Some(xs) => String::from(xs),
+        None => String::from("(no branch)")
+    };
+    let filters = repository_filters(path, storage_set, &head)?;
+    let head_commit = load_commit(storage_set, &head)?;
+    let head_tree = match head_commit.tree() {
+        Some(xs) => xs,
+        None => return Err(ErrorKind::MissingObject.into())
+    };
+
+    let mut put = |typ, data: Vec<u8>| -> Result<Id> { Ok(gitfs::write_loose(path, typ, &data)?) };
+    let index = Index::open_or_from_tree(path, storage_set, &head)?;
+    let index_tree = index.write_tree(&mut put)?;
+    let staged: BTreeMap<Vec<u8>, TreeEntry> = index.entries().iter()
+        .filter(|xs| !xs.intent_to_add)
+        .map(|xs| (xs.path.clone(), TreeEntry { mode: xs.mode, id: xs.id.clone() }))
+        .collect();
+
+    let base = flatten(storage_set, &head)?;
+    let modes = Modes::from_path(path)?;
+    let mut worktree = BTreeMap::new();
+    let mut changed = index_tree != head_tree;
+    for (entry_path, entry) in &staged {
+        if entry.mode.is_gitlink() {
+            worktree.insert(entry_path.clone(), entry.clone());
+            continue
+        }
+
+        match worktree_entry(path, &filters, entry_path, Some(entry.mode), &modes)? {
+            Some((current, contents)) => {
+                if current != *entry {
+                    changed = true;
+                    gitfs::write_loose(path, Type::Blob, &contents)?;
+                }
+                worktree.insert(entry_path.clone(), current);
+            },
+            None => changed = true
+        }
+    }
+
+    if !changed {
+        return Ok(None)
+    }
+
+    let subject = head_commit.message().split(|xs| *xs == b'\n').next().unwrap_or(b"");
+    let summary = format!("{}: {} {}", branch, abbreviate(&head, abbrev::length(path)?), String::from_utf8_lossy(subject));
+    let message = match message {
+        Some(xs) => format!("On {}: {}", branch, xs),
+        None => format!("WIP on {}", summary)
+    };
+
+    let index_commit = write_commit(&mut put, &index_tree, std::slice::from_ref(&head), identity, &format!("index on {}", summary))?;
+    let worktree_tree = write_nested(&worktree, &mut put)?;
+    let stash = write_commit(&mut put, &worktree_tree, &[head.clone(), index_commit], identity, &message)?;
+
+    let previous = list(path)?.first().map(|xs| xs.id.clone());
+    update_ref(path, STASH_REF, &stash)?;
+    reflog::append(path, STASH_REF, &reflog::Entry::new(previous.as_ref(), &stash, identity, &message))?;
+
+    // put the stashed paths back the way HEAD has them; those it doesn't
+    // have go.
+    let checkout = Checkout::new(storage_set, path).filters(filters);
+    for (entry_path, entry) in &base {
+        if worktree.get(entry_path) != Some(entry) {
+            checkout.write_entry(entry_path, entry)?;
+        }
+    }
+    for entry_path in staged.keys().filter(|xs| !base.contains_key(*xs)) {
+        checkout.remove_entry(entry_path)?;
+    }
+    Index::from_tree(storage_set, &head)?.save(path)?;
+
+    Ok(Some(stash))
+}
+
+pub fn list(path: &Path) -> Result<Vec<StashEntry>> {
+    Ok(reflog::read(path, STASH_REF)?.into_iter().rev().map(|entry| {
+        StashEntry {
+            id: entry.new,
+            message: entry.message
+        }
+    }).collect())
+}
+
+// Replays the changes `stash@{n}` recorded onto the worktree and the index.
+// A file changed both in the stash and in the worktree since is merged line
+// by line, as `git stash apply` does; regions both changed are written with
+// conflict markers and recorded as conflicts in the index, and the apply
+// fails with `StashConflict` once everything is written. Changes that can't
+// be merged that way (a side deleted the file, binary files, symlinks, or
+// staged changes the index has moved on from) fail it before anything is
+// written.
+pub fn apply<S: Queryable>(path: &Path, storage_set: &StorageSet<S>, n: usize) -> Result<()> {
+    let stash = match list(path)?.into_iter().nth(n) {
+        Some(xs) => xs.id,
+        None => return Err(ErrorKind::NoSuchStash(n).into())
+    };
+    let parents = load_commit(storage_set, &stash)?.parents().unwrap_or_default();
+    let base = match parents.first() {
+        Some(xs) => xs.clone(),
+        None => return Err(ErrorKind::MissingObject.into())
+    };
+    let head = match RefStore::new(path).read("HEAD")? {
+        Some(xs) => xs,
+        None => return Err(ErrorKind::MissingObject.into())
+    };
+
+    let before = flatten(storage_set, &base)?;
+    let after = flatten(storage_set, &stash)?;
+    let staged = match parents.get(1) {
+        Some(xs) => flatten(storage_set, xs)?,
+        None => before.clone()
+    };
+    let paths: BTreeSet<&Vec<u8>> = before.keys().chain(after.keys()).collect();
+
+    let filters = repository_filters(path, storage_set, &head)?;
+    let modes = Modes::from_path(path)?;
+    let options = file::Options {
+        ours_label: String::from("Updated upstream"),
+        theirs_label: String::from("Stashed changes"),
+        ..file::Options::default()
+    };
+    let mut changes = Vec::new();
+    let mut merges = Vec::new();
+    let mut conflicts = Vec::new();
+    for entry_path in paths {
+        let (old, new) = (before.get(entry_path), after.get(entry_path));
+        if old == new || old.is_some_and(|xs| xs.mode.is_gitlink()) || new.is_some_and(|xs| xs.mode.is_gitlink()) {
+            continue
+        }
+
+        let (current, contents) = match worktree_entry(path, &filters, entry_path, old.map(|xs| xs.mode), &modes)? {
+            Some((entry, contents)) => (Some(entry), contents),
+            None => (None, Vec::new())
+        };
+        if current.as_ref() == new {
+            continue
+        }
+        if current.as_ref() == old {
+            changes.push((entry_path, new));
+            continue
+        }
+
+        // changed on both sides: only text files both still have merge.
+        let (old, current, new) = match (old, current, new) {
+            (Some(old), Some(current), Some(new)) if [old.mode, current.mode, new.mode].iter().all(|xs| *xs != FileMode::SYMLINK) => (old, current, new),
+            _ => {
+                conflicts.push(entry_path.clone());
+                continue
+            }
+        };
+        let (original, stashed) = (load_blob(storage_set, &old.id)?, load_blob(storage_set, &new.id)?);
+        if [&original, &contents, &stashed].iter().any(|xs| is_binary(xs)) {
+            conflicts.push(entry_path.clone());
+            continue
+        }
+        let merged = file::merge(&original, &contents, &stashed, &options);
+        // a mode change one side made carries over.
+        let mode = if current.mode == old.mode { new.mode } else { current.mode };
+        merges.push((entry_path, old, (current, contents), new, mode, merged));
+    }
+
+    let mut index = Index::open_or_from_tree(path, storage_set, &head)?;
+    let mut index_changes = Vec::new();
+    let paths: BTreeSet<&Vec<u8>> = before.keys().chain(staged.keys()).collect();
+    for entry_path in paths {
+        let (old, new) = (before.get(entry_path), staged.get(entry_path));
+        if old == new {
+            continue
+        }
+
+        let current = index.get(entry_path, 0).map(|xs| TreeEntry { mode: xs.mode, id: xs.id.clone() });
+        if current.as_ref() == new {
+            continue
+        }
+        if current.as_ref() != old {
+            if !conflicts.contains(entry_path) {
+                conflicts.push(entry_path.clone());
+            }
+            continue
+        }
+        index_changes.push((entry_path, new));
+    }
+
+    if !conflicts.is_empty() {
+        return Err(ErrorKind::StashConflict(conflicts).into())
+    }
+
+    let mut conflicted = Vec::new();
+    let mut merged_entries = Vec::new();
+    for (entry_path, old, (current, contents), new, mode, merged) in merges {
+        let Merged { contents: merged_contents, conflicts: regions } = merged;
+        let id = gitfs::write_loose(path, Type::Blob, &merged_contents)?;
+        if regions > 0 {
+            // ours as the index will record it.
+            gitfs::write_loose(path, Type::Blob, &contents)?;
+            index.add_conflict(entry_path, Some(old), Some(&current), Some(new));
+            conflicted.push(entry_path.clone());
+        }
+        merged_entries.push((entry_path, TreeEntry { mode, id }));
+    }
+
+    // reopened, so the merged blobs written above can be checked out.
+    let written = gitfs::from(path)?;
+    let checkout = Checkout::new(&written, path).filters(filters);
+    for (entry_path, entry) in changes {
+        match entry {
+            Some(xs) => checkout.write_entry(entry_path, xs)?,
+            None => {
+                checkout.remove_entry(entry_path)?;
+            }
+        }
+    }
+    for (entry_path, entry) in &merged_entries {
+        checkout.write_entry(entry_path, entry)?;
+    }
+
+    for (entry_path, entry) in index_changes {
+        match entry {
+            Some(xs) => index.add(Entry::new(entry_path.clone(), xs.mode, xs.id.clone())),
+            None => { index.remove(entry_path); }
+        }
+    }
+    index.save(path)?;
+
+    if !conflicted.is_empty() {
+        return Err(ErrorKind::StashConflict(conflicted).into())
+    }
+    Ok(())
+}
+
+pub fn pop<S: Queryable>(path: &Path, storage_set: &StorageSet<S>, n: usize) -> Result<()> {
+    apply(path, storage_set, n)?;
+    drop(path, n)
+}
+
+pub fn drop(path: &Path, n: usize) -> Result<()> {
+    let mut entries = reflog::read(path, STASH_REF)?;
+    if n >= entries.len() {
+        return Err(ErrorKind::NoSuchStash(n).into())
+    }
+    let idx = entries.len() - 1 - n;
+    entries.remove(idx);
+
+    // keep the log chained: each entry's old id is its predecessor's new id.
+    if idx < entries.len() {
+        entries[idx].old = if idx == 0 { Id::default() } else { entries[idx - 1].new.clone() };
+    }
+
+    reflog::write(path, STASH_REF, &entries)?;
+    match entries.last() {
+        Some(top) => update_ref(path, STASH_REF, &top.new)?,
+        None => delete_ref(path, STASH_REF)?
+    }
+    Ok(())
+}
+
+fn load_commit<S: Queryable>(storage_set: &StorageSet<S>, id: &Id) -> Result<Commit> {
+    match storage_set.get_and_load(id)? {
+        Some(Object::Commit(xs)) => Ok(xs),
+        _ => Err(ErrorKind::MissingObject.into())
+    }
+}
+
+fn load_blob<S: Queryable>(storage_set: &StorageSet<S>, id: &Id) -> Result<Vec<u8>> {
+    match storage_set.get_and_load(id)? {
+        Some(Object::Blob(xs)) => Ok(xs.contents),
+        _ => Err(ErrorKind::MissingObject.into())
+    }
+}
+
+// The filters and line ending conversion the repository is set up for,
+// with attributes as HEAD has them.
+fn repository_filters<S: Queryable>(path: &Path, storage_set: &StorageSet<S>, head: &Id) -> Result<Arc<Filters>> {
+    let attributes = Attributes::from_tree(storage_set, head)?;
+    Ok(Arc::new(Filters::new(&Config::from_path(path)?, attributes, path)))
+}
+
+// A worktree file as it would be staged, and its contents so: cleaned,
+// unless it is a symlink.
+fn worktree_entry(path: &Path, filters: &Filters, entry_path: &[u8], recorded: Option<FileMode>, modes: &Modes) -> Result<Option<(TreeEntry, Vec<u8>)>> {
+    let (mode, contents) = match read_worktree_as(path, entry_path, recorded, modes)? {
+        Some(xs) => xs,
+        None => return Ok(None)
+    };
+    let contents = if mode == FileMode::SYMLINK { contents } else { filters.clean(entry_path, contents)? };
+    Ok(Some((TreeEntry { mode, id: objects::hash(Type::Blob, &contents) }, contents)))
+}
+
+fn write_commit<F>(put: &mut F, tree: &Id, parents: &[Id], identity: &Identity, message: &str) -> Result<Id>
+    where F: FnMut(Type, Vec<u8>) -> Result<Id> {
+    let mut data = Vec::new();
+    Commit::write(&mut data, tree, parents, identity, identity, format!("{}\n", message).as_bytes())?;
+    put(Type::Commit, data)
+}
+
+// `read_worktree`, keeping the `recorded` mode where the filesystem
+// can't tell (see `Modes`).
+pub(crate) fn read_worktree_as(path: &Path, entry_path: &[u8], recorded: Option<FileMode>, modes: &Modes) -> Result<Option<(FileMode, Vec<u8>)>> {
+    Ok(read_worktree(path, entry_path)?.map(|(mode, contents)| (modes.stage(recorded, mode), contents)))
+}
+
+// The mode and contents of a tracked path as it is on disk; None when it is
+// gone (or replaced by a directory).
+pub(crate) fn read_worktree(path: &Path, entry_path: &[u8]) -> Result<Option<(FileMode, Vec<u8>)>> {
+    let full_path = path.join(OsStr::from_bytes(entry_path));
+    let metadata = match std::fs::symlink_metadata(full_path.as_path()) {
+        Ok(xs) => xs,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into())
+    };
+
+    if metadata.file_type().is_symlink() {
+        let target = std::fs::read_link(full_path.as_path())?;
+        return Ok(Some((FileMode::SYMLINK, target.as_os_str().as_bytes().to_vec())))
+    }
+
+    if metadata.is_dir() {
+        return Ok(None)
+    }
+
+    let mode = if metadata.permissions().mode() & 0o100 != 0 { FileMode::EXECUTABLE } else { FileMode::FILE };
+    Ok(Some((mode, std::fs::read(full_path)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{ FixedOffset, TimeZone, Utc };
+
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::checkout::Checkout;
+    use crate::identity::Identity;
+    use crate::stores::fs as gitfs;
+    use crate::objects::tree::FileMode;
+    use crate::objects::Type;
+    use crate::index::{ Entry, Index };
+    use crate::errors::ErrorKind;
+    use crate::files;
+    use super::{ apply, list, load_commit, pop, push };
+
+    fn identity() -> Identity {
+        Identity::new(
+            b"Test User",
+            b"test@example.com",
+            Utc.timestamp_opt(1_545_300_000, 0).unwrap(),
+            FixedOffset::east_opt(0).unwrap()
+        )
+    }
+
+    #[test]
+    fn push_and_pop_work() {
+        let dir = TempDir::new("stash").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n", "gone" => "bye\n"]);
+        let first = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        {
+            let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+            Checkout::new(&storage_set, dir.path()).run(None, &first).expect("failed to check out");
+
+            assert!(push(dir.path(), &storage_set, &identity(), None).expect("failed to stash").is_none());
+
+            std::fs::write(dir.path().join("README"), "changed\n").unwrap();
+            std::fs::remove_file(dir.path().join("gone")).unwrap();
+            push(dir.path(), &storage_set, &identity(), Some("saved")).expect("failed to stash");
+            assert_eq!(std::fs::read_to_string(dir.path().join("README")).unwrap(), "hello\n");
+            assert!(dir.path().join("gone").exists());
+        }
+
+        let stashes = list(dir.path()).expect("failed to list");
+        assert_eq!(stashes.len(), 1);
+        assert_eq!(stashes[0].message, "On master: saved");
+
+        // stash objects were written after the last store was opened.
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        pop(dir.path(), &storage_set, 0).expect("failed to pop");
+        assert_eq!(std::fs::read_to_string(dir.path().join("README")).unwrap(), "changed\n");
+        assert!(!dir.path().join("gone").exists());
+        assert!(list(dir.path()).expect("failed to list").is_empty());
+        assert!(!dir.path().join(".git/refs/stash").exists());
+    }
+
+    #[test]
+    fn index_is_stashed_and_restored() {
+        let dir = TempDir::new("stash-index").expect("failed to create tempdir");
+        let builder = RepoBuilder::new().commit("first", files!["README" => "hello\n"]);
+        let first = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        let staged = gitfs::write_loose(dir.path(), Type::Blob, b"staged\n").unwrap();
+        let added = gitfs::write_loose(dir.path(), Type::Blob, b"added\n").unwrap();
+        let stash = {
+            let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+            Checkout::new(&storage_set, dir.path()).run(None, &first).expect("failed to check out");
+            let mut index = Index::from_tree(&storage_set, &first).unwrap();
+            index.add(Entry::new(b"README".to_vec(), FileMode::FILE, staged.clone()));
+            index.add(Entry::new(b"new".to_vec(), FileMode::FILE, added.clone()));
+            index.save(dir.path()).unwrap();
+            std::fs::write(dir.path().join("README"), "unstaged\n").unwrap();
+            std::fs::write(dir.path().join("new"), "added\n").unwrap();
+
+            let stash = push(dir.path(), &storage_set, &identity(), None).expect("failed to stash").unwrap();
+            assert_eq!(std::fs::read_to_string(dir.path().join("README")).unwrap(), "hello\n");
+            assert!(!dir.path().join("new").exists());
+            let index = Index::open(dir.path()).unwrap();
+            assert!(index.get(b"new", 0).is_none());
+            assert_ne!(index.get(b"README", 0).unwrap().id, staged);
+            stash
+        };
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let parents = load_commit(&storage_set, &stash).unwrap().parents().unwrap();
+        let index_tree = load_commit(&storage_set, &parents[1]).unwrap().tree().unwrap();
+        let recorded = Index::from_tree(&storage_set, &index_tree).unwrap();
+        assert_eq!(recorded.get(b"README", 0).unwrap().id, staged);
+        assert_eq!(recorded.get(b"new", 0).unwrap().id, added);
+
+        pop(dir.path(), &storage_set, 0).expect("failed to pop");
+        assert_eq!(std::fs::read_to_string(dir.path().join("README")).unwrap(), "unstaged\n");
+        assert_eq!(std::fs::read_to_string(dir.path().join("new")).unwrap(), "added\n");
+        let index = Index::open(dir.path()).unwrap();
+        assert_eq!(index.get(b"README", 0).unwrap().id, staged);
+        assert_eq!(index.get(b"new", 0).unwrap().id, added);
+    }
+
+    #[test]
+    fn apply_merges_overlapping_changes() {
+        let dir = TempDir::new("stash-conflict").expect("failed to create tempdir");
+        let builder = RepoBuilder::new().commit("first", files!["README" => "hello\n", "lines" => "1\n2\n3\n4\n5\n", "gone" => "bye\n"]);
+        let first = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        {
+            let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+            Checkout::new(&storage_set, dir.path()).run(None, &first).expect("failed to check out");
+            std::fs::write(dir.path().join("README"), "stashed\n").unwrap();
+            std::fs::write(dir.path().join("lines"), "one\n2\n3\n4\n5\n").unwrap();
+            std::fs::write(dir.path().join("gone"), "edited\n").unwrap();
+            push(dir.path(), &storage_set, &identity(), None).expect("failed to stash");
+        }
+
+        // a file deleted since can't be merged: nothing is written.
+        std::fs::write(dir.path().join("README"), "local\n").unwrap();
+        std::fs::remove_file(dir.path().join("gone")).unwrap();
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        match apply(dir.path(), &storage_set, 0) {
+            Err(e) => match e.kind() {
+                ErrorKind::StashConflict(paths) => assert_eq!(paths, &vec![b"gone".to_vec()]),
+                _ => panic!("unexpected error")
+            },
+            Ok(_) => panic!("expected a conflict")
+        }
+        assert_eq!(std::fs::read_to_string(dir.path().join("README")).unwrap(), "local\n");
+        assert_eq!(std::fs::read_to_string(dir.path().join("lines")).unwrap(), "1\n2\n3\n4\n5\n");
+
+        // edits elsewhere in a file merge; the same line edited both ways
+        // is left marked, and the stash stays for another try.
+        std::fs::write(dir.path().join("gone"), "bye\n").unwrap();
+        std::fs::write(dir.path().join("lines"), "1\n2\n3\n4\nfive\n").unwrap();
+        match pop(dir.path(), &storage_set, 0) {
+            Err(e) => match e.kind() {
+                ErrorKind::StashConflict(paths) => assert_eq!(paths, &vec![b"README".to_vec()]),
+                _ => panic!("unexpected error")
+            },
+            Ok(_) => panic!("expected a conflict")
+        }
+        assert_eq!(std::fs::read_to_string(dir.path().join("lines")).unwrap(), "one\n2\n3\n4\nfive\n");
+        assert_eq!(std::fs::read_to_string(dir.path().join("gone")).unwrap(), "edited\n");
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("README")).unwrap(),
+            "<<<<<<< Updated upstream\nlocal\n=======\nstashed\n>>>>>>> Stashed changes\n"
+        );
+        let conflicts = Index::open(dir.path()).unwrap().conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].path, b"README");
+        assert!(list(dir.path()).expect("failed to list")[0].message.starts_with("WIP on master: "));
+    }
+
+    #[test]
+    fn push_stashes_files_as_they_would_be_staged() {
+        let dir = TempDir::new("stash-filters").expect("failed to create tempdir");
+        let builder = RepoBuilder::new().commit("first", files![".gitattributes" => "*.txt text eol=crlf\n", "notes.txt" => "a\nb\n"]);
+        let first = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        let stash = {
+            let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+            Checkout::new(&storage_set, dir.path()).run(None, &first).expect("failed to check out");
+            // line endings alone are no change.
+            std::fs::write(dir.path().join("notes.txt"), "a\r\nb\r\n").unwrap();
+            assert!(push(dir.path(), &storage_set, &identity(), None).expect("failed to stash").is_none());
+
+            std::fs::write(dir.path().join("notes.txt"), "a\r\nb\r\nc\r\n").unwrap();
+            let stash = push(dir.path(), &storage_set, &identity(), None).expect("failed to stash").unwrap();
+            assert_eq!(std::fs::read_to_string(dir.path().join("notes.txt")).unwrap(), "a\r\nb\r\n");
+            stash
+        };
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let tree = load_commit(&storage_set, &stash).unwrap().tree().unwrap();
+        let stashed = crate::checkout::flatten(&storage_set, &tree).unwrap();
+        assert_eq!(stashed[&b"notes.txt".to_vec()].id, crate::objects::hash(Type::Blob, b"a\nb\nc\n"));
+        pop(dir.path(), &storage_set, 0).expect("failed to pop");
+        assert_eq!(std::fs::read_to_string(dir.path().join("notes.txt")).unwrap(), "a\r\nb\r\nc\r\n");
+    }
+}
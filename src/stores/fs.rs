@@ -1,49 +1,181 @@
 use crate::stores::loose::{ Store as LooseStore };
-use crate::pack::index::{ read as read_packidx };
+use crate::pack::index::{ read as read_packidx, Indexer, Index };
+use crate::pack::write::{ write_with_progress as write_packfile, Writer as PackWriter };
+use crate::progress::{ NoProgress, Progress };
+use crate::errors::{ Context, ErrorKind as GitErrorKind, Result as GitResult };
 use crate::pack::mmap::Reader as MmapPackReader;
+use crate::pack::cache::{ self, DeltaCache };
+use crate::config::Config;
 use crate::stores::pack::{ Store as PackStore };
-use crate::stores::StorageSet;
+use crate::refs::{ replacements_from_common_dir, RefStore };
+use crate::vfs::{ self, OsFs, VfsProvider, WriteFile };
+use crate::metrics::{ self, Metrics };
+use crate::worktree::{ common_dir, Layout };
+use crate::stores::{ Queryable, StorageSet };
+use crate::objects::{ self, Type };
+use crate::id::Id;
+use crypto::{ sha1::Sha1, digest::Digest };
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
 use memmap::MmapOptions;
 
-use std::path::Path;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::path::{ Path, PathBuf };
+use std::io::{ Read, Write };
+use std::sync::Arc;
 
-pub fn from(path: &Path) -> Result<StorageSet<(Vec<PackStore<MmapPackReader>>, LooseStore)>, std::io::Error> {
-    let packfiles = packfiles_from_path(path)?;
-    let loose = loose_from_path(path)?;
+pub type Backend = (Vec<PackStore<MmapPackReader>>, Vec<LooseStore>);
+
+pub type Storage = StorageSet<Backend>;
+
+pub type VfsPackStore = PackStore<MmapPackReader<Vec<u8>>>;
+
+pub type VfsStorage = StorageSet<(Vec<VfsPackStore>, Vec<LooseStore>)>;
+
+// git stops following alternates of alternates this deep.
+const MAX_ALTERNATE_DEPTH: usize = 5;
+
+pub fn from(path: &Path) -> Result<Storage, std::io::Error> {
+    from_with_metrics(path, metrics::noop())
+}
+
+// Like `from`, but reports to `metrics` from the start, so pack opens are
+// counted too.
+pub fn from_with_metrics(path: &Path, metrics: Arc<dyn Metrics>) -> Result<Storage, std::io::Error> {
+    from_common_dir(&common_dir(path)?, metrics)
+}
+
+// The objects of the repository whose common dir (usually `.git`) is
+// `common_dir`.
+pub fn from_common_dir(common_dir: &Path, metrics: Arc<dyn Metrics>) -> Result<Storage, std::io::Error> {
+    // objects borrowed through alternates are searched after our own.
+    let objects = common_dir.join("objects");
+    let mut packfiles = packfiles_from_dir(&objects)?;
+    let mut loose = vec![loose_from_dir(&objects)?];
+    for dir in alternates_with(&OsFs, &objects)? {
+        packfiles.extend(packfiles_from_dir(&dir)?);
+        loose.push(loose_from_dir(&dir)?);
+    }
+    for _ in &packfiles {
+        metrics.pack_opened();
+    }
+    let packfiles = share_cache(packfiles, &OsFs, common_dir);
+
+    // same opt-out as git's --no-replace-objects
+    let replacements = match std::env::var_os("GIT_NO_REPLACE_OBJECTS") {
+        Some(_) => Default::default(),
+        None => replacements_from_common_dir(common_dir)?
+    };
 
     Ok(StorageSet::new((
         packfiles,
         loose
-    )))
+    )).with_replacements(replacements).with_metrics(metrics))
+}
+
+// Like `from`, but reading the repository at `path` through `vfs`, for
+// targets without an OS filesystem (wasm32, say). Packs are read into memory
+// whole; there is nothing to map.
+pub fn from_vfs(vfs: Arc<dyn VfsProvider>, path: &Path) -> GitResult<VfsStorage> {
+    let objects = Layout::resolve_with(&*vfs, path)?.common_dir.join("objects");
+    let mut packfiles = packfiles_from_vfs(&*vfs, &objects)?;
+    let mut loose = vec![loose_from_vfs(vfs.clone(), &objects)?];
+    for dir in alternates_with(&*vfs, &objects)? {
+        packfiles.extend(packfiles_from_vfs(&*vfs, &dir)?);
+        loose.push(loose_from_vfs(vfs.clone(), &dir)?);
+    }
+    let packfiles = share_cache(packfiles, &*vfs, objects.parent().unwrap_or(&objects));
+
+    let mut replacements = HashMap::new();
+    if std::env::var_os("GIT_NO_REPLACE_OBJECTS").is_none() {
+        for (name, id) in RefStore::new(path).with_vfs(vfs).list()? {
+            if let Some(original) = name.strip_prefix("refs/replace/").and_then(|xs| Id::from_str(xs).ok()) {
+                replacements.insert(original, id);
+            }
+        }
+    }
+
+    Ok(StorageSet::new((
+        packfiles,
+        loose
+    )).with_replacements(replacements))
+}
+
+// Gives every pack a view of one delta base cache, bounded as a whole by
+// core.deltaBaseCacheLimit, rather than a cache of that size each.
+fn share_cache<P: crate::pack::Packfile>(packfiles: Vec<PackStore<P>>, vfs: &dyn VfsProvider, common_dir: &Path) -> Vec<PackStore<P>> {
+    let config = vfs.read(&common_dir.join("config")).ok()
+        .and_then(|xs| Config::parse(&String::from_utf8_lossy(&xs)).ok());
+    let limit = config.and_then(|xs| xs.get_int("core.deltaBaseCacheLimit"))
+        .and_then(|xs| usize::try_from(xs).ok())
+        .unwrap_or(cache::DEFAULT_LIMIT);
+    let bases = DeltaCache::new(limit);
+    packfiles.into_iter().map(|xs| xs.with_cache(bases.share())).collect()
+}
+
+// The object directories listed in `objects/info/alternates`, and in theirs
+// in turn. Relative entries are relative to the listing objects directory.
+pub fn alternates(path: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    alternates_with(&OsFs, &common_dir(path)?.join("objects"))
+}
+
+// The alternates of the objects directory `root`, read through `vfs`.
+pub fn alternates_with(vfs: &dyn VfsProvider, root: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut result: Vec<PathBuf> = Vec::new();
+    let mut stack = vec![(root.to_path_buf(), 0)];
+    while let Some((objects, depth)) = stack.pop() {
+        let listing = match vfs.read(&objects.join("info").join("alternates")) {
+            Ok(xs) => String::from_utf8_lossy(&xs).into_owned(),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e)
+        };
+        for line in listing.lines().map(str::trim).filter(|xs| !xs.is_empty() && !xs.starts_with('#')) {
+            let dir = objects.join(line);
+            let dir = vfs.canonicalize(&dir).unwrap_or(dir);
+            if depth < MAX_ALTERNATE_DEPTH && !result.contains(&dir) {
+                result.push(dir.clone());
+                stack.push((dir, depth + 1));
+            }
+        }
+    }
+    Ok(result)
 }
 
 pub fn loose_from_path(path: &Path) -> Result<LooseStore, std::io::Error> {
-    let mut root = std::path::PathBuf::new();
-    root.push(path);
-    root.push(".git");
-    root.push("objects");
+    loose_from_dir(&common_dir(path)?.join("objects"))
+}
+
+// A loose store over the objects directory `root`.
+pub fn loose_from_dir(root: &Path) -> Result<LooseStore, std::io::Error> {
+    loose_from_vfs(vfs::os(), root)
+}
+
+fn loose_path(root: &Path, id: &Id) -> PathBuf {
+    let as_str = id.to_string();
+    root.join(&as_str[0..2]).join(&as_str[2..40])
+}
 
+pub fn loose_from_vfs(vfs: Arc<dyn VfsProvider>, root: &Path) -> Result<LooseStore, std::io::Error> {
+    let root = root.to_path_buf();
     let mut filter = [false; 256];
-    for entry in std::fs::read_dir(root.as_path())? {
-        let entry = entry?;
-        let os_filename = entry.file_name();
-        if os_filename.len() != 2 {
+    for filename in vfs.read_dir(root.as_path())? {
+        if filename.len() != 2 {
             continue
         }
 
-        let result = match usize::from_str_radix(&os_filename.to_string_lossy(), 16) {
+        let result = match usize::from_str_radix(&filename, 16) {
             Ok(xs) => xs,
             Err(_) => continue
         };
         filter[result] = true;
     }
 
+    let (exists_vfs, exists_root) = (vfs.clone(), root.clone());
     let loose_store = LooseStore::new(move |id| {
-        let as_str = id.to_string();
-        let mut pb = root.clone();
-        pb.push(as_str[0..2].to_string());
-        pb.push(as_str[2..40].to_string());
-        match std::fs::File::open(pb.as_path()) {
+        match vfs.open(loose_path(&root, id).as_path()) {
             Ok(f) => Ok(Some(Box::new(f))),
             Err(e) => {
                 match e.kind() {
@@ -52,18 +184,19 @@ pub fn loose_from_path(path: &Path) -> Result<LooseStore, std::io::Error> {
                 }
             }
         }
-    }, Some(filter));
+    }, Some(filter)).with_exists(move |id| exists_vfs.metadata(&loose_path(&exists_root, id)).is_ok());
 
     Ok(loose_store)
 }
 
 pub fn packfiles_from_path(path: &Path) -> Result<Vec<PackStore<MmapPackReader>>, std::io::Error> {
+    packfiles_from_dir(&common_dir(path)?.join("objects"))
+}
+
+// Every pack under the objects directory `root`.
+pub fn packfiles_from_dir(root: &Path) -> Result<Vec<PackStore<MmapPackReader>>, std::io::Error> {
     let mut stores = vec![];
-    let mut root = std::path::PathBuf::new();
-    root.push(path);
-    root.push(".git");
-    root.push("objects");
-    root.push("pack");
+    let root = root.join("pack");
 
     for entry in std::fs::read_dir(root.as_path())? {
         let entry = entry?;
@@ -78,13 +211,7 @@ pub fn packfiles_from_path(path: &Path) -> Result<Vec<PackStore<MmapPackReader>>
         }
 
         let entry_path = entry.path();
-
-        let index_file = std::fs::File::open(entry_path.clone())?;
-        let index_mmap = unsafe { MmapOptions::new().map(&index_file)? };
-        let idx = match read_packidx(std::io::Cursor::new(index_mmap)) {
-            Ok(xs) => xs,
-            Err(_) => return Err(std::io::ErrorKind::InvalidData.into())
-        };
+        let idx = read_index(entry_path.as_path())?;
 
         let mut epb = entry_path.to_path_buf();
         epb.set_extension("pack");
@@ -92,8 +219,850 @@ pub fn packfiles_from_path(path: &Path) -> Result<Vec<PackStore<MmapPackReader>>
         let file = std::fs::File::open(epb.as_path())?;
         let mmap = unsafe { MmapOptions::new().map(&file)? };
         let packfile = MmapPackReader::new(mmap);
-        stores.push(PackStore::new(packfile, idx));
+        stores.push(PackStore::new(packfile, idx).with_path(epb));
     }
 
     Ok(stores)
 }
+
+// Every pack under the objects directory `root`, read into memory through
+// `vfs` rather than mapped.
+pub fn packfiles_from_vfs(vfs: &dyn VfsProvider, root: &Path) -> Result<Vec<VfsPackStore>, std::io::Error> {
+    let mut stores = vec![];
+    let root = root.join("pack");
+    let mut filenames = match vfs.read_dir(&root) {
+        Ok(xs) => xs,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(stores),
+        Err(e) => return Err(e)
+    };
+    filenames.sort();
+
+    for filename in filenames.iter().filter(|xs| xs.ends_with(".idx")) {
+        let idx_path = root.join(filename);
+        let idx = match read_packidx(std::io::Cursor::new(vfs.read(&idx_path)?)) {
+            Ok(xs) => xs,
+            Err(_) => return Err(std::io::ErrorKind::InvalidData.into())
+        };
+        let pack_path = idx_path.with_extension("pack");
+        let packfile = MmapPackReader::new(vfs.read(&pack_path)?);
+        stores.push(PackStore::new(packfile, idx).with_path(pack_path));
+    }
+
+    Ok(stores)
+}
+
+fn read_index(path: &Path) -> Result<Index, std::io::Error> {
+    let index_file = std::fs::File::open(path)?;
+    let index_mmap = unsafe { MmapOptions::new().map(&index_file)? };
+    match read_packidx(std::io::Cursor::new(index_mmap)) {
+        Ok(xs) => Ok(xs),
+        Err(_) => Err(std::io::ErrorKind::InvalidData.into())
+    }
+}
+
+// The index of every pack in the repository, in directory order.
+pub fn pack_indices(path: &Path) -> Result<Vec<Index>, std::io::Error> {
+    pack_indices_from_dir(&common_dir(path)?.join("objects"))
+}
+
+pub fn pack_indices_from_dir(root: &Path) -> Result<Vec<Index>, std::io::Error> {
+    pack_indices_with(&OsFs, root)
+}
+
+// The index of every pack under the objects directory `root`, read through
+// `vfs`, sorted by pack name.
+pub fn pack_indices_with(vfs: &dyn VfsProvider, root: &Path) -> Result<Vec<Index>, std::io::Error> {
+    let root = root.join("pack");
+    let mut filenames = match vfs.read_dir(&root) {
+        Ok(xs) => xs,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e)
+    };
+    filenames.sort();
+    let mut indices = Vec::new();
+    for filename in filenames.iter().filter(|xs| xs.ends_with(".idx")) {
+        let idx = vfs.map(&root.join(filename))?;
+        match read_packidx(std::io::Cursor::new((*idx).as_ref())) {
+            Ok(xs) => indices.push(xs),
+            Err(_) => return Err(std::io::ErrorKind::InvalidData.into())
+        }
+    }
+    Ok(indices)
+}
+
+// Writes loose objects into a repository, skipping objects it already has.
+// Safe to share between threads and to run alongside other writers (git
+// included): every write goes to its own temp file and is published with a
+// hard link, so a racing writer of the same object is harmless.
+pub struct LooseWriter {
+    vfs: Arc<dyn VfsProvider>,
+    objects: PathBuf,
+    packs: Vec<Index>
+}
+
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// How much of a streamed blob is read at once.
+const BLOB_CHUNK: usize = 64 * 1024;
+
+impl LooseWriter {
+    // Pack indices are read once, here; packs added later are not consulted.
+    pub fn new(path: &Path) -> Result<LooseWriter, std::io::Error> {
+        LooseWriter::with_vfs(vfs::os(), path)
+    }
+
+    // Like `new`, writing the repository at `path` through `vfs`.
+    pub fn with_vfs(vfs: Arc<dyn VfsProvider>, path: &Path) -> Result<LooseWriter, std::io::Error> {
+        let objects = Layout::resolve_with(&*vfs, path)?.common_dir.join("objects");
+        let packs = pack_indices_with(&*vfs, &objects)?;
+        Ok(LooseWriter {
+            vfs,
+            objects,
+            packs
+        })
+    }
+
+    // A writer that only short-circuits on loose objects.
+    fn loose_only(path: &Path) -> Result<LooseWriter, std::io::Error> {
+        Ok(LooseWriter {
+            vfs: vfs::os(),
+            objects: common_dir(path)?.join("objects"),
+            packs: Vec::new()
+        })
+    }
+
+    pub fn write(&self, typ: Type, data: &[u8]) -> Result<Id, std::io::Error> {
+        let id = objects::hash(typ, data);
+        if self.has(&id) {
+            return Ok(id)
+        }
+
+        let as_str = id.to_string();
+        let dir = self.objects.join(&as_str[0..2]);
+        let target = dir.join(&as_str[2..40]);
+
+        // create_dir_all tolerates another writer creating the dir first.
+        self.vfs.create_dir_all(dir.as_path())?;
+        let tmp = dir.join(format!(
+            "tmp_obj_{}_{}_{}",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::SeqCst),
+            &as_str[2..10]
+        ));
+
+        let written = self.vfs.create_new(tmp.as_path()).and_then(|file| {
+            let mut encoder = ZlibEncoder::new(file, Compression::default());
+            write!(encoder, "{} {}\0", typ.as_str(), data.len())?;
+            encoder.write_all(data)?;
+            encoder.finish()?.finish()
+        });
+        if let Err(e) = written {
+            let _ = self.vfs.remove_file(tmp.as_path());
+            return Err(e)
+        }
+
+        publish(&*self.vfs, tmp.as_path(), target.as_path())?;
+        Ok(id)
+    }
+
+    fn has(&self, id: &Id) -> bool {
+        if self.packs.iter().any(|xs| xs.contains(id)) {
+            return true
+        }
+        let as_str = id.to_string();
+        self.vfs.metadata(&self.objects.join(&as_str[0..2]).join(&as_str[2..40])).is_ok()
+    }
+
+    // Streams a blob of `size` bytes into the repository without holding it
+    // in memory; `finish` returns its id.
+    pub fn blob_writer(&self, size: u64) -> Result<BlobWriter<'_>, std::io::Error> {
+        let tmp = self.objects.join(format!(
+            "tmp_obj_{}_{}",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let file = self.vfs.create_new(tmp.as_path())?;
+        let header = format!("{} {}\0", Type::Blob.as_str(), size);
+        let mut hash = Sha1::new();
+        hash.input(header.as_bytes());
+        let mut encoder = ZlibEncoder::new(file, Compression::default());
+        let written = encoder.write_all(header.as_bytes());
+        let writer = BlobWriter {
+            writer: self,
+            tmp,
+            encoder: Some(encoder),
+            hash,
+            size,
+            written: 0
+        };
+        written?;
+        Ok(writer)
+    }
+
+    // Writes the blob read from `input` in BLOB_CHUNK sized pieces, so no
+    // more than one is ever in memory. With `size` only that many bytes are
+    // read, and fewer is an error; without it the blob is first spooled to
+    // a temp file to learn its length.
+    pub fn write_blob<R: Read>(&self, input: R, size: Option<u64>) -> Result<Id, std::io::Error> {
+        let size = match size {
+            Some(xs) => xs,
+            None => {
+                let spool = self.objects.join(format!(
+                    "tmp_spool_{}_{}",
+                    std::process::id(),
+                    TMP_COUNTER.fetch_add(1, Ordering::SeqCst)
+                ));
+                let written = self.spool_blob(input, spool.as_path());
+                let _ = self.vfs.remove_file(spool.as_path());
+                return written
+            }
+        };
+        let mut blob = self.blob_writer(size)?;
+        let mut input = input.take(size);
+        let mut chunk = vec![0u8; BLOB_CHUNK];
+        loop {
+            let count = match input.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(xs) => xs,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e)
+            };
+            blob.write_all(&chunk[..count])?;
+        }
+        blob.finish()
+    }
+
+    fn spool_blob<R: Read>(&self, mut input: R, spool: &Path) -> Result<Id, std::io::Error> {
+        let mut file = self.vfs.create_new(spool)?;
+        let size = std::io::copy(&mut input, &mut file)?;
+        file.finish()?;
+        self.write_blob(self.vfs.open(spool)?, Some(size))
+    }
+}
+
+// Moves a finished temp object into place. Another writer publishing the
+// same object first is fine: theirs is identical.
+fn publish(vfs: &dyn VfsProvider, tmp: &Path, target: &Path) -> Result<(), std::io::Error> {
+    let published = match vfs.hard_link(tmp, target) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        // some filesystems cannot link; rename is the fallback git uses too.
+        Err(_) => vfs.rename(tmp, target),
+        Ok(_) => Ok(())
+    };
+    let _ = vfs.remove_file(tmp);
+    published
+}
+
+// A blob being streamed into a temp loose object, hashed as it is
+// compressed. Nothing is published until `finish`; dropping the writer
+// before then throws the temp file away.
+pub struct BlobWriter<'a> {
+    writer: &'a LooseWriter,
+    tmp: PathBuf,
+    encoder: Option<ZlibEncoder<Box<dyn WriteFile + 'a>>>,
+    hash: Sha1,
+    size: u64,
+    written: u64
+}
+
+impl<'a> Write for BlobWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.written + buf.len() as u64 > self.size {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "blob is larger than its declared size"))
+        }
+        let encoder = self.encoder.as_mut().expect("writer is finished");
+        let count = encoder.write(buf)?;
+        self.hash.input(&buf[..count]);
+        self.written += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> BlobWriter<'a> {
+    pub fn finish(mut self) -> Result<Id, std::io::Error> {
+        if self.written != self.size {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "blob is smaller than its declared size"))
+        }
+        let encoder = self.encoder.take().expect("writer is finished");
+        encoder.finish()?.finish()?;
+
+        let mut id_output = [0u8; 20];
+        self.hash.result(&mut id_output);
+        let id: Id = id_output.into();
+        if self.writer.has(&id) {
+            return Ok(id)
+        }
+
+        let as_str = id.to_string();
+        let dir = self.writer.objects.join(&as_str[0..2]);
+        self.writer.vfs.create_dir_all(dir.as_path())?;
+        publish(&*self.writer.vfs, self.tmp.as_path(), dir.join(&as_str[2..40]).as_path())?;
+        Ok(id)
+    }
+}
+
+impl<'a> Drop for BlobWriter<'a> {
+    fn drop(&mut self) {
+        // dropped early, or the object already existed.
+        self.encoder = None;
+        let _ = self.writer.vfs.remove_file(self.tmp.as_path());
+    }
+}
+
+// Writes one loose object, skipping it if it already exists loose. Use a
+// `LooseWriter` to also skip packed objects or to write many objects.
+pub fn write_loose(path: &Path, typ: Type, data: &[u8]) -> Result<Id, std::io::Error> {
+    LooseWriter::loose_only(path)?.write(typ, data)
+}
+
+// Writes `objects` into a new pack with its index, as `objects/pack/pack-<checksum>`.
+// Readers don't see the pack until its index is in place.
+pub fn write_pack(path: &Path, objects: &[(Type, Vec<u8>)]) -> GitResult<Id> {
+    write_pack_with_progress(path, objects, &NoProgress)
+}
+
+pub fn write_pack_with_progress(path: &Path, objects: &[(Type, Vec<u8>)], progress: &dyn Progress) -> GitResult<Id> {
+    write_pack_with(&OsFs, path, objects, progress)
+}
+
+// Like `write_pack_with_progress`, writing the repository at `path` through
+// `vfs`.
+pub fn write_pack_with(vfs: &dyn VfsProvider, path: &Path, objects: &[(Type, Vec<u8>)], progress: &dyn Progress) -> GitResult<Id> {
+    let dir = Layout::resolve_with(vfs, path)?.common_dir.join("objects").join("pack");
+    vfs.create_dir_all(&dir)?;
+    let mut pack = Vec::new();
+    let checksum = write_packfile(&mut pack, objects, progress)?;
+    let mut idx = Vec::new();
+    Indexer::new().progress(progress).write(std::io::Cursor::new(&pack[..]), &mut idx, None::<&StorageSet<()>>)?;
+    check_indexed(&idx, &checksum)?;
+
+    let name = format!("pack-{}", checksum);
+    vfs.write(&dir.join(format!("{}.pack", name)), &pack)?;
+    let tmp = dir.join(format!("tmp_idx_{}", name));
+    vfs.write(&tmp, &idx)?;
+    vfs.rename(&tmp, &dir.join(format!("{}.idx", name)))?;
+    Ok(checksum)
+}
+
+// Writes the objects `ids` into a new pack as `write_pack` does, reading
+// them from `storage_set` one at a time as the pack is written, so a large
+// pack is never held in memory.
+pub fn write_pack_from<S: Queryable>(path: &Path, storage_set: &StorageSet<S>, ids: &[Id]) -> GitResult<Id> {
+    write_pack_from_with(&OsFs, path, storage_set, ids)
+}
+
+// Like `write_pack_from`, writing the repository at `path` through `vfs`.
+// Packs are only kept out of memory where `vfs` can map them.
+pub fn write_pack_from_with<S: Queryable>(vfs: &dyn VfsProvider, path: &Path, storage_set: &StorageSet<S>, ids: &[Id]) -> GitResult<Id> {
+    let dir = Layout::resolve_with(vfs, path)?.common_dir.join("objects").join("pack");
+    vfs.create_dir_all(&dir)?;
+    let tmp = dir.join(format!("tmp_pack_{}_{}", std::process::id(), TMP_COUNTER.fetch_add(1, Ordering::SeqCst)));
+    let written = stream_pack(vfs, &dir, tmp.as_path(), storage_set, ids);
+    let _ = vfs.remove_file(&tmp);
+    written
+}
+
+fn stream_pack<S: Queryable>(vfs: &dyn VfsProvider, dir: &Path, tmp: &Path, storage_set: &StorageSet<S>, ids: &[Id]) -> GitResult<Id> {
+    let file = vfs.create_new(tmp)?;
+    let mut writer = PackWriter::new(std::io::BufWriter::new(file), ids.len() as u32)?;
+    let mut data = Vec::new();
+    for id in ids {
+        data.clear();
+        let typ = match storage_set.get(id, &mut data)? {
+            Some(xs) => xs,
+            None => return Err(GitErrorKind::MissingObject.into())
+        };
+        writer.add(typ, &data)?;
+    }
+    let (output, checksum) = writer.finish()?;
+    output.into_inner().map_err(|xs| xs.into_error())?.finish()?;
+
+    let pack = vfs.map(tmp)?;
+    let mut idx = Vec::new();
+    Indexer::new().write(std::io::Cursor::new((*pack).as_ref()), &mut idx, None::<&StorageSet<()>>)?;
+    check_indexed(&idx, &checksum)?;
+
+    let name = format!("pack-{}", checksum);
+    publish(vfs, tmp, dir.join(format!("{}.pack", name)).as_path())?;
+    let tmp_idx = dir.join(format!("tmp_idx_{}", name));
+    vfs.write(&tmp_idx, &idx)?;
+    vfs.rename(&tmp_idx, &dir.join(format!("{}.idx", name)))?;
+    Ok(checksum)
+}
+
+// The indexer has checked the pack's trailer against the pack as it reads
+// back; that trailer must also be the checksum the writer computed over the
+// bytes it streamed out, or the pack changed on its way to the disk. An .idx
+// ends with the pack's checksum and then its own.
+fn check_indexed(idx: &[u8], checksum: &Id) -> GitResult<()> {
+    let indexed = Id::from(&idx[idx.len() - 40..idx.len() - 20]);
+    if indexed != *checksum {
+        let context = Context::new("writing pack");
+        return Err(GitErrorKind::PackChecksumMismatch(context.into(), checksum.clone(), indexed).into())
+    }
+    Ok(())
+}
+
+fn keep_path(path: &Path, checksum: &Id) -> Result<PathBuf, std::io::Error> {
+    Ok(common_dir(path)?.join("objects").join("pack").join(format!("pack-{}.keep", checksum)))
+}
+
+// Marks the pack `checksum` as kept with `objects/pack/pack-<checksum>.keep`,
+// holding `reason` as `index-pack --keep=<reason>` does. Repacking and gc
+// leave kept packs alone, so a fetch can keep its pack until the refs
+// pointing into it are in place. A pack already kept keeps its reason.
+pub fn keep_pack(path: &Path, checksum: &Id, reason: &str) -> Result<(), std::io::Error> {
+    let target = keep_path(path, checksum)?;
+    let tmp = target.with_file_name(format!(
+        "tmp_keep_{}_{}",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    let written = std::fs::write(&tmp, format!("{}\n", reason));
+    // a hard link won't replace a marker someone else wrote first.
+    let published = written.and_then(|_| match std::fs::hard_link(&tmp, &target) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        xs => xs
+    });
+    let _ = std::fs::remove_file(&tmp);
+    published
+}
+
+// Drops the pack's keep marker, returning whether it had one.
+pub fn unkeep_pack(path: &Path, checksum: &Id) -> Result<bool, std::io::Error> {
+    match std::fs::remove_file(keep_path(path, checksum)?) {
+        Ok(_) => Ok(true),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e)
+    }
+}
+
+pub fn is_kept(path: &Path, checksum: &Id) -> Result<bool, std::io::Error> {
+    Ok(keep_path(path, checksum)?.exists())
+}
+
+// The checksums of the repository's kept packs, sorted.
+pub fn kept_packs(path: &Path) -> Result<Vec<Id>, std::io::Error> {
+    let dir = common_dir(path)?.join("objects").join("pack");
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(xs) => xs,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e)
+    };
+    let mut kept = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        let checksum = name.strip_prefix("pack-").and_then(|xs| xs.strip_suffix(".keep"));
+        if let Some(id) = checksum.and_then(|xs| Id::from_str(xs).ok()) {
+            kept.push(id);
+        }
+    }
+    kept.sort();
+    Ok(kept)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::path::Path;
+
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::objects::Object;
+    use crate::vfs::{ MemoryFs, VfsProvider };
+    use crate::id::Id;
+    use crate::files;
+
+    #[test]
+    fn replace_refs_are_loaded() {
+        let dir = TempDir::new("fs-replace").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"])
+            .commit("second", files!["README" => "goodbye\n"]);
+        let second = builder.tip().expect("no tip");
+        let builder = builder.commit("third", files![]);
+        let third = builder.tip().expect("no tip");
+        builder.write(dir.path()).expect("failed to write");
+
+        let replace_dir = dir.path().join(".git/refs/replace");
+        std::fs::create_dir_all(&replace_dir).expect("failed to create refs/replace");
+        std::fs::write(replace_dir.join(third.to_string()), format!("{}\n", second)).expect("failed to write ref");
+
+        let storage_set = super::from(dir.path()).expect("failed to open storage");
+        assert_eq!(storage_set.replacements().get(&third), Some(&second));
+        match storage_set.get_and_load(&third).expect("failed to read") {
+            Some(Object::Commit(commit)) => assert_eq!(commit.message(), b"second\n"),
+            _ => panic!("expected commit")
+        }
+    }
+
+    #[test]
+    fn metrics_count_inflated_bytes() {
+        use std::sync::Arc;
+        use crate::metrics::Counters;
+
+        let dir = TempDir::new("fs-metrics").expect("failed to create tempdir");
+        let builder = RepoBuilder::new().commit("first", files!["README" => "hello\n"]);
+        let tip = builder.tip().expect("no tip");
+        builder.write(dir.path()).expect("failed to write");
+
+        let counters = Arc::new(Counters::new());
+        let storage_set = super::from_with_metrics(dir.path(), counters.clone()).expect("failed to open storage");
+        assert!(storage_set.get_and_load(&tip).expect("failed to read").is_some());
+
+        let snapshot = counters.snapshot();
+        assert_eq!(snapshot.commits, 1);
+        assert_eq!(snapshot.packs_opened, 0);
+        // loose objects inflate to their header plus body.
+        assert!(snapshot.bytes_inflated > snapshot.object_bytes);
+    }
+
+    #[test]
+    fn loose_writes_short_circuit_and_tolerate_races() {
+        use std::sync::Arc;
+        use crate::objects::Type;
+        use super::{ write_loose, LooseWriter };
+
+        let dir = TempDir::new("fs-write").expect("failed to create tempdir");
+        RepoBuilder::new().write(dir.path()).expect("failed to write");
+
+        let writer = Arc::new(LooseWriter::new(dir.path()).expect("failed to open writer"));
+        let threads: Vec<_> = (0..8).map(|idx| {
+            let writer = writer.clone();
+            std::thread::spawn(move || {
+                let shared = writer.write(Type::Blob, b"shared\n").expect("failed to write");
+                writer.write(Type::Blob, format!("own {}\n", idx).as_bytes()).expect("failed to write");
+                shared
+            })
+        }).collect();
+        let ids: Vec<_> = threads.into_iter().map(|xs| xs.join().unwrap()).collect();
+        assert!(ids.iter().all(|xs| *xs == ids[0]));
+
+        let objects = dir.path().join(".git/objects");
+        let shared = ids[0].to_string();
+        let fanout = std::fs::read_dir(objects.join(&shared[0..2])).unwrap()
+            .map(|xs| xs.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        assert!(fanout.iter().all(|xs| !xs.starts_with("tmp_obj_")));
+
+        // an existing object is never rewritten.
+        let object_path = objects.join(&shared[0..2]).join(&shared[2..]);
+        std::fs::write(object_path.as_path(), b"sentinel").unwrap();
+        write_loose(dir.path(), Type::Blob, b"shared\n").expect("failed to write");
+        assert_eq!(std::fs::read(object_path).unwrap(), b"sentinel");
+
+        let storage_set = super::from(dir.path()).expect("failed to open storage");
+        let mut output = Vec::new();
+        let own = crate::objects::hash(Type::Blob, b"own 3\n");
+        storage_set.get(&own, &mut output).expect("failed to read");
+        assert_eq!(output, b"own 3\n");
+    }
+
+    #[test]
+    fn loose_writes_skip_packed_objects() {
+        use crate::objects::Type;
+        use super::LooseWriter;
+
+        let dir = TempDir::new("fs-write-packed").expect("failed to create tempdir");
+        RepoBuilder::new().write(dir.path()).expect("failed to write");
+        let pack_dir = dir.path().join(".git/objects/pack");
+        std::fs::write(pack_dir.join("pack-fixture.pack"), &include_bytes!("../../fixtures/packfile")[..]).unwrap();
+        std::fs::write(pack_dir.join("pack-fixture.idx"), &include_bytes!("../../fixtures/pack_index")[..]).unwrap();
+
+        let storage_set = super::from(dir.path()).expect("failed to open storage");
+        let writer = LooseWriter::new(dir.path()).expect("failed to open writer");
+        let index = crate::pack::index::read(&include_bytes!("../../fixtures/pack_index")[..]).expect("failed to read index");
+        let packed = index.ids()[0].clone();
+
+        let mut data = Vec::new();
+        let typ = storage_set.get(&packed, &mut data).expect("failed to read").expect("object is packed");
+        assert_eq!(writer.write(typ, &data).expect("failed to write"), packed);
+        let as_str = packed.to_string();
+        assert!(!dir.path().join(".git/objects").join(&as_str[0..2]).join(&as_str[2..]).exists());
+    }
+
+    #[test]
+    fn get_many_reads_in_pack_order_and_caches_bases() {
+        use std::sync::Arc;
+        use crate::metrics::Counters;
+
+        let dir = TempDir::new("fs-get-many").expect("failed to create tempdir");
+        RepoBuilder::new().write(dir.path()).expect("failed to write");
+        let pack_dir = dir.path().join(".git/objects/pack");
+        std::fs::write(pack_dir.join("pack-fixture.pack"), &include_bytes!("../../fixtures/packfile")[..]).unwrap();
+        std::fs::write(pack_dir.join("pack-fixture.idx"), &include_bytes!("../../fixtures/pack_index")[..]).unwrap();
+
+        let counters = Arc::new(Counters::new());
+        let storage_set = super::from_with_metrics(dir.path(), counters.clone()).expect("failed to open storage");
+        let index = crate::pack::index::read(&include_bytes!("../../fixtures/pack_index")[..]).expect("failed to read index");
+        let mut ids: Vec<Id> = index.ids().iter().rev().cloned().collect();
+        ids.push(crate::objects::hash(crate::objects::Type::Blob, b"not here\n"));
+
+        let objects = storage_set.get_many(&ids).expect("failed to read");
+        assert_eq!(objects.len(), ids.len());
+        assert!(objects.last().unwrap().is_none());
+        for (id, object) in ids.iter().zip(&objects).take(ids.len() - 1) {
+            let (typ, data) = object.as_ref().expect("object is packed");
+            assert_eq!(&crate::objects::hash(*typ, data), id);
+        }
+
+        let batched: Vec<_> = storage_set.batch(ids.clone()).collect::<Result<_, _>>().expect("failed to read");
+        assert_eq!(batched.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>(), ids);
+
+        // 7f1c67 is a delta on 872e26; once the base is cached, reading it
+        // only inflates the delta.
+        let delta = Id::from_str("7f1c6706fbf2edcae73bde0ed0731d01d8f23fe6").unwrap();
+        let before = counters.snapshot().bytes_inflated;
+        storage_set.get(&delta, &mut Vec::new()).expect("failed to read");
+        assert!(counters.snapshot().bytes_inflated - before < 253);
+    }
+
+    #[test]
+    fn existence_checks_read_nothing() {
+        use std::sync::Arc;
+        use crate::metrics::Counters;
+        use crate::objects::{ self, Type };
+
+        let dir = TempDir::new("fs-contains").expect("failed to create tempdir");
+        RepoBuilder::new().write(dir.path()).expect("failed to write");
+        let pack_dir = dir.path().join(".git/objects/pack");
+        std::fs::write(pack_dir.join("pack-fixture.pack"), &include_bytes!("../../fixtures/packfile")[..]).unwrap();
+        std::fs::write(pack_dir.join("pack-fixture.idx"), &include_bytes!("../../fixtures/pack_index")[..]).unwrap();
+        let loose = super::write_loose(dir.path(), Type::Blob, b"loose\n").expect("failed to write");
+        let missing = objects::hash(Type::Blob, b"not here\n");
+
+        let counters = Arc::new(Counters::new());
+        let storage_set = super::from_with_metrics(dir.path(), counters.clone()).expect("failed to open storage");
+        let packed = Id::from_str("7f1c6706fbf2edcae73bde0ed0731d01d8f23fe6").unwrap();
+        assert!(storage_set.contains(&packed));
+        assert!(storage_set.contains(&loose));
+        assert!(!storage_set.contains(&missing));
+        assert_eq!(storage_set.filter_missing(&[missing.clone(), packed, loose, missing.clone()]), vec![missing.clone(), missing]);
+        assert_eq!(counters.snapshot().bytes_inflated, 0);
+    }
+
+    #[test]
+    fn blob_writers_stream_objects_in() {
+        use std::io::Write;
+        use crate::objects::Type;
+        use super::LooseWriter;
+
+        let dir = TempDir::new("fs-blob-writer").expect("failed to create tempdir");
+        RepoBuilder::new().write(dir.path()).expect("failed to write");
+        let writer = LooseWriter::new(dir.path()).expect("failed to open writer");
+
+        let chunk = vec![b'x'; 4096];
+        let mut blob = writer.blob_writer(chunk.len() as u64 * 16).expect("failed to start blob");
+        for _ in 0..16 {
+            blob.write_all(&chunk).expect("failed to write");
+        }
+        let id = blob.finish().expect("failed to finish");
+        let contents = chunk.repeat(16);
+        assert_eq!(id, crate::objects::hash(Type::Blob, &contents));
+
+        let storage_set = super::from(dir.path()).expect("failed to open storage");
+        let mut output = Vec::new();
+        storage_set.get(&id, &mut output).expect("failed to read");
+        assert_eq!(output, contents);
+
+        let mut short = writer.blob_writer(10).expect("failed to start blob");
+        assert!(short.write_all(b"more than ten bytes").is_err());
+        short.write_all(b"five.").expect("failed to write");
+        assert!(short.finish().is_err());
+        writer.blob_writer(4).expect("failed to start blob");
+        let leftovers = std::fs::read_dir(dir.path().join(".git/objects")).unwrap()
+            .filter(|xs| xs.as_ref().unwrap().file_name().to_string_lossy().starts_with("tmp_obj_"))
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn blobs_are_written_from_readers() {
+        use crate::objects::Type;
+        use super::LooseWriter;
+
+        let dir = TempDir::new("fs-write-blob").expect("failed to create tempdir");
+        RepoBuilder::new().write(dir.path()).expect("failed to write");
+        let writer = LooseWriter::new(dir.path()).expect("failed to open writer");
+
+        let contents: Vec<u8> = (0..200_000u32).map(|xs| (xs % 251) as u8).collect();
+        let known = writer.write_blob(&contents[..], Some(contents.len() as u64)).expect("failed to write");
+        assert_eq!(known, crate::objects::hash(Type::Blob, &contents));
+        let unknown = writer.write_blob(&contents[..], None).expect("failed to write");
+        assert_eq!(unknown, known);
+        let storage_set = super::from(dir.path()).expect("failed to open storage");
+        let mut output = Vec::new();
+        storage_set.get(&known, &mut output).expect("failed to read");
+        assert_eq!(output, contents);
+
+        // only `size` bytes are taken, and running out early fails.
+        let prefix = writer.write_blob(&contents[..], Some(10)).expect("failed to write");
+        assert_eq!(prefix, crate::objects::hash(Type::Blob, &contents[..10]));
+        assert!(writer.write_blob(&b"short"[..], Some(10)).is_err());
+        let leftovers = std::fs::read_dir(dir.path().join(".git/objects")).unwrap()
+            .filter(|xs| xs.as_ref().unwrap().file_name().to_string_lossy().starts_with("tmp_"))
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn keep_markers_come_and_go() {
+        use crate::objects::Type;
+
+        let dir = TempDir::new("fs-keep").expect("failed to create tempdir");
+        RepoBuilder::new().write(dir.path()).expect("failed to write");
+        let checksum = super::write_pack(dir.path(), &[(Type::Blob, b"kept\n".to_vec())]).expect("failed to write pack");
+        assert!(!super::is_kept(dir.path(), &checksum).unwrap());
+        assert!(super::kept_packs(dir.path()).unwrap().is_empty());
+
+        super::keep_pack(dir.path(), &checksum, "fetch-pack 1 on host").expect("failed to keep");
+        super::keep_pack(dir.path(), &checksum, "again").expect("failed to keep");
+        let marker = dir.path().join(format!(".git/objects/pack/pack-{}.keep", checksum));
+        assert_eq!(std::fs::read_to_string(marker).unwrap(), "fetch-pack 1 on host\n");
+        assert!(super::is_kept(dir.path(), &checksum).unwrap());
+        assert_eq!(super::kept_packs(dir.path()).unwrap(), vec![checksum.clone()]);
+
+        assert!(super::unkeep_pack(dir.path(), &checksum).unwrap());
+        assert!(!super::unkeep_pack(dir.path(), &checksum).unwrap());
+        assert!(super::kept_packs(dir.path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn indexes_must_record_the_written_checksum() {
+        use crate::objects::Type;
+        use crate::errors::ErrorKind;
+
+        let dir = TempDir::new("fs-pack-checksum").expect("failed to create tempdir");
+        RepoBuilder::new().write(dir.path()).expect("failed to write");
+        let checksum = super::write_pack(dir.path(), &[(Type::Blob, b"checked\n".to_vec())]).expect("failed to write pack");
+        let idx = std::fs::read(dir.path().join(format!(".git/objects/pack/pack-{}.idx", checksum))).unwrap();
+        super::check_indexed(&idx, &checksum).expect("checksums differ");
+
+        let other = crate::objects::hash(Type::Blob, b"other\n");
+        match super::check_indexed(&idx, &other) {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::PackChecksumMismatch(_, expected, actual) if *expected == other && *actual == checksum)),
+            Ok(_) => panic!("expected a mismatch")
+        }
+    }
+
+    #[test]
+    fn write_pack_reports_progress() {
+        use crate::progress::Log;
+        use crate::objects::Type;
+
+        let dir = TempDir::new("fs-pack-progress").expect("failed to create tempdir");
+        RepoBuilder::new().write(dir.path()).expect("failed to write");
+        let objects = vec![(Type::Blob, b"one\n".to_vec()), (Type::Blob, b"two\n".to_vec())];
+        let log = Log::new();
+        super::write_pack_with_progress(dir.path(), &objects, &log).expect("failed to write pack");
+
+        let phases = log.phases();
+        let names: Vec<&str> = phases.iter().map(|xs| xs.name.as_str()).collect();
+        assert_eq!(names, vec!["Writing objects", "Indexing objects", "Resolving deltas"]);
+        assert!(phases.iter().all(|xs| xs.finished && Some(xs.count) == xs.total));
+        assert_eq!(phases[0].total, Some(2));
+        assert_eq!(phases[2].total, Some(0));
+        // the whole pack, trailer included, is indexed.
+        assert_eq!(phases[1].bytes, phases[0].bytes + 20);
+    }
+
+    fn load(vfs: &MemoryFs, path: &Path) {
+        for entry in std::fs::read_dir(path).expect("failed to read dir") {
+            let path = entry.expect("failed to read entry").path();
+            if path.is_dir() {
+                load(vfs, &path);
+            } else {
+                vfs.insert(path.clone(), &std::fs::read(&path).expect("failed to read file"));
+            }
+        }
+    }
+
+    #[test]
+    fn repositories_are_read_through_a_vfs() {
+        use std::sync::Arc;
+        use crate::objects::{ self, Type };
+        use crate::refs::RefStore;
+        use super::{ from_vfs, write_pack };
+
+        let dir = TempDir::new("fs-vfs").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"]);
+        let tip = builder.tip().expect("no tip");
+        builder.write(dir.path()).expect("failed to write");
+        let packed = objects::hash(Type::Blob, b"packed\n");
+        write_pack(dir.path(), &[(Type::Blob, b"packed\n".to_vec())]).expect("failed to write pack");
+
+        let vfs = Arc::new(MemoryFs::new());
+        load(&vfs, dir.path());
+        let path = dir.path().to_path_buf();
+        drop(dir);
+
+        let storage_set = from_vfs(vfs.clone(), &path).expect("failed to open");
+        assert!(matches!(storage_set.get_and_load(&tip).unwrap(), Some(Object::Commit(_))));
+        match storage_set.get_and_load(&packed).unwrap() {
+            Some(Object::Blob(blob)) => assert_eq!(blob.contents, b"packed\n"),
+            _ => panic!("expected the packed blob")
+        }
+        let refs = RefStore::new(&path).with_vfs(vfs);
+        assert_eq!(refs.read("HEAD").unwrap(), Some(tip.clone()));
+        assert_eq!(refs.list().unwrap(), vec![(String::from("HEAD"), tip.clone()), (String::from("refs/heads/master"), tip)]);
+    }
+
+    #[test]
+    fn objects_are_written_through_a_vfs() {
+        use std::sync::Arc;
+        use crate::objects::{ self, Type };
+        use crate::progress::NoProgress;
+        use super::{ from_vfs, write_pack_from_with, write_pack_with, LooseWriter };
+
+        let dir = TempDir::new("fs-vfs-write").expect("failed to create tempdir");
+        RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"])
+            .write(dir.path())
+            .expect("failed to write");
+        let vfs = Arc::new(MemoryFs::new());
+        load(&vfs, dir.path());
+        let path = dir.path().to_path_buf();
+        drop(dir);
+
+        // nothing below may touch the OS filesystem, where `path` is gone.
+        let writer = LooseWriter::with_vfs(vfs.clone(), &path).expect("failed to open");
+        let loose = writer.write(Type::Blob, b"loose\n").expect("failed to write");
+        let streamed = writer.write_blob(&b"streamed\n"[..], None).expect("failed to write");
+        assert_eq!(streamed, objects::hash(Type::Blob, b"streamed\n"));
+        let packed = objects::hash(Type::Blob, b"packed\n");
+        let checksum = write_pack_with(&*vfs, &path, &[(Type::Blob, b"packed\n".to_vec())], &NoProgress).expect("failed to write pack");
+        assert!(!path.exists());
+
+        let storage_set = from_vfs(vfs.clone(), &path).expect("failed to open");
+        for (id, contents) in &[(&loose, &b"loose\n"[..]), (&streamed, &b"streamed\n"[..]), (&packed, &b"packed\n"[..])] {
+            match storage_set.get_and_load(id).unwrap() {
+                Some(Object::Blob(blob)) => assert_eq!(blob.contents, *contents),
+                _ => panic!("expected blob {}", id)
+            }
+        }
+
+        let repacked = write_pack_from_with(&*vfs, &path, &storage_set, &[loose.clone(), streamed.clone()]).expect("failed to write pack");
+        assert_ne!(repacked, checksum);
+        let pack_dir = path.join(".git/objects/pack");
+        let mut names = vfs.read_dir(&pack_dir).unwrap();
+        names.sort();
+        let mut expected = vec![
+            format!("pack-{}.idx", checksum), format!("pack-{}.pack", checksum),
+            format!("pack-{}.idx", repacked), format!("pack-{}.pack", repacked)
+        ];
+        expected.sort();
+        assert_eq!(names, expected);
+        // the temp files are gone, and a packed object is not written loose again.
+        assert!(vfs.read_dir(&path.join(".git/objects")).unwrap().iter().all(|xs| !xs.starts_with("tmp_")));
+        let writer = LooseWriter::with_vfs(vfs.clone(), &path).expect("failed to open");
+        let before = vfs.read_dir(&path.join(".git/objects")).unwrap().len();
+        writer.write(Type::Blob, b"packed\n").expect("failed to write");
+        assert_eq!(vfs.read_dir(&path.join(".git/objects")).unwrap().len(), before);
+        assert!(!path.exists());
+    }
+}
@@ -1,16 +1,18 @@
-use flate2::bufread::ZlibDecoder;
 use std::io::prelude::*;
 use std::io::{ BufReader };
 
 use crate::stores::{ Queryable, StorageSet };
 use crate::errors::{ Result, ErrorKind };
+use crate::pack::inflate::Inflate;
 use crate::objects::Type;
 use crate::id::Id;
 
 type Reader = Fn(&Id) -> Result<Option<Box<std::io::Read>>> + Send + Sync;
+type Exists = dyn Fn(&Id) -> bool + Send + Sync;
 
 pub struct Store {
     read: Box<Reader>,
+    exists: Option<Box<Exists>>,
     filter: [bool; 256]
 }
 
@@ -24,13 +26,22 @@ impl Store {
 
         Store {
             read: Box::new(func),
+            exists: None,
             filter
         }
     }
+
+    // How `contains` finds out whether an object is there without opening
+    // it; otherwise it opens the object, but doesn't inflate it.
+    pub fn with_exists<C>(mut self, func: C) -> Self
+        where C: Fn(&Id) -> bool + 'static + Send + Sync {
+        self.exists = Some(Box::new(func));
+        self
+    }
 }
 
 impl Queryable for Store {
-    fn get<W: Write, S: Queryable>(&self, id: &Id, output: &mut W, _: &StorageSet<S>) -> Result<Option<Type>> {
+    fn get<W: Write, S: Queryable>(&self, id: &Id, output: &mut W, backends: &StorageSet<S>) -> Result<Option<Type>> {
         if !self.filter[id.as_ref()[0] as usize] {
             return Ok(None)
         }
@@ -41,7 +52,7 @@ impl Queryable for Store {
         }
 
         let mut reader = BufReader::new(
-            ZlibDecoder::new(BufReader::new(maybe_reader.unwrap()))
+            Inflate::new(BufReader::new(maybe_reader.unwrap()))
         );
 
         let mut type_vec = Vec::new();
@@ -54,6 +65,9 @@ impl Queryable for Store {
 
         reader.read_until(0x20, &mut type_vec);
         reader.read_until(0, &mut size_vec);
+        if let Some(reason) = reader.get_ref().corruption() {
+            return Err(ErrorKind::CorruptedLooseObject(id.clone(), reason).into())
+        }
 
         let loaded_type = match &type_vec[..] {
             b"commit " => Type::Commit,
@@ -63,9 +77,25 @@ impl Queryable for Store {
             &_ => return Err(ErrorKind::BadLooseObject.into())
         };
 
-        std::io::copy(&mut reader, output)?;
+        if let Err(e) = std::io::copy(&mut reader, output) {
+            return Err(match reader.get_ref().corruption() {
+                Some(reason) => ErrorKind::CorruptedLooseObject(id.clone(), reason).into(),
+                None => e.into()
+            })
+        }
+        backends.metrics().bytes_inflated(reader.get_ref().total_out());
         Ok(Some(loaded_type))
     }
+
+    fn contains(&self, id: &Id) -> bool {
+        if !self.filter[id.as_ref()[0] as usize] {
+            return false
+        }
+        match self.exists {
+            Some(ref exists) => exists(id),
+            None => (self.read)(id).is_ok_and(|xs| xs.is_some())
+        }
+    }
 }
 
 #[cfg(test)]
@@ -135,6 +165,24 @@ mod tests {
         };
     }
 
+    #[test]
+    fn rejects_truncated_and_mismatched_streams() {
+        let fixture = &include_bytes!("../../fixtures/loose_commit")[..];
+        let storage_set = StorageSet::new(());
+        let mut flipped = fixture.to_vec();
+        *flipped.last_mut().unwrap() ^= 1;
+        for data in vec![fixture[..fixture.len() - 4].to_vec(), flipped] {
+            let store = Store::new(move |_| Ok(Some(Box::new(Cursor::new(data.clone())) as Box<dyn std::io::Read>)), None);
+            match store.get(&Id::default(), &mut vec![], &storage_set) {
+                Err(e) => match e.kind() {
+                    ErrorKind::CorruptedLooseObject(..) => (),
+                    xs => panic!("unexpected error {:?}", xs)
+                },
+                Ok(_) => panic!("expected failure!")
+            }
+        }
+    }
+
     #[test]
     fn handles_idtoreadable_misses() {
         let store = Store::new(|_| Ok(None), None);
@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::stores::{ Queryable, StorageSet };
+use crate::errors::Result;
+use crate::objects::{ self, Type };
+use crate::id::Id;
+
+#[derive(Default)]
+pub struct Store(HashMap<Id, (Type, Vec<u8>)>);
+
+impl Store {
+    pub fn new() -> Self {
+        Store(HashMap::new())
+    }
+
+    pub fn insert(&mut self, id: Id, typ: Type, data: Vec<u8>) {
+        self.0.insert(id, (typ, data));
+    }
+
+    pub fn put(&mut self, typ: Type, data: Vec<u8>) -> Id {
+        let id = objects::hash(typ, &data);
+        self.0.insert(id.clone(), (typ, data));
+        id
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Id, &(Type, Vec<u8>))> {
+        self.0.iter()
+    }
+}
+
+impl Queryable for Store {
+    fn get<W: Write, S: Queryable>(&self, id: &Id, output: &mut W, _: &StorageSet<S>) -> Result<Option<Type>> {
+        match self.0.get(id) {
+            Some((typ, data)) => {
+                output.write_all(data)?;
+                Ok(Some(*typ))
+            },
+            None => Ok(None)
+        }
+    }
+
+    fn contains(&self, id: &Id) -> bool {
+        self.0.contains_key(id)
+    }
+}
@@ -1,31 +1,72 @@
-use std::collections::HashSet;
+use std::collections::{ HashMap, HashSet, VecDeque };
+use std::sync::{ Arc, Mutex };
 use std::io::Cursor;
 use std::io::Write;
 
+use lru::LruCache;
+
 use crate::walk::commits::CommitIterator;
 use crate::walk::tree::TreeIterator;
 use crate::objects::{Type, Object};
+use crate::metrics::{ self, Metrics };
 use crate::errors::Result;
 use crate::id::Id;
 
 pub mod loose;
 pub mod pack;
 pub mod fs;
+#[cfg(any(test, feature = "testkit"))]
+pub mod memory;
 
 pub trait Queryable {
     fn get<W: Write, S: Queryable>(&self, id: &Id, output: &mut W, backends: &StorageSet<S>) -> Result<Option<Type>>;
+
+    // Whether `id` is stored here. The built-in stores answer without
+    // reading or inflating it; this fallback reads it whole. An object
+    // that can't be read was still found.
+    fn contains(&self, id: &Id) -> bool {
+        !matches!(self.get(id, &mut std::io::sink(), &StorageSet::new(())), Ok(None))
+    }
+
+    // Where `id` is packed, as the number of the pack (counting the packs
+    // here in search order) and the offset in it; used to read many objects
+    // in pack order. None if it isn't in a pack.
+    fn locate(&self, _id: &Id) -> Option<(usize, u64)> {
+        None
+    }
+
+    // How many packs `locate` numbers.
+    fn packs(&self) -> usize {
+        0
+    }
 }
 
 impl Queryable for () {
     fn get<W: Write, S: Queryable>(&self, id: &Id, _output: &mut W, _backends: &StorageSet<S>) -> Result<Option<Type>> {
         Ok(None)
     }
+
+    fn contains(&self, _id: &Id) -> bool {
+        false
+    }
 }
 
 impl<Q: Queryable> Queryable for (Q,) {
     fn get<W: Write, S: Queryable>(&self, id: &Id, output: &mut W, backends: &StorageSet<S>) -> Result<Option<Type>> {
         self.0.get(id, output, backends)
     }
+
+    fn contains(&self, id: &Id) -> bool {
+        self.0.contains(id)
+    }
+
+    fn locate(&self, id: &Id) -> Option<(usize, u64)> {
+        self.0.locate(id)
+    }
+
+    fn packs(&self) -> usize {
+        self.0.packs()
+    }
 }
 
 impl<H: Queryable, T: Queryable> Queryable for (H, T) {
@@ -37,6 +78,20 @@ impl<H: Queryable, T: Queryable> Queryable for (H, T) {
 
         self.1.get(id, output, backends)
     }
+
+    fn contains(&self, id: &Id) -> bool {
+        self.0.contains(id) || self.1.contains(id)
+    }
+
+    fn locate(&self, id: &Id) -> Option<(usize, u64)> {
+        self.0.locate(id).or_else(|| {
+            self.1.locate(id).map(|(pack, offset)| (self.0.packs() + pack, offset))
+        })
+    }
+
+    fn packs(&self) -> usize {
+        self.0.packs() + self.1.packs()
+    }
 }
 
 impl<Q: Queryable> Queryable for Vec<Q> {
@@ -50,21 +105,191 @@ impl<Q: Queryable> Queryable for Vec<Q> {
 
         Ok(None)
     }
+
+    fn contains(&self, id: &Id) -> bool {
+        self.iter().any(|xs| xs.contains(id))
+    }
+
+    fn locate(&self, id: &Id) -> Option<(usize, u64)> {
+        let mut skipped = 0;
+        for queryable in self {
+            if let Some((pack, offset)) = queryable.locate(id) {
+                return Some((skipped + pack, offset))
+            }
+            skipped += queryable.packs();
+        }
+        None
+    }
+
+    fn packs(&self) -> usize {
+        self.iter().map(Queryable::packs).sum()
+    }
 }
 
+// git gives up on replacement chains deeper than this.
+const MAX_REPLACE_DEPTH: usize = 5;
+
+// An object's type and uncompressed contents.
+pub type RawObject = (Type, Vec<u8>);
+
+type ObjectCache = Mutex<LruCache<Id, RawObject>>;
+
 pub struct StorageSet<Q: Queryable> {
-    backend: Q
+    backend: Q,
+    replacements: HashMap<Id, Id>,
+    metrics: Arc<dyn Metrics>,
+    cache: Option<ObjectCache>
+}
+
+// Passes writes through while counting them.
+struct Counting<'a, W: Write> {
+    inner: &'a mut W,
+    count: u64
+}
+
+impl<'a, W: Write> Write for Counting<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 impl<Q: Queryable> StorageSet<Q> {
     pub fn new(backend: Q) -> StorageSet<Q> {
         StorageSet {
-            backend
+            backend,
+            replacements: HashMap::new(),
+            metrics: metrics::noop(),
+            cache: None
+        }
+    }
+
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> StorageSet<Q> {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn metrics(&self) -> &dyn Metrics {
+        self.metrics.as_ref()
+    }
+
+    // Keeps the last `capacity` objects returned by `get` in memory.
+    pub fn with_cache(mut self, capacity: usize) -> StorageSet<Q> {
+        self.cache = Some(Mutex::new(LruCache::new(capacity)));
+        self
+    }
+
+    pub fn with_replacements(mut self, replacements: HashMap<Id, Id>) -> StorageSet<Q> {
+        self.replacements = replacements;
+        self
+    }
+
+    pub fn without_replacements(mut self) -> StorageSet<Q> {
+        self.replacements.clear();
+        self
+    }
+
+    pub fn replacements(&self) -> &HashMap<Id, Id> {
+        &self.replacements
+    }
+
+    // The object `id` is replaced with, if any.
+    fn resolve<'a>(&'a self, id: &'a Id) -> &'a Id {
+        let mut target = id;
+        for _ in 0..MAX_REPLACE_DEPTH {
+            match self.replacements.get(target) {
+                Some(xs) => target = xs,
+                None => break
+            }
         }
+        target
     }
 
     pub fn get<W: Write>(&self, id: &Id, output: &mut W) -> Result<Option<Type>> {
-        self.backend.get(id, output, &self)
+        let target = self.resolve(id);
+
+        let cache = match self.cache {
+            Some(ref xs) => xs,
+            None => {
+                let mut counting = Counting { inner: output, count: 0 };
+                let result = self.backend.get(target, &mut counting, self)?;
+                if let Some(typ) = result {
+                    self.metrics.object_read(typ, counting.count);
+                }
+                return Ok(result)
+            }
+        };
+
+        if let Some((typ, data)) = cache.lock().unwrap().get(target) {
+            self.metrics.cache_hit();
+            self.metrics.object_read(*typ, data.len() as u64);
+            output.write_all(data)?;
+            return Ok(Some(*typ))
+        }
+        self.metrics.cache_miss();
+
+        let mut data = Vec::new();
+        let typ = match self.backend.get(target, &mut data, self)? {
+            Some(xs) => xs,
+            None => return Ok(None)
+        };
+        self.metrics.object_read(typ, data.len() as u64);
+        output.write_all(&data)?;
+        cache.lock().unwrap().put(target.clone(), (typ, data));
+        Ok(Some(typ))
+    }
+
+    // Whether `id` is stored, ignoring refs/replace as `git cat-file -e`
+    // does with replacements off. Packs answer from their index and loose
+    // objects from their directory entry; nothing is read or inflated.
+    pub fn contains(&self, id: &Id) -> bool {
+        self.backend.contains(id)
+    }
+
+    // The ids in `ids` that aren't stored, in order, as fetch negotiation
+    // and connectivity checks want them.
+    pub fn filter_missing(&self, ids: &[Id]) -> Vec<Id> {
+        ids.iter().filter(|xs| !self.backend.contains(xs)).cloned().collect()
+    }
+
+    // Reads every object in `ids`, returning them in the same order. The
+    // reads happen in pack order, each pack front to back, so that nearby
+    // objects and shared delta bases are read once rather than once per
+    // object; objects outside packs come last.
+    pub fn get_many(&self, ids: &[Id]) -> Result<Vec<Option<RawObject>>> {
+        let mut order: Vec<usize> = (0..ids.len()).collect();
+        let locations: Vec<_> = ids.iter().map(|xs| self.backend.locate(self.resolve(xs))).collect();
+        order.sort_by_key(|xs| (locations[*xs].is_none(), locations[*xs]));
+
+        let mut result: Vec<_> = ids.iter().map(|_| None).collect();
+        for idx in order {
+            let mut data = Vec::new();
+            if let Some(typ) = self.get(&ids[idx], &mut data)? {
+                result[idx] = Some((typ, data));
+            }
+        }
+        Ok(result)
+    }
+
+    // `git cat-file --batch`: the objects `ids` names, read `get_many` at a
+    // time, in order.
+    pub fn batch<I: IntoIterator<Item = Id>>(&self, ids: I) -> Batch<'_, Q, I::IntoIter> {
+        Batch {
+            storage_set: self,
+            ids: ids.into_iter(),
+            pending: VecDeque::new()
+        }
+    }
+
+    // Reads the object stored under `id`, ignoring refs/replace. Delta bases
+    // must always be resolved this way.
+    pub fn get_unreplaced<W: Write>(&self, id: &Id, output: &mut W) -> Result<Option<Type>> {
+        self.backend.get(id, output, self)
     }
 
     pub fn commits(&self, id: &Id, seen: Option<HashSet<Id>>) -> CommitIterator<Q> {
@@ -105,3 +330,114 @@ impl<Q: Queryable> StorageSet<Q> {
         }
     }
 }
+
+// How many ids a batch reads ahead to sort.
+const BATCH_SIZE: usize = 4096;
+
+pub struct Batch<'a, Q: Queryable, I: Iterator<Item = Id>> {
+    storage_set: &'a StorageSet<Q>,
+    ids: I,
+    pending: VecDeque<(Id, Option<RawObject>)>
+}
+
+impl<'a, Q: Queryable, I: Iterator<Item = Id>> Iterator for Batch<'a, Q, I> {
+    type Item = Result<(Id, Option<RawObject>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pending.is_empty() {
+            let ids: Vec<Id> = self.ids.by_ref().take(BATCH_SIZE).collect();
+            let objects = match self.storage_set.get_many(&ids) {
+                Ok(xs) => xs,
+                Err(e) => return Some(Err(e))
+            };
+            self.pending.extend(ids.into_iter().zip(objects));
+        }
+        self.pending.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::stores::memory::Store as MemoryStore;
+    use crate::objects::Type;
+    use crate::id::Id;
+    use super::StorageSet;
+
+    fn fixture() -> (StorageSet<MemoryStore>, Id, Id) {
+        let mut objects = MemoryStore::new();
+        let original = objects.put(Type::Blob, b"original\n".to_vec());
+        let replacement = objects.put(Type::Blob, b"replacement\n".to_vec());
+
+        let mut replacements = HashMap::new();
+        replacements.insert(original.clone(), replacement.clone());
+        (StorageSet::new(objects).with_replacements(replacements), original, replacement)
+    }
+
+    #[test]
+    fn replacements_are_honored() {
+        let (storage_set, original, _) = fixture();
+        let mut output = Vec::new();
+        storage_set.get(&original, &mut output).expect("failed to read");
+        assert_eq!(output, b"replacement\n");
+    }
+
+    #[test]
+    fn replacements_can_be_bypassed() {
+        let (storage_set, original, _) = fixture();
+        let mut output = Vec::new();
+        storage_set.get_unreplaced(&original, &mut output).expect("failed to read");
+        assert_eq!(output, b"original\n");
+
+        let storage_set = storage_set.without_replacements();
+        let mut output = Vec::new();
+        storage_set.get(&original, &mut output).expect("failed to read");
+        assert_eq!(output, b"original\n");
+    }
+
+    #[test]
+    fn contains_falls_back_to_get() {
+        use std::io::Write;
+        use crate::errors::Result;
+        use super::Queryable;
+
+        struct Fixed(Id);
+        impl Queryable for Fixed {
+            fn get<W: Write, S: Queryable>(&self, id: &Id, output: &mut W, _backends: &StorageSet<S>) -> Result<Option<Type>> {
+                if *id != self.0 {
+                    return Ok(None)
+                }
+                output.write_all(b"fixed\n")?;
+                Ok(Some(Type::Blob))
+            }
+        }
+
+        let (_, original, replacement) = fixture();
+        let storage_set = StorageSet::new(Fixed(original.clone()));
+        assert!(storage_set.contains(&original));
+        assert!(!storage_set.contains(&replacement));
+    }
+
+    #[test]
+    fn metrics_and_cache_work() {
+        use std::sync::Arc;
+        use crate::metrics::Counters;
+
+        let (storage_set, original, replacement) = fixture();
+        let counters = Arc::new(Counters::new());
+        let storage_set = storage_set.with_metrics(counters.clone()).with_cache(1);
+
+        for id in &[&original, &original, &replacement] {
+            let mut output = Vec::new();
+            storage_set.get(id, &mut output).expect("failed to read");
+            assert_eq!(output, b"replacement\n");
+        }
+
+        let snapshot = counters.snapshot();
+        assert_eq!(snapshot.blobs, 3);
+        assert_eq!(snapshot.object_bytes, 36);
+        assert_eq!(snapshot.cache_hits, 2);
+        assert_eq!(snapshot.cache_misses, 1);
+    }
+}
@@ -1,24 +1,43 @@
 use std::io::Write;
+use std::path::PathBuf;
 
 use crate::stores::{ Queryable, StorageSet };
+use crate::pack::cache::DeltaCache;
 use crate::pack::index::Index;
-use crate::errors::Result;
+use crate::errors::{ Context, Result };
 use crate::pack::Packfile;
 use crate::objects::Type;
 use crate::id::Id;
 
 pub struct Store<P: Packfile> {
     packfile: P,
-    index: Index
+    index: Index,
+    bases: DeltaCache,
+    path: Option<PathBuf>
 }
 
 impl<P: Packfile> Store<P> {
     pub fn new (packfile: P, index: Index) -> Self {
         Store {
             packfile,
-            index
+            index,
+            bases: DeltaCache::default(),
+            path: None
         }
     }
+
+    // Keeps inflated delta bases in `bases`, which a StorageSet's packs
+    // share, instead of a cache of the pack's own.
+    pub fn with_cache(mut self, bases: DeltaCache) -> Self {
+        self.bases = bases;
+        self
+    }
+
+    // The pack's path, named in errors reading from it.
+    pub fn with_path<T: Into<PathBuf>>(mut self, path: T) -> Self {
+        self.path = Some(path.into());
+        self
+    }
 }
 
 impl<P: Packfile> Queryable for Store<P> {
@@ -28,8 +47,23 @@ impl<P: Packfile> Queryable for Store<P> {
             None => return Ok(None)
         };
 
-        let obj_type = self.packfile.read_bounds(start, end, output, backends)?;
+        let obj_type = self.packfile.read_bounds(start, end, output, backends, &self.bases).map_err(|xs| {
+            let context = Context { pack: self.path.clone(), ..Context::new("reading object") };
+            xs.within(context.offset(start).id(id.clone()))
+        })?;
 
         Ok(Some(obj_type))
     }
+
+    fn contains(&self, id: &Id) -> bool {
+        self.index.contains(id)
+    }
+
+    fn locate(&self, id: &Id) -> Option<(usize, u64)> {
+        self.index.get_bounds(id).map(|(start, _)| (0, start))
+    }
+
+    fn packs(&self) -> usize {
+        1
+    }
 }
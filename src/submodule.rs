@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::stores::{ Queryable, StorageSet };
+use crate::checkout::flatten;
+use crate::errors::Result;
+use crate::objects::Object;
+use crate::config::Config;
+use crate::refs::RefStore;
+use crate::id::Id;
+
+// A `[submodule "<name>"]` section of `.gitmodules`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Submodule {
+    pub name: String,
+    pub path: String,
+    pub url: Option<String>,
+    pub branch: Option<String>
+}
+
+#[derive(Debug)]
+pub struct Status {
+    pub submodule: Submodule,
+    // the commit the superproject's tree pins the submodule at
+    pub recorded: Option<Id>,
+    // `submodule.<name>.url` is set in the superproject's config
+    pub initialized: bool,
+    // HEAD of the repository checked out at the submodule's path
+    pub checked_out: Option<Id>
+}
+
+impl Status {
+    pub fn is_mismatched(&self) -> bool {
+        match (&self.recorded, &self.checked_out) {
+            (Some(recorded), Some(checked_out)) => recorded != checked_out,
+            _ => false
+        }
+    }
+}
+
+impl Submodule {
+    // Sections without a path are ignored, as git does.
+    pub fn from_config(config: &Config) -> Vec<Submodule> {
+        config.subsections("submodule").into_iter().filter_map(|name| {
+            let key = |xs: &str| format!("submodule.{}.{}", name, xs);
+            let path = config.get(&key("path"))?;
+            Some(Submodule {
+                name: String::from(name),
+                path: String::from(path),
+                url: config.get(&key("url")).map(String::from),
+                branch: config.get(&key("branch")).map(String::from)
+            })
+        }).collect()
+    }
+
+    pub fn parse(buffer: &[u8]) -> Result<Vec<Submodule>> {
+        Ok(Submodule::from_config(&Config::parse(std::str::from_utf8(buffer)?)?))
+    }
+
+    // Reads `.gitmodules` from the worktree at `path`.
+    pub fn from_path(path: &Path) -> Result<Vec<Submodule>> {
+        Ok(Submodule::from_config(&Config::from_file(&path.join(".gitmodules"))?))
+    }
+
+    // Reads `.gitmodules` from the root of a commit or tree.
+    pub fn from_tree<S: Queryable>(storage_set: &StorageSet<S>, id: &Id) -> Result<Vec<Submodule>> {
+        let entries = flatten(storage_set, id)?;
+        let entry = match entries.get(&b".gitmodules"[..]) {
+            Some(xs) => xs,
+            None => return Ok(Vec::new())
+        };
+
+        match storage_set.get_and_load(&entry.id)? {
+            Some(Object::Blob(blob)) => Submodule::parse(&blob.contents),
+            _ => Ok(Vec::new())
+        }
+    }
+}
+
+// Every gitlink (mode 160000) under a commit or tree, keyed by path.
+pub fn gitlinks<S: Queryable>(storage_set: &StorageSet<S>, id: &Id) -> Result<BTreeMap<Vec<u8>, Id>> {
+    Ok(flatten(storage_set, id)?.into_iter()
+        .filter(|(_, entry)| entry.mode.is_gitlink())
+        .map(|(entry_path, entry)| (entry_path, entry.id))
+        .collect())
+}
+
+// Reports the submodules named by the worktree's `.gitmodules` against the
+// gitlinks recorded in `commit`.
+pub fn status<S: Queryable>(path: &Path, storage_set: &StorageSet<S>, commit: &Id) -> Result<Vec<Status>> {
+    let config = Config::from_path(path)?;
+    let recorded = gitlinks(storage_set, commit)?;
+
+    let mut statuses = Vec::new();
+    for submodule in Submodule::from_path(path)? {
+        let initialized = config.get(&format!("submodule.{}.url", submodule.name)).is_some();
+
+        let checkout_path = path.join(&submodule.path);
+        let checked_out = if checkout_path.join(".git").exists() {
+            RefStore::new(&checkout_path).read("HEAD")?
+        } else {
+            None
+        };
+
+        statuses.push(Status {
+            recorded: recorded.get(submodule.path.as_bytes()).cloned(),
+            submodule,
+            initialized,
+            checked_out
+        });
+    }
+
+    Ok(statuses)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testkit::{ gitlink, file, RepoBuilder, TempDir };
+    use crate::stores::fs as gitfs;
+    use crate::files;
+    use super::{ gitlinks, status, Submodule };
+
+    const GITMODULES: &str = "\
+[submodule \"lib\"]
+\tpath = vendor/lib
+\turl = https://example.com/lib.git
+\tbranch = stable
+[submodule \"docs\"]
+\tpath = docs
+\turl = ../docs.git
+[submodule \"broken\"]
+\turl = https://example.com/broken.git
+";
+
+    #[test]
+    fn parse_works() {
+        let submodules = Submodule::parse(GITMODULES.as_bytes()).expect("failed to parse");
+        assert_eq!(submodules, vec![
+            Submodule {
+                name: String::from("lib"),
+                path: String::from("vendor/lib"),
+                url: Some(String::from("https://example.com/lib.git")),
+                branch: Some(String::from("stable"))
+            },
+            Submodule {
+                name: String::from("docs"),
+                path: String::from("docs"),
+                url: Some(String::from("../docs.git")),
+                branch: None
+            }
+        ]);
+    }
+
+    #[test]
+    fn status_works() {
+        let dir = TempDir::new("submodule").expect("failed to create tempdir");
+        let root = dir.path().join("super");
+
+        let lib = RepoBuilder::new().commit("lib", files!["lib.rs" => "// lib\n"]);
+        let lib_tip = lib.tip().unwrap();
+        let docs = RepoBuilder::new().commit("docs", files!["index.md" => "# docs\n"]);
+        let docs_tip = docs.tip().unwrap();
+
+        let superproject = RepoBuilder::new().commit("add submodules", vec![
+            file(".gitmodules", GITMODULES),
+            gitlink("vendor/lib", &lib_tip),
+            gitlink("docs", &docs_tip)
+        ]);
+        let tip = superproject.tip().unwrap();
+        superproject.write(root.as_path()).expect("failed to write");
+        std::fs::write(root.join(".gitmodules"), GITMODULES).expect("failed to write .gitmodules");
+        std::fs::write(root.join(".git/config"), "[submodule \"lib\"]\n\turl = https://example.com/lib.git\n")
+            .expect("failed to write config");
+
+        // "lib" is checked out one commit ahead of what the superproject pins.
+        let lib = lib.commit("ahead", files!["lib.rs" => "// lib v2\n"]);
+        let lib_ahead = lib.tip().unwrap();
+        lib.write(root.join("vendor/lib").as_path()).expect("failed to write lib");
+
+        let storage_set = gitfs::from(root.as_path()).expect("failed to open storage");
+        let links = gitlinks(&storage_set, &tip).expect("failed to list gitlinks");
+        assert_eq!(links.len(), 2);
+        assert_eq!(links.get(&b"docs"[..]), Some(&docs_tip));
+
+        let statuses = status(root.as_path(), &storage_set, &tip).expect("failed to get status");
+        assert_eq!(statuses.len(), 2);
+
+        assert_eq!(statuses[0].submodule.name, "lib");
+        assert!(statuses[0].initialized);
+        assert_eq!(statuses[0].recorded, Some(lib_tip));
+        assert_eq!(statuses[0].checked_out, Some(lib_ahead));
+        assert!(statuses[0].is_mismatched());
+
+        assert_eq!(statuses[1].submodule.name, "docs");
+        assert!(!statuses[1].initialized);
+        assert_eq!(statuses[1].checked_out, None);
+        assert!(!statuses[1].is_mismatched());
+
+        // an absorbed checkout's .git is a file pointing into .git/modules.
+        docs.write(root.join("docs").as_path()).expect("failed to write docs");
+        std::fs::create_dir_all(root.join(".git/modules")).unwrap();
+        std::fs::rename(root.join("docs/.git"), root.join(".git/modules/docs")).unwrap();
+        std::fs::write(root.join("docs/.git"), "gitdir: ../.git/modules/docs\n").unwrap();
+        let statuses = status(root.as_path(), &storage_set, &tip).expect("failed to get status");
+        assert_eq!(statuses[1].checked_out, Some(docs_tip));
+        assert!(!statuses[1].is_mismatched());
+    }
+}
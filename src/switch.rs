@@ -0,0 +1,344 @@
+use std::collections::{ BTreeMap, BTreeSet };
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+
+use crate::stores::{ Queryable, StorageSet };
+use crate::errors::{ ErrorKind, Result };
+use crate::checkout::{ flatten, Checkout };
+use crate::objects::tree::TreeEntry;
+use crate::refs::RefStore;
+use crate::index::{ self, Index };
+use crate::objects::{ self, Type };
+use crate::lock::{ LockFile, Retry };
+use crate::filter::Filters;
+use crate::sparse::{ self, Sparse };
+use crate::stash::read_worktree_as;
+use crate::checkout::modes::Modes;
+use crate::identity::Identity;
+use crate::worktree::{ self, Layout };
+use crate::reflog;
+use crate::id::Id;
+
+type Entries = BTreeMap<Vec<u8>, TreeEntry>;
+
+// Moves HEAD of the worktree at `path`, to a branch or detached at a commit,
+// logging the move in HEAD's reflog as `git checkout` does. The worktree and
+// index follow unless `update_worktree(false)`; paths with changes that
+// would be lost make the switch fail with `LocalChanges` unless forced.
+pub struct Switch<'a, S: Queryable> {
+    storage_set: &'a StorageSet<S>,
+    path: PathBuf,
+    identity: Identity,
+    update_worktree: bool,
+    force: bool,
+    filters: Option<Arc<Filters>>
+}
+
+impl<'a, S: Queryable> Switch<'a, S> {
+    pub fn new(storage_set: &'a StorageSet<S>, path: &Path, identity: &Identity) -> Switch<'a, S> {
+        Switch {
+            storage_set,
+            path: path.to_path_buf(),
+            identity: identity.clone(),
+            update_worktree: true,
+            force: false,
+            filters: None
+        }
+    }
+
+    // Only move HEAD, as `git symbolic-ref` and `update-ref --no-deref` do.
+    pub fn update_worktree(mut self, update_worktree: bool) -> Switch<'a, S> {
+        self.update_worktree = update_worktree;
+        self
+    }
+
+    // Overwrite local changes instead of failing (--force).
+    pub fn force(mut self, force: bool) -> Switch<'a, S> {
+        self.force = force;
+        self
+    }
+
+    // Smudges files as they are checked out, and cleans worktree files
+    // before comparing them.
+    pub fn filters(mut self, filters: Arc<Filters>) -> Switch<'a, S> {
+        self.filters = Some(filters);
+        self
+    }
+
+    // Checks out `refs/heads/<name>` and points HEAD at it.
+    pub fn checkout_branch(&self, name: &str) -> Result<()> {
+        let target = format!("refs/heads/{}", name);
+        let id = match RefStore::new(&self.path).read(&target)? {
+            Some(xs) => xs,
+            None => return Err(ErrorKind::NoSuchBranch(String::from(name)).into())
+        };
+        self.switch(&id, &format!("ref: {}\n", target), name)
+    }
+
+    // Checks out commit `id` with HEAD detached at it.
+    pub fn set_head_detached(&self, id: &Id) -> Result<()> {
+        self.switch(id, &format!("{}\n", id), &id.to_string())
+    }
+
+    fn switch(&self, to: &Id, head: &str, label: &str) -> Result<()> {
+        if let Some(branch) = head.trim_end().strip_prefix("ref: refs/heads/") {
+            worktree::check_branch_elsewhere(&self.path, branch)?;
+        }
+        // HEAD is locked before the worktree is touched, so a concurrent
+        // switch fails up front rather than after rewriting files.
+        let mut lock = match LockFile::acquire(&Layout::resolve(&self.path)?.git_dir.join("HEAD"), &Retry::refs()) {
+            Ok(xs) => xs,
+            Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Err(ErrorKind::RefLocked(String::from("HEAD")).into()),
+            Err(e) => return Err(e.into())
+        };
+        let (from_name, from) = RefStore::new(&self.path).resolve_ref("HEAD")?;
+        let from_label = match from_name.as_str() {
+            "HEAD" => from.as_ref().map(Id::to_string).unwrap_or_default(),
+            xs => String::from(xs.strip_prefix("refs/heads/").unwrap_or(xs))
+        };
+
+        if self.update_worktree {
+            let old = match from {
+                Some(ref xs) => flatten(self.storage_set, xs)?,
+                None => Entries::new()
+            };
+            let new = flatten(self.storage_set, to)?;
+            let changed: BTreeSet<&Vec<u8>> = old.keys().chain(new.keys()).filter(|xs| old.get(*xs) != new.get(*xs)).collect();
+            if !self.force {
+                let lost = self.local_changes(&old, &new, &changed)?;
+                if !lost.is_empty() {
+                    return Err(ErrorKind::LocalChanges(lost).into())
+                }
+            }
+
+            let mut checkout = Checkout::new(self.storage_set, &self.path);
+            if let Some(ref filters) = self.filters {
+                checkout = checkout.filters(filters.clone());
+            }
+            checkout.run(from.as_ref(), to)?;
+            // staged changes to paths the switch leaves alone carry over.
+            let mut index = Index::open(&self.path)?;
+            if index.entries().is_empty() {
+                index = Index::from_tree(self.storage_set, to)?;
+            } else {
+                for entry_path in changed {
+                    match new.get(entry_path) {
+                        Some(xs) => index.add(index::Entry::new(entry_path.clone(), xs.mode, xs.id.clone())),
+                        None => {
+                            index.remove(entry_path);
+                        }
+                    }
+                }
+            }
+            if let Some(ref xs) = Sparse::from_path(&self.path)? {
+                sparse::apply(&mut index, Some(xs));
+            }
+            index.save(&self.path)?;
+        }
+
+        lock.write_all(head.as_bytes())?;
+        lock.commit()?;
+
+        let message = format!("checkout: moving from {} to {}", from_label, label);
+        reflog::append(&self.path, "HEAD", &reflog::Entry::new(from.as_ref(), to, &self.identity, &message))?;
+        Ok(())
+    }
+
+    // The paths the switch would write or remove whose worktree file or
+    // index entry is neither what HEAD has nor what the switch would put
+    // there. A repository without an index has nothing staged.
+    fn local_changes(&self, old: &Entries, new: &Entries, changed: &BTreeSet<&Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        let index = Index::open(&self.path)?;
+        let modes = Modes::from_path(&self.path)?;
+        let sparse = Sparse::from_path(&self.path)?;
+        let state = |xs: Option<&TreeEntry>| xs.map(|xs| (xs.mode, xs.id.clone()));
+        let mut lost = Vec::new();
+        for entry_path in changed {
+            let (old, new) = (state(old.get(*entry_path)), state(new.get(*entry_path)));
+            // submodules are left to themselves.
+            if [&old, &new].iter().any(|xs| xs.as_ref().is_some_and(|(mode, _)| mode.is_gitlink())) {
+                continue
+            }
+
+            let staged = index.get(entry_path, 0).map(|xs| (xs.mode, xs.id.clone()));
+            let staged_lost = !index.entries().is_empty() && staged != old && staged != new;
+            let recorded = old.as_ref().map(|(mode, _)| *mode);
+            // paths outside the sparse checkout aren't in the worktree.
+            if sparse.as_ref().is_some_and(|xs| !xs.includes(entry_path)) {
+                if staged_lost {
+                    lost.push(entry_path.to_vec());
+                }
+                continue
+            }
+            let worktree = match self.filters {
+                Some(ref filters) => filters.hash_worktree(entry_path)?.map(|xs| (modes.stage(recorded, xs.mode), xs.id)),
+                None => read_worktree_as(&self.path, entry_path, recorded, &modes)?.map(|(mode, contents)| (mode, objects::hash(Type::Blob, &contents)))
+            };
+            if staged_lost || (worktree != old && worktree != new) {
+                lost.push(entry_path.to_vec());
+            }
+        }
+        Ok(lost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{ FixedOffset, TimeZone, Utc };
+
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::checkout::Checkout;
+    use crate::objects::tree::FileMode;
+    use crate::objects::{ self, Type };
+    use crate::identity::Identity;
+    use crate::stores::fs as gitfs;
+    use crate::errors::ErrorKind;
+    use crate::refs::{ RefPtr, RefSet, RefStore };
+    use crate::index::{ Entry, Index };
+    use crate::worktree;
+    use crate::reflog;
+    use crate::files;
+    use crate::id::Id;
+    use super::Switch;
+
+    fn identity() -> Identity {
+        Identity::new(
+            b"Test User",
+            b"test@example.com",
+            Utc.timestamp_opt(1_545_300_000, 0).unwrap(),
+            FixedOffset::east_opt(0).unwrap()
+        )
+    }
+
+    #[test]
+    fn switches_branches_and_detaches() {
+        let dir = TempDir::new("switch").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n", "notes" => "keep\n"]);
+        let first = builder.tip().unwrap();
+        let builder = builder
+            .branch("feature")
+            .checkout("feature")
+            .commit("second", files!["README" => "goodbye\n", "new" => "added\n"]);
+        let second = builder.tip().unwrap();
+        builder.checkout("master").write(dir.path()).expect("failed to write");
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        Checkout::new(&storage_set, dir.path()).run(None, &first).expect("failed to check out");
+        Index::from_tree(&storage_set, &first).unwrap().save(dir.path()).unwrap();
+        let switch = Switch::new(&storage_set, dir.path(), &identity());
+
+        // a change to a path the switch leaves alone carries over.
+        std::fs::write(dir.path().join("notes"), "edited\n").unwrap();
+        switch.checkout_branch("feature").expect("failed to switch");
+        assert_eq!(std::fs::read_to_string(dir.path().join("README")).unwrap(), "goodbye\n");
+        assert_eq!(std::fs::read_to_string(dir.path().join("notes")).unwrap(), "edited\n");
+        assert!(dir.path().join("new").exists());
+        assert_eq!(std::fs::read_to_string(dir.path().join(".git/HEAD")).unwrap(), "ref: refs/heads/feature\n");
+        assert!(Index::open(dir.path()).unwrap().get(b"new", 0).is_some());
+
+        // one that would be overwritten stops it, unless forced.
+        std::fs::write(dir.path().join("README"), "mine\n").unwrap();
+        match switch.set_head_detached(&first) {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::LocalChanges(xs) if xs == &[b"README".to_vec()])),
+            Ok(_) => panic!("lost a local change")
+        }
+        switch.force(true).set_head_detached(&first).expect("failed to detach");
+        assert_eq!(std::fs::read_to_string(dir.path().join("README")).unwrap(), "hello\n");
+        assert!(!dir.path().join("new").exists());
+        assert_eq!(RefSet::from_path(dir.path()).unwrap().deref("HEAD"), Some(&first));
+
+        let log = reflog::read(dir.path(), "HEAD").unwrap();
+        let messages: Vec<_> = log.iter().map(|xs| xs.message.as_str()).collect();
+        assert_eq!(messages, vec!["checkout: moving from master to feature", &format!("checkout: moving from feature to {}", first)]);
+        assert_eq!(log[0].new, second);
+
+        // moving HEAD alone leaves files be.
+        Switch::new(&storage_set, dir.path(), &identity())
+            .update_worktree(false)
+            .checkout_branch("feature")
+            .expect("failed to switch");
+        assert_eq!(std::fs::read_to_string(dir.path().join("README")).unwrap(), "hello\n");
+        assert_eq!(RefSet::from_path(dir.path()).unwrap().deref("HEAD"), Some(&second));
+        assert!(Switch::new(&storage_set, dir.path(), &identity()).checkout_branch("nope").is_err());
+    }
+
+    #[test]
+    fn switches_lock_head_first_and_respect_other_worktrees() {
+        let dir = TempDir::new("switch-locked").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"])
+            .branch("feature")
+            .branch("linked")
+            .checkout("feature")
+            .commit("second", files!["README" => "goodbye\n"]);
+        builder.checkout("master").write(dir.path()).expect("failed to write");
+        // only what `git init` and branching leave: no refs/remotes.
+        let _ = std::fs::remove_dir_all(dir.path().join(".git/refs/remotes"));
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let head = RefStore::new(dir.path()).read("HEAD").unwrap().unwrap();
+        Checkout::new(&storage_set, dir.path()).run(None, &head).expect("failed to check out");
+        Index::from_tree(&storage_set, &head).unwrap().save(dir.path()).unwrap();
+        let switch = Switch::new(&storage_set, dir.path(), &identity());
+
+        std::fs::write(dir.path().join(".git/HEAD.lock"), "").unwrap();
+        match switch.checkout_branch("feature") {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::RefLocked(_))),
+            Ok(_) => panic!("switched under a lock")
+        }
+        assert_eq!(std::fs::read_to_string(dir.path().join("README")).unwrap(), "hello\n");
+        std::fs::remove_file(dir.path().join(".git/HEAD.lock")).unwrap();
+
+        worktree::add(dir.path(), "linked", &dir.path().join("linked"), &RefPtr::Indirect(String::from("linked"))).expect("failed to add worktree");
+        assert!(switch.checkout_branch("linked").is_err());
+        assert_eq!(std::fs::read_to_string(dir.path().join(".git/HEAD")).unwrap(), "ref: refs/heads/master\n");
+        // switching to where a worktree already is, is fine.
+        switch.checkout_branch("master").expect("failed to switch");
+        switch.checkout_branch("feature").expect("failed to switch");
+        assert_eq!(std::fs::read_to_string(dir.path().join("README")).unwrap(), "goodbye\n");
+    }
+
+    #[test]
+    fn switches_from_unborn_heads_and_keep_staged_changes() {
+        let dir = TempDir::new("switch-staged").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"]);
+        let first = builder.tip().unwrap();
+        let builder = builder.branch("feature").checkout("feature")
+            .commit("second", files!["README" => "goodbye\n"]);
+        builder.checkout("master").write(dir.path()).expect("failed to write");
+        std::fs::write(dir.path().join(".git/HEAD"), "ref: refs/heads/unborn\n").unwrap();
+
+        // nothing checked out and no index: both come from the branch.
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        Switch::new(&storage_set, dir.path(), &identity()).checkout_branch("master").expect("failed to switch");
+        assert_eq!(std::fs::read_to_string(dir.path().join("README")).unwrap(), "hello\n");
+        assert_eq!(Index::open(dir.path()).unwrap().get(b"README", 0).map(|xs| xs.id.clone()), Some(objects::hash(Type::Blob, b"hello\n")));
+        let log = reflog::read(dir.path(), "HEAD").unwrap();
+        assert_eq!((&log[0].old, &log[0].new, log[0].message.as_str()), (&Id::default(), &first, "checkout: moving from unborn to master"));
+
+        let staged = gitfs::write_loose(dir.path(), Type::Blob, b"staged\n").unwrap();
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let switch = Switch::new(&storage_set, dir.path(), &identity());
+        let mut index = Index::open(dir.path()).unwrap();
+        index.add(Entry::new(b"extra".to_vec(), FileMode::FILE, staged.clone()));
+        std::fs::write(dir.path().join("extra"), "staged\n").unwrap();
+        index.save(dir.path()).unwrap();
+        switch.checkout_branch("feature").expect("failed to switch");
+        assert_eq!(Index::open(dir.path()).unwrap().get(b"extra", 0).map(|xs| xs.id.clone()), Some(staged.clone()));
+
+        // a staged change the switch would overwrite stops it, even with the
+        // worktree file as HEAD has it.
+        let mut index = Index::open(dir.path()).unwrap();
+        index.add(Entry::new(b"README".to_vec(), FileMode::FILE, staged));
+        index.save(dir.path()).unwrap();
+        match switch.checkout_branch("master") {
+            Err(e) => assert!(matches!(e.kind(), ErrorKind::LocalChanges(xs) if xs == &[b"README".to_vec()])),
+            Ok(_) => panic!("lost a staged change")
+        }
+        assert_eq!(std::fs::read_to_string(dir.path().join(".git/HEAD")).unwrap(), "ref: refs/heads/feature\n");
+        assert!(!dir.path().join(".git/HEAD.lock").exists());
+    }
+}
@@ -0,0 +1,396 @@
+use std::collections::{ BTreeMap, HashMap };
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+
+use chrono::{ FixedOffset, TimeZone, Utc };
+
+use crate::objects::tree::{ FileMode, Tree, TreeEntry };
+use crate::stores::memory::Store as MemoryStore;
+use crate::refs::{ Kind, Ref, RefPtr, RefSet };
+use crate::objects::commit::Commit;
+use crate::stores::StorageSet;
+use crate::identity::Identity;
+use crate::stores::fs as gitfs;
+use crate::objects::Type;
+use crate::id::Id;
+
+// Builds `(path, contents)` pairs for `RepoBuilder::commit`:
+//
+//     RepoBuilder::new().commit("a", files!["README" => "hello"])
+#[macro_export]
+macro_rules! files {
+    ($($path:expr => $contents:expr),* $(,)*) => {
+        vec![$($crate::testkit::file($path, $contents)),*]
+    };
+}
+
+#[derive(Clone, Debug)]
+pub struct File {
+    path: Vec<u8>,
+    mode: FileMode,
+    contents: Vec<u8>
+}
+
+pub fn file<P: AsRef<[u8]>, C: AsRef<[u8]>>(path: P, contents: C) -> File {
+    File {
+        path: path.as_ref().to_vec(),
+        mode: FileMode::FILE,
+        contents: contents.as_ref().to_vec()
+    }
+}
+
+pub fn executable<P: AsRef<[u8]>, C: AsRef<[u8]>>(path: P, contents: C) -> File {
+    File {
+        mode: FileMode::EXECUTABLE,
+        ..file(path, contents)
+    }
+}
+
+pub fn symlink<P: AsRef<[u8]>, C: AsRef<[u8]>>(path: P, target: C) -> File {
+    File {
+        mode: FileMode::SYMLINK,
+        ..file(path, target)
+    }
+}
+
+// A submodule entry pinned at `commit`; no blob is written for it.
+pub fn gitlink<P: AsRef<[u8]>>(path: P, commit: &Id) -> File {
+    File {
+        mode: FileMode::GITLINK,
+        ..file(path, commit.to_string())
+    }
+}
+
+type Snapshot = BTreeMap<Vec<u8>, (FileMode, Vec<u8>)>;
+
+enum Node {
+    File(FileMode, Id),
+    Dir(BTreeMap<Vec<u8>, Node>)
+}
+
+// Constructs repositories deterministically: identities are fixed and every
+// commit is stamped one minute after the previous one, so ids are stable
+// across runs and platforms.
+pub struct RepoBuilder {
+    objects: MemoryStore,
+    branches: BTreeMap<String, Id>,
+    tags: BTreeMap<String, Id>,
+    snapshots: HashMap<Id, Snapshot>,
+    head: String,
+    name: String,
+    email: String,
+    clock: i64
+}
+
+impl Default for RepoBuilder {
+    fn default() -> Self {
+        RepoBuilder {
+            objects: MemoryStore::new(),
+            branches: BTreeMap::new(),
+            tags: BTreeMap::new(),
+            snapshots: HashMap::new(),
+            head: String::from("master"),
+            name: String::from("Test User"),
+            email: String::from("test@example.com"),
+            clock: 1_545_286_964
+        }
+    }
+}
+
+impl RepoBuilder {
+    pub fn new() -> Self {
+        RepoBuilder::default()
+    }
+
+    pub fn author(mut self, name: &str, email: &str) -> Self {
+        self.name = String::from(name);
+        self.email = String::from(email);
+        self
+    }
+
+    // Files are layered over the snapshot of the current branch tip.
+    pub fn commit(self, message: &str, files: Vec<File>) -> Self {
+        self.commit_with(message, files, &[])
+    }
+
+    pub fn remove(mut self, message: &str, paths: &[&str]) -> Self {
+        let mut snapshot = self.tip_snapshot();
+        for path in paths {
+            snapshot.remove(path.as_bytes());
+        }
+        let parents: Vec<Id> = self.tip().into_iter().collect();
+        self.record(message, snapshot, parents);
+        self
+    }
+
+    pub fn merge(self, message: &str, branch: &str) -> Self {
+        let other = match self.branches.get(branch) {
+            Some(xs) => xs.clone(),
+            None => panic!("unknown branch {:?}", branch)
+        };
+        let files: Vec<File> = self.snapshots[&other].iter().map(|(path, (mode, contents))| {
+            File {
+                path: path.clone(),
+                mode: *mode,
+                contents: contents.clone()
+            }
+        }).collect();
+        self.commit_with(message, files, &[other])
+    }
+
+    // Creates a branch at the current tip without switching to it. There
+    // must be a commit to point it at.
+    pub fn branch(mut self, name: &str) -> Self {
+        let tip = match self.tip() {
+            Some(xs) => xs,
+            None => panic!("cannot create branch {:?} before the first commit", name)
+        };
+        self.branches.insert(String::from(name), tip);
+        self
+    }
+
+    pub fn checkout(mut self, name: &str) -> Self {
+        if !self.branches.contains_key(name) {
+            panic!("unknown branch {:?}", name);
+        }
+        self.head = String::from(name);
+        self
+    }
+
+    pub fn tag(mut self, name: &str) -> Self {
+        let tip = match self.tip() {
+            Some(xs) => xs,
+            None => panic!("cannot create tag {:?} before the first commit", name)
+        };
+        self.tags.insert(String::from(name), tip);
+        self
+    }
+
+    pub fn tip(&self) -> Option<Id> {
+        self.branches.get(&self.head).cloned()
+    }
+
+    pub fn in_memory(self) -> (StorageSet<MemoryStore>, RefSet) {
+        let mut refs = RefSet::default();
+        for (name, id) in &self.branches {
+            refs.insert(name, Ref::new(Kind::Local, RefPtr::Direct(id.clone())));
+        }
+        for (name, id) in &self.tags {
+            refs.insert(name, Ref::new(Kind::Tag, RefPtr::Direct(id.clone())));
+        }
+        refs.insert("HEAD", Ref::new(Kind::Local, RefPtr::Indirect(self.head.clone())));
+
+        (StorageSet::new(self.objects), refs)
+    }
+
+    // Writes a classic `.git` layout (loose objects, loose refs, HEAD) under `path`.
+    pub fn write(self, path: &Path) -> Result<(), std::io::Error> {
+        let mut git_dir = PathBuf::from(path);
+        git_dir.push(".git");
+        for dir in &["objects/pack", "objects/info", "refs/heads", "refs/tags", "refs/remotes"] {
+            std::fs::create_dir_all(git_dir.join(dir))?;
+        }
+
+        for (_, (typ, data)) in self.objects.iter() {
+            gitfs::write_loose(path, *typ, data)?;
+        }
+
+        for (prefix, refs) in &[("refs/heads", &self.branches), ("refs/tags", &self.tags)] {
+            for (name, id) in refs.iter() {
+                let ref_path = git_dir.join(prefix).join(name);
+                if let Some(parent) = ref_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(ref_path, format!("{}\n", id))?;
+            }
+        }
+
+        std::fs::write(git_dir.join("HEAD"), format!("ref: refs/heads/{}\n", self.head))?;
+        Ok(())
+    }
+
+    fn tip_snapshot(&self) -> Snapshot {
+        self.tip().map(|tip| self.snapshots[&tip].clone()).unwrap_or_default()
+    }
+
+    fn commit_with(mut self, message: &str, files: Vec<File>, extra_parents: &[Id]) -> Self {
+        let mut snapshot = self.tip_snapshot();
+        for file in files {
+            snapshot.insert(file.path, (file.mode, file.contents));
+        }
+
+        let mut parents: Vec<Id> = self.tip().into_iter().collect();
+        parents.extend_from_slice(extra_parents);
+        self.record(message, snapshot, parents);
+        self
+    }
+
+    fn record(&mut self, message: &str, snapshot: Snapshot, parents: Vec<Id>) {
+        let mut root = BTreeMap::new();
+        for (path, (mode, contents)) in &snapshot {
+            let id = if mode.is_gitlink() {
+                Id::from_str(std::str::from_utf8(contents).unwrap()).expect("gitlinks hold a commit id")
+            } else {
+                self.objects.put(Type::Blob, contents.clone())
+            };
+            insert_node(&mut root, path, *mode, id);
+        }
+        let tree = write_dir(&mut self.objects, &root);
+
+        let at = Utc.timestamp_opt(self.clock, 0).unwrap();
+        let identity = Identity::new(
+            self.name.as_bytes(),
+            self.email.as_bytes(),
+            at,
+            FixedOffset::east_opt(0).unwrap()
+        );
+        self.clock += 60;
+
+        let mut message = message.as_bytes().to_vec();
+        if !message.ends_with(b"\n") {
+            message.push(b'\n');
+        }
+
+        let mut data = Vec::new();
+        Commit::write(&mut data, &tree, &parents, &identity, &identity, &message)
+            .expect("writing to a vec cannot fail");
+        let id = self.objects.put(Type::Commit, data);
+
+        self.snapshots.insert(id.clone(), snapshot);
+        self.branches.insert(self.head.clone(), id);
+    }
+}
+
+fn insert_node(dir: &mut BTreeMap<Vec<u8>, Node>, path: &[u8], mode: FileMode, id: Id) {
+    match path.iter().position(|xs| *xs == b'/') {
+        Some(idx) => {
+            let child = dir.entry(path[..idx].to_vec()).or_insert_with(|| Node::Dir(BTreeMap::new()));
+            if let Node::File(..) = child {
+                *child = Node::Dir(BTreeMap::new());
+            }
+            if let Node::Dir(ref mut entries) = child {
+                insert_node(entries, &path[idx + 1..], mode, id);
+            }
+        },
+        None => {
+            dir.insert(path.to_vec(), Node::File(mode, id));
+        }
+    }
+}
+
+fn write_dir(objects: &mut MemoryStore, dir: &BTreeMap<Vec<u8>, Node>) -> Id {
+    let mut tree = Tree::new();
+    for (name, node) in dir {
+        let entry = match node {
+            Node::File(mode, id) => TreeEntry { mode: *mode, id: id.clone() },
+            Node::Dir(entries) => TreeEntry { mode: FileMode::TREE, id: write_dir(objects, entries) }
+        };
+        tree.insert(name.clone(), entry);
+    }
+
+    let mut data = Vec::new();
+    tree.write(&mut data).expect("writing to a vec cannot fail");
+    objects.put(Type::Tree, data)
+}
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// A scratch directory that is removed on drop.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(prefix: &str) -> Result<TempDir, std::io::Error> {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "git-rs-{}-{}-{}",
+            prefix,
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        if path.exists() {
+            std::fs::remove_dir_all(path.as_path())?;
+        }
+        std::fs::create_dir_all(path.as_path())?;
+        Ok(TempDir(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        self.0.as_path()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(self.0.as_path());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::stores::fs as gitfs;
+    use crate::objects::Object;
+    use crate::refs::RefSet;
+    use super::{ RepoBuilder, TempDir };
+
+    #[test]
+    fn in_memory_works() {
+        let (storage_set, refs) = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n", "src/lib.rs" => "// lib\n"])
+            .branch("dev")
+            .checkout("dev")
+            .commit("second", files!["README" => "goodbye\n"])
+            .in_memory();
+
+        let head = refs.deref("HEAD").expect("HEAD should resolve").clone();
+        assert_eq!(&head, refs.deref("dev").unwrap());
+
+        let messages: Vec<Vec<u8>> = storage_set.commits(&head, None)
+            .map(|(_, commit)| commit.message().to_vec())
+            .collect();
+        assert_eq!(messages, vec![b"second\n".to_vec(), b"first\n".to_vec()]);
+
+        let paths: Vec<PathBuf> = storage_set.tree(&head).map(|(path, _, _)| path).collect();
+        assert_eq!(paths, vec![PathBuf::from("./README"), PathBuf::from("./src/lib.rs")]);
+    }
+
+    #[test]
+    fn ids_are_deterministic() {
+        let build = || RepoBuilder::new().commit("first", files!["README" => "hello\n"]).tip();
+        assert_eq!(build(), build());
+    }
+
+    #[test]
+    fn on_disk_works() {
+        let dir = TempDir::new("testkit").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"])
+            .tag("v1.0");
+        let tip = builder.tip().expect("no tip");
+        builder.write(dir.path()).expect("failed to write");
+
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let refs = RefSet::from_path(dir.path()).expect("failed to read refs");
+        assert_eq!(refs.deref("HEAD"), Some(&tip));
+        assert_eq!(refs.deref("v1.0"), Some(&tip));
+
+        match storage_set.get_and_load(&tip).expect("failed to read") {
+            Some(Object::Commit(commit)) => assert_eq!(commit.message(), b"first\n"),
+            _ => panic!("expected commit")
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot create branch \"dev\" before the first commit")]
+    fn branches_need_a_commit() {
+        RepoBuilder::new().branch("dev");
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot create tag \"v1.0\" before the first commit")]
+    fn tags_need_a_commit() {
+        RepoBuilder::new().tag("v1.0");
+    }
+}
@@ -0,0 +1,476 @@
+use byteorder::{ BigEndian, ReadBytesExt, WriteBytesExt };
+use std::os::unix::ffi::OsStrExt;
+use std::io::{ Cursor, Read };
+use std::ffi::OsStr;
+use std::path::Path;
+
+use crate::index::{ read_id, read_path, read_varint, write_varint, Index, Stat };
+use crate::errors::{ ErrorKind, Result };
+use crate::objects::{ self, Type };
+use crate::ignore::Ignores;
+use crate::bitmap::Bitmap;
+use crate::config::Config;
+use crate::worktree;
+use crate::id::Id;
+
+const EXCLUDE_PER_DIR: &[u8] = b".gitignore";
+
+// One directory of the untracked cache. `untracked` is only meaningful while
+// `valid`: the directory's own stat hasn't moved since its entries were
+// read, and nothing in it was added to or removed from the index.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Dir {
+    name: Vec<u8>,
+    // untracked names, a nested repository's with a trailing slash
+    untracked: Vec<Vec<u8>>,
+    dirs: Vec<Dir>,
+    valid: bool,
+    check_only: bool,
+    stat: Stat,
+    // the blob id of the directory's .gitignore, if it has one
+    exclude_id: Option<Id>
+}
+
+// The index's UNTR extension: what `git status` found untracked in each
+// directory, and what it needs to know the listing still holds. With a
+// cache, a directory is only read again once its mtime moves, its
+// .gitignore (or a parent's, or info/exclude) changes, or the index gains
+// or loses a path in it. core.excludesFile isn't read, so its stat and id
+// are left zero.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UntrackedCache {
+    ident: Vec<u8>,
+    info_exclude: (Stat, Option<Id>),
+    excludes_file: (Stat, Option<Id>),
+    // git's dir_struct flags the listing was made with; 0 lists every
+    // untracked file, as `git status -uall`.
+    dir_flags: u32,
+    exclude_per_dir: Vec<u8>,
+    root: Option<Dir>
+}
+
+// Who made the cache: entries are only good for the worktree and kind of
+// system they were read from.
+fn ident(worktree: &Path) -> Vec<u8> {
+    let system = match std::env::consts::OS {
+        "linux" => "Linux",
+        "macos" => "Darwin",
+        "freebsd" => "FreeBSD",
+        xs => xs
+    };
+    format!("Location {}, system {}\0", worktree.display(), system).into_bytes()
+}
+
+fn read_stat<R: Read>(input: &mut R) -> Result<Stat> {
+    let mut fields = [0u32; 9];
+    for field in fields.iter_mut() {
+        *field = input.read_u32::<BigEndian>()?;
+    }
+    let [ctime, ctime_nsec, mtime, mtime_nsec, dev, ino, uid, gid, size] = fields;
+    Ok(Stat { ctime: (ctime, ctime_nsec), mtime: (mtime, mtime_nsec), dev, ino, uid, gid, size })
+}
+
+fn write_stat(stat: &Stat, output: &mut Vec<u8>) {
+    for field in &[stat.ctime.0, stat.ctime.1, stat.mtime.0, stat.mtime.1, stat.dev, stat.ino, stat.uid, stat.gid, stat.size] {
+        output.write_u32::<BigEndian>(*field).unwrap();
+    }
+}
+
+fn read_optional_id<R: Read>(input: &mut R) -> Result<Option<Id>> {
+    let id = read_id(input)?;
+    Ok(if id.as_ref().iter().all(|xs| *xs == 0) { None } else { Some(id) })
+}
+
+fn write_optional_id(id: &Option<Id>, output: &mut Vec<u8>) {
+    match id {
+        Some(xs) => output.extend_from_slice(xs.as_ref()),
+        None => output.extend_from_slice(&[0u8; 20])
+    }
+}
+
+// Directory blocks in depth-first order, taking one of `remaining` each.
+fn read_dir_block<R: Read>(input: &mut R, remaining: &mut u64) -> Result<Dir> {
+    if *remaining == 0 {
+        return Err(ErrorKind::CorruptedIndex.into())
+    }
+    *remaining -= 1;
+    let untracked_count = read_varint(input)?;
+    let dir_count = read_varint(input)?;
+    let mut dir = Dir { name: read_path(input)?, ..Dir::default() };
+    for _ in 0..untracked_count {
+        dir.untracked.push(read_path(input)?);
+    }
+    for _ in 0..dir_count {
+        let child = read_dir_block(input, remaining)?;
+        dir.dirs.push(child);
+    }
+    Ok(dir)
+}
+
+fn write_dir_block(dir: &Dir, output: &mut Vec<u8>) {
+    // a listing that isn't valid isn't kept.
+    let untracked: &[Vec<u8>] = if dir.valid { &dir.untracked } else { &[] };
+    write_varint(untracked.len() as u64, output);
+    write_varint(dir.dirs.len() as u64, output);
+    for name in std::iter::once(&dir.name).chain(untracked) {
+        output.extend_from_slice(name);
+        output.push(0);
+    }
+    for child in &dir.dirs {
+        write_dir_block(child, output);
+    }
+}
+
+// Calls `f` on `dir` and everything under it, depth first as the blocks
+// are written.
+fn each_dir(dir: &mut Dir, f: &mut dyn FnMut(&mut Dir) -> Result<()>) -> Result<()> {
+    f(dir)?;
+    for child in dir.dirs.iter_mut() {
+        each_dir(child, f)?;
+    }
+    Ok(())
+}
+
+fn all_dirs<'a>(dir: &'a Dir, result: &mut Vec<&'a Dir>) {
+    result.push(dir);
+    for child in &dir.dirs {
+        all_dirs(child, result);
+    }
+}
+
+impl UntrackedCache {
+    // An empty cache for the worktree at `worktree`, as `git update-index
+    // --untracked-cache` starts one.
+    pub fn new(worktree: &Path) -> UntrackedCache {
+        UntrackedCache {
+            ident: ident(worktree),
+            info_exclude: (Stat::default(), None),
+            excludes_file: (Stat::default(), None),
+            dir_flags: 0,
+            exclude_per_dir: EXCLUDE_PER_DIR.to_vec(),
+            root: None
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Result<UntrackedCache> {
+        let mut input = Cursor::new(data);
+        let length = read_varint(&mut input)? as usize;
+        let mut ident = vec![0u8; length.min(data.len())];
+        input.read_exact(&mut ident)?;
+        let info_stat = read_stat(&mut input)?;
+        let excludes_stat = read_stat(&mut input)?;
+        let dir_flags = input.read_u32::<BigEndian>()?;
+        let info_exclude = (info_stat, read_optional_id(&mut input)?);
+        let excludes_file = (excludes_stat, read_optional_id(&mut input)?);
+        let exclude_per_dir = read_path(&mut input)?;
+        let mut cache = UntrackedCache { ident, info_exclude, excludes_file, dir_flags, exclude_per_dir, root: None };
+
+        let count = read_varint(&mut input)?;
+        if count == 0 {
+            return Ok(cache)
+        }
+        let mut remaining = count;
+        let mut root = read_dir_block(&mut input, &mut remaining)?;
+        if remaining != 0 {
+            return Err(ErrorKind::CorruptedIndex.into())
+        }
+        let count = count as usize;
+        let valid = Bitmap::read_ewah(&mut input, count)?;
+        let check_only = Bitmap::read_ewah(&mut input, count)?;
+        let id_valid = Bitmap::read_ewah(&mut input, count)?;
+        let mut i = 0;
+        each_dir(&mut root, &mut |dir| {
+            dir.valid = valid.contains(i);
+            dir.check_only = check_only.contains(i);
+            i += 1;
+            Ok(())
+        })?;
+        each_dir(&mut root, &mut |dir| {
+            if dir.valid {
+                dir.stat = read_stat(&mut input)?;
+            }
+            Ok(())
+        })?;
+        let mut i = 0;
+        each_dir(&mut root, &mut |dir| {
+            if id_valid.contains(i) {
+                dir.exclude_id = Some(read_id(&mut input)?);
+            }
+            i += 1;
+            Ok(())
+        })?;
+        cache.root = Some(root);
+        Ok(cache)
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        write_varint(self.ident.len() as u64, &mut output);
+        output.extend_from_slice(&self.ident);
+        write_stat(&self.info_exclude.0, &mut output);
+        write_stat(&self.excludes_file.0, &mut output);
+        output.write_u32::<BigEndian>(self.dir_flags).unwrap();
+        write_optional_id(&self.info_exclude.1, &mut output);
+        write_optional_id(&self.excludes_file.1, &mut output);
+        output.extend_from_slice(&self.exclude_per_dir);
+        output.push(0);
+
+        let root = match self.root {
+            Some(ref xs) => xs,
+            None => {
+                write_varint(0, &mut output);
+                return output
+            }
+        };
+        let mut dirs = Vec::new();
+        all_dirs(root, &mut dirs);
+        write_varint(dirs.len() as u64, &mut output);
+        write_dir_block(root, &mut output);
+        let bits = |f: fn(&Dir) -> bool| {
+            let mut bitmap = Bitmap::new();
+            for (i, _) in dirs.iter().enumerate().filter(|(_, xs)| f(xs)) {
+                bitmap.insert(i);
+            }
+            bitmap
+        };
+        bits(|xs| xs.valid).write_ewah(&mut output);
+        bits(|xs| xs.valid && xs.check_only).write_ewah(&mut output);
+        bits(|xs| xs.exclude_id.is_some()).write_ewah(&mut output);
+        for dir in dirs.iter().filter(|xs| xs.valid) {
+            write_stat(&dir.stat, &mut output);
+        }
+        for dir in &dirs {
+            if let Some(ref id) = dir.exclude_id {
+                output.extend_from_slice(id.as_ref());
+            }
+        }
+        // a guard for the strings above, as git writes one.
+        output.push(0);
+        output
+    }
+
+    // Forgets the listing of the directory holding `path`, whose entry the
+    // index just gained or lost.
+    pub fn invalidate(&mut self, path: &[u8]) {
+        let mut dir = match self.root {
+            Some(ref mut xs) => xs,
+            None => return
+        };
+        let mut components: Vec<&[u8]> = path.split(|xs| *xs == b'/').collect();
+        components.pop();
+        for component in components {
+            dir = match dir.dirs.iter_mut().find(|xs| xs.name == component) {
+                Some(xs) => xs,
+                None => return
+            };
+        }
+        dir.valid = false;
+        dir.untracked.clear();
+    }
+}
+
+fn is_tracked(index: &Index, path: &[u8]) -> bool {
+    index.entries().binary_search_by(|xs| xs.path.as_slice().cmp(path)).is_ok()
+}
+
+fn read_if_exists(path: &Path) -> Result<Option<(std::fs::Metadata, Vec<u8>)>> {
+    match std::fs::read(path) {
+        Ok(xs) => Ok(Some((std::fs::metadata(path)?, xs))),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into())
+    }
+}
+
+struct Scan<'a> {
+    worktree: &'a Path,
+    index: &'a Index,
+    ignores: Ignores,
+    found: Vec<Vec<u8>>
+}
+
+impl<'a> Scan<'a> {
+    // Lists directory `dir`, at `prefix` ("" for the root, otherwise with a
+    // trailing slash), from the cache where it still holds. `stale` is set
+    // when a parent's .gitignore changed, which no stat of `dir` shows.
+    fn visit(&mut self, prefix: &[u8], dir: &mut Dir, stale: bool) -> Result<()> {
+        let full = self.worktree.join(OsStr::from_bytes(prefix));
+        let stat = Stat::from_metadata(&std::fs::metadata(&full)?);
+        let gitignore = read_if_exists(&full.join(OsStr::from_bytes(EXCLUDE_PER_DIR)))?;
+        let exclude_id = gitignore.as_ref().map(|(_, xs)| objects::hash(Type::Blob, xs));
+        if let Some((_, ref contents)) = gitignore {
+            self.ignores.add(prefix.strip_suffix(b"/").unwrap_or(prefix), contents);
+        }
+        let stale = stale || exclude_id != dir.exclude_id;
+
+        if !stale && dir.valid && dir.stat == stat {
+            self.found.extend(dir.untracked.iter().map(|xs| [prefix, xs].concat()));
+            for child in dir.dirs.iter_mut() {
+                self.visit(&[prefix, &child.name, b"/"].concat(), child, false)?;
+            }
+            return Ok(())
+        }
+
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&full)? {
+            let entry = entry?;
+            entries.push((entry.file_name().as_bytes().to_vec(), entry.file_type()?.is_dir()));
+        }
+        entries.sort();
+        let mut cached = std::mem::take(&mut dir.dirs);
+        let mut untracked = Vec::new();
+        for (name, is_dir) in entries {
+            let path = [prefix, &name].concat();
+            if name == b".git" || is_tracked(self.index, &path) || self.ignores.is_ignored(&path, is_dir) {
+                continue
+            }
+            if !is_dir {
+                untracked.push(name);
+            } else if full.join(OsStr::from_bytes(&name)).join(".git").exists() {
+                untracked.push([&name[..], b"/"].concat());
+            } else {
+                let mut child = match cached.iter().position(|xs| xs.name == name) {
+                    Some(xs) => cached.swap_remove(xs),
+                    None => Dir { name, ..Dir::default() }
+                };
+                self.visit(&[&path[..], b"/"].concat(), &mut child, stale)?;
+                dir.dirs.push(child);
+            }
+        }
+        self.found.extend(untracked.iter().map(|xs| [prefix, xs].concat()));
+        dir.untracked = untracked;
+        dir.valid = true;
+        dir.stat = stat;
+        dir.exclude_id = exclude_id;
+        Ok(())
+    }
+}
+
+// The untracked files of the worktree at `path`, sorted, as `git status
+// -uall` lists them: not in `index`, not ignored, and a nested repository
+// as its directory. The index's untracked cache is used and brought up to
+// date if it has one, or started if core.untrackedCache is true; save the
+// index to keep it. core.untrackedCache=false drops it.
+pub fn untracked_files(path: &Path, index: &mut Index) -> Result<Vec<Vec<u8>>> {
+    let exclude = read_if_exists(&worktree::common_dir(path)?.join("info").join("exclude"))?;
+    let exclude_stat = exclude.as_ref().map(|(xs, _)| Stat::from_metadata(xs)).unwrap_or_default();
+    let exclude_id = exclude.as_ref().map(|(_, xs)| objects::hash(Type::Blob, xs));
+    let mut ignores = Ignores::new();
+    if let Some((_, ref contents)) = exclude {
+        ignores.add(b"", contents);
+    }
+
+    let enabled = Config::from_path(path)?.get_bool("core.untrackedcache");
+    let cache = match (enabled, index.set_untracked_cache(None)) {
+        (Some(false), _) | (None, None) => None,
+        (_, Some(xs)) if xs.ident == ident(path) && xs.dir_flags == 0 => Some(xs),
+        _ => Some(UntrackedCache::new(path))
+    };
+    let mut root = match cache {
+        Some(ref xs) if xs.info_exclude.1 == exclude_id => xs.root.clone().unwrap_or_default(),
+        _ => Dir::default()
+    };
+
+    let mut scan = Scan { worktree: path, index, ignores, found: Vec::new() };
+    scan.visit(b"", &mut root, false)?;
+    let mut found = scan.found;
+    found.sort();
+    if let Some(mut cache) = cache {
+        cache.info_exclude = (exclude_stat, exclude_id);
+        cache.root = Some(root);
+        index.set_untracked_cache(Some(cache));
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::objects::tree::FileMode;
+    use crate::stores::fs as gitfs;
+    use crate::index::{ Entry, Index };
+    use crate::files;
+    use std::path::Path;
+
+    use super::{ ident, untracked_files, UntrackedCache };
+
+    #[test]
+    fn reuses_listings_of_unchanged_directories() {
+        let dir = TempDir::new("untracked").expect("failed to create tempdir");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n", "src/lib.rs" => "\n"]);
+        let tip = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let mut index = Index::from_tree(&storage_set, &tip).unwrap();
+
+        std::fs::create_dir_all(dir.path().join("src")).unwrap();
+        std::fs::create_dir_all(dir.path().join("build")).unwrap();
+        for (name, contents) in &[("README", "hello\n"), ("src/lib.rs", "\n"), ("notes.txt", ""), ("src/new.rs", ""), ("src/debug.log", ""), ("build/out.o", ""), (".gitignore", "build/\n*.log\n")] {
+            std::fs::write(dir.path().join(name), contents).unwrap();
+        }
+        std::fs::create_dir_all(dir.path().join(".git/info")).unwrap();
+        std::fs::write(dir.path().join(".git/info/exclude"), "*.swp\n").unwrap();
+        std::fs::write(dir.path().join("src/.lib.rs.swp"), "").unwrap();
+        let expected = vec![b".gitignore".to_vec(), b"notes.txt".to_vec(), b"src/new.rs".to_vec()];
+        // without a cache, nothing is kept.
+        assert_eq!(untracked_files(dir.path(), &mut index).unwrap(), expected);
+        assert!(index.untracked_cache().is_none());
+
+        index.set_untracked_cache(Some(UntrackedCache::new(dir.path())));
+        assert_eq!(untracked_files(dir.path(), &mut index).unwrap(), expected);
+        let mut data = Vec::new();
+        index.write(&mut data).unwrap();
+        let mut index = Index::parse(&data).unwrap();
+        let mut cache = index.set_untracked_cache(None).unwrap();
+        assert_eq!(UntrackedCache::parse(&cache.write()).unwrap(), cache);
+
+        // an unchanged directory isn't read again, so a name planted in its
+        // listing comes back.
+        cache.root.as_mut().unwrap().untracked.push(b"planted".to_vec());
+        index.set_untracked_cache(Some(cache));
+        let listed = untracked_files(dir.path(), &mut index).unwrap();
+        assert!(listed.contains(&b"planted".to_vec()));
+
+        // staging a file forgets its directory's listing; a new file moves
+        // its directory's mtime.
+        let id = index.get(b"README", 0).unwrap().id.clone();
+        index.add(Entry::new(b"notes.txt".to_vec(), FileMode::FILE, id));
+        std::fs::write(dir.path().join("src/other.rs"), "").unwrap();
+        assert_eq!(untracked_files(dir.path(), &mut index).unwrap(), vec![b".gitignore".to_vec(), b"src/new.rs".to_vec(), b"src/other.rs".to_vec()]);
+
+        // so does a changed .gitignore for everything under it.
+        std::fs::write(dir.path().join(".gitignore"), "build/\n*.log\n*.rs\n").unwrap();
+        assert_eq!(untracked_files(dir.path(), &mut index).unwrap(), vec![b".gitignore".to_vec()]);
+    }
+
+    #[test]
+    fn config_starts_and_drops_the_cache() {
+        let dir = TempDir::new("untracked-config").expect("failed to create tempdir");
+        let builder = RepoBuilder::new().commit("first", files!["README" => "hello\n"]);
+        let tip = builder.tip().unwrap();
+        builder.write(dir.path()).expect("failed to write");
+        let storage_set = gitfs::from(dir.path()).expect("failed to open storage");
+        let mut index = Index::from_tree(&storage_set, &tip).unwrap();
+        std::fs::write(dir.path().join("README"), "hello\n").unwrap();
+        std::fs::create_dir_all(dir.path().join("vendor/dep/.git")).unwrap();
+        std::fs::write(dir.path().join("vendor/dep/lib.rs"), "").unwrap();
+        let config = dir.path().join(".git/config");
+        let original = std::fs::read_to_string(&config).unwrap_or_default();
+
+        // a nested repository is listed as its directory, not its files.
+        std::fs::write(&config, format!("{}[core]\n\tuntrackedCache = true\n", original)).unwrap();
+        assert_eq!(untracked_files(dir.path(), &mut index).unwrap(), vec![b"vendor/dep/".to_vec()]);
+        assert!(index.untracked_cache().is_some());
+
+        // a cache made for another worktree is started over.
+        let mut cache = index.set_untracked_cache(None).unwrap();
+        cache.root.as_mut().unwrap().untracked.push(b"planted".to_vec());
+        cache.ident = ident(Path::new("/elsewhere"));
+        index.set_untracked_cache(Some(cache));
+        assert_eq!(untracked_files(dir.path(), &mut index).unwrap(), vec![b"vendor/dep/".to_vec()]);
+
+        std::fs::write(&config, format!("{}[core]\n\tuntrackedCache = false\n", original)).unwrap();
+        assert_eq!(untracked_files(dir.path(), &mut index).unwrap(), vec![b"vendor/dep/".to_vec()]);
+        assert!(index.untracked_cache().is_none());
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::BTreeMap;
+use std::io::{ self, Cursor, Read, Seek, Write };
+use std::path::{ Path, PathBuf };
+use std::sync::{ Arc, RwLock };
+
+pub trait ReadSeek: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+// A file being written through a provider. Its contents are only durable,
+// and may only be visible, once `finish` returns.
+pub trait WriteFile: Write + Send {
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+impl WriteFile for std::fs::File {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub len: u64,
+    pub is_dir: bool
+}
+
+// Filesystem access for the object and ref stores, so a repository can be
+// read from somewhere other than the OS filesystem: memory, or a browser's
+// storage under wasm32. Paths are whatever the provider makes of them.
+pub trait VfsProvider: Send + Sync {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadSeek>>;
+    // the names of the entries in directory `path`, in no particular order
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<String>>;
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    // replaces whatever is at `path`
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()>;
+    // fails with AlreadyExists rather than replace a file
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn WriteFile + '_>>;
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    // Gives `to` the contents of `from`, failing with AlreadyExists rather
+    // than replace a file. Providers without links copy.
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if self.metadata(to).is_ok() {
+            return Err(io::ErrorKind::AlreadyExists.into())
+        }
+        self.write(to, &self.read(from)?)
+    }
+
+    // The whole of `path`, mapped where the provider can.
+    fn map(&self, path: &Path) -> io::Result<Box<dyn AsRef<[u8]> + Send + Sync>> {
+        Ok(Box::new(self.read(path)?))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.open(path)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    // Only the OS filesystem has links to resolve.
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        Ok(path.to_path_buf())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsFs;
+
+pub fn os() -> Arc<dyn VfsProvider> {
+    Arc::new(OsFs)
+}
+
+impl VfsProvider for OsFs {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadSeek>> {
+        Ok(Box::new(std::fs::File::open(path)?))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(path)? {
+            // names that aren't UTF-8 can't be object dirs or ref names.
+            if let Ok(xs) = entry?.file_name().into_string() {
+                names.push(xs);
+            }
+        }
+        Ok(names)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(Metadata { len: metadata.len(), is_dir: metadata.is_dir() })
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        std::fs::write(path, data)
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn WriteFile + '_>> {
+        Ok(Box::new(std::fs::OpenOptions::new().write(true).create_new(true).open(path)?))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::hard_link(from, to)
+    }
+
+    fn map(&self, path: &Path) -> io::Result<Box<dyn AsRef<[u8]> + Send + Sync>> {
+        let file = std::fs::File::open(path)?;
+        Ok(Box::new(unsafe { memmap::MmapOptions::new().map(&file)? }))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+}
+
+// Files kept in memory by path. Directories exist while files are under
+// them.
+#[derive(Debug, Default)]
+pub struct MemoryFs {
+    files: RwLock<BTreeMap<PathBuf, Arc<[u8]>>>
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    pub fn insert<P: Into<PathBuf>>(&self, path: P, data: &[u8]) {
+        self.files.write().unwrap().insert(path.into(), Arc::from(data));
+    }
+
+    pub fn remove(&self, path: &Path) -> Option<Arc<[u8]>> {
+        self.files.write().unwrap().remove(path)
+    }
+
+    fn not_found() -> io::Error {
+        io::Error::from(io::ErrorKind::NotFound)
+    }
+}
+
+// Collects a file for a `MemoryFs`, which gains it on `finish`.
+struct MemoryFile<'a> {
+    fs: &'a MemoryFs,
+    path: PathBuf,
+    data: Vec<u8>
+}
+
+impl<'a> Write for MemoryFile<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> WriteFile for MemoryFile<'a> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.fs.insert(self.path, &self.data);
+        Ok(())
+    }
+}
+
+impl VfsProvider for MemoryFs {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn ReadSeek>> {
+        match self.files.read().unwrap().get(path) {
+            Some(xs) => Ok(Box::new(Cursor::new(xs.clone()))),
+            None => Err(MemoryFs::not_found())
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<String>> {
+        let files = self.files.read().unwrap();
+        let mut names: Vec<String> = files.range(path.to_path_buf()..)
+            .map(|(xs, _)| xs)
+            .take_while(|xs| xs.starts_with(path))
+            .filter_map(|xs| xs.strip_prefix(path).ok()?.components().next())
+            .filter_map(|xs| xs.as_os_str().to_str().map(String::from))
+            .collect();
+        if names.is_empty() {
+            return Err(MemoryFs::not_found())
+        }
+        names.dedup();
+        Ok(names)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let files = self.files.read().unwrap();
+        if let Some(xs) = files.get(path) {
+            return Ok(Metadata { len: xs.len() as u64, is_dir: false })
+        }
+        match files.range(path.to_path_buf()..).next() {
+            Some((xs, _)) if xs.starts_with(path) => Ok(Metadata { len: 0, is_dir: true }),
+            _ => Err(MemoryFs::not_found())
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut files = self.files.write().unwrap();
+        let data = files.remove(from).ok_or_else(MemoryFs::not_found)?;
+        files.insert(to.to_path_buf(), data);
+        Ok(())
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        self.insert(path, data);
+        Ok(())
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn WriteFile + '_>> {
+        if self.files.read().unwrap().contains_key(path) {
+            return Err(io::ErrorKind::AlreadyExists.into())
+        }
+        Ok(Box::new(MemoryFile { fs: self, path: path.to_path_buf(), data: Vec::new() }))
+    }
+
+    // directories are implicit.
+    fn create_dir_all(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.remove(path).map(|_| ()).ok_or_else(MemoryFs::not_found)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.files.read().unwrap().get(path) {
+            Some(xs) => Ok(xs.to_vec()),
+            None => Err(MemoryFs::not_found())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{ MemoryFs, Metadata, VfsProvider };
+
+    #[test]
+    fn memory_fs_has_implicit_directories() {
+        let fs = MemoryFs::new();
+        fs.insert("repo/.git/HEAD", b"ref: refs/heads/master\n");
+        fs.insert("repo/.git/refs/heads/master", b"0000000000000000000000000000000000000000\n");
+        fs.insert("repo/.git/refs/tags/v1", b"0000000000000000000000000000000000000000\n");
+
+        assert_eq!(fs.read(Path::new("repo/.git/HEAD")).unwrap(), b"ref: refs/heads/master\n");
+        assert_eq!(fs.metadata(Path::new("repo/.git/refs")).unwrap(), Metadata { len: 0, is_dir: true });
+        assert_eq!(fs.metadata(Path::new("repo/.git/HEAD")).unwrap().len, 23);
+        assert!(fs.metadata(Path::new("repo/.git/objects")).is_err());
+        let mut names = fs.read_dir(Path::new("repo/.git/refs")).unwrap();
+        names.sort();
+        assert_eq!(names, vec!["heads", "tags"]);
+
+        fs.rename(Path::new("repo/.git/refs/tags/v1"), Path::new("repo/.git/refs/tags/v2")).unwrap();
+        assert_eq!(fs.read_dir(Path::new("repo/.git/refs/tags")).unwrap(), vec!["v2"]);
+        assert!(fs.open(Path::new("repo/.git/refs/tags/v1")).is_err());
+    }
+}
@@ -1,22 +1,26 @@
 use std::collections::{ HashSet, BinaryHeap };
 
 use crate::stores::{ Queryable, StorageSet };
+use crate::errors::{ ErrorKind, Result };
 use crate::objects::commit::Commit;
+use crate::walk::tree::TreeWalk;
+use crate::pathspec::Pathspec;
 use crate::objects::Object;
+use crate::shallow::Shallow;
+use crate::cancel::Token;
 use crate::id::Id;
 
 #[derive(Debug)]
 pub struct IdCommit(Id, Commit);
 
+// Newest first by committer time. Commits from the same second come out in
+// ascending id order, and commits without a committer last, so a walk's order
+// never depends on the platform, the storage or the order parents were
+// queued in.
 impl std::cmp::Ord for IdCommit {
     fn cmp(&self, other: &IdCommit) -> std::cmp::Ordering {
-        if let Some(ref rhs) = self.1.committer() {
-            if let Some(ref lhs) = other.1.committer() {
-                return rhs.at().cmp(lhs.at());
-            }
-        }
-
-        std::cmp::Ordering::Equal
+        let at = |xs: &IdCommit| xs.1.committer().map(|xs| xs.at().timestamp());
+        at(self).cmp(&at(other)).then_with(|| other.0.cmp(&self.0))
     }
 }
 
@@ -37,6 +41,8 @@ impl std::cmp::Eq for IdCommit { }
 pub struct CommitIterator<'a, S: Queryable> {
     storage_set: &'a StorageSet<S>,
     seen: HashSet<Id>,
+    shallow: Option<&'a Shallow>,
+    cancel: Option<&'a Token>,
     target: BinaryHeap<IdCommit>
 }
 
@@ -59,9 +65,23 @@ impl<'a, S: Queryable> CommitIterator<'a, S> {
         CommitIterator {
             target,
             storage_set,
+            shallow: None,
+            cancel: None,
             seen,
         }
     }
+
+    pub fn with_shallow(mut self, shallow: &'a Shallow) -> CommitIterator<'a, S> {
+        self.shallow = Some(shallow);
+        self
+    }
+
+    // Ends the walk early once `cancel` is cancelled; check the token to
+    // tell a cancelled walk from a finished one.
+    pub fn with_cancel(mut self, cancel: &'a Token) -> CommitIterator<'a, S> {
+        self.cancel = Some(cancel);
+        self
+    }
 }
 
 impl<'a, S: Queryable> Iterator for CommitIterator<'a, S> {
@@ -76,8 +96,16 @@ impl<'a, S: Queryable> Iterator for CommitIterator<'a, S> {
         //          add the remaining parent ids to seen.
         //          push remaining parent commits into the vector.
 
+        if self.cancel.is_some_and(Token::is_cancelled) {
+            return None
+        }
         let newest = self.target.pop()?;
 
+        // shallow boundary commits are treated as parentless.
+        if self.shallow.is_some_and(|shallow| shallow.contains(&newest.0)) {
+            return Some((newest.0, newest.1))
+        }
+
         if let Some(xs) = newest.1.parents() {
             let seen = &mut self.seen;
             let storage_set = &self.storage_set;
@@ -102,3 +130,130 @@ impl<'a, S: Queryable> Iterator for CommitIterator<'a, S> {
         Some((newest.0, newest.1))
     }
 }
+
+// Whether `commit` changes anything `pathspec` matches: `git log -- <paths>`
+// keeps a commit unless some parent has the same matching entries.
+pub fn touches<S: Queryable>(storage_set: &StorageSet<S>, commit: &Commit, pathspec: &Pathspec) -> Result<bool> {
+    let walk = TreeWalk::new(storage_set).pathspec(pathspec.clone());
+    let entries = match commit.tree() {
+        Some(xs) => walk.entries(&xs)?,
+        None => return Err(ErrorKind::MissingObject.into())
+    };
+    let parents = commit.parents().unwrap_or_default();
+    if parents.is_empty() {
+        return Ok(!entries.is_empty())
+    }
+    for parent in parents {
+        if walk.entries(&parent)? == entries {
+            return Ok(false)
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stores::memory::Store as MemoryStore;
+    use crate::testkit::RepoBuilder;
+    use crate::pathspec::Pathspec;
+    use crate::stores::StorageSet;
+    use crate::shallow::Shallow;
+    use crate::cancel::Token;
+    use crate::objects::Type;
+    use crate::id::Id;
+    use crate::files;
+    use super::touches;
+
+    fn id(byte: u8) -> Id {
+        Id::from(&[byte; 20])
+    }
+
+    fn commit(parent: Option<Id>, timestamp: u32) -> Vec<u8> {
+        merge(parent.as_slice(), timestamp)
+    }
+
+    fn merge(parents: &[Id], timestamp: u32) -> Vec<u8> {
+        let ident = format!("Chris Dickinson <christopher.s.dickinson@gmail.com> {} -0800", timestamp);
+        let parents: String = parents.iter().map(|xs| format!("parent {}\n", xs)).collect();
+        format!(
+            "tree {}\n{}author {}\ncommitter {}\n\nmessage\n", Id::default(), parents, ident, ident
+        ).into_bytes()
+    }
+
+    #[test]
+    fn walk_works() {
+        let mut objects = MemoryStore::new();
+        objects.insert(id(1), Type::Commit, commit(None, 1545286964));
+        objects.insert(id(2), Type::Commit, commit(Some(id(1)), 1545286965));
+        objects.insert(id(3), Type::Commit, commit(Some(id(2)), 1545286966));
+        let storage_set = StorageSet::new(objects);
+
+        let ids: Vec<Id> = storage_set.commits(&id(3), None).map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![id(3), id(2), id(1)]);
+    }
+
+    #[test]
+    fn walk_breaks_ties_by_id() {
+        let mut objects = MemoryStore::new();
+        objects.insert(id(1), Type::Commit, commit(None, 1545286964));
+        for byte in 2..6 {
+            objects.insert(id(byte), Type::Commit, commit(Some(id(1)), 1545286965));
+        }
+        objects.insert(id(8), Type::Commit, merge(&[id(5), id(3), id(4), id(2)], 1545286966));
+        objects.insert(id(9), Type::Commit, merge(&[id(2), id(4), id(3), id(5)], 1545286966));
+        let storage_set = StorageSet::new(objects);
+
+        for tip in &[id(8), id(9)] {
+            let ids: Vec<Id> = storage_set.commits(tip, None).map(|(id, _)| id).collect();
+            assert_eq!(ids, vec![tip.clone(), id(2), id(3), id(4), id(5), id(1)]);
+        }
+    }
+
+    #[test]
+    fn walk_ends_once_cancelled() {
+        let mut objects = MemoryStore::new();
+        objects.insert(id(1), Type::Commit, commit(None, 1545286964));
+        objects.insert(id(2), Type::Commit, commit(Some(id(1)), 1545286965));
+        let storage_set = StorageSet::new(objects);
+
+        let token = Token::new();
+        let mut walk = storage_set.commits(&id(2), None).with_cancel(&token);
+        assert_eq!(walk.next().map(|(id, _)| id), Some(id(2)));
+        token.cancel();
+        assert!(walk.next().is_none());
+    }
+
+    #[test]
+    fn walk_stops_at_shallow_boundary() {
+        let mut objects = MemoryStore::new();
+        objects.insert(id(2), Type::Commit, commit(Some(id(1)), 1545286965));
+        objects.insert(id(3), Type::Commit, commit(Some(id(2)), 1545286966));
+        let storage_set = StorageSet::new(objects);
+
+        let mut shallow = Shallow::default();
+        shallow.update(&[id(2)], &[]);
+
+        let ids: Vec<Id> = storage_set.commits(&id(3), None)
+            .with_shallow(&shallow)
+            .map(|(id, _)| id)
+            .collect();
+        assert_eq!(ids, vec![id(3), id(2)]);
+    }
+
+    #[test]
+    fn touches_limits_log_to_a_pathspec() {
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "a\n", "src/lib.rs" => "lib\n"])
+            .commit("docs", files!["README" => "b\n", "src/lib.rs" => "lib\n"])
+            .commit("code", files!["README" => "b\n", "src/lib.rs" => "lib 2\n"]);
+        let tip = builder.tip().unwrap();
+        let (storage_set, _) = builder.in_memory();
+
+        let pathspec = Pathspec::parse(&["src"]).unwrap();
+        let messages: Vec<Vec<u8>> = storage_set.commits(&tip, None)
+            .filter(|(_, commit)| touches(&storage_set, commit, &pathspec).unwrap())
+            .map(|(_, commit)| commit.message().to_vec())
+            .collect();
+        assert_eq!(messages, vec![b"code\n".to_vec(), b"first\n".to_vec()]);
+    }
+}
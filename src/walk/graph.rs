@@ -0,0 +1,385 @@
+use std::collections::{ HashSet, VecDeque };
+use std::io::Write;
+
+use crate::stores::{ Queryable, StorageSet };
+use crate::objects::Object;
+use crate::errors::Result;
+use crate::id::Id;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    EdgeList,
+    GraphML
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub format: Format,
+    // number of parent hops to follow from the roots; None walks all history
+    pub depth: Option<usize>,
+    pub trees: bool,
+    pub blobs: bool
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            format: Format::EdgeList,
+            depth: None,
+            trees: true,
+            blobs: true
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum NodeKind {
+    Commit,
+    Tree,
+    Blob,
+    Tag,
+    Gitlink
+}
+
+impl NodeKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            NodeKind::Commit => "commit",
+            NodeKind::Tree => "tree",
+            NodeKind::Blob => "blob",
+            NodeKind::Tag => "tag",
+            NodeKind::Gitlink => "gitlink"
+        }
+    }
+}
+
+struct Sink<'a, W: Write> {
+    format: Format,
+    output: &'a mut W,
+    nodes: HashSet<Id>,
+    // edge targets with the type their edge implies, so that GraphML can
+    // give those never reached (missing from the store) a stub node.
+    targets: Vec<(Id, &'static str)>
+}
+
+// Text as XML 1.0 element content. Characters XML 1.0 can't hold at all,
+// even as references (most C0 controls, U+FFFE and U+FFFF), become U+FFFD.
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            // kept as references so that parsers don't normalize them away.
+            '\t' | '\n' | '\r' => escaped.push_str(&format!("&#{};", c as u32)),
+            '\u{0}'..='\u{1f}' | '\u{fffe}' | '\u{ffff}' => escaped.push('\u{fffd}'),
+            _ => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+impl<'a, W: Write> Sink<'a, W> {
+    fn begin(&mut self) -> Result<()> {
+        if self.format == Format::GraphML {
+            self.output.write_all(concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+                "  <key id=\"type\" for=\"node\" attr.name=\"type\" attr.type=\"string\"/>\n",
+                "  <key id=\"stub\" for=\"node\" attr.name=\"stub\" attr.type=\"boolean\"><default>false</default></key>\n",
+                "  <key id=\"kind\" for=\"edge\" attr.name=\"kind\" attr.type=\"string\"/>\n",
+                "  <key id=\"name\" for=\"edge\" attr.name=\"name\" attr.type=\"string\"/>\n",
+                "  <graph id=\"G\" edgedefault=\"directed\">\n"
+            ).as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn end(&mut self) -> Result<()> {
+        if self.format == Format::GraphML {
+            // every edge needs both its endpoints.
+            for (id, kind) in std::mem::take(&mut self.targets) {
+                if self.nodes.insert(id.clone()) {
+                    writeln!(
+                        self.output,
+                        "    <node id=\"{}\"><data key=\"type\">{}</data><data key=\"stub\">true</data></node>",
+                        id,
+                        kind
+                    )?;
+                }
+            }
+            self.output.write_all(b"  </graph>\n</graphml>\n")?;
+        }
+        Ok(())
+    }
+
+    // returns true the first time a node is seen.
+    fn node(&mut self, id: &Id, kind: NodeKind) -> Result<bool> {
+        if !self.nodes.insert(id.clone()) {
+            return Ok(false)
+        }
+
+        if self.format == Format::GraphML {
+            writeln!(
+                self.output,
+                "    <node id=\"{}\"><data key=\"type\">{}</data></node>",
+                id,
+                kind.as_str()
+            )?;
+        }
+        Ok(true)
+    }
+
+    // `target` is the type of object `kind` of edge leads to.
+    fn edge(&mut self, from: &Id, to: &Id, kind: &str, name: &[u8], target: &'static str) -> Result<()> {
+        let name = String::from_utf8_lossy(name);
+        match self.format {
+            Format::EdgeList => {
+                let escaped = name
+                    .replace('\\', "\\\\")
+                    .replace('\t', "\\t")
+                    .replace('\n', "\\n");
+                writeln!(self.output, "{}\t{}\t{}\t{}", from, to, kind, escaped)?;
+            },
+            Format::GraphML => {
+                if !self.nodes.contains(to) {
+                    self.targets.push((to.clone(), target));
+                }
+                let escaped = xml_escape(&name);
+                write!(
+                    self.output,
+                    "    <edge source=\"{}\" target=\"{}\"><data key=\"kind\">{}</data>",
+                    from,
+                    to,
+                    kind
+                )?;
+                if !escaped.is_empty() {
+                    write!(self.output, "<data key=\"name\">{}</data>", escaped)?;
+                }
+                self.output.write_all(b"</edge>\n")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn export<S, W>(
+    storage_set: &StorageSet<S>,
+    roots: &[Id],
+    options: &Options,
+    output: &mut W
+) -> Result<()> where
+    S: Queryable,
+    W: Write {
+
+    let mut sink = Sink {
+        format: options.format,
+        output,
+        nodes: HashSet::new(),
+        targets: Vec::new()
+    };
+    sink.begin()?;
+
+    let mut queue: VecDeque<(Id, usize)> = roots.iter().map(|id| (id.clone(), 0)).collect();
+    let mut queued: HashSet<Id> = roots.iter().cloned().collect();
+
+    while let Some((id, depth)) = queue.pop_front() {
+        let object = match storage_set.get_and_load(&id)? {
+            Some(xs) => xs,
+            None => continue
+        };
+
+        match object {
+            Object::Tag(tag) => {
+                sink.node(&id, NodeKind::Tag)?;
+                if let Some(target) = tag.object() {
+                    sink.edge(&id, &target, "tag", b"", "object")?;
+                    if queued.insert(target.clone()) {
+                        queue.push_back((target, depth));
+                    }
+                }
+            },
+
+            Object::Commit(commit) => {
+                sink.node(&id, NodeKind::Commit)?;
+
+                if options.trees {
+                    if let Some(tree) = commit.tree() {
+                        sink.edge(&id, &tree, "tree", b"", "tree")?;
+                        export_tree(storage_set, &tree, options, &mut sink)?;
+                    }
+                }
+
+                if options.depth.is_some_and(|max| depth >= max) {
+                    continue
+                }
+
+                for parent in commit.parents().unwrap_or_default() {
+                    sink.edge(&id, &parent, "parent", b"", "commit")?;
+                    if queued.insert(parent.clone()) {
+                        queue.push_back((parent, depth + 1));
+                    }
+                }
+            },
+
+            Object::Tree(_) => {
+                export_tree(storage_set, &id, options, &mut sink)?;
+            },
+
+            Object::Blob(_) => {
+                sink.node(&id, NodeKind::Blob)?;
+            }
+        }
+    }
+
+    sink.end()
+}
+
+fn export_tree<S, W>(
+    storage_set: &StorageSet<S>,
+    root: &Id,
+    options: &Options,
+    sink: &mut Sink<W>
+) -> Result<()> where
+    S: Queryable,
+    W: Write {
+
+    let mut stack = vec![root.clone()];
+    while let Some(id) = stack.pop() {
+        if !sink.node(&id, NodeKind::Tree)? {
+            continue
+        }
+
+        let tree = match storage_set.get_and_load(&id)? {
+            Some(Object::Tree(xs)) => xs,
+            _ => continue
+        };
+
+        for (name, entry) in tree {
+            if entry.mode.is_tree() {
+                sink.edge(&id, &entry.id, "entry", &name, "tree")?;
+                stack.push(entry.id);
+            } else if entry.mode.is_gitlink() {
+                sink.node(&entry.id, NodeKind::Gitlink)?;
+                sink.edge(&id, &entry.id, "entry", &name, "gitlink")?;
+            } else if options.blobs {
+                sink.node(&entry.id, NodeKind::Blob)?;
+                sink.edge(&id, &entry.id, "entry", &name, "blob")?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stores::memory::Store as MemoryStore;
+    use crate::stores::StorageSet;
+    use crate::objects::Type;
+    use crate::id::Id;
+    use super::{ export, Format, Options };
+
+    fn id(byte: u8) -> Id {
+        Id::from(&[byte; 20])
+    }
+
+    fn fixture() -> StorageSet<MemoryStore> {
+        let mut objects = MemoryStore::new();
+
+        let mut tree = b"100644 README\0".to_vec();
+        tree.extend_from_slice(id(3).as_ref());
+        objects.insert(id(2), Type::Tree, tree);
+        objects.insert(id(3), Type::Blob, b"hello\n".to_vec());
+
+        let ident = "Chris Dickinson <christopher.s.dickinson@gmail.com> 1545286964 -0800";
+        objects.insert(id(1), Type::Commit, format!(
+            "tree {}\nauthor {}\ncommitter {}\n\nfirst\n", id(2), ident, ident
+        ).into_bytes());
+        objects.insert(id(4), Type::Commit, format!(
+            "tree {}\nparent {}\nauthor {}\ncommitter {}\n\nsecond\n", id(2), id(1), ident, ident
+        ).into_bytes());
+
+        StorageSet::new(objects)
+    }
+
+    #[test]
+    fn edge_list_works() {
+        let storage_set = fixture();
+        let mut output = Vec::new();
+        export(&storage_set, &[id(4)], &Options::default(), &mut output).expect("export failed");
+
+        let text = String::from_utf8(output).expect("not utf8");
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines, vec![
+            format!("{}\t{}\ttree\t", id(4), id(2)),
+            format!("{}\t{}\tentry\tREADME", id(2), id(3)),
+            format!("{}\t{}\tparent\t", id(4), id(1)),
+            format!("{}\t{}\ttree\t", id(1), id(2)),
+        ]);
+    }
+
+    #[test]
+    fn depth_and_filters_work() {
+        let storage_set = fixture();
+        let mut output = Vec::new();
+        let options = Options {
+            depth: Some(0),
+            blobs: false,
+            ..Options::default()
+        };
+        export(&storage_set, &[id(4)], &options, &mut output).expect("export failed");
+
+        let text = String::from_utf8(output).expect("not utf8");
+        assert_eq!(text, format!("{}\t{}\ttree\t\n", id(4), id(2)));
+    }
+
+    #[test]
+    fn graphml_works() {
+        let storage_set = fixture();
+        let mut output = Vec::new();
+        let options = Options {
+            format: Format::GraphML,
+            ..Options::default()
+        };
+        export(&storage_set, &[id(1)], &options, &mut output).expect("export failed");
+
+        let text = String::from_utf8(output).expect("not utf8");
+        assert!(text.starts_with("<?xml"));
+        assert!(text.contains(&format!("<node id=\"{}\"><data key=\"type\">commit</data></node>", id(1))));
+        assert!(text.contains(&format!("<node id=\"{}\"><data key=\"type\">blob</data></node>", id(3))));
+        assert!(text.contains("<data key=\"name\">README</data>"));
+        assert!(text.trim_end().ends_with("</graphml>"));
+    }
+
+    #[test]
+    fn graphml_stubs_missing_nodes_and_escapes_names() {
+        let mut objects = MemoryStore::new();
+        let mut tree = b"100644 a\x01&b\rc\0".to_vec();
+        tree.extend_from_slice(id(3).as_ref());
+        objects.insert(id(2), Type::Tree, tree);
+        objects.insert(id(3), Type::Blob, b"hello\n".to_vec());
+        let ident = "Chris Dickinson <christopher.s.dickinson@gmail.com> 1545286964 -0800";
+        objects.insert(id(1), Type::Commit, format!(
+            "tree {}\nparent {}\nauthor {}\ncommitter {}\n\nfirst\n", id(2), id(9), ident, ident
+        ).into_bytes());
+        objects.insert(id(4), Type::Commit, format!(
+            "tree {}\nparent {}\nauthor {}\ncommitter {}\n\nsecond\n", id(2), id(1), ident, ident
+        ).into_bytes());
+        let storage_set = StorageSet::new(objects);
+
+        let options = Options { format: Format::GraphML, ..Options::default() };
+        let mut output = Vec::new();
+        export(&storage_set, &[id(4)], &options, &mut output).expect("export failed");
+        let text = String::from_utf8(output).expect("not utf8");
+
+        // id(9) is missing, but the edge to it still needs a node.
+        let stub = |id: Id| format!("<node id=\"{}\"><data key=\"type\">commit</data><data key=\"stub\">true</data></node>", id);
+        assert!(text.contains(&format!("target=\"{}\"><data key=\"kind\">parent</data>", id(9))));
+        assert!(text.contains(&stub(id(9))));
+        assert!(!text.contains(&stub(id(1))));
+        assert!(text.contains("<data key=\"name\">a\u{fffd}&amp;b&#13;c</data>"));
+    }
+}
@@ -5,8 +5,11 @@ use std::os::unix::ffi::OsStrExt;
 
 use crate::objects::tree::{ TreeEntry, FileMode };
 use crate::stores::{ StorageSet, Queryable };
+use crate::errors::{ ErrorKind, Result };
+use crate::pathspec::Pathspec;
 use crate::objects::blob::Blob;
 use crate::objects::Object;
+use crate::id::Id;
 
 pub struct TreeIterator<'a, S: Queryable> {
     storage_set: &'a StorageSet<S>,
@@ -38,6 +41,12 @@ impl<'a, S: Queryable> Iterator for TreeIterator<'a, S> {
             }
 
             let (key, entry) = next.unwrap();
+            // gitlinks name commits in another repository; there is nothing
+            // here to load for them.
+            if entry.mode.is_gitlink() {
+                continue
+            }
+
             let item = self.storage_set.get_and_load(&entry.id).ok().unwrap_or(None);
             if let Some(xs) = item {
                 match xs {
@@ -60,3 +69,142 @@ impl<'a, S: Queryable> Iterator for TreeIterator<'a, S> {
         }
     }
 }
+
+// What a `TreeWalk` callback wants done after seeing an entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Visit {
+    Continue,
+    // don't descend into this tree
+    Skip,
+    Stop
+}
+
+// Walks a tree depth first, handing each entry's full path ("dir/file") to
+// a callback before descending into it. Limiting to a pathspec prunes every
+// subtree that can't lead to a match.
+pub struct TreeWalk<'a, S: Queryable> {
+    storage_set: &'a StorageSet<S>,
+    pathspec: Pathspec
+}
+
+impl<'a, S: Queryable> TreeWalk<'a, S> {
+    pub fn new(storage_set: &'a StorageSet<S>) -> TreeWalk<'a, S> {
+        TreeWalk {
+            storage_set,
+            pathspec: Pathspec::new()
+        }
+    }
+
+    // Only visits these paths, everything under them, and the trees on
+    // the way to them.
+    pub fn paths<P: AsRef<[u8]>>(self, paths: &[P]) -> TreeWalk<'a, S> {
+        self.pathspec(Pathspec::literal(paths))
+    }
+
+    // Only visits what `pathspec` matches, and the trees on the way to it.
+    pub fn pathspec(mut self, pathspec: Pathspec) -> TreeWalk<'a, S> {
+        self.pathspec = pathspec;
+        self
+    }
+
+    fn root(&self, id: &Id) -> Result<Id> {
+        match self.storage_set.get_and_load(id)? {
+            Some(Object::Commit(commit)) => commit.tree().ok_or_else(|| ErrorKind::MissingObject.into()),
+            Some(Object::Tree(_)) => Ok(id.clone()),
+            _ => Err(ErrorKind::MissingObject.into())
+        }
+    }
+
+    // Calls `visit` with every entry under the tree (or commit) `id`, trees
+    // included; gitlinks are entries like any other.
+    pub fn run<F>(&self, id: &Id, mut visit: F) -> Result<()> where F: FnMut(&[u8], &TreeEntry) -> Result<Visit> {
+        let root = self.root(id)?;
+        self.walk(&[], &root, &mut visit)?;
+        Ok(())
+    }
+
+    // false once the callback asks to stop.
+    fn walk<F>(&self, base: &[u8], id: &Id, visit: &mut F) -> Result<bool> where F: FnMut(&[u8], &TreeEntry) -> Result<Visit> {
+        let tree = match self.storage_set.get_and_load(id)? {
+            Some(Object::Tree(xs)) => xs,
+            _ => return Err(ErrorKind::MissingObject.into())
+        };
+        for (name, entry) in tree {
+            let mut path = base.to_vec();
+            if !path.is_empty() {
+                path.push(b'/');
+            }
+            path.extend_from_slice(&name);
+
+            let wanted = self.pathspec.matches(&path);
+            if !(wanted || entry.mode.is_tree() && self.pathspec.may_match_under(&path)) {
+                continue
+            }
+            match visit(&path, &entry)? {
+                Visit::Stop => return Ok(false),
+                Visit::Skip => continue,
+                Visit::Continue => ()
+            }
+            if entry.mode.is_tree() && !self.walk(&path, &entry.id, visit)? {
+                return Ok(false)
+            }
+        }
+        Ok(true)
+    }
+
+    // Every non-tree entry, in walk order.
+    pub fn entries(&self, id: &Id) -> Result<Vec<(Vec<u8>, TreeEntry)>> {
+        let mut entries = Vec::new();
+        self.run(id, |path, entry| {
+            if !entry.mode.is_tree() {
+                entries.push((path.to_vec(), entry.clone()));
+            }
+            Ok(Visit::Continue)
+        })?;
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testkit::RepoBuilder;
+    use crate::pathspec::Pathspec;
+    use crate::files;
+    use super::{ TreeWalk, Visit };
+
+    #[test]
+    fn walks_prunes_and_limits_paths() {
+        let builder = RepoBuilder::new()
+            .commit("first", files![
+                "README" => "hello\n",
+                "src/lib.rs" => "lib\n",
+                "src/bin/main.rs" => "main\n",
+                "vendor/dep/lib.rs" => "dep\n"
+            ]);
+        let tip = builder.tip().unwrap();
+        let (storage_set, _) = builder.in_memory();
+
+        let mut seen = Vec::new();
+        TreeWalk::new(&storage_set).run(&tip, |path, _| {
+            seen.push(String::from_utf8_lossy(path).into_owned());
+            Ok(if path == b"vendor" { Visit::Skip } else { Visit::Continue })
+        }).expect("failed to walk");
+        assert_eq!(seen, vec!["README", "src", "src/bin", "src/bin/main.rs", "src/lib.rs", "vendor"]);
+
+        let limited = TreeWalk::new(&storage_set).paths(&["src/bin/", "README"]).entries(&tip).expect("failed to walk");
+        let paths: Vec<_> = limited.iter().map(|(xs, _)| String::from_utf8_lossy(xs).into_owned()).collect();
+        assert_eq!(paths, vec!["README", "src/bin/main.rs"]);
+
+        let pathspec = Pathspec::parse(&["*.rs", ":!vendor"]).unwrap();
+        let limited = TreeWalk::new(&storage_set).pathspec(pathspec).entries(&tip).expect("failed to walk");
+        let paths: Vec<_> = limited.iter().map(|(xs, _)| String::from_utf8_lossy(xs).into_owned()).collect();
+        assert_eq!(paths, vec!["src/bin/main.rs", "src/lib.rs"]);
+
+        let mut count = 0;
+        TreeWalk::new(&storage_set).run(&tip, |_, _| {
+            count += 1;
+            Ok(if count == 2 { Visit::Stop } else { Visit::Continue })
+        }).expect("failed to walk");
+        assert_eq!(count, 2);
+    }
+}
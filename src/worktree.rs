@@ -0,0 +1,302 @@
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+
+use crate::vfs::{ OsFs, VfsProvider };
+use crate::refs::RefPtr;
+use crate::config::Config;
+use crate::id::Id;
+
+// Where a worktree keeps its git data. For a classic checkout both dirs are
+// `<worktree>/.git`. A linked worktree has a `.git` file
+// ("gitdir: <common>/worktrees/<name>"). That admin dir holds HEAD and the
+// index; objects and refs live in the common dir named by its "commondir"
+// file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    pub worktree: PathBuf,
+    pub git_dir: PathBuf,
+    pub common_dir: PathBuf
+}
+
+#[derive(Debug)]
+pub struct Worktree {
+    pub name: String,
+    pub path: PathBuf,
+    pub head: Option<RefPtr>
+}
+
+impl Layout {
+    pub fn resolve(path: &Path) -> Result<Layout, std::io::Error> {
+        Layout::resolve_with(&OsFs, path)
+    }
+
+    // `path` is a worktree, or a bare repository's git dir.
+    pub fn resolve_with(vfs: &dyn VfsProvider, path: &Path) -> Result<Layout, std::io::Error> {
+        let dot_git = path.join(".git");
+        let git_dir = match vfs.metadata(&dot_git) {
+            Err(_) if is_git_dir(vfs, path) => path.to_path_buf(),
+            Ok(xs) if !xs.is_dir => {
+                let contents = String::from_utf8(vfs.read(&dot_git)?).map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidData))?;
+                let target = match contents.trim_end().strip_prefix("gitdir: ") {
+                    Some(xs) => xs,
+                    None => return Err(std::io::ErrorKind::InvalidData.into())
+                };
+                path.join(target)
+            },
+            _ => dot_git
+        };
+
+        Layout::from_git_dir(vfs, path, &git_dir)
+    }
+
+    // The layout of git dir `git_dir` with its worktree at `worktree`, as
+    // GIT_DIR and GIT_WORK_TREE give them; the same path for a bare
+    // repository.
+    pub fn from_git_dir(vfs: &dyn VfsProvider, worktree: &Path, git_dir: &Path) -> Result<Layout, std::io::Error> {
+        let common_dir = match vfs.read(&git_dir.join("commondir")) {
+            Ok(contents) => git_dir.join(String::from_utf8_lossy(&contents).trim_end()),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => git_dir.to_path_buf(),
+            Err(e) => return Err(e)
+        };
+
+        Ok(Layout {
+            worktree: worktree.to_path_buf(),
+            git_dir: git_dir.to_path_buf(),
+            common_dir
+        })
+    }
+
+    pub fn is_bare(&self) -> bool {
+        self.worktree == self.git_dir
+    }
+
+    pub fn is_linked(&self) -> bool {
+        self.git_dir != self.common_dir
+    }
+}
+
+// Whether `path` is itself a git dir: it has HEAD, objects and refs, as
+// git's own check looks for.
+pub fn is_git_dir(vfs: &dyn VfsProvider, path: &Path) -> bool {
+    vfs.metadata(&path.join("HEAD")).is_ok_and(|xs| !xs.is_dir)
+        && vfs.metadata(&path.join("objects")).is_ok_and(|xs| xs.is_dir)
+        && vfs.metadata(&path.join("refs")).is_ok_and(|xs| xs.is_dir)
+}
+
+pub fn git_dir(path: &Path) -> Result<PathBuf, std::io::Error> {
+    Ok(Layout::resolve(path)?.git_dir)
+}
+
+pub fn common_dir(path: &Path) -> Result<PathBuf, std::io::Error> {
+    Ok(Layout::resolve(path)?.common_dir)
+}
+
+// Lists the linked worktrees registered in the repository `path` belongs to.
+// The main worktree is not included.
+pub fn list(path: &Path) -> Result<Vec<Worktree>, std::io::Error> {
+    let admin_root = common_dir(path)?.join("worktrees");
+    let mut worktrees = Vec::new();
+    if !admin_root.is_dir() {
+        return Ok(worktrees)
+    }
+
+    for entry in std::fs::read_dir(admin_root.as_path())? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(xs) => xs,
+            Err(_) => continue
+        };
+
+        // "gitdir" names the worktree's `.git` file.
+        let gitdir = match std::fs::read_to_string(entry.path().join("gitdir")) {
+            Ok(xs) => PathBuf::from(xs.trim_end()),
+            Err(_) => continue
+        };
+        let worktree_path = match gitdir.parent() {
+            Some(xs) => xs.to_path_buf(),
+            None => continue
+        };
+
+        let head = std::fs::read_to_string(entry.path().join("HEAD")).ok().and_then(|contents| {
+            match contents.trim_end().strip_prefix("ref: refs/heads/") {
+                Some(branch) => Some(RefPtr::Indirect(String::from(branch))),
+                None => Id::from_str(contents.trim_end()).ok().map(RefPtr::Direct)
+            }
+        });
+
+        worktrees.push(Worktree {
+            name,
+            path: worktree_path,
+            head
+        });
+    }
+
+    worktrees.sort_by(|lhs, rhs| lhs.name.cmp(&rhs.name));
+    Ok(worktrees)
+}
+
+// Worktree names are one component of `<common>/worktrees/`.
+fn check_name(name: &str) -> Result<(), std::io::Error> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid worktree name '{}'", name)))
+    }
+    Ok(())
+}
+
+// Fails if `branch` is checked out in the main worktree or a linked one, as
+// git refuses to check one branch out twice; the worktree whose git dir is
+// `except` doesn't count. A bare repository's HEAD checks nothing out.
+fn check_branch_free(common: &Path, branch: &str, except: Option<&Path>) -> Result<(), std::io::Error> {
+    let bare = Config::from_file(&common.join("config")).ok().and_then(|xs| xs.get_bool("core.bare")) == Some(true);
+    let main_head = std::fs::read_to_string(common.join("HEAD")).unwrap_or_default();
+    let mut holders = Vec::new();
+    if !bare && except != Some(common) && main_head.trim_end().strip_prefix("ref: refs/heads/") == Some(branch) {
+        holders.push(common.parent().unwrap_or(common).to_path_buf());
+    }
+    for worktree in list(common)? {
+        if except == Some(common.join("worktrees").join(&worktree.name).as_path()) {
+            continue
+        }
+        if let Some(RefPtr::Indirect(ref xs)) = worktree.head {
+            if xs == branch {
+                holders.push(worktree.path);
+            }
+        }
+    }
+    match holders.first() {
+        Some(xs) => Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("'{}' is already checked out at '{}'", branch, xs.display()))),
+        None => Ok(())
+    }
+}
+
+// Fails if `branch` is checked out in a worktree other than the one at
+// `path`, as `git switch` refuses.
+pub fn check_branch_elsewhere(path: &Path, branch: &str) -> Result<(), std::io::Error> {
+    let layout = Layout::resolve(path)?;
+    let common = std::fs::canonicalize(&layout.common_dir)?;
+    check_branch_free(&common, branch, Some(&std::fs::canonicalize(&layout.git_dir)?))
+}
+
+// Registers `worktree_path` as a linked worktree with the given HEAD. Files
+// are not checked out; run a `Checkout` against the new path for that. A
+// branch checked out in another worktree is refused.
+pub fn add(path: &Path, name: &str, worktree_path: &Path, head: &RefPtr) -> Result<Layout, std::io::Error> {
+    check_name(name)?;
+    let common = std::fs::canonicalize(common_dir(path)?)?;
+    let admin_dir = common.join("worktrees").join(name);
+    if admin_dir.exists() {
+        return Err(std::io::ErrorKind::AlreadyExists.into())
+    }
+    if let RefPtr::Indirect(branch) = head {
+        check_branch_free(&common, branch, None)?;
+    }
+
+    std::fs::create_dir_all(worktree_path)?;
+    let worktree_path = std::fs::canonicalize(worktree_path)?;
+    let dot_git = worktree_path.join(".git");
+    if dot_git.exists() {
+        return Err(std::io::ErrorKind::AlreadyExists.into())
+    }
+
+    std::fs::create_dir_all(admin_dir.as_path())?;
+    std::fs::write(admin_dir.join("commondir"), "../..\n")?;
+    std::fs::write(admin_dir.join("gitdir"), format!("{}\n", dot_git.display()))?;
+    std::fs::write(admin_dir.join("HEAD"), match head {
+        RefPtr::Indirect(branch) => format!("ref: refs/heads/{}\n", branch),
+        RefPtr::Direct(id) => format!("{}\n", id)
+    })?;
+    std::fs::write(dot_git.as_path(), format!("gitdir: {}\n", admin_dir.display()))?;
+
+    Layout::resolve(worktree_path.as_path())
+}
+
+// Unregisters a linked worktree. The checked out files are left in place;
+// only the admin dir and the worktree's `.git` file are removed.
+pub fn remove(path: &Path, name: &str) -> Result<(), std::io::Error> {
+    check_name(name)?;
+    let admin_dir = common_dir(path)?.join("worktrees").join(name);
+    let gitdir = std::fs::read_to_string(admin_dir.join("gitdir"))?;
+    match std::fs::remove_file(gitdir.trim_end()) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+        Ok(_) => ()
+    }
+    std::fs::remove_dir_all(admin_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testkit::{ RepoBuilder, TempDir };
+    use crate::refs::{ RefPtr, RefSet };
+    use crate::stores::fs as gitfs;
+    use crate::files;
+    use super::{ add, list, remove, Layout };
+
+    #[test]
+    fn linked_worktrees_work() {
+        let dir = TempDir::new("worktree").expect("failed to create tempdir");
+        let main = dir.path().join("main");
+        let builder = RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"])
+            .branch("dev");
+        let tip = builder.tip().unwrap();
+        builder.write(main.as_path()).expect("failed to write");
+
+        let linked = dir.path().join("linked");
+        let layout = add(main.as_path(), "linked", linked.as_path(), &RefPtr::Indirect(String::from("dev")))
+            .expect("failed to add worktree");
+        assert!(layout.is_linked());
+        assert_eq!(std::fs::canonicalize(layout.common_dir).unwrap(), std::fs::canonicalize(main.join(".git")).unwrap());
+
+        // objects and refs come from the common dir, HEAD from the admin dir.
+        let storage_set = gitfs::from(linked.as_path()).expect("failed to open storage");
+        assert!(storage_set.get_and_load(&tip).expect("failed to read").is_some());
+        let refs = RefSet::from_path(linked.as_path()).expect("failed to read refs");
+        assert_eq!(refs.deref("HEAD"), Some(&tip));
+
+        let worktrees = list(main.as_path()).expect("failed to list");
+        assert_eq!(worktrees.len(), 1);
+        assert_eq!(worktrees[0].name, "linked");
+        assert_eq!(worktrees[0].path, std::fs::canonicalize(linked.as_path()).unwrap());
+
+        remove(main.as_path(), "linked").expect("failed to remove");
+        assert!(list(main.as_path()).expect("failed to list").is_empty());
+        assert!(!linked.join(".git").exists());
+    }
+
+    #[test]
+    fn names_and_branches_are_checked() {
+        let dir = TempDir::new("worktree-checks").expect("failed to create tempdir");
+        let main = dir.path().join("main");
+        RepoBuilder::new()
+            .commit("first", files!["README" => "hello\n"])
+            .branch("dev")
+            .write(main.as_path())
+            .expect("failed to write");
+        let dev = RefPtr::Indirect(String::from("dev"));
+
+        for name in &["", ".", "..", "../escape", "a/b"] {
+            let err = add(main.as_path(), name, dir.path().join("bad").as_path(), &dev).expect_err("bad names are refused");
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            assert_eq!(remove(main.as_path(), name).expect_err("bad names are refused").kind(), std::io::ErrorKind::InvalidInput);
+        }
+        assert!(!dir.path().join("bad").exists());
+        assert!(main.join(".git").exists());
+
+        add(main.as_path(), "one", dir.path().join("one").as_path(), &dev).expect("failed to add worktree");
+        let twice = add(main.as_path(), "two", dir.path().join("two").as_path(), &dev).expect_err("dev is checked out");
+        assert_eq!(twice.kind(), std::io::ErrorKind::AlreadyExists);
+        let master = RefPtr::Indirect(String::from("master"));
+        let main_branch = add(main.as_path(), "two", dir.path().join("two").as_path(), &master).expect_err("master is checked out");
+        assert_eq!(main_branch.kind(), std::io::ErrorKind::AlreadyExists);
+        assert!(!dir.path().join("two").exists());
+    }
+
+    #[test]
+    fn classic_layout_works() {
+        let dir = TempDir::new("worktree-classic").expect("failed to create tempdir");
+        let layout = Layout::resolve(dir.path()).expect("failed to resolve");
+        assert!(!layout.is_linked());
+        assert_eq!(layout.git_dir, dir.path().join(".git"));
+    }
+}